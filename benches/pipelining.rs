@@ -0,0 +1,311 @@
+//! A suite of benchmarks covering the main costs of going through a
+//! `FileHandle`, plus the performance contract the crate promises to keep.
+//!
+//! Run it with `cargo bench --bench pipelining`. Results can be saved and
+//! compared against later runs, so regressions show up in your own
+//! environment:
+//!
+//! ```text
+//! cargo bench --bench pipelining -- --save-baseline before
+//! # ... make some changes ...
+//! cargo bench --bench pipelining -- --baseline before --threshold 10
+//! ```
+//!
+//! Baselines are stored as JSON in `target/pipelining-baselines/`, and the
+//! process exits with an error if the contract is broken or any benchmark is
+//! more than `--threshold` percent slower than the baseline.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, BufWriter, Write},
+    os::raw::c_int,
+    path::PathBuf,
+    ptr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+use thin_trait_objects::{
+    file_handle_destroy, file_handle_is_poisoned, file_handle_write,
+    new_null_file_handle, FileHandle, OwnedFileHandle, ShardedWriter,
+    TypedFileHandle,
+};
+
+const ITERATIONS: u32 = 2_000_000;
+const THREADS: u32 = 4;
+const DATA: &[u8] = &[0xAA; 16];
+
+/// Relationships between benchmarks which should always hold, as
+/// `(benchmark, reference, maximum slowdown)`.
+///
+/// These are deliberately loose so they only fail when something is badly
+/// wrong (e.g. the poison check accidentally taking a lock).
+const CONTRACT: &[(&str, &str, f64)] = &[
+    // dispatching through the header stays in the same league as a vtable
+    ("OwnedFileHandle::write", "Box<dyn Write>", 10.0),
+    // knowing the writer's type is never slower than dynamic dispatch
+    ("TypedFileHandle::write", "OwnedFileHandle::write", 1.0),
+    // adopting a boxed trait object doesn't add another layer
+    ("OwnedFileHandle::from_dyn", "OwnedFileHandle::write", 1.25),
+    // the extern "C" entry point adds very little on top of that
+    ("file_handle_write", "OwnedFileHandle::write", 2.0),
+    // rejecting writes to a poisoned handle never gets expensive
+    ("file_handle_write (poisoned)", "file_handle_write", 10.0),
+    // adding a BufWriter to the chain never makes small writes much dearer
+    ("buffered chain", "unbuffered chain", 1.25),
+];
+
+/// Time `ITERATIONS` calls to `write`, returning the nanoseconds per call.
+fn time(mut write: impl FnMut(&[u8]) -> usize) -> f64 {
+    // warm up the caches and branch predictor first
+    let mut total = 0;
+    for _ in 0..ITERATIONS / 10 {
+        total += write(DATA);
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        total += write(DATA);
+    }
+    let elapsed = start.elapsed();
+
+    // make sure the writes can't be optimised away
+    assert!(total > 0);
+    elapsed.as_secs_f64() * 1e9 / f64::from(ITERATIONS)
+}
+
+/// Time `ITERATIONS` writes spread across several threads all using the same
+/// handle, returning the nanoseconds per write.
+fn time_threaded(handle: *mut FileHandle) -> f64 {
+    // raw pointers aren't Send, but this handle may be shared
+    let handle = handle as usize;
+    let start = Instant::now();
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            thread::spawn(move || {
+                let handle = handle as *mut FileHandle;
+                let len = DATA.len() as c_int;
+
+                for _ in 0..ITERATIONS / THREADS {
+                    unsafe {
+                        file_handle_write(handle, DATA.as_ptr().cast(), len);
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    start.elapsed().as_secs_f64() * 1e9 / f64::from(ITERATIONS)
+}
+
+unsafe fn ffi_write(handle: *mut FileHandle, data: &[u8]) -> usize {
+    file_handle_write(handle, data.as_ptr().cast(), data.len() as c_int)
+        as usize
+}
+
+struct Panicking;
+
+impl Write for Panicking {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        panic!("Deliberately poisoning the handle")
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+fn run() -> BTreeMap<&'static str, f64> {
+    let mut results = BTreeMap::new();
+
+    let mut boxed: Box<dyn Write> = Box::new(io::sink());
+    let boxed: *mut Box<dyn Write> = &mut boxed;
+    // Note: the volatile read stops the compiler from devirtualizing
+    let ns = time(|data| unsafe {
+        (*ptr::read_volatile(&boxed)).write(data).unwrap()
+    });
+    results.insert("Box<dyn Write>", ns);
+
+    let mut owned = OwnedFileHandle::new(io::sink());
+    let owned: *mut OwnedFileHandle = &mut owned;
+    let ns = time(|data| unsafe {
+        (*ptr::read_volatile(&owned)).write(data).unwrap()
+    });
+    results.insert("OwnedFileHandle::write", ns);
+
+    let mut typed = TypedFileHandle::new(io::sink());
+    let typed: *mut TypedFileHandle<io::Sink> = &mut typed;
+    let ns = time(|data| unsafe {
+        (*ptr::read_volatile(&typed)).write(data).unwrap()
+    });
+    results.insert("TypedFileHandle::write", ns);
+
+    let boxed: Box<dyn Write + Send + Sync> = Box::new(io::sink());
+    let mut adopted = OwnedFileHandle::from_dyn(boxed);
+    let adopted: *mut OwnedFileHandle = &mut adopted;
+    let ns = time(|data| unsafe {
+        (*ptr::read_volatile(&adopted)).write(data).unwrap()
+    });
+    results.insert("OwnedFileHandle::from_dyn", ns);
+
+    unsafe {
+        let handle = new_null_file_handle();
+        results.insert("file_handle_write", time(|d| ffi_write(handle, d)));
+        file_handle_destroy(handle);
+
+        let handle = FileHandle::for_writer(Panicking);
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(|_| {}));
+        ffi_write(handle, DATA);
+        std::panic::set_hook(hook);
+        assert!(file_handle_is_poisoned(handle));
+        // every write fails, so count the calls instead of the bytes
+        let ns = time(|d| ffi_write(handle, d).min(1));
+        results.insert("file_handle_write (poisoned)", ns);
+        file_handle_destroy(handle);
+
+        let inner = OwnedFileHandle::new(io::sink());
+        let handle = FileHandle::for_writer(inner);
+        results.insert("unbuffered chain", time(|d| ffi_write(handle, d)));
+        file_handle_destroy(handle);
+
+        let inner = OwnedFileHandle::new(io::sink());
+        let handle = FileHandle::for_writer(BufWriter::new(inner));
+        results.insert("buffered chain", time(|d| ffi_write(handle, d)));
+        file_handle_destroy(handle);
+
+        let shared = Arc::new(Mutex::new(io::sink()));
+        let handle = FileHandle::for_shared_writer(shared);
+        results.insert("synchronized handle", time_threaded(handle));
+        file_handle_destroy(handle);
+
+        let sharded = ShardedWriter::new(THREADS as usize, |_| {
+            Ok(OwnedFileHandle::new(io::sink()))
+        });
+        let handle = FileHandle::for_concurrent_writer(sharded);
+        results.insert("sharded handle", time_threaded(handle));
+        file_handle_destroy(handle);
+    }
+
+    results
+}
+
+fn baseline_path(name: &str) -> PathBuf {
+    let target = env::var_os("CARGO_TARGET_DIR");
+    PathBuf::from(target.unwrap_or_else(|| "target".into()))
+        .join("pipelining-baselines")
+        .join(format!("{}.json", name))
+}
+
+/// Save results as a flat JSON object, one benchmark per line.
+fn save(name: &str, results: &BTreeMap<&str, f64>) -> io::Result<PathBuf> {
+    let path = baseline_path(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let entries: Vec<_> = results
+        .iter()
+        .map(|(name, ns)| format!("  \"{}\": {:.3}", name, ns))
+        .collect();
+    fs::write(&path, format!("{{\n{}\n}}\n", entries.join(",\n")))?;
+
+    Ok(path)
+}
+
+/// Load a baseline written by [`save()`].
+fn load(name: &str) -> io::Result<BTreeMap<String, f64>> {
+    let json = fs::read_to_string(baseline_path(name))?;
+
+    Ok(json
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let colon = line.rfind(':')?;
+            let (key, value) = (&line[..colon], &line[colon + 1..]);
+            let key = key.trim().trim_matches('"');
+            let value = value.trim().trim_end_matches(',').parse().ok()?;
+            Some((key.to_string(), value))
+        })
+        .collect())
+}
+
+#[derive(Default)]
+struct Args {
+    save_baseline: Option<String>,
+    baseline: Option<String>,
+    threshold: f64,
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        threshold: 10.0,
+        ..Args::default()
+    };
+    let mut raw = env::args().skip(1);
+
+    // Note: cargo also passes flags like --bench, which we ignore
+    while let Some(arg) = raw.next() {
+        match arg.as_str() {
+            "--save-baseline" => args.save_baseline = raw.next(),
+            "--baseline" => args.baseline = raw.next(),
+            "--threshold" => {
+                let value = raw.next().and_then(|t| t.parse().ok());
+                args.threshold = value.expect("--threshold needs a number");
+            },
+            _ => {},
+        }
+    }
+
+    args
+}
+
+fn main() {
+    let args = parse_args();
+    let results = run();
+    let mut ok = true;
+
+    let baseline = args.baseline.as_ref().map(|name| {
+        load(name).unwrap_or_else(|e| panic!("Unable to load {}: {}", name, e))
+    });
+
+    for (&name, &ns) in &results {
+        print!("{:<30} {:>8.2} ns/write", name, ns);
+
+        if let Some(&old) = baseline.as_ref().and_then(|b| b.get(name)) {
+            let change = (ns - old) / old * 100.0;
+            print!("  ({:+.1}%)", change);
+
+            if change > args.threshold {
+                print!("  REGRESSION");
+                ok = false;
+            }
+        }
+        println!();
+    }
+
+    println!();
+    for &(name, reference, max_slowdown) in CONTRACT {
+        let ratio = results[name] / results[reference];
+        let verdict = if ratio <= max_slowdown { "ok" } else { "BROKEN" };
+        println!(
+            "{:<30} {:.2}x {} (at most {:.1}x) ... {}",
+            name, ratio, reference, max_slowdown, verdict
+        );
+        ok &= ratio <= max_slowdown;
+    }
+
+    if let Some(name) = &args.save_baseline {
+        match save(name, &results) {
+            Ok(path) => println!("\nSaved the baseline to {}", path.display()),
+            Err(e) => panic!("Unable to save the baseline: {}", e),
+        }
+    }
+
+    if !ok {
+        std::process::exit(1);
+    }
+}
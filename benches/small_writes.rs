@@ -0,0 +1,135 @@
+//! Measures the overhead of lots of small writes through a `FileHandle`,
+//! compared to calling a normal `dyn Write` trait object, both for a single
+//! handle and when writes are spread across many handles (optionally of
+//! different types).
+//!
+//! Run it with `cargo bench --bench small_writes`.
+
+use std::{
+    io::{self, Write},
+    os::raw::c_int,
+    ptr,
+    time::{Duration, Instant},
+};
+use thin_trait_objects::{
+    file_handle_destroy, file_handle_write, new_null_file_handle, FileHandle,
+};
+
+const ITERATIONS: u32 = 5_000_000;
+const HANDLES: usize = 100_000;
+
+/// Another writer which throws everything away, so handles can have
+/// different types.
+struct Discard;
+
+impl Write for Discard {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+thin_trait_objects::static_dispatch_handles! {
+    enum Either {
+        Sink(io::Sink),
+        Discard(Discard),
+    }
+}
+
+/// Write to each handle in turn.
+unsafe fn time_round_robin(
+    name: &str,
+    handles: Vec<*mut FileHandle>,
+    data: &[u8],
+) {
+    let c_len = data.len() as c_int;
+    let mut next = 0;
+    let write = |data: &[u8]| {
+        next = (next + 1) % handles.len();
+        let data = data.as_ptr().cast();
+        file_handle_write(handles[next], data, c_len) as usize
+    };
+    let elapsed = time(write, data);
+    report(name, data.len(), elapsed);
+
+    handles.iter().for_each(|&h| file_handle_destroy(h));
+}
+
+fn time(mut write: impl FnMut(&[u8]) -> usize, data: &[u8]) -> Duration {
+    // warm up the caches and branch predictor first
+    let mut total = 0;
+    for _ in 0..ITERATIONS / 10 {
+        total += write(data);
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        total += write(data);
+    }
+    let elapsed = start.elapsed();
+
+    // make sure the writes can't be optimised away
+    assert!(total > 0);
+    elapsed
+}
+
+fn report(name: &str, len: usize, elapsed: Duration) {
+    let nanos = elapsed.as_secs_f64() * 1e9 / f64::from(ITERATIONS);
+    println!("{:<12} {:>3} bytes: {:>6.2} ns/write", name, len, nanos);
+}
+
+fn main() {
+    for &len in &[1, 8, 64] {
+        let data = vec![0xAA; len];
+
+        let mut sink = io::sink();
+        let sink: *mut dyn Write = &mut sink;
+        // Note: the volatile read stops the compiler from devirtualizing
+        let elapsed = time(
+            |data| unsafe { (*ptr::read_volatile(&sink)).write(data).unwrap() },
+            &data,
+        );
+        report("dyn Write", len, elapsed);
+
+        unsafe {
+            let handle = new_null_file_handle();
+            let c_len = len as c_int;
+            let write = |data: &[u8]| {
+                let data = data.as_ptr().cast();
+                let ret = file_handle_write(handle, data, c_len);
+                ret as usize
+            };
+            let elapsed = time(write, &data);
+            report("FileHandle", len, elapsed);
+            file_handle_destroy(handle);
+        }
+
+        // Spreading writes across lots of handles means their headers are
+        // no longer all sitting in the cache
+        unsafe {
+            let handles =
+                (0..HANDLES).map(|_| new_null_file_handle()).collect();
+            time_round_robin("Many handles", handles, &data);
+        }
+
+        // Alternating between writer types makes the call through the
+        // handle's function pointer hard to predict, unless every handle
+        // uses the same enum
+        unsafe {
+            let handles = (0..HANDLES)
+                .map(|i| match i % 2 {
+                    0 => FileHandle::for_writer(io::sink()),
+                    _ => FileHandle::for_writer(Discard),
+                })
+                .collect();
+            time_round_robin("Mixed types", handles, &data);
+
+            let handles = (0..HANDLES)
+                .map(|i| match i % 2 {
+                    0 => Either::from(io::sink()).into_raw_handle(),
+                    _ => Either::from(Discard).into_raw_handle(),
+                })
+                .collect();
+            time_round_robin("Static enum", handles, &data);
+        }
+    }
+}
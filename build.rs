@@ -0,0 +1,145 @@
+//! Records details about the build for `BuildInfo`, passes on the symbol
+//! prefix from `$THIN_TRAIT_OBJECTS_SYMBOL_PREFIX` and the panic check from
+//! `$THIN_TRAIT_OBJECTS_CHECK_PANICS`, and compiles the C host demo in
+//! `examples/c_host/` when the `c-host-demo` feature is enabled.
+//!
+//! The C compiler is invoked directly (honouring `$CC` and `$AR`) so the
+//! crate doesn't need any build dependencies.
+
+use std::{env, fs, path::PathBuf, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    record_build_info();
+    symbol_prefix();
+    check_panics();
+
+    if env::var_os("CARGO_FEATURE_C_HOST_DEMO").is_some() {
+        build_c_host();
+    }
+}
+
+fn record_build_info() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = output_of(Command::new(rustc).arg("--version"))
+        .unwrap_or_else(|| String::from("unknown"));
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            let name = key.strip_prefix("CARGO_FEATURE_")?;
+            Some(name.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    let vars = [
+        ("GIT_HASH", git_hash()),
+        ("RUSTC_VERSION", rustc_version),
+        ("TARGET", env::var("TARGET").unwrap_or_default()),
+        ("PROFILE", env::var("PROFILE").unwrap_or_default()),
+        ("FEATURES", features.join(",")),
+    ];
+    for (name, value) in vars.iter() {
+        println!("cargo:rustc-env=THIN_TRAIT_OBJECTS_{}={}", name, value);
+    }
+}
+
+fn symbol_prefix() {
+    const VAR: &str = "THIN_TRAIT_OBJECTS_SYMBOL_PREFIX";
+    println!("cargo:rerun-if-env-changed={}", VAR);
+    println!("cargo:rustc-check-cfg=cfg(tto_symbol_prefix)");
+
+    let prefix = env::var(VAR).unwrap_or_default();
+    let valid = prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !prefix.starts_with(|c: char| c.is_ascii_digit());
+    assert!(valid, "${} must be a C identifier, not {:?}", VAR, prefix);
+
+    println!("cargo:rustc-env={}={}", VAR, prefix);
+    if !prefix.is_empty() {
+        println!("cargo:rustc-cfg=tto_symbol_prefix");
+    }
+}
+
+/// Set by the `forbid-panics` test to make the build fail to link if the
+/// hot path can panic (see `src/forbid_panics.rs`).
+fn check_panics() {
+    const VAR: &str = "THIN_TRAIT_OBJECTS_CHECK_PANICS";
+    println!("cargo:rerun-if-env-changed={}", VAR);
+    println!("cargo:rustc-check-cfg=cfg(tto_check_panics)");
+
+    if env::var_os(VAR).map_or(false, |value| !value.is_empty()) {
+        println!("cargo:rustc-cfg=tto_check_panics");
+    }
+}
+
+fn git_hash() -> String {
+    let git_dir = PathBuf::from(".git");
+
+    // rerun when HEAD moves, either to another branch or to a new commit
+    if let Ok(head) = fs::read_to_string(git_dir.join("HEAD")) {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", reference);
+        }
+        println!("cargo:rerun-if-changed=.git/index");
+    }
+
+    let git = |args: &[&str]| output_of(Command::new("git").args(args));
+
+    let hash = match git(&["rev-parse", "HEAD"]) {
+        Some(hash) => hash,
+        None => return String::from("unknown"),
+    };
+    let dirty =
+        git(&["status", "--porcelain"]).map_or(false, |s| !s.is_empty());
+
+    if dirty {
+        format!("{}-dirty", hash)
+    } else {
+        hash
+    }
+}
+
+/// Run a command, returning its trimmed stdout if it succeeded.
+fn output_of(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.trim().to_string())
+}
+
+fn build_c_host() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let source = PathBuf::from("examples").join("c_host").join("host.c");
+    let object = out_dir.join("host.o");
+    let archive = out_dir.join("libc_host.a");
+
+    println!("cargo:rerun-if-changed=examples/c_host");
+    println!("cargo:rerun-if-env-changed=CC");
+    println!("cargo:rerun-if-env-changed=AR");
+
+    let cc = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    run(Command::new(cc)
+        .args(&["-std=c11", "-Wall", "-Wextra", "-Werror", "-c"])
+        .arg(&source)
+        .arg("-o")
+        .arg(&object));
+
+    let ar = env::var("AR").unwrap_or_else(|_| String::from("ar"));
+    run(Command::new(ar).arg("crs").arg(&archive).arg(&object));
+
+    println!("cargo:rustc-link-search=native={}", out_dir.display());
+    println!("cargo:rustc-link-lib=static=c_host");
+}
+
+fn run(command: &mut Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|e| panic!("Unable to run {:?}: {}", command, e));
+
+    assert!(status.success(), "{:?} failed with {}", command, status);
+}
@@ -0,0 +1,109 @@
+/*
+ * A hand-written copy of the declarations the demo relies on.
+ *
+ * This is deliberately *not* generated by cbindgen. It is what a C caller
+ * who read the documentation would write, so if a Rust signature or layout
+ * changes without this file being updated, the demo breaks.
+ */
+
+#ifndef THIN_TRAIT_OBJECTS_FFI_CONTRACT_H
+#define THIN_TRAIT_OBJECTS_FFI_CONTRACT_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct FileHandle FileHandle;
+typedef struct ReadHandle ReadHandle;
+typedef struct ExternalFileHandleBuilder ExternalFileHandleBuilder;
+
+typedef struct FfiSlice
+{
+    const uint8_t *data;
+    size_t len;
+} FfiSlice;
+
+typedef struct FileHandleBuilder
+{
+    FileHandle *file_handle;
+    void *place;
+} FileHandleBuilder;
+
+typedef struct FfiConfig
+{
+    size_t size;
+    int zero_write_policy;
+    int zero_write_retries;
+    bool ownership_history;
+} FfiConfig;
+
+typedef struct FfiStr
+{
+    const char *data;
+    size_t len;
+} FfiStr;
+
+typedef struct BuildInfo
+{
+    FfiStr version;
+    FfiStr git_hash;
+    FfiStr rustc_version;
+    FfiStr target;
+    FfiStr profile;
+    FfiStr features;
+} BuildInfo;
+
+#define BOUNDED_MEMORY_RING 2
+#define ENCODING_UTF8 0
+#define ENCODING_LATIN1 3
+
+/* constructors */
+FileHandle *new_null_file_handle(void);
+FileHandle *new_memory_file_handle(void);
+FileHandle *new_chunked_memory_file_handle(void);
+FileHandle *new_bounded_memory_file_handle(size_t capacity, int policy);
+FileHandle *new_file_handle_from_path(const char *path);
+FileHandle *new_background_file_handle(FileHandle *inner, int capacity);
+FileHandle *new_lossy_file_handle(FileHandle *inner, size_t max_pending);
+FileHandle *new_indirect_file_handle(FileHandle *inner);
+FileHandle *new_sequenced_file_handle(FileHandle *inner);
+FileHandle *new_short_write_file_handle(FileHandle *inner, size_t max);
+FileHandle *new_scoped_file_handle(FileHandle *inner);
+FileHandle *new_redacting_file_handle(FileHandle *inner,
+                                      const FfiSlice *patterns,
+                                      size_t count);
+FileHandle *new_transcoding_file_handle(FileHandle *inner, int from, int to);
+FileHandle *new_sharded_file_handle(FileHandle *(*factory)(int), int shards);
+
+/* the external builder */
+ExternalFileHandleBuilder *file_handle_builder_new(void);
+void file_handle_builder_set_layout(ExternalFileHandleBuilder *builder,
+                                    int size, int alignment);
+void file_handle_builder_set_destroy(ExternalFileHandleBuilder *builder,
+                                     void (*destroy)(void *));
+void file_handle_builder_set_write(ExternalFileHandleBuilder *builder,
+                                   int (*write)(void *, const char *, int));
+void file_handle_builder_set_flush(ExternalFileHandleBuilder *builder,
+                                   int (*flush)(void *));
+void file_handle_builder_set_name(ExternalFileHandleBuilder *builder,
+                                  const char *name);
+FileHandleBuilder file_handle_builder_finish(
+    ExternalFileHandleBuilder *builder);
+
+/* using handles */
+int file_handle_write(FileHandle *handle, const char *data, int len);
+int file_handle_flush(FileHandle *handle);
+void file_handle_destroy(FileHandle *handle);
+FfiSlice file_handle_as_memory(FileHandle *handle);
+FileHandle *file_handle_swap(FileHandle *handle, FileHandle *replacement);
+FileHandle *file_handle_child(FileHandle *parent, const char *name);
+ReadHandle *file_handle_reopen_for_read(FileHandle *handle);
+int read_handle_read(ReadHandle *handle, char *buffer, int len);
+void read_handle_destroy(ReadHandle *handle);
+
+/* configuration */
+int thin_trait_objects_current_config(FfiConfig *config);
+FfiStr thin_trait_objects_version(void);
+BuildInfo thin_trait_objects_build_info(void);
+
+#endif
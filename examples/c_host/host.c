@@ -0,0 +1,274 @@
+/*
+ * A small C host which uses the library the way a real application would,
+ * checking the results as it goes.
+ *
+ * It is compiled by build.rs when the "c-host-demo" feature is enabled and
+ * run by `cargo test --features c-host-demo`.
+ */
+
+#include "ffi_contract.h"
+#include <stdalign.h>
+#include <stdio.h>
+#include <string.h>
+
+static int failures = 0;
+
+#define CHECK(condition)                                                      \
+    do                                                                        \
+    {                                                                         \
+        if (!(condition))                                                     \
+        {                                                                     \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__,          \
+                    __LINE__, #condition);                                    \
+            failures++;                                                       \
+        }                                                                     \
+    } while (0)
+
+static void write_str(FileHandle *handle, const char *msg)
+{
+    int len = (int)strlen(msg);
+    CHECK(file_handle_write(handle, msg, len) == len);
+}
+
+static bool memory_equals(FileHandle *handle, const char *expected)
+{
+    FfiSlice slice = file_handle_as_memory(handle);
+    return slice.len == strlen(expected) &&
+           memcmp(slice.data, expected, slice.len) == 0;
+}
+
+/* A sink implemented in C, which captures everything written to it */
+
+typedef struct Capture
+{
+    char data[1024];
+    size_t len;
+    int flushes;
+    int destroyed;
+} Capture;
+
+typedef struct Sink
+{
+    Capture *capture;
+} Sink;
+
+static int sink_write(void *instance, const char *data, int len)
+{
+    Capture *capture = ((Sink *)instance)->capture;
+    size_t space = sizeof(capture->data) - capture->len;
+    size_t n = (size_t)len < space ? (size_t)len : space;
+
+    memcpy(capture->data + capture->len, data, n);
+    capture->len += n;
+    return (int)n;
+}
+
+static int sink_flush(void *instance)
+{
+    ((Sink *)instance)->capture->flushes++;
+    return 0;
+}
+
+static void sink_destroy(void *instance)
+{
+    ((Sink *)instance)->capture->destroyed++;
+}
+
+static FileHandle *new_sink(Capture *capture)
+{
+    memset(capture, 0, sizeof(*capture));
+
+    ExternalFileHandleBuilder *builder = file_handle_builder_new();
+    file_handle_builder_set_layout(builder, sizeof(Sink), alignof(Sink));
+    file_handle_builder_set_write(builder, sink_write);
+    file_handle_builder_set_flush(builder, sink_flush);
+    file_handle_builder_set_destroy(builder, sink_destroy);
+    file_handle_builder_set_name(builder, "c-sink");
+
+    FileHandleBuilder result = file_handle_builder_finish(builder);
+    CHECK(result.file_handle != NULL);
+    ((Sink *)result.place)->capture = capture;
+
+    return result.file_handle;
+}
+
+static bool captured(const Capture *capture, const char *expected)
+{
+    return capture->len == strlen(expected) &&
+           memcmp(capture->data, expected, capture->len) == 0;
+}
+
+static void memory_handles(void)
+{
+    FileHandle *null = new_null_file_handle();
+    write_str(null, "ignored");
+    CHECK(file_handle_flush(null) == 0);
+    file_handle_destroy(null);
+
+    FileHandle *memory = new_memory_file_handle();
+    write_str(memory, "Hello, ");
+    write_str(memory, "World!");
+    CHECK(memory_equals(memory, "Hello, World!"));
+    file_handle_destroy(memory);
+
+    FileHandle *chunked = new_chunked_memory_file_handle();
+    write_str(chunked, "abc");
+    CHECK(memory_equals(chunked, "abc"));
+    file_handle_destroy(chunked);
+
+    FileHandle *bounded =
+        new_bounded_memory_file_handle(4, BOUNDED_MEMORY_RING);
+    CHECK(bounded != NULL);
+    write_str(bounded, "ab");
+    write_str(bounded, "cd");
+    write_str(bounded, "ef");
+    file_handle_destroy(bounded);
+}
+
+static void files(const char *dir)
+{
+    char path[512];
+    snprintf(path, sizeof(path), "%s/c-host-demo.txt", dir);
+
+    FileHandle *file = new_file_handle_from_path(path);
+    CHECK(file != NULL);
+    write_str(file, "written from C");
+
+    ReadHandle *reader = file_handle_reopen_for_read(file);
+    CHECK(reader != NULL);
+    char buffer[64] = {0};
+    CHECK(read_handle_read(reader, buffer, sizeof(buffer)) == 14);
+    CHECK(strcmp(buffer, "written from C") == 0);
+    read_handle_destroy(reader);
+
+    file_handle_destroy(file);
+    remove(path);
+}
+
+static void external_builder(void)
+{
+    Capture capture;
+    FileHandle *sink = new_sink(&capture);
+
+    write_str(sink, "direct");
+    CHECK(file_handle_flush(sink) == 0);
+    CHECK(captured(&capture, "direct"));
+    CHECK(capture.flushes == 1);
+
+    file_handle_destroy(sink);
+    CHECK(capture.destroyed == 1);
+
+    /* a builder without a write callback is rejected */
+    ExternalFileHandleBuilder *builder = file_handle_builder_new();
+    CHECK(file_handle_builder_finish(builder).file_handle == NULL);
+}
+
+static void adapters(void)
+{
+    Capture capture;
+    FileHandle *handle;
+
+    handle = new_background_file_handle(new_sink(&capture), 4);
+    write_str(handle, "queued");
+    CHECK(file_handle_flush(handle) == 0);
+    CHECK(captured(&capture, "queued"));
+    file_handle_destroy(handle);
+    CHECK(capture.destroyed == 1);
+
+    handle = new_lossy_file_handle(new_sink(&capture), 64);
+    write_str(handle, "telemetry");
+    file_handle_destroy(handle);
+    CHECK(captured(&capture, "telemetry"));
+
+    handle = new_short_write_file_handle(new_sink(&capture), 2);
+    CHECK(file_handle_write(handle, "abc", 3) == 2);
+    file_handle_destroy(handle);
+    CHECK(captured(&capture, "ab"));
+
+    handle = new_sequenced_file_handle(new_sink(&capture));
+    write_str(handle, "first");
+    write_str(handle, "second");
+    file_handle_destroy(handle);
+    CHECK(captured(&capture, "0 first1 second"));
+
+    FfiSlice secret = {(const uint8_t *)"hunter2", 7};
+    handle = new_redacting_file_handle(new_sink(&capture), &secret, 1);
+    write_str(handle, "password=hunter2");
+    file_handle_destroy(handle);
+    CHECK(captured(&capture, "password=*******"));
+
+    handle = new_transcoding_file_handle(new_sink(&capture), ENCODING_LATIN1,
+                                         ENCODING_UTF8);
+    CHECK(file_handle_write(handle, "\xE9", 1) == 1);
+    file_handle_destroy(handle);
+    CHECK(captured(&capture, "\xC3\xA9"));
+
+    Capture other;
+    handle = new_indirect_file_handle(new_sink(&capture));
+    write_str(handle, "before");
+    FileHandle *previous = file_handle_swap(handle, new_sink(&other));
+    write_str(handle, "after");
+    file_handle_destroy(previous);
+    file_handle_destroy(handle);
+    CHECK(captured(&capture, "before"));
+    CHECK(captured(&other, "after"));
+
+    handle = new_scoped_file_handle(new_sink(&capture));
+    FileHandle *child = file_handle_child(handle, "child");
+    CHECK(child != NULL);
+    write_str(child, "hi");
+    file_handle_destroy(child);
+    file_handle_destroy(handle);
+    CHECK(capture.len > 2);
+}
+
+static Capture shard_captures[2];
+
+static FileHandle *shard_factory(int shard)
+{
+    return new_sink(&shard_captures[shard]);
+}
+
+static void sharding(void)
+{
+    FileHandle *handle = new_sharded_file_handle(shard_factory, 2);
+    write_str(handle, "sharded");
+    file_handle_destroy(handle);
+
+    size_t total = shard_captures[0].len + shard_captures[1].len;
+    CHECK(total == strlen("sharded"));
+}
+
+static void config(void)
+{
+    FfiConfig config = {0};
+    config.size = sizeof(config);
+    CHECK(thin_trait_objects_current_config(&config) == 0);
+    CHECK(!config.ownership_history);
+}
+
+static void build_info(void)
+{
+    FfiStr version = thin_trait_objects_version();
+    BuildInfo info = thin_trait_objects_build_info();
+
+    CHECK(version.len > 0 && version.data[version.len] == '\0');
+    CHECK(info.version.data == version.data);
+    CHECK(strncmp(info.rustc_version.data, "rustc ", 6) == 0);
+    CHECK(info.profile.len > 0);
+}
+
+int c_host_run_demo(const char *scratch_dir)
+{
+    failures = 0;
+
+    memory_handles();
+    files(scratch_dir);
+    external_builder();
+    adapters();
+    sharding();
+    config();
+    build_info();
+
+    return failures;
+}
@@ -0,0 +1,46 @@
+#include "ostream_handle.hpp"
+#include <cstring>
+#include <fstream>
+#include <iostream>
+#include <memory>
+
+// Write a message to a handle, the same way plain C code would.
+static bool say_hello(FileHandle *handle)
+{
+    const char *msg = "Hello, World\n";
+    const int len = std::strlen(msg);
+
+    return file_handle_write(handle, msg, len) == len &&
+           file_handle_flush(handle) == 0;
+}
+
+int main(int argc, char **argv)
+{
+    using thin_trait_objects::file_handle_from_ostream;
+
+    // a stream we keep ownership of
+    FileHandle *handle = file_handle_from_ostream(std::cout);
+
+    if (!handle || !say_hello(handle))
+    {
+        std::cerr << "Unable to write to stdout" << std::endl;
+        return 1;
+    }
+    file_handle_destroy(handle);
+
+    if (argc > 1)
+    {
+        // a stream the handle owns, which gets closed when it is destroyed
+        std::unique_ptr<std::ostream> file(new std::ofstream(argv[1]));
+        handle = file_handle_from_ostream(std::move(file));
+
+        if (!handle || !say_hello(handle))
+        {
+            std::cerr << "Unable to write to " << argv[1] << std::endl;
+            return 1;
+        }
+        file_handle_destroy(handle);
+    }
+
+    return 0;
+}
@@ -0,0 +1,88 @@
+// A small shim for turning a C++ std::ostream into a FileHandle.
+//
+// The callbacks below are handed to new_file_handle_from_ostream(), which
+// treats the stream as an opaque pointer and never looks inside it.
+#pragma once
+
+#include "thin_trait_objects.h"
+#include <cerrno>
+#include <memory>
+#include <ostream>
+
+namespace thin_trait_objects
+{
+namespace detail
+{
+
+inline int ostream_write(void *object, const char *data, int len) noexcept
+{
+    std::ostream *stream = static_cast<std::ostream *>(object);
+
+    try
+    {
+        stream->write(data, len);
+    }
+    catch (...)
+    {
+        return -EIO;
+    }
+
+    return stream->good() ? len : -EIO;
+}
+
+inline int ostream_flush(void *object) noexcept
+{
+    std::ostream *stream = static_cast<std::ostream *>(object);
+
+    try
+    {
+        stream->flush();
+    }
+    catch (...)
+    {
+        return -EIO;
+    }
+
+    return stream->good() ? 0 : -EIO;
+}
+
+inline void ostream_delete(void *object) noexcept
+{
+    delete static_cast<std::ostream *>(object);
+}
+
+} // namespace detail
+
+// Wrap a stream the caller keeps ownership of. The stream must outlive the
+// FileHandle.
+inline FileHandle *file_handle_from_ostream(std::ostream &stream)
+{
+    OstreamVtable vtable = {
+        detail::ostream_write,
+        detail::ostream_flush,
+        nullptr,
+    };
+
+    return new_file_handle_from_ostream(&stream, &vtable);
+}
+
+// Wrap a stream, deleting it when the FileHandle is destroyed.
+inline FileHandle *file_handle_from_ostream(std::unique_ptr<std::ostream> stream)
+{
+    OstreamVtable vtable = {
+        detail::ostream_write,
+        detail::ostream_flush,
+        detail::ostream_delete,
+    };
+
+    FileHandle *handle = new_file_handle_from_ostream(stream.get(), &vtable);
+
+    if (handle)
+    {
+        stream.release();
+    }
+
+    return handle;
+}
+
+} // namespace thin_trait_objects
@@ -0,0 +1,31 @@
+#include "tto.hpp"
+#include <iostream>
+
+int main(int argc, char **argv)
+{
+    tto::FileHandle handle =
+        argc > 1 ? tto::FileHandle::open(argv[1]) : tto::FileHandle::null();
+
+    if (!handle)
+    {
+        std::cerr << "Unable to open the handle" << std::endl;
+        return 1;
+    }
+
+    // ownership moves along with the wrapper, so it is destroyed exactly once
+    tto::FileHandle moved = std::move(handle);
+
+    std::error_code ec = moved.write("Hello, World\n");
+    if (!ec)
+    {
+        ec = moved.flush();
+    }
+
+    if (ec)
+    {
+        std::cerr << "Unable to write: " << ec.message() << std::endl;
+        return 1;
+    }
+
+    return 0;
+}
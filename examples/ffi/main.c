@@ -162,13 +162,22 @@ int custom_flush(void *handle)
 
 FileHandle *custom_file_handle()
 {
-    // Allocate our custom file handle
-    FileHandleBuilder builder = new_file_handle_builder(
-        sizeof(CustomFileHandle),
-        alignof(CustomFileHandle),
-        custom_destroy,
-        custom_write,
-        custom_flush);
+    // Describe our custom file handle
+    ExternalFileHandleBuilder *b = file_handle_builder_new();
+    file_handle_builder_set_layout(b, sizeof(CustomFileHandle),
+                                   alignof(CustomFileHandle));
+    file_handle_builder_set_destroy(b, custom_destroy);
+    file_handle_builder_set_write(b, custom_write);
+    file_handle_builder_set_flush(b, custom_flush);
+    file_handle_builder_set_name(b, "custom");
+
+    // then allocate it
+    FileHandleBuilder builder = file_handle_builder_finish(b);
+
+    if (!builder.file_handle)
+    {
+        return NULL;
+    }
 
     // and initialize it
     CustomFileHandle *custom = builder.place;
@@ -0,0 +1,149 @@
+//! A reference-counted [`OwnedFileHandle`] which can be shared between
+//! threads and still be taken apart again afterwards.
+
+use crate::{
+    sync::{Mutex, MutexGuard},
+    OwnedFileHandle,
+};
+use std::{
+    io::{Error, Write},
+    sync::{Arc, TryLockError},
+};
+
+/// A cheaply cloneable handle where every clone writes to the same
+/// [`OwnedFileHandle`].
+///
+/// Writes from different clones are serialized by a lock. Once only one
+/// clone is left, [`ArcFileHandle::get_mut()`],
+/// [`ArcFileHandle::try_unwrap()`] and [`ArcFileHandle::downcast_arc()`]
+/// give access to whatever is behind the handle again, mirroring
+/// [`Arc::get_mut()`] and [`Arc::try_unwrap()`].
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use thin_trait_objects::{ArcFileHandle, OwnedFileHandle};
+/// let handle = ArcFileHandle::new(OwnedFileHandle::new(Vec::<u8>::new()));
+/// let other = handle.clone();
+///
+/// // still shared, so we get the handle back
+/// let handle = handle.downcast_arc::<Vec<u8>>().unwrap_err();
+/// drop(other);
+///
+/// let buffer: Arc<Vec<u8>> = handle.downcast_arc().ok().unwrap();
+/// assert!(buffer.is_empty());
+/// ```
+#[derive(Clone)]
+pub struct ArcFileHandle(Arc<Mutex<OwnedFileHandle>>);
+
+impl ArcFileHandle {
+    /// Start sharing an [`OwnedFileHandle`].
+    pub fn new(handle: OwnedFileHandle) -> Self {
+        ArcFileHandle(Arc::new(Mutex::new(handle)))
+    }
+
+    /// How many clones of this handle exist (including this one).
+    pub fn strong_count(this: &Self) -> usize { Arc::strong_count(&this.0) }
+
+    /// Lock the handle, giving temporary exclusive access to it.
+    pub fn lock(&self) -> MutexGuard<'_, OwnedFileHandle> {
+        // the handle tracks panics itself (see OwnedFileHandle::is_poisoned())
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get exclusive access to the handle without locking, if this is the
+    /// only clone.
+    pub fn get_mut(&mut self) -> Option<&mut OwnedFileHandle> {
+        let mutex = Arc::get_mut(&mut self.0)?;
+        Some(mutex.get_mut().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Take the [`OwnedFileHandle`] back out, if this is the only clone.
+    pub fn try_unwrap(self) -> Result<OwnedFileHandle, Self> {
+        match Arc::try_unwrap(self.0) {
+            Ok(mutex) => {
+                Ok(mutex.into_inner().unwrap_or_else(|e| e.into_inner()))
+            },
+            Err(shared) => Err(ArcFileHandle(shared)),
+        }
+    }
+
+    /// Extract the object behind the handle, if this is the only clone and
+    /// the object has type `W`.
+    ///
+    /// On failure the handle is given back untouched.
+    pub fn downcast_arc<W>(self) -> Result<Arc<W>, Self>
+    where
+        W: Send + Sync + 'static,
+    {
+        match self.try_unwrap().map(OwnedFileHandle::downcast::<W>) {
+            Ok(Ok(writer)) => Ok(Arc::new(writer)),
+            Ok(Err(handle)) => Err(ArcFileHandle::new(handle)),
+            Err(this) => Err(this),
+        }
+    }
+
+    /// Lock the handle being wrapped, unless someone else is using it.
+    pub(crate) fn try_inner(
+        &self,
+    ) -> Option<MutexGuard<'_, OwnedFileHandle>> {
+        match self.0.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+impl From<OwnedFileHandle> for ArcFileHandle {
+    fn from(handle: OwnedFileHandle) -> Self { ArcFileHandle::new(handle) }
+}
+
+impl Write for &ArcFileHandle {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.lock().flush() }
+}
+
+impl Write for ArcFileHandle {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { (&*self).flush() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::tests::SharedBuffer;
+
+    #[test]
+    fn exclusive_access_only_with_one_clone() {
+        let mut handle = ArcFileHandle::new(OwnedFileHandle::new(Vec::new()));
+        let mut other = handle.clone();
+        assert_eq!(ArcFileHandle::strong_count(&handle), 2);
+
+        other.write_all(b"Hello").unwrap();
+        assert!(handle.get_mut().is_none());
+        drop(other);
+
+        let inner = handle.get_mut().unwrap();
+        assert_eq!(inner.downcast_ref::<Vec<u8>>().unwrap(), b"Hello");
+        assert!(handle.try_unwrap().is_ok());
+    }
+
+    #[test]
+    fn downcasting_checks_the_type() {
+        let buffer = SharedBuffer::default();
+        let handle = ArcFileHandle::from(OwnedFileHandle::new(buffer.clone()));
+
+        let handle = handle.downcast_arc::<Vec<u8>>().unwrap_err();
+        (&handle).write_all(b"still works").unwrap();
+
+        let got: Arc<SharedBuffer> = handle.downcast_arc().ok().unwrap();
+        assert!(Arc::ptr_eq(&got.0, &buffer.0));
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"still works");
+    }
+}
@@ -0,0 +1,244 @@
+//! A bounded, lock-free queue which any number of threads can push to and
+//! pop from at once.
+//!
+//! This is the classic array-based design (see Dmitry Vyukov's "bounded
+//! MPMC queue"), where each slot has a stamp saying which lap of the ring it
+//! is ready for. The head and tail hold a lap in their upper bits and an
+//! index into the ring in their lower bits, so the capacity doesn't need to
+//! be a power of two.
+
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{self, AtomicUsize, Ordering},
+};
+
+struct Slot<T> {
+    /// The head or tail position this slot is waiting for. It is `tail` when
+    /// the slot is empty and ready to be pushed to, and `tail + 1` once it
+    /// holds a value which is ready to be popped.
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A fixed-size queue where pushing and popping never take a lock.
+pub(crate) struct ArrayQueue<T> {
+    /// Where the next value will be popped from.
+    head: AtomicUsize,
+    /// Where the next value will be pushed to.
+    tail: AtomicUsize,
+    slots: Box<[Slot<T>]>,
+    /// Adding this to a position moves it to the same index on the next lap.
+    one_lap: usize,
+}
+
+unsafe impl<T: Send> Send for ArrayQueue<T> {}
+unsafe impl<T: Send> Sync for ArrayQueue<T> {}
+
+impl<T> ArrayQueue<T> {
+    /// Create a queue which holds up to `capacity` values.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is `0`.
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "The capacity must be positive");
+
+        let slots = (0..capacity)
+            .map(|i| Slot {
+                stamp: AtomicUsize::new(i),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+
+        ArrayQueue {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            slots,
+            one_lap: (capacity + 1).next_power_of_two(),
+        }
+    }
+
+    /// The position after `pos`, wrapping around to the next lap at the end
+    /// of the ring.
+    fn next(&self, pos: usize) -> usize {
+        let index = pos & (self.one_lap - 1);
+
+        if index + 1 < self.slots.len() {
+            pos + 1
+        } else {
+            (pos & !(self.one_lap - 1)).wrapping_add(self.one_lap)
+        }
+    }
+
+    /// Add `value` to the back of the queue, handing it back if the queue is
+    /// full.
+    pub(crate) fn push(&self, value: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[tail & (self.one_lap - 1)];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if stamp == tail {
+                let next = self.next(tail);
+                match self.tail.compare_exchange_weak(
+                    tail,
+                    next,
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // Safety: winning the exchange gives us the slot
+                        unsafe {
+                            (*slot.value.get()).as_mut_ptr().write(value);
+                        }
+                        slot.stamp.store(tail + 1, Ordering::Release);
+                        return Ok(());
+                    },
+                    Err(current) => tail = current,
+                }
+            } else if stamp.wrapping_add(self.one_lap) == tail + 1 {
+                // the slot still holds a value from the previous lap
+                atomic::fence(Ordering::SeqCst);
+                let head = self.head.load(Ordering::Relaxed);
+
+                if head.wrapping_add(self.one_lap) == tail {
+                    return Err(value);
+                }
+                tail = self.tail.load(Ordering::Relaxed);
+            } else {
+                // another thread is part way through using the slot
+                std::thread::yield_now();
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Remove the value at the front of the queue, if there is one.
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut head = self.head.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[head & (self.one_lap - 1)];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if stamp == head + 1 {
+                let next = self.next(head);
+                match self.head.compare_exchange_weak(
+                    head,
+                    next,
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // Safety: winning the exchange gives us the value
+                        let value =
+                            unsafe { (*slot.value.get()).as_ptr().read() };
+                        slot.stamp.store(
+                            head.wrapping_add(self.one_lap),
+                            Ordering::Release,
+                        );
+                        return Some(value);
+                    },
+                    Err(current) => head = current,
+                }
+            } else if stamp == head {
+                // nothing has been pushed to this slot yet
+                atomic::fence(Ordering::SeqCst);
+                let tail = self.tail.load(Ordering::Relaxed);
+
+                if tail == head {
+                    return None;
+                }
+                head = self.head.load(Ordering::Relaxed);
+            } else {
+                std::thread::yield_now();
+                head = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T> Drop for ArrayQueue<T> {
+    fn drop(&mut self) { while self.pop().is_some() {} }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn values_come_out_in_order_until_full() {
+        let queue = ArrayQueue::new(3);
+
+        for lap in 0..5 {
+            for i in 0..3 {
+                queue.push(lap * 3 + i).unwrap();
+            }
+            assert_eq!(queue.push(42), Err(42));
+
+            for i in 0..3 {
+                assert_eq!(queue.pop(), Some(lap * 3 + i));
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn leftover_values_are_dropped() {
+        let value = Arc::new(());
+        let queue = ArrayQueue::new(4);
+        queue.push(Arc::clone(&value)).unwrap();
+        queue.push(Arc::clone(&value)).unwrap();
+        assert_eq!(Arc::strong_count(&value), 3);
+
+        drop(queue);
+
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn concurrent_pushes_and_pops() {
+        const PER_THREAD: usize = 10_000;
+        let queue = Arc::new(ArrayQueue::new(7));
+
+        let producers: Vec<_> = (0..4)
+            .map(|t| {
+                let queue = Arc::clone(&queue);
+                std::thread::spawn(move || {
+                    for i in 0..PER_THREAD {
+                        let mut value = t * PER_THREAD + i;
+                        while let Err(v) = queue.push(value) {
+                            value = v;
+                            std::thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let mut seen = vec![false; 4 * PER_THREAD];
+        let mut last = [None; 4];
+        for _ in 0..seen.len() {
+            let value = loop {
+                match queue.pop() {
+                    Some(value) => break value,
+                    None => std::thread::yield_now(),
+                }
+            };
+            // each producer's values arrive in the order they were pushed
+            let producer = value / PER_THREAD;
+            assert!(last[producer].map_or(true, |prev| prev < value));
+            last[producer] = Some(value);
+            seen[value] = true;
+        }
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert!(seen.iter().all(|&seen| seen));
+        assert_eq!(queue.pop(), None);
+    }
+}
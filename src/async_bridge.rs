@@ -0,0 +1,481 @@
+//! Bridging between [`OwnedFileHandle`] and asynchronous Rust code.
+//!
+//! This crate has no dependencies, so instead of using
+//! `futures::io::AsyncWrite` directly we define an [`AsyncWrite`] trait with
+//! the exact same signature. Forwarding between the two is a couple of lines
+//! of boilerplate.
+
+use crate::OwnedFileHandle;
+use std::{
+    io::{Error, ErrorKind, Write},
+    mem,
+    pin::Pin,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    thread::{JoinHandle, Thread},
+};
+
+/// A mirror of `futures::io::AsyncWrite`.
+pub trait AsyncWrite {
+    /// Attempt to write bytes from `buf` into the object.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>>;
+
+    /// Attempt to flush the object, ensuring that any buffered data reach
+    /// their destination.
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Error>>;
+
+    /// Attempt to close the object.
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Error>>;
+}
+
+/// Something which can run blocking tasks without stalling an async
+/// executor (e.g. `tokio::task::spawn_blocking()`).
+pub trait SpawnBlocking {
+    /// Run the `task` to completion on some other thread.
+    ///
+    /// The task must eventually be run, otherwise anything waiting on it will
+    /// never be woken up.
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send + 'static>);
+}
+
+impl<F> SpawnBlocking for F
+where
+    F: Fn(Box<dyn FnOnce() + Send + 'static>),
+{
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send + 'static>) {
+        self(task)
+    }
+}
+
+enum Operation {
+    Write(Vec<u8>),
+    Flush,
+}
+
+#[derive(Default)]
+struct Pending {
+    outcome: Option<(OwnedFileHandle, Result<(), Error>)>,
+    waker: Option<Waker>,
+}
+
+enum State {
+    Idle(OwnedFileHandle),
+    Busy(Arc<Mutex<Pending>>),
+    /// Only used while transitioning between states.
+    Empty,
+}
+
+/// An [`AsyncWrite`] adapter which runs each blocking operation on an
+/// [`OwnedFileHandle`] using a [`SpawnBlocking`] executor.
+///
+/// Writes are copied and handed off to the executor, completing immediately,
+/// and only one operation is in flight at a time. Any error a write
+/// encounters is reported by the next call to
+/// [`AsyncWrite::poll_write()`] or [`AsyncWrite::poll_flush()`].
+pub struct AsyncFileHandle<E> {
+    executor: E,
+    state: State,
+    flushing: bool,
+}
+
+impl<E: SpawnBlocking> AsyncFileHandle<E> {
+    /// Wrap an [`OwnedFileHandle`].
+    pub fn new(handle: OwnedFileHandle, executor: E) -> Self {
+        AsyncFileHandle {
+            executor,
+            state: State::Idle(handle),
+            flushing: false,
+        }
+    }
+
+    /// Wait for the in-flight operation (if any) to finish.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let pending = match self.state {
+            State::Idle(_) => return Poll::Ready(Ok(())),
+            State::Busy(ref pending) => Arc::clone(pending),
+            State::Empty => unreachable!(),
+        };
+
+        let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+
+        match pending.outcome.take() {
+            Some((handle, result)) => {
+                self.state = State::Idle(handle);
+                self.flushing = false;
+                Poll::Ready(result)
+            },
+            None => {
+                pending.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+
+    fn start(&mut self, op: Operation) {
+        let mut handle = match mem::replace(&mut self.state, State::Empty) {
+            State::Idle(handle) => handle,
+            _ => unreachable!("Operations are only started when idle"),
+        };
+
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let task_pending = Arc::clone(&pending);
+
+        self.executor.spawn_blocking(Box::new(move || {
+            let result = match op {
+                Operation::Write(data) => handle.write_all(&data),
+                Operation::Flush => handle.flush(),
+            };
+
+            let waker = {
+                let mut pending =
+                    task_pending.lock().unwrap_or_else(|e| e.into_inner());
+                pending.outcome = Some((handle, result));
+                pending.waker.take()
+            };
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }));
+
+        self.state = State::Busy(pending);
+    }
+}
+
+impl<E: SpawnBlocking + Unpin> AsyncWrite for AsyncFileHandle<E> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+
+        match this.poll_idle(cx) {
+            Poll::Ready(Ok(())) => {},
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        if !buf.is_empty() {
+            this.start(Operation::Write(buf.to_vec()));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+
+        if !this.flushing {
+            // make sure the previous write went through before flushing
+            match this.poll_idle(cx) {
+                Poll::Ready(Ok(())) => {},
+                other => return other,
+            }
+
+            this.start(Operation::Flush);
+            this.flushing = true;
+        }
+
+        this.poll_idle(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl OwnedFileHandle {
+    /// Turn this handle into an [`AsyncWrite`]r which uses `executor` to run
+    /// the blocking I/O.
+    ///
+    /// ```rust
+    /// # use thin_trait_objects::OwnedFileHandle;
+    /// let handle = OwnedFileHandle::new(Vec::new());
+    /// let writer = handle.into_async(|task: Box<dyn FnOnce() + Send>| {
+    ///     std::thread::spawn(task);
+    /// });
+    /// ```
+    pub fn into_async<E: SpawnBlocking>(
+        self,
+        executor: E,
+    ) -> AsyncFileHandle<E> {
+        AsyncFileHandle::new(self, executor)
+    }
+
+    /// Create an [`OwnedFileHandle`] for an [`AsyncWrite`]r, driving each
+    /// operation to completion on a dedicated thread.
+    ///
+    /// The writer is closed when the handle is destroyed.
+    pub fn from_async_write<A>(writer: A) -> OwnedFileHandle
+    where
+        A: AsyncWrite + Send + 'static,
+    {
+        OwnedFileHandle::new(AsyncWriteBridge::new(writer))
+    }
+}
+
+enum Command {
+    Write(Vec<u8>, SyncSender<Result<usize, Error>>),
+    Flush(SyncSender<Result<(), Error>>),
+}
+
+/// A blocking [`Write`]r which forwards each call to an [`AsyncWrite`] owned
+/// by a dedicated thread.
+struct AsyncWriteBridge {
+    sender: Option<SyncSender<Command>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AsyncWriteBridge {
+    fn new<A: AsyncWrite + Send + 'static>(writer: A) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(0);
+
+        let thread = std::thread::Builder::new()
+            .name(String::from("async-file-handle"))
+            .spawn(move || drive(Box::pin(writer), receiver))
+            .expect("Unable to spawn the async bridge thread");
+
+        AsyncWriteBridge {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    fn call<T>(
+        &self,
+        command: impl FnOnce(SyncSender<Result<T, Error>>) -> Command,
+    ) -> Result<T, Error> {
+        let (reply_sender, reply) = mpsc::sync_channel(1);
+
+        self.sender
+            .as_ref()
+            .expect("The sender is only removed on drop")
+            .send(command(reply_sender))
+            .map_err(|_| stopped())?;
+
+        reply.recv().map_err(|_| stopped())?
+    }
+}
+
+impl Write for AsyncWriteBridge {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.call(|reply| Command::Write(buf.to_vec(), reply))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.call(Command::Flush) }
+}
+
+impl Drop for AsyncWriteBridge {
+    fn drop(&mut self) {
+        // Hanging up tells the thread to close the writer and exit
+        drop(self.sender.take());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn stopped() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "The async bridge thread has stopped")
+}
+
+fn drive<A: AsyncWrite>(mut writer: Pin<Box<A>>, receiver: Receiver<Command>) {
+    for command in receiver {
+        match command {
+            Command::Write(data, reply) => {
+                let result =
+                    block_on(|cx| writer.as_mut().poll_write(cx, &data));
+                let _ = reply.send(result);
+            },
+            Command::Flush(reply) => {
+                let result = block_on(|cx| writer.as_mut().poll_flush(cx));
+                let _ = reply.send(result);
+            },
+        }
+    }
+
+    let _ = block_on(|cx| writer.as_mut().poll_close(cx));
+}
+
+/// Repeatedly poll until a value is ready, parking the current thread in
+/// between.
+pub(crate) fn block_on<T>(
+    mut poll: impl FnMut(&mut Context<'_>) -> Poll<T>,
+) -> T {
+    let waker = thread_waker(std::thread::current());
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        match poll(&mut cx) {
+            Poll::Ready(value) => return value,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+/// Create a [`Waker`] which will unpark a particular thread.
+fn thread_waker(thread: Thread) -> Waker {
+    // Safety: each RawWaker owns a boxed Thread
+    unsafe fn clone(data: *const ()) -> RawWaker {
+        let thread = &*(data as *const Thread);
+        raw_waker(thread.clone())
+    }
+    unsafe fn wake(data: *const ()) {
+        let thread = Box::from_raw(data as *mut Thread);
+        thread.unpark();
+    }
+    unsafe fn wake_by_ref(data: *const ()) {
+        (*(data as *const Thread)).unpark();
+    }
+    unsafe fn drop(data: *const ()) {
+        mem::drop(Box::from_raw(data as *mut Thread));
+    }
+
+    static VTABLE: RawWakerVTable =
+        RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+
+    fn raw_waker(thread: Thread) -> RawWaker {
+        RawWaker::new(Box::into_raw(Box::new(thread)) as *const (), &VTABLE)
+    }
+
+    unsafe { Waker::from_raw(raw_waker(thread)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::tests::SharedBuffer;
+
+    fn thread_per_task(task: Box<dyn FnOnce() + Send + 'static>) {
+        std::thread::spawn(task);
+    }
+
+    #[test]
+    fn write_to_a_handle_asynchronously() {
+        let buffer = SharedBuffer::default();
+        let handle = OwnedFileHandle::new(buffer.clone());
+        let mut writer = handle.into_async(thread_per_task);
+
+        for _ in 0..10 {
+            let ret = block_on(|cx| {
+                Pin::new(&mut writer).poll_write(cx, b"Hello, World!")
+            });
+            assert_eq!(ret.unwrap(), 13);
+        }
+        block_on(|cx| Pin::new(&mut writer).poll_flush(cx)).unwrap();
+
+        assert_eq!(buffer.0.lock().unwrap().len(), 10 * 13);
+    }
+
+    #[test]
+    fn async_errors_are_reported_on_the_next_call() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+                Err(Error::from_raw_os_error(42))
+            }
+
+            fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+        }
+
+        let mut writer =
+            OwnedFileHandle::new(Broken).into_async(thread_per_task);
+
+        block_on(|cx| Pin::new(&mut writer).poll_write(cx, b"asdf")).unwrap();
+        let err = block_on(|cx| Pin::new(&mut writer).poll_flush(cx))
+            .unwrap_err();
+
+        assert_eq!(err.raw_os_error(), Some(42));
+    }
+
+    /// An [`AsyncWrite`] which is only ready every second time it is polled.
+    struct Reluctant {
+        buffer: SharedBuffer,
+        ready: bool,
+        closed: Arc<Mutex<bool>>,
+    }
+
+    impl Reluctant {
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            self.ready = !self.ready;
+
+            if self.ready {
+                Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    impl AsyncWrite for Reluctant {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, Error>> {
+            let this = self.get_mut();
+            match this.poll_ready(cx) {
+                Poll::Ready(()) => Poll::Ready(this.buffer.write(buf)),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Error>> {
+            self.get_mut().poll_ready(cx).map(Ok)
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Error>> {
+            *self.closed.lock().unwrap() = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn drive_an_async_writer_from_a_handle() {
+        let buffer = SharedBuffer::default();
+        let closed = Arc::new(Mutex::new(false));
+        let writer = Reluctant {
+            buffer: buffer.clone(),
+            ready: false,
+            closed: Arc::clone(&closed),
+        };
+
+        let mut handle = OwnedFileHandle::from_async_write(writer);
+        handle.write_all(b"Hello, World!").unwrap();
+        handle.flush().unwrap();
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"Hello, World!");
+        assert!(!*closed.lock().unwrap());
+
+        drop(handle);
+        assert!(*closed.lock().unwrap());
+    }
+}
@@ -0,0 +1,420 @@
+//! Flushing handles periodically (or once they've been idle for a while)
+//! from a background thread, so buffered data still reaches its destination
+//! when the caller never flushes.
+
+use crate::{
+    global::Global,
+    sync::{AtomicBool, Condvar, JoinHandle, Mutex, MutexGuard},
+    FileHandle,
+};
+use std::{
+    collections::HashMap,
+    io::Error,
+    os::raw::c_int,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+/// Per-handle state letting the timer thread flush a handle without
+/// overlapping the caller's own operations.
+#[derive(Debug, Default)]
+pub(crate) struct AutoflushLock {
+    enabled: AtomicBool,
+    lock: Mutex<Activity>,
+}
+
+/// How the caller has been using a handle, so idle flushes can wait for it
+/// to go quiet.
+#[derive(Debug, Default)]
+pub(crate) struct Activity {
+    operations: u64,
+    last_used: Option<Instant>,
+}
+
+impl AutoflushLock {
+    /// Lock the handle against the timer thread, if autoflushing was ever
+    /// enabled.
+    pub(crate) fn guard(&self) -> Option<MutexGuard<'_, Activity>> {
+        if self.enabled.load(Ordering::Acquire) {
+            let mut activity =
+                self.lock.lock().unwrap_or_else(|e| e.into_inner());
+            activity.operations += 1;
+            activity.last_used = Some(Instant::now());
+            Some(activity)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    interval: Duration,
+    next_due: Instant,
+    /// Only flush once the handle hasn't been used for `interval`.
+    when_idle: bool,
+    /// How many operations the handle had done at its last idle flush.
+    flushed_at: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<usize, Entry>,
+    /// The handle currently being flushed by the timer thread.
+    in_flight: Option<usize>,
+    stop: bool,
+}
+
+#[derive(Debug, Default)]
+struct Scheduler {
+    state: Mutex<State>,
+    changed: Condvar,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(&self) {
+        let mut state = self.state();
+
+        while !state.stop {
+            let now = Instant::now();
+            let due = state
+                .entries
+                .iter()
+                .find(|(_, entry)| entry.next_due <= now)
+                .map(|(&handle, _)| handle);
+
+            if let Some(handle) = due {
+                let entry = &state.entries[&handle];
+                let idle = if entry.when_idle {
+                    Some((entry.interval, entry.flushed_at))
+                } else {
+                    None
+                };
+
+                state.in_flight = Some(handle);
+                drop(state);
+                let idle = unsafe {
+                    flush_if_idle(handle as *mut FileHandle, idle)
+                };
+                state = self.state();
+                state.in_flight = None;
+
+                if let Some(entry) = state.entries.get_mut(&handle) {
+                    match idle {
+                        Some((next_due, flushed_at)) => {
+                            entry.next_due = next_due;
+                            entry.flushed_at = flushed_at;
+                        },
+                        None => {
+                            entry.next_due = Instant::now() + entry.interval
+                        },
+                    }
+                }
+                self.changed.notify_all();
+                continue;
+            }
+
+            let next_due = state.entries.values().map(|e| e.next_due).min();
+            state = match next_due {
+                Some(next_due) => {
+                    let timeout = next_due.saturating_duration_since(now);
+                    self.changed
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                },
+                None => self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// Stop tracking a handle, waiting for the timer thread to finish
+    /// flushing it.
+    fn remove(&self, handle: usize) {
+        let mut state = self.state();
+        state.entries.remove(&handle);
+
+        while state.in_flight == Some(handle) {
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Flush the handle unless the caller is using it.
+///
+/// When only flushing idle handles, `idle` is the idle time and how many
+/// operations the handle had done at its last idle flush, and the next time
+/// to check and the new operation count are returned.
+unsafe fn flush_if_idle(
+    handle: *mut FileHandle,
+    idle: Option<(Duration, u64)>,
+) -> Option<(Instant, u64)> {
+    let ext = (*handle).extensions()?;
+    let activity = ext.autoflush.lock.try_lock().ok()?;
+
+    let flushed_at = match idle {
+        Some((interval, flushed_at)) => {
+            if activity.operations == flushed_at {
+                // nothing has been written since the last flush
+                return None;
+            }
+
+            let last_used = activity.last_used.unwrap_or_else(Instant::now);
+            if last_used.elapsed() < interval {
+                return Some((last_used + interval, flushed_at));
+            }
+            Some((Instant::now() + interval, activity.operations))
+        },
+        None => None,
+    };
+
+    if !(*handle).is_frozen() {
+        let _ = FileHandle::flush_unguarded(handle);
+    }
+
+    flushed_at
+}
+
+static SCHEDULER: Global<Arc<Scheduler>> = Global::new();
+
+/// Get the scheduler, starting its timer thread if it isn't running yet.
+fn scheduler() -> Result<&'static Arc<Scheduler>, Error> {
+    let scheduler = SCHEDULER.get_or_init(Default::default);
+    let mut thread =
+        scheduler.thread.lock().unwrap_or_else(|e| e.into_inner());
+
+    if thread.is_none() {
+        let scheduler = Arc::clone(scheduler);
+        *thread = Some(crate::sync::spawn(
+            "thin-trait-objects-autoflush",
+            move || scheduler.run(),
+        )?);
+    }
+
+    Ok(scheduler)
+}
+
+/// Stop the timer thread and forget about every registered handle.
+pub(crate) unsafe fn shutdown() {
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.state().stop = true;
+        scheduler.changed.notify_all();
+
+        let thread = scheduler
+            .thread
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+
+    SCHEDULER.reset();
+}
+
+/// Forget about a handle which is about to be destroyed.
+pub(crate) unsafe fn unregister(handle: *mut FileHandle) {
+    let enabled = (*handle)
+        .extensions()
+        .map_or(false, |ext| ext.autoflush.enabled.load(Ordering::Acquire));
+
+    if enabled {
+        if let Some(scheduler) = SCHEDULER.get() {
+            scheduler.remove(handle as usize);
+        }
+    }
+}
+
+export! {
+    /// Flush this [`FileHandle`] from a background thread every `interval_ms`
+    /// milliseconds, or stop doing so if `interval_ms` is `0`.
+    ///
+    /// The background flush is skipped whenever the handle is busy with another
+    /// operation, so calls from the host still never overlap, and the handle is
+    /// automatically unregistered when it is destroyed. Calling this again
+    /// changes the interval.
+    ///
+    /// Returns `0` on success, or a negative value (also recorded as the
+    /// handle's last error) if the background thread couldn't be started.
+    pub unsafe extern "C" fn file_handle_enable_autoflush(
+        handle: *mut FileHandle,
+        interval_ms: u32,
+    ) -> c_int {
+        if interval_ms == 0 {
+            file_handle_disable_autoflush(handle);
+            return 0;
+        }
+
+        into_status(handle, register(handle, interval_ms, false))
+    }
+}
+
+export! {
+    /// Flush this [`FileHandle`] from a background thread once it hasn't been
+    /// used for `idle_ms` milliseconds, or stop doing so if `idle_ms` is `0`.
+    ///
+    /// This is handy for buffered log files which are being watched with
+    /// `tail -f`, since output appears shortly after a burst of writes
+    /// instead of whenever the buffer fills up. It replaces any interval set
+    /// with [`file_handle_enable_autoflush()`] (and vice versa), and
+    /// otherwise behaves the same way, including its return value.
+    pub unsafe extern "C" fn file_handle_enable_idle_flush(
+        handle: *mut FileHandle,
+        idle_ms: u32,
+    ) -> c_int {
+        if idle_ms == 0 {
+            file_handle_disable_autoflush(handle);
+            return 0;
+        }
+
+        into_status(handle, register(handle, idle_ms, true))
+    }
+}
+
+/// The code returned by the `file_handle_enable_*()` functions.
+unsafe fn into_status(
+    handle: *mut FileHandle,
+    result: Result<(), Error>,
+) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            (*handle).cold.last_error.record(&e);
+            crate::forbid_panics::into_errno(e)
+        },
+    }
+}
+
+/// Start flushing the handle in the background, failing if the timer thread
+/// couldn't be started.
+pub(crate) unsafe fn register(
+    handle: *mut FileHandle,
+    interval_ms: u32,
+    when_idle: bool,
+) -> Result<(), Error> {
+    let scheduler = scheduler()?;
+    let autoflush = &(*handle).extensions_or_default().autoflush;
+    autoflush.enabled.store(true, Ordering::Release);
+    let flushed_at = autoflush
+        .lock
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .operations;
+
+    let interval = Duration::from_millis(interval_ms.into());
+    scheduler.state().entries.insert(
+        handle as usize,
+        Entry {
+            interval,
+            next_due: Instant::now() + interval,
+            when_idle,
+            flushed_at,
+        },
+    );
+    scheduler.changed.notify_all();
+
+    Ok(())
+}
+
+export! {
+    /// Stop flushing this [`FileHandle`] in the background, waiting for any
+    /// background flush which is already running to finish.
+    pub unsafe extern "C" fn file_handle_disable_autoflush(
+        handle: *mut FileHandle,
+    ) {
+        unregister(handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, OwnedFileHandle};
+    use std::io::{BufWriter, Error, Write};
+
+    #[derive(Clone, Default)]
+    struct Flushes(Arc<Mutex<Vec<u8>>>, Arc<Mutex<usize>>);
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            *self.1.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn buffered_data_is_flushed_in_the_background() {
+        let _global = crate::lifecycle::lock_global_state();
+        let flushes = Flushes::default();
+        let mut handle = OwnedFileHandle::new(BufWriter::new(flushes.clone()));
+
+        handle.write_all(b"Hello, World!").unwrap();
+        handle.enable_autoflush(Duration::from_millis(5)).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(flushes.0.lock().unwrap().as_slice(), b"Hello, World!");
+        assert!(*flushes.1.lock().unwrap() >= 2);
+
+        drop(handle);
+        let count = *flushes.1.lock().unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(*flushes.1.lock().unwrap(), count);
+        unsafe { shutdown() };
+    }
+
+    #[test]
+    fn idle_handles_are_flushed_once() {
+        let _global = crate::lifecycle::lock_global_state();
+        let flushes = Flushes::default();
+        let mut handle = OwnedFileHandle::new(BufWriter::new(flushes.clone()));
+        handle.enable_idle_flush(Duration::from_millis(50)).unwrap();
+
+        // a steady stream of writes never looks idle
+        for _ in 0..10 {
+            handle.write_all(b"asdf").unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(*flushes.1.lock().unwrap(), 0);
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(flushes.0.lock().unwrap().len(), 40);
+        assert_eq!(*flushes.1.lock().unwrap(), 1);
+
+        drop(handle);
+        unsafe { shutdown() };
+    }
+
+    #[test]
+    fn disabled_handles_are_left_alone() {
+        let _global = crate::lifecycle::lock_global_state();
+        let flushes = Flushes::default();
+
+        unsafe {
+            let handle = FileHandle::for_writer(flushes.clone());
+            assert_eq!(file_handle_enable_autoflush(handle, 5), 0);
+            assert_eq!(file_handle_enable_autoflush(handle, 0), 0);
+            std::thread::sleep(Duration::from_millis(30));
+            file_handle_destroy(handle);
+            shutdown();
+        }
+
+        assert_eq!(*flushes.1.lock().unwrap(), 0);
+    }
+}
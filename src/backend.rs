@@ -0,0 +1,232 @@
+//! A safe trait for implementing [`FileHandle`]s which carry extra metadata.
+
+use crate::FileHandle;
+use std::{io::Error, ops::BitOr, os::raw::c_char, ptr};
+
+/// Things a [`FileHandle`]'s object may be able to do, returned by
+/// [`file_handle_capabilities()`] as a bitmask.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Size hints (see [`file_handle_hint_total_size()`]) are used to
+    /// preallocate space instead of being ignored.
+    ///
+    /// [`file_handle_hint_total_size()`]: crate::file_handle_hint_total_size
+    pub const SIZE_HINTS: Capabilities = Capabilities(CAPABILITY_SIZE_HINTS);
+    /// A successful flush means the data has reached durable storage.
+    pub const DURABLE_FLUSH: Capabilities =
+        Capabilities(CAPABILITY_DURABLE_FLUSH);
+    /// Data may be dropped instead of written (e.g. when a buffer is full).
+    pub const LOSSY: Capabilities = Capabilities(CAPABILITY_LOSSY);
+    /// The object is a raw file descriptor (see [`file_handle_as_fd()`]), so
+    /// the kernel can move data into it directly (see
+    /// [`file_handle_splice()`]).
+    ///
+    /// [`file_handle_as_fd()`]: crate::file_handle_as_fd
+    /// [`file_handle_splice()`]: crate::file_handle_splice
+    pub const RAW_FD: Capabilities = Capabilities(CAPABILITY_RAW_FD);
+
+    /// No capabilities.
+    pub const fn empty() -> Self { Capabilities(0) }
+
+    /// Create a set of capabilities from a bitmask, ignoring unknown bits.
+    pub const fn from_bits(bits: u32) -> Self {
+        Capabilities(bits & ALL_CAPABILITIES)
+    }
+
+    /// The raw bitmask.
+    pub const fn bits(self) -> u32 { self.0 }
+
+    /// Are all of `other`'s capabilities also in `self`?
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// The bit for [`Capabilities::SIZE_HINTS`].
+pub const CAPABILITY_SIZE_HINTS: u32 = 1 << 0;
+/// The bit for [`Capabilities::DURABLE_FLUSH`].
+pub const CAPABILITY_DURABLE_FLUSH: u32 = 1 << 1;
+/// The bit for [`Capabilities::LOSSY`].
+pub const CAPABILITY_LOSSY: u32 = 1 << 2;
+/// The bit for [`Capabilities::RAW_FD`].
+pub const CAPABILITY_RAW_FD: u32 = 1 << 3;
+const ALL_CAPABILITIES: u32 = CAPABILITY_SIZE_HINTS
+    | CAPABILITY_DURABLE_FLUSH
+    | CAPABILITY_LOSSY
+    | CAPABILITY_RAW_FD;
+
+/// The object behind a [`FileHandle`] created with
+/// [`FileHandle::for_backend()`].
+///
+/// This is like [`std::io::Write`], except backends may also describe
+/// themselves and react to size hints, all without writing any `unsafe`
+/// code or going through the
+/// [`FileHandleBuilder`][crate::FileHandleBuilder].
+///
+/// ```rust
+/// # use std::io::Error;
+/// # use thin_trait_objects::{
+/// #     Capabilities, FileHandle, OwnedFileHandle, WriterBackend,
+/// # };
+/// struct Counter(usize);
+///
+/// impl WriterBackend for Counter {
+///     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+///         self.0 += buf.len();
+///         Ok(buf.len())
+///     }
+///
+///     fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+///
+///     fn name(&self) -> Option<&str> { Some("counter") }
+///
+///     fn capabilities(&self) -> Capabilities { Capabilities::LOSSY }
+/// }
+///
+/// let handle = unsafe {
+///     OwnedFileHandle::from_raw(FileHandle::for_backend(Counter(0)))
+/// };
+///
+/// assert_eq!(handle.name(), Some("counter"));
+/// assert!(handle.capabilities().contains(Capabilities::LOSSY));
+/// ```
+pub trait WriterBackend: Send + Sync + 'static {
+    /// Write some data, returning how many bytes were written.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error>;
+
+    /// Make sure any buffered data reaches its destination.
+    fn flush(&mut self) -> Result<(), Error>;
+
+    /// A human-readable name for the backend.
+    ///
+    /// This is only checked once, when the handle is created.
+    fn name(&self) -> Option<&str> { None }
+
+    /// What the backend can do.
+    ///
+    /// This is only checked once, when the handle is created.
+    /// [`WriterBackend::hint_total_size()`] is only called when the
+    /// capabilities include [`Capabilities::SIZE_HINTS`].
+    fn capabilities(&self) -> Capabilities { Capabilities::empty() }
+
+    /// Prepare for roughly `bytes` more bytes being written.
+    fn hint_total_size(&mut self, bytes: u64) -> Result<(), Error> {
+        let _ = bytes;
+        Ok(())
+    }
+}
+
+export! {
+    /// Get the name of a [`FileHandle`], as given by its
+    /// [`WriterBackend::name()`] or
+    /// [`file_handle_builder_set_name()`][crate::file_handle_builder_set_name].
+    ///
+    /// The string is owned by the handle. Returns `null` if the handle has no
+    /// name.
+    pub unsafe extern "C" fn file_handle_name(
+        handle: *mut FileHandle,
+    ) -> *const c_char {
+        match &(*handle).cold.name {
+            Some(name) => name.as_ptr(),
+            None => ptr::null(),
+        }
+    }
+}
+
+export! {
+    /// Get a bitmask of what a [`FileHandle`] can do (e.g.
+    /// [`CAPABILITY_SIZE_HINTS`]).
+    pub unsafe extern "C" fn file_handle_capabilities(
+        handle: *mut FileHandle,
+    ) -> u32 {
+        (*handle).capabilities().bits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::{
+        ffi::CStr,
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl WriterBackend for Recorder {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            let mut calls = self.0.lock().unwrap();
+            calls.push(format!("write {}", buf.len()));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.0.lock().unwrap().push("flush".into());
+            Ok(())
+        }
+
+        fn name(&self) -> Option<&str> { Some("recorder") }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::SIZE_HINTS | Capabilities::DURABLE_FLUSH
+        }
+
+        fn hint_total_size(&mut self, bytes: u64) -> Result<(), Error> {
+            self.0.lock().unwrap().push(format!("hint {}", bytes));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backends_are_called_through_the_handle() {
+        let recorder = Recorder::default();
+        let calls = Arc::clone(&recorder.0);
+
+        unsafe {
+            let handle = FileHandle::for_backend(recorder);
+
+            assert_eq!(file_handle_hint_total_size(handle, 42), 0);
+            file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            assert_eq!(file_handle_flush(handle), 0);
+
+            let name = CStr::from_ptr(file_handle_name(handle));
+            assert_eq!(name.to_str().unwrap(), "recorder");
+            assert_eq!(
+                file_handle_capabilities(handle),
+                CAPABILITY_SIZE_HINTS | CAPABILITY_DURABLE_FLUSH
+            );
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(*calls.lock().unwrap(), ["hint 42", "write 4", "flush"]);
+    }
+
+    #[test]
+    fn plain_writers_have_no_name() {
+        unsafe {
+            let memory = new_memory_file_handle();
+            let null = new_null_file_handle();
+
+            assert!(file_handle_name(memory).is_null());
+            let capabilities = file_handle_capabilities(memory);
+            assert_eq!(capabilities, CAPABILITY_SIZE_HINTS);
+            assert_eq!(file_handle_capabilities(null), 0);
+
+            file_handle_destroy(memory);
+            file_handle_destroy(null);
+        }
+    }
+}
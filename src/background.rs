@@ -0,0 +1,417 @@
+//! A [`FileHandle`] which hands all I/O off to a dedicated background thread.
+
+use crate::{
+    array_queue::ArrayQueue, barrier::FlushToken, write_owned::Payload,
+    FileHandle, OwnedFileHandle,
+};
+use std::{
+    io::{Error, ErrorKind, Write},
+    os::raw::c_int,
+    ptr,
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, SyncSender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{JoinHandle, Thread},
+};
+
+enum Message {
+    Write(Payload),
+    /// A barrier. The background thread will flush the inner handle once
+    /// every write before it has been processed, then send back the result.
+    Flush(SyncSender<Result<(), Error>>),
+    /// Like [`Message::Flush`], except the inner handle only gets a barrier
+    /// instead of a full flush.
+    Barrier(SyncSender<Result<(), Error>>),
+}
+
+/// The state shared with the background thread.
+struct Shared {
+    queue: ArrayQueue<Message>,
+    /// Set when the [`BackgroundWriter`] is dropped, after which the
+    /// background thread exits as soon as the queue is empty.
+    closing: AtomicBool,
+    /// Set by the background thread when it exits.
+    stopped: AtomicBool,
+    /// How many writers are waiting for room in a full queue.
+    waiting: AtomicUsize,
+    /// Only used by writers waiting for room, so enqueuing never locks it.
+    room: Mutex<()>,
+    /// Signalled when the background thread takes a message off the queue
+    /// while someone is waiting.
+    room_made: Condvar,
+    error: Mutex<Option<Error>>,
+}
+
+impl Shared {
+    fn lock_room(&self) -> MutexGuard<'_, ()> {
+        self.room.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wake any writers waiting for room in the queue.
+    fn made_room(&self) {
+        // pairs with the fence in BackgroundWriter::wait_for_room()
+        atomic::fence(Ordering::SeqCst);
+
+        if self.waiting.load(Ordering::SeqCst) > 0 {
+            drop(self.lock_room());
+            self.room_made.notify_all();
+        }
+    }
+}
+
+/// A writer which turns every write into a cheap enqueue, with the actual
+/// I/O performed by a background thread.
+///
+/// Writes go through a bounded lock-free queue, so the only cost on the
+/// caller's thread is copying the data and waking the background thread.
+/// Writing will block when the background thread falls more than
+/// `queue_capacity` writes behind. Because writes complete asynchronously,
+/// any error they encounter is reported by the next call to
+/// [`Write::write()`] or [`Write::flush()`].
+///
+/// Dropping a [`BackgroundWriter`] waits for all pending writes to be
+/// processed, flushes the inner handle, then shuts the thread down.
+pub struct BackgroundWriter {
+    shared: Arc<Shared>,
+    /// The background thread, which parks while the queue is empty.
+    consumer: Thread,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    /// Start a background thread which will write to `inner`.
+    ///
+    /// A `queue_capacity` of `0` is treated as `1`.
+    ///
+    /// # Panics
+    ///
+    /// If the thread can't be started (see [`BackgroundWriter::try_new()`]).
+    pub fn new(inner: OwnedFileHandle, queue_capacity: usize) -> Self {
+        BackgroundWriter::try_new(inner, queue_capacity)
+            .expect("Unable to spawn the background thread")
+    }
+
+    /// Start a background thread which will write to `inner`, failing
+    /// (and dropping `inner`) if the thread can't be started.
+    pub fn try_new(
+        inner: OwnedFileHandle,
+        queue_capacity: usize,
+    ) -> Result<Self, Error> {
+        let inner = inner.into_raw();
+
+        unsafe {
+            BackgroundWriter::start(inner, queue_capacity).map_err(|e| {
+                drop(OwnedFileHandle::from_raw(inner));
+                e
+            })
+        }
+    }
+
+    /// Start the background thread, only taking ownership of `inner` if it
+    /// was started.
+    unsafe fn start(
+        inner: *mut FileHandle,
+        queue_capacity: usize,
+    ) -> Result<Self, Error> {
+        let shared = Arc::new(Shared {
+            queue: ArrayQueue::new(queue_capacity.max(1)),
+            closing: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            waiting: AtomicUsize::new(0),
+            room: Mutex::new(()),
+            room_made: Condvar::new(),
+            error: Mutex::new(None),
+        });
+
+        let thread_shared = Arc::clone(&shared);
+        // the handle is only passed by address, so a thread which can't be
+        // started doesn't destroy it
+        let address = inner as usize;
+        let thread = std::thread::Builder::new()
+            .name(String::from("background-file-handle"))
+            .spawn(move || {
+                let inner = OwnedFileHandle::from_raw(address as *mut _);
+                run(inner, &thread_shared)
+            })?;
+
+        Ok(BackgroundWriter {
+            shared,
+            consumer: thread.thread().clone(),
+            thread: Some(thread),
+        })
+    }
+
+    fn take_error(&self) -> Result<(), Error> {
+        take_error(&self.shared.error)
+    }
+
+    /// Queue up a barrier, returning a token which resolves once every write
+    /// before it has reached the inner handle.
+    pub(crate) fn flush_token(&self) -> FlushToken {
+        let (ack_sender, ack) = mpsc::sync_channel(1);
+        if let Err(e) = self.send(Message::Barrier(ack_sender)) {
+            return FlushToken::failed(e);
+        }
+
+        let shared = Arc::clone(&self.shared);
+        FlushToken::pending(move || {
+            let result = ack.recv().map_err(|_| stopped())?;
+            take_error(&shared.error)?;
+            result
+        })
+    }
+
+    fn send(&self, msg: Message) -> Result<(), Error> {
+        if self.shared.stopped.load(Ordering::Acquire) {
+            return Err(stopped());
+        }
+
+        let result = match self.shared.queue.push(msg) {
+            Ok(()) => Ok(()),
+            Err(msg) => self.wait_for_room(msg),
+        };
+        // a no-op unless the background thread is parked
+        self.consumer.unpark();
+
+        result
+    }
+
+    /// The slow path for [`BackgroundWriter::send()`], blocking until the
+    /// background thread has made room in the queue.
+    fn wait_for_room(&self, mut msg: Message) -> Result<(), Error> {
+        let shared = &*self.shared;
+        shared.waiting.fetch_add(1, Ordering::SeqCst);
+        // pairs with the fence in Shared::made_room()
+        atomic::fence(Ordering::SeqCst);
+        let mut room = shared.lock_room();
+
+        let result = loop {
+            if shared.stopped.load(Ordering::Acquire) {
+                break Err(stopped());
+            }
+
+            match shared.queue.push(msg) {
+                Ok(()) => break Ok(()),
+                Err(rejected) => msg = rejected,
+            }
+
+            self.consumer.unpark();
+            room = shared
+                .room_made
+                .wait(room)
+                .unwrap_or_else(|e| e.into_inner());
+        };
+
+        drop(room);
+        shared.waiting.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+impl Write for BackgroundWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.take_error()?;
+        self.send(Message::Write(Payload::take_or_copy(self, buf)?))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let (ack_sender, ack) = mpsc::sync_channel(1);
+        self.send(Message::Flush(ack_sender))?;
+
+        let flushed = ack.recv().map_err(|_| stopped())?;
+
+        self.take_error()?;
+        flushed
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        // the background thread drains the queue before exiting
+        self.shared.closing.store(true, Ordering::SeqCst);
+        self.consumer.unpark();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn take_error(error: &Mutex<Option<Error>>) -> Result<(), Error> {
+    match error.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn stopped() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "The background thread has stopped")
+}
+
+fn run(mut inner: OwnedFileHandle, shared: &Shared) {
+    /// Lets blocked writers know the thread has gone, even if it panicked.
+    struct Stopped<'a>(&'a Shared);
+
+    impl Drop for Stopped<'_> {
+        fn drop(&mut self) {
+            self.0.stopped.store(true, Ordering::Release);
+            drop(self.0.lock_room());
+            self.0.room_made.notify_all();
+        }
+    }
+
+    let _stopped = Stopped(shared);
+    let record_error = |e: Error| {
+        let mut error = shared.error.lock().unwrap_or_else(|e| e.into_inner());
+        // only the first error is kept, later ones are usually a consequence
+        if error.is_none() {
+            *error = Some(e);
+        }
+    };
+
+    loop {
+        // anything pushed before the writer was dropped is visible once we
+        // see it closing
+        let closing = shared.closing.load(Ordering::SeqCst);
+        let msg = match shared.queue.pop() {
+            Some(msg) => msg,
+            None if closing => break,
+            None => {
+                std::thread::park();
+                continue;
+            },
+        };
+        shared.made_room();
+
+        match msg {
+            Message::Write(data) => {
+                if let Err(e) = inner.write_all(&data) {
+                    record_error(e);
+                }
+            },
+            Message::Flush(ack) => {
+                let _ = ack.send(inner.flush());
+            },
+            Message::Barrier(ack) => {
+                let _ = ack.send(inner.barrier());
+            },
+        }
+    }
+
+    if let Err(e) = inner.flush() {
+        record_error(e);
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to `inner` from a background
+    /// thread, taking ownership of `inner`.
+    ///
+    /// Writes are copied into a queue which holds up to `queue_capacity`
+    /// pending writes. Flushing blocks until everything written so far has
+    /// reached `inner` and it has been flushed.
+    ///
+    /// Returns `null` if `inner` is `null`, `queue_capacity` isn't positive,
+    /// or the background thread couldn't be started, in which case ownership
+    /// of `inner` is not taken. A thread which couldn't be started is
+    /// recorded as `inner`'s last error.
+    pub unsafe extern "C" fn new_background_file_handle(
+        inner: *mut FileHandle,
+        queue_capacity: c_int,
+    ) -> *mut FileHandle {
+        if inner.is_null() || queue_capacity <= 0 {
+            return ptr::null_mut();
+        }
+
+        match BackgroundWriter::start(inner, queue_capacity as usize) {
+            Ok(writer) => FileHandle::for_writer(writer),
+            Err(e) => {
+                (*inner).cold.last_error.record(&e);
+                ptr::null_mut()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn flushing_waits_for_pending_writes() {
+        let buffer = SharedBuffer::default();
+        let inner = OwnedFileHandle::new(buffer.clone());
+        let mut writer = BackgroundWriter::new(inner, 4);
+
+        for _ in 0..100 {
+            writer.write_all(b"Hello, World!").unwrap();
+        }
+        writer.flush().unwrap();
+
+        assert_eq!(buffer.0.lock().unwrap().len(), 100 * 13);
+    }
+
+    #[test]
+    fn destroying_drains_the_queue() {
+        let buffer = SharedBuffer::default();
+        let msg = "Hello, World!";
+
+        unsafe {
+            let inner = FileHandle::for_writer(buffer.clone());
+            let handle = new_background_file_handle(inner, 16);
+            assert!(!handle.is_null());
+
+            for _ in 0..10 {
+                let ret = file_handle_write(
+                    handle,
+                    msg.as_ptr() as *const _,
+                    msg.len() as _,
+                );
+                assert_eq!(ret, msg.len() as c_int);
+            }
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().len(), 10 * msg.len());
+    }
+
+    #[test]
+    fn errors_are_reported_on_the_next_call() {
+        struct DodgyWriter;
+        impl Write for DodgyWriter {
+            fn write(&mut self, _data: &[u8]) -> Result<usize, Error> {
+                Err(Error::from_raw_os_error(42))
+            }
+
+            fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+        }
+
+        let mut writer =
+            BackgroundWriter::new(OwnedFileHandle::new(DodgyWriter), 4);
+
+        // the write itself is only enqueued
+        writer.write_all(b"asdf").unwrap();
+
+        let err = writer.flush().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(42));
+        // and the error is only reported once
+        writer.flush().unwrap();
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        unsafe {
+            assert!(new_background_file_handle(ptr::null_mut(), 4).is_null());
+
+            let inner = new_null_file_handle();
+            assert!(new_background_file_handle(inner, 0).is_null());
+            file_handle_destroy(inner);
+        }
+    }
+}
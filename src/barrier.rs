@@ -0,0 +1,179 @@
+//! Ordering points for handles which forward writes from a background
+//! thread.
+
+use crate::{BackgroundWriter, FileHandle, LossyWriter};
+use std::{io::Error, os::raw::c_int};
+
+/// A promise that everything written to a handle before the token was
+/// created will have been forwarded once [`FlushToken::wait()`] returns.
+pub(crate) struct FlushToken(Box<dyn FnOnce() -> Result<(), Error> + Send>);
+
+impl FlushToken {
+    /// A token for a handle which never holds writes back.
+    pub(crate) fn ready() -> Self { FlushToken(Box::new(|| Ok(()))) }
+
+    /// A token which resolves by calling `wait`.
+    pub(crate) fn pending<F>(wait: F) -> Self
+    where
+        F: FnOnce() -> Result<(), Error> + Send + 'static,
+    {
+        FlushToken(Box::new(wait))
+    }
+
+    /// A token for a handle which couldn't accept the barrier.
+    pub(crate) fn failed(e: Error) -> Self {
+        FlushToken(Box::new(move || Err(e)))
+    }
+
+    /// Block until the writes before this token have been forwarded.
+    pub(crate) fn wait(self) -> Result<(), Error> { (self.0)() }
+}
+
+/// Ask a handle for a [`FlushToken`] without waiting for it.
+pub(crate) unsafe fn submit(handle: *mut FileHandle) -> FlushToken {
+    if let Some(writer) = FileHandle::downcast_raw::<BackgroundWriter>(handle)
+    {
+        (*writer).flush_token()
+    } else if let Some(writer) = FileHandle::downcast_raw::<LossyWriter>(handle)
+    {
+        (*writer).flush_token()
+    } else {
+        FlushToken::ready()
+    }
+}
+
+/// Wait until every write made to these handles so far has been forwarded,
+/// returning one result per handle.
+///
+/// Tokens are submitted to every handle before waiting on any of them, so
+/// the handles drain in parallel.
+pub(crate) unsafe fn barrier(
+    handles: &[*mut FileHandle],
+) -> Vec<Result<(), Error>> {
+    let tokens: Vec<FlushToken> = handles.iter().map(|&h| submit(h)).collect();
+    tokens.into_iter().map(FlushToken::wait).collect()
+}
+
+c_unwind! {
+    /// Wait until every write made to these `count` handles so far has been
+    /// forwarded to whatever they wrap, establishing an ordering point (e.g.
+    /// before calling `fork()`).
+    ///
+    /// Handles which write from a background thread (see
+    /// [`new_background_file_handle()`] and [`new_lossy_file_handle()`]) are
+    /// waited on, including any background handles they wrap. Other handles
+    /// forward writes immediately, so nothing is flushed and they are
+    /// skipped. Returns `0` on success, otherwise the first error (which is
+    /// also recorded as each failed handle's last error).
+    ///
+    /// [`new_background_file_handle()`]: crate::new_background_file_handle
+    /// [`new_lossy_file_handle()`]: crate::new_lossy_file_handle
+    pub unsafe extern fn file_handle_barrier(
+        handles: *const *mut FileHandle,
+        count: usize,
+    ) -> c_int {
+        if handles.is_null() {
+            return 0;
+        }
+
+        let handles = std::slice::from_raw_parts(handles, count);
+        let mut ret = 0;
+
+        for (&handle, result) in handles.iter().zip(barrier(handles)) {
+            if let Err(e) = result {
+                (*handle).cold.last_error.record(&e);
+                if ret == 0 {
+                    ret = crate::forbid_panics::into_errno(e);
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ffi::{tests::SharedBuffer, *},
+        OwnedFileHandle,
+    };
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    /// A writer which is slow and keeps track of whether it was flushed.
+    #[derive(Clone, Default)]
+    struct Slow {
+        written: SharedBuffer,
+        flushed: Arc<Mutex<bool>>,
+    }
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            *self.flushed.lock().unwrap() = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn barriers_wait_for_nested_background_handles() {
+        let slow = Slow::default();
+        let msg = b"queued";
+
+        unsafe {
+            let inner = new_background_file_handle(
+                FileHandle::for_writer(slow.clone()),
+                4,
+            );
+            let outer = new_background_file_handle(inner, 4);
+            let lossy = new_lossy_file_handle(new_memory_file_handle(), 64);
+            let plain = new_memory_file_handle();
+
+            for _ in 0..10 {
+                file_handle_write(outer, msg.as_ptr().cast(), 6);
+            }
+            let handles = [outer, lossy, plain];
+            assert_eq!(file_handle_barrier(handles.as_ptr(), 3), 0);
+
+            // everything arrived, but nothing was flushed
+            assert_eq!(slow.written.0.lock().unwrap().len(), 60);
+            assert!(!*slow.flushed.lock().unwrap());
+
+            for handle in handles.iter() {
+                file_handle_destroy(*handle);
+            }
+        }
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+                Err(Error::from_raw_os_error(5))
+            }
+
+            fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+        }
+
+        let inner = OwnedFileHandle::new(Broken);
+        let mut background = BackgroundWriter::new(inner, 2);
+        background.write_all(b"lost").unwrap();
+
+        unsafe {
+            let handle = FileHandle::for_writer(background);
+            let ret = file_handle_barrier(&handle, 1);
+            assert_eq!(ret, -5);
+            assert_eq!(file_handle_last_error_os_error(handle), 5);
+            file_handle_destroy(handle);
+        }
+    }
+}
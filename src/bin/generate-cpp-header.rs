@@ -0,0 +1,18 @@
+//! Write `tto.hpp`, the C++ wrapper around the FFI, to the path given on the
+//! command line (or stdout).
+
+use std::{env, fs, io::Write, process};
+
+fn main() {
+    let header = thin_trait_objects::cpp_header();
+
+    let result = match env::args_os().nth(1) {
+        Some(path) => fs::write(&path, header),
+        None => std::io::stdout().write_all(header.as_bytes()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("Unable to write the header: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,251 @@
+//! Encoding binary data as text while it is written, so it can be sent to
+//! sinks which only accept text (e.g. a JSON log line).
+
+use crate::{FileHandle, OwnedFileHandle};
+use std::{
+    io::{Error, Write},
+    ptr,
+};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// A binary-to-text encoding understood by [`BinaryEncodingWriter`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BinaryEncoding {
+    /// Standard base64 (RFC 4648) with `=` padding.
+    Base64,
+    /// Lower-case hexadecimal, two digits per byte.
+    Hex,
+}
+
+impl BinaryEncoding {
+    /// How many input bytes are encoded together.
+    fn group_len(self) -> usize {
+        match self {
+            BinaryEncoding::Base64 => 3,
+            BinaryEncoding::Hex => 1,
+        }
+    }
+
+    /// Encode a group of up to [`BinaryEncoding::group_len()`] bytes, padding
+    /// it if it is short.
+    fn encode_group(self, group: &[u8], text: &mut Vec<u8>) {
+        match self {
+            BinaryEncoding::Base64 => {
+                let mut bytes = [0; 3];
+                bytes[..group.len()].copy_from_slice(group);
+                let bits = u32::from(bytes[0]) << 16
+                    | u32::from(bytes[1]) << 8
+                    | u32::from(bytes[2]);
+
+                for i in 0..4 {
+                    if i <= group.len() {
+                        let index = (bits >> (18 - 6 * i)) & 0x3F;
+                        text.push(BASE64_ALPHABET[index as usize]);
+                    } else {
+                        text.push(b'=');
+                    }
+                }
+            },
+            BinaryEncoding::Hex => {
+                for &b in group {
+                    text.push(HEX_DIGITS[usize::from(b >> 4)]);
+                    text.push(HEX_DIGITS[usize::from(b & 0xF)]);
+                }
+            },
+        }
+    }
+}
+
+/// A writer which encodes binary data as text before passing it to an inner
+/// handle.
+///
+/// Bytes which don't make up a whole group (e.g. the last one or two bytes
+/// of base64) are held back until more data arrives. Flushing doesn't write
+/// them, because padding in the middle of the output would corrupt it, so
+/// they are only padded and written by [`BinaryEncodingWriter::finish()`] or
+/// when the writer is dropped.
+///
+/// ```rust
+/// # use std::io::Write;
+/// # use thin_trait_objects::{
+/// #     BinaryEncoding, BinaryEncodingWriter, OwnedFileHandle,
+/// # };
+/// let inner = OwnedFileHandle::new(Vec::<u8>::new());
+/// let mut writer = BinaryEncodingWriter::new(inner, BinaryEncoding::Base64);
+///
+/// writer.write_all(b"Ma").unwrap();
+/// writer.write_all(b"n!").unwrap();
+///
+/// let inner = writer.finish().unwrap();
+/// assert_eq!(inner.downcast_ref::<Vec<u8>>().unwrap(), b"TWFuIQ==");
+/// ```
+pub struct BinaryEncodingWriter {
+    inner: Option<OwnedFileHandle>,
+    encoding: BinaryEncoding,
+    pending: Vec<u8>,
+}
+
+impl BinaryEncodingWriter {
+    /// Create a new [`BinaryEncodingWriter`].
+    pub fn new(inner: OwnedFileHandle, encoding: BinaryEncoding) -> Self {
+        BinaryEncodingWriter {
+            inner: Some(inner),
+            encoding,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Write anything held back (with padding) and get the inner handle
+    /// back.
+    pub fn finish(mut self) -> Result<OwnedFileHandle, Error> {
+        self.encode(&[], true)?;
+        Ok(self.inner.take().expect("The inner handle is only taken once"))
+    }
+
+    fn encode(&mut self, buf: &[u8], last: bool) -> Result<(), Error> {
+        self.pending.extend_from_slice(buf);
+
+        let group_len = self.encoding.group_len();
+        let complete = if last {
+            self.pending.len()
+        } else {
+            self.pending.len() - self.pending.len() % group_len
+        };
+        if complete == 0 {
+            return Ok(());
+        }
+
+        let mut text = Vec::with_capacity(complete * 2 + 4);
+        for group in self.pending[..complete].chunks(group_len) {
+            self.encoding.encode_group(group, &mut text);
+        }
+        self.pending.drain(..complete);
+
+        match self.inner.as_mut() {
+            Some(inner) => inner.write_all(&text),
+            None => Ok(()),
+        }
+    }
+
+    /// The handle being wrapped.
+    pub(crate) fn get_ref(&self) -> Option<&OwnedFileHandle> {
+        self.inner.as_ref()
+    }
+}
+
+impl Write for BinaryEncodingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.encode(buf, false)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BinaryEncodingWriter {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.encode(&[], true);
+        }
+    }
+}
+
+unsafe fn new_encoding_file_handle(
+    inner: *mut FileHandle,
+    encoding: BinaryEncoding,
+) -> *mut FileHandle {
+    if inner.is_null() {
+        return ptr::null_mut();
+    }
+
+    let inner = OwnedFileHandle::from_raw(inner);
+    FileHandle::for_writer(BinaryEncodingWriter::new(inner, encoding))
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes everything as base64 to
+    /// `inner`, taking ownership of `inner`.
+    ///
+    /// The final padding is written when the handle is destroyed, so the output
+    /// is only complete after that. Returns `null` if `inner` is `null`.
+    pub unsafe extern "C" fn new_base64_file_handle(
+        inner: *mut FileHandle,
+    ) -> *mut FileHandle {
+        new_encoding_file_handle(inner, BinaryEncoding::Base64)
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes everything as lower-case hex to
+    /// `inner`, taking ownership of `inner`.
+    ///
+    /// Returns `null` if `inner` is `null`.
+    pub unsafe extern "C" fn new_hex_file_handle(
+        inner: *mut FileHandle,
+    ) -> *mut FileHandle {
+        new_encoding_file_handle(inner, BinaryEncoding::Hex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    fn encode(encoding: BinaryEncoding, writes: &[&[u8]]) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let inner = OwnedFileHandle::new(buffer.clone());
+        let mut writer = BinaryEncodingWriter::new(inner, encoding);
+
+        for data in writes {
+            writer.write_all(data).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let written = buffer.0.lock().unwrap();
+        written.clone()
+    }
+
+    #[test]
+    fn base64_groups_span_writes_and_are_padded_at_the_end() {
+        let base64 = BinaryEncoding::Base64;
+
+        assert_eq!(encode(base64, &[]), b"");
+        assert_eq!(encode(base64, &[b"f"]), b"Zg==");
+        assert_eq!(encode(base64, &[b"f", b"o"]), b"Zm8=");
+        assert_eq!(encode(base64, &[b"fo", b"ob", b"ar"]), b"Zm9vYmFy");
+        assert_eq!(encode(base64, &[b"\xFF\xFE\xFD\xFC"]), b"//79/A==");
+    }
+
+    #[test]
+    fn encode_from_c() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            assert!(new_hex_file_handle(ptr::null_mut()).is_null());
+
+            let inner = FileHandle::for_writer(buffer.clone());
+            let hex = new_hex_file_handle(inner);
+            let base64 = new_base64_file_handle(hex);
+            file_handle_write(base64, b"\x00\x01".as_ptr().cast(), 2);
+            file_handle_flush(base64);
+
+            // nothing was written because the group isn't complete
+            assert!(buffer.0.lock().unwrap().is_empty());
+            file_handle_destroy(base64);
+        }
+
+        // "AAE=" in hex
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"4141453d");
+    }
+}
@@ -0,0 +1,304 @@
+//! An in-memory [`FileHandle`] which never grows past a fixed capacity.
+
+use crate::{budget::MemoryBuffer, FfiSlice, FileHandle};
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Write},
+    os::raw::c_int,
+    ptr,
+};
+
+/// Fail with an error once the buffer is full.
+pub const BOUNDED_MEMORY_REJECT: c_int = 0;
+/// Silently drop anything which doesn't fit.
+pub const BOUNDED_MEMORY_TRUNCATE: c_int = 1;
+/// Discard the oldest writes to make room for new ones.
+pub const BOUNDED_MEMORY_RING: c_int = 2;
+
+/// What a [`BoundedBuffer`] does when a write won't fit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Accept as much as will fit, failing once the buffer is full.
+    Reject,
+    /// Silently drop anything which doesn't fit.
+    Truncate,
+    /// Discard the oldest writes until the new one fits.
+    Ring,
+}
+
+/// An in-memory buffer which holds at most `capacity` bytes.
+///
+/// Each write is stored as a separate chunk, so the boundaries between writes
+/// are preserved. When using [`OverflowPolicy::Ring`], whole chunks are
+/// evicted (oldest first) to make room, and only a write which is bigger than
+/// the entire buffer gets cut down to its last `capacity` bytes.
+#[derive(Debug, Clone)]
+pub struct BoundedBuffer {
+    chunks: VecDeque<Vec<u8>>,
+    len: usize,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: u64,
+}
+
+impl BoundedBuffer {
+    /// Create an empty [`BoundedBuffer`].
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        BoundedBuffer {
+            chunks: VecDeque::new(),
+            len: 0,
+            capacity,
+            policy,
+            dropped: 0,
+        }
+    }
+
+    /// The total number of bytes currently stored.
+    pub fn len(&self) -> usize { self.len }
+
+    /// Is the buffer empty?
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// The maximum number of bytes this buffer can hold.
+    pub fn capacity(&self) -> usize { self.capacity }
+
+    /// The number of bytes which were dropped or evicted because the buffer
+    /// was full.
+    pub fn dropped(&self) -> u64 { self.dropped }
+
+    /// Iterate over the stored writes, oldest first.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.chunks.iter().map(|chunk| chunk.as_slice())
+    }
+
+    /// Copy the stored bytes into a single buffer.
+    pub fn contents(&self) -> Vec<u8> {
+        let mut contents = Vec::with_capacity(self.len);
+        for chunk in self.chunks() {
+            contents.extend_from_slice(chunk);
+        }
+        contents
+    }
+
+    /// Release any capacity which isn't being used (e.g. the space for
+    /// chunks evicted by [`OverflowPolicy::Ring`]).
+    pub fn shrink_to_fit(&mut self) { self.chunks.shrink_to_fit(); }
+
+    /// The number of bytes allocated but not being used.
+    pub(crate) fn spare_capacity(&self) -> usize {
+        let spare_chunks = self.chunks.capacity() - self.chunks.len();
+        spare_chunks * std::mem::size_of::<Vec<u8>>()
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        if !chunk.is_empty() {
+            self.len += chunk.len();
+            self.chunks.push_back(chunk.to_vec());
+        }
+    }
+}
+
+impl Write for BoundedBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let remaining = self.capacity - self.len;
+
+        match self.policy {
+            OverflowPolicy::Reject => {
+                if remaining == 0 && !buf.is_empty() {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "The bounded buffer is full",
+                    ));
+                }
+
+                let accepted = buf.len().min(remaining);
+                self.push(&buf[..accepted]);
+                Ok(accepted)
+            },
+            OverflowPolicy::Truncate => {
+                let accepted = buf.len().min(remaining);
+                self.push(&buf[..accepted]);
+                self.dropped += (buf.len() - accepted) as u64;
+                Ok(buf.len())
+            },
+            OverflowPolicy::Ring => {
+                let start = buf.len().saturating_sub(self.capacity);
+                let chunk = &buf[start..];
+                self.dropped += start as u64;
+
+                while self.len + chunk.len() > self.capacity {
+                    let evicted = self
+                        .chunks
+                        .pop_front()
+                        .expect("The buffer can't be empty");
+                    self.len -= evicted.len();
+                    self.dropped += evicted.len() as u64;
+                }
+
+                self.push(chunk);
+                Ok(buf.len())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+}
+
+impl MemoryBuffer for BoundedBuffer {
+    fn allocated(&self) -> usize {
+        self.len + self.chunks.capacity() * std::mem::size_of::<Vec<u8>>()
+    }
+
+    fn growth(&self, len: usize) -> usize {
+        // evicting old chunks makes room for new ones, so we never hold more
+        // than the capacity
+        len.min(self.capacity - self.len)
+    }
+}
+
+export! {
+    /// Create a new in-memory [`FileHandle`] which holds at most `capacity`
+    /// bytes.
+    ///
+    /// The `policy` decides what happens when a write won't fit and must be one
+    /// of [`BOUNDED_MEMORY_REJECT`], [`BOUNDED_MEMORY_TRUNCATE`], or
+    /// [`BOUNDED_MEMORY_RING`]. Returns `null` if the policy is invalid.
+    ///
+    /// Writes also fail with
+    /// [`FILE_HANDLE_OUT_OF_BUDGET`][crate::FILE_HANDLE_OUT_OF_BUDGET] if
+    /// storing them would use up the memory budget.
+    pub unsafe extern "C" fn new_bounded_memory_file_handle(
+        capacity: usize,
+        policy: c_int,
+    ) -> *mut FileHandle {
+        let policy = match policy {
+            BOUNDED_MEMORY_REJECT => OverflowPolicy::Reject,
+            BOUNDED_MEMORY_TRUNCATE => OverflowPolicy::Truncate,
+            BOUNDED_MEMORY_RING => OverflowPolicy::Ring,
+            _ => return ptr::null_mut(),
+        };
+
+        FileHandle::for_memory(BoundedBuffer::new(capacity, policy))
+    }
+}
+
+export! {
+    /// Get the number of chunks stored by a [`FileHandle`] created with
+    /// [`new_bounded_memory_file_handle()`], or `0` if it isn't a bounded
+    /// memory handle.
+    pub unsafe extern "C" fn bounded_memory_handle_chunk_count(
+        handle: *mut FileHandle,
+    ) -> usize {
+        match FileHandle::downcast_raw::<BoundedBuffer>(handle) {
+            Some(buffer) => (*buffer).chunks.len(),
+            None => 0,
+        }
+    }
+}
+
+export! {
+    /// Get a particular chunk (in the order they were written) from a bounded
+    /// memory handle.
+    ///
+    /// The returned buffer is only valid until the next time the handle is
+    /// written to or destroyed, and [`FfiSlice::NULL`] is returned if the index
+    /// is out of bounds or this isn't a bounded memory handle.
+    pub unsafe extern "C" fn bounded_memory_handle_chunk(
+        handle: *mut FileHandle,
+        index: usize,
+    ) -> FfiSlice {
+        FileHandle::downcast_raw::<BoundedBuffer>(handle)
+            .and_then(|buffer| (*buffer).chunks.get(index))
+            .map(|chunk| FfiSlice::new(chunk))
+            .unwrap_or(FfiSlice::NULL)
+    }
+}
+
+export! {
+    /// Get the total number of bytes stored by a bounded memory handle, or `0`
+    /// if it isn't a bounded memory handle.
+    pub unsafe extern "C" fn bounded_memory_handle_len(
+        handle: *mut FileHandle,
+    ) -> usize {
+        match FileHandle::downcast_raw::<BoundedBuffer>(handle) {
+            Some(buffer) => (*buffer).len(),
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+
+    #[test]
+    fn reject_writes_once_full() {
+        let mut buffer = BoundedBuffer::new(8, OverflowPolicy::Reject);
+
+        assert_eq!(buffer.write(b"Hello").unwrap(), 5);
+        assert_eq!(buffer.write(b", World!").unwrap(), 3);
+        assert!(buffer.write(b"!").is_err());
+
+        assert_eq!(buffer.contents(), b"Hello, W");
+    }
+
+    #[test]
+    fn truncate_drops_the_excess() {
+        let mut buffer = BoundedBuffer::new(8, OverflowPolicy::Truncate);
+
+        buffer.write_all(b"Hello, World!").unwrap();
+        buffer.write_all(b"asdf").unwrap();
+
+        assert_eq!(buffer.contents(), b"Hello, W");
+        assert_eq!(buffer.dropped(), 9);
+    }
+
+    #[test]
+    fn ring_buffers_evict_whole_chunks() {
+        let mut buffer = BoundedBuffer::new(8, OverflowPolicy::Ring);
+
+        buffer.write_all(b"abc").unwrap();
+        buffer.write_all(b"def").unwrap();
+        buffer.write_all(b"ghi").unwrap();
+
+        let chunks: Vec<&[u8]> = buffer.chunks().collect();
+        assert_eq!(chunks, vec![&b"def"[..], &b"ghi"[..]]);
+        assert_eq!(buffer.dropped(), 3);
+
+        // chunks bigger than the entire buffer keep their tail
+        buffer.write_all(b"0123456789").unwrap();
+        assert_eq!(buffer.contents(), b"23456789");
+        assert_eq!(buffer.chunks().count(), 1);
+    }
+
+    #[test]
+    fn inspect_chunks_from_c() {
+        unsafe {
+            let handle =
+                new_bounded_memory_file_handle(4, BOUNDED_MEMORY_RING);
+            assert!(!handle.is_null());
+
+            file_handle_write(handle, b"ab".as_ptr() as _, 2);
+            file_handle_write(handle, b"cd".as_ptr() as _, 2);
+            file_handle_write(handle, b"e".as_ptr() as _, 1);
+
+            assert_eq!(bounded_memory_handle_chunk_count(handle), 2);
+            assert_eq!(bounded_memory_handle_len(handle), 3);
+            let first = bounded_memory_handle_chunk(handle, 0);
+            assert_eq!(first.as_slice(), b"cd");
+            let second = bounded_memory_handle_chunk(handle, 1);
+            assert_eq!(second.as_slice(), b"e");
+            assert_eq!(bounded_memory_handle_chunk(handle, 2), FfiSlice::NULL);
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        unsafe {
+            assert!(new_bounded_memory_file_handle(4, 42).is_null());
+        }
+    }
+}
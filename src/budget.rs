@@ -0,0 +1,355 @@
+//! A hard cap on how much memory the crate's own buffers may use, for
+//! embedded devices and plugin hosts which can't let a library grow without
+//! bound.
+//!
+//! Everything which holds on to data for the caller (memory handles,
+//! background queues, the buffer pool's free buffers, and the ownership
+//! history) takes a [`Charge`] against the budget before growing. Once the
+//! budget is used up, writes which need more memory fail with
+//! [`FILE_HANDLE_OUT_OF_BUDGET`] instead.
+
+use crate::{
+    errors::{crate_status_code, crate_status_error, CRATE_ERROR_OUT_OF_BUDGET},
+    FileHandle,
+};
+use std::{
+    io::{Error, ErrorKind, Write},
+    os::raw::c_int,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Returned when a write would take the crate's buffers past the limit set
+/// with [`thin_trait_objects_set_memory_budget()`].
+///
+/// This is [`CRATE_ERROR_OUT_OF_BUDGET`] in the [`ErrorDomain::Crate`]
+/// domain, so it can't be confused with a real `ENOMEM`.
+///
+/// [`CRATE_ERROR_OUT_OF_BUDGET`]: crate::CRATE_ERROR_OUT_OF_BUDGET
+/// [`ErrorDomain::Crate`]: crate::ErrorDomain::Crate
+pub const FILE_HANDLE_OUT_OF_BUDGET: c_int =
+    crate_status_code(CRATE_ERROR_OUT_OF_BUDGET);
+
+/// Passed to [`thin_trait_objects_set_memory_budget()`] to remove the limit
+/// (the default).
+pub const MEMORY_BUDGET_UNLIMITED: usize = usize::MAX;
+
+/// A limit and the memory counted against it.
+#[derive(Debug)]
+pub(crate) struct Account {
+    limit: AtomicUsize,
+    used: AtomicUsize,
+}
+
+impl Account {
+    const fn new(limit: usize) -> Self {
+        Account {
+            limit: AtomicUsize::new(limit),
+            used: AtomicUsize::new(0),
+        }
+    }
+}
+
+static GLOBAL: Account = Account::new(MEMORY_BUDGET_UNLIMITED);
+
+#[cfg(test)]
+thread_local! {
+    /// Lets a test use its own budget without affecting tests running on
+    /// other threads.
+    static TEST_ACCOUNT: std::cell::Cell<Option<&'static Account>> =
+        std::cell::Cell::new(None);
+}
+
+/// The account new charges on this thread are taken from.
+fn account() -> &'static Account {
+    #[cfg(test)]
+    {
+        if let Some(account) = TEST_ACCOUNT.with(|a| a.get()) {
+            return account;
+        }
+    }
+
+    &GLOBAL
+}
+
+/// Limit the memory used by the crate's own buffers to `bytes`, or remove
+/// the limit with `None`.
+///
+/// Lowering the limit below what is already in use doesn't free anything,
+/// it just stops the buffers from growing until they have shrunk again.
+pub fn set_memory_budget(bytes: Option<usize>) {
+    let limit = bytes.unwrap_or(MEMORY_BUDGET_UNLIMITED);
+    account().limit.store(limit, Ordering::Relaxed);
+}
+
+/// The limit set with [`set_memory_budget()`], if any.
+pub fn memory_budget() -> Option<usize> {
+    match account().limit.load(Ordering::Relaxed) {
+        MEMORY_BUDGET_UNLIMITED => None,
+        limit => Some(limit),
+    }
+}
+
+/// The number of bytes the crate's buffers are currently charged for.
+pub fn memory_used() -> usize { account().used.load(Ordering::Relaxed) }
+
+/// The error used when the budget has been used up.
+pub(crate) fn out_of_budget() -> Error {
+    crate_status_error(ErrorKind::Other, CRATE_ERROR_OUT_OF_BUDGET)
+}
+
+/// Go back to having no limit.
+pub(crate) fn shutdown() { set_memory_budget(None); }
+
+/// Memory counted against the budget, which is given back when dropped.
+#[derive(Debug)]
+pub(crate) struct Charge {
+    account: &'static Account,
+    bytes: usize,
+}
+
+impl Charge {
+    /// A charge for nothing, which can be grown later.
+    pub(crate) fn empty() -> Charge {
+        Charge {
+            account: account(),
+            bytes: 0,
+        }
+    }
+
+    /// Charge for `bytes`, failing if that would exceed the budget.
+    pub(crate) fn new(bytes: usize) -> Result<Charge, Error> {
+        let mut charge = Charge::empty();
+        charge.grow(bytes)?;
+        Ok(charge)
+    }
+
+    pub(crate) fn bytes(&self) -> usize { self.bytes }
+
+    /// Charge for `bytes` more, failing if that would exceed the budget.
+    pub(crate) fn grow(&mut self, bytes: usize) -> Result<(), Error> {
+        if bytes == 0 {
+            return Ok(());
+        }
+
+        let Account { limit, used } = self.account;
+        let limit = limit.load(Ordering::Relaxed);
+        used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            used.checked_add(bytes).filter(|&total| total <= limit)
+        })
+        .map_err(|_| out_of_budget())?;
+
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Change the charge to match memory which has already been allocated
+    /// (or freed), whether or not it fits in the budget.
+    pub(crate) fn update(&mut self, bytes: usize) {
+        let used = &self.account.used;
+
+        if bytes > self.bytes {
+            used.fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else {
+            used.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+    }
+
+    /// Reduce the charge to `bytes` after some memory was freed.
+    pub(crate) fn shrink_to(&mut self, bytes: usize) {
+        if bytes < self.bytes {
+            self.update(bytes);
+        }
+    }
+}
+
+impl Default for Charge {
+    fn default() -> Self { Charge::empty() }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) { self.update(0); }
+}
+
+/// An in-memory buffer whose allocation is charged to the budget when it is
+/// the object in a memory handle (see [`FileHandle::for_memory()`]).
+pub(crate) trait MemoryBuffer: Write + Send + Sync + 'static {
+    /// The number of bytes currently allocated.
+    fn allocated(&self) -> usize;
+
+    /// Roughly how many more bytes will be allocated by writing `len`
+    /// bytes.
+    fn growth(&self, len: usize) -> usize;
+}
+
+impl MemoryBuffer for Vec<u8> {
+    fn allocated(&self) -> usize { self.capacity() }
+
+    fn growth(&self, len: usize) -> usize {
+        let required = self.len().saturating_add(len);
+
+        if required <= self.capacity() {
+            0
+        } else {
+            // a Vec at least doubles when it grows
+            required.max(self.capacity().saturating_mul(2)) - self.capacity()
+        }
+    }
+}
+
+/// Give back whatever a memory handle's buffer no longer needs (e.g. after
+/// it was shrunk or drained).
+pub(crate) unsafe fn release_unused(
+    handle: *mut FileHandle,
+    allocated: usize,
+) {
+    (*handle).cold.budget.shrink_to(allocated);
+}
+
+/// Bring a memory handle's charge up to date after its buffer was changed
+/// without going through a write (e.g. by
+/// [`OwnedFileHandle::replace_writer()`][replace]).
+///
+/// The memory has already been allocated, so this can take the budget past
+/// its limit until the buffer shrinks again.
+///
+/// [replace]: crate::OwnedFileHandle::replace_writer
+pub(crate) unsafe fn recharge(handle: *mut FileHandle) {
+    if let Some(allocated) = (*handle).cold.allocated {
+        let allocated = allocated(handle);
+        (*handle).cold.budget.update(allocated);
+    }
+}
+
+export! {
+    /// Limit the memory used by the crate's own buffers (memory handles,
+    /// background queues, the buffer pool, and the ownership history) to
+    /// `bytes`, or remove the limit with [`MEMORY_BUDGET_UNLIMITED`].
+    ///
+    /// Writes which would need more memory fail with
+    /// [`FILE_HANDLE_OUT_OF_BUDGET`] instead of growing the buffer.
+    pub unsafe extern "C" fn thin_trait_objects_set_memory_budget(
+        bytes: usize,
+    ) {
+        match bytes {
+            MEMORY_BUDGET_UNLIMITED => set_memory_budget(None),
+            bytes => set_memory_budget(Some(bytes)),
+        }
+    }
+}
+
+export! {
+    /// Get the number of bytes currently counted against the memory budget.
+    pub unsafe extern "C" fn thin_trait_objects_memory_used() -> usize {
+        memory_used()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    /// Give the current thread its own budget of `bytes` until the guard is
+    /// dropped, so tests don't see each other's memory.
+    pub(crate) fn with_budget(bytes: usize) -> impl Drop {
+        struct Reset;
+
+        impl Drop for Reset {
+            fn drop(&mut self) { TEST_ACCOUNT.with(|a| a.set(None)); }
+        }
+
+        let account = Box::leak(Box::new(Account::new(bytes)));
+        TEST_ACCOUNT.with(move |a| a.set(Some(account)));
+        Reset
+    }
+
+    #[test]
+    fn charges_are_given_back_when_dropped() {
+        let _budget = with_budget(100);
+
+        let mut first = Charge::new(60).unwrap();
+        let err = Charge::new(60).unwrap_err();
+        assert_eq!(
+            crate::errors::crate_status_of(&err),
+            Some(CRATE_ERROR_OUT_OF_BUDGET)
+        );
+        first.shrink_to(10);
+        let second = Charge::new(60).unwrap();
+        assert!(first.grow(40).is_err());
+
+        drop(second);
+        first.grow(40).unwrap();
+        assert_eq!(first.bytes(), 50);
+        assert_eq!(memory_used(), 50);
+        drop(first);
+        assert_eq!(memory_used(), 0);
+    }
+
+    #[test]
+    fn memory_handles_stop_growing_once_the_budget_is_used_up() {
+        let _budget = with_budget(64);
+        let data = [0_u8; 48];
+
+        unsafe {
+            let handle = new_memory_file_handle();
+
+            assert_eq!(file_handle_write(handle, data.as_ptr().cast(), 48), 48);
+            assert_eq!(memory_used(), 48);
+            // growing the buffer would take us past 64 bytes
+            let ret = file_handle_write(handle, data.as_ptr().cast(), 48);
+            assert_eq!(ret, FILE_HANDLE_OUT_OF_BUDGET);
+            assert_eq!(file_handle_as_memory(handle).as_slice(), &data[..]);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(memory_used(), 0);
+    }
+
+    #[test]
+    fn size_hints_and_replacements_are_charged() {
+        let _budget = with_budget(64);
+
+        unsafe {
+            let handle = new_memory_file_handle();
+
+            let ret = file_handle_hint_total_size(handle, 1024);
+            assert_eq!(ret, FILE_HANDLE_OUT_OF_BUDGET);
+            assert_eq!(memory_used(), 0);
+            assert_eq!(file_handle_hint_total_size(handle, 32), 0);
+            assert!(memory_used() >= 32);
+
+            let mut owned = crate::OwnedFileHandle::from_raw(handle);
+            owned.replace_writer(|_: Vec<u8>| vec![0; 100]).unwrap();
+            assert_eq!(memory_used(), 100);
+            owned.replace_writer(|_: Vec<u8>| Vec::new()).unwrap();
+            assert_eq!(memory_used(), 0);
+        }
+    }
+
+    #[test]
+    fn background_queues_reject_writes_over_budget() {
+        let buffer = SharedBuffer::default();
+        let _budget = with_budget(MEMORY_BUDGET_UNLIMITED);
+
+        unsafe {
+            let inner = FileHandle::for_writer(buffer.clone());
+            let handle = new_background_file_handle(inner, 4);
+
+            thin_trait_objects_set_memory_budget(0);
+            assert_eq!(memory_budget(), Some(0));
+            let ret = file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            assert_eq!(ret, FILE_HANDLE_OUT_OF_BUDGET);
+
+            thin_trait_objects_set_memory_budget(MEMORY_BUDGET_UNLIMITED);
+            let ret = file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            assert_eq!(ret, 4);
+            assert_eq!(file_handle_flush(handle), 0);
+            file_handle_destroy(handle);
+            assert_eq!(thin_trait_objects_memory_used(), 0);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"asdf");
+    }
+}
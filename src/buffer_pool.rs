@@ -0,0 +1,280 @@
+//! Reusing temporary buffers between calls, so wrapper handles don't need to
+//! hit the allocator for every write.
+
+use crate::budget::Charge;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// Free buffers, which are charged to the memory budget while they are kept.
+type FreeList = Vec<(Vec<u8>, Charge)>;
+
+thread_local! {
+    /// Each thread's free buffers, keyed by the pool they belong to.
+    static FREE_LISTS: RefCell<HashMap<usize, FreeList>> =
+        RefCell::new(HashMap::new());
+}
+
+/// A pool of reusable byte buffers.
+///
+/// Every thread has its own list of free buffers, so taking and returning a
+/// buffer never needs a lock. Wrapper handles use the [global
+/// pool][BufferPool::global], but hosts may create their own pools with
+/// different limits.
+///
+/// ```rust
+/// # use thin_trait_objects::BufferPool;
+/// let pool = BufferPool::new(4, 1024);
+///
+/// let mut buffer = pool.take();
+/// buffer.extend_from_slice(b"Hello, World!");
+/// drop(buffer);
+///
+/// // the second buffer reuses the first one's allocation
+/// let buffer = pool.take();
+/// assert!(buffer.is_empty());
+/// assert_eq!(pool.stats().hits, 1);
+/// ```
+#[derive(Debug)]
+pub struct BufferPool {
+    id: usize,
+    max_buffers: usize,
+    max_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    discarded: AtomicU64,
+}
+
+/// Statistics about how well a [`BufferPool`] is working.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct BufferPoolStats {
+    /// How many times a free buffer was reused.
+    pub hits: u64,
+    /// How many times a new buffer needed to be allocated.
+    pub misses: u64,
+    /// How many buffers were freed instead of being returned to the pool,
+    /// either because they were too big, the pool was full, or the memory
+    /// budget was used up.
+    pub discarded: u64,
+}
+
+impl BufferPoolStats {
+    /// The fraction of buffers which were reused, between `0.0` and `1.0`.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+impl BufferPool {
+    /// The number of free buffers each thread keeps for the global pool.
+    pub const DEFAULT_MAX_BUFFERS: usize = 8;
+    /// The largest buffer the global pool will hold on to.
+    pub const DEFAULT_MAX_CAPACITY: usize = 64 * 1024;
+
+    /// Create a new [`BufferPool`] where each thread keeps up to
+    /// `max_buffers` free buffers, and buffers which grew beyond
+    /// `max_capacity` bytes are freed instead of being reused.
+    pub fn new(max_buffers: usize, max_capacity: usize) -> Self {
+        // Note: 0 is reserved for the global pool
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+        BufferPool {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            max_buffers,
+            max_capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        }
+    }
+
+    /// The pool used by this crate's wrapper handles.
+    pub fn global() -> &'static BufferPool { &GLOBAL_POOL }
+
+    /// Get an empty buffer, reusing a free one if possible.
+    pub fn take(&self) -> PooledBuffer<'_> {
+        let reused = FREE_LISTS
+            .try_with(|lists| {
+                lists
+                    .borrow_mut()
+                    .get_mut(&self.id)
+                    .and_then(|free| free.pop())
+                    .map(|(buffer, _charge)| buffer)
+            })
+            .ok()
+            .flatten();
+
+        let buffer = match reused {
+            Some(buffer) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buffer
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            },
+        };
+
+        PooledBuffer { buffer, pool: self }
+    }
+
+    /// Get a snapshot of the pool's statistics.
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+        }
+    }
+
+    fn give_back(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+
+        let returned = buffer.capacity() <= self.max_capacity
+            && FREE_LISTS
+                .try_with(|lists| {
+                    let mut lists = lists.borrow_mut();
+                    let free = lists.entry(self.id).or_insert_with(Vec::new);
+
+                    if free.len() >= self.max_buffers {
+                        return false;
+                    }
+
+                    match Charge::new(buffer.capacity()) {
+                        Ok(charge) => {
+                            buffer.clear();
+                            free.push((std::mem::take(&mut buffer), charge));
+                            true
+                        },
+                        Err(_) => false,
+                    }
+                })
+                .unwrap_or(false);
+
+        if !returned {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        // Note: other threads will free their buffers when they exit
+        let _ =
+            FREE_LISTS.try_with(|lists| lists.borrow_mut().remove(&self.id));
+    }
+}
+
+/// The global pool only holds counters (the buffers themselves belong to
+/// each thread), so it doesn't need to be torn down.
+static GLOBAL_POOL: BufferPool = BufferPool {
+    id: 0,
+    max_buffers: BufferPool::DEFAULT_MAX_BUFFERS,
+    max_capacity: BufferPool::DEFAULT_MAX_CAPACITY,
+    hits: AtomicU64::new(0),
+    misses: AtomicU64::new(0),
+    discarded: AtomicU64::new(0),
+};
+
+/// A buffer borrowed from a [`BufferPool`], which is returned to the pool
+/// when dropped.
+#[derive(Debug)]
+pub struct PooledBuffer<'pool> {
+    buffer: Vec<u8>,
+    pool: &'pool BufferPool,
+}
+
+impl<'pool> PooledBuffer<'pool> {
+    /// Take the buffer out of the pool permanently.
+    pub fn into_inner(mut self) -> Vec<u8> { std::mem::take(&mut self.buffer) }
+}
+
+impl<'pool> Deref for PooledBuffer<'pool> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> { &self.buffer }
+}
+
+impl<'pool> DerefMut for PooledBuffer<'pool> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> { &mut self.buffer }
+}
+
+impl<'pool> Drop for PooledBuffer<'pool> {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.buffer));
+    }
+}
+
+export! {
+    /// Get statistics for the buffer pool used by this crate's wrapper handles.
+    pub unsafe extern "C" fn buffer_pool_stats() -> BufferPoolStats {
+        BufferPool::global().stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = BufferPool::new(2, 1024);
+
+        let mut first = pool.take();
+        first.extend_from_slice(b"Hello, World!");
+        let ptr = first.as_ptr();
+        drop(first);
+
+        let second = pool.take();
+        assert!(second.is_empty());
+        assert_eq!(second.as_ptr(), ptr);
+
+        let stats = pool.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn big_buffers_and_extra_buffers_are_discarded() {
+        let pool = BufferPool::new(1, 16);
+
+        let mut big = pool.take();
+        big.resize(1024, 0);
+        drop(big);
+
+        let (mut a, mut b) = (pool.take(), pool.take());
+        a.push(1);
+        b.push(2);
+        drop(a);
+        drop(b);
+
+        assert_eq!(pool.stats().discarded, 2);
+    }
+
+    #[test]
+    fn each_thread_has_its_own_buffers() {
+        let pool = std::sync::Arc::new(BufferPool::new(4, 1024));
+        pool.take().push(42);
+
+        let other = std::sync::Arc::clone(&pool);
+        std::thread::spawn(move || other.take().push(42))
+            .join()
+            .unwrap();
+
+        assert_eq!(pool.stats().hits, 0);
+        assert_eq!(pool.stats().misses, 2);
+    }
+}
@@ -0,0 +1,23 @@
+//! Runs the C host demo from `examples/c_host/`, which build.rs compiles and
+//! links in when the `c-host-demo` feature is enabled.
+
+use std::{ffi::CString, os::raw::c_char};
+
+extern "C" {
+    /// Exercise the FFI from C, returning the number of failed checks.
+    fn c_host_run_demo(scratch_dir: *const c_char) -> i32;
+}
+
+#[test]
+fn the_c_host_demo_passes() {
+    let _global = crate::lifecycle::lock_global_state();
+    let dir = std::env::temp_dir()
+        .join(format!("c-host-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let c_dir = CString::new(dir.to_str().unwrap()).unwrap();
+
+    let failures = unsafe { c_host_run_demo(c_dir.as_ptr()) };
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(failures, 0, "See stderr for the failed checks");
+}
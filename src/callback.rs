@@ -0,0 +1,443 @@
+//! FFI-safe closures, for passing callbacks across the boundary in either
+//! direction without going through a [`FileHandle`][crate::FileHandle].
+
+use crate::external::DestroyCallback;
+use std::{
+    alloc::Layout,
+    fmt::{self, Debug, Formatter},
+    os::raw::{c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for fn(&[u8]) -> super::c_int {}
+    impl Sealed for fn() {}
+}
+
+/// The signatures an [`FfiCallback`] can have.
+pub trait Signature: private::Sealed + 'static {
+    /// The function which calls the closure.
+    #[doc(hidden)]
+    type Invoke: Copy;
+}
+
+impl Signature for fn(&[u8]) -> c_int {
+    type Invoke = unsafe fn(*mut BytesCallback, &[u8]) -> c_int;
+}
+
+impl Signature for fn() {
+    type Invoke = unsafe fn(*mut VoidCallback);
+}
+
+/// A callback which is given some bytes and returns an `int`.
+pub type BytesCallback = FfiCallback<fn(&[u8]) -> c_int>;
+/// A callback which takes no arguments and returns nothing.
+pub type VoidCallback = FfiCallback<fn()>;
+
+/// A thin trait object for a closure with the signature `S` (see
+/// [`BytesCallback`] and [`VoidCallback`]).
+///
+/// Like a [`FileHandle`][crate::FileHandle], this is just the header at the
+/// start of a larger object and must always be kept behind a pointer. A
+/// callback may be sent to another thread, but calls must not overlap.
+#[repr(C)]
+pub struct FfiCallback<S: Signature> {
+    layout: Layout,
+    /// Set when the closure panicked, after which it is never called again.
+    poisoned: AtomicBool,
+    destroy: unsafe fn(*mut FfiCallback<S>),
+    invoke: S::Invoke,
+}
+
+#[repr(C)]
+struct Repr<S: Signature, F> {
+    // Safety: The FfiCallback must be the first field so we can cast between
+    // *mut Repr<S, F> and *mut FfiCallback<S>
+    base: FfiCallback<S>,
+    closure: F,
+}
+
+impl<S: Signature> FfiCallback<S> {
+    fn allocate<F>(closure: F, invoke: S::Invoke) -> *mut FfiCallback<S> {
+        let repr = Repr {
+            base: FfiCallback {
+                layout: Layout::new::<Repr<S, F>>(),
+                poisoned: AtomicBool::new(false),
+                destroy: destroy::<S, F>,
+                invoke,
+            },
+            closure,
+        };
+
+        Box::into_raw(Box::new(repr)).cast()
+    }
+
+    /// Has the closure panicked?
+    pub fn is_poisoned(&self) -> bool { self.poisoned.load(Ordering::Acquire) }
+
+    /// Destroy the closure and free the [`FfiCallback`].
+    pub(crate) unsafe fn dispatch_destroy(callback: *mut FfiCallback<S>) {
+        if callback.is_null() {
+            return;
+        }
+
+        let destroy = (*callback).destroy;
+        destroy(callback);
+    }
+
+    /// Run `thunk` with the closure, returning `on_panic` if it panics (or
+    /// panicked last time).
+    unsafe fn call<F, T>(
+        callback: *mut FfiCallback<S>,
+        on_panic: T,
+        thunk: impl FnOnce(&mut F) -> T,
+    ) -> T {
+        if (*callback).is_poisoned() {
+            return on_panic;
+        }
+
+        let repr = &mut *(callback as *mut Repr<S, F>);
+        let closure = &mut repr.closure;
+
+        match panic::catch_unwind(AssertUnwindSafe(|| thunk(closure))) {
+            Ok(value) => value,
+            Err(_) => {
+                repr.base.poisoned.store(true, Ordering::Release);
+                on_panic
+            },
+        }
+    }
+}
+
+unsafe fn destroy<S: Signature, F>(callback: *mut FfiCallback<S>) {
+    let repr = callback as *mut Repr<S, F>;
+
+    // Safety: a closure which panicked may be in an inconsistent state, so we
+    // skip its destructor and just free the memory
+    if (*callback).is_poisoned() {
+        std::alloc::dealloc(repr.cast(), (*callback).layout);
+    } else {
+        let _ = Box::from_raw(repr);
+    }
+}
+
+impl BytesCallback {
+    /// Create a new [`BytesCallback`] which calls `closure`.
+    ///
+    /// If the closure panics it is poisoned and every call returns `-1`.
+    pub fn for_fn<F>(closure: F) -> *mut BytesCallback
+    where
+        F: FnMut(&[u8]) -> c_int + Send + 'static,
+    {
+        FfiCallback::allocate(closure, invoke_bytes::<F> as _)
+    }
+
+    pub(crate) unsafe fn dispatch_invoke(
+        callback: *mut BytesCallback,
+        data: &[u8],
+    ) -> c_int {
+        let invoke = (*callback).invoke;
+        invoke(callback, data)
+    }
+}
+
+unsafe fn invoke_bytes<F>(callback: *mut BytesCallback, data: &[u8]) -> c_int
+where
+    F: FnMut(&[u8]) -> c_int,
+{
+    BytesCallback::call(callback, -1, |closure: &mut F| closure(data))
+}
+
+impl VoidCallback {
+    /// Create a new [`VoidCallback`] which calls `closure`.
+    ///
+    /// If the closure panics it is poisoned and never called again.
+    pub fn for_fn<F>(closure: F) -> *mut VoidCallback
+    where
+        F: FnMut() + Send + 'static,
+    {
+        FfiCallback::allocate(closure, invoke_void::<F> as _)
+    }
+
+    pub(crate) unsafe fn dispatch_invoke(callback: *mut VoidCallback) {
+        let invoke = (*callback).invoke;
+        invoke(callback)
+    }
+}
+
+unsafe fn invoke_void<F: FnMut()>(callback: *mut VoidCallback) {
+    VoidCallback::call(callback, (), |closure: &mut F| closure())
+}
+
+/// An owned [`FfiCallback`], which destroys it when dropped.
+///
+/// ```rust
+/// # use thin_trait_objects::{OwnedCallback, bytes_callback_invoke};
+/// let mut total = 0;
+/// let mut callback = OwnedCallback::<fn(&[u8]) -> _>::new(move |data| {
+///     total += data.len() as i32;
+///     total
+/// });
+///
+/// assert_eq!(callback.call(b"Hello, "), 7);
+/// // native code can call it too
+/// let data = b"World!".as_ptr();
+/// let ret = unsafe { bytes_callback_invoke(callback.as_ptr(), data, 6) };
+/// assert_eq!(ret, 13);
+/// ```
+pub struct OwnedCallback<S: Signature>(NonNull<FfiCallback<S>>);
+
+// Safety: every closure is Send and calls are serialized by &mut self
+unsafe impl<S: Signature> Send for OwnedCallback<S> {}
+
+impl<S: Signature> OwnedCallback<S> {
+    /// Take ownership of a `*mut FfiCallback`.
+    ///
+    /// # Safety
+    ///
+    /// The `callback` must be a non-null pointer to a valid [`FfiCallback`],
+    /// and the original pointer may no longer be used.
+    pub unsafe fn from_raw(callback: *mut FfiCallback<S>) -> Self {
+        debug_assert!(!callback.is_null());
+        OwnedCallback(NonNull::new_unchecked(callback))
+    }
+
+    /// Give up ownership of the callback, e.g. to pass it to native code.
+    pub fn into_raw(self) -> *mut FfiCallback<S> {
+        let ptr = self.0.as_ptr();
+        std::mem::forget(self);
+        ptr
+    }
+
+    /// Get the underlying pointer without giving up ownership.
+    pub fn as_ptr(&self) -> *mut FfiCallback<S> { self.0.as_ptr() }
+
+    /// Has the closure panicked?
+    pub fn is_poisoned(&self) -> bool {
+        unsafe { self.0.as_ref().is_poisoned() }
+    }
+}
+
+impl OwnedCallback<fn(&[u8]) -> c_int> {
+    /// Wrap a closure (see [`BytesCallback::for_fn()`]).
+    pub fn new<F>(closure: F) -> Self
+    where
+        F: FnMut(&[u8]) -> c_int + Send + 'static,
+    {
+        unsafe { OwnedCallback::from_raw(BytesCallback::for_fn(closure)) }
+    }
+
+    /// Call the closure.
+    pub fn call(&mut self, data: &[u8]) -> c_int {
+        unsafe { BytesCallback::dispatch_invoke(self.as_ptr(), data) }
+    }
+}
+
+impl OwnedCallback<fn()> {
+    /// Wrap a closure (see [`VoidCallback::for_fn()`]).
+    pub fn new<F>(closure: F) -> Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        unsafe { OwnedCallback::from_raw(VoidCallback::for_fn(closure)) }
+    }
+
+    /// Call the closure.
+    pub fn call(&mut self) {
+        unsafe { VoidCallback::dispatch_invoke(self.as_ptr()) }
+    }
+}
+
+impl<S: Signature> Drop for OwnedCallback<S> {
+    fn drop(&mut self) {
+        unsafe { FfiCallback::dispatch_destroy(self.as_ptr()) }
+    }
+}
+
+impl<S: Signature> Debug for OwnedCallback<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OwnedCallback")
+            .field("ptr", &self.0)
+            .field("poisoned", &self.is_poisoned())
+            .finish()
+    }
+}
+
+c_unwind! {
+    type BytesCallbackFn =
+        unsafe fn(*mut c_void, *const u8, usize) -> c_int;
+}
+c_unwind! { type VoidCallbackFn = unsafe fn(*mut c_void); }
+
+/// A callback implemented by native code, which owns its `user_data`.
+struct Foreign<F> {
+    invoke: F,
+    user_data: *mut c_void,
+    destroy: Option<DestroyCallback>,
+}
+
+// Safety: whoever created the callback promised it can be used from another
+// thread
+unsafe impl<F> Send for Foreign<F> {}
+
+impl<F> Drop for Foreign<F> {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            unsafe { destroy(self.user_data) };
+        }
+    }
+}
+
+export! {
+    /// Create a new [`BytesCallback`] which calls `invoke` with `user_data`
+    /// and the bytes it was given.
+    ///
+    /// The `destroy` function (if provided) is called with `user_data` when
+    /// the callback is destroyed. Both functions may be called from any
+    /// thread.
+    pub unsafe extern "C" fn new_bytes_callback(
+        invoke: BytesCallbackFn,
+        user_data: *mut c_void,
+        destroy: Option<DestroyCallback>,
+    ) -> *mut BytesCallback {
+        let foreign = Foreign {
+            invoke,
+            user_data,
+            destroy,
+        };
+
+        BytesCallback::for_fn(move |data: &[u8]| {
+            (foreign.invoke)(foreign.user_data, data.as_ptr(), data.len())
+        })
+    }
+}
+
+c_unwind! {
+    /// Call a [`BytesCallback`] with `len` bytes starting at `data`,
+    /// returning whatever it returns (or `-1` if it panicked).
+    pub unsafe extern fn bytes_callback_invoke(
+        callback: *mut BytesCallback,
+        data: *const u8,
+        len: usize,
+    ) -> c_int {
+        let data = if data.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(data, len)
+        };
+
+        BytesCallback::dispatch_invoke(callback, data)
+    }
+}
+
+c_unwind! {
+    /// Destroy a [`BytesCallback`]. Passing `null` does nothing.
+    pub unsafe extern fn bytes_callback_destroy(callback: *mut BytesCallback) {
+        FfiCallback::dispatch_destroy(callback);
+    }
+}
+
+export! {
+    /// Create a new [`VoidCallback`] which calls `invoke` with `user_data`,
+    /// with the same rules as [`new_bytes_callback()`].
+    pub unsafe extern "C" fn new_void_callback(
+        invoke: VoidCallbackFn,
+        user_data: *mut c_void,
+        destroy: Option<DestroyCallback>,
+    ) -> *mut VoidCallback {
+        let foreign = Foreign {
+            invoke,
+            user_data,
+            destroy,
+        };
+
+        VoidCallback::for_fn(move || (foreign.invoke)(foreign.user_data))
+    }
+}
+
+c_unwind! {
+    /// Call a [`VoidCallback`].
+    pub unsafe extern fn void_callback_invoke(callback: *mut VoidCallback) {
+        VoidCallback::dispatch_invoke(callback);
+    }
+}
+
+c_unwind! {
+    /// Destroy a [`VoidCallback`]. Passing `null` does nothing.
+    pub unsafe extern fn void_callback_destroy(callback: *mut VoidCallback) {
+        FfiCallback::dispatch_destroy(callback);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    c_unwind! {
+        unsafe fn count_bytes(
+            user_data: *mut c_void,
+            _data: *const u8,
+            len: usize,
+        ) -> c_int {
+            let total = &*(user_data as *const AtomicUsize);
+            (total.fetch_add(len, Ordering::SeqCst) + len) as c_int
+        }
+    }
+
+    c_unwind! {
+        unsafe fn release(user_data: *mut c_void) {
+            drop(Arc::from_raw(user_data as *const AtomicUsize));
+        }
+    }
+
+    #[test]
+    fn rust_can_call_native_callbacks() {
+        let total = Arc::new(AtomicUsize::new(0));
+        let user_data = Arc::into_raw(Arc::clone(&total)) as *mut c_void;
+
+        let mut callback = unsafe {
+            OwnedCallback::from_raw(new_bytes_callback(
+                count_bytes,
+                user_data,
+                Some(release),
+            ))
+        };
+        assert_eq!(callback.call(b"asdf"), 4);
+        assert_eq!(callback.call(b"qwerty"), 10);
+
+        drop(callback);
+        assert_eq!(Arc::strong_count(&total), 1);
+    }
+
+    #[test]
+    fn panicking_closures_are_poisoned() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut callback = OwnedCallback::<fn()>::new({
+            let calls = Arc::clone(&calls);
+            move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                panic!("Oops");
+            }
+        });
+
+        callback.call();
+        assert!(callback.is_poisoned());
+
+        unsafe {
+            let raw = callback.into_raw();
+            void_callback_invoke(raw);
+            void_callback_destroy(raw);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // the poisoned closure was leaked instead of dropped
+        assert_eq!(Arc::strong_count(&calls), 2);
+    }
+}
@@ -0,0 +1,257 @@
+//! An in-memory buffer which remembers where each write started, so framed
+//! data can be consumed one write at a time.
+
+use crate::{budget::MemoryBuffer, FfiSlice, FileHandle};
+use std::io::{Error, Write};
+
+/// A growable buffer which keeps every write as a separate chunk.
+///
+/// ```rust
+/// # use std::io::Write;
+/// # use thin_trait_objects::ChunkedBuffer;
+/// let mut buffer = ChunkedBuffer::new();
+/// buffer.write_all(b"Hello, ").unwrap();
+/// buffer.write_all(b"World!").unwrap();
+///
+/// assert_eq!(buffer.as_bytes(), b"Hello, World!");
+/// let chunks: Vec<Vec<u8>> = buffer.into_iter().collect();
+/// assert_eq!(chunks, [b"Hello, ".to_vec(), b"World!".to_vec()]);
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChunkedBuffer {
+    data: Vec<u8>,
+    /// Where each chunk ends in `data`.
+    ends: Vec<usize>,
+}
+
+impl ChunkedBuffer {
+    /// Create an empty [`ChunkedBuffer`].
+    pub fn new() -> Self { ChunkedBuffer::default() }
+
+    /// Everything written so far, with the chunks joined together.
+    pub fn as_bytes(&self) -> &[u8] { &self.data }
+
+    /// The number of chunks written so far.
+    pub fn len(&self) -> usize { self.ends.len() }
+
+    /// Has nothing been written yet?
+    pub fn is_empty(&self) -> bool { self.ends.is_empty() }
+
+    /// Get the data passed to the `index`'th write.
+    pub fn chunk(&self, index: usize) -> Option<&[u8]> {
+        let end = *self.ends.get(index)?;
+        let start = match index {
+            0 => 0,
+            _ => self.ends[index - 1],
+        };
+
+        Some(&self.data[start..end])
+    }
+
+    /// Iterate over the chunks without consuming them.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        (0..self.len()).filter_map(move |i| self.chunk(i))
+    }
+
+    /// Release any capacity which isn't being used.
+    pub fn shrink_to_fit(&mut self) {
+        self.data.shrink_to_fit();
+        self.ends.shrink_to_fit();
+    }
+
+    /// The number of bytes allocated but not being used.
+    pub(crate) fn spare_capacity(&self) -> usize {
+        let spare_ends = self.ends.capacity() - self.ends.len();
+        self.data.capacity() - self.data.len()
+            + spare_ends * std::mem::size_of::<usize>()
+    }
+}
+
+impl Write for ChunkedBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.data.extend_from_slice(buf);
+        self.ends.push(self.data.len());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+}
+
+impl MemoryBuffer for ChunkedBuffer {
+    fn allocated(&self) -> usize {
+        self.data.capacity()
+            + self.ends.capacity() * std::mem::size_of::<usize>()
+    }
+
+    fn growth(&self, len: usize) -> usize {
+        let ends = if self.ends.len() == self.ends.capacity() {
+            self.ends.capacity().max(4) * std::mem::size_of::<usize>()
+        } else {
+            0
+        };
+
+        self.data.growth(len) + ends
+    }
+}
+
+impl IntoIterator for ChunkedBuffer {
+    type Item = Vec<u8>;
+    type IntoIter = IntoChunks;
+
+    fn into_iter(self) -> IntoChunks {
+        IntoChunks {
+            buffer: self,
+            next: 0,
+        }
+    }
+}
+
+/// An iterator over the chunks in a [`ChunkedBuffer`], created by
+/// [`ChunkedBuffer::into_iter()`] or
+/// [`OwnedFileHandle::drain_chunks()`][crate::OwnedFileHandle::drain_chunks].
+#[derive(Debug, Clone)]
+pub struct IntoChunks {
+    buffer: ChunkedBuffer,
+    next: usize,
+}
+
+impl Iterator for IntoChunks {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let chunk = self.buffer.chunk(self.next)?.to_vec();
+        self.next += 1;
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.buffer.len() - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for IntoChunks {}
+
+/// Take everything written to a memory handle, as chunks.
+///
+/// Plain memory handles don't remember write boundaries, so all their data
+/// comes back as a single chunk.
+pub(crate) unsafe fn drain(handle: *mut FileHandle) -> IntoChunks {
+    let mut drained = ChunkedBuffer::new();
+
+    if let Some(buffer) = FileHandle::downcast_raw::<ChunkedBuffer>(handle) {
+        drained = std::mem::take(&mut *buffer);
+        crate::budget::release_unused(handle, (*buffer).allocated());
+    } else if let Some(buffer) = FileHandle::downcast_raw::<Vec<u8>>(handle) {
+        if !(*buffer).is_empty() {
+            drained.data = std::mem::take(&mut *buffer);
+            drained.ends.push(drained.data.len());
+            crate::budget::release_unused(handle, (*buffer).allocated());
+        }
+    }
+
+    drained.into_iter()
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to memory, keeping each write
+    /// as a separate chunk.
+    ///
+    /// The chunks can be read back with [`memory_handle_next_chunk()`], or all
+    /// at once with [`file_handle_as_memory()`][crate::file_handle_as_memory].
+    /// Writes fail with
+    /// [`FILE_HANDLE_OUT_OF_BUDGET`][crate::FILE_HANDLE_OUT_OF_BUDGET] once
+    /// the memory budget is used up.
+    pub unsafe extern "C" fn new_chunked_memory_file_handle() -> *mut FileHandle
+    {
+        FileHandle::for_memory(ChunkedBuffer::new())
+    }
+}
+
+export! {
+    /// Get the next chunk written to a memory handle.
+    ///
+    /// `cursor` must be set to `0` before the first call and is advanced each
+    /// time a chunk is returned. The chunk is stored in `out` and is only valid
+    /// until the handle is next written to or destroyed. Handles created with
+    /// [`new_memory_file_handle()`][crate::new_memory_file_handle] return all
+    /// their data as a single chunk.
+    ///
+    /// Returns `false` once there are no more chunks, or if the handle isn't a
+    /// memory handle.
+    pub unsafe extern "C" fn memory_handle_next_chunk(
+        handle: *mut FileHandle,
+        cursor: *mut usize,
+        out: *mut FfiSlice,
+    ) -> bool {
+        let chunk = if let Some(buffer) =
+            FileHandle::downcast_raw::<ChunkedBuffer>(handle)
+        {
+            (*buffer).chunk(*cursor)
+        } else if let Some(buffer) = FileHandle::downcast_raw::<Vec<u8>>(handle)
+        {
+            Some((*buffer).as_slice()).filter(|b| *cursor == 0 && !b.is_empty())
+        } else {
+            None
+        };
+
+        match chunk {
+            Some(chunk) => {
+                out.write(FfiSlice::new(chunk));
+                *cursor += 1;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, OwnedFileHandle};
+
+    #[test]
+    fn iterate_over_chunks_from_c() {
+        let mut cursor = 0;
+        let mut chunk = FfiSlice::NULL;
+        let mut chunks = Vec::new();
+
+        unsafe {
+            let handle = new_chunked_memory_file_handle();
+            file_handle_write(handle, b"first".as_ptr().cast(), 5);
+            file_handle_write(handle, b"second".as_ptr().cast(), 6);
+
+            while memory_handle_next_chunk(handle, &mut cursor, &mut chunk) {
+                chunks.push(chunk.as_slice().to_vec());
+            }
+            let everything = file_handle_as_memory(handle).as_slice();
+            assert_eq!(everything, b"firstsecond");
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(cursor, 2);
+        assert_eq!(chunks, [b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn drain_chunks_from_memory_handles() {
+        let mut chunked = OwnedFileHandle::new(ChunkedBuffer::new());
+        chunked.write_all(b"a").unwrap();
+        chunked.write_all(b"bc").unwrap();
+
+        let chunks: Vec<_> = chunked.drain_chunks().collect();
+        assert_eq!(chunks, [b"a".to_vec(), b"bc".to_vec()]);
+        assert_eq!(chunked.drain_chunks().len(), 0);
+
+        let mut flat = OwnedFileHandle::new(Vec::new());
+        flat.write_all(b"a").unwrap();
+        flat.write_all(b"bc").unwrap();
+        let chunks: Vec<_> = flat.drain_chunks().collect();
+        assert_eq!(chunks, [b"abc".to_vec()]);
+
+        let mut other = OwnedFileHandle::new(std::io::sink());
+        assert_eq!(other.drain_chunks().next(), None);
+    }
+}
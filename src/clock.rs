@@ -0,0 +1,171 @@
+//! A mockable source of time, so handles which care about time (timeouts,
+//! throttling, timestamps, etc.) can be tested deterministically.
+
+use crate::global::Global;
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Something which can tell the time.
+///
+/// Types which depend on time should accept an `Arc<dyn Clock>` (falling
+/// back to [`global_clock()`] when none is given) instead of calling
+/// [`Instant::now()`] directly.
+pub trait Clock: Debug + Send + Sync {
+    /// The current time, as measured by a monotonic clock.
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time, for things like timestamps.
+    fn wall_time(&self) -> SystemTime;
+}
+
+/// A [`Clock`] which uses the operating system's clocks.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant { Instant::now() }
+
+    fn wall_time(&self) -> SystemTime { SystemTime::now() }
+}
+
+/// A [`Clock`] which only moves when you tell it to.
+///
+/// ```rust
+/// # use std::time::{Duration, SystemTime};
+/// # use thin_trait_objects::{Clock, ManualClock};
+/// let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+/// let start = clock.now();
+///
+/// clock.advance(Duration::from_secs(5));
+///
+/// assert_eq!(clock.now() - start, Duration::from_secs(5));
+/// assert_eq!(
+///     clock.wall_time(),
+///     SystemTime::UNIX_EPOCH + Duration::from_secs(5),
+/// );
+/// ```
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    wall_start: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Create a new [`ManualClock`] which reports `wall_time` as the current
+    /// wall-clock time.
+    pub fn new(wall_time: SystemTime) -> Self {
+        ManualClock {
+            start: Instant::now(),
+            wall_start: wall_time,
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Move the clock forwards.
+    pub fn advance(&self, duration: Duration) {
+        let mut elapsed =
+            self.elapsed.lock().unwrap_or_else(|e| e.into_inner());
+        *elapsed += duration;
+    }
+
+    /// How far the clock has moved since it was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self { ManualClock::new(SystemTime::UNIX_EPOCH) }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant { self.start + self.elapsed() }
+
+    fn wall_time(&self) -> SystemTime { self.wall_start + self.elapsed() }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant { (**self).now() }
+
+    fn wall_time(&self) -> SystemTime { (**self).wall_time() }
+}
+
+/// The clock used by anything which wasn't given its own.
+static GLOBAL_CLOCK: Global<Mutex<Option<Arc<dyn Clock>>>> = Global::new();
+
+fn global_clock_slot() -> &'static Mutex<Option<Arc<dyn Clock>>> {
+    GLOBAL_CLOCK.get_or_init(|| Mutex::new(None))
+}
+
+/// Free the global clock's storage.
+pub(crate) unsafe fn shutdown() { GLOBAL_CLOCK.reset(); }
+
+/// Replace the [`Clock`] used by anything which wasn't given its own.
+///
+/// This is mainly intended for tests. Anything which already looked up the
+/// global clock will keep using the old one.
+pub fn set_global_clock(clock: Arc<dyn Clock>) {
+    let mut slot =
+        global_clock_slot().lock().unwrap_or_else(|e| e.into_inner());
+    *slot = Some(clock);
+}
+
+/// The clock set with [`set_global_clock()`], if there is one.
+pub(crate) fn configured_clock() -> Option<Arc<dyn Clock>> {
+    global_clock_slot()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Replace (or clear) the global clock.
+pub(crate) fn replace_global_clock(clock: Option<Arc<dyn Clock>>) {
+    *global_clock_slot().lock().unwrap_or_else(|e| e.into_inner()) = clock;
+}
+
+/// Get the [`Clock`] used by anything which wasn't given its own, defaulting
+/// to the [`SystemClock`].
+pub fn global_clock() -> Arc<dyn Clock> {
+    let slot = global_clock_slot().lock().unwrap_or_else(|e| e.into_inner());
+
+    match &*slot {
+        Some(clock) => Arc::clone(clock),
+        None => Arc::new(SystemClock),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clocks_only_move_when_advanced() {
+        let clock = ManualClock::default();
+        let start = clock.now();
+
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_millis(1500));
+
+        assert_eq!(clock.now() - start, Duration::from_millis(1500));
+        assert_eq!(clock.elapsed(), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn swap_out_the_global_clock() {
+        let _global = crate::lifecycle::lock_global_state();
+        let manual = Arc::new(ManualClock::default());
+
+        set_global_clock(Arc::clone(&manual) as Arc<dyn Clock>);
+        manual.advance(Duration::from_secs(60));
+        let wall_time = global_clock().wall_time();
+        set_global_clock(Arc::new(SystemClock));
+
+        let expected = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        assert_eq!(wall_time, expected);
+        assert_ne!(global_clock().wall_time(), wall_time);
+    }
+}
@@ -0,0 +1,350 @@
+//! All of the crate's global settings in one place.
+
+use crate::{
+    clock, history, lifecycle::spin_lock, log_bridge, metrics, zero_write,
+    Clock, LogSink, MetricsSink, ZeroWritePolicy,
+};
+use std::{
+    mem,
+    os::raw::c_int,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+/// Returned by [`thin_trait_objects_configure()`] and
+/// [`thin_trait_objects_current_config()`] when the [`FfiConfig`] is invalid.
+pub const CONFIG_INVALID: c_int = -1;
+
+/// Makes sure configuration changes never overlap.
+static CONFIGURING: AtomicBool = AtomicBool::new(false);
+
+/// The size of the original [`FfiConfig`], before `ownership_history` was
+/// added.
+const FFI_CONFIG_V1_SIZE: usize =
+    mem::size_of::<usize>() + 2 * mem::size_of::<c_int>();
+
+/// The crate's global settings, replacing everything which used to be set
+/// one global at a time (e.g. with [`set_global_clock()`]).
+///
+/// Applying a [`Config`] replaces *every* setting, and anything left unset
+/// goes back to its default, so applying the same config twice has the same
+/// effect as applying it once.
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use thin_trait_objects::{current_config, Config, ZeroWritePolicy};
+/// current_config()
+///     .with_zero_write_policy(ZeroWritePolicy::Error)
+///     .apply();
+///
+/// assert_eq!(current_config().zero_write_policy(), ZeroWritePolicy::Error);
+/// # Config::default().apply();
+/// ```
+///
+/// [`set_global_clock()`]: crate::set_global_clock
+#[derive(Clone, Default)]
+pub struct Config {
+    clock: Option<Arc<dyn Clock>>,
+    log_sink: Option<Arc<dyn LogSink>>,
+    metrics_sink: Option<Arc<dyn MetricsSink>>,
+    metrics_sample_every: u32,
+    zero_write_policy: ZeroWritePolicy,
+    ownership_history: bool,
+}
+
+impl Config {
+    /// A [`Config`] where everything has its default value.
+    pub fn new() -> Self { Config::default() }
+
+    /// Set the [`Clock`] used by anything which wasn't given its own
+    /// (defaults to the [`SystemClock`][crate::SystemClock]).
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Set where log records go when a [`LogWriter`][crate::LogWriter]
+    /// wasn't given its own sink (by default they're dropped).
+    pub fn with_log_sink(mut self, sink: Arc<dyn LogSink>) -> Self {
+        self.log_sink = Some(sink);
+        self
+    }
+
+    /// Send metrics from handles with stats enabled to `sink`, reporting one
+    /// in every `sample_every` measurements (see
+    /// [`set_metrics_sink()`][crate::set_metrics_sink]).
+    pub fn with_metrics_sink(
+        mut self,
+        sink: Arc<dyn MetricsSink>,
+        sample_every: u32,
+    ) -> Self {
+        self.metrics_sink = Some(sink);
+        self.metrics_sample_every = sample_every;
+        self
+    }
+
+    /// Set the [`ZeroWritePolicy`] newly created handles start with.
+    pub fn with_zero_write_policy(mut self, policy: ZeroWritePolicy) -> Self {
+        self.zero_write_policy = policy;
+        self
+    }
+
+    /// Record every change in each handle's ownership so it can be retrieved
+    /// with [`OwnedFileHandle::history()`][crate::OwnedFileHandle::history]
+    /// or [`file_handle_history()`][crate::file_handle_history] (off by
+    /// default).
+    pub fn with_ownership_history(mut self, enabled: bool) -> Self {
+        self.ownership_history = enabled;
+        self
+    }
+
+    /// The clock, if one was set.
+    pub fn clock(&self) -> Option<&Arc<dyn Clock>> { self.clock.as_ref() }
+
+    /// The log sink, if one was set.
+    pub fn log_sink(&self) -> Option<&Arc<dyn LogSink>> {
+        self.log_sink.as_ref()
+    }
+
+    /// The metrics sink, if one was set.
+    pub fn metrics_sink(&self) -> Option<&Arc<dyn MetricsSink>> {
+        self.metrics_sink.as_ref()
+    }
+
+    /// How many measurements are taken for each one sent to the metrics
+    /// sink.
+    pub fn metrics_sample_every(&self) -> u32 {
+        self.metrics_sample_every.max(1)
+    }
+
+    /// The [`ZeroWritePolicy`] new handles start with.
+    pub fn zero_write_policy(&self) -> ZeroWritePolicy {
+        self.zero_write_policy
+    }
+
+    /// Is ownership history being recorded?
+    pub fn ownership_history(&self) -> bool { self.ownership_history }
+
+    /// Make this the crate's configuration.
+    ///
+    /// This may be called from any thread. Handles which already exist keep
+    /// their current [`ZeroWritePolicy`].
+    pub fn apply(&self) {
+        let _guard = spin_lock(&CONFIGURING);
+
+        clock::replace_global_clock(self.clock.clone());
+        log_bridge::replace_log_sink(self.log_sink.clone());
+        metrics::replace_metrics_sink(
+            self.metrics_sink.clone(),
+            self.metrics_sample_every(),
+        );
+        zero_write::set_default_policy(self.zero_write_policy);
+        history::set_enabled(self.ownership_history);
+    }
+}
+
+/// Get the crate's current configuration.
+pub fn current_config() -> Config {
+    let _guard = spin_lock(&CONFIGURING);
+    let (metrics_sink, metrics_sample_every) =
+        match metrics::configured_metrics_sink() {
+            Some((sink, every)) => (Some(sink), every),
+            None => (None, 1),
+        };
+
+    Config {
+        clock: clock::configured_clock(),
+        log_sink: log_bridge::configured_log_sink(),
+        metrics_sink,
+        metrics_sample_every,
+        zero_write_policy: zero_write::default_policy(),
+        ownership_history: history::is_enabled(),
+    }
+}
+
+/// The parts of [`Config`] which can be set from C.
+///
+/// New fields may be appended in later versions, so callers must set `size`
+/// to `sizeof(FfiConfig)`. Fields past the end of an older, smaller struct
+/// are left alone.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct FfiConfig {
+    /// The size of this struct, in bytes.
+    pub size: usize,
+    /// The zero-write policy new handles start with (see
+    /// [`file_handle_set_zero_write_policy()`]).
+    ///
+    /// [`file_handle_set_zero_write_policy()`]:
+    /// crate::file_handle_set_zero_write_policy
+    pub zero_write_policy: c_int,
+    /// The number of retries used by [`ZERO_WRITE_RETRY`].
+    ///
+    /// [`ZERO_WRITE_RETRY`]: crate::ZERO_WRITE_RETRY
+    pub zero_write_retries: c_int,
+    /// Whether to record each handle's ownership history (see
+    /// [`Config::with_ownership_history()`]).
+    pub ownership_history: bool,
+}
+
+impl Default for FfiConfig {
+    fn default() -> Self {
+        let (zero_write_policy, zero_write_retries) =
+            ZeroWritePolicy::default().to_raw();
+
+        FfiConfig {
+            size: mem::size_of::<FfiConfig>(),
+            zero_write_policy,
+            zero_write_retries,
+            ownership_history: false,
+        }
+    }
+}
+
+export! {
+    /// Update the crate's configuration, leaving settings which can't be
+    /// expressed in an [`FfiConfig`] (e.g. the Rust log sink) untouched.
+    ///
+    /// Returns `0` on success or [`CONFIG_INVALID`] if the config is invalid,
+    /// in which case nothing is changed.
+    pub unsafe extern "C" fn thin_trait_objects_configure(
+        config: *const FfiConfig,
+    ) -> c_int {
+        // Note: fields are accessed through the pointer because an older
+        // caller's struct may be too small to take a reference to
+        if config.is_null() || (*config).size < FFI_CONFIG_V1_SIZE {
+            return CONFIG_INVALID;
+        }
+
+        let mut updated = current_config();
+        if (*config).size >= mem::size_of::<FfiConfig>() {
+            updated =
+                updated.with_ownership_history((*config).ownership_history);
+        }
+        let policy = ZeroWritePolicy::from_raw(
+            (*config).zero_write_policy,
+            (*config).zero_write_retries,
+        );
+
+        match policy {
+            Some(policy) => {
+                updated.with_zero_write_policy(policy).apply();
+                0
+            },
+            None => CONFIG_INVALID,
+        }
+    }
+}
+
+export! {
+    /// Copy the crate's current configuration into `config`, whose `size` must
+    /// already be set.
+    ///
+    /// Returns `0` on success or [`CONFIG_INVALID`] if `config` is invalid.
+    pub unsafe extern "C" fn thin_trait_objects_current_config(
+        config: *mut FfiConfig,
+    ) -> c_int {
+        if config.is_null() || (*config).size < FFI_CONFIG_V1_SIZE {
+            return CONFIG_INVALID;
+        }
+
+        let current = current_config();
+        let (policy, retries) = current.zero_write_policy().to_raw();
+        (*config).zero_write_policy = policy;
+        (*config).zero_write_retries = retries;
+        if (*config).size >= mem::size_of::<FfiConfig>() {
+            (*config).ownership_history = current.ownership_history();
+        }
+
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ffi::*, global_clock, lifecycle::lock_global_state, ManualClock,
+        OwnedFileHandle,
+    };
+    use std::{io::Write, time::SystemTime};
+
+    #[test]
+    fn applying_a_config_replaces_everything() {
+        let _global = lock_global_state();
+
+        Config::new()
+            .with_clock(Arc::new(ManualClock::default()))
+            .with_zero_write_policy(ZeroWritePolicy::Error)
+            .apply();
+        assert_eq!(global_clock().wall_time(), SystemTime::UNIX_EPOCH);
+
+        struct Stubborn;
+        impl Write for Stubborn {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> { Ok(0) }
+
+            fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+        }
+        let mut handle = OwnedFileHandle::new(Stubborn);
+        assert!(handle.write(b"x").is_err());
+
+        let current = current_config();
+        assert!(current.clock().is_some());
+        assert!(current.log_sink().is_none());
+
+        Config::default().apply();
+        assert_ne!(global_clock().wall_time(), SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            current_config().zero_write_policy(),
+            ZeroWritePolicy::PassThrough
+        );
+    }
+
+    #[test]
+    fn configure_from_c() {
+        let _global = lock_global_state();
+
+        unsafe {
+            let mut config = FfiConfig {
+                zero_write_policy: ZERO_WRITE_RETRY,
+                zero_write_retries: 3,
+                ..FfiConfig::default()
+            };
+            assert_eq!(thin_trait_objects_configure(&config), 0);
+
+            let mut current = FfiConfig::default();
+            assert_eq!(thin_trait_objects_current_config(&mut current), 0);
+            assert_eq!(current, config);
+
+            config.zero_write_retries = -1;
+            let ret = thin_trait_objects_configure(&config);
+            assert_eq!(ret, CONFIG_INVALID);
+            config.size = 0;
+            let ret = thin_trait_objects_current_config(&mut config);
+            assert_eq!(ret, CONFIG_INVALID);
+        }
+
+        Config::default().apply();
+    }
+
+    #[test]
+    fn older_ffi_configs_are_still_accepted() {
+        let _global = lock_global_state();
+        Config::new().with_ownership_history(true).apply();
+
+        unsafe {
+            let mut config = FfiConfig {
+                size: FFI_CONFIG_V1_SIZE,
+                ownership_history: false,
+                ..FfiConfig::default()
+            };
+            assert_eq!(thin_trait_objects_configure(&config), 0);
+            assert!(current_config().ownership_history());
+
+            config.ownership_history = false;
+            assert_eq!(thin_trait_objects_current_config(&mut config), 0);
+            assert!(!config.ownership_history);
+        }
+
+        Config::default().apply();
+    }
+}
@@ -0,0 +1,292 @@
+//! Pumping data from a [`ReadHandle`] into a [`FileHandle`] without making
+//! native code write its own copy loop.
+
+use crate::{FileHandle, ReadHandle};
+use std::{
+    cell::RefCell,
+    io::{Error, ErrorKind},
+    os::raw::c_int,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Returned by [`handle_copy_with_cancel()`] when the copy was stopped by a
+/// [`CancelToken`] before reaching the end of the stream.
+pub const HANDLE_COPY_CANCELLED: c_int = 1;
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    /// A buffer which is reused between copies on the same thread.
+    static COPY_BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// A flag which can be used to stop a [`handle_copy_with_cancel()`] from
+/// another thread.
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    cancelled: AtomicBool,
+}
+
+impl CancelToken {
+    /// Create a new [`CancelToken`].
+    pub fn new() -> Self { CancelToken::default() }
+
+    /// Ask any copies using this token to stop.
+    pub fn cancel(&self) { self.cancelled.store(true, Ordering::Release); }
+
+    /// Has [`CancelToken::cancel()`] been called?
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+/// Copy everything from `reader` into `writer`, recording the number of bytes
+/// successfully written in `copied`.
+///
+/// Returns `Ok(false)` if the copy was cancelled before reaching the end of
+/// the stream.
+pub(crate) unsafe fn copy(
+    reader: *mut ReadHandle,
+    writer: *mut FileHandle,
+    cancel: Option<&CancelToken>,
+    copied: &mut u64,
+) -> Result<bool, Error> {
+    copy_up_to(reader, writer, cancel, u64::MAX, copied)
+}
+
+/// Like [`copy()`], except it stops once `limit` bytes have been copied.
+pub(crate) unsafe fn copy_up_to(
+    reader: *mut ReadHandle,
+    writer: *mut FileHandle,
+    cancel: Option<&CancelToken>,
+    limit: u64,
+    copied: &mut u64,
+) -> Result<bool, Error> {
+    COPY_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+        Ok(mut buffer) => {
+            buffer.resize(COPY_BUFFER_SIZE, 0);
+            pump(reader, writer, cancel, &mut buffer, limit, copied)
+        },
+        // A writer is copying from inside another copy, so we can't reuse the
+        // thread's buffer
+        Err(_) => {
+            let mut buffer = vec![0; COPY_BUFFER_SIZE];
+            pump(reader, writer, cancel, &mut buffer, limit, copied)
+        },
+    })
+}
+
+unsafe fn pump(
+    reader: *mut ReadHandle,
+    writer: *mut FileHandle,
+    cancel: Option<&CancelToken>,
+    buffer: &mut [u8],
+    limit: u64,
+    copied: &mut u64,
+) -> Result<bool, Error> {
+    let mut remaining = limit;
+
+    loop {
+        if cancel.map(CancelToken::is_cancelled).unwrap_or(false) {
+            return Ok(false);
+        }
+        if remaining == 0 {
+            return Ok(true);
+        }
+
+        let len = remaining.min(buffer.len() as u64) as usize;
+        let space = &mut buffer[..len];
+        let bytes_read = match ReadHandle::dispatch_read(reader, space) {
+            Ok(0) => return Ok(true),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut chunk = &buffer[..bytes_read];
+
+        while !chunk.is_empty() {
+            match FileHandle::dispatch_write(writer, chunk) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    chunk = &chunk[n..];
+                    *copied += n as u64;
+                    remaining -= n as u64;
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+export! {
+    /// Copy everything from `reader` into `writer` until the end of the stream.
+    ///
+    /// The number of bytes written is stored in `out_copied` (if it isn't
+    /// `null`), even when the copy fails part way through. Returns `0` on
+    /// success or a negative value on failure.
+    pub unsafe extern "C" fn handle_copy(
+        reader: *mut ReadHandle,
+        writer: *mut FileHandle,
+        out_copied: *mut u64,
+    ) -> c_int {
+        handle_copy_with_cancel(reader, writer, std::ptr::null(), out_copied)
+    }
+}
+
+export! {
+    /// The same as [`handle_copy()`], except the copy will stop early
+    /// (returning [`HANDLE_COPY_CANCELLED`]) once `cancel` is triggered.
+    ///
+    /// The token is checked between chunks, so a copy which is blocked on a
+    /// read or write won't notice until that call returns. A `null` token is
+    /// never cancelled.
+    pub unsafe extern "C" fn handle_copy_with_cancel(
+        reader: *mut ReadHandle,
+        writer: *mut FileHandle,
+        cancel: *const CancelToken,
+        out_copied: *mut u64,
+    ) -> c_int {
+        let mut copied = 0;
+        let result = copy(reader, writer, cancel.as_ref(), &mut copied);
+
+        if !out_copied.is_null() {
+            *out_copied = copied;
+        }
+
+        match result {
+            Ok(true) => 0,
+            Ok(false) => HANDLE_COPY_CANCELLED,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+export! {
+    /// Create a new [`CancelToken`].
+    pub unsafe extern "C" fn cancel_token_new() -> *mut CancelToken {
+        Box::into_raw(Box::new(CancelToken::new()))
+    }
+}
+
+export! {
+    /// Cancel any copies using this [`CancelToken`]. This may be called from
+    /// any thread.
+    pub unsafe extern "C" fn cancel_token_cancel(token: *const CancelToken) {
+        (*token).cancel();
+    }
+}
+
+export! {
+    /// Free a [`CancelToken`]. It must not be used by any in-progress copies.
+    pub unsafe extern "C" fn cancel_token_destroy(token: *mut CancelToken) {
+        if !token.is_null() {
+            drop(Box::from_raw(token));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn copy_everything() {
+        let data: Vec<u8> = (0..COPY_BUFFER_SIZE * 3 + 7)
+            .map(|i| i as u8)
+            .collect();
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let reader = new_memory_read_handle(data.as_ptr(), data.len());
+            let writer = FileHandle::for_writer(buffer.clone());
+            let mut copied = 0;
+
+            let ret = handle_copy(reader, writer, &mut copied);
+
+            assert_eq!(ret, 0);
+            assert_eq!(copied, data.len() as u64);
+            read_handle_destroy(reader);
+            file_handle_destroy(writer);
+        }
+
+        assert_eq!(*buffer.0.lock().unwrap(), data);
+    }
+
+    #[test]
+    fn errors_report_how_much_was_copied() {
+        /// Accepts the first `n` bytes, then fails.
+        struct Limited(usize);
+        impl Write for Limited {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+                if self.0 == 0 {
+                    return Err(Error::from_raw_os_error(28));
+                }
+                let n = buf.len().min(self.0);
+                self.0 -= n;
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+        }
+
+        let data = [0_u8; 100];
+
+        unsafe {
+            let reader = new_memory_read_handle(data.as_ptr(), data.len());
+            let writer = FileHandle::for_writer(Limited(42));
+            let mut copied = 0;
+
+            let ret = handle_copy(reader, writer, &mut copied);
+
+            assert_eq!(ret, -28);
+            assert_eq!(copied, 42);
+            read_handle_destroy(reader);
+            file_handle_destroy(writer);
+        }
+    }
+
+    #[test]
+    fn cancel_a_copy_from_another_thread() {
+        /// An endless stream which cancels the copy after a while.
+        struct Endless {
+            token: *const CancelToken,
+            reads: usize,
+        }
+        unsafe impl Send for Endless {}
+        unsafe impl Sync for Endless {}
+        impl Read for Endless {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+                self.reads += 1;
+                if self.reads == 3 {
+                    let token = self.token as usize;
+                    std::thread::spawn(move || unsafe {
+                        cancel_token_cancel(token as *const CancelToken)
+                    })
+                    .join()
+                    .unwrap();
+                }
+                Ok(buf.len().min(10))
+            }
+        }
+
+        unsafe {
+            let token = cancel_token_new();
+            let reader = ReadHandle::for_reader(Endless { token, reads: 0 });
+            let writer = new_null_file_handle();
+            let mut copied = 0;
+
+            let ret =
+                handle_copy_with_cancel(reader, writer, token, &mut copied);
+
+            assert_eq!(ret, HANDLE_COPY_CANCELLED);
+            assert_eq!(copied, 30);
+            read_handle_destroy(reader);
+            file_handle_destroy(writer);
+            cancel_token_destroy(token);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Generating `tto.hpp`, a single-header C++ wrapper around the FFI.
+//!
+//! The C declarations in the header come from the same list which is
+//! checked against the real functions at compile time, so renaming a
+//! function or changing its signature breaks the build instead of the C++
+//! code.
+
+use std::fmt::Write;
+
+/// A function the C++ wrapper calls.
+struct Declaration {
+    name: &'static str,
+    args: &'static [(&'static str, &'static str)],
+    ret: &'static str,
+}
+
+macro_rules! declarations {
+    ($( fn $name:ident($($arg:ident: $ty:ty),* $(,)?) -> $ret:ty; )*) => {
+        // make sure every declaration matches the function being exported
+        #[cfg(not(feature = "c-unwind"))]
+        #[allow(dead_code)]
+        fn check_declarations() {
+            use crate::FileHandle;
+            use std::os::raw::{c_char, c_int};
+
+            $(
+                let _: unsafe extern "C" fn($($ty),*) -> $ret = crate::$name;
+            )*
+        }
+
+        const DECLARATIONS: &[Declaration] = &[
+            $(
+                Declaration {
+                    name: stringify!($name),
+                    args: &[$( (stringify!($arg), stringify!($ty)) ),*],
+                    ret: stringify!($ret),
+                },
+            )*
+        ];
+    };
+}
+
+declarations! {
+    fn new_null_file_handle() -> *mut FileHandle;
+    fn new_file_handle_from_path(path: *const c_char) -> *mut FileHandle;
+    fn file_handle_write(
+        handle: *mut FileHandle,
+        data: *const c_char,
+        len: c_int,
+    ) -> c_int;
+    fn file_handle_flush(handle: *mut FileHandle) -> c_int;
+    fn file_handle_sync(handle: *mut FileHandle) -> c_int;
+    fn file_handle_destroy(handle: *mut FileHandle) -> ();
+}
+
+/// Translate a Rust type (as written in [`declarations!`]) to C.
+fn c_type(rust: &str) -> &'static str {
+    match rust {
+        "*mut FileHandle" => "FileHandle *",
+        "*const c_char" => "const char *",
+        "c_int" => "int",
+        "()" => "void",
+        other => panic!("No C equivalent for \"{}\"", other),
+    }
+}
+
+const PRELUDE: &str = r#"// Generated by generate-cpp-header. Do not edit.
+//
+// Regenerate it by running this from the thin-trait-objects repository:
+//
+//     cargo run --features cpp-header --bin generate-cpp-header tto.hpp
+//
+// A move-only RAII wrapper around thin-trait-objects' FileHandle. Requires
+// C++17 and linking against the thin_trait_objects library.
+#pragma once
+
+#include <climits>
+#include <cstddef>
+#include <string_view>
+#include <system_error>
+#include <utility>
+
+extern "C"
+{
+struct FileHandle;
+"#;
+
+const CLASS: &str = r#"}
+
+namespace tto
+{
+
+// Turn a return code from the C API into a std::error_code, where negative
+// values are errno codes.
+inline std::error_code make_error_code(int ret) noexcept
+{
+    if (ret >= 0)
+    {
+        return std::error_code();
+    }
+    return std::error_code(-ret, std::generic_category());
+}
+
+// Owns a ::FileHandle, destroying it when it goes out of scope.
+class FileHandle
+{
+  public:
+    FileHandle() noexcept = default;
+
+    // Take ownership of a handle created by the C API.
+    explicit FileHandle(::FileHandle *raw) noexcept : raw_(raw) {}
+
+    // A handle which throws away everything written to it.
+    static FileHandle null() noexcept
+    {
+        return FileHandle(::new_null_file_handle());
+    }
+
+    // Create (or truncate) a file, returning an empty handle on failure.
+    static FileHandle open(const char *path) noexcept
+    {
+        return FileHandle(::new_file_handle_from_path(path));
+    }
+
+    FileHandle(const FileHandle &) = delete;
+    FileHandle &operator=(const FileHandle &) = delete;
+
+    FileHandle(FileHandle &&other) noexcept : raw_(other.release()) {}
+
+    FileHandle &operator=(FileHandle &&other) noexcept
+    {
+        if (this != &other)
+        {
+            reset(other.release());
+        }
+        return *this;
+    }
+
+    ~FileHandle() { reset(); }
+
+    // Write all of data, stopping at the first error.
+    std::error_code write(std::string_view data) noexcept
+    {
+        while (!data.empty())
+        {
+            int len = data.size() > INT_MAX ? INT_MAX : int(data.size());
+            int ret = ::file_handle_write(raw_, data.data(), len);
+            if (ret < 0)
+            {
+                return make_error_code(ret);
+            }
+            if (ret == 0)
+            {
+                return std::make_error_code(std::errc::io_error);
+            }
+            data.remove_prefix(std::size_t(ret));
+        }
+        return std::error_code();
+    }
+
+    std::error_code flush() noexcept
+    {
+        return make_error_code(::file_handle_flush(raw_));
+    }
+
+    // Flush all the way to durable storage (e.g. with fsync()).
+    std::error_code sync() noexcept
+    {
+        return make_error_code(::file_handle_sync(raw_));
+    }
+
+    ::FileHandle *get() const noexcept { return raw_; }
+
+    // Give up ownership without destroying the handle.
+    ::FileHandle *release() noexcept { return std::exchange(raw_, nullptr); }
+
+    void reset(::FileHandle *raw = nullptr) noexcept
+    {
+        ::FileHandle *old = std::exchange(raw_, raw);
+        if (old)
+        {
+            ::file_handle_destroy(old);
+        }
+    }
+
+    explicit operator bool() const noexcept { return raw_ != nullptr; }
+
+  private:
+    ::FileHandle *raw_ = nullptr;
+};
+
+} // namespace tto
+"#;
+
+/// Declare something of a C type, keeping pointers next to the name.
+fn declare(ty: &str, name: &str) -> String {
+    if ty.ends_with('*') {
+        format!("{}{}", ty, name)
+    } else {
+        format!("{} {}", ty, name)
+    }
+}
+
+/// Generate the contents of `tto.hpp`, calling the functions by their
+/// exported names (see [`symbol_prefix()`][crate::symbol_prefix]).
+pub fn cpp_header() -> String {
+    let prefix = crate::symbol_prefix();
+    let mut header = String::from(PRELUDE);
+    let mut class = String::from(CLASS);
+
+    for decl in DECLARATIONS {
+        let args: Vec<String> = decl
+            .args
+            .iter()
+            .map(|(name, ty)| declare(c_type(ty), name))
+            .collect();
+        let args = if args.is_empty() {
+            String::from("void")
+        } else {
+            args.join(", ")
+        };
+
+        let name = format!("{}{}", prefix, decl.name);
+        let function = declare(c_type(decl.ret), &name);
+        writeln!(header, "{}({});", function, args).unwrap();
+
+        let call = format!("::{}(", decl.name);
+        class = class.replace(&call, &format!("::{}(", name));
+    }
+
+    header.push_str(&class);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_declaration_is_in_the_header() {
+        let header = cpp_header();
+
+        let prefix = crate::symbol_prefix();
+
+        assert!(header.contains(&format!(
+            "int {}file_handle_write(FileHandle *handle, const char *data, \
+             int len);",
+            prefix
+        )));
+        assert!(header.contains(&format!(
+            "FileHandle *{}new_null_file_handle(void);",
+            prefix
+        )));
+        for decl in DECLARATIONS {
+            assert!(header.contains(&format!("::{}{}(", prefix, decl.name)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "No C equivalent")]
+    fn unknown_types_are_rejected() { c_type("Vec<u8>"); }
+}
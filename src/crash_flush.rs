@@ -0,0 +1,303 @@
+//! Best-effort flushing when the process is about to die (e.g. from
+//! `SIGTERM` or `SIGSEGV`), so the tail of a plugin's log isn't lost.
+//!
+//! Handles registered for exit flushing are copied into a fixed set of
+//! atomic slots, letting them be found without taking any locks.
+
+use crate::FileHandle;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// The most handles [`emergency_flush_all()`] can see at a time. Handles
+/// registered once this is reached are still flushed on exit.
+pub const EMERGENCY_FLUSH_CAPACITY: usize = 256;
+
+/// How long the crash handler spends flushing before letting the signal
+/// through (see [`install_crash_flush_handler()`]).
+pub const CRASH_FLUSH_TIMEOUT_MS: u32 = 100;
+
+/// Set on a slot's address while [`emergency_flush_all()`] is flushing it.
+const BUSY: usize = 1;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicUsize = AtomicUsize::new(0);
+
+/// The address of every handle registered for exit flushing, or `0`.
+static SLOTS: [AtomicUsize; EMERGENCY_FLUSH_CAPACITY] =
+    [EMPTY; EMERGENCY_FLUSH_CAPACITY];
+
+/// Include `handle` in future emergency flushes.
+pub(crate) fn register(handle: *mut FileHandle) {
+    let addr = handle as usize;
+
+    for slot in SLOTS.iter() {
+        let claimed = slot.compare_exchange(
+            0,
+            addr,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        if claimed.is_ok() {
+            return;
+        }
+    }
+}
+
+/// Forget about a handle which is about to be destroyed, waiting for an
+/// emergency flush which is using it to finish.
+pub(crate) fn unregister(handle: *mut FileHandle) {
+    let addr = handle as usize;
+
+    for slot in SLOTS.iter() {
+        loop {
+            match slot.compare_exchange(
+                addr,
+                0,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(current) if current == addr | BUSY => {
+                    std::thread::yield_now()
+                },
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Forget about every registered handle.
+pub(crate) fn shutdown() {
+    for slot in SLOTS.iter() {
+        slot.store(0, Ordering::Release);
+    }
+}
+
+/// Flush every handle registered with [`OwnedFileHandle::flush_on_exit()`]
+/// or [`file_handle_register_for_exit_flush()`], giving up once `timeout`
+/// has passed. Returns how many were flushed successfully.
+///
+/// This never takes a lock, so it can be used from a signal handler as long
+/// as the handles' own `flush()` is safe to call there. Handles which are
+/// poisoned, frozen or stuck (see
+/// [`file_handle_set_watchdog()`][crate::file_handle_set_watchdog]) are
+/// skipped, as is any handle an interrupted emergency flush was part way
+/// through.
+///
+/// Handles are flushed without checking whether another thread is using
+/// them, so this is only meant for when the process is going down.
+///
+/// [`OwnedFileHandle::flush_on_exit()`]:
+/// crate::OwnedFileHandle::flush_on_exit
+/// [`file_handle_register_for_exit_flush()`]:
+/// crate::file_handle_register_for_exit_flush
+pub fn emergency_flush_all(timeout: Duration) -> usize {
+    let deadline = Instant::now().checked_add(timeout);
+    let mut flushed = 0;
+
+    for slot in SLOTS.iter() {
+        if deadline.map_or(false, |d| Instant::now() >= d) {
+            break;
+        }
+
+        let addr = slot.load(Ordering::Acquire);
+        if addr == 0 || addr & BUSY != 0 {
+            continue;
+        }
+        let claimed = slot.compare_exchange(
+            addr,
+            addr | BUSY,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        if claimed.is_err() {
+            continue;
+        }
+
+        if unsafe { flush_quietly(addr as *mut FileHandle) } {
+            flushed += 1;
+        }
+        slot.store(addr, Ordering::Release);
+    }
+
+    flushed
+}
+
+unsafe fn flush_quietly(handle: *mut FileHandle) -> bool {
+    let unusable = FileHandle::POISONED
+        | FileHandle::FROZEN
+        | FileHandle::TIMED_OUT
+        | FileHandle::FLUSHING;
+    if (*handle).has_flag(unusable) {
+        return false;
+    }
+
+    // Note: this skips FileHandle::dispatch_flush() because its stats and
+    // timers use locks the crashing thread may have been holding
+    let flush = (*handle).flush;
+    flush(handle).is_ok()
+}
+
+/// Call [`emergency_flush_all()`] when the process receives `SIGTERM` or
+/// `SIGSEGV`, spending at most [`CRASH_FLUSH_TIMEOUT_MS`] before the signal
+/// is passed on to whichever handler was installed beforehand.
+///
+/// Installing the handler more than once has no extra effect. Returns
+/// `false` if signal handlers aren't supported on this platform.
+pub fn install_crash_flush_handler() -> bool { signals::install() }
+
+#[cfg(unix)]
+mod signals {
+    use super::{emergency_flush_all, CRASH_FLUSH_TIMEOUT_MS};
+    use std::{
+        os::raw::c_int,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Once,
+        },
+        time::Duration,
+    };
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn raise(signum: c_int) -> c_int;
+    }
+
+    const SIGSEGV: c_int = 11;
+    const SIGTERM: c_int = 15;
+    const SIG_ERR: usize = !0;
+
+    /// The handlers which were installed before ours.
+    static PREVIOUS_SEGV: AtomicUsize = AtomicUsize::new(0);
+    static PREVIOUS_TERM: AtomicUsize = AtomicUsize::new(0);
+
+    fn previous(signum: c_int) -> &'static AtomicUsize {
+        if signum == SIGSEGV {
+            &PREVIOUS_SEGV
+        } else {
+            &PREVIOUS_TERM
+        }
+    }
+
+    extern "C" fn on_signal(signum: c_int) {
+        let timeout = Duration::from_millis(CRASH_FLUSH_TIMEOUT_MS.into());
+        emergency_flush_all(timeout);
+
+        unsafe {
+            signal(signum, previous(signum).load(Ordering::Acquire));
+            raise(signum);
+        }
+    }
+
+    pub(super) fn install() -> bool {
+        static INSTALL: Once = Once::new();
+        static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+        INSTALL.call_once(|| unsafe {
+            let mut ok = true;
+
+            for &signum in &[SIGSEGV, SIGTERM] {
+                let handler = on_signal as extern "C" fn(c_int) as usize;
+                match signal(signum, handler) {
+                    SIG_ERR => ok = false,
+                    old => previous(signum).store(old, Ordering::Release),
+                }
+            }
+
+            INSTALLED.store(ok, Ordering::Release);
+        });
+
+        INSTALLED.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(not(unix))]
+mod signals {
+    pub(super) fn install() -> bool { false }
+}
+
+export! {
+    /// Flush every handle registered for exit flushing, giving up after
+    /// `timeout_ms` milliseconds, and return how many were flushed successfully
+    /// (see [`emergency_flush_all()`]).
+    ///
+    /// This never takes a lock, so it may be called from a signal handler.
+    pub unsafe extern "C" fn thin_trait_objects_emergency_flush_all(
+        timeout_ms: u32,
+    ) -> usize {
+        emergency_flush_all(Duration::from_millis(timeout_ms.into()))
+    }
+}
+
+export! {
+    /// Flush registered handles on `SIGTERM` and `SIGSEGV` (see
+    /// [`install_crash_flush_handler()`]).
+    ///
+    /// Returns `false` if signal handlers aren't supported on this platform.
+    pub unsafe extern "C" fn thin_trait_objects_install_crash_flush_handler(
+    ) -> bool {
+        install_crash_flush_handler()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, OwnedFileHandle};
+    use std::{
+        io::{BufWriter, Error, Write},
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct CountFlushes(Arc<Mutex<usize>>);
+
+    impl Write for CountFlushes {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_everything_registered_for_exit() {
+        let _global = crate::lifecycle::lock_global_state();
+        let flushes = CountFlushes::default();
+        let handle = OwnedFileHandle::new(BufWriter::new(flushes.clone()));
+        handle.flush_on_exit();
+        handle.flush_on_exit();
+        let frozen_flushes = CountFlushes::default();
+        let mut frozen = OwnedFileHandle::new(frozen_flushes.clone());
+        frozen.flush_on_exit();
+        frozen.freeze().unwrap();
+
+        let flushed = emergency_flush_all(Duration::from_secs(1));
+
+        assert_eq!(flushed, 1);
+        assert_eq!(*flushes.0.lock().unwrap(), 1);
+        // only the flush from freezing it
+        assert_eq!(*frozen_flushes.0.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn destroyed_handles_are_forgotten() {
+        let _global = crate::lifecycle::lock_global_state();
+        let flushes = CountFlushes::default();
+
+        unsafe {
+            let handle = FileHandle::for_writer(flushes.clone());
+            file_handle_register_for_exit_flush(handle);
+            file_handle_destroy(handle);
+
+            assert_eq!(thin_trait_objects_emergency_flush_all(1000), 0);
+        }
+
+        assert_eq!(*flushes.0.lock().unwrap(), 0);
+    }
+}
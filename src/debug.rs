@@ -0,0 +1,239 @@
+//! Human-readable descriptions of a [`FileHandle`], for use while debugging
+//! an integration.
+
+use crate::{
+    ArcFileHandle, BinaryEncodingWriter, Capabilities, FileHandle,
+    IndirectWriter, LossyWriter, OwnedFileHandle, RecordingWriter,
+    RedactingWriter, SequencedWriter, ShortWriter, TranscodingWriter,
+};
+use std::{
+    ffi::CStr,
+    fmt::{self, Debug, Formatter, Write as _},
+    io::BufWriter,
+    os::raw::c_char,
+};
+
+/// How many layers of wrappers will be followed before giving up, in case
+/// something has managed to wrap itself.
+const MAX_DEPTH: usize = 32;
+
+/// Call `visit` with the handle that `handle`'s object wraps, if it is one of
+/// the crate's wrappers.
+///
+/// Wrappers whose inner handle is behind a lock are skipped while the lock is
+/// held by someone else, so this can never block.
+unsafe fn with_inner(
+    handle: *mut FileHandle,
+    visit: &mut dyn FnMut(*mut FileHandle),
+) {
+    if let Some(w) = FileHandle::downcast_raw::<RecordingWriter>(handle) {
+        visit((*w).get_ref().as_ptr());
+    } else if let Some(w) = FileHandle::downcast_raw::<RedactingWriter>(handle)
+    {
+        visit((*w).get_ref().as_ptr());
+    } else if let Some(w) = FileHandle::downcast_raw::<ShortWriter>(handle) {
+        visit((*w).get_ref().as_ptr());
+    } else if let Some(w) =
+        FileHandle::downcast_raw::<TranscodingWriter>(handle)
+    {
+        if let Some(inner) = (*w).get_ref() {
+            visit(inner.as_ptr());
+        }
+    } else if let Some(w) =
+        FileHandle::downcast_raw::<BinaryEncodingWriter>(handle)
+    {
+        if let Some(inner) = (*w).get_ref() {
+            visit(inner.as_ptr());
+        }
+    } else if let Some(w) =
+        FileHandle::downcast_raw::<BufWriter<OwnedFileHandle>>(handle)
+    {
+        visit((*w).get_ref().as_ptr());
+    } else if let Some(w) = FileHandle::downcast_raw::<IndirectWriter>(handle)
+    {
+        if let Some(inner) = (*w).try_inner() {
+            visit(inner.as_ptr());
+        }
+    } else if let Some(w) = FileHandle::downcast_raw::<SequencedWriter>(handle)
+    {
+        if let Some(inner) = (*w).try_inner() {
+            visit(inner.as_ptr());
+        }
+    } else if let Some(w) = FileHandle::downcast_raw::<LossyWriter>(handle) {
+        if let Some(inner) = (*w).try_inner() {
+            visit(inner.as_ptr());
+        }
+    } else if let Some(w) = FileHandle::downcast_raw::<ArcFileHandle>(handle) {
+        if let Some(inner) = (*w).try_inner() {
+            visit(inner.as_ptr());
+        }
+    }
+}
+
+/// The type names of every layer below `handle`, outermost first.
+unsafe fn wrapper_chain(
+    handle: *mut FileHandle,
+    chain: &mut Vec<&'static str>,
+) {
+    if chain.len() >= MAX_DEPTH {
+        return;
+    }
+
+    with_inner(handle, &mut |inner| {
+        chain.push((*inner).cold.type_name);
+        wrapper_chain(inner, chain);
+    });
+}
+
+fn capability_names(capabilities: Capabilities) -> Vec<&'static str> {
+    let names = [
+        (Capabilities::SIZE_HINTS, "SIZE_HINTS"),
+        (Capabilities::DURABLE_FLUSH, "DURABLE_FLUSH"),
+        (Capabilities::LOSSY, "LOSSY"),
+        (Capabilities::RAW_FD, "RAW_FD"),
+    ];
+
+    names
+        .iter()
+        .filter(|(capability, _)| capabilities.contains(*capability))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Write a description of the handle, in the style of `#[derive(Debug)]`.
+pub(crate) unsafe fn describe(
+    handle: *mut FileHandle,
+    f: &mut Formatter<'_>,
+) -> fmt::Result {
+    let header = &*handle;
+    let name = crate::file_handle_name(handle);
+    let name = if name.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(name).to_string_lossy())
+    };
+
+    // byte counters are only kept while thread stats are turned on
+    let stats = header
+        .extensions()
+        .filter(|ext| ext.thread_stats.is_enabled())
+        .map(|ext| ext.thread_stats.snapshot());
+    let writes = stats
+        .as_ref()
+        .map(|s| s.iter().map(|t| t.writes).sum::<u64>());
+    let bytes_written = stats
+        .as_ref()
+        .map(|s| s.iter().map(|t| t.bytes_written).sum::<u64>());
+
+    let mut wraps = Vec::new();
+    wrapper_chain(handle, &mut wraps);
+
+    f.debug_struct("OwnedFileHandle")
+        .field("address", &handle)
+        .field("type_name", &header.cold.type_name)
+        .field("name", &name)
+        .field("poisoned", &header.is_poisoned())
+        .field("frozen", &header.has_flag(FileHandle::FROZEN))
+        .field("capabilities", &capability_names(header.capabilities()))
+        .field("writes", &writes)
+        .field("bytes_written", &bytes_written)
+        .field("wraps", &wraps)
+        .finish()
+}
+
+/// Lets [`describe()`] be used with `format!()`.
+struct Describe(*mut FileHandle);
+
+impl Debug for Describe {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        unsafe { describe(self.0, f) }
+    }
+}
+
+export! {
+    /// Copy a multi-line, human-readable description of the [`FileHandle`] into
+    /// `buffer` as a null-terminated string.
+    ///
+    /// The description includes the object's type, whether the handle is
+    /// poisoned or frozen, its capabilities, byte counters (while thread stats
+    /// are enabled), and the type of every handle it wraps. It is meant for
+    /// people and its format may change at any time.
+    ///
+    /// At most `len` bytes (including the null terminator) are written, and the
+    /// length of the full description (excluding the null terminator) is
+    /// returned.
+    pub unsafe extern "C" fn file_handle_debug_dump(
+        handle: *mut FileHandle,
+        buffer: *mut c_char,
+        len: usize,
+    ) -> usize {
+        let mut description = String::new();
+        let _ = write!(description, "{:#?}", Describe(handle));
+
+        if !buffer.is_null() {
+            let buffer =
+                std::slice::from_raw_parts_mut(buffer.cast::<u8>(), len);
+
+            if let Some(space) = len.checked_sub(1) {
+                let copied = description.len().min(space);
+                buffer[..copied]
+                    .copy_from_slice(&description.as_bytes()[..copied]);
+                buffer[copied] = 0;
+            }
+        }
+
+        description.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::io::Write;
+
+    #[test]
+    fn describe_the_whole_chain() {
+        let inner = OwnedFileHandle::new(Vec::<u8>::new());
+        let shorter = OwnedFileHandle::new(ShortWriter::new(inner, 2));
+        let mut outer = OwnedFileHandle::new(IndirectWriter::new(shorter));
+        outer.enable_thread_stats();
+        outer.write_all(b"Hello").unwrap();
+
+        let description = format!("{:?}", outer);
+
+        assert!(description.contains("type_name: \"thin_trait_objects::"));
+        assert!(description.contains("bytes_written: Some(5)"));
+        assert!(description.contains("poisoned: false"));
+        let wraps = "wraps: [\"thin_trait_objects::short_write::ShortWriter\", \
+                     \"alloc::vec::Vec<u8>\"]";
+        assert!(description.contains(wraps), "{}", description);
+    }
+
+    #[test]
+    fn dump_into_a_c_buffer() {
+        unsafe {
+            let handle = new_memory_file_handle();
+            let total = file_handle_debug_dump(handle, std::ptr::null_mut(), 0);
+            assert!(total > 0);
+
+            let mut buffer = vec![0xff_u8; total + 1];
+            let got = file_handle_debug_dump(
+                handle,
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            );
+            assert_eq!(got, total);
+            let dump = CStr::from_bytes_with_nul(&buffer).unwrap();
+            let dump = dump.to_str().unwrap();
+            assert!(dump.starts_with("OwnedFileHandle {\n"));
+            assert!(dump.contains("writes: None"));
+
+            let mut small = [0xff_u8; 4];
+            file_handle_debug_dump(handle, small.as_mut_ptr().cast(), 4);
+            assert_eq!(&small, b"Own\0");
+
+            file_handle_destroy(handle);
+        }
+    }
+}
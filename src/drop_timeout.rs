@@ -0,0 +1,140 @@
+//! Dropping a handle's writer without letting a slow destructor block the
+//! caller (see [`FileHandle::for_writer_with_drop_timeout()`]).
+//!
+//! [`FileHandle::for_writer_with_drop_timeout()`]:
+//! crate::FileHandle::for_writer_with_drop_timeout
+
+use crate::log_bridge::{self, LogLevel, LogRecord};
+use std::{sync::mpsc, thread, time::Duration};
+
+/// Drop `value` on a helper thread, waiting at most `timeout` for it to
+/// finish.
+///
+/// Returns `false` (after logging a warning) if the destructor was still
+/// running when we stopped waiting, in which case it is left to finish in
+/// the background.
+pub(crate) fn drop_with_timeout<T: Send + 'static>(
+    value: T,
+    timeout: Duration,
+    type_name: &str,
+) -> bool {
+    let (done_tx, done_rx) = mpsc::channel();
+
+    let spawned = thread::Builder::new()
+        .name(String::from("file-handle-drop"))
+        .spawn(move || {
+            drop(value);
+            let _ = done_tx.send(());
+        });
+
+    if spawned.is_err() {
+        // This only happens when the OS can't create any more threads, in
+        // which case the value was dropped when the closure was
+        return true;
+    }
+
+    match done_rx.recv_timeout(timeout) {
+        // Note: a disconnect means the destructor panicked, which the helper
+        // thread already reported
+        Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => true,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            let message = format!(
+                "Gave up waiting for a {} to be dropped after {:?}, leaving \
+                 it to finish in the background",
+                type_name, timeout
+            );
+            log_bridge::emit_to_global_sink(&LogRecord {
+                level: LogLevel::Warn,
+                target: "thin_trait_objects::drop_timeout",
+                message: &message,
+            });
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, FileHandle};
+    use std::{
+        io::{Error, Write},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Instant,
+    };
+
+    /// A writer whose destructor waits until it is told to finish.
+    struct SlowToDrop {
+        release: Arc<Mutex<Option<mpsc::Receiver<()>>>>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Write for SlowToDrop {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+
+    impl Drop for SlowToDrop {
+        fn drop(&mut self) {
+            if let Some(release) = self.release.lock().unwrap().take() {
+                let _ = release.recv();
+            }
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn slow_to_drop() -> (SlowToDrop, mpsc::Sender<()>, Arc<AtomicBool>) {
+        let (tx, rx) = mpsc::channel();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let writer = SlowToDrop {
+            release: Arc::new(Mutex::new(Some(rx))),
+            dropped: Arc::clone(&dropped),
+        };
+
+        (writer, tx, dropped)
+    }
+
+    #[test]
+    fn quick_destructors_finish_before_destroy_returns() {
+        let (writer, release, dropped) = slow_to_drop();
+        release.send(()).unwrap();
+
+        unsafe {
+            let timeout = Duration::from_secs(10);
+            let handle =
+                FileHandle::for_writer_with_drop_timeout(writer, timeout);
+            assert_eq!(file_handle_write(handle, b"a".as_ptr().cast(), 1), 1);
+            file_handle_destroy(handle);
+        }
+
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn hung_destructors_dont_block_destroy() {
+        let (writer, release, dropped) = slow_to_drop();
+        let timeout = Duration::from_millis(10);
+        let started = Instant::now();
+
+        unsafe {
+            let handle =
+                FileHandle::for_writer_with_drop_timeout(writer, timeout);
+            file_handle_destroy(handle);
+        }
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        // let the destructor finish in the background
+        release.send(()).unwrap();
+        while !dropped.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+    }
+}
@@ -0,0 +1,669 @@
+//! FFI-safe descriptions of the errors which can be returned by this crate.
+
+use crate::global::Global;
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    fmt::{self, Display, Formatter},
+    io::{Error, ErrorKind},
+    os::raw::{c_char, c_int},
+    ptr,
+    sync::Mutex,
+};
+
+macro_rules! error_kinds {
+    ($( $(#[$meta:meta])* $name:ident = $value:expr ),* $(,)?) => {
+        /// A stable, FFI-safe mirror of [`std::io::ErrorKind`].
+        ///
+        /// The numeric values are part of this crate's ABI and will never
+        /// change. Any [`ErrorKind`] without an equivalent variant (e.g. ones
+        /// added to `std` in the future) maps to [`ThinErrorKind::Other`].
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        #[repr(i32)]
+        pub enum ThinErrorKind {
+            $(
+                $(#[$meta])*
+                $name = $value,
+            )*
+        }
+
+        impl ThinErrorKind {
+            /// Every [`ThinErrorKind`], in order.
+            pub const ALL: &'static [ThinErrorKind] =
+                &[$( ThinErrorKind::$name ),*];
+
+            /// Get the [`ThinErrorKind`] with a particular numeric value.
+            pub fn from_i32(value: i32) -> Option<ThinErrorKind> {
+                match value {
+                    $( $value => Some(ThinErrorKind::$name), )*
+                    _ => None,
+                }
+            }
+
+            /// The variant's name as a null-terminated string.
+            fn name_with_nul(self) -> &'static str {
+                match self {
+                    $(
+                        ThinErrorKind::$name => {
+                            concat!(stringify!($name), "\0")
+                        },
+                    )*
+                }
+            }
+        }
+
+        impl From<ErrorKind> for ThinErrorKind {
+            fn from(kind: ErrorKind) -> ThinErrorKind {
+                match kind {
+                    $( ErrorKind::$name => ThinErrorKind::$name, )*
+                    _ => ThinErrorKind::Other,
+                }
+            }
+        }
+
+        impl From<ThinErrorKind> for ErrorKind {
+            fn from(kind: ThinErrorKind) -> ErrorKind {
+                match kind {
+                    $( ThinErrorKind::$name => ErrorKind::$name, )*
+                }
+            }
+        }
+    };
+}
+
+error_kinds! {
+    /// An entity was not found, often a file.
+    NotFound = 1,
+    /// The operation lacked the necessary privileges to complete.
+    PermissionDenied = 2,
+    /// The connection was refused by the remote server.
+    ConnectionRefused = 3,
+    /// The connection was reset by the remote server.
+    ConnectionReset = 4,
+    /// The connection was aborted (terminated) by the remote server.
+    ConnectionAborted = 5,
+    /// The network operation failed because it was not connected yet.
+    NotConnected = 6,
+    /// A socket address could not be bound because it is already in use.
+    AddrInUse = 7,
+    /// A nonexistent interface was requested or the requested address was not
+    /// local.
+    AddrNotAvailable = 8,
+    /// The operation failed because a pipe was closed.
+    BrokenPipe = 9,
+    /// An entity already exists, often a file.
+    AlreadyExists = 10,
+    /// The operation needs to block to complete, but the blocking operation
+    /// was requested to not occur.
+    WouldBlock = 11,
+    /// A parameter was incorrect.
+    InvalidInput = 12,
+    /// Data not valid for the operation were encountered.
+    InvalidData = 13,
+    /// The I/O operation's timeout expired.
+    TimedOut = 14,
+    /// A call to `write` returned `Ok(0)`.
+    WriteZero = 15,
+    /// This operation was interrupted.
+    Interrupted = 16,
+    /// An error which doesn't fall under any other category.
+    Other = 17,
+    /// An EOF was reached prematurely.
+    UnexpectedEof = 18,
+}
+
+impl ThinErrorKind {
+    /// The variant's name (e.g. `"NotFound"`).
+    pub fn name(self) -> &'static str {
+        let name = self.name_with_nul();
+        &name[..name.len() - 1]
+    }
+
+    /// Classify a raw OS error code (e.g. `errno`) using the platform's own
+    /// conventions.
+    ///
+    /// Negative values are accepted too, because that's how this crate's FFI
+    /// functions report OS errors.
+    pub fn from_raw_os_error(code: i32) -> ThinErrorKind {
+        let code = code.checked_abs().unwrap_or(i32::MAX);
+        Error::from_raw_os_error(code).kind().into()
+    }
+}
+
+impl From<&Error> for ThinErrorKind {
+    fn from(e: &Error) -> ThinErrorKind { e.kind().into() }
+}
+
+impl From<ThinErrorKind> for Error {
+    fn from(kind: ThinErrorKind) -> Error { ErrorKind::from(kind).into() }
+}
+
+export! {
+    /// Figure out which [`ThinErrorKind`] an `errno` value corresponds to
+    /// (either positive or negated, as returned by functions like
+    /// [`file_handle_write()`][crate::file_handle_write]).
+    pub unsafe extern "C" fn thin_error_kind_from_errno(
+        errno: c_int,
+    ) -> ThinErrorKind {
+        ThinErrorKind::from_raw_os_error(errno)
+    }
+}
+
+export! {
+    /// Get the name of a [`ThinErrorKind`] as a null-terminated string with a
+    /// static lifetime, or `null` if `kind` isn't valid.
+    pub unsafe extern "C" fn thin_error_kind_name(
+        kind: c_int,
+    ) -> *const c_char {
+        match ThinErrorKind::from_i32(kind) {
+            Some(kind) => kind.name_with_nul().as_ptr() as *const c_char,
+            None => std::ptr::null(),
+        }
+    }
+}
+
+/// Where the value inside an error code returned by one of the `_v2`
+/// functions (e.g. [`file_handle_write_v2()`][crate::file_handle_write_v2])
+/// comes from.
+///
+/// Error codes are negative, with the domain in bits 24 and up and the
+/// value in the 24 bits below that. OS errors have a domain tag of `0`, so
+/// on Unix they are the same negated `errno` values returned by the original
+/// functions.
+///
+/// Values in the [`ErrorDomain::Crate`] domain are split in two. Everything
+/// below [`USER_STATUS_MIN`] is reserved for this crate, and the rest may be
+/// claimed by downstream crates with [`register_user_status()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub enum ErrorDomain {
+    /// Not an error (the code was zero or positive).
+    None = 0,
+    /// An `errno` value.
+    Errno = 1,
+    /// A Windows error code, as returned by `GetLastError()`.
+    Win32 = 2,
+    /// A [`ThinErrorKind`], used when the error didn't come from the OS.
+    Kind = 3,
+    /// An error specific to this crate (e.g. [`CRATE_ERROR_POISONED`]), or
+    /// a user-defined status registered with [`register_user_status()`].
+    Crate = 4,
+}
+
+/// The crate-specific error used when a [`FileHandle`][crate::FileHandle]
+/// has been poisoned. The original functions (e.g.
+/// [`file_handle_write()`][crate::file_handle_write]) report it with the same
+/// code as the `_v2` ones.
+pub const CRATE_ERROR_POISONED: c_int = 1;
+/// The crate-specific error used when a
+/// [`SharedSinkProducer`][crate::SharedSinkProducer] has used up its rate
+/// limit.
+pub const CRATE_ERROR_RATE_LIMITED: c_int = 2;
+/// The crate-specific error used when an external handle's callback returns
+/// something out of range (see [`FILE_HANDLE_BAD_CALLBACK_RESULT`]).
+///
+/// [`FILE_HANDLE_BAD_CALLBACK_RESULT`]: crate::FILE_HANDLE_BAD_CALLBACK_RESULT
+pub const CRATE_ERROR_BAD_CALLBACK_RESULT: c_int = 3;
+/// The crate-specific error used when a write is given a negative length
+/// (see [`FILE_HANDLE_INVALID_LENGTH`][crate::FILE_HANDLE_INVALID_LENGTH]).
+pub const CRATE_ERROR_INVALID_LENGTH: c_int = 4;
+/// The crate-specific error used when a handle is frozen (see
+/// [`FILE_HANDLE_SUSPENDED`][crate::FILE_HANDLE_SUSPENDED]).
+pub const CRATE_ERROR_SUSPENDED: c_int = 5;
+/// The crate-specific error used when a handle's quota has been used up (see
+/// [`QUOTA_EXCEEDED`][crate::QUOTA_EXCEEDED]).
+pub const CRATE_ERROR_QUOTA_EXCEEDED: c_int = 6;
+/// The crate-specific error used when the memory budget has been used up
+/// (see [`FILE_HANDLE_OUT_OF_BUDGET`][crate::FILE_HANDLE_OUT_OF_BUDGET]).
+pub const CRATE_ERROR_OUT_OF_BUDGET: c_int = 7;
+/// The crate-specific error used when a handle's watchdog gave up on it (see
+/// [`FILE_HANDLE_TIMED_OUT`][crate::FILE_HANDLE_TIMED_OUT]).
+pub const CRATE_ERROR_TIMED_OUT: c_int = 8;
+/// The crate-specific error used when a callback writes to its own handle
+/// (see [`FILE_HANDLE_REENTRANT_CALL`][crate::FILE_HANDLE_REENTRANT_CALL]).
+pub const CRATE_ERROR_REENTRANT_CALL: c_int = 9;
+
+/// The first value in the [`ErrorDomain::Crate`] domain which downstream
+/// crates may use for their own errors.
+pub const USER_STATUS_MIN: c_int = 0x1000;
+/// The last value downstream crates may use for their own errors.
+pub const USER_STATUS_MAX: c_int = VALUE_MASK;
+
+/// Returned by [`thin_trait_objects_register_user_status()`] when the code is
+/// outside the user-defined range or the name isn't valid.
+pub const USER_STATUS_INVALID: c_int = -1;
+/// Returned by [`thin_trait_objects_register_user_status()`] when the code
+/// was already registered with a different name.
+pub const USER_STATUS_TAKEN: c_int = -2;
+
+/// The names of this crate's own statuses.
+const CRATE_STATUS_NAMES: &[(c_int, &str)] = &[
+    (CRATE_ERROR_POISONED, "Poisoned\0"),
+    (CRATE_ERROR_RATE_LIMITED, "RateLimited\0"),
+    (CRATE_ERROR_BAD_CALLBACK_RESULT, "BadCallbackResult\0"),
+    (CRATE_ERROR_INVALID_LENGTH, "InvalidLength\0"),
+    (CRATE_ERROR_SUSPENDED, "Suspended\0"),
+    (CRATE_ERROR_QUOTA_EXCEEDED, "QuotaExceeded\0"),
+    (CRATE_ERROR_OUT_OF_BUDGET, "OutOfBudget\0"),
+    (CRATE_ERROR_TIMED_OUT, "TimedOut\0"),
+    (CRATE_ERROR_REENTRANT_CALL, "ReentrantCall\0"),
+];
+
+const DOMAIN_SHIFT: u32 = 24;
+const VALUE_MASK: c_int = (1 << DOMAIN_SHIFT) - 1;
+const TAG_OS: c_int = 0;
+const TAG_KIND: c_int = 1;
+const TAG_CRATE: c_int = 2;
+
+#[cfg(windows)]
+const OS_DOMAIN: ErrorDomain = ErrorDomain::Win32;
+#[cfg(not(windows))]
+const OS_DOMAIN: ErrorDomain = ErrorDomain::Errno;
+
+impl ErrorDomain {
+    /// Figure out which domain an error code belongs to.
+    pub fn of(code: c_int) -> ErrorDomain {
+        if code >= 0 {
+            return ErrorDomain::None;
+        }
+
+        match code.checked_neg().map(|c| c >> DOMAIN_SHIFT) {
+            Some(TAG_OS) => OS_DOMAIN,
+            Some(TAG_KIND) => ErrorDomain::Kind,
+            Some(TAG_CRATE) => ErrorDomain::Crate,
+            _ => ErrorDomain::None,
+        }
+    }
+}
+
+/// One of this crate's own statuses (e.g. [`CRATE_ERROR_RATE_LIMITED`]), as
+/// created by [`crate_status_error()`].
+#[derive(Debug)]
+struct CrateStatus(c_int);
+
+impl Display for CrateStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match CRATE_STATUS_NAMES.iter().find(|(code, _)| *code == self.0) {
+            Some((_, name)) => f.write_str(name.trim_end_matches('\0')),
+            None => write!(f, "Crate status {:#x}", self.0),
+        }
+    }
+}
+
+impl std::error::Error for CrateStatus {}
+
+/// Create an error for one of this crate's own statuses, which the FFI
+/// reports as [`crate_status_code()`].
+pub(crate) fn crate_status_error(kind: ErrorKind, status: c_int) -> Error {
+    Error::new(kind, CrateStatus(status))
+}
+
+/// The code returned by the FFI for one of this crate's own statuses, which
+/// is the same whichever encoding is used.
+pub(crate) const fn crate_status_code(status: c_int) -> c_int {
+    -((TAG_CRATE << DOMAIN_SHIFT) | status)
+}
+
+/// Create an error for one of this crate's own statuses without allocating,
+/// for paths which must work when allocation fails.
+///
+/// The status is stored as a raw OS error holding its full code (which no
+/// OS uses), so its [`ErrorKind`] is whatever the OS makes of that, usually
+/// [`ErrorKind::Other`].
+pub(crate) fn crate_status_unboxed(status: c_int) -> Error {
+    Error::from_raw_os_error(-crate_status_code(status))
+}
+
+/// The crate status inside an error created with [`crate_status_error()`]
+/// or [`crate_status_unboxed()`].
+pub(crate) fn crate_status_of(e: &Error) -> Option<c_int> {
+    if let Some(code) = e.raw_os_error() {
+        return if code >> DOMAIN_SHIFT == TAG_CRATE {
+            Some(code & VALUE_MASK)
+        } else {
+            None
+        };
+    }
+
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<CrateStatus>())
+        .map(|status| status.0)
+}
+
+/// Turn an error into a (negative) error code tagged with its
+/// [`ErrorDomain`].
+pub fn encode_error(e: &Error) -> c_int {
+    let user_status = e.get_ref().and_then(|e| e.downcast_ref::<UserStatus>());
+
+    let (tag, value) = if let Some(status) = crate_status_of(e) {
+        (TAG_CRATE, status)
+    } else if let Some(status) = user_status {
+        (TAG_CRATE, status.0)
+    } else {
+        match e.raw_os_error() {
+            Some(code) if code > 0 && code <= VALUE_MASK => (TAG_OS, code),
+            _ => (TAG_KIND, ThinErrorKind::from(e) as c_int),
+        }
+    };
+
+    -((tag << DOMAIN_SHIFT) | value)
+}
+
+export! {
+    /// Get the [`ErrorDomain`] for an error code returned by one of the `_v2`
+    /// functions.
+    pub unsafe extern "C" fn file_handle_error_domain(
+        code: c_int,
+    ) -> ErrorDomain {
+        ErrorDomain::of(code)
+    }
+}
+
+export! {
+    /// Get the value inside an error code returned by one of the `_v2`
+    /// functions, to be interpreted according to its [`ErrorDomain`]. Returns
+    /// `0` if the code isn't an error.
+    pub unsafe extern "C" fn file_handle_error_value(code: c_int) -> c_int {
+        match ErrorDomain::of(code) {
+            ErrorDomain::None => 0,
+            _ => -code & VALUE_MASK,
+        }
+    }
+}
+
+export! {
+    /// Figure out which [`ThinErrorKind`] an error code returned by one of the
+    /// `_v2` functions corresponds to, regardless of its [`ErrorDomain`].
+    pub unsafe extern "C" fn thin_error_kind_from_code(
+        code: c_int,
+    ) -> ThinErrorKind {
+        let value = file_handle_error_value(code);
+
+        match ErrorDomain::of(code) {
+            ErrorDomain::Errno | ErrorDomain::Win32 => {
+                ThinErrorKind::from_raw_os_error(value)
+            },
+            ErrorDomain::Kind => {
+                ThinErrorKind::from_i32(value).unwrap_or(ThinErrorKind::Other)
+            },
+            ErrorDomain::Crate | ErrorDomain::None => ThinErrorKind::Other,
+        }
+    }
+}
+
+/// The names of every status registered by a downstream crate.
+static USER_STATUSES: Global<Mutex<HashMap<c_int, CString>>> = Global::new();
+
+fn with_user_statuses<T>(
+    thunk: impl FnOnce(&mut HashMap<c_int, CString>) -> T,
+) -> T {
+    let statuses = USER_STATUSES.get_or_init(Default::default);
+    let mut statuses = statuses.lock().unwrap_or_else(|e| e.into_inner());
+    thunk(&mut statuses)
+}
+
+/// Forget every user-defined status.
+pub(crate) unsafe fn shutdown() { USER_STATUSES.reset(); }
+
+/// Claim a status `code` in the user-defined range (from
+/// [`USER_STATUS_MIN`] to [`USER_STATUS_MAX`]) so errors created with
+/// [`user_status_error()`] can be reported through the FFI.
+///
+/// Registering the same code and name again does nothing, but trying to
+/// give a code a different name fails with [`ErrorKind::AlreadyExists`] so
+/// two crates can't accidentally share a code.
+///
+/// ```rust
+/// # use thin_trait_objects::{encode_error, register_user_status};
+/// # use thin_trait_objects::{user_status_error, ErrorDomain};
+/// register_user_status(0x1234, "DeviceUnplugged").unwrap();
+///
+/// let code = encode_error(&user_status_error(0x1234));
+/// assert_eq!(ErrorDomain::of(code), ErrorDomain::Crate);
+/// assert!(register_user_status(0x1234, "SomethingElse").is_err());
+/// ```
+pub fn register_user_status(code: c_int, name: &str) -> Result<(), Error> {
+    if !(USER_STATUS_MIN..=USER_STATUS_MAX).contains(&code) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The code is outside the user-defined range",
+        ));
+    }
+    let name = CString::new(name)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    with_user_statuses(|statuses| match statuses.get(&code) {
+        Some(existing) if *existing == name => Ok(()),
+        Some(_) => Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "The code was already registered with a different name",
+        )),
+        None => {
+            statuses.insert(code, name);
+            Ok(())
+        },
+    })
+}
+
+/// A user-defined status, as created by [`user_status_error()`].
+#[derive(Debug)]
+struct UserStatus(c_int);
+
+impl Display for UserStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = with_user_statuses(|statuses| {
+            statuses
+                .get(&self.0)
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+
+        match name {
+            Some(name) => write!(f, "{} (status {:#x})", name, self.0),
+            None => write!(f, "Unregistered status {:#x}", self.0),
+        }
+    }
+}
+
+impl std::error::Error for UserStatus {}
+
+/// Create an error which [`encode_error()`] turns into the user-defined
+/// status `code` (see [`register_user_status()`]).
+pub fn user_status_error(code: c_int) -> Error {
+    Error::new(ErrorKind::Other, UserStatus(code))
+}
+
+export! {
+    /// Register a user-defined status code so it can be identified by
+    /// [`file_handle_status_name()`].
+    ///
+    /// Returns `0` on success, [`USER_STATUS_INVALID`] if `code` isn't between
+    /// [`USER_STATUS_MIN`] and [`USER_STATUS_MAX`] or `name` is `null`, or
+    /// [`USER_STATUS_TAKEN`] if the code already has a different name.
+    pub unsafe extern "C" fn thin_trait_objects_register_user_status(
+        code: c_int,
+        name: *const c_char,
+    ) -> c_int {
+        if name.is_null() {
+            return USER_STATUS_INVALID;
+        }
+
+        match CStr::from_ptr(name).to_str() {
+            Ok(name) => match register_user_status(code, name) {
+                Ok(_) => 0,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    USER_STATUS_TAKEN
+                },
+                Err(_) => USER_STATUS_INVALID,
+            },
+            Err(_) => USER_STATUS_INVALID,
+        }
+    }
+}
+
+export! {
+    /// Get a name for an error code returned by one of the `_v2` functions as a
+    /// null-terminated string.
+    ///
+    /// This covers [`ThinErrorKind`]s, this crate's own errors, and every
+    /// registered user-defined status. Names of user-defined statuses are valid
+    /// until
+    /// [`thin_trait_objects_shutdown()`][crate::thin_trait_objects_shutdown]
+    /// and the rest are static. Returns `null` for OS errors (use `strerror()`
+    /// instead) and for anything without a name.
+    pub unsafe extern "C" fn file_handle_status_name(
+        code: c_int,
+    ) -> *const c_char {
+        let value = file_handle_error_value(code);
+
+        match ErrorDomain::of(code) {
+            ErrorDomain::Kind => thin_error_kind_name(value),
+            ErrorDomain::Crate if value < USER_STATUS_MIN => CRATE_STATUS_NAMES
+                .iter()
+                .find(|(code, _)| *code == value)
+                .map_or(ptr::null(), |(_, name)| name.as_ptr().cast()),
+            ErrorDomain::Crate => with_user_statuses(|statuses| {
+                statuses.get(&value).map_or(ptr::null(), |name| name.as_ptr())
+            }),
+            _ => ptr::null(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::lock_global_state;
+
+    #[test]
+    fn error_kinds_survive_a_round_trip() {
+        for &kind in ThinErrorKind::ALL {
+            let std_kind = ErrorKind::from(kind);
+            assert_eq!(ThinErrorKind::from(std_kind), kind);
+            assert_eq!(ThinErrorKind::from_i32(kind as i32), Some(kind));
+        }
+    }
+
+    #[test]
+    fn names_are_null_terminated() {
+        unsafe {
+            let name = thin_error_kind_name(ThinErrorKind::BrokenPipe as c_int);
+            let name = CStr::from_ptr(name).to_str().unwrap();
+            assert_eq!(name, "BrokenPipe");
+            assert_eq!(ThinErrorKind::BrokenPipe.name(), "BrokenPipe");
+
+            assert!(thin_error_kind_name(0).is_null());
+            assert!(thin_error_kind_name(42).is_null());
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn classify_errno_values() {
+        // These values are the same on every unix we care about
+        const ENOENT: c_int = 2;
+        const EPIPE: c_int = 32;
+
+        unsafe {
+            assert_eq!(
+                thin_error_kind_from_errno(ENOENT),
+                ThinErrorKind::NotFound
+            );
+            assert_eq!(
+                thin_error_kind_from_errno(-EPIPE),
+                ThinErrorKind::BrokenPipe
+            );
+        }
+        assert_eq!(
+            ThinErrorKind::from_raw_os_error(c_int::MIN),
+            ThinErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn error_codes_are_tagged_with_their_domain() {
+        let os = Error::from_raw_os_error(2);
+        let kind = Error::new(ErrorKind::WriteZero, "oops");
+
+        assert_eq!(encode_error(&os), -2);
+        assert_eq!(ErrorDomain::of(encode_error(&os)), OS_DOMAIN);
+        assert_eq!(ErrorDomain::of(encode_error(&kind)), ErrorDomain::Kind);
+        assert_eq!(ErrorDomain::of(5), ErrorDomain::None);
+        assert_eq!(ErrorDomain::of(c_int::MIN), ErrorDomain::None);
+
+        unsafe {
+            let code = encode_error(&kind);
+            assert_eq!(
+                file_handle_error_value(code),
+                ThinErrorKind::WriteZero as c_int
+            );
+            assert_eq!(
+                thin_error_kind_from_code(code),
+                ThinErrorKind::WriteZero
+            );
+            assert_eq!(file_handle_error_value(10), 0);
+        }
+    }
+
+    #[test]
+    fn user_statuses_have_names() {
+        let _global = lock_global_state();
+        let name = |code| unsafe {
+            let name = file_handle_status_name(code);
+            assert!(!name.is_null());
+            CStr::from_ptr(name).to_str().unwrap().to_string()
+        };
+
+        unsafe {
+            let ret = thin_trait_objects_register_user_status(
+                0x1001,
+                b"QueueFull\0".as_ptr().cast(),
+            );
+            assert_eq!(ret, 0);
+            let ret = thin_trait_objects_register_user_status(
+                0x1001,
+                b"Other\0".as_ptr().cast(),
+            );
+            assert_eq!(ret, USER_STATUS_TAKEN);
+            let ret = thin_trait_objects_register_user_status(
+                CRATE_ERROR_POISONED,
+                b"Mine\0".as_ptr().cast(),
+            );
+            assert_eq!(ret, USER_STATUS_INVALID);
+        }
+
+        let e = user_status_error(0x1001);
+        assert_eq!(e.to_string(), "QueueFull (status 0x1001)");
+        assert_eq!(name(encode_error(&e)), "QueueFull");
+        let kind = Error::new(ErrorKind::WriteZero, "oops");
+        assert_eq!(name(encode_error(&kind)), "WriteZero");
+        assert_eq!(name(-((TAG_CRATE << DOMAIN_SHIFT) | 1)), "Poisoned");
+
+        unsafe {
+            let unregistered = encode_error(&user_status_error(0x2000));
+            assert!(file_handle_status_name(unregistered).is_null());
+            shutdown();
+            assert!(file_handle_status_name(encode_error(&e)).is_null());
+        }
+    }
+
+    #[test]
+    fn crate_statuses_are_distinct() {
+        let codes = [
+            crate::FILE_HANDLE_SUSPENDED,
+            crate::QUOTA_EXCEEDED,
+            crate::FILE_HANDLE_OUT_OF_BUDGET,
+            crate::FILE_HANDLE_TIMED_OUT,
+            crate::FILE_HANDLE_REENTRANT_CALL,
+            crate::FILE_HANDLE_BAD_CALLBACK_RESULT,
+            crate::FILE_HANDLE_INVALID_LENGTH,
+        ];
+
+        for (i, &code) in codes.iter().enumerate() {
+            assert_eq!(ErrorDomain::of(code), ErrorDomain::Crate);
+            assert!(!codes[..i].contains(&code));
+            assert!(CRATE_STATUS_NAMES
+                .iter()
+                .any(|&(status, _)| crate_status_code(status) == code));
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Flushing handles when the process exits, so buffered output isn't lost if
+//! the host calls `exit()` without cleaning up.
+
+use crate::{global::Global, FileHandle};
+use std::{
+    collections::HashSet,
+    os::raw::c_int,
+    sync::{Mutex, Once},
+};
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> c_int;
+}
+
+/// The addresses of every handle which should be flushed on exit.
+static REGISTRY: Global<Mutex<HashSet<usize>>> = Global::new();
+
+fn with_registry<T>(thunk: impl FnOnce(&mut HashSet<usize>) -> T) -> T {
+    let registry = REGISTRY.get_or_init(Default::default);
+    let mut registry = registry.lock().unwrap_or_else(|e| e.into_inner());
+    thunk(&mut registry)
+}
+
+extern "C" fn flush_registered_handles() {
+    with_registry(|registry| {
+        for &handle in registry.iter() {
+            unsafe {
+                let _ = FileHandle::dispatch_flush(handle as *mut FileHandle);
+            }
+        }
+    });
+}
+
+/// Make sure `handle` gets flushed when the process exits.
+pub(crate) unsafe fn register(handle: *mut FileHandle) {
+    static INSTALL_HOOK: Once = Once::new();
+    INSTALL_HOOK.call_once(|| {
+        atexit(flush_registered_handles);
+    });
+
+    if with_registry(|registry| registry.insert(handle as usize)) {
+        crate::crash_flush::register(handle);
+    }
+    (*handle).set_flag(FileHandle::FLUSH_ON_EXIT);
+}
+
+/// Forget about a handle which is about to be destroyed.
+pub(crate) unsafe fn unregister(handle: *mut FileHandle) {
+    if (*handle).has_flag(FileHandle::FLUSH_ON_EXIT) {
+        with_registry(|registry| registry.remove(&(handle as usize)));
+        crate::crash_flush::unregister(handle);
+    }
+}
+
+/// Forget about every registered handle.
+pub(crate) unsafe fn shutdown() {
+    REGISTRY.reset();
+    crate::crash_flush::shutdown();
+}
+
+export! {
+    /// Flush (but not destroy) this [`FileHandle`] when the process exits.
+    ///
+    /// The handle is automatically unregistered when it is destroyed, and
+    /// registering the same handle multiple times has no extra effect.
+    pub unsafe extern "C" fn file_handle_register_for_exit_flush(
+        handle: *mut FileHandle,
+    ) {
+        register(handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, OwnedFileHandle};
+    use std::{
+        io::{BufWriter, Error, Write},
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct CountFlushes(Arc<Mutex<usize>>);
+
+    impl Write for CountFlushes {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn registered_handles_are_flushed() {
+        let _global = crate::lifecycle::lock_global_state();
+        let flushes = CountFlushes::default();
+        let handle = OwnedFileHandle::new(BufWriter::new(flushes.clone()));
+        handle.flush_on_exit();
+
+        flush_registered_handles();
+
+        assert_eq!(*flushes.0.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn destroyed_handles_are_unregistered() {
+        let _global = crate::lifecycle::lock_global_state();
+        let flushes = CountFlushes::default();
+
+        unsafe {
+            let handle = FileHandle::for_writer(flushes.clone());
+            file_handle_register_for_exit_flush(handle);
+            file_handle_register_for_exit_flush(handle);
+            file_handle_destroy(handle);
+        }
+
+        flush_registered_handles();
+
+        assert_eq!(*flushes.0.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,43 @@
+//! Exporting functions under the symbol prefix chosen at build time, so
+//! several libraries which embed this crate can be loaded into the same
+//! process.
+//!
+//! Setting `$THIN_TRAIT_OBJECTS_SYMBOL_PREFIX` while building (e.g. to
+//! `myplugin_`) exports `file_handle_write()` as `myplugin_file_handle_write`
+//! and so on. Prefixes require Rust 1.54. Function names on the Rust side
+//! don't change.
+
+/// Export a function, prefixing its symbol with [`symbol_prefix()`].
+///
+/// [`symbol_prefix()`]: crate::symbol_prefix
+#[cfg(not(tto_symbol_prefix))]
+macro_rules! export {
+    (
+        $(#[$attr:meta])*
+        $vis:vis unsafe extern $abi:literal fn $name:ident ($($args:tt)*)
+            $(-> $ret:ty)? $body:block
+    ) => {
+        $(#[$attr])*
+        #[no_mangle]
+        $vis unsafe extern $abi fn $name($($args)*) $(-> $ret)? $body
+    };
+}
+
+/// Export a function, prefixing its symbol with [`symbol_prefix()`].
+///
+/// [`symbol_prefix()`]: crate::symbol_prefix
+#[cfg(tto_symbol_prefix)]
+macro_rules! export {
+    (
+        $(#[$attr:meta])*
+        $vis:vis unsafe extern $abi:literal fn $name:ident ($($args:tt)*)
+            $(-> $ret:ty)? $body:block
+    ) => {
+        $(#[$attr])*
+        #[export_name = concat!(
+            env!("THIN_TRAIT_OBJECTS_SYMBOL_PREFIX"),
+            stringify!($name),
+        )]
+        $vis unsafe extern $abi fn $name($($args)*) $(-> $ret)? $body
+    };
+}
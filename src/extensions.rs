@@ -0,0 +1,43 @@
+//! Optional per-handle state which is only allocated when a feature that
+//! needs it gets enabled.
+
+use crate::{
+    autoflush::AutoflushLock, flush_timeout::BackgroundFlush,
+    latency::LatencyTable, quota::Quota, shrink::ShrinkSlot, stats::Counters,
+    thread_stats::ThreadStatsTable, watchdog::WatchdogTimer,
+    write_filter::WriteFilterSlot,
+};
+
+/// Extra state hanging off a [`FileHandle`][crate::FileHandle].
+///
+/// Everything in here may be accessed by multiple threads at once (e.g. when
+/// the handle was created with `FileHandle::for_concurrent_writer()`), so it
+/// must all be thread-safe.
+#[derive(Debug, Default)]
+pub(crate) struct Extensions {
+    pub(crate) thread_stats: ThreadStatsTable,
+    pub(crate) quota: Quota,
+    pub(crate) latency: LatencyTable,
+    pub(crate) autoflush: AutoflushLock,
+    pub(crate) watchdog: WatchdogTimer,
+    pub(crate) write_filter: WriteFilterSlot,
+    pub(crate) background_flush: BackgroundFlush,
+    pub(crate) counters: Counters,
+    pub(crate) shrink: ShrinkSlot,
+    #[cfg(feature = "tracing")]
+    pub(crate) tracing: crate::trace_spans::TraceLevelSlot,
+}
+
+impl Extensions {
+    /// Does this handle report its writes and flushes to the trace sink?
+    pub(crate) fn is_traced(&self) -> bool {
+        #[cfg(feature = "tracing")]
+        {
+            self.tracing.get().is_some()
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            false
+        }
+    }
+}
@@ -2,68 +2,459 @@
 
 #![allow(missing_docs)]
 
-use crate::FileHandle;
+use crate::{
+    backend::Capabilities,
+    budget::Charge,
+    errors::{
+        crate_status_code, crate_status_error, CRATE_ERROR_BAD_CALLBACK_RESULT,
+        CRATE_ERROR_INVALID_LENGTH,
+    },
+    file_handle::{dealloc_global, write_many_one_by_one, ColdHeader},
+    last_error::ErrorSlot,
+    sync::AtomicU32,
+    unwind::PoisonOnUnwind,
+    validation::{self, CANARY_LEN},
+    FileHandle, OwnershipEvent,
+};
 use std::{
     alloc::Layout,
     any::TypeId,
-    convert::TryInto,
-    io::Error,
+    convert::{TryFrom, TryInto},
+    ffi::{CStr, CString},
+    io::{Error, ErrorKind},
     os::raw::{c_char, c_int, c_void},
+    ptr,
+    sync::atomic::AtomicPtr,
 };
 
+/// Returned when a callback's return value is out of range, i.e. a `write`
+/// callback claimed to write more bytes than it was given or a callback
+/// returned `INT_MIN` (which has no matching `errno` value).
+///
+/// This is [`CRATE_ERROR_BAD_CALLBACK_RESULT`] in the
+/// [`ErrorDomain::Crate`] domain, so it can't be confused with an error the
+/// callback reported itself.
+///
+/// [`ErrorDomain::Crate`]: crate::ErrorDomain::Crate
+pub const FILE_HANDLE_BAD_CALLBACK_RESULT: c_int =
+    crate_status_code(CRATE_ERROR_BAD_CALLBACK_RESULT);
+
+/// Returned by [`file_handle_write()`] when `len` is negative.
+///
+/// This is [`CRATE_ERROR_INVALID_LENGTH`] in the [`ErrorDomain::Crate`]
+/// domain, so it can't be confused with an `EINVAL` from the writer.
+///
+/// [`file_handle_write()`]: crate::file_handle_write
+/// [`ErrorDomain::Crate`]: crate::ErrorDomain::Crate
+pub const FILE_HANDLE_INVALID_LENGTH: c_int =
+    crate_status_code(CRATE_ERROR_INVALID_LENGTH);
+
+pub(crate) fn bad_callback_result() -> Error {
+    crate_status_error(ErrorKind::InvalidData, CRATE_ERROR_BAD_CALLBACK_RESULT)
+}
+
+pub(crate) fn invalid_length() -> Error {
+    crate_status_error(ErrorKind::InvalidInput, CRATE_ERROR_INVALID_LENGTH)
+}
+
+/// Turn a callback's return value into the number of bytes it handled (at
+/// most `max`), or the error it reported as a negative `errno` value.
+fn callback_result(ret: c_int, max: usize) -> Result<usize, Error> {
+    if ret < 0 {
+        return match ret.checked_neg() {
+            Some(code) => Err(Error::from_raw_os_error(code)),
+            None => Err(bad_callback_result()),
+        };
+    }
+
+    match usize::try_from(ret) {
+        Ok(n) if n <= max => Ok(n),
+        _ => Err(bad_callback_result()),
+    }
+}
+
+/// The most bytes which can be passed to a callback in one go.
+fn clamp_len(len: usize) -> c_int {
+    c_int::try_from(len).unwrap_or(c_int::MAX)
+}
+
 #[repr(C)]
 pub struct FileHandleBuilder {
     pub file_handle: *mut FileHandle,
     pub place: *mut c_void,
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn new_file_handle_builder(
+impl FileHandleBuilder {
+    const NULL: FileHandleBuilder = FileHandleBuilder {
+        file_handle: ptr::null_mut(),
+        place: ptr::null_mut(),
+    };
+}
+
+c_unwind! { pub(crate) type DestroyCallback = unsafe fn(*mut c_void); }
+c_unwind! {
+    pub(crate) type WriteCallback =
+        unsafe fn(*mut c_void, *const c_char, c_int) -> c_int;
+}
+c_unwind! {
+    pub(crate) type FlushCallback = unsafe fn(*mut c_void) -> c_int;
+}
+c_unwind! { type HintSizeCallback = unsafe fn(*mut c_void, u64) -> c_int; }
+
+/// An opaque object used to describe an externally implemented
+/// [`FileHandle`] before allocating it.
+///
+/// The only required setting is the `write` callback. All other callbacks are
+/// optional and objects are assumed to be zero-sized unless a layout is set.
+/// The setters ignore a `null` builder.
+pub struct ExternalFileHandleBuilder {
     size: c_int,
     alignment: c_int,
-    destroy: unsafe extern "C" fn(*mut c_void),
-    write: unsafe extern "C" fn(*mut c_void, *const c_char, c_int) -> c_int,
-    flush: unsafe extern "C" fn(*mut c_void) -> c_int,
-) -> FileHandleBuilder {
-    let header_layout = Layout::new::<ExternalFileHandle>();
-
-    // FIXME: panic in `extern "C"` code... no bueno.
-    let size = size.try_into().unwrap();
-    let alignment = alignment.try_into().unwrap();
-    let object_layout = Layout::from_size_align(size, alignment).unwrap();
-
-    let (overall_layout, object_offset) =
-        header_layout.extend(object_layout).unwrap();
-
-    // So this is a bit tricky. We're effectively trying to emulate
-    // placement-new, but in Rust.
-    //
-    // First we'll allocate some memory for the entire object
-    let ptr = std::alloc::alloc_zeroed(overall_layout);
-
-    // now let's initialize the header part
-    let ptr = ptr as *mut ExternalFileHandle;
-
-    ptr.write(ExternalFileHandle {
-        base: FileHandle {
-            layout: overall_layout,
-            type_id: TypeId::of::<ExternalFileHandle>(),
-            poisoned: false,
-            destroy: destroy_external_file_handle,
-            write: write_external_file_handle,
-            flush: flush_external_file_handle,
-        },
-        object_offset,
-        destroy,
-        flush,
-        write,
-    });
-
-    // we use the offset from earlier to find where the caller needs to
-    // initialize their object
-    FileHandleBuilder {
-        file_handle: ptr.cast(),
-        place: ptr.cast::<u8>().add(object_offset).cast(),
+    destroy: Option<DestroyCallback>,
+    write: Option<WriteCallback>,
+    flush: Option<FlushCallback>,
+    hint_size: Option<HintSizeCallback>,
+    name: Option<CString>,
+    validate: bool,
+}
+
+export! {
+    /// Start building a new externally implemented [`FileHandle`].
+    ///
+    /// The builder must be passed to either [`file_handle_builder_finish()`] or
+    /// [`file_handle_builder_free()`] to release it.
+    pub unsafe extern "C" fn file_handle_builder_new(
+    ) -> *mut ExternalFileHandleBuilder {
+        Box::into_raw(Box::new(ExternalFileHandleBuilder {
+            size: 0,
+            alignment: 1,
+            destroy: None,
+            write: None,
+            flush: None,
+            hint_size: None,
+            name: None,
+            validate: false,
+        }))
+    }
+}
+
+export! {
+    /// Set the size and alignment of the caller's object.
+    ///
+    /// Negative values, alignments which aren't a power of two, and layouts too
+    /// big to allocate (more than `isize::MAX` bytes once the handle's header
+    /// is included) make [`file_handle_builder_finish()`] return `null`.
+    pub unsafe extern "C" fn file_handle_builder_set_layout(
+        builder: *mut ExternalFileHandleBuilder,
+        size: c_int,
+        alignment: c_int,
+    ) {
+        if builder.is_null() {
+            return;
+        }
+
+        (*builder).size = size;
+        (*builder).alignment = alignment;
+    }
+}
+
+export! {
+    /// Set the callback used to destroy the caller's object in place.
+    pub unsafe extern "C" fn file_handle_builder_set_destroy(
+        builder: *mut ExternalFileHandleBuilder,
+        destroy: Option<DestroyCallback>,
+    ) {
+        if builder.is_null() {
+            return;
+        }
+
+        (*builder).destroy = destroy;
+    }
+}
+
+export! {
+    /// Set the callback used to write data to the caller's object (required).
+    ///
+    /// The callback should return the number of bytes written, or a negative
+    /// `errno` value on failure. The callback is given at most `INT_MAX` bytes
+    /// at a time, and returning more than `len` or `INT_MIN` fails the write
+    /// with [`FILE_HANDLE_BAD_CALLBACK_RESULT`].
+    pub unsafe extern "C" fn file_handle_builder_set_write(
+        builder: *mut ExternalFileHandleBuilder,
+        write: Option<WriteCallback>,
+    ) {
+        if builder.is_null() {
+            return;
+        }
+
+        (*builder).write = write;
+    }
+}
+
+export! {
+    /// Set the callback used to flush the caller's object.
+    ///
+    /// The callback should return `0` on success, or a negative `errno` value
+    /// on failure. Positive values and `INT_MIN` fail the flush with
+    /// [`FILE_HANDLE_BAD_CALLBACK_RESULT`].
+    pub unsafe extern "C" fn file_handle_builder_set_flush(
+        builder: *mut ExternalFileHandleBuilder,
+        flush: Option<FlushCallback>,
+    ) {
+        if builder.is_null() {
+            return;
+        }
+
+        (*builder).flush = flush;
+    }
+}
+
+export! {
+    /// Set the callback used to tell the caller's object roughly how many more
+    /// bytes are about to be written (see [`file_handle_hint_total_size()`]).
+    ///
+    /// [`file_handle_hint_total_size()`]: crate::file_handle_hint_total_size
+    pub unsafe extern "C" fn file_handle_builder_set_hint_size(
+        builder: *mut ExternalFileHandleBuilder,
+        hint_size: Option<HintSizeCallback>,
+    ) {
+        if builder.is_null() {
+            return;
+        }
+
+        (*builder).hint_size = hint_size;
+    }
+}
+
+export! {
+    /// Give the handle a human-readable name, which is copied into the handle.
+    ///
+    /// Passing `null` clears the name.
+    pub unsafe extern "C" fn file_handle_builder_set_name(
+        builder: *mut ExternalFileHandleBuilder,
+        name: *const c_char,
+    ) {
+        if builder.is_null() {
+            return;
+        }
+
+        (*builder).name = if name.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(name).to_owned())
+        };
+    }
+}
+
+export! {
+    /// Check that the handle's callbacks stick to their contracts, for tracking
+    /// down bugs in a new implementation (off by default).
+    ///
+    /// While validating, the handle notices when:
+    ///
+    /// - `write` returns more than the number of bytes it was given
+    /// - `flush` or `hint_size` return a positive value
+    /// - a callback writes past the end of the object's layout (detected using
+    ///   canary bytes placed after the object)
+    /// - the handle is used or destroyed again after being destroyed
+    ///
+    /// Violations are logged as errors to the global log sink (see
+    /// [`set_log_sink()`][crate::set_log_sink]), fail the operation, and poison
+    /// the handle so its callbacks aren't called again. To catch a second
+    /// destroy, the memory for recently destroyed handles is only freed once
+    /// newer validated handles have been destroyed.
+    pub unsafe extern "C" fn file_handle_builder_set_validation(
+        builder: *mut ExternalFileHandleBuilder,
+        enabled: bool,
+    ) {
+        if builder.is_null() {
+            return;
+        }
+
+        (*builder).validate = enabled;
+    }
+}
+
+export! {
+    /// Discard a builder without creating a [`FileHandle`].
+    pub unsafe extern "C" fn file_handle_builder_free(
+        builder: *mut ExternalFileHandleBuilder,
+    ) {
+        if !builder.is_null() {
+            drop(Box::from_raw(builder));
+        }
+    }
+}
+
+export! {
+    /// Consume the builder and allocate the [`FileHandle`].
+    ///
+    /// The caller must initialize their object at the returned `place` before
+    /// using the `file_handle`. Both pointers are `null` if the builder was
+    /// `null` or invalid (e.g. a bad layout or no `write` callback).
+    pub unsafe extern "C" fn file_handle_builder_finish(
+        builder: *mut ExternalFileHandleBuilder,
+    ) -> FileHandleBuilder {
+        if builder.is_null() {
+            return FileHandleBuilder::NULL;
+        }
+
+        let builder = *Box::from_raw(builder);
+
+        match builder.allocate() {
+            Some(allocated) => allocated,
+            None => FileHandleBuilder::NULL,
+        }
+    }
+}
+
+impl ExternalFileHandleBuilder {
+    unsafe fn allocate(self) -> Option<FileHandleBuilder> {
+        let write = self.write?;
+        let header_layout = Layout::new::<ExternalFileHandle>();
+
+        let size: usize = self.size.try_into().ok()?;
+        let alignment = self.alignment.try_into().ok()?;
+        let padded_size = if self.validate {
+            size.checked_add(CANARY_LEN)?
+        } else {
+            size
+        };
+        let object_layout =
+            Layout::from_size_align(padded_size, alignment).ok()?;
+
+        let (overall_layout, object_offset) =
+            header_layout.extend(object_layout).ok()?;
+
+        // So this is a bit tricky. We're effectively trying to emulate
+        // placement-new, but in Rust.
+        //
+        // First we'll allocate some memory for the entire object
+        let ptr = std::alloc::alloc_zeroed(overall_layout);
+
+        if ptr.is_null() {
+            return None;
+        }
+
+        // now let's initialize the header part
+        let ptr = ptr as *mut ExternalFileHandle;
+
+        ptr.write(ExternalFileHandle {
+            base: FileHandle {
+                write: write_external_file_handle,
+                flags: AtomicU32::new(0),
+                zero_write_policy: AtomicU32::new(
+                    crate::zero_write::default_policy().to_bits(),
+                ),
+                flush: flush_external_file_handle,
+                write_many: write_many_one_by_one,
+                extensions: AtomicPtr::new(ptr::null_mut()),
+                cold: Box::new(ColdHeader {
+                    layout: overall_layout,
+                    payload_offset: object_offset,
+                    type_id: TypeId::of::<ExternalFileHandle>(),
+                    type_name: "external",
+                    destroy: destroy_external_file_handle,
+                    drop_timeout: None,
+                    dealloc: dealloc_global,
+                    hint_size: self
+                        .hint_size
+                        .map(|_| hint_size_external_file_handle as _),
+                    last_error: ErrorSlot::new(),
+                    name: self.name,
+                    path: None,
+                    seek: None,
+                    read: None,
+                    sync: None,
+                    write_tagged: None,
+                    budget: Charge::default(),
+                    allocated: None,
+                    capabilities: if self.hint_size.is_some() {
+                        Capabilities::SIZE_HINTS
+                    } else {
+                        Capabilities::empty()
+                    },
+                }),
+            },
+            object_offset,
+            object_size: size,
+            validated: self.validate,
+            destroyed: false,
+            destroy: self.destroy,
+            flush: self.flush,
+            write,
+            hint_size: self.hint_size,
+        });
+
+        let place = ptr.cast::<u8>().add(object_offset);
+        if self.validate {
+            validation::fill_canary(place.add(size));
+        }
+
+        crate::history::record(ptr.cast(), OwnershipEvent::Created);
+
+        // we use the offset from earlier to find where the caller needs to
+        // initialize their object
+        Some(FileHandleBuilder {
+            file_handle: ptr.cast(),
+            place: place.cast(),
+        })
+    }
+}
+
+export! {
+    /// Allocate an externally implemented [`FileHandle`] in one call.
+    ///
+    /// This is equivalent to using [`file_handle_builder_new()`] and setting
+    /// the layout and each callback, and is kept for existing callers. New code
+    /// should prefer the builder functions.
+    pub unsafe extern "C" fn new_file_handle_builder(
+        size: c_int,
+        alignment: c_int,
+        destroy: DestroyCallback,
+        write: WriteCallback,
+        flush: FlushCallback,
+    ) -> FileHandleBuilder {
+        let builder = file_handle_builder_new();
+        file_handle_builder_set_layout(builder, size, alignment);
+        file_handle_builder_set_destroy(builder, Some(destroy));
+        file_handle_builder_set_write(builder, Some(write));
+        file_handle_builder_set_flush(builder, Some(flush));
+
+        file_handle_builder_finish(builder)
+    }
+}
+
+export! {
+    /// Get the name given to a [`FileHandle`] using
+    /// [`file_handle_builder_set_name()`].
+    ///
+    /// Returns `null` if the handle has no name or wasn't created by the
+    /// builder.
+    pub unsafe extern "C" fn file_handle_external_name(
+        handle: *mut FileHandle,
+    ) -> *const c_char {
+        if (*handle).cold.type_id != TypeId::of::<ExternalFileHandle>() {
+            return ptr::null();
+        }
+
+        crate::file_handle_name(handle)
+    }
+}
+
+export! {
+    /// Get a pointer to the object inside a [`FileHandle`] created using
+    /// [`new_file_handle_builder()`] (i.e. the `place` it was initialized at).
+    ///
+    /// Returns `null` if the handle wasn't created by the builder.
+    pub unsafe extern "C" fn file_handle_as_external(
+        handle: *mut FileHandle,
+    ) -> *mut c_void {
+        if (*handle).cold.type_id == TypeId::of::<ExternalFileHandle>() {
+            object_ptr(handle.cast())
+        } else {
+            ptr::null_mut()
+        }
     }
 }
 
@@ -71,27 +462,125 @@ pub unsafe extern "C" fn new_file_handle_builder(
 struct ExternalFileHandle {
     base: FileHandle,
     object_offset: usize,
-    destroy: unsafe extern "C" fn(*mut c_void),
-    write: unsafe extern "C" fn(*mut c_void, *const c_char, c_int) -> c_int,
-    flush: unsafe extern "C" fn(*mut c_void) -> c_int,
+    /// The object's size, not including any canary bytes.
+    object_size: usize,
+    validated: bool,
+    destroyed: bool,
+    destroy: Option<DestroyCallback>,
+    write: WriteCallback,
+    flush: Option<FlushCallback>,
+    hint_size: Option<HintSizeCallback>,
 }
 
 unsafe fn object_ptr(external: *mut ExternalFileHandle) -> *mut c_void {
     (external as *mut u8).add((*external).object_offset) as *mut c_void
 }
 
+/// Make sure a callback is allowed to be called, i.e. the handle isn't
+/// poisoned and (when validating) hasn't been destroyed.
+unsafe fn check_usable(
+    external: *mut ExternalFileHandle,
+    callback: &str,
+) -> Result<(), Error> {
+    let handle = external.cast::<FileHandle>();
+
+    if (*external).destroyed {
+        let message = format!(
+            "The {} callback was about to be called on the destroyed handle \
+             at {:p}",
+            callback, handle
+        );
+        Err(validation::violation(handle, message))
+    } else if (*handle).is_poisoned() {
+        Err(crate::file_handle::poison_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Make sure a callback returned something sensible and didn't write past
+/// the end of the object, when validating.
+unsafe fn check_callback(
+    external: *mut ExternalFileHandle,
+    callback: &str,
+    ret: c_int,
+    max: c_int,
+) -> Result<(), Error> {
+    let handle = external.cast::<FileHandle>();
+    let size = (*external).object_size;
+    let canary = object_ptr(external).cast::<u8>().add(size);
+
+    if !validation::canary_intact(canary) {
+        let message = format!(
+            "The {} callback wrote past the end of the {} byte object for \
+             the handle at {:p}",
+            callback, size, handle
+        );
+        Err(validation::violation(handle, message))
+    } else if ret > max {
+        let message = format!(
+            "The {} callback for the handle at {:p} returned {} (expected at \
+             most {})",
+            callback, handle, ret, max
+        );
+        Err(validation::violation(handle, message))
+    } else {
+        Ok(())
+    }
+}
+
+/// Destroy a handle whose object was never initialized, without calling its
+/// `destroy` callback.
+pub(crate) unsafe fn destroy_uninitialized(handle: *mut FileHandle) {
+    (*handle.cast::<ExternalFileHandle>()).destroy = None;
+    FileHandle::dispatch_destroy(handle);
+}
+
 unsafe fn destroy_external_file_handle(handle: *mut FileHandle) {
     let external = handle as *mut ExternalFileHandle;
 
-    // first we destroy the object in place
-    let destroy = (*external).destroy;
-    destroy(object_ptr(external));
+    if (*external).validated {
+        if (*external).destroyed {
+            let message =
+                format!("The handle at {:p} was destroyed twice", handle);
+            validation::violation(handle, message);
+            return;
+        }
+
+        (*external).destroyed = true;
+        if let Some(destroy) = (*external).destroy {
+            if !(*handle).has_flag(FileHandle::LEAK_ON_DESTROY) {
+                destroy(object_ptr(external));
+            }
+        }
+        let _ = check_callback(external, "destroy", 0, 0);
+
+        // keep the memory around so a second destroy can be detected
+        validation::quarantine(handle, free_external_file_handle);
+        return;
+    }
+
+    // first we destroy the object in place, unless a callback unwound and
+    // left it in an unknown state
+    if let Some(destroy) = (*external).destroy {
+        if !(*handle).has_flag(FileHandle::LEAK_ON_DESTROY) {
+            destroy(object_ptr(external));
+        }
+    }
+
+    free_external_file_handle(handle);
+}
+
+unsafe fn free_external_file_handle(handle: *mut FileHandle) {
+    let external = handle as *mut ExternalFileHandle;
 
     // then we can destroy the ExternalFileHandle
-    std::ptr::drop_in_place(external);
+    let layout = (*external).base.cold.layout;
+    let dealloc = (*external).base.cold.dealloc;
+    ptr::drop_in_place(external);
 
     // and finally deallocate
-    std::alloc::dealloc(external.cast(), (*external).base.layout);
+    FileHandle::deallocate(handle, dealloc, layout);
 }
 
 unsafe fn write_external_file_handle(
@@ -100,78 +589,198 @@ unsafe fn write_external_file_handle(
 ) -> Result<usize, Error> {
     let external = handle as *mut ExternalFileHandle;
     let write = (*external).write;
+    check_usable(external, "write")?;
 
-    let ret = write(
-        object_ptr(external),
-        data.as_ptr() as *const _,
-        data.len() as _,
-    );
+    let len = clamp_len(data.len());
+    let guard = PoisonOnUnwind::new(handle);
+    let ret = write(object_ptr(external), data.as_ptr().cast(), len);
+    guard.disarm();
 
-    if ret >= 0 {
-        Ok(ret as usize)
-    } else {
-        Err(Error::from_raw_os_error(-ret))
+    if (*external).validated {
+        check_callback(external, "write", ret, len)?;
     }
+
+    callback_result(ret, data.len())
 }
+
 unsafe fn flush_external_file_handle(
     handle: *mut FileHandle,
 ) -> Result<(), Error> {
     let external = handle as *mut ExternalFileHandle;
-    let flush = (*external).flush;
+    let flush = match (*external).flush {
+        Some(flush) => flush,
+        None => return Ok(()),
+    };
+    check_usable(external, "flush")?;
 
+    let guard = PoisonOnUnwind::new(handle);
     let ret = flush(object_ptr(external));
+    guard.disarm();
 
-    if ret >= 0 {
-        Ok(())
-    } else {
-        Err(Error::from_raw_os_error(-ret))
+    if (*external).validated {
+        check_callback(external, "flush", ret, 0)?;
+    }
+
+    callback_result(ret, 0).map(|_| ())
+}
+
+unsafe fn hint_size_external_file_handle(
+    handle: *mut FileHandle,
+    bytes: u64,
+) -> Result<(), Error> {
+    let external = handle as *mut ExternalFileHandle;
+    let hint_size = match (*external).hint_size {
+        Some(hint_size) => hint_size,
+        None => return Ok(()),
+    };
+    check_usable(external, "hint_size")?;
+
+    let guard = PoisonOnUnwind::new(handle);
+    let ret = hint_size(object_ptr(external), bytes);
+    guard.disarm();
+
+    if (*external).validated {
+        check_callback(external, "hint_size", ret, 0)?;
     }
+
+    callback_result(ret, 0).map(|_| ())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::ffi::{tests::SharedBuffer, *};
-    use std::io::Write;
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    c_unwind! {
+        unsafe fn destroy_data(data: *mut c_void) {
+            std::ptr::drop_in_place(data.cast::<SharedBuffer>());
+        }
+    }
+
+    c_unwind! {
+        unsafe fn write_data(
+            data: *mut c_void,
+            buffer: *const c_char,
+            len: c_int,
+        ) -> c_int {
+            let buffer =
+                std::slice::from_raw_parts(buffer as *const u8, len as usize);
 
-    unsafe extern "C" fn destroy_data(data: *mut c_void) {
-        std::ptr::drop_in_place(data.cast::<SharedBuffer>());
+            match data
+                .cast::<SharedBuffer>()
+                .as_mut()
+                .unwrap()
+                .0
+                .lock()
+                .unwrap()
+                .write(buffer)
+            {
+                Ok(bytes_written) => bytes_written as c_int,
+                Err(e) => e.raw_os_error().map(|code| -code).unwrap_or(-1),
+            }
+        }
     }
 
-    unsafe extern "C" fn write_data(
-        data: *mut c_void,
-        buffer: *const c_char,
-        len: c_int,
-    ) -> c_int {
-        let buffer =
-            std::slice::from_raw_parts(buffer as *const u8, len as usize);
+    c_unwind! {
+        unsafe fn flush_data(data: *mut c_void) -> c_int {
+            match data
+                .cast::<SharedBuffer>()
+                .as_mut()
+                .unwrap()
+                .0
+                .lock()
+                .unwrap()
+                .flush()
+            {
+                Ok(_) => 0,
+                Err(e) => e.raw_os_error().map(|code| -code).unwrap_or(-1),
+            }
+        }
+    }
+
+    #[cfg(feature = "c-unwind")]
+    #[test]
+    fn callbacks_can_unwind_through_the_handle() {
+        c_unwind! {
+            unsafe fn panicking_write(
+                _: *mut c_void,
+                _: *const c_char,
+                _: c_int,
+            ) -> c_int {
+                panic!("Oops...")
+            }
+        }
+
+        unsafe {
+            let builder = file_handle_builder_new();
+            file_handle_builder_set_write(builder, Some(panicking_write));
+            let handle = file_handle_builder_finish(builder).file_handle;
+
+            let got = std::panic::catch_unwind(|| {
+                file_handle_write(handle, b"asdf".as_ptr().cast(), 4)
+            });
 
-        match data
-            .cast::<SharedBuffer>()
-            .as_mut()
-            .unwrap()
-            .0
-            .lock()
-            .unwrap()
-            .write(buffer)
-        {
-            Ok(bytes_written) => bytes_written as c_int,
-            Err(e) => e.raw_os_error().map(|code| -code).unwrap_or(-1),
+            assert!(got.is_err());
+            assert!(file_handle_is_poisoned(handle));
+            // the callback isn't called again
+            assert!(file_handle_write(handle, b"asdf".as_ptr().cast(), 4) < 0);
+            file_handle_destroy(handle);
         }
     }
 
-    unsafe extern "C" fn flush_data(data: *mut c_void) -> c_int {
-        match data
-            .cast::<SharedBuffer>()
-            .as_mut()
-            .unwrap()
-            .0
-            .lock()
-            .unwrap()
-            .flush()
-        {
-            Ok(_) => 0,
-            Err(e) => e.raw_os_error().map(|code| -code).unwrap_or(-1),
+    #[test]
+    fn poisoned_handles_never_call_their_callbacks() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        c_unwind! {
+            unsafe fn counting_destroy(_: *mut c_void) {
+                CALLS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        c_unwind! {
+            unsafe fn counting_write(
+                _: *mut c_void,
+                _: *const c_char,
+                len: c_int,
+            ) -> c_int {
+                CALLS.fetch_add(1, Ordering::SeqCst);
+                len
+            }
+        }
+        c_unwind! {
+            unsafe fn counting_flush(_: *mut c_void) -> c_int {
+                CALLS.fetch_add(1, Ordering::SeqCst);
+                0
+            }
+        }
+
+        for &validate in &[false, true] {
+            unsafe {
+                let builder = file_handle_builder_new();
+                let destroy = Some(counting_destroy as DestroyCallback);
+                file_handle_builder_set_destroy(builder, destroy);
+                file_handle_builder_set_write(builder, Some(counting_write));
+                file_handle_builder_set_flush(builder, Some(counting_flush));
+                file_handle_builder_set_validation(builder, validate);
+                let handle = file_handle_builder_finish(builder).file_handle;
+                let before = CALLS.load(Ordering::SeqCst);
+
+                // what's left behind when a callback unwinds
+                (*handle).set_flag(
+                    FileHandle::POISONED | FileHandle::LEAK_ON_DESTROY,
+                );
+
+                assert!(file_handle_write(handle, b"a".as_ptr().cast(), 1) < 0);
+                assert!(file_handle_flush(handle) < 0);
+                file_handle_destroy(handle);
+
+                let calls = CALLS.load(Ordering::SeqCst);
+                assert_eq!(calls, before, "validate={}", validate);
+            }
         }
     }
 
@@ -208,7 +817,246 @@ mod tests {
             let ret = file_handle_flush(handle);
             assert_eq!(ret, 0);
 
+            // we can also get the original object back
+            assert_eq!(file_handle_as_external(handle), place);
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn build_an_external_file_handle_using_setters() {
+        unsafe {
+            let layout = Layout::new::<SharedBuffer>();
+            let name = CString::new("shared-buffer").unwrap();
+
+            let builder = file_handle_builder_new();
+            file_handle_builder_set_layout(
+                builder,
+                layout.size() as _,
+                layout.align() as _,
+            );
+            file_handle_builder_set_destroy(builder, Some(destroy_data));
+            file_handle_builder_set_write(builder, Some(write_data));
+            file_handle_builder_set_name(builder, name.as_ptr());
+            let FileHandleBuilder {
+                file_handle: handle,
+                place,
+            } = file_handle_builder_finish(builder);
+            assert!(!handle.is_null());
+
+            let buffer = SharedBuffer::default();
+            place.cast::<SharedBuffer>().write(buffer.clone());
+
+            let ret = file_handle_write(handle, b"asdf".as_ptr() as _, 4);
+            assert_eq!(ret, 4);
+            // flushing is optional
+            assert_eq!(file_handle_flush(handle), 0);
+
+            let got = CStr::from_ptr(file_handle_external_name(handle));
+            assert_eq!(got, name.as_c_str());
+            let got = CStr::from_ptr(file_handle_name(handle));
+            assert_eq!(got, name.as_c_str());
+            let owned = crate::OwnedFileHandle::from_raw(handle);
+            assert_eq!(owned.name(), Some("shared-buffer"));
+            let handle = owned.into_raw();
+
+            file_handle_destroy(handle);
+            assert_eq!(&*buffer.0.lock().unwrap(), b"asdf");
+        }
+    }
+
+    #[test]
+    fn null_builders_are_ignored() {
+        unsafe {
+            let builder = ptr::null_mut();
+            let name = CString::new("ignored").unwrap();
+
+            file_handle_builder_set_layout(builder, 8, 8);
+            file_handle_builder_set_destroy(builder, Some(destroy_data));
+            file_handle_builder_set_write(builder, Some(write_data));
+            file_handle_builder_set_flush(builder, None);
+            file_handle_builder_set_hint_size(builder, None);
+            file_handle_builder_set_name(builder, name.as_ptr());
+            file_handle_builder_set_validation(builder, true);
+            let got = file_handle_builder_finish(builder);
+
+            assert!(got.file_handle.is_null());
+            assert!(got.place.is_null());
+            file_handle_builder_free(builder);
+        }
+    }
+
+    #[test]
+    fn invalid_builders_produce_null() {
+        unsafe {
+            // no write callback
+            let builder = file_handle_builder_new();
+            let got = file_handle_builder_finish(builder);
+            assert!(got.file_handle.is_null());
+            assert!(got.place.is_null());
+
+            // the alignment isn't a power of two
+            let builder = file_handle_builder_new();
+            file_handle_builder_set_write(builder, Some(write_data));
+            file_handle_builder_set_layout(builder, 8, 3);
+            let got = file_handle_builder_finish(builder);
+            assert!(got.file_handle.is_null());
+
+            // negative sizes
+            let builder = file_handle_builder_new();
+            file_handle_builder_set_write(builder, Some(write_data));
+            file_handle_builder_set_layout(builder, -1, 8);
+            let got = file_handle_builder_finish(builder);
+            assert!(got.file_handle.is_null());
+
+            // extreme values
+            let layouts = [(c_int::MIN, 1), (8, c_int::MIN), (8, 0)];
+            for &(size, alignment) in &layouts {
+                let builder = file_handle_builder_new();
+                file_handle_builder_set_write(builder, Some(write_data));
+                file_handle_builder_set_layout(builder, size, alignment);
+                let got = file_handle_builder_finish(builder);
+                assert!(got.file_handle.is_null(), "{} {}", size, alignment);
+            }
+        }
+    }
+
+    c_unwind! {
+        /// Claims to have written more than it was given.
+        unsafe fn overclaiming_write(
+            _: *mut c_void,
+            _: *const c_char,
+            len: c_int,
+        ) -> c_int {
+            len + 1
+        }
+    }
+
+    c_unwind! {
+        /// Scribbles past the end of its 4 byte object.
+        unsafe fn overflowing_write(
+            object: *mut c_void,
+            _: *const c_char,
+            len: c_int,
+        ) -> c_int {
+            object.cast::<u8>().add(4).write(0);
+            len
+        }
+    }
+
+    unsafe fn validated_handle(write: WriteCallback) -> *mut FileHandle {
+        let builder = file_handle_builder_new();
+        file_handle_builder_set_layout(builder, 4, 1);
+        file_handle_builder_set_write(builder, Some(write));
+        file_handle_builder_set_validation(builder, true);
+        file_handle_builder_finish(builder).file_handle
+    }
+
+    #[test]
+    fn validation_catches_misbehaving_callbacks() {
+        let _global = crate::lifecycle::lock_global_state();
+
+        let callbacks: [WriteCallback; 2] =
+            [overclaiming_write, overflowing_write];
+
+        for &write in &callbacks {
+            unsafe {
+                let handle = validated_handle(write);
+
+                let ret = file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+                assert!(ret < 0);
+                assert!(file_handle_is_poisoned(handle));
+
+                file_handle_destroy(handle);
+            }
+        }
+    }
+
+    c_unwind! {
+        /// Returns whatever is stored in its object.
+        unsafe fn scripted_write(
+            object: *mut c_void,
+            _: *const c_char,
+            _: c_int,
+        ) -> c_int {
+            object.cast::<c_int>().read()
+        }
+    }
+
+    #[test]
+    fn out_of_range_callback_results_are_errors() {
+        let results = [
+            (c_int::MIN, FILE_HANDLE_BAD_CALLBACK_RESULT),
+            (c_int::MIN + 1, -c_int::MAX),
+            (c_int::MAX, FILE_HANDLE_BAD_CALLBACK_RESULT),
+            (5, FILE_HANDLE_BAD_CALLBACK_RESULT),
+            (4, 4),
+            // a callback's own errors are passed through untouched
+            (-75, -75),
+            (-22, -22),
+        ];
+        assert_eq!(
+            crate::ErrorDomain::of(FILE_HANDLE_BAD_CALLBACK_RESULT),
+            crate::ErrorDomain::Crate
+        );
+
+        for &(ret, expected) in &results {
+            unsafe {
+                let builder = file_handle_builder_new();
+                file_handle_builder_set_layout(builder, 4, 4);
+                file_handle_builder_set_write(builder, Some(scripted_write));
+                let got = file_handle_builder_finish(builder);
+                got.place.cast::<c_int>().write(ret);
+
+                let handle = got.file_handle;
+                let data = b"asdf".as_ptr().cast();
+                assert_eq!(file_handle_write(handle, data, 4), expected);
+                // nothing was actually wrong with the handle
+                assert!(!file_handle_is_poisoned(handle));
+
+                file_handle_destroy(handle);
+            }
+        }
+    }
+
+    #[test]
+    fn lengths_are_clamped_and_checked() {
+        assert_eq!(clamp_len(0), 0);
+        assert_eq!(clamp_len(c_int::MAX as usize), c_int::MAX);
+        assert_eq!(clamp_len(isize::MAX as usize), c_int::MAX);
+        assert_eq!(clamp_len(usize::MAX), c_int::MAX);
+
+        assert_eq!(callback_result(0, 0).unwrap(), 0);
+        let got = callback_result(c_int::MAX, isize::MAX as usize);
+        assert_eq!(got.unwrap(), c_int::MAX as usize);
+
+        unsafe {
+            let handle = new_null_file_handle();
+            let ret = file_handle_write(handle, ptr::null(), -1);
+            assert_eq!(ret, FILE_HANDLE_INVALID_LENGTH);
+            let ret = file_handle_write(handle, ptr::null(), c_int::MIN);
+            assert_eq!(ret, FILE_HANDLE_INVALID_LENGTH);
+            // both encodings agree
+            let ret = file_handle_write_v2(handle, ptr::null(), -1);
+            assert_eq!(ret, FILE_HANDLE_INVALID_LENGTH);
             file_handle_destroy(handle);
         }
     }
+
+    #[test]
+    fn validation_detects_a_second_destroy() {
+        let _global = crate::lifecycle::lock_global_state();
+
+        unsafe {
+            let handle = validated_handle(overclaiming_write);
+            file_handle_destroy(handle);
+
+            // the memory is still around, so this is reported instead
+            file_handle_destroy(handle);
+            assert!(file_handle_is_poisoned(handle));
+
+            crate::validation::shutdown();
+        }
+    }
 }
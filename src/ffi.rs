@@ -1,80 +1,533 @@
-pub use crate::external::{new_file_handle_builder, FileHandleBuilder};
+pub use crate::{
+    autoflush::{
+        file_handle_disable_autoflush, file_handle_enable_autoflush,
+        file_handle_enable_idle_flush,
+    },
+    backend::{
+        file_handle_capabilities, file_handle_name, CAPABILITY_DURABLE_FLUSH,
+        CAPABILITY_LOSSY, CAPABILITY_RAW_FD, CAPABILITY_SIZE_HINTS,
+    },
+    background::new_background_file_handle,
+    chunks::{memory_handle_next_chunk, new_chunked_memory_file_handle},
+    config::{
+        thin_trait_objects_configure, thin_trait_objects_current_config,
+        CONFIG_INVALID,
+    },
+    copy::{
+        cancel_token_cancel, cancel_token_destroy, cancel_token_new,
+        handle_copy, handle_copy_with_cancel, HANDLE_COPY_CANCELLED,
+    },
+    crash_flush::{
+        thin_trait_objects_emergency_flush_all,
+        thin_trait_objects_install_crash_flush_handler,
+    },
+    debug::file_handle_debug_dump,
+    errors::{
+        file_handle_error_domain, file_handle_error_value,
+        file_handle_status_name, thin_error_kind_from_code,
+        thin_error_kind_from_errno, thin_error_kind_name,
+        thin_trait_objects_register_user_status,
+        CRATE_ERROR_BAD_CALLBACK_RESULT, CRATE_ERROR_INVALID_LENGTH,
+        CRATE_ERROR_OUT_OF_BUDGET, CRATE_ERROR_POISONED,
+        CRATE_ERROR_QUOTA_EXCEEDED, CRATE_ERROR_RATE_LIMITED,
+        CRATE_ERROR_REENTRANT_CALL, CRATE_ERROR_SUSPENDED,
+        CRATE_ERROR_TIMED_OUT, USER_STATUS_INVALID, USER_STATUS_MAX,
+        USER_STATUS_MIN, USER_STATUS_TAKEN,
+    },
+    barrier::file_handle_barrier,
+    binary_text::{new_base64_file_handle, new_hex_file_handle},
+    bounded::{
+        bounded_memory_handle_chunk, bounded_memory_handle_chunk_count,
+        bounded_memory_handle_len, new_bounded_memory_file_handle,
+        BOUNDED_MEMORY_REJECT, BOUNDED_MEMORY_RING, BOUNDED_MEMORY_TRUNCATE,
+    },
+    budget::{
+        thin_trait_objects_memory_used, thin_trait_objects_set_memory_budget,
+        FILE_HANDLE_OUT_OF_BUDGET, MEMORY_BUDGET_UNLIMITED,
+    },
+    buffer_pool::buffer_pool_stats,
+    callback::{
+        bytes_callback_destroy, bytes_callback_invoke, new_bytes_callback,
+        new_void_callback, void_callback_destroy, void_callback_invoke,
+    },
+    external::{
+        file_handle_as_external, file_handle_builder_finish,
+        file_handle_builder_free, file_handle_builder_new,
+        file_handle_builder_set_destroy, file_handle_builder_set_flush,
+        file_handle_builder_set_hint_size, file_handle_builder_set_layout,
+        file_handle_builder_set_name, file_handle_builder_set_validation,
+        file_handle_builder_set_write, file_handle_external_name,
+        new_file_handle_builder, ExternalFileHandleBuilder, FileHandleBuilder,
+        FILE_HANDLE_BAD_CALLBACK_RESULT, FILE_HANDLE_INVALID_LENGTH,
+    },
+    exit_flush::file_handle_register_for_exit_flush,
+    flush_timeout::file_handle_flush_timeout,
+    freeze::{
+        file_handle_freeze, file_handle_is_frozen, file_handle_thaw,
+        FILE_HANDLE_SUSPENDED,
+    },
+    fmt_handle::{
+        fmt_handle_as_string, fmt_handle_destroy, fmt_handle_write_utf8,
+        new_file_handle_for_fmt_handle, new_fmt_handle_for_file_handle,
+        new_string_fmt_handle, FMT_HANDLE_ERROR, FMT_HANDLE_INVALID_UTF8,
+    },
+    group::{
+        handle_group_add, handle_group_close_all, handle_group_flush_all,
+        handle_group_len, handle_group_new,
+    },
+    history::file_handle_history,
+    idempotent::{file_handle_write_idempotent, new_idempotent_file_handle},
+    indirect::{file_handle_swap, new_indirect_file_handle},
+    last_error::{
+        file_handle_clear_last_error, file_handle_last_error_kind,
+        file_handle_last_error_message, file_handle_last_error_os_error,
+        LAST_ERROR_MESSAGE_CAPACITY,
+    },
+    latency::{
+        file_handle_enable_latency_stats, file_handle_flush_latency_percentile,
+        file_handle_latency_percentile,
+    },
+    layout::{file_handle_payload_offset, FILE_HANDLE_HEADER_SIZE},
+    lifecycle::{
+        thin_trait_objects_init, thin_trait_objects_shutdown, InitConfig,
+        INIT_INVALID_CONFIG,
+    },
+    log_bridge::new_log_crate_file_handle,
+    loopback::{loopback_assert_contains, loopback_handle_new},
+    lossy::{lossy_file_handle_stats, new_lossy_file_handle},
+    metrics::thin_trait_objects_set_metrics_sink,
+    open_options::{
+        new_file_handle_from_path_with_flags, FILE_OPEN_APPEND,
+        FILE_OPEN_REOPEN_ON_ERROR, FILE_OPEN_SYNC_ON_FLUSH,
+    },
+    optional::{
+        file_handle_read, file_handle_reserve, file_handle_seek,
+        file_handle_supports, file_handle_sync, FILE_HANDLE_SEEK_CUR,
+        FILE_HANDLE_SEEK_END, FILE_HANDLE_SEEK_SET, FILE_HANDLE_UNSUPPORTED,
+    },
+    ostream::{new_file_handle_from_ostream, new_file_handle_from_vtable},
+    pipe::{FILE_HANDLE_BROKEN_PIPE, PIPE_OPEN_NONBLOCK, PIPE_OPEN_WAIT},
+    quota::{
+        file_handle_quota_used, file_handle_set_quota,
+        file_handle_set_quota_callback, QuotaCallback, QUOTA_EXCEEDED,
+    },
+    read_handle::{
+        new_memory_read_handle, new_read_handle_from_path, read_handle_destroy,
+        read_handle_read,
+    },
+    recording::{
+        new_recording_file_handle, replay_recording, RECORDING_MAGIC,
+    },
+    redact::new_redacting_file_handle,
+    reentrant::FILE_HANDLE_REENTRANT_CALL,
+    reopen::file_handle_reopen_for_read,
+    rotation::{
+        file_handle_rotate_now, new_rotating_file_handle, ROTATE_DAILY,
+        ROTATE_HOURLY, ROTATE_NEVER,
+    },
+    scoped::{file_handle_child, new_scoped_file_handle},
+    sequenced::new_sequenced_file_handle,
+    sharded::new_sharded_file_handle,
+    shared_sink::{
+        file_handle_set_priority, file_handle_set_rate, new_shared_sink,
+        shared_sink_destroy, shared_sink_new_producer,
+        FILE_HANDLE_RATE_LIMITED,
+    },
+    short_write::new_short_write_file_handle,
+    shrink::{
+        file_handle_set_shrink_policy, file_handle_shrink_to_fit, SHRINK_NEVER,
+    },
+    splice::file_handle_splice,
+    stats::file_handle_stats_snapshot,
+    tagged::file_handle_write_tagged,
+    thread_stats::{
+        file_handle_enable_thread_stats, file_handle_thread_stats,
+        thin_trait_objects_current_thread_id,
+    },
+    transcode::new_transcoding_file_handle,
+    version::{
+        thin_trait_objects_build_info, thin_trait_objects_symbol_prefix,
+        thin_trait_objects_version,
+    },
+    watchdog::{
+        file_handle_set_watchdog, file_handle_set_watchdog_callback,
+        FILE_HANDLE_TIMED_OUT, WATCHDOG_CALLBACK, WATCHDOG_FAIL, WATCHDOG_LOG,
+    },
+    wiring::new_file_handle_from_wiring,
+    write_filter::{file_handle_set_write_filter, FilterAlloc, WriteFilter},
+    write_owned::{file_handle_write_owned, FreeCallback},
+    zero_write::{
+        file_handle_set_zero_write_policy, ZERO_WRITE_ERROR,
+        ZERO_WRITE_PASS_THROUGH, ZERO_WRITE_RETRY,
+    },
+};
+#[cfg(feature = "tracing")]
+pub use crate::trace_spans::{
+    file_handle_set_tracing_level, TRACE_LEVEL_ERRORS, TRACE_LEVEL_OFF,
+    TRACE_LEVEL_SPANS,
+};
+#[cfg(unix)]
+pub use crate::{
+    os_handle::new_file_handle_from_fd,
+    pipe::{new_fifo_file_handle, new_fifo_file_handle_with_policy},
+    read_handle::read_handle_as_fd,
+    stdio::file_handle_make_pipe_writer_fd,
+};
+#[cfg(windows)]
+pub use crate::{
+    os_handle::{file_handle_as_win32_handle, new_file_handle_from_win32_handle},
+    overlapped::new_overlapped_file_handle,
+    pipe::{
+        new_named_pipe_file_handle, new_named_pipe_file_handle_with_policy,
+    },
+};
 
-use crate::FileHandle;
+use crate::{ChunkedBuffer, FileHandle};
 use std::{
+    convert::TryFrom,
     ffi::CStr,
     fs::File,
     os::raw::{c_char, c_int},
     ptr,
 };
 
-/// Create a new [`FileHandle`] which throws away all data written to it.
-#[no_mangle]
-pub unsafe extern "C" fn new_null_file_handle() -> *mut FileHandle {
-    FileHandle::for_writer(std::io::sink())
+export! {
+    /// Create a new [`FileHandle`] which throws away all data written to it.
+    pub unsafe extern "C" fn new_null_file_handle() -> *mut FileHandle {
+        FileHandle::for_writer(std::io::sink())
+    }
 }
 
-/// Create a new [`FileHandle`] which writes directly to stdout.
-#[no_mangle]
-pub unsafe extern "C" fn new_stdout_file_handle() -> *mut FileHandle {
-    FileHandle::for_writer(std::io::stdout())
+export! {
+    /// Create a new [`FileHandle`] which writes to a growable buffer in memory.
+    ///
+    /// The buffer's contents can be inspected using
+    /// [`file_handle_as_memory()`]. Writes fail with
+    /// [`FILE_HANDLE_OUT_OF_BUDGET`] once the memory budget set with
+    /// [`thin_trait_objects_set_memory_budget()`] is used up.
+    pub unsafe extern "C" fn new_memory_file_handle() -> *mut FileHandle {
+        FileHandle::for_memory(Vec::<u8>::new())
+    }
 }
 
-/// Create a new [`FileHandle`] which will write to a file on disk.
-#[no_mangle]
-pub unsafe extern "C" fn new_file_handle_from_path(
-    path: *const c_char,
-) -> *mut FileHandle {
-    let path = match CStr::from_ptr(path).to_str() {
-        Ok(p) => p,
-        Err(_) => return ptr::null_mut(),
-    };
+export! {
+    /// Create a new [`FileHandle`] which writes directly to stdout.
+    pub unsafe extern "C" fn new_stdout_file_handle() -> *mut FileHandle {
+        FileHandle::for_writer(std::io::stdout())
+    }
+}
 
-    let f = match File::create(path) {
-        Ok(f) => f,
-        Err(_) => return ptr::null_mut(),
-    };
+export! {
+    /// Create a new [`FileHandle`] which will write to a file on disk.
+    pub unsafe extern "C" fn new_file_handle_from_path(
+        path: *const c_char,
+    ) -> *mut FileHandle {
+        new_file_handle_from_path_with_sync(path, false)
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which will write to a file on disk, and if
+    /// `sync_on_flush` is set, sync it to durable storage every time it is
+    /// flushed (see [`FileHandle::for_file()`]).
+    pub unsafe extern "C" fn new_file_handle_from_path_with_sync(
+        path: *const c_char,
+        sync_on_flush: bool,
+    ) -> *mut FileHandle {
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(p) => p,
+            Err(_) => return ptr::null_mut(),
+        };
+
+        let f = match File::create(path) {
+            Ok(f) => f,
+            Err(_) => return ptr::null_mut(),
+        };
+
+        let handle = FileHandle::for_file(f, sync_on_flush);
+        (*handle).cold.path = Some(path.into());
+        handle
+    }
+}
+
+c_unwind! {
+    /// Free the [`FileHandle`], calling any destructors and cleaning up any
+    /// resources being used.
+    pub unsafe extern fn file_handle_destroy(handle: *mut FileHandle) {
+        FileHandle::dispatch_destroy(handle);
+    }
+}
+
+c_unwind! {
+    /// Write some data to the file handle, returning the number of bytes
+    /// written.
+    ///
+    /// The return value is negative when writing fails, or
+    /// [`FILE_HANDLE_INVALID_LENGTH`] if `len` is negative.
+    pub unsafe extern fn file_handle_write(
+        handle: *mut FileHandle,
+        data: *const c_char,
+        len: c_int,
+    ) -> c_int {
+        let len = match usize::try_from(len) {
+            Ok(len) => len,
+            Err(_) => return FILE_HANDLE_INVALID_LENGTH,
+        };
+        let data = std::slice::from_raw_parts(data as *const u8, len);
+
+        match FileHandle::dispatch_write(handle, data) {
+            Ok(bytes_written) => bytes_written as c_int,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
 
-    FileHandle::for_writer(f)
+c_unwind! {
+    /// Like [`file_handle_write()`], except errors are reported using the
+    /// platform-independent encoding described by [`ErrorDomain`].
+    ///
+    /// [`ErrorDomain`]: crate::ErrorDomain
+    pub unsafe extern fn file_handle_write_v2(
+        handle: *mut FileHandle,
+        data: *const c_char,
+        len: c_int,
+    ) -> c_int {
+        let len = match usize::try_from(len) {
+            Ok(len) => len,
+            Err(_) => {
+                let e = crate::external::invalid_length();
+                return crate::errors::encode_error(&e);
+            },
+        };
+        let data = std::slice::from_raw_parts(data as *const u8, len);
+
+        match FileHandle::dispatch_write(handle, data) {
+            Ok(bytes_written) => bytes_written as c_int,
+            Err(e) => crate::errors::encode_error(&e),
+        }
+    }
+}
+
+c_unwind! {
+    /// Write several buffers to the file handle in one call.
+    ///
+    /// The result of each write (the number of bytes written, or a negative
+    /// value on failure) is stored in the corresponding element of
+    /// `out_results` if it isn't `null`. Every buffer is attempted, even if an
+    /// earlier write failed, unless the handle gets poisoned part way through.
+    ///
+    /// Returns `0` if every write succeeded, otherwise the result of the first
+    /// write which failed.
+    pub unsafe extern fn file_handle_write_many(
+        handle: *mut FileHandle,
+        buffers: *const FfiSlice,
+        count: usize,
+        out_results: *mut c_int,
+    ) -> c_int {
+        let buffers = if buffers.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(buffers, count)
+        };
+        let mut first_error = 0;
+
+        FileHandle::dispatch_write_many(handle, buffers, |i, result| {
+            let ret = match result {
+                Ok(bytes_written) => bytes_written as c_int,
+                Err(e) => crate::forbid_panics::errno_of(e),
+            };
+
+            if ret < 0 && first_error == 0 {
+                first_error = ret;
+            }
+            if !out_results.is_null() {
+                out_results.add(i).write(ret);
+            }
+        });
+
+        first_error
+    }
+}
+
+c_unwind! {
+    /// Flush this output stream, ensuring that all intermediately buffered
+    /// contents reach their destination.
+    ///
+    /// Returns `0` on success or a negative value on failure.
+    pub unsafe extern fn file_handle_flush(handle: *mut FileHandle) -> c_int {
+        match FileHandle::dispatch_flush(handle) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+c_unwind! {
+    /// Like [`file_handle_flush()`], except errors are reported using the
+    /// platform-independent encoding described by [`ErrorDomain`].
+    ///
+    /// [`ErrorDomain`]: crate::ErrorDomain
+    pub unsafe extern fn file_handle_flush_v2(
+        handle: *mut FileHandle,
+    ) -> c_int {
+        match FileHandle::dispatch_flush(handle) {
+            Ok(_) => 0,
+            Err(e) => crate::errors::encode_error(&e),
+        }
+    }
 }
 
-/// Free the [`FileHandle`], calling any destructors and cleaning up any
-/// resources being used.
-#[no_mangle]
-pub unsafe extern "C" fn file_handle_destroy(handle: *mut FileHandle) {
-    let destructor = (*handle).destroy;
-    destructor(handle);
+export! {
+    /// Check whether the [`FileHandle`] has been poisoned and will reject any
+    /// further operations.
+    ///
+    /// A handle is poisoned when its object panics, or when it wraps another
+    /// handle which was poisoned.
+    pub unsafe extern "C" fn file_handle_is_poisoned(
+        handle: *mut FileHandle,
+    ) -> bool {
+        (*handle).is_poisoned()
+    }
 }
 
-/// Write some data to the file handle, returning the number of bytes written.
+c_unwind! {
+    /// Let the [`FileHandle`] know that roughly `bytes` more bytes are about
+    /// to be written, so it can allocate space up front.
+    ///
+    /// This is only a hint, and handles which don't support it will ignore it.
+    /// Returns `0` on success or a negative value on failure.
+    pub unsafe extern fn file_handle_hint_total_size(
+        handle: *mut FileHandle,
+        bytes: u64,
+    ) -> c_int {
+        let hint_size = match (*handle).cold.hint_size {
+            Some(hint_size) => hint_size,
+            None => return 0,
+        };
+
+        match hint_size(handle, bytes) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+/// A borrowed, FFI-safe view of a byte buffer.
 ///
-/// The return value is negative when writing fails.
-#[no_mangle]
-pub unsafe extern "C" fn file_handle_write(
-    handle: *mut FileHandle,
-    data: *const c_char,
-    len: c_int,
-) -> c_int {
-    let write = (*handle).write;
-    let data = std::slice::from_raw_parts(data as *const u8, len as usize);
+/// An "empty" [`FfiSlice`] with a `null` data pointer is used to indicate
+/// that there is no buffer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct FfiSlice {
+    /// A pointer to the first byte.
+    pub data: *const u8,
+    /// The number of bytes in the buffer.
+    pub len: usize,
+}
 
-    match write(handle, data) {
-        Ok(bytes_written) => bytes_written as c_int,
-        Err(e) => -e.raw_os_error().unwrap_or(1),
+impl FfiSlice {
+    /// A slice with no data.
+    pub const NULL: FfiSlice = FfiSlice {
+        data: ptr::null(),
+        len: 0,
+    };
+
+    /// Create a [`FfiSlice`] which borrows from a Rust slice.
+    pub fn new(data: &[u8]) -> Self {
+        FfiSlice {
+            data: data.as_ptr(),
+            len: data.len(),
+        }
+    }
+
+    /// Get the data being pointed to as a Rust slice.
+    ///
+    /// # Safety
+    ///
+    /// Unless it is [`FfiSlice::NULL`], the [`FfiSlice`] must point to `len`
+    /// bytes which are valid for the lifetime `'a`.
+    pub unsafe fn as_slice<'a>(self) -> &'a [u8] {
+        if self.data.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(self.data, self.len)
+        }
     }
 }
 
-/// Flush this output stream, ensuring that all intermediately buffered contents
-/// reach their destination.
+/// A borrowed, FFI-safe view of a UTF-8 string.
 ///
-/// Returns `0` on success or a negative value on failure.
-#[no_mangle]
-pub unsafe extern "C" fn file_handle_flush(handle: *mut FileHandle) -> c_int {
-    let flush = (*handle).flush;
+/// The string is also null-terminated (the terminator isn't included in
+/// `len`), so `data` can be passed straight to functions like `printf()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct FfiStr {
+    /// A pointer to the first byte.
+    pub data: *const c_char,
+    /// The length of the string in bytes, not counting the null terminator.
+    pub len: usize,
+}
+
+impl FfiStr {
+    /// Create a [`FfiStr`] from a string literal which ends in `"\0"`.
+    pub(crate) fn from_nul_terminated(s: &'static str) -> Self {
+        FfiStr {
+            data: s.as_ptr() as *const c_char,
+            len: s.len() - 1,
+        }
+    }
+
+    /// Get the string being pointed to.
+    ///
+    /// # Safety
+    ///
+    /// The [`FfiStr`] must point to `len` bytes of valid UTF-8 which live for
+    /// the lifetime `'a`.
+    pub unsafe fn as_str<'a>(self) -> &'a str {
+        let bytes =
+            std::slice::from_raw_parts(self.data as *const u8, self.len);
+        std::str::from_utf8_unchecked(bytes)
+    }
+}
+
+export! {
+    /// Get the contents of a [`FileHandle`] created with
+    /// [`new_memory_file_handle()`] or [`new_chunked_memory_file_handle()`].
+    ///
+    /// The returned buffer is only valid until the next time the handle is
+    /// written to or destroyed, and [`FfiSlice::NULL`] is returned if the
+    /// handle isn't a memory handle.
+    pub unsafe extern "C" fn file_handle_as_memory(
+        handle: *mut FileHandle,
+    ) -> FfiSlice {
+        if let Some(buffer) = FileHandle::downcast_raw::<ChunkedBuffer>(handle)
+        {
+            return FfiSlice::new((*buffer).as_bytes());
+        }
 
-    match flush(handle) {
-        Ok(_) => 0,
-        Err(e) => -e.raw_os_error().unwrap_or(1),
+        match FileHandle::downcast_raw::<Vec<u8>>(handle) {
+            Some(buffer) => FfiSlice::new(&*buffer),
+            None => FfiSlice::NULL,
+        }
+    }
+}
+
+export! {
+    /// Get the file descriptor used by a [`FileHandle`] created with
+    /// [`new_file_handle_from_path()`] or [`new_file_handle_from_fd()`].
+    ///
+    /// The file descriptor is still owned by the [`FileHandle`] and must not be
+    /// closed. Returns `-1` if the handle doesn't wrap a file.
+    #[cfg(unix)]
+    pub unsafe extern "C" fn file_handle_as_fd(
+        handle: *mut FileHandle,
+    ) -> c_int {
+        use std::os::unix::io::AsRawFd;
+
+        match FileHandle::downcast_raw::<File>(handle) {
+            Some(f) => (*f).as_raw_fd(),
+            None => -1,
+        }
     }
 }
 
@@ -148,6 +601,42 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn bulk_writes_report_the_same_codes_as_single_writes() {
+        struct RateLimited;
+        impl Write for RateLimited {
+            fn write(&mut self, _buf: &[u8]) -> Result<usize, Error> {
+                Err(crate::errors::crate_status_error(
+                    std::io::ErrorKind::WouldBlock,
+                    CRATE_ERROR_RATE_LIMITED,
+                ))
+            }
+
+            fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+        }
+
+        let buffers = [FfiSlice::new(b"asdf")];
+        let mut results = [0; 1];
+
+        unsafe {
+            let handle = FileHandle::for_writer(RateLimited);
+
+            let single = file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            let ret = file_handle_write_many(
+                handle,
+                buffers.as_ptr(),
+                buffers.len(),
+                results.as_mut_ptr(),
+            );
+
+            assert_eq!(single, crate::shared_sink::FILE_HANDLE_RATE_LIMITED);
+            assert_eq!(ret, single);
+            assert_eq!(results, [single]);
+
+            file_handle_destroy(handle);
+        }
+    }
+
     #[derive(Debug, Clone, Default)]
     pub(crate) struct SharedBuffer(pub(crate) Arc<Mutex<Vec<u8>>>);
 
@@ -188,4 +677,179 @@ pub(crate) mod tests {
 
         assert_eq!(got, msg);
     }
+
+    #[test]
+    fn inspect_a_memory_handle() {
+        let msg = "Hello, World!";
+
+        unsafe {
+            let handle = new_memory_file_handle();
+            assert_eq!(file_handle_as_memory(handle).as_slice(), b"");
+
+            file_handle_write(handle, msg.as_ptr() as *const _, msg.len() as _);
+
+            let got = file_handle_as_memory(handle);
+            assert_eq!(got.as_slice(), msg.as_bytes());
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn downcasting_to_the_wrong_type_returns_null() {
+        unsafe {
+            let handle = new_null_file_handle();
+
+            assert_eq!(file_handle_as_memory(handle), FfiSlice::NULL);
+            assert!(file_handle_as_external(handle).is_null());
+            #[cfg(unix)]
+            assert_eq!(file_handle_as_fd(handle), -1);
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn memory_handles_preallocate_when_given_a_size_hint() {
+        unsafe {
+            let handle = new_memory_file_handle();
+
+            let ret = file_handle_hint_total_size(handle, 1024);
+            assert_eq!(ret, 0);
+
+            let buffer = FileHandle::downcast_raw::<Vec<u8>>(handle).unwrap();
+            assert!((*buffer).capacity() >= 1024);
+            assert!((*buffer).is_empty());
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn impossible_size_hints_are_ignored() {
+        unsafe {
+            let handle = new_memory_file_handle();
+            file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+
+            // far more than could ever be allocated
+            assert_eq!(file_handle_hint_total_size(handle, 1 << 60), 0);
+            assert_eq!(file_handle_hint_total_size(handle, u64::MAX), 0);
+            assert_eq!(file_handle_as_memory(handle).as_slice(), b"asdf");
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn size_hints_are_ignored_by_other_handles() {
+        unsafe {
+            let handle = new_null_file_handle();
+            assert_eq!(file_handle_hint_total_size(handle, u64::MAX), 0);
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn size_hints_dont_change_a_files_length() {
+        let path = std::env::temp_dir().join("size_hints_dont_change_length");
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let handle = new_file_handle_from_path(c_path.as_ptr());
+            assert!(!handle.is_null());
+
+            assert_eq!(file_handle_hint_total_size(handle, 4096), 0);
+            file_handle_write(handle, b"asdf".as_ptr() as *const _, 4);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_several_buffers_at_once() {
+        let buffers = [
+            FfiSlice::new(b"Hello"),
+            FfiSlice::NULL,
+            FfiSlice::new(b", World!"),
+        ];
+        let mut results = [0; 3];
+
+        unsafe {
+            let handle = new_memory_file_handle();
+
+            let ret = file_handle_write_many(
+                handle,
+                buffers.as_ptr(),
+                buffers.len(),
+                results.as_mut_ptr(),
+            );
+
+            assert_eq!(ret, 0);
+            assert_eq!(results, [5, 0, 8]);
+            let got = file_handle_as_memory(handle);
+            assert_eq!(got.as_slice(), b"Hello, World!");
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn bulk_writes_report_each_failure() {
+        /// Fails every second write, then panics on the fourth.
+        #[derive(Default)]
+        struct Flaky(usize);
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+                self.0 += 1;
+                match self.0 {
+                    2 => Err(Error::from_raw_os_error(42)),
+                    4 => panic!("Oops..."),
+                    _ => Ok(buf.len()),
+                }
+            }
+
+            fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+        }
+
+        let buffers = [FfiSlice::new(b"asdf"); 5];
+        let mut results = [0; 5];
+
+        unsafe {
+            let handle = FileHandle::for_writer(Flaky::default());
+
+            let ret = file_handle_write_many(
+                handle,
+                buffers.as_ptr(),
+                buffers.len(),
+                results.as_mut_ptr(),
+            );
+
+            assert_eq!(ret, -42);
+            assert_eq!(&results[..3], &[4, -42, 4]);
+            // the panic poisons the handle, so the last two are never written
+            assert!(results[3] < 0);
+            assert_eq!(results[3], results[4]);
+            assert!(file_handle_is_poisoned(handle));
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn hot_fields_are_at_the_start_of_the_header() {
+        unsafe {
+            let handle = new_null_file_handle();
+            let start = handle as usize;
+
+            let write = &(*handle).write as *const _ as usize;
+            let flags = &(*handle).flags as *const _ as usize;
+            assert!(write + std::mem::size_of::<usize>() - start <= 16);
+            assert!(flags + std::mem::size_of::<u32>() - start <= 16);
+
+            file_handle_destroy(handle);
+        }
+    }
 }
@@ -1,9 +1,24 @@
+use crate::{
+    backend::{Capabilities, WriterBackend},
+    budget::{Charge, MemoryBuffer},
+    extensions::Extensions, last_error::ErrorSlot, quota::Quota,
+    sync::AtomicU32, FfiSlice, OwnershipEvent, TaggedWrite, ZeroWritePolicy,
+};
 use std::{
     alloc::Layout,
     any::{Any, TypeId},
-    fmt::{Display, Formatter},
-    io::{Error, ErrorKind, Write},
-    sync::Mutex,
+    convert::TryFrom,
+    ffi::CString,
+    fs::File,
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::raw::c_void,
+    path::PathBuf,
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 /// A FFI-safe version of the trait object, [`dyn std::io::Write`][Write].
@@ -18,47 +33,997 @@ use std::{
 /// pointer. Copying a [`FileHandle`] to the stack will result in a phenomenon
 /// called [*Object Slicing*][slicing], corrupting the `FileHandle`.
 ///
+/// # Thread Safety
+///
+/// Every [`FileHandle`] is `Send + Sync` by construction, so it may be moved
+/// to or accessed from any thread. However, `write` and `flush` need exclusive
+/// access to the underlying object, so the caller must make sure calls on the
+/// same handle never overlap (e.g. by wrapping it in a mutex).
+///
+/// Handles created with [`FileHandle::for_concurrent_writer()`] are the
+/// exception. Their object is only ever accessed via a shared reference, so
+/// calls may overlap freely.
+///
+/// The only state shared between calls is the header's `flags` word, which is
+/// atomic, and a record of the last error, which has its own lock. Setting a
+/// flag (e.g. poisoning after a panic) uses `Release` ordering and every check
+/// uses `Acquire`, so once one thread observes that a handle is poisoned it
+/// also observes everything that happened before the panic. The tests in
+/// `src/model.rs` check writes, flushes and poisoning racing each other
+/// (run them with `cargo test --features loom-tests`).
+///
 /// [slicing]: https://stackoverflow.com/questions/274626/what-is-object-slicing
-#[derive(Clone)]
 #[repr(C)]
 pub struct FileHandle {
+    // Note: Everything touched by a successful write comes first, with the
+    // write function and flags in the first 16 bytes
+    pub(crate) write: unsafe fn(*mut FileHandle, &[u8]) -> Result<usize, Error>,
+    pub(crate) flags: AtomicU32,
+    /// A [`ZeroWritePolicy`], packed into an integer.
+    pub(crate) zero_write_policy: AtomicU32,
+    pub(crate) flush: unsafe fn(*mut FileHandle) -> Result<(), Error>,
+    /// Write several buffers in one go, passing each buffer's outcome to a
+    /// callback.
+    pub(crate) write_many: WriteManyFn,
+    /// Optional state which is allocated on demand.
+    pub(crate) extensions: AtomicPtr<Extensions>,
+    /// Everything which is rarely needed, kept out of the way of writes.
+    pub(crate) cold: Box<ColdHeader>,
+}
+
+/// The parts of a [`FileHandle`] which are only used when creating,
+/// destroying, or inspecting a handle, or when something goes wrong.
+pub(crate) struct ColdHeader {
     pub(crate) layout: Layout,
+    /// How far the object is from the start of the handle (see
+    /// [`FileHandle::payload_ptr()`]).
+    pub(crate) payload_offset: usize,
     pub(crate) type_id: TypeId,
-    pub(crate) poisoned: bool,
+    /// The name of the object's type, for debugging.
+    pub(crate) type_name: &'static str,
     pub(crate) destroy: unsafe fn(*mut FileHandle),
-    pub(crate) write: unsafe fn(*mut FileHandle, &[u8]) -> Result<usize, Error>,
-    pub(crate) flush: unsafe fn(*mut FileHandle) -> Result<(), Error>,
+    /// How long to wait for the object's destructor before giving up on it
+    /// (see [`FileHandle::for_writer_with_drop_timeout()`]).
+    pub(crate) drop_timeout: Option<Duration>,
+    /// Frees the handle's allocation using the global allocator of whichever
+    /// library created it, which may not be ours when handles are passed
+    /// between dynamic libraries.
+    pub(crate) dealloc: DeallocFn,
+    /// An optional hook letting the object prepare for `bytes` more bytes of
+    /// data being written.
+    pub(crate) hint_size: Option<HintSizeFn>,
+    /// The most recent error, allocated up front so recording it can't fail.
+    pub(crate) last_error: ErrorSlot,
+    pub(crate) name: Option<CString>,
+    /// The file the object writes to, if it was opened from a path.
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) capabilities: Capabilities,
+    /// Optional operations, which report [`FILE_HANDLE_UNSUPPORTED`] when
+    /// missing.
+    ///
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub(crate) seek: Option<SeekFn>,
+    pub(crate) read: Option<ReadFn>,
+    /// Makes sure everything written has reached durable storage, optionally
+    /// skipping metadata which isn't needed to read the data back.
+    pub(crate) sync: Option<SyncFn>,
+    /// Writes data along with a tag (see [`TaggedWrite`]), for objects which
+    /// understand tags.
+    ///
+    /// [`TaggedWrite`]: crate::TaggedWrite
+    pub(crate) write_tagged: Option<WriteTaggedFn>,
+    /// The memory a memory handle's buffer is charged for (see
+    /// [`FileHandle::for_memory()`]).
+    pub(crate) budget: Charge,
+    /// How much a memory handle's buffer has allocated, so the charge can be
+    /// brought up to date when the buffer is changed behind its back.
+    pub(crate) allocated: Option<AllocatedFn>,
 }
 
+/// Free an allocation given its address, size, and alignment.
+pub(crate) type DeallocFn = unsafe extern "C" fn(*mut u8, usize, usize);
+
+/// Return memory to this copy of the crate's global allocator.
+pub(crate) unsafe extern "C" fn dealloc_global(
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+) {
+    std::alloc::dealloc(ptr, Layout::from_size_align_unchecked(size, align));
+}
+
+pub(crate) type HintSizeFn =
+    unsafe fn(*mut FileHandle, u64) -> Result<(), Error>;
+pub(crate) type AllocatedFn = unsafe fn(*mut FileHandle) -> usize;
+pub(crate) type SeekFn =
+    unsafe fn(*mut FileHandle, SeekFrom) -> Result<u64, Error>;
+pub(crate) type ReadFn =
+    unsafe fn(*mut FileHandle, &mut [u8]) -> Result<usize, Error>;
+pub(crate) type SyncFn = unsafe fn(*mut FileHandle, bool) -> Result<(), Error>;
+pub(crate) type WriteTaggedFn =
+    unsafe fn(*mut FileHandle, &[u8], u32) -> Result<usize, Error>;
+
+/// Write each buffer in turn, reporting the result of each write.
+///
+/// An error is returned if the object panicked or the handle was poisoned,
+/// in which case the buffers that weren't reported never got written.
+pub(crate) type WriteManyFn = unsafe fn(
+    *mut FileHandle,
+    &[FfiSlice],
+    &mut dyn FnMut(usize, Result<usize, Error>),
+) -> Result<(), Error>;
+
 impl FileHandle {
+    /// Set when the handle can no longer be used, either because a panic
+    /// occurred while calling into the object or because a handle it wraps
+    /// was poisoned. A poisoned handle rejects all further operations.
+    pub(crate) const POISONED: u32 = 1 << 0;
+    /// Set when a panic occurred while calling into the object, meaning its
+    /// destructor must not be run.
+    pub(crate) const LEAK_ON_DESTROY: u32 = 1 << 1;
+    /// Set when the handle has been registered to be flushed on exit.
+    pub(crate) const FLUSH_ON_EXIT: u32 = 1 << 2;
+    /// Set while the handle is frozen (see `file_handle_freeze()`).
+    pub(crate) const FROZEN: u32 = 1 << 3;
+    /// Set when the watchdog gave up on an operation which got stuck (see
+    /// `file_handle_set_watchdog()`).
+    pub(crate) const TIMED_OUT: u32 = 1 << 4;
+    /// Set when panics from the object shouldn't be caught (see
+    /// [`FileHandle::for_writer_unguarded()`]).
+    pub(crate) const NO_PANIC_GUARD: u32 = 1 << 5;
+    /// Set when every successful flush should be followed by a sync (see
+    /// [`FileHandle::for_file()`]).
+    pub(crate) const SYNC_ON_FLUSH: u32 = 1 << 6;
+    /// Set while a flush started by `file_handle_flush_timeout()` is still
+    /// running on a helper thread.
+    pub(crate) const FLUSHING: u32 = 1 << 7;
+    /// Set while the handle is lent to native code (see
+    /// [`OwnedFileHandle::loan()`][crate::OwnedFileHandle::loan]).
+    pub(crate) const LOANED: u32 = 1 << 8;
+    /// Set when something tried to destroy the handle while it was on loan.
+    pub(crate) const DESTROYED_ON_LOAN: u32 = 1 << 9;
+
     /// Create a new [`FileHandle`] that wraps a Rust [`std::io::Write`]r.
     pub fn for_writer<W>(writer: W) -> *mut FileHandle
     where
         W: Write + Send + Sync + 'static,
     {
-        let repr = Repr {
-            base: FileHandle::vtable::<W>(),
+        FileHandle::from_repr(Repr {
+            base: FileHandle::vtable::<W>(
+                write::<W>,
+                flush::<W>,
+                write_many::<W>,
+            ),
             writer,
-        };
+        })
+    }
+
+    /// Create a new [`FileHandle`] like [`FileHandle::for_writer()`], except
+    /// panics from `writer` aren't caught.
+    ///
+    /// This saves the cost of guarding every call, but a panic will unwind
+    /// straight through the handle without poisoning it (aborting the process
+    /// if it reaches an `extern "C"` function), so it should only be used
+    /// for writers which never panic. With the `forbid-panics` feature,
+    /// panics are still caught by the handle.
+    pub fn for_writer_unguarded<W>(writer: W) -> *mut FileHandle
+    where
+        W: Write + Send + Sync + 'static,
+    {
+        let handle = FileHandle::for_writer(writer);
+        unsafe { (*handle).set_flag(FileHandle::NO_PANIC_GUARD) };
+        handle
+    }
+
+    /// Create a new [`FileHandle`] for an in-memory buffer, which fails with
+    /// [`FILE_HANDLE_OUT_OF_BUDGET`] instead of growing past the memory
+    /// budget.
+    ///
+    /// [`FILE_HANDLE_OUT_OF_BUDGET`]: crate::FILE_HANDLE_OUT_OF_BUDGET
+    pub(crate) fn for_memory<W: MemoryBuffer>(buffer: W) -> *mut FileHandle {
+        let mut base = FileHandle::vtable::<W>(
+            write_budgeted::<W>,
+            flush::<W>,
+            write_many_budgeted::<W>,
+        );
+        base.cold.budget.update(buffer.allocated());
+        base.cold.allocated = Some(allocated::<W>);
+        if TypeId::of::<W>() == TypeId::of::<Vec<u8>>() {
+            base.cold.hint_size = Some(hint_size_budgeted);
+        }
+
+        FileHandle::from_repr(Repr {
+            base,
+            writer: buffer,
+        })
+    }
+
+    /// Create a new [`FileHandle`] for a writer which understands the tags
+    /// passed to [`file_handle_write_tagged()`].
+    ///
+    /// [`file_handle_write_tagged()`]: crate::file_handle_write_tagged
+    pub fn for_tagged_writer<W>(writer: W) -> *mut FileHandle
+    where
+        W: TaggedWrite + Send + Sync + 'static,
+    {
+        let handle = FileHandle::for_writer(writer);
+        unsafe { (*handle).cold.write_tagged = Some(write_tagged::<W>) };
+        handle
+    }
+
+    /// Create a new [`FileHandle`] like [`FileHandle::for_writer()`], except
+    /// destroying it gives up on `writer`'s destructor after `timeout`.
+    ///
+    /// The writer is dropped on a helper thread. If that takes longer than
+    /// `timeout` (e.g. because it flushes to a hung network filesystem), the
+    /// destroy returns anyway and a warning is logged via the global
+    /// [`LogSink`][crate::LogSink], leaving the writer to finish (or leak) in
+    /// the background instead of blocking the caller.
+    pub fn for_writer_with_drop_timeout<W>(
+        writer: W,
+        timeout: Duration,
+    ) -> *mut FileHandle
+    where
+        W: Write + Send + Sync + 'static,
+    {
+        let mut base =
+            FileHandle::vtable::<W>(write::<W>, flush::<W>, write_many::<W>);
+        base.cold.destroy = destroy_with_timeout::<W>;
+        base.cold.drop_timeout = Some(timeout);
+
+        FileHandle::from_repr(Repr { base, writer })
+    }
+
+    /// Create a new [`FileHandle`] for a [`File`], optionally making every
+    /// flush also sync the file to disk.
+    ///
+    /// Flushing a [`File`] normally only hands the data to the OS, so it can
+    /// still be lost if the machine crashes. With `sync_on_flush`, each flush
+    /// is followed by [`File::sync_all()`] and the handle reports
+    /// [`Capabilities::DURABLE_FLUSH`].
+    pub fn for_file(file: File, sync_on_flush: bool) -> *mut FileHandle {
+        let handle = FileHandle::for_writer(file);
+
+        if sync_on_flush {
+            unsafe {
+                (*handle).set_flag(FileHandle::SYNC_ON_FLUSH);
+                let capabilities = &mut (*handle).cold.capabilities;
+                *capabilities = *capabilities | Capabilities::DURABLE_FLUSH;
+            }
+        }
+
+        handle
+    }
+
+    /// Make a handle created by [`FileHandle::for_file()`] reopen `path` and
+    /// retry once when a write fails because the file was removed or went
+    /// stale (see [`FileOpenOptions::reopen_on_error()`]).
+    ///
+    /// [`FileOpenOptions::reopen_on_error()`]:
+    /// crate::FileOpenOptions::reopen_on_error
+    pub(crate) unsafe fn reopen_on_error(
+        handle: *mut FileHandle,
+        path: PathBuf,
+    ) {
+        (*handle).cold.path = Some(path);
+        (*handle).write = write_reopening;
+        (*handle).write_many = write_many_reopening;
+    }
+
+    /// Create a new [`FileHandle`] for a writer which can be written to via a
+    /// shared reference (e.g. [`std::fs::File`] or
+    /// [`ShardedWriter`][crate::ShardedWriter]), allowing calls to overlap.
+    pub fn for_concurrent_writer<W>(writer: W) -> *mut FileHandle
+    where
+        W: Send + Sync + 'static,
+        for<'a> &'a W: Write,
+    {
+        FileHandle::from_repr(Repr {
+            base: FileHandle::vtable::<W>(
+                write_concurrent::<W>,
+                flush_concurrent::<W>,
+                write_many_concurrent::<W>,
+            ),
+            writer,
+        })
+    }
+
+    /// Create a new [`FileHandle`] which shares a writer with the rest of the
+    /// program.
+    ///
+    /// The handle holds on to the [`Arc`] itself (not a copy of the writer) and
+    /// locks the [`Mutex`] for each operation, so calls may overlap. The same
+    /// [`Arc`] can be retrieved later using
+    /// [`OwnedFileHandle::shared_writer()`].
+    ///
+    /// [`OwnedFileHandle::shared_writer()`]: crate::OwnedFileHandle::shared_writer
+    pub fn for_shared_writer<W>(shared: Arc<Mutex<W>>) -> *mut FileHandle
+    where
+        W: Write + Send + 'static,
+    {
+        FileHandle::for_concurrent_writer(SharedWriter(shared))
+    }
+
+    /// Create a new [`FileHandle`] for a [`WriterBackend`], recording its
+    /// name and capabilities.
+    pub fn for_backend<B: WriterBackend>(backend: B) -> *mut FileHandle {
+        let capabilities = backend.capabilities();
+        let mut base = FileHandle::vtable::<Backend<B>>(
+            write::<Backend<B>>,
+            flush::<Backend<B>>,
+            write_many::<Backend<B>>,
+        );
+
+        base.cold.name = backend.name().and_then(|n| CString::new(n).ok());
+        base.cold.capabilities = capabilities;
+        if capabilities.contains(Capabilities::SIZE_HINTS) {
+            base.cold.hint_size = Some(hint_size_backend::<B>);
+        }
+
+        FileHandle::from_repr(Repr {
+            base,
+            writer: Backend(backend),
+        })
+    }
+
+    /// Get a pointer to the object stored after the handle's header (e.g.
+    /// the writer passed to [`FileHandle::for_writer()`], or the `place` an
+    /// external handle was initialized at).
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a valid handle which hasn't been destroyed.
+    pub unsafe fn payload_ptr(handle: *mut FileHandle) -> *mut c_void {
+        handle.cast::<u8>().add((*handle).cold.payload_offset).cast()
+    }
 
+    fn from_repr<W>(repr: Repr<W>) -> *mut FileHandle {
         let boxed = Box::into_raw(Box::new(repr));
+        crate::history::record(boxed.cast(), OwnershipEvent::Created);
 
         // Safety: A pointer to the first field on a #[repr(C)] struct has the
         // same address as the struct itself
         boxed as *mut _
     }
 
-    fn vtable<W: Write + 'static>() -> FileHandle {
+    fn vtable<W: 'static>(
+        write: unsafe fn(*mut FileHandle, &[u8]) -> Result<usize, Error>,
+        flush: unsafe fn(*mut FileHandle) -> Result<(), Error>,
+        write_many: WriteManyFn,
+    ) -> FileHandle {
         let layout = Layout::new::<Repr<W>>();
+        // Note: Repr<W> is #[repr(C)], so its fields are laid out the same
+        // way Layout::extend() does it
+        let (_, payload_offset) = Layout::new::<FileHandle>()
+            .extend(Layout::new::<W>())
+            .expect("The layout was already valid as a Repr<W>");
         let type_id = TypeId::of::<W>();
+        let hint_size = hint_size_slot::<W>();
+        let mut capabilities = if hint_size.is_some() {
+            Capabilities::SIZE_HINTS
+        } else {
+            Capabilities::empty()
+        };
+        if cfg!(unix) {
+            if let Some(raw_fd) = file_slot::<W, _>(Capabilities::RAW_FD) {
+                capabilities = capabilities | raw_fd;
+            }
+        }
+
+        FileHandle {
+            write,
+            flags: AtomicU32::new(0),
+            zero_write_policy: AtomicU32::new(
+                crate::zero_write::default_policy().to_bits(),
+            ),
+            flush,
+            write_many,
+            extensions: AtomicPtr::new(ptr::null_mut()),
+            cold: Box::new(ColdHeader {
+                layout,
+                payload_offset,
+                type_id,
+                type_name: std::any::type_name::<W>(),
+                destroy: destroy::<W>,
+                drop_timeout: None,
+                dealloc: dealloc_global,
+                hint_size,
+                last_error: ErrorSlot::new(),
+                name: None,
+                path: None,
+                capabilities,
+                seek: file_slot::<W, _>(seek_file as SeekFn),
+                read: file_slot::<W, _>(read_file as ReadFn),
+                sync: file_slot::<W, _>(sync_file as SyncFn),
+                write_tagged: None,
+                budget: Charge::default(),
+                allocated: None,
+            }),
+        }
+    }
+
+    /// Check whether a particular flag is set.
+    pub(crate) fn has_flag(&self, flag: u32) -> bool {
+        self.flags.load(Ordering::Acquire) & flag != 0
+    }
+
+    /// Set a flag, making it visible to every thread which later checks it.
+    pub(crate) fn set_flag(&self, flag: u32) {
+        self.flags.fetch_or(flag, Ordering::Release);
+    }
+
+    /// Clear a flag.
+    pub(crate) fn clear_flag(&self, flag: u32) {
+        self.flags.fetch_and(!flag, Ordering::Release);
+    }
+
+    /// Get a pointer to the object behind a [`FileHandle`] if it was created
+    /// for a `W`.
+    pub(crate) unsafe fn downcast_raw<W: 'static>(
+        handle: *mut FileHandle,
+    ) -> Option<*mut W> {
+        if (*handle).cold.type_id == TypeId::of::<W>() {
+            let repr = handle as *mut Repr<W>;
+            Some(&mut (*repr).writer as *mut W)
+        } else {
+            None
+        }
+    }
+
+    /// Write some data to the object, applying any policies configured on
+    /// the handle.
+    pub(crate) unsafe fn dispatch_write(
+        handle: *mut FileHandle,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        FileHandle::dispatch_write_tagged(handle, data, None)
+    }
+
+    /// Keep calling [`FileHandle::dispatch_write()`] until all of `data` has
+    /// been written.
+    pub(crate) unsafe fn dispatch_write_all(
+        handle: *mut FileHandle,
+        mut data: &[u8],
+    ) -> Result<(), Error> {
+        while !data.is_empty() {
+            match FileHandle::dispatch_write(handle, data) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => data = &data[n..],
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like [`FileHandle::dispatch_write()`], passing the `tag` to objects
+    /// which understand tags.
+    pub(crate) unsafe fn dispatch_write_tagged(
+        handle: *mut FileHandle,
+        data: &[u8],
+        tag: Option<u32>,
+    ) -> Result<usize, Error> {
+        let ext = match (*handle).extensions() {
+            Some(ext) => ext,
+            None => {
+                return forbid_panics!({
+                    FileHandle::check_frozen(handle)
+                        .and_then(|_| FileHandle::check_timed_out(handle))
+                        .and_then(|_| {
+                            FileHandle::write_plain(handle, data, tag)
+                        })
+                });
+            },
+        };
+
+        let write =
+            || FileHandle::write_with_extensions(handle, ext, data, tag);
+
+        #[cfg(feature = "tracing")]
+        {
+            if let Some(level) = ext.tracing.get() {
+                return crate::trace_spans::traced(
+                    handle,
+                    level,
+                    crate::TraceOperation::Write,
+                    data.len(),
+                    |n| *n,
+                    write,
+                );
+            }
+        }
+
+        write()
+    }
+
+    /// Write through every policy the handle's [`Extensions`] enable.
+    unsafe fn write_with_extensions(
+        handle: *mut FileHandle,
+        ext: &Extensions,
+        data: &[u8],
+        tag: Option<u32>,
+    ) -> Result<usize, Error> {
+        FileHandle::check_frozen(handle)?;
+        FileHandle::check_timed_out(handle)?;
+
+        let _autoflush = ext.autoflush.guard();
+        let _watchdog = ext.watchdog.start();
+        let started = ext.latency.start();
+        let result = match ext.write_filter.apply(handle, data) {
+            None => {
+                FileHandle::write_within_quota(handle, &ext.quota, data, tag)
+            },
+            Some(Ok(filtered)) => FileHandle::write_all_within_quota(
+                handle,
+                &ext.quota,
+                &filtered,
+                tag,
+            )
+            .map(|_| data.len()),
+            Some(Err(e)) => {
+                (*handle).cold.last_error.record(&e);
+                Err(e)
+            },
+        };
+        let elapsed = ext.latency.record_write(started);
+
+        if crate::metrics::is_enabled()
+            && (elapsed.is_some() || ext.thread_stats.is_enabled())
+        {
+            crate::metrics::report_write(handle, &result, elapsed);
+        }
+
+        result
+    }
+
+    unsafe fn write_within_quota(
+        handle: *mut FileHandle,
+        quota: &Quota,
+        data: &[u8],
+        tag: Option<u32>,
+    ) -> Result<usize, Error> {
+        if !quota.is_enabled() {
+            return FileHandle::write_unchecked(handle, data, tag);
+        }
+
+        let granted = match quota.reserve(handle, data.len()) {
+            Ok(granted) => granted,
+            Err(e) => {
+                (*handle).cold.last_error.record(&e);
+                return Err(e);
+            },
+        };
+        let data = &data[..granted];
+
+        let result = FileHandle::write_unchecked(handle, data, tag);
+        quota.settle(granted, result.as_ref().ok().copied());
+
+        result
+    }
+
+    /// Keep writing until all of `data` has been written (used when a write
+    /// filter changed the data, so the caller can't retry a partial write).
+    unsafe fn write_all_within_quota(
+        handle: *mut FileHandle,
+        quota: &Quota,
+        mut data: &[u8],
+        tag: Option<u32>,
+    ) -> Result<(), Error> {
+        while !data.is_empty() {
+            match FileHandle::write_within_quota(handle, quota, data, tag) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => data = &data[n..],
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// [`FileHandle::write_unchecked()`] for handles without any
+    /// [`Extensions`], which is the path most writes take.
+    unsafe fn write_plain(
+        handle: *mut FileHandle,
+        data: &[u8],
+        tag: Option<u32>,
+    ) -> Result<usize, Error> {
+        let result = FileHandle::write_raw(handle, data, tag).and_then(
+            |bytes_written| {
+                FileHandle::retry_zero_write(handle, data, tag, bytes_written)
+            },
+        );
+
+        if let Err(ref e) = result {
+            (*handle).cold.last_error.record(e);
+        }
+
+        result
+    }
+
+    unsafe fn write_unchecked(
+        handle: *mut FileHandle,
+        data: &[u8],
+        tag: Option<u32>,
+    ) -> Result<usize, Error> {
+        let result = FileHandle::write_raw(handle, data, tag);
+        FileHandle::after_write(handle, data, tag, result)
+    }
+
+    /// Call the object's write function, using the tagged version when there
+    /// is a tag and the object understands it.
+    unsafe fn write_raw(
+        handle: *mut FileHandle,
+        data: &[u8],
+        tag: Option<u32>,
+    ) -> Result<usize, Error> {
+        if let Some(tag) = tag {
+            if let Some(write_tagged) = (*handle).cold.write_tagged {
+                let write = || write_tagged(handle, data, tag);
+                return FileHandle::call_object(handle, write);
+            }
+        }
+
+        let write = (*handle).write;
+        FileHandle::call_object(handle, || write(handle, data))
+    }
+
+    /// Call one of the object's methods.
+    ///
+    /// Only the `forbid-panics` feature needs this, because the object's
+    /// methods already catch their own panics unless the handle was created
+    /// with [`FileHandle::for_writer_unguarded()`]. With `c-unwind` as well,
+    /// whatever escapes the object is passed through to the caller instead.
+    #[inline(always)]
+    unsafe fn call_object<T>(
+        handle: *mut FileHandle,
+        method: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if cfg!(feature = "c-unwind") {
+            return method();
+        }
+
+        crate::forbid_panics::contain(method, |payload| {
+            (*handle).set_flag(
+                FileHandle::POISONED | FileHandle::LEAK_ON_DESTROY,
+            );
+            Err(poisoned_by(handle, payload))
+        })
+    }
+
+    /// Write several buffers under a single poison check and panic guard,
+    /// applying the same policies as [`FileHandle::dispatch_write()`] to each
+    /// buffer and passing the outcome to `report`.
+    pub(crate) unsafe fn dispatch_write_many(
+        handle: *mut FileHandle,
+        buffers: &[FfiSlice],
+        mut report: impl FnMut(usize, Result<usize, &Error>),
+    ) {
+        let usable = FileHandle::check_frozen(handle)
+            .and_then(|_| FileHandle::check_timed_out(handle));
+
+        if let Err(e) = usable {
+            for i in 0..buffers.len() {
+                report(i, Err(&e));
+            }
+            return;
+        }
+
+        let one_at_a_time = (*handle).extensions().map_or(false, |ext| {
+            ext.quota.is_enabled()
+                || ext.latency.is_enabled()
+                || ext.write_filter.is_enabled()
+                || ext.is_traced()
+        });
+
+        if one_at_a_time {
+            // each buffer needs its own reservation and timing
+            for (i, buffer) in buffers.iter().enumerate() {
+                let data = buffer.as_slice();
+                let result = FileHandle::dispatch_write(handle, data);
+                report(i, result.as_ref().map(|n| *n));
+            }
+            return;
+        }
+
+        let _autoflush =
+            (*handle).extensions().and_then(|ext| ext.autoflush.guard());
+        let _watchdog =
+            (*handle).extensions().and_then(|ext| ext.watchdog.start());
+        let write_many = (*handle).write_many;
+        let mut reported = 0;
+
+        let outcome = write_many(handle, buffers, &mut |i, result| {
+            let data = buffers[i].as_slice();
+            let result = FileHandle::after_write(handle, data, None, result);
+            report(i, result.as_ref().map(|n| *n));
+            reported = i + 1;
+        });
+
+        if let Err(e) = outcome {
+            (*handle).cold.last_error.record(&e);
+
+            for i in reported..buffers.len() {
+                report(i, Err(&e));
+            }
+        }
+    }
+
+    /// Apply the handle's [`ZeroWritePolicy`] to a write of `data`.
+    unsafe fn retry_zero_write(
+        handle: *mut FileHandle,
+        data: &[u8],
+        tag: Option<u32>,
+        bytes_written: usize,
+    ) -> Result<usize, Error> {
+        if data.is_empty() {
+            Ok(bytes_written)
+        } else {
+            (*handle).zero_write_policy().apply(bytes_written, || {
+                FileHandle::write_raw(handle, data, tag)
+            })
+        }
+    }
+
+    /// Apply the handle's policies to the result of writing `data`.
+    unsafe fn after_write(
+        handle: *mut FileHandle,
+        data: &[u8],
+        tag: Option<u32>,
+        result: Result<usize, Error>,
+    ) -> Result<usize, Error> {
+        let result = result.and_then(|bytes_written| {
+            FileHandle::retry_zero_write(handle, data, tag, bytes_written)
+        });
+
+        match result {
+            Ok(bytes_written) => {
+                if let Some(ext) = (*handle).extensions() {
+                    ext.thread_stats.record_write(bytes_written);
+                }
+            },
+            Err(ref e) => (*handle).cold.last_error.record(e),
+        }
+        if let Some(counters) = (*handle).counters() {
+            counters.record_write(result.as_ref().map(|n| *n));
+        }
+
+        result
+    }
+
+    /// Flush the object.
+    pub(crate) unsafe fn dispatch_flush(
+        handle: *mut FileHandle,
+    ) -> Result<(), Error> {
+        if (*handle).extensions().is_none() {
+            return forbid_panics!({
+                FileHandle::check_frozen(handle)
+                    .and_then(|_| FileHandle::check_timed_out(handle))
+                    .and_then(|_| FileHandle::flush_plain(handle))
+            });
+        }
+
+        FileHandle::check_frozen(handle)?;
+        FileHandle::check_timed_out(handle)?;
+        FileHandle::flush_with_guards(handle)
+    }
+
+    /// Flush the object, synchronising with the autoflush thread and
+    /// watchdog but without checking whether the handle may be used.
+    pub(crate) unsafe fn flush_with_guards(
+        handle: *mut FileHandle,
+    ) -> Result<(), Error> {
+        let _autoflush =
+            (*handle).extensions().and_then(|ext| ext.autoflush.guard());
+        let _watchdog =
+            (*handle).extensions().and_then(|ext| ext.watchdog.start());
+        FileHandle::flush_unguarded(handle)
+    }
+
+    /// [`FileHandle::flush_unguarded()`] for handles without any
+    /// [`Extensions`], which have nothing to synchronise with.
+    unsafe fn flush_plain(handle: *mut FileHandle) -> Result<(), Error> {
+        let result = FileHandle::flush_raw(handle);
+
+        if let Err(ref e) = result {
+            (*handle).cold.last_error.record(e);
+        }
+
+        result
+    }
+
+    /// Call the object's flush function, syncing it to disk if requested.
+    unsafe fn flush_raw(handle: *mut FileHandle) -> Result<(), Error> {
+        let flush = (*handle).flush;
+        let result = FileHandle::call_object(handle, || flush(handle));
+
+        match (*handle).cold.sync {
+            Some(sync)
+                if result.is_ok()
+                    && (*handle).has_flag(FileHandle::SYNC_ON_FLUSH) =>
+            {
+                FileHandle::call_object(handle, || sync(handle, false))
+            },
+            _ => result,
+        }
+    }
+
+    /// Flush the object without synchronising with the autoflush thread.
+    pub(crate) unsafe fn flush_unguarded(
+        handle: *mut FileHandle,
+    ) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        {
+            let ext = (*handle).extensions();
+            if let Some(level) = ext.and_then(|ext| ext.tracing.get()) {
+                return crate::trace_spans::traced(
+                    handle,
+                    level,
+                    crate::TraceOperation::Flush,
+                    0,
+                    |_| 0,
+                    || FileHandle::flush_measured(handle),
+                );
+            }
+        }
+
+        FileHandle::flush_measured(handle)
+    }
+
+    /// Flush the object, recording how long it took.
+    unsafe fn flush_measured(handle: *mut FileHandle) -> Result<(), Error> {
+        let latency = (*handle).extensions().map(|ext| &ext.latency);
+        let started = latency.and_then(|l| l.start());
+
+        let result = FileHandle::flush_raw(handle);
+
+        let elapsed = latency.and_then(|l| l.record_flush(started));
+        if let Some(counters) = (*handle).counters() {
+            counters.record_flush(result.is_ok());
+        }
+        if let Some(elapsed) = elapsed {
+            if crate::metrics::is_enabled() {
+                crate::metrics::report_flush(handle, elapsed);
+            }
+        }
+
+        match result {
+            Ok(_) => crate::shrink::after_flush(handle),
+            Err(ref e) => (*handle).cold.last_error.record(e),
+        }
+
+        result
+    }
+
+    /// Destroy the object and free the [`FileHandle`].
+    pub(crate) unsafe fn dispatch_destroy(handle: *mut FileHandle) {
+        if (*handle).has_flag(FileHandle::LOANED) {
+            crate::loan::destroy_on_loan(handle);
+            return;
+        }
+
+        FileHandle::unregister(handle);
+
+        let destroy = (*handle).cold.destroy;
+        destroy(handle);
+    }
+
+    /// Remove the handle from everything which refers to it by address,
+    /// ready for it to be freed.
+    pub(crate) unsafe fn unregister(handle: *mut FileHandle) {
+        crate::flush_timeout::unregister(handle);
+        crate::exit_flush::unregister(handle);
+        crate::autoflush::unregister(handle);
+        crate::watchdog::unregister(handle);
+        crate::history::record(handle, OwnershipEvent::Destroyed);
+        (*handle).release_extensions();
+    }
+
+    /// Free the memory used by a handle whose contents (including the
+    /// header) have already been dropped, using the `dealloc` and `layout`
+    /// that were read from its header beforehand.
+    pub(crate) unsafe fn deallocate(
+        handle: *mut FileHandle,
+        dealloc: DeallocFn,
+        layout: Layout,
+    ) {
+        dealloc(handle.cast(), layout.size(), layout.align());
+    }
+
+    pub(crate) fn extensions(&self) -> Option<&Extensions> {
+        unsafe { self.extensions.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Get the [`Extensions`], allocating them if this is the first time
+    /// they've been used.
+    pub(crate) fn extensions_or_default(&self) -> &Extensions {
+        if let Some(ext) = self.extensions() {
+            return ext;
+        }
 
+        let fresh = Box::into_raw(Box::new(Extensions::default()));
+
+        match self.extensions.compare_exchange(
+            ptr::null_mut(),
+            fresh,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => unsafe { &*fresh },
+            Err(existing) => unsafe {
+                // Someone else beat us to it
+                drop(Box::from_raw(fresh));
+                &*existing
+            },
+        }
+    }
+
+    /// Free the [`Extensions`], if there are any.
+    pub(crate) fn release_extensions(&self) {
+        let ext = self.extensions.swap(ptr::null_mut(), Ordering::AcqRel);
+
+        if !ext.is_null() {
+            unsafe {
+                drop(Box::from_raw(ext));
+            }
+        }
+    }
+
+    pub(crate) fn zero_write_policy(&self) -> ZeroWritePolicy {
+        ZeroWritePolicy::from_bits(
+            self.zero_write_policy.load(Ordering::Relaxed),
+        )
+    }
+
+    pub(crate) fn set_zero_write_policy(&self, policy: ZeroWritePolicy) {
+        self.zero_write_policy
+            .store(policy.to_bits(), Ordering::Relaxed);
+    }
+
+    /// What this handle's object can do.
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        self.cold.capabilities
+    }
+
+    /// Has a panic poisoned this [`FileHandle`]?
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.has_flag(FileHandle::POISONED)
+    }
+}
+
+impl Clone for FileHandle {
+    fn clone(&self) -> Self {
         FileHandle {
-            layout,
-            type_id,
-            poisoned: false,
-            destroy: destroy::<W>,
-            write: write::<W>,
-            flush: flush::<W>,
+            write: self.write,
+            flags: AtomicU32::new(self.flags.load(Ordering::Acquire)),
+            zero_write_policy: AtomicU32::new(
+                self.zero_write_policy.load(Ordering::Relaxed),
+            ),
+            flush: self.flush,
+            write_many: self.write_many,
+            // Note: extensions and errors belong to a particular handle
+            extensions: AtomicPtr::new(ptr::null_mut()),
+            cold: Box::new(ColdHeader {
+                layout: self.cold.layout,
+                payload_offset: self.cold.payload_offset,
+                type_id: self.cold.type_id,
+                type_name: self.cold.type_name,
+                destroy: self.cold.destroy,
+                drop_timeout: self.cold.drop_timeout,
+                dealloc: self.cold.dealloc,
+                hint_size: self.cold.hint_size,
+                last_error: ErrorSlot::new(),
+                name: self.cold.name.clone(),
+                path: self.cold.path.clone(),
+                capabilities: self.cold.capabilities,
+                seek: self.cold.seek,
+                read: self.cold.read,
+                sync: self.cold.sync,
+                write_tagged: self.cold.write_tagged,
+                budget: Charge::default(),
+                allocated: self.cold.allocated,
+            }),
         }
     }
 }
@@ -72,41 +1037,115 @@ unsafe fn destroy<W>(handle: *mut FileHandle) {
     }
 
     let repr = handle as *mut Repr<W>;
+    let layout = (*handle).cold.layout;
+    let dealloc = (*handle).cold.dealloc;
 
     // Safety: If there was a panic it is no longer safe to call the object's
     // destructor (it's probably FUBAR), but we can still reclaim the memory
     // used by the original allocation.
 
-    if (*handle).poisoned {
-        let layout = (*handle).layout;
-        std::alloc::dealloc(repr.cast(), layout);
+    if (*handle).has_flag(FileHandle::LEAK_ON_DESTROY) {
+        // the header is still fine, so we only skip the object's destructor
+        ptr::drop_in_place(&mut (*handle).cold);
     } else {
-        let _ = Box::from_raw(repr);
+        ptr::drop_in_place(repr);
+    }
+
+    FileHandle::deallocate(handle, dealloc, layout);
+}
+
+unsafe fn destroy_with_timeout<W: Send + 'static>(handle: *mut FileHandle) {
+    if handle.is_null() {
+        return;
+    }
+
+    if (*handle).has_flag(FileHandle::LEAK_ON_DESTROY) {
+        return destroy::<W>(handle);
     }
+
+    let repr = handle as *mut Repr<W>;
+    let layout = (*handle).cold.layout;
+    let dealloc = (*handle).cold.dealloc;
+    let timeout = (*handle).cold.drop_timeout.unwrap_or_default();
+    let type_name = (*handle).cold.type_name;
+
+    // move the writer out so the handle's memory can be freed straight away,
+    // even if the writer's destructor never finishes
+    let writer = ptr::read(&(*repr).writer);
+    ptr::drop_in_place(&mut (*repr).base);
+    FileHandle::deallocate(handle, dealloc, layout);
+
+    crate::drop_timeout::drop_with_timeout(writer, timeout, type_name);
 }
 
 macro_rules! auto_poison {
     ($handle:expr, $body:block) => {{
-        if (*$handle).poisoned {
-            Err(Error::new(
-                std::io::ErrorKind::InvalidData,
-                "A panic occurred and this object is now poisoned",
-            ))
+        if (*$handle).is_poisoned() {
+            Err(poison_error())
         } else {
-            let got = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
-                move || $body,
-            ));
+            let body = move || $body;
+            // Note: with the no-panic-guard feature this is always true, so
+            // the catch_unwind() is optimised away
+            let got = if cfg!(feature = "no-panic-guard")
+                || (*$handle).has_flag(FileHandle::NO_PANIC_GUARD)
+            {
+                call_unguarded(body)
+            } else {
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(body))
+            };
             match got {
+                Ok(Err(e)) if is_poison_error(&e) => {
+                    // The object is wrapping a handle which was poisoned, so
+                    // we can't be used any more either
+                    (*$handle).set_flag(FileHandle::POISONED);
+                    Err(e)
+                },
                 Ok(value) => value,
                 Err(payload) => {
-                    (*$handle).poisoned = true;
-                    Err(Error::new(ErrorKind::Other, Poisoned::from(payload)))
+                    (*$handle).set_flag(
+                        FileHandle::POISONED | FileHandle::LEAK_ON_DESTROY,
+                    );
+                    Err(poisoned_by($handle, payload))
                 },
             }
         }
     }};
 }
 
+/// Call `thunk` the same way `catch_unwind()` would, but without catching
+/// anything.
+fn call_unguarded<T>(thunk: impl FnOnce() -> T) -> std::thread::Result<T> {
+    Ok(thunk())
+}
+
+/// The error returned when a panic poisons a [`FileHandle`], and by every
+/// call on it afterwards.
+///
+/// Creating it never allocates. What the panic said is kept in the handle's
+/// last error instead (see [`ErrorSlot::record_panic()`]).
+///
+/// [`ErrorSlot::record_panic()`]: crate::last_error::ErrorSlot::record_panic
+pub(crate) fn poison_error() -> Error {
+    crate::errors::crate_status_unboxed(crate::errors::CRATE_ERROR_POISONED)
+}
+
+/// Was this error caused by a poisoned [`FileHandle`]?
+pub(crate) fn is_poison_error(e: &Error) -> bool {
+    let status = crate::errors::crate_status_of(e);
+    status == Some(crate::errors::CRATE_ERROR_POISONED)
+}
+
+/// Record why a handle was poisoned and get rid of the panic's payload.
+unsafe fn poisoned_by(
+    handle: *mut FileHandle,
+    payload: Box<dyn Any + Send + 'static>,
+) -> Error {
+    (*handle).cold.last_error.record_panic(&*payload);
+    // the payload's destructor might panic as well, so leak it
+    crate::forbid_panics::contain(move || drop(payload), std::mem::forget);
+    poison_error()
+}
+
 unsafe fn write<W: Write>(
     handle: *mut FileHandle,
     data: &[u8],
@@ -117,6 +1156,17 @@ unsafe fn write<W: Write>(
     })
 }
 
+unsafe fn write_tagged<W: TaggedWrite>(
+    handle: *mut FileHandle,
+    data: &[u8],
+    tag: u32,
+) -> Result<usize, Error> {
+    auto_poison!(handle, {
+        let repr = &mut *(handle as *mut Repr<W>);
+        repr.writer.write_tagged(data, tag)
+    })
+}
+
 unsafe fn flush<W: Write>(handle: *mut FileHandle) -> Result<(), Error> {
     auto_poison!(handle, {
         let repr = &mut *(handle as *mut Repr<W>);
@@ -124,31 +1174,384 @@ unsafe fn flush<W: Write>(handle: *mut FileHandle) -> Result<(), Error> {
     })
 }
 
-#[derive(Debug)]
-struct Poisoned(Mutex<Box<dyn Any + Send + 'static>>);
+unsafe fn write_many<W: Write>(
+    handle: *mut FileHandle,
+    buffers: &[FfiSlice],
+    report: &mut dyn FnMut(usize, Result<usize, Error>),
+) -> Result<(), Error> {
+    auto_poison!(handle, {
+        for (i, buffer) in buffers.iter().enumerate() {
+            let result = {
+                let repr = &mut *(handle as *mut Repr<W>);
+                repr.writer.write(buffer.as_slice())
+            };
+
+            match result {
+                Err(e) if is_poison_error(&e) => return Err(e),
+                result => report(i, result),
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Write to a [`MemoryBuffer`], keeping the handle's charge up to date.
+unsafe fn write_charged<W: MemoryBuffer>(
+    handle: *mut FileHandle,
+    data: &[u8],
+) -> Result<usize, Error> {
+    let repr = &mut *(handle as *mut Repr<W>);
+    let budget = &mut repr.base.cold.budget;
+
+    budget.grow(repr.writer.growth(data.len()))?;
+    let result = repr.writer.write(data);
+    budget.update(repr.writer.allocated());
+
+    result
+}
+
+unsafe fn allocated<W: MemoryBuffer>(handle: *mut FileHandle) -> usize {
+    (*handle.cast::<Repr<W>>()).writer.allocated()
+}
+
+unsafe fn write_budgeted<W: MemoryBuffer>(
+    handle: *mut FileHandle,
+    data: &[u8],
+) -> Result<usize, Error> {
+    auto_poison!(handle, { write_charged::<W>(handle, data) })
+}
+
+unsafe fn write_many_budgeted<W: MemoryBuffer>(
+    handle: *mut FileHandle,
+    buffers: &[FfiSlice],
+    report: &mut dyn FnMut(usize, Result<usize, Error>),
+) -> Result<(), Error> {
+    auto_poison!(handle, {
+        for (i, buffer) in buffers.iter().enumerate() {
+            match write_charged::<W>(handle, buffer.as_slice()) {
+                Err(e) if is_poison_error(&e) => return Err(e),
+                result => report(i, result),
+            }
+        }
 
-impl From<Box<dyn Any + Send + 'static>> for Poisoned {
-    fn from(payload: Box<dyn Any + Send + 'static>) -> Self {
-        Poisoned(Mutex::new(payload))
+        Ok(())
+    })
+}
+
+/// Write to a [`File`], reopening its path if it was removed.
+unsafe fn write_reopened(
+    handle: *mut FileHandle,
+    data: &[u8],
+) -> Result<usize, Error> {
+    let repr = &mut *(handle as *mut Repr<File>);
+    let path = repr.base.cold.path.as_deref();
+    crate::open_options::write_or_reopen(&mut repr.writer, path, data)
+}
+
+unsafe fn write_reopening(
+    handle: *mut FileHandle,
+    data: &[u8],
+) -> Result<usize, Error> {
+    auto_poison!(handle, { write_reopened(handle, data) })
+}
+
+unsafe fn write_many_reopening(
+    handle: *mut FileHandle,
+    buffers: &[FfiSlice],
+    report: &mut dyn FnMut(usize, Result<usize, Error>),
+) -> Result<(), Error> {
+    auto_poison!(handle, {
+        for (i, buffer) in buffers.iter().enumerate() {
+            match write_reopened(handle, buffer.as_slice()) {
+                Err(e) if is_poison_error(&e) => return Err(e),
+                result => report(i, result),
+            }
+        }
+
+        Ok(())
+    })
+}
+
+unsafe fn write_concurrent<W>(
+    handle: *mut FileHandle,
+    data: &[u8],
+) -> Result<usize, Error>
+where
+    for<'a> &'a W: Write,
+{
+    auto_poison!(handle, {
+        let repr = &*(handle as *const Repr<W>);
+        (&repr.writer).write(data)
+    })
+}
+
+unsafe fn write_many_concurrent<W>(
+    handle: *mut FileHandle,
+    buffers: &[FfiSlice],
+    report: &mut dyn FnMut(usize, Result<usize, Error>),
+) -> Result<(), Error>
+where
+    for<'a> &'a W: Write,
+{
+    auto_poison!(handle, {
+        let repr = &*(handle as *const Repr<W>);
+
+        for (i, buffer) in buffers.iter().enumerate() {
+            match (&repr.writer).write(buffer.as_slice()) {
+                Err(e) if is_poison_error(&e) => return Err(e),
+                result => report(i, result),
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// A [`WriteManyFn`] for objects which don't have a more efficient way of
+/// writing several buffers at once.
+pub(crate) unsafe fn write_many_one_by_one(
+    handle: *mut FileHandle,
+    buffers: &[FfiSlice],
+    report: &mut dyn FnMut(usize, Result<usize, Error>),
+) -> Result<(), Error> {
+    let write = (*handle).write;
+
+    for (i, buffer) in buffers.iter().enumerate() {
+        report(i, write(handle, buffer.as_slice()));
+    }
+
+    Ok(())
+}
+
+unsafe fn flush_concurrent<W>(handle: *mut FileHandle) -> Result<(), Error>
+where
+    for<'a> &'a W: Write,
+{
+    auto_poison!(handle, {
+        let repr = &*(handle as *const Repr<W>);
+        (&repr.writer).flush()
+    })
+}
+
+/// The object stored by [`FileHandle::for_backend()`].
+struct Backend<B>(B);
+
+impl<B: WriterBackend> Write for Backend<B> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.0.flush() }
+}
+
+unsafe fn hint_size_backend<B: WriterBackend>(
+    handle: *mut FileHandle,
+    bytes: u64,
+) -> Result<(), Error> {
+    auto_poison!(handle, {
+        let backend = &mut (*handle.cast::<Repr<Backend<B>>>()).writer;
+        backend.0.hint_total_size(bytes)
+    })
+}
+
+/// The object stored by [`FileHandle::for_shared_writer()`].
+pub(crate) struct SharedWriter<W>(pub(crate) Arc<Mutex<W>>);
+
+impl<W: Write> Write for &SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.lock().map_err(|_| lock_poisoned())?.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.0.lock().map_err(|_| lock_poisoned())?.flush()
     }
 }
 
-impl std::error::Error for Poisoned {}
+fn lock_poisoned() -> Error {
+    Error::new(
+        ErrorKind::Other,
+        "Another thread panicked while using the shared writer",
+    )
+}
 
-impl Display for Poisoned {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let payload = self.0.lock().unwrap();
+/// Size hints are only understood by a handful of well-known writers.
+fn hint_size_slot<W: 'static>() -> Option<HintSizeFn> {
+    let type_id = TypeId::of::<W>();
 
-        if let Some(s) = payload.downcast_ref::<&str>() {
-            write!(f, "A panic occurred: {}", s)
-        } else if let Some(s) = payload.downcast_ref::<String>() {
-            write!(f, "A panic occurred: {}", s)
+    if type_id == TypeId::of::<Vec<u8>>() {
+        Some(hint_size_memory)
+    } else if type_id == TypeId::of::<File>() {
+        Some(hint_size_file)
+    } else {
+        None
+    }
+}
+
+unsafe fn hint_size_memory(
+    handle: *mut FileHandle,
+    bytes: u64,
+) -> Result<(), Error> {
+    auto_poison!(handle, {
+        let buffer = &mut (*handle.cast::<Repr<Vec<u8>>>()).writer;
+
+        // Note: it's only a hint, so requests we can't satisfy are ignored
+        // rather than aborting the process
+        if let Ok(bytes) = usize::try_from(bytes) {
+            try_reserve(buffer, bytes);
+        }
+
+        Ok(())
+    })
+}
+
+/// Like [`hint_size_memory()`], except the reservation is charged to the
+/// memory budget first.
+unsafe fn hint_size_budgeted(
+    handle: *mut FileHandle,
+    bytes: u64,
+) -> Result<(), Error> {
+    auto_poison!(handle, {
+        let repr = &mut *handle.cast::<Repr<Vec<u8>>>();
+        let bytes = match usize::try_from(bytes) {
+            Ok(bytes) => bytes,
+            Err(_) => return Ok(()),
+        };
+        let growth = repr
+            .writer
+            .len()
+            .saturating_add(bytes)
+            .saturating_sub(repr.writer.capacity());
+
+        repr.base.cold.budget.grow(growth)?;
+        try_reserve(&mut repr.writer, bytes);
+        repr.base.cold.budget.update(repr.writer.capacity());
+
+        Ok(())
+    })
+}
+
+/// Like `Vec::try_reserve()` (which needs Rust 1.57), returning whether the
+/// space could be allocated instead of aborting when it can't.
+fn try_reserve(buffer: &mut Vec<u8>, additional: usize) -> bool {
+    let required = match buffer.len().checked_add(additional) {
+        Some(required) if required <= isize::MAX as usize => required,
+        _ => return false,
+    };
+    if required <= buffer.capacity() {
+        return true;
+    }
+
+    let layout = match Layout::array::<u8>(required) {
+        Ok(layout) => layout,
+        Err(_) => return false,
+    };
+
+    unsafe {
+        let data = std::alloc::alloc(layout);
+        if data.is_null() {
+            return false;
+        }
+
+        // Safety: this is how a Vec<u8> with `required` capacity is allocated
+        ptr::copy_nonoverlapping(buffer.as_ptr(), data, buffer.len());
+        *buffer = Vec::from_raw_parts(data, buffer.len(), required);
+    }
+
+    true
+}
+
+unsafe fn hint_size_file(
+    handle: *mut FileHandle,
+    bytes: u64,
+) -> Result<(), Error> {
+    auto_poison!(handle, {
+        let file = &mut (*handle.cast::<Repr<File>>()).writer;
+        preallocate(file, bytes);
+        Ok(())
+    })
+}
+
+/// Seeking and reading are only supported by files.
+fn file_slot<W: 'static, F>(slot: F) -> Option<F> {
+    if TypeId::of::<W>() == TypeId::of::<File>() {
+        Some(slot)
+    } else {
+        None
+    }
+}
+
+unsafe fn seek_file(
+    handle: *mut FileHandle,
+    pos: SeekFrom,
+) -> Result<u64, Error> {
+    auto_poison!(handle, {
+        let file = &mut (*handle.cast::<Repr<File>>()).writer;
+        file.seek(pos)
+    })
+}
+
+unsafe fn read_file(
+    handle: *mut FileHandle,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    auto_poison!(handle, {
+        let file = &mut (*handle.cast::<Repr<File>>()).writer;
+        file.read(buffer)
+    })
+}
+
+unsafe fn sync_file(
+    handle: *mut FileHandle,
+    data_only: bool,
+) -> Result<(), Error> {
+    auto_poison!(handle, {
+        let file = &mut (*handle.cast::<Repr<File>>()).writer;
+        if data_only {
+            file.sync_data()
         } else {
-            write!(f, "A panic occurred")
+            file.sync_all()
         }
+    })
+}
+
+/// Ask the OS to reserve space for `bytes` more bytes after the current
+/// position without changing the file's size. This is purely an optimisation,
+/// so any errors are ignored.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn preallocate(file: &mut File, bytes: u64) {
+    use std::{
+        io::{Seek, SeekFrom},
+        os::{raw::c_int, unix::io::AsRawFd},
+    };
+
+    extern "C" {
+        fn fallocate(fd: c_int, mode: c_int, offset: i64, len: i64) -> c_int;
+    }
+    const FALLOC_FL_KEEP_SIZE: c_int = 0x01;
+
+    let offset = match file.seek(SeekFrom::Current(0)) {
+        Ok(offset) => offset,
+        Err(_) => return,
+    };
+
+    if offset > i64::MAX as u64 || bytes > i64::MAX as u64 {
+        return;
+    }
+
+    unsafe {
+        fallocate(
+            file.as_raw_fd(),
+            FALLOC_FL_KEEP_SIZE,
+            offset as i64,
+            bytes as i64,
+        );
     }
 }
 
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn preallocate(_file: &mut File, _bytes: u64) {}
+
 /// The "child class" which inherits from [`FileHandle`] and holds some type,
 /// `W`, which we can write to.
 #[repr(C)]
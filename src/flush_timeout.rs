@@ -0,0 +1,264 @@
+//! Flushing with a time limit, so a host can't get stuck waiting on a slow
+//! sink (e.g. a network filesystem) in the middle of something interactive.
+
+use crate::{extensions::Extensions, watchdog, FileHandle, OwnedFileHandle};
+use std::{
+    io::Error,
+    os::raw::c_int,
+    sync::{Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The state of a flush started by [`flush_timeout()`].
+#[derive(Debug, Default)]
+pub(crate) struct BackgroundFlush {
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: bool,
+    /// The result of a flush which finished after its caller stopped
+    /// waiting.
+    outcome: Option<Result<(), Error>>,
+}
+
+impl BackgroundFlush {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until no flush is running or the deadline passes, returning
+    /// whether it finished.
+    fn wait<'a>(
+        &self,
+        mut state: MutexGuard<'a, State>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, State>, bool) {
+        while state.running {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return (state, false);
+                    }
+                    deadline - now
+                },
+                None => Duration::from_secs(3600),
+            };
+
+            state = self
+                .finished
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+
+        (state, true)
+    }
+}
+
+/// Wait for any flush started by [`flush_timeout()`] to finish, returning a
+/// guard which stops a new one from starting until it is dropped.
+pub(crate) fn pause(ext: &Extensions) -> impl Drop + '_ {
+    let pending = &ext.background_flush;
+    pending.wait(pending.lock(), None).0
+}
+
+/// Flush the handle on a helper thread, giving up after `timeout`.
+///
+/// If a flush from an earlier call is still running, it gets the same amount
+/// of time to finish before a new flush starts, and if it failed its error is
+/// returned instead.
+pub(crate) unsafe fn flush_timeout(
+    handle: *mut FileHandle,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let deadline = Instant::now().checked_add(timeout);
+    let pending = &(*handle).extensions_or_default().background_flush;
+
+    let (mut state, finished) = pending.wait(pending.lock(), deadline);
+    if !finished {
+        return Err(record(handle, watchdog::timed_out()));
+    }
+    if let Some(Err(e)) = state.outcome.take() {
+        return Err(record(handle, e));
+    }
+
+    FileHandle::check_frozen(handle)?;
+    FileHandle::check_timed_out(handle)?;
+
+    state.running = true;
+    (*handle).set_flag(FileHandle::FLUSHING);
+
+    let address = handle as usize;
+    let spawned = thread::Builder::new()
+        .name(String::from("file-handle-flush"))
+        .spawn(move || finish_flush(address as *mut FileHandle));
+
+    if let Err(e) = spawned {
+        state.running = false;
+        (*handle).clear_flag(FileHandle::FLUSHING);
+        return Err(record(handle, e));
+    }
+
+    let (mut state, finished) = pending.wait(state, deadline);
+    if finished {
+        state.outcome.take().unwrap_or(Ok(()))
+    } else {
+        Err(record(handle, watchdog::timed_out()))
+    }
+}
+
+unsafe fn finish_flush(handle: *mut FileHandle) {
+    let result = FileHandle::flush_with_guards(handle);
+    let pending = &(*handle).extensions_or_default().background_flush;
+
+    let mut state = pending.lock();
+    (*handle).clear_flag(FileHandle::FLUSHING);
+    state.outcome = Some(result);
+    state.running = false;
+    pending.finished.notify_all();
+    // Note: unlocking must be the last thing we do, because unregister()
+    // takes the lock before the handle (and this mutex) can be freed
+    drop(state);
+}
+
+unsafe fn record(handle: *mut FileHandle, e: Error) -> Error {
+    (*handle).cold.last_error.record(&e);
+    e
+}
+
+/// Block until any flush started by [`flush_timeout()`] has finished, so the
+/// handle can be destroyed.
+///
+/// This always takes the lock instead of checking
+/// [`FileHandle::FLUSHING`], because the helper thread clears the flag
+/// before it has finished with the handle.
+pub(crate) unsafe fn unregister(handle: *mut FileHandle) {
+    if let Some(ext) = (*handle).extensions() {
+        let pending = &ext.background_flush;
+        drop(pending.wait(pending.lock(), None));
+    }
+}
+
+impl OwnedFileHandle {
+    /// Flush the handle, giving up after `timeout` instead of waiting for a
+    /// slow flush to finish.
+    ///
+    /// See [`file_handle_flush_timeout()`] for details.
+    pub fn flush_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        unsafe { flush_timeout(self.as_ptr(), timeout) }
+    }
+}
+
+export! {
+    /// Flush the [`FileHandle`] on a helper thread, returning
+    /// [`FILE_HANDLE_TIMED_OUT`] if it takes more than `millis` milliseconds.
+    ///
+    /// A flush which times out keeps running in the background. Until it
+    /// finishes, every other operation on the handle fails with
+    /// [`FILE_HANDLE_SUSPENDED`] and destroying the handle waits for it. The
+    /// next call waits up to `millis` for it to finish, returning its error if
+    /// it failed, before flushing again.
+    ///
+    /// Returns `0` on success or a negative value on failure.
+    ///
+    /// [`FILE_HANDLE_TIMED_OUT`]: crate::FILE_HANDLE_TIMED_OUT
+    /// [`FILE_HANDLE_SUSPENDED`]: crate::FILE_HANDLE_SUSPENDED
+    pub unsafe extern "C" fn file_handle_flush_timeout(
+        handle: *mut FileHandle,
+        millis: u32,
+    ) -> c_int {
+        match flush_timeout(handle, Duration::from_millis(u64::from(millis))) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::{
+        io::Write,
+        sync::{mpsc, Arc},
+    };
+
+    /// A writer whose flushes wait until they are told to finish.
+    struct SlowFlush(Arc<Mutex<mpsc::Receiver<Result<(), Error>>>>);
+
+    impl Write for SlowFlush {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.0.lock().unwrap().recv().unwrap()
+        }
+    }
+
+    fn slow_flush() -> (OwnedFileHandle, mpsc::Sender<Result<(), Error>>) {
+        let (tx, rx) = mpsc::channel();
+        let handle = OwnedFileHandle::new(SlowFlush(Arc::new(Mutex::new(rx))));
+        (handle, tx)
+    }
+
+    #[test]
+    fn quick_flushes_return_their_result() {
+        let (mut handle, finish) = slow_flush();
+        let timeout = Duration::from_secs(10);
+
+        finish.send(Ok(())).unwrap();
+        handle.flush_timeout(timeout).unwrap();
+
+        finish.send(Err(Error::from_raw_os_error(5))).unwrap();
+        let err = handle.flush_timeout(timeout).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(5));
+    }
+
+    #[test]
+    fn slow_flushes_are_reported_by_the_next_call() {
+        let (handle, finish) = slow_flush();
+        let handle = handle.into_raw();
+
+        unsafe {
+            let ret = file_handle_flush_timeout(handle, 10);
+            assert_eq!(ret, FILE_HANDLE_TIMED_OUT);
+
+            // the handle can't be used while the flush is still running
+            let ret = file_handle_write(handle, b"a".as_ptr().cast(), 1);
+            assert_eq!(ret, FILE_HANDLE_SUSPENDED);
+
+            finish.send(Err(Error::from_raw_os_error(28))).unwrap();
+            assert_eq!(file_handle_flush_timeout(handle, 10_000), -28);
+
+            assert_eq!(file_handle_write(handle, b"a".as_ptr().cast(), 1), 1);
+
+            // destroying waits for the flush which was just started
+            let ret = file_handle_flush_timeout(handle, 0);
+            assert_eq!(ret, FILE_HANDLE_TIMED_OUT);
+            finish.send(Ok(())).unwrap();
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn destroy_waits_for_the_helper_to_unlock() {
+        for _ in 0..50 {
+            let (handle, finish) = slow_flush();
+            let handle = handle.into_raw();
+
+            unsafe {
+                let ret = file_handle_flush_timeout(handle, 0);
+                assert_eq!(ret, FILE_HANDLE_TIMED_OUT);
+                // race the helper thread finishing against the destroy
+                finish.send(Ok(())).unwrap();
+                file_handle_destroy(handle);
+            }
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! A FFI-safe version of [`dyn std::fmt::Write`][fmt::Write], for plugins
+//! which exchange text instead of bytes.
+
+use crate::{FfiSlice, FileHandle, OwnedFileHandle};
+use std::{
+    alloc::Layout,
+    any::TypeId,
+    fmt,
+    io::{Error, ErrorKind, Write},
+    os::raw::c_int,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Returned by [`fmt_handle_write_utf8()`] when the underlying writer failed.
+pub const FMT_HANDLE_ERROR: c_int = -1;
+/// Returned by [`fmt_handle_write_utf8()`] when the text isn't valid UTF-8.
+pub const FMT_HANDLE_INVALID_UTF8: c_int = -2;
+
+/// A thin trait object for something which accepts text.
+///
+/// This mirrors [`FileHandle`], except the only operation is
+/// [`fmt::Write::write_str()`] and the data is always valid UTF-8.
+#[repr(C)]
+pub struct FmtHandle {
+    pub(crate) layout: Layout,
+    pub(crate) type_id: TypeId,
+    /// Set when the object panicked, after which it may not be used again.
+    pub(crate) poisoned: AtomicBool,
+    pub(crate) destroy: unsafe fn(*mut FmtHandle),
+    pub(crate) write_str: unsafe fn(*mut FmtHandle, &str) -> fmt::Result,
+}
+
+impl FmtHandle {
+    /// Create a new [`FmtHandle`] that wraps a Rust [`fmt::Write`]r.
+    pub fn for_writer<W>(writer: W) -> *mut FmtHandle
+    where
+        W: fmt::Write + Send + Sync + 'static,
+    {
+        let repr = FmtRepr {
+            base: FmtHandle {
+                layout: Layout::new::<FmtRepr<W>>(),
+                type_id: TypeId::of::<W>(),
+                poisoned: AtomicBool::new(false),
+                destroy: destroy::<W>,
+                write_str: write_str::<W>,
+            },
+            writer,
+        };
+
+        // Safety: The FmtHandle is the first field of a #[repr(C)] struct
+        Box::into_raw(Box::new(repr)) as *mut FmtHandle
+    }
+
+    /// Create a new [`FmtHandle`] which writes the text's bytes to a
+    /// [`FileHandle`].
+    pub fn for_file_handle(handle: OwnedFileHandle) -> *mut FmtHandle {
+        FmtHandle::for_writer(TextToBytes(handle))
+    }
+
+    unsafe fn downcast_raw<W: 'static>(
+        handle: *mut FmtHandle,
+    ) -> Option<*mut W> {
+        if (*handle).type_id == TypeId::of::<W>() {
+            let repr = handle as *mut FmtRepr<W>;
+            Some(&mut (*repr).writer as *mut W)
+        } else {
+            None
+        }
+    }
+}
+
+#[repr(C)]
+struct FmtRepr<W> {
+    // Safety: The FmtHandle must be the first field so we can cast between
+    // *mut FmtRepr<W> and *mut FmtHandle
+    base: FmtHandle,
+    writer: W,
+}
+
+unsafe fn destroy<W>(handle: *mut FmtHandle) {
+    if handle.is_null() {
+        return;
+    }
+
+    let repr = handle as *mut FmtRepr<W>;
+
+    // Safety: A writer which panicked might be in an inconsistent state so we
+    // skip its destructor and just free the memory
+    if (*handle).poisoned.load(Ordering::Acquire) {
+        std::alloc::dealloc(repr.cast(), (*handle).layout);
+    } else {
+        let _ = Box::from_raw(repr);
+    }
+}
+
+unsafe fn write_str<W: fmt::Write>(
+    handle: *mut FmtHandle,
+    s: &str,
+) -> fmt::Result {
+    if (*handle).poisoned.load(Ordering::Acquire) {
+        return Err(fmt::Error);
+    }
+
+    let got = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let repr = &mut *(handle as *mut FmtRepr<W>);
+        repr.writer.write_str(s)
+    }));
+
+    got.unwrap_or_else(|_| {
+        (*handle).poisoned.store(true, Ordering::Release);
+        Err(fmt::Error)
+    })
+}
+
+/// An owned wrapper around a [`*mut FmtHandle`][FmtHandle], the text
+/// equivalent of [`OwnedFileHandle`].
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct OwnedFmtHandle(NonNull<FmtHandle>);
+
+impl OwnedFmtHandle {
+    /// Create a new [`OwnedFmtHandle`] which wraps some [`fmt::Write`]r.
+    pub fn new<W: fmt::Write + Send + Sync + 'static>(writer: W) -> Self {
+        unsafe { OwnedFmtHandle::from_raw(FmtHandle::for_writer(writer)) }
+    }
+
+    /// Create an [`OwnedFmtHandle`] from a `*mut FmtHandle`, taking
+    /// ownership of the [`FmtHandle`].
+    ///
+    /// # Safety
+    ///
+    /// The `handle` must be a non-null pointer to a valid [`FmtHandle`], and
+    /// the original pointer may no longer be used.
+    pub unsafe fn from_raw(handle: *mut FmtHandle) -> Self {
+        debug_assert!(!handle.is_null());
+        OwnedFmtHandle(NonNull::new_unchecked(handle))
+    }
+
+    /// Consume the [`OwnedFmtHandle`] and get a `*mut FmtHandle` that can be
+    /// used from native code.
+    pub fn into_raw(self) -> *mut FmtHandle {
+        let ptr = self.0.as_ptr();
+        std::mem::forget(self);
+        ptr
+    }
+
+    /// Create a [`FileHandle`] which checks that everything written to it is
+    /// valid UTF-8 before passing it on to this [`OwnedFmtHandle`].
+    ///
+    /// A multi-byte character may be split across several writes, but
+    /// flushing while part way through a character is an error.
+    pub fn into_file_handle(self) -> OwnedFileHandle {
+        OwnedFileHandle::new(Utf8Bridge {
+            inner: self,
+            partial: Vec::new(),
+        })
+    }
+}
+
+impl fmt::Write for OwnedFmtHandle {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe {
+            let ptr = self.0.as_ptr();
+            ((*ptr).write_str)(ptr, s)
+        }
+    }
+}
+
+impl Drop for OwnedFmtHandle {
+    fn drop(&mut self) {
+        unsafe {
+            let ptr = self.0.as_ptr();
+            ((*ptr).destroy)(ptr);
+        }
+    }
+}
+
+// SAFETY: FmtHandle::for_writer() requires the object to be Send + Sync.
+unsafe impl Send for OwnedFmtHandle {}
+unsafe impl Sync for OwnedFmtHandle {}
+
+/// The object behind [`FmtHandle::for_file_handle()`].
+struct TextToBytes(OwnedFileHandle);
+
+impl fmt::Write for TextToBytes {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// The object behind [`OwnedFmtHandle::into_file_handle()`].
+struct Utf8Bridge {
+    inner: OwnedFmtHandle,
+    /// The start of a multi-byte character which was cut off at the end of
+    /// the previous write.
+    partial: Vec<u8>,
+}
+
+impl Write for Utf8Bridge {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let joined;
+        let data = if self.partial.is_empty() {
+            buf
+        } else {
+            joined = [self.partial.as_slice(), buf].concat();
+            joined.as_slice()
+        };
+
+        let (text, rest) = match std::str::from_utf8(data) {
+            Ok(text) => (text, &[][..]),
+            // the last character is incomplete, wait for the rest of it
+            Err(e) if e.error_len().is_none() => {
+                let (valid, rest) = data.split_at(e.valid_up_to());
+                (std::str::from_utf8(valid).unwrap(), rest)
+            },
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+
+        fmt::Write::write_str(&mut self.inner, text).map_err(|_| {
+            Error::new(ErrorKind::Other, "Unable to write to the FmtHandle")
+        })?;
+        self.partial = rest.to_vec();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.partial.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "Flushed part way through a UTF-8 character",
+            ))
+        }
+    }
+}
+
+export! {
+    /// Create a new [`FmtHandle`] which appends text to a string in memory.
+    ///
+    /// The string can be inspected using [`fmt_handle_as_string()`].
+    pub unsafe extern "C" fn new_string_fmt_handle() -> *mut FmtHandle {
+        FmtHandle::for_writer(String::new())
+    }
+}
+
+export! {
+    /// Create a new [`FmtHandle`] which writes to a [`FileHandle`], taking
+    /// ownership of `inner`.
+    ///
+    /// Returns `null` if `inner` is `null`.
+    pub unsafe extern "C" fn new_fmt_handle_for_file_handle(
+        inner: *mut FileHandle,
+    ) -> *mut FmtHandle {
+        if inner.is_null() {
+            return ptr::null_mut();
+        }
+
+        FmtHandle::for_file_handle(OwnedFileHandle::from_raw(inner))
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which checks that everything written to it
+    /// is valid UTF-8 and passes it on to a [`FmtHandle`], taking ownership of
+    /// `inner`.
+    ///
+    /// Returns `null` if `inner` is `null`.
+    pub unsafe extern "C" fn new_file_handle_for_fmt_handle(
+        inner: *mut FmtHandle,
+    ) -> *mut FileHandle {
+        if inner.is_null() {
+            return ptr::null_mut();
+        }
+
+        OwnedFmtHandle::from_raw(inner).into_file_handle().into_raw()
+    }
+}
+
+export! {
+    /// Write `len` bytes of UTF-8 text to a [`FmtHandle`].
+    ///
+    /// Returns `0` on success, [`FMT_HANDLE_INVALID_UTF8`] if the text isn't
+    /// valid UTF-8 (in which case nothing is written), or [`FMT_HANDLE_ERROR`]
+    /// if the write failed.
+    pub unsafe extern "C" fn fmt_handle_write_utf8(
+        handle: *mut FmtHandle,
+        data: *const u8,
+        len: usize,
+    ) -> c_int {
+        let data = FfiSlice { data, len }.as_slice();
+
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => return FMT_HANDLE_INVALID_UTF8,
+        };
+
+        match ((*handle).write_str)(handle, text) {
+            Ok(()) => 0,
+            Err(fmt::Error) => FMT_HANDLE_ERROR,
+        }
+    }
+}
+
+export! {
+    /// Get the text written to a [`FmtHandle`] created with
+    /// [`new_string_fmt_handle()`].
+    ///
+    /// The returned buffer is only valid until the next time the handle is
+    /// written to or destroyed, and [`FfiSlice::NULL`] is returned if the
+    /// handle isn't a string handle.
+    pub unsafe extern "C" fn fmt_handle_as_string(
+        handle: *mut FmtHandle,
+    ) -> FfiSlice {
+        match FmtHandle::downcast_raw::<String>(handle) {
+            Some(s) => FfiSlice::new((*s).as_bytes()),
+            None => FfiSlice::NULL,
+        }
+    }
+}
+
+export! {
+    /// Free the [`FmtHandle`], calling any destructors and cleaning up any
+    /// resources being used.
+    pub unsafe extern "C" fn fmt_handle_destroy(handle: *mut FmtHandle) {
+        drop(OwnedFmtHandle::from_raw(handle));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::fmt::Write as _;
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        unsafe {
+            let handle = new_string_fmt_handle();
+
+            let text = "Hello, 🌍!";
+            let ret =
+                fmt_handle_write_utf8(handle, text.as_ptr(), text.len());
+            assert_eq!(ret, 0);
+            let ret = fmt_handle_write_utf8(handle, b"\xff\xfe".as_ptr(), 2);
+            assert_eq!(ret, FMT_HANDLE_INVALID_UTF8);
+
+            let written = fmt_handle_as_string(handle).as_slice();
+            assert_eq!(written, text.as_bytes());
+            fmt_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn characters_can_be_split_across_writes() {
+        let handle = OwnedFmtHandle::new(String::new());
+        let mut bytes = handle.into_file_handle();
+        let text = "🌍é";
+
+        for byte in text.as_bytes() {
+            bytes.write_all(&[*byte]).unwrap();
+        }
+        bytes.flush().unwrap();
+
+        // a character which never gets finished
+        bytes.write_all(&text.as_bytes()[..2]).unwrap();
+        assert_eq!(bytes.flush().unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let bridge = bytes.downcast::<Utf8Bridge>().ok().unwrap();
+        let written = unsafe {
+            fmt_handle_as_string(bridge.inner.0.as_ptr()).as_slice().to_vec()
+        };
+        assert_eq!(written, text.as_bytes());
+    }
+
+    #[test]
+    fn text_can_be_written_to_a_file_handle() {
+        unsafe {
+            let inner = new_memory_file_handle();
+            let mut handle = OwnedFmtHandle::from_raw(
+                new_fmt_handle_for_file_handle(inner),
+            );
+
+            write!(handle, "{} + {} = {}", 1, 2, 3).unwrap();
+
+            assert_eq!(file_handle_as_memory(inner).as_slice(), b"1 + 2 = 3");
+        }
+    }
+}
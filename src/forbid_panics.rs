@@ -0,0 +1,174 @@
+//! Making the compiler check that the hot write and flush paths can't
+//! panic, for embedders built with `panic = "abort"` where any panic takes
+//! down the whole process.
+//!
+//! When `$THIN_TRAIT_OBJECTS_CHECK_PANICS` is set, optimised builds wrap each
+//! checked block in a `Guard` which refers to a symbol that doesn't exist.
+//! The guard is forgotten at the end of the block, so the only way the
+//! symbol is kept is if the optimiser can't prove the block never unwinds.
+//! The `no_panics_on_the_hot_path` test (run with the `forbid-panics`
+//! feature) builds the release library this way and fails if the symbol
+//! made it in. Normal builds never refer to the symbol, because how much the
+//! optimiser can prove depends on the build settings.
+//!
+//! Only writes and flushes to handles without any optional features (quotas,
+//! write filters, statistics, etc.) are checked. Calls into the writer
+//! itself are wrapped in [`contain()`], so a panicking writer poisons the
+//! handle like it would without the feature, and unwinding builds still
+//! report it as an error.
+//!
+//! Combined with the `c-unwind` feature, anything which unwinds out of the
+//! object (e.g. a callback throwing a C++ exception) isn't contained, and
+//! propagates to the caller as `c-unwind` promises. The guarantee then only
+//! covers the crate's own code, which is why the check builds the library
+//! without `c-unwind`.
+
+use std::{
+    any::Any,
+    io::Error,
+    mem::{self, ManuallyDrop, MaybeUninit},
+    os::raw::c_int,
+    panic::{self, AssertUnwindSafe},
+};
+
+/// The symbol referenced by code which might panic.
+#[cfg(all(test, feature = "forbid-panics"))]
+const VIOLATION: &str = "thin_trait_objects_forbid_panics_violation";
+
+/// Evaluate `$body`, making optimised builds prove that it can't panic.
+macro_rules! forbid_panics {
+    ($body:block) => {{
+        #[cfg(all(tto_check_panics, not(debug_assertions)))]
+        let guard = $crate::forbid_panics::Guard;
+        // Note: the closure stops an early return from skipping the forget
+        #[allow(clippy::redundant_closure_call)]
+        let result = (move || $body)();
+        #[cfg(all(tto_check_panics, not(debug_assertions)))]
+        ::std::mem::forget(guard);
+        result
+    }};
+}
+
+/// Only dropped while unwinding out of a [`forbid_panics!()`] block.
+#[cfg(all(tto_check_panics, not(debug_assertions)))]
+pub(crate) struct Guard;
+
+#[cfg(all(tto_check_panics, not(debug_assertions)))]
+impl Drop for Guard {
+    fn drop(&mut self) {
+        extern "C" {
+            #[link_name = "thin_trait_objects_forbid_panics_violation"]
+            fn violation() -> !;
+        }
+
+        unsafe { violation() }
+    }
+}
+
+/// Call code we don't control (e.g. a writer, or an error's `Display` impl)
+/// from inside a [`forbid_panics!()`] block.
+///
+/// With the `forbid-panics` feature a panic is caught and handed to
+/// `on_panic` instead of unwinding into the block, because the guarantee
+/// only covers this crate's own code. The process is aborted if `on_panic`
+/// panics too.
+#[inline(always)]
+pub(crate) fn contain<T>(
+    body: impl FnOnce() -> T,
+    on_panic: impl FnOnce(Box<dyn Any + Send + 'static>) -> T,
+) -> T {
+    if !cfg!(feature = "forbid-panics") {
+        return body();
+    }
+
+    let caught = ManuallyDrop::new(move || {
+        match panic::catch_unwind(AssertUnwindSafe(body)) {
+            Ok(value) => value,
+            Err(payload) => on_panic(payload),
+        }
+    });
+    let mut result = MaybeUninit::uninit();
+
+    unsafe {
+        call_nounwind(&*caught, result.as_mut_ptr());
+        result.assume_init()
+    }
+}
+
+/// Call `f` through an ABI which can't unwind, because the optimiser
+/// doesn't know that nothing escapes a [`panic::catch_unwind()`].
+///
+/// # Safety
+///
+/// `f` is moved out of and must not be used again.
+#[inline(never)]
+unsafe extern "C" fn call_nounwind<F, T>(f: *const F, result: *mut T)
+where
+    F: FnOnce() -> T,
+{
+    result.write(f.read()());
+}
+
+/// Turn an error into the negative `errno` value returned by the FFI,
+/// without letting a panicking destructor unwind into the caller.
+#[inline(always)]
+pub(crate) fn into_errno(error: Error) -> c_int {
+    let code = errno_of(&error);
+    // the payload's destructor might panic as well, so leak it
+    contain(move || drop(error), mem::forget);
+    code
+}
+
+/// The negative `errno` value returned by the FFI for an error which is
+/// only borrowed (see [`into_errno()`]).
+#[inline(always)]
+pub(crate) fn errno_of(error: &Error) -> c_int {
+    match error.raw_os_error() {
+        Some(code) => -code,
+        None => crate::errors::crate_status_of(error)
+            .map_or(-1, crate::errors::crate_status_code),
+    }
+}
+
+#[cfg(all(test, feature = "forbid-panics"))]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path, process::Command};
+
+    #[test]
+    fn no_panics_on_the_hot_path() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target_dir = manifest_dir.join("target").join("forbid-panics");
+
+        let output = Command::new(env!("CARGO"))
+            .args(&["build", "--release", "--lib", "--features"])
+            .arg("forbid-panics")
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .current_dir(manifest_dir)
+            .env("THIN_TRAIT_OBJECTS_CHECK_PANICS", "1")
+            // the optimiser needs to see the whole crate at once
+            .env("CARGO_PROFILE_RELEASE_CODEGEN_UNITS", "1")
+            .output()
+            .unwrap();
+        // some linkers reject the undefined symbol outright
+        assert!(
+            output.status.success(),
+            "Unable to build the library (a panic on the hot path?)\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let library = target_dir.join("release").join(format!(
+            "{}thin_trait_objects{}",
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_SUFFIX
+        ));
+        let library = fs::read(&library).unwrap();
+        let violation = VIOLATION.as_bytes();
+
+        assert!(
+            !library.windows(violation.len()).any(|w| w == violation),
+            "Something on the hot path can panic"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Temporarily stopping all I/O on a handle without destroying it (e.g. while
+//! a plugin host checkpoints its state).
+
+use crate::{
+    errors::{crate_status_code, crate_status_unboxed, CRATE_ERROR_SUSPENDED},
+    FileHandle,
+};
+use std::{io::Error, os::raw::c_int};
+
+/// Returned when writing to or flushing a [`FileHandle`] which has been
+/// frozen with [`file_handle_freeze()`], or while a flush which timed out is
+/// still running.
+///
+/// This is [`CRATE_ERROR_SUSPENDED`] in the [`ErrorDomain::Crate`] domain,
+/// so it can't be confused with an `EAGAIN` from the writer itself.
+///
+/// [`CRATE_ERROR_SUSPENDED`]: crate::CRATE_ERROR_SUSPENDED
+/// [`ErrorDomain::Crate`]: crate::ErrorDomain::Crate
+pub const FILE_HANDLE_SUSPENDED: c_int =
+    crate_status_code(CRATE_ERROR_SUSPENDED);
+
+/// The error used when a [`FileHandle`] is frozen, which is checked on every
+/// write so mustn't allocate.
+pub(crate) fn suspended() -> Error {
+    crate_status_unboxed(CRATE_ERROR_SUSPENDED)
+}
+
+impl FileHandle {
+    pub(crate) fn is_frozen(&self) -> bool {
+        self.has_flag(FileHandle::FROZEN)
+    }
+
+    /// Fail with [`suspended()`] if the handle is frozen, or a flush which
+    /// timed out is still running.
+    pub(crate) unsafe fn check_frozen(
+        handle: *mut FileHandle,
+    ) -> Result<(), Error> {
+        if (*handle).has_flag(FileHandle::FROZEN | FileHandle::FLUSHING) {
+            let e = suspended();
+            (*handle).cold.last_error.record(&e);
+            Err(e)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Freeze the handle, flushing it one last time.
+pub(crate) unsafe fn freeze(handle: *mut FileHandle) -> Result<(), Error> {
+    if (*handle).is_frozen() {
+        return Ok(());
+    }
+
+    let _autoflush =
+        (*handle).extensions().and_then(|ext| ext.autoflush.guard());
+    (*handle).set_flag(FileHandle::FROZEN);
+
+    FileHandle::flush_unguarded(handle)
+}
+
+c_unwind! {
+    /// Flush the [`FileHandle`] and stop it from doing any more I/O until
+    /// [`file_handle_thaw()`] is called.
+    ///
+    /// While frozen, writes and flushes fail with [`FILE_HANDLE_SUSPENDED`]
+    /// and background flushing is skipped. Freezing a frozen handle does
+    /// nothing. Returns `0` on success, or a negative value if the final
+    /// flush failed (the handle is frozen either way).
+    pub unsafe extern fn file_handle_freeze(handle: *mut FileHandle) -> c_int {
+        match freeze(handle) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+export! {
+    /// Let a [`FileHandle`] frozen by [`file_handle_freeze()`] be used again.
+    pub unsafe extern "C" fn file_handle_thaw(handle: *mut FileHandle) {
+        (*handle).clear_flag(FileHandle::FROZEN);
+    }
+}
+
+export! {
+    /// Check whether a [`FileHandle`] is frozen.
+    pub unsafe extern "C" fn file_handle_is_frozen(
+        handle: *mut FileHandle,
+    ) -> bool {
+        (*handle).is_frozen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, OwnedFileHandle};
+    use std::io::{BufWriter, Write};
+
+    #[test]
+    fn frozen_handles_reject_io_until_thawed() {
+        unsafe {
+            let handle = new_memory_file_handle();
+            file_handle_write(handle, b"before ".as_ptr().cast(), 7);
+
+            assert_eq!(file_handle_freeze(handle), 0);
+            assert!(file_handle_is_frozen(handle));
+            let ret = file_handle_write(handle, b"lost".as_ptr().cast(), 4);
+            assert_eq!(ret, FILE_HANDLE_SUSPENDED);
+            assert_eq!(file_handle_flush(handle), FILE_HANDLE_SUSPENDED);
+
+            file_handle_thaw(handle);
+            file_handle_write(handle, b"after".as_ptr().cast(), 5);
+
+            let written = file_handle_as_memory(handle).as_slice();
+            assert_eq!(written, b"before after");
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn freezing_flushes_first() {
+        let buffer = crate::ffi::tests::SharedBuffer::default();
+        let mut handle = OwnedFileHandle::new(BufWriter::new(buffer.clone()));
+        handle.write_all(b"buffered").unwrap();
+        assert!(buffer.0.lock().unwrap().is_empty());
+
+        handle.freeze().unwrap();
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"buffered");
+        let err = handle.write(b"x").unwrap_err();
+        assert_eq!(
+            crate::errors::crate_status_of(&err),
+            Some(CRATE_ERROR_SUSPENDED)
+        );
+
+        handle.thaw();
+        assert!(!handle.is_frozen());
+        handle.write_all(b"!").unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+//! Lazily initialized global state which can be torn down again.
+
+use std::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// A global value which is allocated on first use and freed by
+/// [`Global::reset()`] (typically during
+/// [`thin_trait_objects_shutdown()`][crate::thin_trait_objects_shutdown]).
+pub(crate) struct Global<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T> Global<T> {
+    pub(crate) const fn new() -> Self {
+        Global {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Get the value, if it has been initialized.
+    pub(crate) fn get(&self) -> Option<&T> {
+        unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Get the value, initializing it if this is the first time it has been
+    /// used.
+    pub(crate) fn get_or_init(&self, init: impl FnOnce() -> T) -> &T {
+        let existing = self.ptr.load(Ordering::Acquire);
+        if !existing.is_null() {
+            return unsafe { &*existing };
+        }
+
+        let fresh = Box::into_raw(Box::new(init()));
+
+        match self.ptr.compare_exchange(
+            ptr::null_mut(),
+            fresh,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => unsafe { &*fresh },
+            Err(existing) => unsafe {
+                // Someone else beat us to it
+                drop(Box::from_raw(fresh));
+                &*existing
+            },
+        }
+    }
+
+    /// Free the value.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may still be using a reference returned by
+    /// [`Global::get_or_init()`].
+    pub(crate) unsafe fn reset(&self) {
+        let value = self.ptr.swap(ptr::null_mut(), Ordering::AcqRel);
+
+        if !value.is_null() {
+            drop(Box::from_raw(value));
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Managing many handles at once, while keeping track of which ones failed.
+
+use crate::{FileHandle, OwnedFileHandle};
+use std::{
+    io::{Error, Write},
+    os::raw::c_int,
+};
+
+/// A collection of handles which are flushed or closed together.
+///
+/// Every operation visits all members, even after one of them fails, and
+/// reports one result per member in the order they were added.
+///
+/// ```rust
+/// # use thin_trait_objects::{HandleGroup, OwnedFileHandle};
+/// let mut group = HandleGroup::new();
+/// group.add(OwnedFileHandle::new(Vec::new()));
+/// group.add(OwnedFileHandle::new(std::io::sink()));
+///
+/// let results = group.flush_all();
+/// assert!(results.iter().all(|r| r.is_ok()));
+/// assert_eq!(group.close_all().len(), 2);
+/// ```
+#[derive(Default)]
+pub struct HandleGroup {
+    members: Vec<OwnedFileHandle>,
+}
+
+impl HandleGroup {
+    /// Create an empty [`HandleGroup`].
+    pub fn new() -> Self { HandleGroup::default() }
+
+    /// Add a handle to the group, returning its index.
+    pub fn add(&mut self, handle: OwnedFileHandle) -> usize {
+        self.members.push(handle);
+        self.members.len() - 1
+    }
+
+    /// The number of handles in the group.
+    pub fn len(&self) -> usize { self.members.len() }
+
+    /// Is the group empty?
+    pub fn is_empty(&self) -> bool { self.members.is_empty() }
+
+    /// Get the handle at a particular index.
+    pub fn get(&self, index: usize) -> Option<&OwnedFileHandle> {
+        self.members.get(index)
+    }
+
+    /// Get mutable access to the handle at a particular index.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut OwnedFileHandle> {
+        self.members.get_mut(index)
+    }
+
+    /// Flush every handle.
+    pub fn flush_all(&mut self) -> Vec<Result<(), Error>> {
+        self.members.iter_mut().map(|h| h.flush()).collect()
+    }
+
+    /// Flush and then destroy every handle.
+    pub fn close_all(mut self) -> Vec<Result<(), Error>> {
+        let results = self.flush_all();
+        self.members.clear();
+        results
+    }
+}
+
+/// Copy each result into `results` (as `0` or a negative error code),
+/// returning how many failed.
+unsafe fn report(
+    outcome: Vec<Result<(), Error>>,
+    results: *mut c_int,
+    len: usize,
+) -> c_int {
+    let mut failures = 0;
+
+    for (i, result) in outcome.into_iter().enumerate() {
+        let code = match result {
+            Ok(_) => 0,
+            Err(e) => {
+                failures += 1;
+                crate::forbid_panics::into_errno(e)
+            },
+        };
+
+        if !results.is_null() && i < len {
+            results.add(i).write(code);
+        }
+    }
+
+    failures
+}
+
+export! {
+    /// Create an empty [`HandleGroup`].
+    ///
+    /// The group must be released with [`handle_group_close_all()`].
+    pub unsafe extern "C" fn handle_group_new() -> *mut HandleGroup {
+        Box::into_raw(Box::new(HandleGroup::new()))
+    }
+}
+
+export! {
+    /// Add a [`FileHandle`] to the group, taking ownership of it.
+    ///
+    /// Returns the handle's index in the group, which is also its position in
+    /// the results of [`handle_group_flush_all()`] and
+    /// [`handle_group_close_all()`], or `-1` if `handle` is `null`.
+    pub unsafe extern "C" fn handle_group_add(
+        group: *mut HandleGroup,
+        handle: *mut FileHandle,
+    ) -> c_int {
+        if handle.is_null() {
+            return -1;
+        }
+
+        (*group).add(OwnedFileHandle::from_raw(handle)) as c_int
+    }
+}
+
+export! {
+    /// The number of handles in the group.
+    pub unsafe extern "C" fn handle_group_len(
+        group: *const HandleGroup,
+    ) -> usize {
+        (*group).len()
+    }
+}
+
+c_unwind! {
+    /// Flush every handle in the group, even if some fail.
+    ///
+    /// When `results` isn't `null`, the result for each handle (`0` or a
+    /// negative error code) is stored at its index, up to `len` entries.
+    /// Returns the number of handles which failed to flush.
+    pub unsafe extern fn handle_group_flush_all(
+        group: *mut HandleGroup,
+        results: *mut c_int,
+        len: usize,
+    ) -> c_int {
+        report((*group).flush_all(), results, len)
+    }
+}
+
+c_unwind! {
+    /// Flush and destroy every handle in the group, then free the group.
+    ///
+    /// The `results` are reported the same way as
+    /// [`handle_group_flush_all()`], and the return value is the number of
+    /// handles which failed their final flush.
+    pub unsafe extern fn handle_group_close_all(
+        group: *mut HandleGroup,
+        results: *mut c_int,
+        len: usize,
+    ) -> c_int {
+        let group = Box::from_raw(group);
+        report(group.close_all(), results, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Err(Error::from_raw_os_error(5))
+        }
+    }
+
+    #[test]
+    fn failures_are_reported_per_handle() {
+        let mut group = HandleGroup::new();
+        group.add(OwnedFileHandle::new(Vec::new()));
+        let broken = group.add(OwnedFileHandle::new(Broken));
+        group.add(OwnedFileHandle::new(Vec::new()));
+
+        let results = group.flush_all();
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok() && results[2].is_ok());
+        let err = results[broken].as_ref().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(5));
+        assert!(group.get(broken).unwrap().is::<Broken>());
+    }
+
+    #[test]
+    fn manage_a_group_from_c() {
+        let mut results = [42; 4];
+
+        unsafe {
+            let group = handle_group_new();
+            assert_eq!(handle_group_add(group, std::ptr::null_mut()), -1);
+            assert_eq!(handle_group_add(group, new_memory_file_handle()), 0);
+            let broken = FileHandle::for_writer(Broken);
+            assert_eq!(handle_group_add(group, broken), 1);
+            assert_eq!(handle_group_len(group), 2);
+
+            let ptr = results.as_mut_ptr();
+            assert_eq!(handle_group_flush_all(group, ptr, 1), 1);
+            assert_eq!(results, [0, 42, 42, 42]);
+
+            assert_eq!(handle_group_close_all(group, ptr, 4), 1);
+        }
+
+        assert_eq!(results, [0, -5, 42, 42]);
+    }
+}
@@ -0,0 +1,388 @@
+//! A small gzip encoder (RFC 1951 and 1952) for compressing rotated files,
+//! using LZ77 and the fixed Huffman codes so it doesn't need any tables
+//! besides the ones in the spec.
+
+use std::io::{Error, Read, Write};
+
+/// How much input is compressed as a single block. Matches never cross
+/// blocks, which keeps memory use bounded.
+const BLOCK_SIZE: usize = 1 << 20;
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are checked for a match.
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59,
+    67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5,
+    5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513,
+    769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10,
+    11, 11, 12, 12, 13, 13,
+];
+
+/// The CRC-32 used by gzip.
+pub(crate) fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+/// Writes bits least-significant first, the way DEFLATE packs them.
+struct BitWriter<W> {
+    inner: W,
+    bits: u64,
+    len: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn new(inner: W) -> Self {
+        BitWriter {
+            inner,
+            bits: 0,
+            len: 0,
+            buffer: Vec::with_capacity(8192),
+        }
+    }
+
+    fn bits(&mut self, value: u32, len: u32) {
+        self.bits |= u64::from(value) << self.len;
+        self.len += len;
+
+        while self.len >= 8 {
+            self.buffer.push(self.bits as u8);
+            self.bits >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Write a Huffman code, which is packed most-significant bit first.
+    fn code(&mut self, code: u32, len: u32) {
+        let reversed = code.reverse_bits() >> (32 - len);
+        self.bits(reversed, len);
+    }
+
+    fn drain(&mut self) -> Result<(), Error> {
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W, Error> {
+        if self.len > 0 {
+            self.buffer.push(self.bits as u8);
+        }
+        self.drain()?;
+        Ok(self.inner)
+    }
+}
+
+fn literal<W: Write>(out: &mut BitWriter<W>, symbol: u32) {
+    match symbol {
+        0..=143 => out.code(0x30 + symbol, 8),
+        144..=255 => out.code(0x190 + symbol - 144, 9),
+        256..=279 => out.code(symbol - 256, 7),
+        _ => out.code(0xC0 + symbol - 280, 8),
+    }
+}
+
+fn copy<W: Write>(out: &mut BitWriter<W>, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= length)
+        .expect("Matches are at least 3 bytes long");
+    literal(out, 257 + code as u32);
+    let extra = length - usize::from(LENGTH_BASE[code]);
+    out.bits(extra as u32, u32::from(LENGTH_EXTRA[code]));
+
+    let code = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .expect("Distances start at 1");
+    out.code(code as u32, 5);
+    let extra = distance - usize::from(DISTANCE_BASE[code]);
+    out.bits(extra as u32, u32::from(DISTANCE_EXTRA[code]));
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = u32::from(data[0]) << 16
+        | u32::from(data[1]) << 8
+        | u32::from(data[2]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compress one block using the fixed Huffman codes.
+fn block<W: Write>(out: &mut BitWriter<W>, data: &[u8], last: bool) {
+    out.bits(u32::from(last), 1);
+    out.bits(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let mut i = 0;
+
+    while i < data.len() {
+        let mut best = (0, 0);
+
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(&data[i..]);
+            let mut candidate = head[h];
+            let longest = (data.len() - i).min(MAX_MATCH);
+
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || i - candidate > WINDOW_SIZE {
+                    break;
+                }
+
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[i..i + longest])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, i - candidate);
+                }
+                candidate = prev[candidate];
+            }
+
+            prev[i] = head[h];
+            head[h] = i;
+        }
+
+        if best.0 >= MIN_MATCH {
+            copy(out, best.0, best.1);
+
+            // remember the positions we skipped over so later matches can
+            // start from them
+            for j in i + 1..(i + best.0).min(data.len() + 1 - MIN_MATCH) {
+                let h = hash(&data[j..]);
+                prev[j] = head[h];
+                head[h] = j;
+            }
+            i += best.0;
+        } else {
+            literal(out, u32::from(data[i]));
+            i += 1;
+        }
+    }
+
+    literal(out, 256);
+}
+
+/// Compress everything from `reader` into `writer` as a gzip stream.
+pub(crate) fn compress<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+) -> Result<W, Error> {
+    let mut out = BitWriter::new(writer);
+    // magic, deflate, no flags, no timestamp, no extra flags, unknown OS
+    out.inner
+        .write_all(&[0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF])?;
+
+    let mut crc = 0;
+    let mut total: u32 = 0;
+    let mut chunk = vec![0; BLOCK_SIZE];
+    let mut filled = read_full(&mut reader, &mut chunk)?;
+
+    loop {
+        let mut next = vec![0; BLOCK_SIZE];
+        let next_filled = if filled == BLOCK_SIZE {
+            read_full(&mut reader, &mut next)?
+        } else {
+            0
+        };
+        let last = next_filled == 0;
+
+        let data = &chunk[..filled];
+        crc = crc32(crc, data);
+        total = total.wrapping_add(data.len() as u32);
+        block(&mut out, data, last);
+        out.drain()?;
+
+        if last {
+            break;
+        }
+        chunk = next;
+        filled = next_filled;
+    }
+
+    let mut writer = out.finish()?;
+    writer.write_all(&crc.to_le_bytes())?;
+    writer.write_all(&total.to_le_bytes())?;
+
+    Ok(writer)
+}
+
+fn read_full<R: Read>(
+    reader: &mut R,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_crc() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn repetitive_data_gets_smaller() {
+        let line = b"2026-10-14 level=info message=\"request handled\"\n";
+        let data: Vec<u8> =
+            line.iter().cycle().take(100_000).copied().collect();
+
+        let compressed = compress(&data[..], Vec::new()).unwrap();
+
+        assert_eq!(&compressed[..3], &[0x1F, 0x8B, 8]);
+        assert!(compressed.len() < data.len() / 20, "{}", compressed.len());
+        let trailer = &compressed[compressed.len() - 8..];
+        assert_eq!(&trailer[..4], &crc32(0, &data).to_le_bytes());
+        assert_eq!(&trailer[4..], &(data.len() as u32).to_le_bytes());
+    }
+
+    /// Reads bits least-significant first, undoing [`BitWriter`].
+    struct BitReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, len: u32) -> u32 {
+            let mut value = 0;
+
+            for i in 0..len {
+                let byte = self.data[self.position / 8];
+                let bit = (byte >> (self.position % 8)) & 1;
+                value |= u32::from(bit) << i;
+                self.position += 1;
+            }
+
+            value
+        }
+
+        /// Read a Huffman code, which is packed most-significant bit first.
+        fn code(&mut self, len: u32) -> u32 {
+            (0..len).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        /// Decode a symbol using the fixed literal/length code.
+        fn literal(&mut self) -> u32 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return code + 256;
+            }
+
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xBF => code - 0x30,
+                0xC0..=0xC7 => code - 0xC0 + 280,
+                _ => (code << 1 | self.bits(1)) - 0x190 + 144,
+            }
+        }
+    }
+
+    /// Decompress a gzip stream which only uses fixed Huffman blocks.
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        assert_eq!(&compressed[..10], &[0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF]);
+        let mut input = BitReader {
+            data: &compressed[10..],
+            position: 0,
+        };
+        let mut out: Vec<u8> = Vec::new();
+
+        loop {
+            let last = input.bits(1) == 1;
+            assert_eq!(input.bits(2), 1, "Only fixed blocks are used");
+
+            loop {
+                let symbol = input.literal() as usize;
+                if symbol < 256 {
+                    out.push(symbol as u8);
+                    continue;
+                } else if symbol == 256 {
+                    break;
+                }
+
+                let code = symbol - 257;
+                let extra = input.bits(u32::from(LENGTH_EXTRA[code]));
+                let length = usize::from(LENGTH_BASE[code]) + extra as usize;
+                let code = input.code(5) as usize;
+                let extra = input.bits(u32::from(DISTANCE_EXTRA[code]));
+                let distance =
+                    usize::from(DISTANCE_BASE[code]) + extra as usize;
+
+                assert!(distance <= out.len() && distance <= WINDOW_SIZE);
+                for _ in 0..length {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        let trailer = &compressed[10 + (input.position + 7) / 8..];
+        assert_eq!(trailer.len(), 8);
+        assert_eq!(&trailer[..4], &crc32(0, &out).to_le_bytes());
+        assert_eq!(&trailer[4..], &(out.len() as u32).to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        // every byte value, runs much longer than the longest match, and
+        // repeats from near the far end of the window
+        let mut data: Vec<u8> = (0..=255).collect();
+        data.extend(std::iter::repeat(b'a').take(5000));
+        let mut state: u32 = 1;
+        let noise: Vec<u8> = (0..WINDOW_SIZE - 100)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        data.extend_from_slice(&noise);
+        data.extend_from_slice(&noise[..1000]);
+        data.extend((0..=255).rev());
+
+        for input in &[&data[..], b"", b"x"] {
+            let compressed = compress(*input, Vec::new()).unwrap();
+            assert_eq!(decompress(&compressed), *input);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! A logger which writes log records to a [`FileHandle`][crate::FileHandle],
+//! so Rust code inside a plugin can send its logs to a handle provided by the
+//! host.
+
+use crate::{
+    global_clock, set_log_sink, Clock, LogLevel, LogRecord, LogSink,
+    OwnedFileHandle,
+};
+use std::{
+    fmt::Write as _,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// How a [`HandleLogger`] formats each record.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `[INFO plugin] message`
+    Compact,
+    /// Like [`LogFormat::Compact`], but starting with the number of seconds
+    /// since the Unix epoch (e.g. `1700000000.123 [INFO plugin] message`).
+    Timestamped,
+    /// One JSON object per line, with `level`, `target`, `message` and
+    /// `timestamp` fields.
+    Json,
+}
+
+/// A [`LogSink`] which writes each record to an [`OwnedFileHandle`] as a
+/// single line.
+///
+/// Plugins using the `log` crate can forward to it from their `log::Log`
+/// implementation by calling [`HandleLogger::log_message()`]. Errors while
+/// writing are ignored, and the handle is flushed after every record.
+#[derive(Debug)]
+pub struct HandleLogger {
+    handle: Mutex<OwnedFileHandle>,
+    format: LogFormat,
+    max_level: LogLevel,
+    clock: Arc<dyn Clock>,
+}
+
+impl HandleLogger {
+    /// Create a new [`HandleLogger`] which logs everything.
+    pub fn new(handle: OwnedFileHandle, format: LogFormat) -> Self {
+        HandleLogger::with_clock(handle, format, global_clock())
+    }
+
+    /// Create a new [`HandleLogger`] which gets timestamps from `clock`.
+    pub fn with_clock(
+        handle: OwnedFileHandle,
+        format: LogFormat,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        HandleLogger {
+            handle: Mutex::new(handle),
+            format,
+            max_level: LogLevel::Trace,
+            clock,
+        }
+    }
+
+    /// Create a new [`HandleLogger`] and make it the global [`LogSink`] (see
+    /// [`set_log_sink()`]).
+    pub fn init(handle: OwnedFileHandle, format: LogFormat) -> Arc<Self> {
+        let logger = Arc::new(HandleLogger::new(handle, format));
+        set_log_sink(Arc::clone(&logger) as Arc<dyn LogSink>);
+        logger
+    }
+
+    /// Ignore any records less important than `max_level`.
+    pub fn with_max_level(self, max_level: LogLevel) -> Self {
+        HandleLogger { max_level, ..self }
+    }
+
+    /// Would a record at this level be logged?
+    pub fn enabled(&self, level: LogLevel) -> bool { level <= self.max_level }
+
+    /// Log a message.
+    pub fn log_message(&self, level: LogLevel, target: &str, message: &str) {
+        self.log(&LogRecord {
+            level,
+            target,
+            message,
+        });
+    }
+
+    fn format(&self, record: &LogRecord<'_>) -> String {
+        let mut line = String::new();
+        let timestamp = self
+            .clock
+            .wall_time()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let timestamp = format!(
+            "{}.{:03}",
+            timestamp.as_secs(),
+            timestamp.subsec_millis()
+        );
+
+        let _ = match self.format {
+            LogFormat::Compact => write!(
+                line,
+                "[{} {}] {}",
+                record.level, record.target, record.message
+            ),
+            LogFormat::Timestamped => write!(
+                line,
+                "{} [{} {}] {}",
+                timestamp, record.level, record.target, record.message
+            ),
+            LogFormat::Json => write!(
+                line,
+                r#"{{"timestamp":{},"level":"{}","target":{},"message":{}}}"#,
+                timestamp,
+                record.level,
+                json_string(record.target),
+                json_string(record.message),
+            ),
+        };
+
+        line.push('\n');
+        line
+    }
+}
+
+impl LogSink for HandleLogger {
+    fn log(&self, record: &LogRecord<'_>) {
+        if !self.enabled(record.level) {
+            return;
+        }
+
+        let line = self.format(record);
+        let mut handle =
+            self.handle.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = handle.write_all(line.as_bytes());
+        let _ = handle.flush();
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            },
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::tests::SharedBuffer, ManualClock};
+    use std::time::Duration;
+
+    fn logger(format: LogFormat) -> (HandleLogger, SharedBuffer) {
+        let buffer = SharedBuffer::default();
+        let clock = ManualClock::default();
+        clock.advance(Duration::from_millis(1500));
+        let logger = HandleLogger::with_clock(
+            OwnedFileHandle::new(buffer.clone()),
+            format,
+            Arc::new(clock),
+        );
+
+        (logger, buffer)
+    }
+
+    fn written(buffer: &SharedBuffer) -> String {
+        String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn plain_formats() {
+        let (compact, compact_buffer) = logger(LogFormat::Compact);
+        let (timestamped, timestamped_buffer) = logger(LogFormat::Timestamped);
+
+        compact.log_message(LogLevel::Info, "plugin", "Hello");
+        timestamped.log_message(LogLevel::Error, "plugin", "Oops");
+
+        assert_eq!(written(&compact_buffer), "[INFO plugin] Hello\n");
+        assert_eq!(
+            written(&timestamped_buffer),
+            "1.500 [ERROR plugin] Oops\n"
+        );
+    }
+
+    #[test]
+    fn json_is_escaped() {
+        let (logger, buffer) = logger(LogFormat::Json);
+
+        logger.log_message(LogLevel::Warn, "plugin", "say \"hi\"\n");
+
+        assert_eq!(
+            written(&buffer),
+            "{\"timestamp\":1.500,\"level\":\"WARN\",\"target\":\"plugin\",\
+             \"message\":\"say \\\"hi\\\"\\n\"}\n"
+        );
+    }
+
+    #[test]
+    fn less_important_records_are_ignored() {
+        let (logger, buffer) = logger(LogFormat::Compact);
+        let logger = logger.with_max_level(LogLevel::Info);
+
+        logger.log_message(LogLevel::Debug, "plugin", "Ignored");
+        logger.log_message(LogLevel::Warn, "plugin", "Logged");
+
+        assert!(!logger.enabled(LogLevel::Trace));
+        assert_eq!(written(&buffer), "[WARN plugin] Logged\n");
+    }
+}
@@ -0,0 +1,292 @@
+//! An opt-in audit trail of every time a handle changes hands, for tracking
+//! down use-after-free and double-free bugs at the FFI boundary.
+//!
+//! Recording is off by default and costs a single atomic load per ownership
+//! transition while it is off. Turn it on with
+//! [`Config::with_ownership_history()`][crate::Config::with_ownership_history].
+
+use crate::{
+    budget::Charge, global::Global, global_clock, thread_stats, FileHandle,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+/// The most events remembered for a single handle. Older events are
+/// discarded first.
+const MAX_EVENTS: usize = 64;
+
+/// How many destroyed handles have their history kept around.
+const MAX_DESTROYED: usize = 1024;
+
+/// How much of the memory budget each recorded event takes.
+const RECORD_SIZE: usize = std::mem::size_of::<OwnershipRecord>();
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static HISTORY: Global<Mutex<History>> = Global::new();
+
+/// Something which happened to a handle's ownership.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub enum OwnershipEvent {
+    /// The handle was allocated (e.g. by [`FileHandle::for_writer()`]).
+    Created = 0,
+    /// An [`OwnedFileHandle`][crate::OwnedFileHandle] gave up ownership with
+    /// [`into_raw()`][crate::OwnedFileHandle::into_raw].
+    IntoRaw = 1,
+    /// An [`OwnedFileHandle`][crate::OwnedFileHandle] took ownership with
+    /// [`from_raw()`][crate::OwnedFileHandle::from_raw].
+    FromRaw = 2,
+    /// The handle was freed.
+    Destroyed = 3,
+}
+
+/// A single entry in a handle's ownership history.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct OwnershipRecord {
+    /// What happened.
+    pub event: OwnershipEvent,
+    /// The thread it happened on, as returned by
+    /// [`thin_trait_objects_current_thread_id()`].
+    ///
+    /// [`thin_trait_objects_current_thread_id()`]:
+    /// crate::thin_trait_objects_current_thread_id
+    pub thread_id: u64,
+    /// When it happened, in microseconds since the Unix epoch according to
+    /// the [`global_clock()`].
+    pub timestamp_us: u64,
+}
+
+#[derive(Default)]
+struct History {
+    /// Every event, keyed by the handle's address.
+    handles: HashMap<usize, Vec<OwnershipRecord>>,
+    /// Destroyed handles, oldest first.
+    destroyed: VecDeque<usize>,
+    /// The memory budget taken by the events in `handles`.
+    charge: Charge,
+}
+
+impl History {
+    fn record(&mut self, address: usize, record: OwnershipRecord) {
+        if record.event == OwnershipEvent::Created {
+            // the allocator reused the address, so start afresh
+            self.forget(address);
+        }
+
+        let events = self.handles.entry(address).or_default();
+        if events.len() == MAX_EVENTS {
+            events.remove(0);
+        } else if self.charge.grow(RECORD_SIZE).is_err() {
+            // the memory budget is used up, so this event goes unrecorded
+            return;
+        }
+        events.push(record);
+
+        if record.event == OwnershipEvent::Destroyed {
+            self.destroyed.push_back(address);
+
+            if self.destroyed.len() > MAX_DESTROYED {
+                self.forget_oldest_destroyed();
+            }
+        }
+    }
+
+    fn forget_oldest_destroyed(&mut self) {
+        let address = match self.destroyed.pop_front() {
+            Some(a) => a,
+            None => return,
+        };
+
+        // only forget the history if the address hasn't been reused since
+        let still_destroyed = self
+            .handles
+            .get(&address)
+            .and_then(|events| events.last())
+            .map_or(false, |last| last.event == OwnershipEvent::Destroyed);
+
+        if still_destroyed {
+            self.forget(address);
+        }
+    }
+
+    fn forget(&mut self, address: usize) {
+        if let Some(events) = self.handles.remove(&address) {
+            let remaining = self.charge.bytes() - events.len() * RECORD_SIZE;
+            self.charge.shrink_to(remaining);
+        }
+    }
+}
+
+fn with_history<T>(thunk: impl FnOnce(&mut History) -> T) -> T {
+    let history = HISTORY.get_or_init(Default::default);
+    let mut history = history.lock().unwrap_or_else(|e| e.into_inner());
+    thunk(&mut history)
+}
+
+pub(crate) fn is_enabled() -> bool { ENABLED.load(Ordering::Relaxed) }
+
+/// Start or stop recording. Stopping also forgets everything recorded so
+/// far.
+pub(crate) fn set_enabled(enabled: bool) {
+    let was_enabled = ENABLED.swap(enabled, Ordering::Relaxed);
+
+    if was_enabled && !enabled {
+        if let Some(history) = HISTORY.get() {
+            let mut history =
+                history.lock().unwrap_or_else(|e| e.into_inner());
+            *history = History::default();
+        }
+    }
+}
+
+/// Stop recording and free everything recorded so far.
+pub(crate) unsafe fn shutdown() {
+    ENABLED.store(false, Ordering::Relaxed);
+    HISTORY.reset();
+}
+
+/// Add an event to the handle's history, if recording is turned on.
+pub(crate) fn record(handle: *const FileHandle, event: OwnershipEvent) {
+    if !is_enabled() {
+        return;
+    }
+
+    let timestamp_us = global_clock()
+        .wall_time()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64);
+    let record = OwnershipRecord {
+        event,
+        thread_id: thread_stats::current_thread_id(),
+        timestamp_us,
+    };
+
+    with_history(|history| history.record(handle as usize, record));
+}
+
+/// Get everything recorded for the handle at this address, oldest first.
+pub(crate) fn records(handle: *const FileHandle) -> Vec<OwnershipRecord> {
+    match HISTORY.get() {
+        Some(_) => with_history(|history| {
+            history
+                .handles
+                .get(&(handle as usize))
+                .cloned()
+                .unwrap_or_default()
+        }),
+        None => Vec::new(),
+    }
+}
+
+export! {
+    /// Copy up to `len` of the handle's [`OwnershipRecord`]s into `buffer`,
+    /// oldest first.
+    ///
+    /// The handle is only used as an address and is never dereferenced, so this
+    /// may be called with a handle which has already been destroyed. Returns
+    /// the total number of records, which will be more than `len` if `buffer`
+    /// was too small. Nothing is recorded unless ownership history has been
+    /// turned on (see [`FfiConfig`][crate::FfiConfig]).
+    pub unsafe extern "C" fn file_handle_history(
+        handle: *const FileHandle,
+        buffer: *mut OwnershipRecord,
+        len: usize,
+    ) -> usize {
+        let records = records(handle);
+
+        if !buffer.is_null() {
+            let count = records.len().min(len);
+            ptr::copy_nonoverlapping(records.as_ptr(), buffer, count);
+        }
+
+        records.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, lifecycle::lock_global_state, Config, OwnedFileHandle};
+
+    fn events(records: &[OwnershipRecord]) -> Vec<OwnershipEvent> {
+        records.iter().map(|r| r.event).collect()
+    }
+
+    #[test]
+    fn ownership_transitions_are_recorded() {
+        let _global = lock_global_state();
+        Config::new().with_ownership_history(true).apply();
+
+        let handle = OwnedFileHandle::new(Vec::new());
+        let raw = handle.into_raw();
+        let handle = unsafe { OwnedFileHandle::from_raw(raw) };
+        let thread_id = thread_stats::current_thread_id();
+        assert_eq!(
+            events(&handle.history()),
+            [
+                OwnershipEvent::Created,
+                OwnershipEvent::IntoRaw,
+                OwnershipEvent::FromRaw
+            ]
+        );
+        drop(handle);
+
+        unsafe {
+            let mut buffer = [OwnershipRecord {
+                event: OwnershipEvent::Created,
+                thread_id: 0,
+                timestamp_us: 0,
+            }; 2];
+            // the history outlives the handle
+            let total = file_handle_history(raw, buffer.as_mut_ptr(), 2);
+            assert_eq!(total, 4);
+            assert_eq!(buffer[1].event, OwnershipEvent::IntoRaw);
+            assert_eq!(buffer[1].thread_id, thread_id);
+            assert!(buffer[1].timestamp_us >= buffer[0].timestamp_us);
+
+            let total = file_handle_history(raw, ptr::null_mut(), 0);
+            assert_eq!(total, 4);
+        }
+
+        Config::default().apply();
+    }
+
+    #[test]
+    fn nothing_is_recorded_by_default() {
+        let _global = lock_global_state();
+
+        unsafe {
+            let handle = new_memory_file_handle();
+            assert_eq!(file_handle_history(handle, ptr::null_mut(), 0), 0);
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn reused_addresses_start_afresh() {
+        let mut history = History::default();
+        let record = |event| OwnershipRecord {
+            event,
+            thread_id: 0,
+            timestamp_us: 0,
+        };
+
+        history.record(1, record(OwnershipEvent::Created));
+        history.record(1, record(OwnershipEvent::Destroyed));
+        history.record(1, record(OwnershipEvent::Created));
+        assert_eq!(events(&history.handles[&1]), [OwnershipEvent::Created]);
+
+        // the stale entry in the destroyed list mustn't wipe the new history
+        history.forget_oldest_destroyed();
+        assert_eq!(history.handles[&1].len(), 1);
+    }
+}
@@ -0,0 +1,311 @@
+//! Skipping writes which have already been applied, so native callers can
+//! safely retry a write (e.g. after a timeout) without duplicating records.
+
+use crate::{FileHandle, OwnedFileHandle};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    io::{Error, Write},
+    os::raw::{c_char, c_int},
+    ptr,
+    sync::{Mutex, MutexGuard},
+};
+
+/// The most recently used keys, forgetting the least recently used ones
+/// once there are more than `capacity`.
+#[derive(Debug)]
+struct RecentKeys {
+    capacity: usize,
+    /// When each key was last used.
+    last_used: HashMap<u64, u64>,
+    /// Keys in the order they were used, including stale entries for keys
+    /// which were used again later.
+    order: VecDeque<(u64, u64)>,
+    clock: u64,
+}
+
+impl RecentKeys {
+    fn new(capacity: usize) -> Self {
+        RecentKeys {
+            capacity,
+            last_used: HashMap::new(),
+            order: VecDeque::new(),
+            clock: 0,
+        }
+    }
+
+    /// Check whether `key` was seen recently, marking it as used.
+    fn touch(&mut self, key: u64) -> bool {
+        match self.last_used.get_mut(&key) {
+            Some(last_used) => {
+                self.clock += 1;
+                *last_used = self.clock;
+                self.order.push_back((key, self.clock));
+                self.compact();
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, key: u64) {
+        self.clock += 1;
+        self.last_used.insert(key, self.clock);
+        self.order.push_back((key, self.clock));
+
+        while self.last_used.len() > self.capacity {
+            let (key, used) = match self.order.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if self.last_used.get(&key) == Some(&used) {
+                self.last_used.remove(&key);
+            }
+        }
+        self.compact();
+    }
+
+    /// Drop stale entries once they outnumber the live ones, so repeatedly
+    /// touching the same key doesn't grow the queue forever.
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.capacity.max(1) {
+            let last_used = &self.last_used;
+            self.order
+                .retain(|(key, used)| last_used.get(key) == Some(used));
+        }
+    }
+}
+
+/// A writer which remembers the keys of recent
+/// [`IdempotentWriter::write_idempotent()`] calls and quietly skips any
+/// write whose key it has already seen.
+///
+/// Only the last `capacity` keys are remembered, so keys should be unique
+/// (e.g. a sequence number or hash of the record) and retries should happen
+/// soon after the original attempt. Normal writes pass straight through.
+///
+/// ```rust
+/// # use thin_trait_objects::{IdempotentWriter, OwnedFileHandle};
+/// let inner = OwnedFileHandle::new(Vec::<u8>::new());
+/// let mut writer = IdempotentWriter::new(inner, 128);
+///
+/// assert!(writer.write_idempotent(1, b"first\n").unwrap());
+/// // a retry of a write which actually succeeded
+/// assert!(!writer.write_idempotent(1, b"first\n").unwrap());
+/// assert!(writer.write_idempotent(2, b"second\n").unwrap());
+///
+/// let inner = writer.into_inner();
+/// assert_eq!(inner.downcast_ref::<Vec<u8>>().unwrap(), b"first\nsecond\n");
+/// ```
+#[derive(Debug)]
+pub struct IdempotentWriter {
+    inner: OwnedFileHandle,
+    seen: Mutex<RecentKeys>,
+}
+
+impl IdempotentWriter {
+    /// Create a new [`IdempotentWriter`] which remembers up to `capacity`
+    /// keys.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn new(inner: OwnedFileHandle, capacity: usize) -> Self {
+        assert!(capacity > 0, "At least one key must be remembered");
+
+        IdempotentWriter {
+            inner,
+            seen: Mutex::new(RecentKeys::new(capacity)),
+        }
+    }
+
+    /// Write all of `data` unless a write with the same `key` was applied
+    /// recently, returning whether the write went ahead.
+    ///
+    /// The key is only remembered once the write succeeds.
+    pub fn write_idempotent(
+        &mut self,
+        key: u64,
+        data: &[u8],
+    ) -> Result<bool, Error> {
+        if self.seen().touch(key) {
+            return Ok(false);
+        }
+
+        self.inner.write_all(data)?;
+        self.seen().insert(key);
+
+        Ok(true)
+    }
+
+    /// Get a reference to the inner handle.
+    pub fn get_ref(&self) -> &OwnedFileHandle { &self.inner }
+
+    /// Get the inner handle back.
+    pub fn into_inner(self) -> OwnedFileHandle { self.inner }
+
+    fn seen(&self) -> MutexGuard<'_, RecentKeys> {
+        self.seen.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Write for IdempotentWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.inner.flush() }
+}
+
+impl OwnedFileHandle {
+    /// Write all of `data` unless it is a retry of a recent write with the
+    /// same `key` (see [`file_handle_write_idempotent()`]), returning whether
+    /// the write went ahead.
+    pub fn write_idempotent(
+        &mut self,
+        key: u64,
+        data: &[u8],
+    ) -> Result<bool, Error> {
+        unsafe { write_idempotent(self.as_ptr(), key, data) }
+    }
+}
+
+unsafe fn write_idempotent(
+    handle: *mut FileHandle,
+    key: u64,
+    data: &[u8],
+) -> Result<bool, Error> {
+    let writer = match FileHandle::downcast_raw::<IdempotentWriter>(handle) {
+        Some(writer) => writer as *const IdempotentWriter,
+        None => {
+            let unsupported = -crate::FILE_HANDLE_UNSUPPORTED;
+            return Err(Error::from_raw_os_error(unsupported));
+        },
+    };
+
+    if (*writer).seen().touch(key) {
+        return Ok(false);
+    }
+
+    // go through the handle so its policies (quotas, freezing, etc.) apply
+    FileHandle::dispatch_write_all(handle, data)?;
+    (*writer).seen().insert(key);
+
+    Ok(true)
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to `inner` and skips any
+    /// [`file_handle_write_idempotent()`] whose key is one of the last
+    /// `capacity` keys it has seen, taking ownership of `inner`.
+    ///
+    /// Returns `null` if `inner` is `null` or `capacity` is zero, in which
+    /// case ownership of `inner` is not taken.
+    pub unsafe extern "C" fn new_idempotent_file_handle(
+        inner: *mut FileHandle,
+        capacity: usize,
+    ) -> *mut FileHandle {
+        if inner.is_null() || capacity == 0 {
+            return ptr::null_mut();
+        }
+
+        let inner = OwnedFileHandle::from_raw(inner);
+        FileHandle::for_writer(IdempotentWriter::new(inner, capacity))
+    }
+}
+
+c_unwind! {
+    /// Write all `len` bytes of `data` to a handle created with
+    /// [`new_idempotent_file_handle()`], unless a write with the same `key`
+    /// was applied recently.
+    ///
+    /// Returns `1` if the data was written, `0` if it was skipped as a
+    /// duplicate, or a negative error code (e.g.
+    /// [`FILE_HANDLE_UNSUPPORTED`] for other handles). The key is only
+    /// remembered once the write succeeds, so failed writes can be retried
+    /// with the same key.
+    ///
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub unsafe extern fn file_handle_write_idempotent(
+        handle: *mut FileHandle,
+        key: u64,
+        data: *const c_char,
+        len: c_int,
+    ) -> c_int {
+        let len = match usize::try_from(len) {
+            Ok(len) => len,
+            Err(_) => return crate::FILE_HANDLE_INVALID_LENGTH,
+        };
+        let data = std::slice::from_raw_parts(data as *const u8, len);
+
+        match write_idempotent(handle, key, data) {
+            Ok(applied) => applied as c_int,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn only_recent_keys_are_remembered() {
+        let mut keys = RecentKeys::new(2);
+
+        keys.insert(1);
+        keys.insert(2);
+        assert!(keys.touch(1));
+        // 2 is now the least recently used
+        keys.insert(3);
+
+        assert!(keys.touch(1));
+        assert!(!keys.touch(2));
+        assert!(keys.touch(3));
+
+        for _ in 0..100 {
+            keys.touch(1);
+        }
+        assert!(keys.order.len() <= 4);
+    }
+
+    #[test]
+    fn retries_from_c_are_skipped() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let inner = FileHandle::for_writer(buffer.clone());
+            let handle = new_idempotent_file_handle(inner, 16);
+            let data = b"record\n".as_ptr().cast();
+
+            assert_eq!(file_handle_write_idempotent(handle, 42, data, 7), 1);
+            assert_eq!(file_handle_write_idempotent(handle, 42, data, 7), 0);
+            assert_eq!(file_handle_write_idempotent(handle, 7, data, 7), 1);
+            let ret = file_handle_write_idempotent(handle, 1, data, -1);
+            assert_eq!(ret, FILE_HANDLE_INVALID_LENGTH);
+            file_handle_destroy(handle);
+
+            let other = new_null_file_handle();
+            let ret = file_handle_write_idempotent(other, 42, data, 7);
+            assert_eq!(ret, FILE_HANDLE_UNSUPPORTED);
+            file_handle_destroy(other);
+        }
+
+        let written = buffer.0.lock().unwrap().clone();
+        assert_eq!(written, b"record\nrecord\n");
+    }
+
+    #[test]
+    fn failed_writes_can_be_retried() {
+        let inner = OwnedFileHandle::new(SharedBuffer::default());
+        let mut handle = OwnedFileHandle::new(IdempotentWriter::new(inner, 4));
+
+        handle.freeze().unwrap();
+        assert!(handle.write_idempotent(1, b"asdf").is_err());
+        handle.thaw();
+
+        assert!(handle.write_idempotent(1, b"asdf").unwrap());
+        assert!(!handle.write_idempotent(1, b"asdf").unwrap());
+    }
+}
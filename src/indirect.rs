@@ -0,0 +1,172 @@
+//! A [`FileHandle`] whose destination can be replaced at runtime.
+
+use crate::{
+    sync::{Mutex, MutexGuard},
+    FileHandle, OwnedFileHandle,
+};
+use std::{
+    io::{Error, Write},
+    ptr,
+    sync::TryLockError,
+};
+
+/// A writer which forwards everything to an inner [`OwnedFileHandle`] that
+/// can be swapped out while the outer handle is in use.
+///
+/// This lets a host redirect output (e.g. during log rotation) without
+/// handing out new pointers. Swapping is atomic with respect to writes, so
+/// every write goes entirely to either the old or the new destination.
+pub struct IndirectWriter {
+    slot: Mutex<OwnedFileHandle>,
+}
+
+impl IndirectWriter {
+    /// Create a new [`IndirectWriter`] which initially writes to `inner`.
+    pub fn new(inner: OwnedFileHandle) -> Self {
+        IndirectWriter {
+            slot: Mutex::new(inner),
+        }
+    }
+
+    /// Replace the inner handle, returning the previous one.
+    ///
+    /// The previous handle is *not* flushed, letting the caller decide what
+    /// to do with it.
+    pub fn swap(&self, replacement: OwnedFileHandle) -> OwnedFileHandle {
+        std::mem::replace(&mut *self.inner(), replacement)
+    }
+
+    fn inner(&self) -> MutexGuard<'_, OwnedFileHandle> {
+        // The inner handle catches its own panics so the lock can only be
+        // poisoned by a panic in swap(), which leaves the slot intact.
+        self.slot.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lock the handle being wrapped, unless someone else is using it.
+    pub(crate) fn try_inner(
+        &self,
+    ) -> Option<MutexGuard<'_, OwnedFileHandle>> {
+        match self.slot.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+impl Write for &IndirectWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.inner().flush() }
+}
+
+impl Write for IndirectWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { (&*self).flush() }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which forwards to `inner`, taking ownership
+    /// of it. The destination can later be replaced using
+    /// [`file_handle_swap()`].
+    ///
+    /// Returns `null` if `inner` is `null`.
+    pub unsafe extern "C" fn new_indirect_file_handle(
+        inner: *mut FileHandle,
+    ) -> *mut FileHandle {
+        if inner.is_null() {
+            return ptr::null_mut();
+        }
+
+        let writer = IndirectWriter::new(OwnedFileHandle::from_raw(inner));
+        FileHandle::for_concurrent_writer(writer)
+    }
+}
+
+export! {
+    /// Replace the destination of a [`FileHandle`] created by
+    /// [`new_indirect_file_handle()`], returning the previous destination.
+    ///
+    /// The `handle` takes ownership of `replacement` and the caller becomes
+    /// responsible for destroying the returned handle. If `handle` isn't an
+    /// indirect handle or `replacement` is `null`, nothing happens and `null`
+    /// is returned.
+    pub unsafe extern "C" fn file_handle_swap(
+        handle: *mut FileHandle,
+        replacement: *mut FileHandle,
+    ) -> *mut FileHandle {
+        if replacement.is_null() {
+            return ptr::null_mut();
+        }
+
+        match FileHandle::downcast_raw::<IndirectWriter>(handle) {
+            Some(indirect) => {
+                let replacement = OwnedFileHandle::from_raw(replacement);
+                (*indirect).swap(replacement).into_raw()
+            },
+            None => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn swap_the_destination() {
+        let first = SharedBuffer::default();
+        let second = SharedBuffer::default();
+        let mut writer =
+            IndirectWriter::new(OwnedFileHandle::new(first.clone()));
+
+        writer.write_all(b"Hello, ").unwrap();
+        let previous = writer.swap(OwnedFileHandle::new(second.clone()));
+        writer.write_all(b"World!").unwrap();
+
+        assert!(previous.downcast_ref::<SharedBuffer>().is_some());
+        assert_eq!(&*first.0.lock().unwrap(), b"Hello, ");
+        assert_eq!(&*second.0.lock().unwrap(), b"World!");
+    }
+
+    #[test]
+    fn swap_via_the_c_api() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let handle = new_indirect_file_handle(new_null_file_handle());
+            assert!(!handle.is_null());
+
+            let replacement = FileHandle::for_writer(buffer.clone());
+            let previous = file_handle_swap(handle, replacement);
+            assert!(!previous.is_null());
+            file_handle_destroy(previous);
+
+            let ret = file_handle_write(handle, b"asdf".as_ptr() as _, 4);
+            assert_eq!(ret, 4);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(&*buffer.0.lock().unwrap(), b"asdf");
+    }
+
+    #[test]
+    fn only_indirect_handles_can_be_swapped() {
+        unsafe {
+            let handle = new_null_file_handle();
+            let replacement = new_null_file_handle();
+
+            assert!(file_handle_swap(handle, replacement).is_null());
+
+            file_handle_destroy(replacement);
+            file_handle_destroy(handle);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! A preallocated record of the most recent error each [`FileHandle`]
+//! encountered.
+//!
+//! Recording an error never allocates, so callers can always find out what
+//! went wrong (e.g. while running under allocation-failure injection).
+
+use crate::{FileHandle, ThinErrorKind};
+use std::{
+    any::Any,
+    fmt::{self, Write as _},
+    io::Error,
+    os::raw::{c_char, c_int},
+    sync::Mutex,
+};
+
+/// The longest message which can be stored, in bytes. Longer messages are
+/// truncated.
+pub const LAST_ERROR_MESSAGE_CAPACITY: usize = 128;
+
+#[derive(Debug)]
+struct ErrorRecord {
+    kind: Option<ThinErrorKind>,
+    os_error: c_int,
+    /// The message describes why the handle was poisoned, which later
+    /// poison errors shouldn't overwrite.
+    poisoned: bool,
+    message: [u8; LAST_ERROR_MESSAGE_CAPACITY],
+    len: usize,
+}
+
+impl ErrorRecord {
+    const EMPTY: ErrorRecord = ErrorRecord {
+        kind: None,
+        os_error: 0,
+        poisoned: false,
+        message: [0; LAST_ERROR_MESSAGE_CAPACITY],
+        len: 0,
+    };
+}
+
+impl fmt::Write for ErrorRecord {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = LAST_ERROR_MESSAGE_CAPACITY - self.len;
+        let mut end = s.len().min(remaining);
+
+        // make sure we never split a UTF-8 character in half
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.message[self.len..self.len + end]
+            .copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+
+        Ok(())
+    }
+}
+
+/// Storage for the last error a [`FileHandle`] encountered, allocated up
+/// front when the handle is created.
+#[derive(Debug)]
+pub(crate) struct ErrorSlot(Mutex<ErrorRecord>);
+
+impl ErrorSlot {
+    pub(crate) fn new() -> Self { ErrorSlot(Mutex::new(ErrorRecord::EMPTY)) }
+
+    fn with<T>(&self, thunk: impl FnOnce(&mut ErrorRecord) -> T) -> T {
+        let mut record = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        thunk(&mut record)
+    }
+
+    /// Save a description of `error`, overwriting the previous one.
+    pub(crate) fn record(&self, error: &Error) {
+        // recording is best-effort, and the error's Display impl might panic
+        let record = || self.record_unguarded(error);
+        crate::forbid_panics::contain(record, std::mem::forget);
+    }
+
+    fn record_unguarded(&self, error: &Error) {
+        let poisoned = crate::file_handle::is_poison_error(error);
+
+        self.with(|record| {
+            if poisoned && record.poisoned {
+                // keep what the panic said
+                return;
+            }
+
+            *record = ErrorRecord::EMPTY;
+            let kind = ThinErrorKind::from(error);
+            record.kind = Some(kind);
+
+            // Note: Displaying an OS error looks up its description, which
+            // allocates
+            let _ = match error.raw_os_error() {
+                Some(_) if poisoned => {
+                    record.poisoned = true;
+                    write!(record, "A panic occurred and this object is now \
+                                    poisoned")
+                },
+                Some(code) => {
+                    record.os_error = code;
+                    write!(record, "{} (os error {})", kind.name(), code)
+                },
+                None => write!(record, "{}", error),
+            };
+        });
+    }
+
+    /// Save a description of a panic which poisoned the handle.
+    pub(crate) fn record_panic(&self, payload: &(dyn Any + Send + 'static)) {
+        let record = || {
+            self.with(|record| {
+                *record = ErrorRecord::EMPTY;
+                record.kind = Some(ThinErrorKind::Other);
+                record.poisoned = true;
+
+                let _ = if let Some(s) = payload.downcast_ref::<&str>() {
+                    write!(record, "A panic occurred: {}", s)
+                } else if let Some(s) = payload.downcast_ref::<String>() {
+                    write!(record, "A panic occurred: {}", s)
+                } else {
+                    write!(record, "A panic occurred")
+                };
+            })
+        };
+        crate::forbid_panics::contain(record, std::mem::forget);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.with(|record| *record = ErrorRecord::EMPTY)
+    }
+
+    fn kind(&self) -> Option<ThinErrorKind> { self.with(|record| record.kind) }
+
+    fn os_error(&self) -> c_int { self.with(|record| record.os_error) }
+
+    /// Copy the message into `buffer` as a null-terminated string, returning
+    /// the message's full length.
+    fn copy_message(&self, buffer: &mut [u8]) -> usize {
+        self.with(|record| {
+            if let Some(space) = buffer.len().checked_sub(1) {
+                let len = record.len.min(space);
+                buffer[..len].copy_from_slice(&record.message[..len]);
+                buffer[len] = 0;
+            }
+
+            record.len
+        })
+    }
+}
+
+impl Default for ErrorSlot {
+    fn default() -> Self { ErrorSlot::new() }
+}
+
+export! {
+    /// Get the [`ThinErrorKind`] of the last error this [`FileHandle`]
+    /// encountered, or `0` if there hasn't been one.
+    pub unsafe extern "C" fn file_handle_last_error_kind(
+        handle: *mut FileHandle,
+    ) -> c_int {
+        match (*handle).cold.last_error.kind() {
+            Some(kind) => kind as c_int,
+            None => 0,
+        }
+    }
+}
+
+export! {
+    /// Get the raw OS error code (e.g. `errno`) behind the last error this
+    /// [`FileHandle`] encountered, or `0` if it didn't come from the OS.
+    pub unsafe extern "C" fn file_handle_last_error_os_error(
+        handle: *mut FileHandle,
+    ) -> c_int {
+        (*handle).cold.last_error.os_error()
+    }
+}
+
+export! {
+    /// Copy a human-readable description of the last error this [`FileHandle`]
+    /// encountered into `buffer` as a null-terminated string.
+    ///
+    /// At most `len` bytes (including the null terminator) are written, and the
+    /// length of the full message (excluding the null terminator) is returned.
+    /// Messages are truncated to [`LAST_ERROR_MESSAGE_CAPACITY`] bytes when
+    /// they're recorded, and the message is empty if there hasn't been an
+    /// error.
+    pub unsafe extern "C" fn file_handle_last_error_message(
+        handle: *mut FileHandle,
+        buffer: *mut c_char,
+        len: usize,
+    ) -> usize {
+        let buffer: &mut [u8] = if buffer.is_null() {
+            &mut []
+        } else {
+            std::slice::from_raw_parts_mut(buffer.cast(), len)
+        };
+
+        (*handle).cold.last_error.copy_message(buffer)
+    }
+}
+
+export! {
+    /// Forget about the last error this [`FileHandle`] encountered.
+    pub unsafe extern "C" fn file_handle_clear_last_error(
+        handle: *mut FileHandle,
+    ) {
+        (*handle).cold.last_error.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        ffi::CStr,
+        io::{ErrorKind, Write},
+        ptr,
+    };
+
+    /// Counts how many times each thread allocates.
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = Cell::new(0);
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// How many times `thunk` allocated.
+    fn allocations(thunk: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        thunk();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    #[test]
+    fn long_messages_are_truncated_on_a_char_boundary() {
+        let slot = ErrorSlot::new();
+        let message = "é".repeat(LAST_ERROR_MESSAGE_CAPACITY);
+
+        slot.record(&Error::new(ErrorKind::Other, message));
+
+        let mut buffer = [0xff; 2 * LAST_ERROR_MESSAGE_CAPACITY];
+        let len = slot.copy_message(&mut buffer);
+        assert_eq!(len, LAST_ERROR_MESSAGE_CAPACITY);
+        let message = CStr::from_bytes_with_nul(&buffer[..=len]).unwrap();
+        assert_eq!(message.to_str().unwrap(), "é".repeat(len / 2));
+    }
+
+    #[test]
+    fn os_errors_are_recorded() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+                Err(Error::from(ThinErrorKind::BrokenPipe))
+            }
+
+            fn flush(&mut self) -> Result<(), Error> {
+                Err(Error::from_raw_os_error(42))
+            }
+        }
+
+        unsafe {
+            let handle = FileHandle::for_writer(Broken);
+            assert_eq!(file_handle_last_error_kind(handle), 0);
+
+            assert!(file_handle_write(handle, b"asdf".as_ptr() as _, 4) < 0);
+            assert_eq!(
+                file_handle_last_error_kind(handle),
+                ThinErrorKind::BrokenPipe as c_int
+            );
+            assert_eq!(file_handle_last_error_os_error(handle), 0);
+
+            assert!(file_handle_flush(handle) < 0);
+            assert_eq!(file_handle_last_error_os_error(handle), 42);
+            let mut buffer = [0 as c_char; 64];
+            file_handle_last_error_message(
+                handle,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            );
+            let message = CStr::from_ptr(buffer.as_ptr()).to_str().unwrap();
+            assert!(message.ends_with("(os error 42)"), "{}", message);
+
+            file_handle_clear_last_error(handle);
+            assert_eq!(file_handle_last_error_kind(handle), 0);
+            let len =
+                file_handle_last_error_message(handle, ptr::null_mut(), 0);
+            assert_eq!(len, 0);
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn panics_are_recorded() {
+        struct Panicking;
+        impl Write for Panicking {
+            fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+                panic!("Oops...")
+            }
+
+            fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+        }
+
+        unsafe {
+            let handle = FileHandle::for_writer(Panicking);
+            file_handle_write(handle, b"asdf".as_ptr() as _, 4);
+
+            let mut buffer = [0 as c_char; 64];
+            file_handle_last_error_message(
+                handle,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            );
+            let message = CStr::from_ptr(buffer.as_ptr()).to_str().unwrap();
+            assert_eq!(message, "A panic occurred: Oops...");
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn poisoned_handles_never_allocate() {
+        struct Panicking;
+        impl Write for Panicking {
+            fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+                panic!("Oops...")
+            }
+
+            fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+        }
+
+        unsafe {
+            let handle = FileHandle::for_writer(Panicking);
+            // the panic itself allocates, so that has to happen up front
+            assert!(file_handle_write(handle, b"a".as_ptr().cast(), 1) < 0);
+            let payload: Box<dyn Any + Send> = Box::new("Oops...");
+            let mut buffer = [0 as c_char; 64];
+
+            let got = allocations(|| {
+                (*handle).cold.last_error.record_panic(&*payload);
+                assert!(file_handle_write(handle, b"a".as_ptr().cast(), 1) < 0);
+                assert!(file_handle_flush(handle) < 0);
+                file_handle_last_error_message(
+                    handle,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                );
+            });
+
+            assert_eq!(got, 0);
+            let message = CStr::from_ptr(buffer.as_ptr()).to_str().unwrap();
+            assert_eq!(message, "A panic occurred: Oops...");
+            file_handle_destroy(handle);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Recording how long writes and flushes take, so hosts can keep an eye on
+//! tail latencies.
+
+use crate::{global_clock, Clock, FileHandle};
+use std::{
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Values below `2^SUB_BUCKET_BITS` nanoseconds are recorded exactly, and
+/// everything else is accurate to within about 6%.
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Durations are clamped to `2^MAX_EXPONENT` nanoseconds (about 68 seconds).
+const MAX_EXPONENT: u32 = 36;
+const BUCKETS: usize =
+    (MAX_EXPONENT - SUB_BUCKET_BITS + 2) as usize * SUB_BUCKETS;
+
+fn bucket_index(nanos: u64) -> usize {
+    let nanos = nanos.min((1 << (MAX_EXPONENT + 1)) - 1);
+
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+
+    let exponent = 63 - nanos.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (nanos >> shift) as usize - SUB_BUCKETS;
+
+    (shift as usize + 1) * SUB_BUCKETS + sub_bucket
+}
+
+/// The largest value which would be recorded in a bucket.
+fn bucket_value(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub_bucket = (index % SUB_BUCKETS + SUB_BUCKETS) as u64;
+
+    ((sub_bucket + 1) << shift) - 1
+}
+
+/// A fixed-size histogram with exponentially growing buckets.
+#[derive(Debug)]
+struct Histogram {
+    counts: Vec<AtomicU64>,
+    total_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            total_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyStats {
+        LatencyStats {
+            counts: self
+                .counts
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect(),
+            total_nanos: self.total_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Histograms {
+    clock: Arc<dyn Clock>,
+    writes: Histogram,
+    flushes: Histogram,
+}
+
+/// Per-handle latency histograms, which are only allocated once they get
+/// enabled.
+#[derive(Debug, Default)]
+pub(crate) struct LatencyTable {
+    histograms: AtomicPtr<Histograms>,
+}
+
+impl LatencyTable {
+    pub(crate) fn enable(&self, clock: Arc<dyn Clock>) {
+        let fresh = Box::into_raw(Box::new(Histograms {
+            clock,
+            writes: Histogram::new(),
+            flushes: Histogram::new(),
+        }));
+
+        let swapped = self.histograms.compare_exchange(
+            ptr::null_mut(),
+            fresh,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+
+        if swapped.is_err() {
+            // already enabled
+            unsafe {
+                let _ = Box::from_raw(fresh);
+            }
+        }
+    }
+
+    fn histograms(&self) -> Option<&Histograms> {
+        unsafe { self.histograms.load(Ordering::Acquire).as_ref() }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool { self.histograms().is_some() }
+
+    /// Start timing an operation, if latencies are being recorded.
+    pub(crate) fn start(&self) -> Option<Instant> {
+        self.histograms().map(|h| h.clock.now())
+    }
+
+    /// Record how long a write took, returning the duration.
+    pub(crate) fn record_write(
+        &self,
+        started: Option<Instant>,
+    ) -> Option<Duration> {
+        let (h, started) = (self.histograms()?, started?);
+        let elapsed = h.clock.now() - started;
+        h.writes.record(elapsed);
+        Some(elapsed)
+    }
+
+    /// Record how long a flush took, returning the duration.
+    pub(crate) fn record_flush(
+        &self,
+        started: Option<Instant>,
+    ) -> Option<Duration> {
+        let (h, started) = (self.histograms()?, started?);
+        let elapsed = h.clock.now() - started;
+        h.flushes.record(elapsed);
+        Some(elapsed)
+    }
+
+    pub(crate) fn writes(&self) -> Option<LatencyStats> {
+        self.histograms().map(|h| h.writes.snapshot())
+    }
+
+    pub(crate) fn flushes(&self) -> Option<LatencyStats> {
+        self.histograms().map(|h| h.flushes.snapshot())
+    }
+}
+
+impl Drop for LatencyTable {
+    fn drop(&mut self) {
+        let histograms = *self.histograms.get_mut();
+
+        if !histograms.is_null() {
+            unsafe {
+                let _ = Box::from_raw(histograms);
+            }
+        }
+    }
+}
+
+/// A snapshot of how long a handle's writes or flushes have taken.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyStats {
+    counts: Vec<u64>,
+    total_nanos: u64,
+}
+
+impl LatencyStats {
+    /// The number of operations recorded.
+    pub fn count(&self) -> u64 { self.counts.iter().sum() }
+
+    /// The average duration.
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::from_secs(0),
+            count => Duration::from_nanos(self.total_nanos / count),
+        }
+    }
+
+    /// The duration which `p` percent of operations were faster than (e.g.
+    /// `percentile(99.0)` for the p99), or zero if nothing was recorded.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::from_secs(0);
+        }
+
+        let p = if p.is_nan() { 100.0 } else { p.max(0.0).min(100.0) };
+        let rank = ((p / 100.0 * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        for (index, &bucket_count) in self.counts.iter().enumerate() {
+            seen += bucket_count;
+
+            if seen >= rank {
+                return Duration::from_nanos(bucket_value(index));
+            }
+        }
+
+        Duration::from_nanos(bucket_value(BUCKETS - 1))
+    }
+
+    /// The longest recorded duration.
+    pub fn max(&self) -> Duration { self.percentile(100.0) }
+}
+
+export! {
+    /// Start recording how long each write and flush on this [`FileHandle`]
+    /// takes, using the global clock.
+    ///
+    /// Each handle's histograms take about 9 KiB, and recording can't be turned
+    /// off again.
+    pub unsafe extern "C" fn file_handle_enable_latency_stats(
+        handle: *mut FileHandle,
+    ) {
+        (*handle).extensions_or_default().latency.enable(global_clock());
+    }
+}
+
+export! {
+    /// Get the write latency (in nanoseconds) which `p` percent of writes were
+    /// faster than, where `p` is between `0.0` and `100.0`.
+    ///
+    /// Returns `0` if nothing has been recorded (see
+    /// [`file_handle_enable_latency_stats()`]).
+    pub unsafe extern "C" fn file_handle_latency_percentile(
+        handle: *mut FileHandle,
+        p: f64,
+    ) -> u64 {
+        match (*handle).extensions().and_then(|ext| ext.latency.writes()) {
+            Some(stats) => stats.percentile(p).as_nanos() as u64,
+            None => 0,
+        }
+    }
+}
+
+export! {
+    /// Like [`file_handle_latency_percentile()`], but for flushes.
+    pub unsafe extern "C" fn file_handle_flush_latency_percentile(
+        handle: *mut FileHandle,
+        p: f64,
+    ) -> u64 {
+        match (*handle).extensions().and_then(|ext| ext.latency.flushes()) {
+            Some(stats) => stats.percentile(p).as_nanos() as u64,
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, OwnedFileHandle};
+    use std::io::{Error, Write};
+
+    #[test]
+    fn buckets_round_trip() {
+        for &nanos in &[0, 1, 15, 16, 17, 31, 32, 1000, 123_456_789] {
+            let value = bucket_value(bucket_index(nanos));
+
+            assert!(value >= nanos, "{} -> {}", nanos, value);
+            assert!(value - nanos <= nanos / 16, "{} -> {}", nanos, value);
+        }
+
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+    }
+
+    /// A writer whose writes take as many microseconds as there are bytes.
+    struct Slow(Arc<ManualClock>);
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.0.advance(Duration::from_micros(buf.len() as u64));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.0.advance(Duration::from_millis(1));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn percentiles_of_recorded_writes() {
+        let clock = Arc::new(ManualClock::default());
+        let mut handle = OwnedFileHandle::new(Slow(Arc::clone(&clock)));
+        assert!(handle.write_latency().is_none());
+        handle.enable_latency_stats_with_clock(clock);
+
+        for len in 1..=100 {
+            handle.write_all(&vec![0; len]).unwrap();
+        }
+        handle.flush().unwrap();
+
+        let writes = handle.write_latency().unwrap();
+        assert_eq!(writes.count(), 100);
+        assert_eq!(writes.percentile(0.0).as_micros(), 1);
+        let p50 = writes.percentile(50.0).as_micros();
+        assert!((50..=53).contains(&p50), "{}", p50);
+        let p99 = writes.percentile(99.0).as_micros();
+        assert!((99..=104).contains(&p99), "{}", p99);
+        assert!(writes.max() >= Duration::from_micros(100));
+
+        let flushes = handle.flush_latency().unwrap();
+        assert_eq!(flushes.count(), 1);
+        assert!(flushes.mean() >= Duration::from_millis(1));
+    }
+}
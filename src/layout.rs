@@ -0,0 +1,98 @@
+//! The memory layout of a handle, for C code which needs to find the object
+//! stored inside it.
+//!
+//! Every handle starts with a [`FileHandle`] header of
+//! [`FILE_HANDLE_HEADER_SIZE`] bytes, aligned like a pointer, followed by the
+//! object (padded to the object's alignment).
+
+use crate::FileHandle;
+use std::mem;
+
+/// The size of the [`FileHandle`] header at the start of every handle.
+pub const FILE_HANDLE_HEADER_SIZE: usize = mem::size_of::<FileHandle>();
+
+// The header is 5 pointers and 2 32-bit integers on every platform, without
+// any padding
+const _HEADER_SIZE_IS_STABLE: [(); 0] = [(); FILE_HANDLE_HEADER_SIZE
+    - (5 * mem::size_of::<usize>() + 2 * mem::size_of::<u32>())];
+const _HEADER_IS_POINTER_ALIGNED: [(); 0] =
+    [(); mem::align_of::<FileHandle>() - mem::align_of::<usize>()];
+
+export! {
+    /// How many bytes after the start of `handle` its object is stored (see
+    /// [`FileHandle::payload_ptr()`]).
+    ///
+    /// This is at least [`FILE_HANDLE_HEADER_SIZE`], but may be more if the
+    /// object needs a bigger alignment than the header.
+    pub unsafe extern "C" fn file_handle_payload_offset(
+        handle: *const FileHandle,
+    ) -> usize {
+        (*handle).cold.payload_offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::{
+        io::{Error, Write},
+        os::raw::{c_char, c_int, c_void},
+    };
+
+    #[repr(align(64))]
+    struct OverAligned(usize);
+
+    impl Write for OverAligned {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+
+    c_unwind! { unsafe fn destroy_nothing(_: *mut c_void) {} }
+    c_unwind! {
+        unsafe fn write_nothing(_: *mut c_void, _: *const c_char, len: c_int)
+            -> c_int { len }
+    }
+    c_unwind! { unsafe fn flush_nothing(_: *mut c_void) -> c_int { 0 } }
+
+    #[test]
+    fn payloads_follow_the_header() {
+        unsafe {
+            let handle = new_memory_file_handle();
+            let offset = file_handle_payload_offset(handle);
+            assert_eq!(offset, FILE_HANDLE_HEADER_SIZE);
+            let vec = FileHandle::downcast_raw::<Vec<u8>>(handle).unwrap();
+            assert_eq!(FileHandle::payload_ptr(handle), vec.cast());
+            file_handle_destroy(handle);
+
+            let handle = FileHandle::for_writer(OverAligned(0));
+            assert_eq!(file_handle_payload_offset(handle), 64);
+            file_handle_write(handle, b"abc".as_ptr().cast(), 3);
+            let payload = FileHandle::payload_ptr(handle).cast::<OverAligned>();
+            assert_eq!((*payload).0, 3);
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn external_payloads_are_where_they_were_initialized() {
+        unsafe {
+            let FileHandleBuilder { file_handle, place } =
+                new_file_handle_builder(
+                    8,
+                    128,
+                    destroy_nothing,
+                    write_nothing,
+                    flush_nothing,
+                );
+
+            assert_eq!(file_handle_payload_offset(file_handle), 128);
+            assert_eq!(FileHandle::payload_ptr(file_handle), place);
+            file_handle_destroy(file_handle);
+        }
+    }
+}
@@ -1,12 +1,219 @@
 //! Proof of concept for creating FFI-safe trait objects in Rust.
+//!
+//! # Safety
+//!
+//! All `extern "C"` functions exported by this crate share the same contract:
+//! any `*mut FileHandle` passed to them must be a valid, non-null pointer
+//! created by this crate which hasn't been destroyed yet, and any buffers must
+//! be valid for the given length. Calls on a single handle must not overlap.
+//!
+//! Callbacks must not unwind (panic, throw a C++ exception, etc.) unless
+//! the crate was compiled with the `c-unwind` feature, otherwise the process
+//! is aborted. See [`PanicBarrier`] for wrapping callbacks written in Rust.
+//!
+//! Panics from a Rust writer are normally caught and poison its handle. The
+//! `no-panic-guard` feature (which requires `panic = "abort"`) skips this for
+//! every handle, and [`FileHandle::for_writer_unguarded()`] skips it for a
+//! single handle.
 
 #![deny(missing_docs)]
+// The FFI functions' safety requirements are documented once at the crate
+// level instead of on each individual function.
+#![allow(clippy::missing_safety_doc)]
 
+#[cfg(all(feature = "no-panic-guard", not(panic = "abort")))]
+compile_error!(
+    "The `no-panic-guard` feature can only be used with `panic = \"abort\"`"
+);
+
+// declared first so their macros are available to the other modules
+#[macro_use]
+mod export;
+#[macro_use]
+mod unwind;
+#[macro_use]
+mod forbid_panics;
+
+mod arc_handle;
+mod array_queue;
+mod async_bridge;
+mod autoflush;
+mod backend;
+mod background;
+mod barrier;
+mod binary_text;
+mod bounded;
+mod budget;
+mod buffer_pool;
+mod callback;
+#[cfg(all(test, feature = "c-host-demo"))]
+mod c_host;
+mod chunks;
+mod clock;
+mod config;
+mod copy;
+#[cfg(feature = "cpp-header")]
+mod cpp_header;
+mod crash_flush;
+mod debug;
+mod drop_timeout;
+mod errors;
+mod exit_flush;
+mod extensions;
 mod external;
 mod ffi;
 mod file_handle;
+mod flush_timeout;
+mod fmt_handle;
+mod freeze;
+mod global;
+mod group;
+mod gzip;
+mod handle_logger;
+mod history;
+mod idempotent;
+mod indirect;
+mod last_error;
+mod latency;
+mod layout;
+mod loan;
+mod lifecycle;
+mod log_bridge;
+mod loopback;
+mod lossy;
+mod metrics;
+#[cfg(all(test, feature = "loom-tests"))]
+mod model;
+mod open_options;
+mod optional;
+mod os_handle;
+mod ostream;
+#[cfg(windows)]
+mod overlapped;
 mod owned;
+mod pipe;
+mod placement;
+mod quota;
+mod read_handle;
+mod recording;
+mod redact;
+mod reentrant;
+mod reopen;
+mod rotation;
+mod scoped;
+mod sequenced;
+mod sharded;
+mod shared_sink;
+mod short_write;
+mod shrink;
+mod splice;
+mod static_dispatch;
+mod stats;
+mod stdio;
+mod sync;
+mod tagged;
+#[cfg(feature = "proptest-support")]
+pub mod test_support;
+#[cfg(feature = "test-util")]
+pub mod test_writers;
+mod thread_stats;
+#[cfg(feature = "tracing")]
+mod trace_spans;
+mod transcode;
+mod typed;
+mod validation;
+mod version;
+#[doc(hidden)]
+pub mod vtable;
+mod watchdog;
+mod wiring;
+mod write_filter;
+mod write_owned;
+mod zero_write;
 
+pub use arc_handle::ArcFileHandle;
+pub use async_bridge::{AsyncFileHandle, AsyncWrite, SpawnBlocking};
+pub use backend::{Capabilities, WriterBackend};
+pub use background::BackgroundWriter;
+pub use binary_text::{BinaryEncoding, BinaryEncodingWriter};
+pub use bounded::{BoundedBuffer, OverflowPolicy};
+pub use budget::{memory_budget, memory_used, set_memory_budget};
+pub use buffer_pool::{BufferPool, BufferPoolStats, PooledBuffer};
+pub use callback::{
+    BytesCallback, FfiCallback, OwnedCallback, Signature, VoidCallback,
+};
+pub use chunks::{ChunkedBuffer, IntoChunks};
+pub use clock::{
+    global_clock, set_global_clock, Clock, ManualClock, SystemClock,
+};
+pub use config::{current_config, Config, FfiConfig};
+pub use copy::CancelToken;
+#[cfg(feature = "cpp-header")]
+pub use cpp_header::cpp_header;
+pub use crash_flush::{
+    emergency_flush_all, install_crash_flush_handler, CRASH_FLUSH_TIMEOUT_MS,
+    EMERGENCY_FLUSH_CAPACITY,
+};
+pub use errors::{
+    encode_error, register_user_status, user_status_error, ErrorDomain,
+    ThinErrorKind,
+};
 pub use ffi::*;
 pub use file_handle::FileHandle;
-pub use owned::OwnedFileHandle;
+pub use fmt_handle::{FmtHandle, OwnedFmtHandle};
+pub use group::HandleGroup;
+pub use handle_logger::{HandleLogger, LogFormat};
+pub use history::{OwnershipEvent, OwnershipRecord};
+pub use idempotent::IdempotentWriter;
+pub use indirect::IndirectWriter;
+pub use latency::LatencyStats;
+pub use log_bridge::{
+    dropped_log_records, set_log_sink, LogLevel, LogRecord, LogSink,
+    LogWriter,
+};
+pub use loan::{LoanError, LoanedHandle};
+pub use loopback::{Expectations, LoopbackHandle};
+pub use lossy::{LossyStats, LossyWriter};
+pub use metrics::{
+    set_metrics_sink, Metric, MetricsSink, METRIC_BYTES_WRITTEN,
+    METRIC_FLUSH_LATENCY_US, METRIC_WRITE_ERRORS, METRIC_WRITE_LATENCY_US,
+};
+#[cfg(windows)]
+pub use overlapped::OverlappedFile;
+pub use open_options::FileOpenOptions;
+pub use optional::Operation;
+pub use ostream::{CWriterVTable, OstreamVtable};
+pub use owned::{OwnedFileHandle, WrongType};
+pub use placement::{
+    ExternalCallbacks, ExternalHandleBuilder, Place, PlaceFlushCallback,
+    PlaceWriteCallback,
+};
+pub use read_handle::ReadHandle;
+pub use recording::{
+    replay_session, RecordedCall, RecordedEntry, RecordingReader,
+    RecordingWriter,
+};
+pub use redact::RedactingWriter;
+pub use rotation::{RotatingFile, RotationConfig, RotationInterval};
+pub use scoped::ScopedWriter;
+pub use sequenced::{SequenceFormat, SequencedWriter};
+pub use sharded::ShardedWriter;
+pub use shared_sink::{SharedSink, SharedSinkProducer};
+pub use short_write::ShortWriter;
+pub use shrink::ShrinkPolicy;
+pub use stats::FileHandleStats;
+pub use tagged::TaggedWrite;
+pub use thread_stats::ThreadStats;
+#[cfg(feature = "tracing")]
+pub use trace_spans::{
+    set_trace_sink, TraceLevel, TraceOperation, TraceOutcome, TraceSink,
+    TraceSpan,
+};
+pub use transcode::{Encoding, TranscodingWriter};
+pub use typed::TypedFileHandle;
+pub use unwind::PanicBarrier;
+pub use version::{symbol_prefix, BuildInfo};
+pub use vtable::FfiSafe;
+pub use watchdog::WatchdogAction;
+pub use wiring::{WiringError, WiringErrorKind};
+pub use zero_write::ZeroWritePolicy;
@@ -0,0 +1,174 @@
+//! Explicit setup and teardown of this crate's global state, for hosts which
+//! repeatedly load and unload it as a shared library.
+
+use std::{
+    mem,
+    os::raw::c_int,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// Returned by [`thin_trait_objects_init()`] when the [`InitConfig`] is
+/// invalid.
+pub const INIT_INVALID_CONFIG: c_int = -1;
+
+/// Options passed to [`thin_trait_objects_init()`].
+///
+/// New fields may be appended in later versions, so callers must set `size`
+/// to `sizeof(InitConfig)` and zero-initialize anything they don't use.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct InitConfig {
+    /// The size of this struct, in bytes.
+    pub size: usize,
+}
+
+impl Default for InitConfig {
+    fn default() -> Self {
+        InitConfig {
+            size: mem::size_of::<InitConfig>(),
+        }
+    }
+}
+
+/// How many times [`thin_trait_objects_init()`] has been called without a
+/// matching [`thin_trait_objects_shutdown()`].
+static INIT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes sure initializing and shutting down never overlap.
+static LIFECYCLE: AtomicBool = AtomicBool::new(false);
+
+/// A minimal lock which can live in a `static` (`Mutex::new()` isn't a
+/// `const fn` on our MSRV).
+pub(crate) struct SpinLockGuard(&'static AtomicBool);
+
+impl Drop for SpinLockGuard {
+    fn drop(&mut self) { self.0.store(false, Ordering::Release); }
+}
+
+pub(crate) fn spin_lock(flag: &'static AtomicBool) -> SpinLockGuard {
+    let (acquire, relaxed) = (Ordering::Acquire, Ordering::Relaxed);
+
+    while flag
+        .compare_exchange_weak(false, true, acquire, relaxed)
+        .is_err()
+    {
+        std::thread::yield_now();
+    }
+
+    SpinLockGuard(flag)
+}
+
+/// Free all global state, returning the crate to the way it was when first
+/// loaded.
+fn teardown() {
+    unsafe {
+        crate::autoflush::shutdown();
+        crate::budget::shutdown();
+        crate::clock::shutdown();
+        crate::errors::shutdown();
+        crate::exit_flush::shutdown();
+        crate::history::shutdown();
+        crate::log_bridge::shutdown();
+        crate::metrics::shutdown();
+        #[cfg(feature = "tracing")]
+        crate::trace_spans::shutdown();
+        crate::validation::shutdown();
+        crate::watchdog::shutdown();
+        crate::zero_write::shutdown();
+    }
+}
+
+export! {
+    /// Prepare this crate's global state.
+    ///
+    /// Calling this is optional because global state is created on first use,
+    /// but it must be balanced by a call to [`thin_trait_objects_shutdown()`].
+    /// Calls may be nested, in which case only the outermost shutdown tears
+    /// anything down. A `null` config uses the defaults.
+    ///
+    /// Returns `0` on success or [`INIT_INVALID_CONFIG`] if the config is
+    /// invalid.
+    pub unsafe extern "C" fn thin_trait_objects_init(
+        config: *const InitConfig,
+    ) -> c_int {
+        if let Some(config) = config.as_ref() {
+            // Note: older callers may pass a smaller struct, but it can't be
+            // smaller than the first version
+            if config.size < mem::size_of::<usize>() {
+                return INIT_INVALID_CONFIG;
+            }
+        }
+
+        let _guard = spin_lock(&LIFECYCLE);
+        INIT_COUNT.fetch_add(1, Ordering::Relaxed);
+
+        0
+    }
+}
+
+export! {
+    /// Tear down all global state (e.g. the [global clock][crate::Clock]) so
+    /// the library can be unloaded without leaking anything.
+    ///
+    /// This must only be called once nothing else is using the library, and
+    /// does nothing if [`thin_trait_objects_init()`] hasn't been called or some
+    /// other caller still has it initialized.
+    pub unsafe extern "C" fn thin_trait_objects_shutdown() {
+        let _guard = spin_lock(&LIFECYCLE);
+
+        match INIT_COUNT.load(Ordering::Relaxed) {
+            0 => {},
+            1 => {
+                INIT_COUNT.store(0, Ordering::Relaxed);
+                teardown();
+            },
+            n => INIT_COUNT.store(n - 1, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Used by tests which touch global state, so they don't interfere with
+/// each other.
+#[cfg(test)]
+pub(crate) fn lock_global_state() -> SpinLockGuard {
+    static GLOBAL_STATE: AtomicBool = AtomicBool::new(false);
+    spin_lock(&GLOBAL_STATE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{global_clock, set_global_clock, ManualClock};
+    use std::{ptr, sync::Arc, time::SystemTime};
+
+    #[test]
+    fn nested_initialization() {
+        let _global = lock_global_state();
+
+        unsafe {
+            let config = InitConfig::default();
+            assert_eq!(thin_trait_objects_init(&config), 0);
+            assert_eq!(thin_trait_objects_init(ptr::null()), 0);
+
+            set_global_clock(Arc::new(ManualClock::default()));
+            thin_trait_objects_shutdown();
+            assert_eq!(global_clock().wall_time(), SystemTime::UNIX_EPOCH);
+
+            // only the outermost shutdown resets the global state
+            thin_trait_objects_shutdown();
+            assert_ne!(global_clock().wall_time(), SystemTime::UNIX_EPOCH);
+
+            // unbalanced shutdowns are ignored
+            thin_trait_objects_shutdown();
+        }
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let config = InitConfig { size: 0 };
+
+        unsafe {
+            assert_eq!(thin_trait_objects_init(&config), INIT_INVALID_CONFIG);
+        }
+    }
+}
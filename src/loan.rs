@@ -0,0 +1,224 @@
+//! Lending an [`OwnedFileHandle`] to native code for the duration of a call,
+//! and checking it comes back intact.
+
+use crate::{
+    log_bridge::{self, LogLevel, LogRecord},
+    FileHandle, OwnedFileHandle,
+};
+use std::{
+    any::TypeId,
+    fmt::{self, Display, Formatter},
+};
+
+/// What went wrong while a handle was lent out with
+/// [`OwnedFileHandle::loan()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoanError {
+    /// Native code tried to destroy the handle. The handle was kept alive
+    /// and still belongs to the [`OwnedFileHandle`].
+    Destroyed,
+    /// The handle's header was overwritten.
+    Corrupted,
+    /// The handle was poisoned while it was lent out.
+    Poisoned,
+}
+
+impl std::error::Error for LoanError {}
+
+impl Display for LoanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoanError::Destroyed => {
+                write!(f, "The handle was destroyed while it was on loan")
+            },
+            LoanError::Corrupted => {
+                write!(f, "The handle was corrupted while it was on loan")
+            },
+            LoanError::Poisoned => {
+                write!(f, "The handle was poisoned while it was on loan")
+            },
+        }
+    }
+}
+
+/// The parts of the header which must not change during a loan.
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    write: usize,
+    flush: usize,
+    write_many: usize,
+    cold: usize,
+    type_id: TypeId,
+}
+
+impl Snapshot {
+    unsafe fn take(handle: *mut FileHandle) -> Snapshot {
+        Snapshot {
+            write: (*handle).write as usize,
+            flush: (*handle).flush as usize,
+            write_many: (*handle).write_many as usize,
+            cold: &*(*handle).cold as *const _ as usize,
+            type_id: (*handle).cold.type_id,
+        }
+    }
+}
+
+/// A `*mut FileHandle` borrowed from an [`OwnedFileHandle`], created by
+/// [`OwnedFileHandle::loan()`].
+///
+/// Ending the loan with [`LoanedHandle::finish()`] reports anything native
+/// code did to the handle which it wasn't allowed to. Dropping the loan
+/// instead logs the problem as an error (see
+/// [`set_log_sink()`][crate::set_log_sink]).
+#[derive(Debug)]
+pub struct LoanedHandle<'a> {
+    owner: &'a mut OwnedFileHandle,
+    snapshot: Snapshot,
+    was_poisoned: bool,
+    finished: bool,
+}
+
+impl<'a> LoanedHandle<'a> {
+    /// The pointer to give to native code. It must not be used after the
+    /// loan ends.
+    pub fn as_ptr(&self) -> *mut FileHandle { self.owner.as_ptr() }
+
+    /// End the loan, checking the handle came back in one piece.
+    pub fn finish(mut self) -> Result<(), LoanError> { self.end() }
+
+    fn end(&mut self) -> Result<(), LoanError> {
+        self.finished = true;
+        let handle = self.owner.as_ptr();
+
+        unsafe {
+            // compare against the snapshot before trusting anything else
+            let corrupted = Snapshot::take(handle) != self.snapshot
+                || !(*handle).has_flag(FileHandle::LOANED);
+            let destroyed = (*handle).has_flag(FileHandle::DESTROYED_ON_LOAN);
+            (*handle).clear_flag(FileHandle::LOANED);
+            (*handle).clear_flag(FileHandle::DESTROYED_ON_LOAN);
+
+            if corrupted {
+                Err(LoanError::Corrupted)
+            } else if destroyed {
+                Err(LoanError::Destroyed)
+            } else if (*handle).is_poisoned() && !self.was_poisoned {
+                Err(LoanError::Poisoned)
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a> Drop for LoanedHandle<'a> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        if let Err(e) = self.end() {
+            let message = format!("{} ({:p})", e, self.owner.as_ptr());
+            log_bridge::emit_to_global_sink(&LogRecord {
+                level: LogLevel::Error,
+                target: "thin_trait_objects::loan",
+                message: &message,
+            });
+        }
+    }
+}
+
+impl OwnedFileHandle {
+    /// Lend the handle to native code (e.g. for the duration of a callback),
+    /// keeping ownership on the Rust side.
+    ///
+    /// While the loan lasts, [`file_handle_destroy()`] leaves the handle
+    /// alone and the attempt is reported when the loan ends.
+    ///
+    /// ```rust
+    /// # use thin_trait_objects::{
+    /// #     file_handle_destroy, LoanError, OwnedFileHandle,
+    /// # };
+    /// let mut handle = OwnedFileHandle::new(Vec::new());
+    ///
+    /// let loan = handle.loan();
+    /// // a misbehaving C library which thinks it owns the handle
+    /// unsafe { file_handle_destroy(loan.as_ptr()) };
+    /// assert_eq!(loan.finish(), Err(LoanError::Destroyed));
+    ///
+    /// // but it is still usable
+    /// assert!(handle.downcast_ref::<Vec<u8>>().is_some());
+    /// ```
+    ///
+    /// [`file_handle_destroy()`]: crate::file_handle_destroy
+    pub fn loan(&mut self) -> LoanedHandle<'_> {
+        let handle = self.as_ptr();
+
+        unsafe {
+            (*handle).set_flag(FileHandle::LOANED);
+
+            LoanedHandle {
+                snapshot: Snapshot::take(handle),
+                was_poisoned: (*handle).is_poisoned(),
+                owner: self,
+                finished: false,
+            }
+        }
+    }
+}
+
+/// Called instead of destroying a handle which is on loan.
+pub(crate) unsafe fn destroy_on_loan(handle: *mut FileHandle) {
+    (*handle).set_flag(FileHandle::DESTROYED_ON_LOAN);
+
+    let message = format!(
+        "Ignoring an attempt to destroy the handle at {:p}, which is on loan",
+        handle
+    );
+    log_bridge::emit_to_global_sink(&LogRecord {
+        level: LogLevel::Error,
+        target: "thin_trait_objects::loan",
+        message: &message,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn native_code_can_use_a_loaned_handle() {
+        let buffer = SharedBuffer::default();
+        let mut handle = OwnedFileHandle::new(buffer.clone());
+
+        let loan = handle.loan();
+        unsafe {
+            let data = b"asdf".as_ptr().cast();
+            assert_eq!(file_handle_write(loan.as_ptr(), data, 4), 4);
+        }
+        loan.finish().unwrap();
+
+        // the loan can be taken out again
+        handle.loan().finish().unwrap();
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"asdf");
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut handle = OwnedFileHandle::new(Vec::new());
+
+        let loan = handle.loan();
+        unsafe {
+            // e.g. C code scribbling over the header
+            (*loan.as_ptr()).clear_flag(FileHandle::LOANED);
+        }
+        assert_eq!(loan.finish(), Err(LoanError::Corrupted));
+
+        let loan = handle.loan();
+        unsafe {
+            (*loan.as_ptr()).set_flag(FileHandle::POISONED);
+        }
+        assert_eq!(loan.finish(), Err(LoanError::Poisoned));
+    }
+}
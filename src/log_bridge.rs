@@ -0,0 +1,387 @@
+//! Turning text written to a [`FileHandle`] into log records, so output from
+//! C plugins ends up in the host's logging pipeline instead of on stdout.
+//!
+//! This crate doesn't depend on any particular logging framework. Instead,
+//! the host installs a [`LogSink`] with [`set_log_sink()`] which forwards
+//! each [`LogRecord`] to `log::log!()`, a `tracing` event, or wherever else
+//! it should go.
+
+use crate::{global::Global, FileHandle};
+use std::{
+    ffi::CStr,
+    fmt::{self, Display, Formatter},
+    io::{Error, Write},
+    os::raw::{c_char, c_int},
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// How important a log message is.
+///
+/// The values are the same as `log::Level`'s.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(C)]
+pub enum LogLevel {
+    /// Something went wrong.
+    Error = 1,
+    /// Something unexpected happened.
+    Warn = 2,
+    /// Useful information.
+    Info = 3,
+    /// Lower priority information.
+    Debug = 4,
+    /// Very low priority, often extremely verbose, information.
+    Trace = 5,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 5] = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    /// Convert the integer representation back into a [`LogLevel`].
+    pub fn from_raw(raw: c_int) -> Option<LogLevel> {
+        LogLevel::ALL.iter().copied().find(|&l| l as c_int == raw)
+    }
+
+    /// The level's name, in upper case.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+
+    /// Check whether a line starts with a level (e.g. `"WARN: ..."` or
+    /// `"[debug] ..."`), returning the level and the rest of the line.
+    pub fn strip_prefix(line: &str) -> Option<(LogLevel, &str)> {
+        let (name, rest) = if line.starts_with('[') {
+            let end = line.find(']')?;
+            (&line[1..end], &line[end + 1..])
+        } else {
+            let end = line.find(':')?;
+            (&line[..end], &line[end + 1..])
+        };
+
+        let level = match name.to_ascii_uppercase().as_str() {
+            "WARNING" => LogLevel::Warn,
+            name => *LogLevel::ALL.iter().find(|l| l.as_str() == name)?,
+        };
+
+        Some((level, rest.trim_start()))
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A single log message.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LogRecord<'a> {
+    /// How important the message is.
+    pub level: LogLevel,
+    /// Where the message came from (e.g. the plugin's name).
+    pub target: &'a str,
+    /// The message itself, without a trailing newline.
+    pub message: &'a str,
+}
+
+/// Somewhere log records can be sent.
+pub trait LogSink: Send + Sync {
+    /// Handle a log record.
+    fn log(&self, record: &LogRecord<'_>);
+}
+
+impl<F> LogSink for F
+where
+    F: Fn(&LogRecord<'_>) + Send + Sync,
+{
+    fn log(&self, record: &LogRecord<'_>) { self(record) }
+}
+
+/// The sink used by anything which wasn't given its own.
+static LOG_SINK: Global<Mutex<Option<Arc<dyn LogSink>>>> = Global::new();
+
+fn log_sink_slot() -> &'static Mutex<Option<Arc<dyn LogSink>>> {
+    LOG_SINK.get_or_init(|| Mutex::new(None))
+}
+
+/// How many records were dropped because no global sink was installed.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Free the global log sink's storage.
+pub(crate) unsafe fn shutdown() { LOG_SINK.reset(); }
+
+/// Set where log records go when a [`LogWriter`] wasn't given its own sink.
+///
+/// Until this is called, records are dropped (see [`dropped_log_records()`]).
+pub fn set_log_sink(sink: Arc<dyn LogSink>) {
+    let mut slot = log_sink_slot().lock().unwrap_or_else(|e| e.into_inner());
+    *slot = Some(sink);
+}
+
+/// The sink set with [`set_log_sink()`], if there is one.
+pub(crate) fn configured_log_sink() -> Option<Arc<dyn LogSink>> {
+    log_sink_slot()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Replace (or clear) the global log sink.
+pub(crate) fn replace_log_sink(sink: Option<Arc<dyn LogSink>>) {
+    *log_sink_slot().lock().unwrap_or_else(|e| e.into_inner()) = sink;
+}
+
+pub(crate) fn emit_to_global_sink(record: &LogRecord<'_>) {
+    let sink = log_sink_slot()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+
+    match sink {
+        Some(sink) => sink.log(record),
+        None => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        },
+    }
+}
+
+/// How many records have been dropped because they were sent to the global
+/// sink before [`set_log_sink()`] was called.
+pub fn dropped_log_records() -> u64 { DROPPED.load(Ordering::Relaxed) }
+
+/// A writer which splits everything written to it into lines and sends
+/// each line to a [`LogSink`] as a [`LogRecord`].
+///
+/// Lines starting with a level (e.g. `"ERROR: ..."` or `"[warn] ..."`) are
+/// logged at that level, with the prefix removed. Everything else is logged
+/// at the default level. Text after the last newline is held back until the
+/// line is finished or the writer is dropped.
+pub struct LogWriter {
+    target: String,
+    level: LogLevel,
+    pending: Vec<u8>,
+    sink: Option<Arc<dyn LogSink>>,
+}
+
+impl LogWriter {
+    /// Create a new [`LogWriter`] which sends records to the global sink
+    /// (see [`set_log_sink()`]).
+    pub fn new(target: impl Into<String>, level: LogLevel) -> Self {
+        LogWriter {
+            target: target.into(),
+            level,
+            pending: Vec::new(),
+            sink: None,
+        }
+    }
+
+    /// Create a new [`LogWriter`] which sends records to `sink`.
+    pub fn with_sink(
+        target: impl Into<String>,
+        level: LogLevel,
+        sink: Arc<dyn LogSink>,
+    ) -> Self {
+        let mut writer = LogWriter::new(target, level);
+        writer.sink = Some(sink);
+        writer
+    }
+
+    /// Log a line, at `level` if it is set or else whatever level the line
+    /// starts with.
+    fn emit(&self, line: &[u8], level: Option<LogLevel>) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        let (level, message) = match level {
+            Some(level) => (level, line),
+            None => LogLevel::strip_prefix(line).unwrap_or((self.level, line)),
+        };
+        let record = LogRecord {
+            level,
+            target: &self.target,
+            message,
+        };
+
+        match &self.sink {
+            Some(sink) => sink.log(&record),
+            None => emit_to_global_sink(&record),
+        }
+    }
+}
+
+impl LogWriter {
+    /// Write some text, logging any lines it completes at `level` (see
+    /// [`LogWriter::emit()`]).
+    pub(crate) fn write_with_level(
+        &mut self,
+        buf: &[u8],
+        level: Option<LogLevel>,
+    ) -> Result<usize, Error> {
+        self.pending.extend_from_slice(buf);
+
+        let last_newline = self.pending.iter().rposition(|&b| b == b'\n');
+
+        if let Some(last_newline) = last_newline {
+            let rest = self.pending.split_off(last_newline + 1);
+            let complete = std::mem::replace(&mut self.pending, rest);
+
+            for line in complete[..last_newline].split(|&b| b == b'\n') {
+                self.emit(line, level);
+            }
+        }
+
+        Ok(buf.len())
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.write_with_level(buf, None)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            self.emit(&self.pending, None);
+        }
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which turns each line written to it into a
+    /// log record from `target`, sent to the sink installed by the host (see
+    /// [`set_log_sink()`]).
+    ///
+    /// Lines without a level prefix are logged at `level`, which must be one of
+    /// the [`LogLevel`] values. Returns `null` if `target` isn't valid UTF-8 or
+    /// `level` is invalid.
+    pub unsafe extern "C" fn new_log_crate_file_handle(
+        target: *const c_char,
+        level: c_int,
+    ) -> *mut FileHandle {
+        let level = match LogLevel::from_raw(level) {
+            Some(level) => level,
+            None => return ptr::null_mut(),
+        };
+
+        match CStr::from_ptr(target).to_str() {
+            Ok(target) => {
+                FileHandle::for_tagged_writer(LogWriter::new(target, level))
+            },
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<(LogLevel, String, String)>>);
+
+    impl LogSink for Collect {
+        fn log(&self, record: &LogRecord<'_>) {
+            self.0.lock().unwrap().push((
+                record.level,
+                record.target.to_string(),
+                record.message.to_string(),
+            ));
+        }
+    }
+
+    #[test]
+    fn lines_become_records() {
+        let sink = Arc::new(Collect::default());
+        let mut writer = LogWriter::with_sink(
+            "plugin",
+            LogLevel::Info,
+            Arc::clone(&sink) as Arc<dyn LogSink>,
+        );
+
+        writer.write_all(b"starting up\r\nWARN: low on ").unwrap();
+        writer.write_all(b"disk space\n[debug] partial").unwrap();
+        assert_eq!(sink.0.lock().unwrap().len(), 2);
+        drop(writer);
+
+        let records = sink.0.lock().unwrap();
+        let expected = [
+            (LogLevel::Info, "starting up"),
+            (LogLevel::Warn, "low on disk space"),
+            (LogLevel::Debug, "partial"),
+        ];
+        assert_eq!(records.len(), expected.len());
+        for (record, (level, message)) in records.iter().zip(&expected) {
+            assert_eq!(record, &(*level, "plugin".into(), message.to_string()));
+        }
+    }
+
+    #[test]
+    fn parse_level_prefixes() {
+        let inputs = vec![
+            ("error: oops", Some((LogLevel::Error, "oops"))),
+            ("Warning: careful", Some((LogLevel::Warn, "careful"))),
+            ("[TRACE]details", Some((LogLevel::Trace, "details"))),
+            ("time: 12:00", None),
+            ("[unclosed", None),
+        ];
+
+        for (line, expected) in inputs {
+            assert_eq!(LogLevel::strip_prefix(line), expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn records_without_a_sink_are_counted() {
+        let _global = crate::lifecycle::lock_global_state();
+        replace_log_sink(None);
+        let before = dropped_log_records();
+
+        let mut writer = LogWriter::new("test", LogLevel::Info);
+        writer.write_all(b"first\nsecond\n").unwrap();
+
+        assert_eq!(dropped_log_records() - before, 2);
+    }
+
+    #[test]
+    fn handles_use_the_global_sink() {
+        let _global = crate::lifecycle::lock_global_state();
+        let sink = Arc::new(Collect::default());
+        set_log_sink(Arc::clone(&sink) as Arc<dyn LogSink>);
+
+        unsafe {
+            let handle =
+                new_log_crate_file_handle(b"c-plugin\0".as_ptr().cast(), 2);
+            let msg = b"Hello, World!\n";
+            crate::file_handle_write(handle, msg.as_ptr().cast(), 14);
+            crate::file_handle_destroy(handle);
+
+            assert!(new_log_crate_file_handle(b"\0".as_ptr().cast(), 0)
+                .is_null());
+            shutdown();
+        }
+
+        let records = sink.0.lock().unwrap();
+        assert_eq!(
+            records.as_slice(),
+            &[(LogLevel::Warn, "c-plugin".into(), "Hello, World!".into())]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! A [`FileHandle`] for tests which remembers everything written to it, so
+//! code on the other side of the FFI (e.g. a C plugin) can be checked
+//! end-to-end.
+
+use crate::{FileHandle, OwnedFileHandle};
+use std::{
+    ffi::CStr,
+    io::{Error, Write},
+    os::raw::c_char,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+#[derive(Debug, Default)]
+struct Log {
+    writes: Vec<Vec<u8>>,
+    flushes: usize,
+}
+
+/// A view of everything written to a [`LoopbackHandle`], with assertions
+/// which panic with a helpful message when they fail.
+#[derive(Debug, Default, Clone)]
+pub struct Expectations(Arc<Mutex<Log>>);
+
+impl Expectations {
+    fn lock(&self) -> MutexGuard<'_, Log> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Every byte written so far.
+    pub fn written(&self) -> Vec<u8> { self.lock().writes.concat() }
+
+    /// The length of each write, in the order they were made.
+    pub fn write_boundaries(&self) -> Vec<usize> {
+        self.lock().writes.iter().map(Vec::len).collect()
+    }
+
+    /// How many times the handle has been flushed.
+    pub fn flush_count(&self) -> usize { self.lock().flushes }
+
+    /// Check that exactly `expected` was written.
+    #[track_caller]
+    pub fn assert_written(&self, expected: &[u8]) {
+        assert_eq!(self.written(), expected, "Unexpected bytes were written");
+    }
+
+    /// Check that exactly `expected` was written, showing the output as text
+    /// if it doesn't match.
+    #[track_caller]
+    pub fn assert_written_utf8(&self, expected: &str) {
+        let written = self.written();
+        assert_eq!(
+            String::from_utf8_lossy(&written),
+            expected,
+            "Unexpected text was written"
+        );
+    }
+
+    /// Check that `needle` appears somewhere in the output.
+    #[track_caller]
+    pub fn assert_contains(&self, needle: &str) {
+        let written = self.written();
+        assert!(
+            contains(&written, needle.as_bytes()),
+            "{:?} was never written (got {:?})",
+            needle,
+            String::from_utf8_lossy(&written)
+        );
+    }
+
+    /// Check how many times the handle was flushed.
+    #[track_caller]
+    pub fn assert_flush_count(&self, expected: usize) {
+        assert_eq!(self.flush_count(), expected, "Unexpected flush count");
+    }
+
+    /// Check the length of each write, e.g. to make sure a plugin doesn't
+    /// split or merge records.
+    #[track_caller]
+    pub fn assert_write_boundaries(&self, expected: &[usize]) {
+        assert_eq!(
+            self.write_boundaries(),
+            expected,
+            "Writes had unexpected lengths"
+        );
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// The writer inside a loopback handle.
+struct Loopback(Expectations);
+
+impl Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.lock().writes.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.0.lock().flushes += 1;
+        Ok(())
+    }
+}
+
+/// A handle which accepts every write, together with the [`Expectations`]
+/// used to check what was written to it.
+///
+/// ```rust
+/// # use std::io::Write;
+/// # use thin_trait_objects::LoopbackHandle;
+/// let LoopbackHandle {
+///     mut handle,
+///     expectations,
+/// } = LoopbackHandle::new();
+///
+/// handle.write_all(b"Hello").unwrap();
+/// handle.write_all(b", World").unwrap();
+/// handle.flush().unwrap();
+///
+/// expectations.assert_written_utf8("Hello, World");
+/// expectations.assert_write_boundaries(&[5, 7]);
+/// expectations.assert_flush_count(1);
+/// ```
+#[derive(Debug)]
+pub struct LoopbackHandle {
+    /// The handle to give to the code being tested.
+    pub handle: OwnedFileHandle,
+    /// Everything written to `handle`.
+    pub expectations: Expectations,
+}
+
+impl LoopbackHandle {
+    /// Create a new [`LoopbackHandle`].
+    pub fn new() -> Self {
+        let expectations = Expectations::default();
+        let handle = OwnedFileHandle::new(Loopback(expectations.clone()));
+
+        LoopbackHandle {
+            handle,
+            expectations,
+        }
+    }
+
+    /// Get the [`Expectations`] for a handle created by
+    /// [`LoopbackHandle::new()`] or [`loopback_handle_new()`].
+    pub fn expectations_for(handle: &OwnedFileHandle) -> Option<Expectations> {
+        handle.downcast_ref::<Loopback>().map(|l| l.0.clone())
+    }
+}
+
+impl Default for LoopbackHandle {
+    fn default() -> Self { LoopbackHandle::new() }
+}
+
+export! {
+    /// Create a handle which remembers everything written to it, for testing
+    /// code which writes to a [`FileHandle`].
+    ///
+    /// Use [`loopback_assert_contains()`] to check what was written.
+    pub unsafe extern "C" fn loopback_handle_new() -> *mut FileHandle {
+        LoopbackHandle::new().handle.into_raw()
+    }
+}
+
+export! {
+    /// Check whether `needle` (a null-terminated string) was written to a
+    /// handle created by [`loopback_handle_new()`].
+    ///
+    /// Returns `false` if it wasn't, or if this isn't a loopback handle.
+    pub unsafe extern "C" fn loopback_assert_contains(
+        handle: *mut FileHandle,
+        needle: *const c_char,
+    ) -> bool {
+        if needle.is_null() {
+            return false;
+        }
+
+        match FileHandle::downcast_raw::<Loopback>(handle) {
+            Some(loopback) => {
+                let needle = CStr::from_ptr(needle).to_bytes();
+                contains(&(*loopback).0.written(), needle)
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+
+    #[test]
+    #[should_panic(expected = "\"Goodbye\" was never written")]
+    fn failed_assertions_show_what_was_written() {
+        let LoopbackHandle {
+            mut handle,
+            expectations,
+        } = LoopbackHandle::new();
+        handle.write_all(b"Hello").unwrap();
+
+        expectations.assert_contains("ell");
+        expectations.assert_contains("Goodbye");
+    }
+
+    #[test]
+    fn check_writes_made_from_c() {
+        unsafe {
+            let handle = loopback_handle_new();
+            file_handle_write(handle, b"level=info".as_ptr().cast(), 10);
+            file_handle_flush(handle);
+
+            let needle = b"info\0".as_ptr().cast();
+            assert!(loopback_assert_contains(handle, needle));
+            let missing = b"warn\0".as_ptr().cast();
+            assert!(!loopback_assert_contains(handle, missing));
+
+            let owned = OwnedFileHandle::from_raw(handle);
+            let expectations = LoopbackHandle::expectations_for(&owned);
+            let expectations = expectations.unwrap();
+            expectations.assert_write_boundaries(&[10]);
+            expectations.assert_flush_count(1);
+
+            let other = new_null_file_handle();
+            assert!(!loopback_assert_contains(other, needle));
+            file_handle_destroy(other);
+        }
+    }
+}
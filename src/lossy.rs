@@ -0,0 +1,388 @@
+//! A [`FileHandle`] for best-effort output (e.g. telemetry) which drops data
+//! instead of ever making the caller wait for I/O.
+
+use crate::{
+    barrier::FlushToken, write_owned::Payload, FileHandle, OwnedFileHandle,
+};
+use std::{
+    collections::VecDeque,
+    io::{Error, Write},
+    ptr,
+    sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, TryLockError},
+    thread::JoinHandle,
+};
+
+/// Counters describing how much a [`LossyWriter`] has dropped.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct LossyStats {
+    /// The number of bytes waiting to be written to the inner handle.
+    pub pending_bytes: u64,
+    /// The number of writes which were discarded, either to make room for
+    /// newer ones, because they were bigger than the whole queue, or because
+    /// the memory budget was used up.
+    pub dropped_writes: u64,
+    /// The total size of the discarded writes.
+    pub dropped_bytes: u64,
+    /// The number of writes which reached the inner handle but failed.
+    pub failed_writes: u64,
+}
+
+struct Queue {
+    chunks: VecDeque<Payload>,
+    max_pending_bytes: usize,
+    /// Is the background thread part way through writing a chunk?
+    in_flight: bool,
+    stopping: bool,
+    stats: LossyStats,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled whenever the queue changes.
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until the background thread has written everything in the queue.
+    fn drain(&self) {
+        let mut queue = self.lock();
+
+        while !queue.chunks.is_empty() || queue.in_flight {
+            queue = self
+                .changed
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// A writer which queues up to `max_pending_bytes` for a background thread
+/// to write to an inner handle, discarding the oldest writes once the queue
+/// is full.
+///
+/// Writing only ever copies into the queue (and never waits on the inner
+/// handle), so it is safe to use from threads which can't block, like a
+/// real-time audio callback. Errors from the inner handle are counted in the
+/// [`LossyStats`] rather than reported.
+///
+/// Flushing waits until everything queued so far has been written, then
+/// flushes the inner handle. Dropping a [`LossyWriter`] does the same before
+/// stopping the background thread.
+pub struct LossyWriter {
+    shared: Arc<Shared>,
+    inner: Arc<Mutex<OwnedFileHandle>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LossyWriter {
+    /// Start a background thread which will write to `inner`.
+    ///
+    /// # Panics
+    ///
+    /// If the thread can't be started (see [`LossyWriter::try_new()`]).
+    pub fn new(inner: OwnedFileHandle, max_pending_bytes: usize) -> Self {
+        LossyWriter::try_new(inner, max_pending_bytes)
+            .expect("Unable to spawn the background thread")
+    }
+
+    /// Start a background thread which will write to `inner`, failing
+    /// (and dropping `inner`) if the thread can't be started.
+    pub fn try_new(
+        inner: OwnedFileHandle,
+        max_pending_bytes: usize,
+    ) -> Result<Self, Error> {
+        LossyWriter::start(inner, max_pending_bytes).map_err(|(e, _)| e)
+    }
+
+    /// Start the background thread, handing `inner` back if it couldn't be
+    /// started.
+    fn start(
+        inner: OwnedFileHandle,
+        max_pending_bytes: usize,
+    ) -> Result<Self, (Error, OwnedFileHandle)> {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                chunks: VecDeque::new(),
+                max_pending_bytes,
+                in_flight: false,
+                stopping: false,
+                stats: LossyStats::default(),
+            }),
+            changed: Condvar::new(),
+        });
+
+        // the handle is only sent over once the thread has started, so we
+        // still have it if it couldn't be
+        let (send_inner, receive_inner) = mpsc::channel();
+        let thread_shared = Arc::clone(&shared);
+        let spawned = std::thread::Builder::new()
+            .name(String::from("lossy-file-handle"))
+            .spawn(move || {
+                let inner: Result<Arc<Mutex<_>>, _> = receive_inner.recv();
+                if let Ok(inner) = inner {
+                    run(&thread_shared, &inner);
+                }
+            });
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(e) => return Err((e, inner)),
+        };
+
+        let inner = Arc::new(Mutex::new(inner));
+        let _ = send_inner.send(Arc::clone(&inner));
+
+        Ok(LossyWriter {
+            shared,
+            inner,
+            thread: Some(thread),
+        })
+    }
+
+    /// How much has been dropped so far.
+    pub fn stats(&self) -> LossyStats { self.shared.lock().stats }
+
+    /// Get a token which resolves once everything queued so far has been
+    /// written to the inner handle.
+    pub(crate) fn flush_token(&self) -> FlushToken {
+        let shared = Arc::clone(&self.shared);
+        let inner = Arc::clone(&self.inner);
+
+        FlushToken::pending(move || {
+            shared.drain();
+            inner.lock().unwrap_or_else(|e| e.into_inner()).barrier()
+        })
+    }
+
+    /// Lock the handle being wrapped, unless someone else is using it.
+    pub(crate) fn try_inner(
+        &self,
+    ) -> Option<MutexGuard<'_, OwnedFileHandle>> {
+        match self.inner.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+impl Write for LossyWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut queue = self.shared.lock();
+        let max = queue.max_pending_bytes;
+
+        if buf.len() > max {
+            queue.stats.dropped_writes += 1;
+            queue.stats.dropped_bytes += buf.len() as u64;
+            return Ok(buf.len());
+        }
+
+        while queue.stats.pending_bytes as usize + buf.len() > max {
+            let evicted = queue
+                .chunks
+                .pop_front()
+                .expect("The queue can't be empty");
+            queue.stats.pending_bytes -= evicted.len() as u64;
+            queue.stats.dropped_writes += 1;
+            queue.stats.dropped_bytes += evicted.len() as u64;
+        }
+
+        match Payload::take_or_copy(self, buf) {
+            Ok(payload) => queue.chunks.push_back(payload),
+            Err(_) => {
+                // over the memory budget, so this write gets dropped too
+                queue.stats.dropped_writes += 1;
+                queue.stats.dropped_bytes += buf.len() as u64;
+                return Ok(buf.len());
+            },
+        }
+        queue.stats.pending_bytes += buf.len() as u64;
+        drop(queue);
+        self.shared.changed.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.shared.drain();
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+impl Drop for LossyWriter {
+    fn drop(&mut self) {
+        self.shared.lock().stopping = true;
+        self.shared.changed.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(shared: &Shared, inner: &Mutex<OwnedFileHandle>) {
+    let mut queue = shared.lock();
+
+    loop {
+        let chunk = match queue.chunks.pop_front() {
+            Some(chunk) => chunk,
+            None if queue.stopping => break,
+            None => {
+                queue = shared
+                    .changed
+                    .wait(queue)
+                    .unwrap_or_else(|e| e.into_inner());
+                continue;
+            },
+        };
+        queue.stats.pending_bytes -= chunk.len() as u64;
+        queue.in_flight = true;
+        drop(queue);
+
+        // Note: the queue is unlocked during I/O so writers never wait on it
+        let result = inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_all(&chunk);
+
+        queue = shared.lock();
+        queue.in_flight = false;
+        if result.is_err() {
+            queue.stats.failed_writes += 1;
+        }
+        shared.changed.notify_all();
+    }
+
+    drop(queue);
+    let _ = inner.lock().unwrap_or_else(|e| e.into_inner()).flush();
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to `inner` from a background
+    /// thread without ever blocking the caller, taking ownership of `inner`.
+    ///
+    /// Up to `max_pending_bytes` may be waiting to be written at a time. Once
+    /// that is reached, the oldest pending writes are dropped to make room for
+    /// new ones, and a single write bigger than `max_pending_bytes` is dropped
+    /// entirely. Use [`lossy_file_handle_stats()`] to see how much was lost.
+    ///
+    /// Returns `null` if `inner` is `null`, `max_pending_bytes` is `0`, or the
+    /// background thread couldn't be started, in which case ownership of
+    /// `inner` is not taken. A thread which couldn't be started is recorded
+    /// as `inner`'s last error.
+    pub unsafe extern "C" fn new_lossy_file_handle(
+        inner: *mut FileHandle,
+        max_pending_bytes: usize,
+    ) -> *mut FileHandle {
+        if inner.is_null() || max_pending_bytes == 0 {
+            return ptr::null_mut();
+        }
+
+        let inner = OwnedFileHandle::from_raw(inner);
+        match LossyWriter::start(inner, max_pending_bytes) {
+            Ok(writer) => FileHandle::for_writer(writer),
+            Err((e, inner)) => {
+                let inner = inner.into_raw();
+                (*inner).cold.last_error.record(&e);
+                ptr::null_mut()
+            },
+        }
+    }
+}
+
+export! {
+    /// Copy the [`LossyStats`] for a handle created with
+    /// [`new_lossy_file_handle()`] into `stats`.
+    ///
+    /// Returns `false` if this isn't a lossy handle.
+    pub unsafe extern "C" fn lossy_file_handle_stats(
+        handle: *mut FileHandle,
+        stats: *mut LossyStats,
+    ) -> bool {
+        match FileHandle::downcast_raw::<LossyWriter>(handle) {
+            Some(writer) => {
+                stats.write((*writer).stats());
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+    use std::{os::raw::c_int, sync::mpsc};
+
+    /// A writer which blocks until it is told to continue.
+    struct Gate(Mutex<mpsc::Receiver<()>>, SharedBuffer);
+
+    impl Write for Gate {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            let _ = self.0.lock().unwrap().recv();
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+
+    #[test]
+    fn oldest_writes_are_dropped_when_the_queue_is_full() {
+        let (open, gate) = mpsc::channel();
+        let buffer = SharedBuffer::default();
+        let gate = Gate(Mutex::new(gate), buffer.clone());
+        let mut writer = LossyWriter::new(OwnedFileHandle::new(gate), 4);
+
+        // the first write gets stuck in the inner handle
+        writer.write_all(b"a").unwrap();
+        while writer.stats().pending_bytes > 0 {
+            std::thread::yield_now();
+        }
+
+        for chunk in &[&b"bb"[..], b"cc", b"dd", b"toolong"] {
+            writer.write_all(chunk).unwrap();
+        }
+        let stats = writer.stats();
+        assert_eq!(stats.pending_bytes, 4);
+        assert_eq!(stats.dropped_writes, 2);
+        assert_eq!(stats.dropped_bytes, 2 + 7);
+
+        drop(open);
+        writer.flush().unwrap();
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"accdd");
+    }
+
+    #[test]
+    fn create_and_inspect_from_c() {
+        let buffer = SharedBuffer::default();
+        let mut stats = LossyStats::default();
+
+        unsafe {
+            let inner = FileHandle::for_writer(buffer.clone());
+            assert!(new_lossy_file_handle(inner, 0).is_null());
+
+            let handle = new_lossy_file_handle(inner, 64);
+            let msg = b"telemetry";
+            let ret = file_handle_write(handle, msg.as_ptr().cast(), 9);
+            assert_eq!(ret, 9 as c_int);
+            assert!(lossy_file_handle_stats(handle, &mut stats));
+            assert_eq!(stats.dropped_writes, 0);
+            file_handle_destroy(handle);
+
+            let other = new_null_file_handle();
+            assert!(!lossy_file_handle_stats(other, &mut stats));
+            file_handle_destroy(other);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"telemetry");
+    }
+}
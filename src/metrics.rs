@@ -0,0 +1,377 @@
+//! Pushing handle statistics to the embedder's own metrics pipeline (e.g. a
+//! statsd or OpenTelemetry agent) instead of making it poll every handle.
+//!
+//! Only handles with thread stats or latency stats enabled report metrics,
+//! and nothing is done at all until a sink is installed.
+
+use crate::{global::Global, FileHandle};
+use std::{
+    ffi::{CStr, CString},
+    io::Error,
+    os::raw::{c_char, c_void},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// The number of bytes accepted by a write.
+pub const METRIC_BYTES_WRITTEN: &str = "file_handle.bytes_written";
+/// A write which failed, always with a value of `1`.
+pub const METRIC_WRITE_ERRORS: &str = "file_handle.write_errors";
+/// How long a write took, in microseconds (only with latency stats).
+pub const METRIC_WRITE_LATENCY_US: &str = "file_handle.write_latency_us";
+/// How long a flush took, in microseconds (only with latency stats).
+pub const METRIC_FLUSH_LATENCY_US: &str = "file_handle.flush_latency_us";
+
+/// A single measurement.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Metric<'a> {
+    /// What was measured (e.g. [`METRIC_BYTES_WRITTEN`]).
+    pub name: &'a str,
+    /// The measurement itself.
+    pub value: f64,
+    /// Tags in statsd's `"key:value"` form. Every metric is tagged with the
+    /// handle's `type`, and with its `name` if it has one.
+    pub tags: &'a [&'a str],
+    /// The fraction of events which are being reported, so counters can be
+    /// scaled back up.
+    pub sample_rate: f64,
+}
+
+/// Somewhere metrics can be sent.
+///
+/// The sink is called from whichever thread used the handle, in the middle
+/// of the operation being measured, so it should return quickly.
+pub trait MetricsSink: Send + Sync {
+    /// Handle a measurement.
+    fn record(&self, metric: &Metric<'_>);
+}
+
+impl<F> MetricsSink for F
+where
+    F: Fn(&Metric<'_>) + Send + Sync,
+{
+    fn record(&self, metric: &Metric<'_>) { self(metric) }
+}
+
+c_unwind! {
+    /// Called with `user_data`, the metric's name, its value, an array of
+    /// `tag_count` tags, and the sample rate.
+    pub(crate) type MetricsCallback = unsafe fn(
+        *mut c_void,
+        *const c_char,
+        f64,
+        *const *const c_char,
+        usize,
+        f64,
+    );
+}
+
+struct Sampled {
+    sink: Arc<dyn MetricsSink>,
+    every: u32,
+    seen: AtomicU64,
+}
+
+/// Lets the hot path skip locking when there is no sink.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static METRICS_SINK: Global<Mutex<Option<Arc<Sampled>>>> = Global::new();
+
+fn sink_slot() -> &'static Mutex<Option<Arc<Sampled>>> {
+    METRICS_SINK.get_or_init(|| Mutex::new(None))
+}
+
+/// Free the metrics sink.
+pub(crate) unsafe fn shutdown() {
+    ENABLED.store(false, Ordering::Relaxed);
+    METRICS_SINK.reset();
+}
+
+/// Send metrics from every handle with stats enabled to `sink`, reporting
+/// one in every `sample_every` measurements (`0` and `1` both mean all of
+/// them).
+pub fn set_metrics_sink(sink: Arc<dyn MetricsSink>, sample_every: u32) {
+    replace_metrics_sink(Some(sink), sample_every);
+}
+
+/// The sink set with [`set_metrics_sink()`] and its sampling, if there is
+/// one.
+pub(crate) fn configured_metrics_sink(
+) -> Option<(Arc<dyn MetricsSink>, u32)> {
+    sink_slot()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|sampled| (Arc::clone(&sampled.sink), sampled.every))
+}
+
+/// Replace (or clear) the metrics sink.
+pub(crate) fn replace_metrics_sink(
+    sink: Option<Arc<dyn MetricsSink>>,
+    sample_every: u32,
+) {
+    let sampled = sink.map(|sink| {
+        Arc::new(Sampled {
+            sink,
+            every: sample_every.max(1),
+            seen: AtomicU64::new(0),
+        })
+    });
+
+    let mut slot = sink_slot().lock().unwrap_or_else(|e| e.into_inner());
+    ENABLED.store(sampled.is_some(), Ordering::Relaxed);
+    *slot = sampled;
+}
+
+fn current() -> Option<Arc<Sampled>> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    sink_slot()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Send some of the handle's metrics to the sink, if this measurement is
+/// part of the sample.
+unsafe fn emit(handle: *mut FileHandle, metrics: &[(&str, f64)]) {
+    let sampled = match current() {
+        Some(sampled) => sampled,
+        None => return,
+    };
+
+    let seen = sampled.seen.fetch_add(1, Ordering::Relaxed);
+    if seen % u64::from(sampled.every) != 0 {
+        return;
+    }
+
+    let type_tag = format!("type:{}", (*handle).cold.type_name);
+    let name = crate::file_handle_name(handle);
+    let name_tag = if name.is_null() {
+        None
+    } else {
+        Some(format!("name:{}", CStr::from_ptr(name).to_string_lossy()))
+    };
+    let mut tags = vec![type_tag.as_str()];
+    tags.extend(name_tag.as_deref());
+
+    for &(name, value) in metrics {
+        sampled.sink.record(&Metric {
+            name,
+            value,
+            tags: &tags,
+            sample_rate: 1.0 / f64::from(sampled.every),
+        });
+    }
+}
+
+fn micros(elapsed: Duration) -> f64 { elapsed.as_secs_f64() * 1_000_000.0 }
+
+/// Report a write made through a handle with stats enabled.
+pub(crate) unsafe fn report_write(
+    handle: *mut FileHandle,
+    result: &Result<usize, Error>,
+    elapsed: Option<Duration>,
+) {
+    let outcome = match result {
+        Ok(bytes) => (METRIC_BYTES_WRITTEN, *bytes as f64),
+        Err(_) => (METRIC_WRITE_ERRORS, 1.0),
+    };
+
+    match elapsed {
+        Some(elapsed) => emit(
+            handle,
+            &[outcome, (METRIC_WRITE_LATENCY_US, micros(elapsed))],
+        ),
+        None => emit(handle, &[outcome]),
+    }
+}
+
+/// Report how long a flush took.
+pub(crate) unsafe fn report_flush(
+    handle: *mut FileHandle,
+    elapsed: Duration,
+) {
+    emit(handle, &[(METRIC_FLUSH_LATENCY_US, micros(elapsed))]);
+}
+
+/// Is anyone listening for metrics?
+pub(crate) fn is_enabled() -> bool { ENABLED.load(Ordering::Relaxed) }
+
+/// Forwards metrics to a [`MetricsCallback`].
+struct CallbackSink {
+    callback: MetricsCallback,
+    user_data: *mut c_void,
+}
+
+// Safety: the caller of thin_trait_objects_set_metrics_sink() promises the
+// callback may be called from any thread.
+unsafe impl Send for CallbackSink {}
+unsafe impl Sync for CallbackSink {}
+
+impl MetricsSink for CallbackSink {
+    fn record(&self, metric: &Metric<'_>) {
+        let to_c = |s: &str| CString::new(s).unwrap_or_default();
+        let name = to_c(metric.name);
+        let tags: Vec<CString> =
+            metric.tags.iter().map(|t| to_c(t)).collect();
+        let tag_ptrs: Vec<*const c_char> =
+            tags.iter().map(|t| t.as_ptr()).collect();
+
+        unsafe {
+            (self.callback)(
+                self.user_data,
+                name.as_ptr(),
+                metric.value,
+                tag_ptrs.as_ptr(),
+                tag_ptrs.len(),
+                metric.sample_rate,
+            );
+        }
+    }
+}
+
+export! {
+    /// Send metrics from every handle with thread stats or latency stats
+    /// enabled to `callback`, reporting one in every `sample_every`
+    /// measurements (`0` and `1` both mean all of them). Passing a `null`
+    /// callback stops reporting metrics.
+    ///
+    /// The callback is given `user_data`, the metric's name (one of the
+    /// `file_handle.*` names listed below), its value, an array of `tag_count`
+    /// `"key:value"` tags, and the sample rate. All strings are only valid for
+    /// the duration of the call. It is called from whichever thread used the
+    /// handle, so it must be thread-safe and should return quickly.
+    ///
+    /// | Metric                          | Value                       |
+    /// | ------------------------------- | --------------------------- |
+    /// | `file_handle.bytes_written`     | Bytes accepted by a write   |
+    /// | `file_handle.write_errors`      | `1` for each failed write   |
+    /// | `file_handle.write_latency_us`  | How long a write took       |
+    /// | `file_handle.flush_latency_us`  | How long a flush took       |
+    pub unsafe extern "C" fn thin_trait_objects_set_metrics_sink(
+        callback: Option<MetricsCallback>,
+        user_data: *mut c_void,
+        sample_every: u32,
+    ) {
+        let sink = callback.map(|callback| {
+            Arc::new(CallbackSink {
+                callback,
+                user_data,
+            }) as Arc<dyn MetricsSink>
+        });
+
+        replace_metrics_sink(sink, sample_every);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lifecycle::lock_global_state, Config, OwnedFileHandle};
+    use std::io::Write;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Recorded {
+        name: String,
+        value: f64,
+        tags: Vec<String>,
+        sample_rate: f64,
+    }
+
+    fn recorder() -> (Arc<dyn MetricsSink>, Arc<Mutex<Vec<Recorded>>>) {
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let sink_recorded = Arc::clone(&recorded);
+        let sink = move |m: &Metric<'_>| {
+            sink_recorded.lock().unwrap().push(Recorded {
+                name: m.name.to_string(),
+                value: m.value,
+                tags: m.tags.iter().map(|t| t.to_string()).collect(),
+                sample_rate: m.sample_rate,
+            });
+        };
+
+        (Arc::new(sink), recorded)
+    }
+
+    #[test]
+    fn only_handles_with_stats_report_metrics() {
+        let _global = lock_global_state();
+        let (sink, recorded) = recorder();
+        set_metrics_sink(sink, 2);
+
+        let mut quiet = OwnedFileHandle::new(Vec::<u8>::new());
+        quiet.write_all(b"ignored").unwrap();
+        assert!(recorded.lock().unwrap().is_empty());
+
+        let mut handle = OwnedFileHandle::new(Vec::<u8>::new());
+        handle.enable_thread_stats();
+        for _ in 0..4 {
+            handle.write_all(b"abc").unwrap();
+        }
+
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].name, METRIC_BYTES_WRITTEN);
+        assert_eq!(recorded[0].value, 3.0);
+        assert_eq!(recorded[0].tags, ["type:alloc::vec::Vec<u8>"]);
+        assert_eq!(recorded[0].sample_rate, 0.5);
+        drop(recorded);
+
+        Config::default().apply();
+        assert!(!is_enabled());
+    }
+
+    #[test]
+    fn latencies_are_sent_to_a_c_callback() {
+        let _global = lock_global_state();
+
+        c_unwind! {
+            unsafe fn collect(
+                user_data: *mut c_void,
+                name: *const c_char,
+                _value: f64,
+                tags: *const *const c_char,
+                tag_count: usize,
+                _sample_rate: f64,
+            ) {
+                let names = &*(user_data as *const Mutex<Vec<String>>);
+                let name = CStr::from_ptr(name).to_str().unwrap();
+                let tags = std::slice::from_raw_parts(tags, tag_count);
+                let tag = CStr::from_ptr(tags[1]).to_str().unwrap();
+                assert_eq!(tag, "name:metered");
+                names.lock().unwrap().push(name.to_string());
+            }
+        }
+
+        let names = Mutex::new(Vec::<String>::new());
+        unsafe {
+            let user_data = &names as *const _ as *mut c_void;
+            thin_trait_objects_set_metrics_sink(Some(collect), user_data, 0);
+
+            let handle = crate::FileHandle::for_writer(Vec::<u8>::new());
+            (*handle).cold.name = Some(CString::new("metered").unwrap());
+            let mut handle = OwnedFileHandle::from_raw(handle);
+            handle.enable_latency_stats();
+            handle.write_all(b"x").unwrap();
+            handle.flush().unwrap();
+
+            thin_trait_objects_set_metrics_sink(None, std::ptr::null_mut(), 0);
+            handle.write_all(b"y").unwrap();
+        }
+
+        assert_eq!(
+            *names.lock().unwrap(),
+            [
+                METRIC_BYTES_WRITTEN,
+                METRIC_WRITE_LATENCY_US,
+                METRIC_FLUSH_LATENCY_US
+            ]
+        );
+    }
+}
@@ -0,0 +1,979 @@
+//! A small model checker in the spirit of [loom](https://docs.rs/loom),
+//! used by the `loom-tests` feature to run a test under many interleavings
+//! of its threads.
+//!
+//! With the feature enabled, `crate::sync` hands out the primitives from
+//! this module instead of `std`'s. They behave exactly like `std`'s outside
+//! of [`model()`]. Inside it, only one thread runs at a time and every
+//! atomic operation or lock is a point where the checker may switch to
+//! another thread, so each call to [`model()`] runs the test once for every
+//! schedule within the bounds below.
+//!
+//! This is not loom, and a passing test is weaker evidence than it would be
+//! there:
+//!
+//! - only sequentially consistent executions are explored, so it can't find
+//!   bugs caused by too weak an [`Ordering`] (every `Relaxed`, `Acquire` or
+//!   `Release` is treated as `SeqCst`)
+//! - like `LOOM_MAX_PREEMPTIONS`, a thread which could keep running is
+//!   switched away from at most [`MAX_PREEMPTIONS`] times per execution, so
+//!   bugs which need more context switches than that to show up are missed
+//! - only the primitives in `crate::sync` are switch points, so races
+//!   through anything else (`UnsafeCell`s, plain `std` atomics or locks)
+//!   aren't seen at all
+//! - timed waits only time out once no other thread can make progress
+//! - the code being tested must not block on anything else (e.g. a
+//!   `std::sync::Mutex` held across one of these operations)
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    fmt::{self, Debug, Formatter},
+    io,
+    ops::{Deref, DerefMut},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        self as std_sync, atomic::Ordering, Arc, LockResult, PoisonError,
+        TryLockError, TryLockResult,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How many times a runnable thread may be switched away from in a single
+/// execution.
+const MAX_PREEMPTIONS: usize = 2;
+/// Executions taking more steps than this are assumed to be livelocked.
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Status {
+    Runnable,
+    /// Waiting for the lock at this address to be released.
+    Locking(usize),
+    /// Waiting for the condition variable at this address.
+    Waiting { condvar: usize, timed: bool },
+    /// Waiting for another thread to finish.
+    Joining(usize),
+    Finished,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    threads: Vec<Status>,
+    /// Which threads woke up because their timed wait ran out.
+    timed_out: Vec<bool>,
+    /// The one thread allowed to run.
+    active: usize,
+    /// The choices made so far, and how many options each one had.
+    path: Vec<(usize, usize)>,
+    depth: usize,
+    preemptions: usize,
+    steps: usize,
+    /// A thread failed, so every other thread should stop.
+    aborted: bool,
+}
+
+impl State {
+    /// Pick the next thread to run while `me` is about to pause.
+    fn pick(&mut self, me: usize) -> Option<usize> {
+        let mut options: Vec<usize> = (0..self.threads.len())
+            .filter(|&t| self.threads[t] == Status::Runnable)
+            .collect();
+
+        if options.is_empty() {
+            // nobody can make progress, so enough time passes for any timed
+            // wait to run out
+            options = (0..self.threads.len())
+                .filter(|&t| match self.threads[t] {
+                    Status::Waiting { timed, .. } => timed,
+                    _ => false,
+                })
+                .collect();
+        } else if self.preemptions >= MAX_PREEMPTIONS && options.contains(&me)
+        {
+            options = vec![me];
+        }
+
+        if options.is_empty() {
+            return None;
+        }
+
+        let next = options[self.choose(options.len())];
+
+        if let Status::Waiting { .. } = self.threads[next] {
+            self.threads[next] = Status::Runnable;
+            self.timed_out[next] = true;
+        }
+        if next != me && self.threads[me] == Status::Runnable {
+            self.preemptions += 1;
+        }
+
+        Some(next)
+    }
+
+    fn choose(&mut self, options: usize) -> usize {
+        if options == 1 {
+            return 0;
+        }
+
+        if self.depth == self.path.len() {
+            self.path.push((0, options));
+        }
+        let (choice, expected) = self.path[self.depth];
+        assert_eq!(expected, options, "The test isn't deterministic");
+        self.depth += 1;
+
+        choice
+    }
+
+    /// Make every thread blocked on something runnable again.
+    fn wake(&mut self, blocked: impl Fn(Status) -> bool) {
+        for status in &mut self.threads {
+            if blocked(*status) {
+                *status = Status::Runnable;
+            }
+        }
+    }
+}
+
+/// One run of the test under a particular schedule.
+#[derive(Default)]
+pub struct Execution {
+    state: std_sync::Mutex<State>,
+    changed: std_sync::Condvar,
+    os_threads: std_sync::Mutex<Vec<thread::JoinHandle<()>>>,
+    failure: std_sync::Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+/// The panic payload used to unwind the other threads once one has failed.
+struct Aborted;
+
+thread_local! {
+    static CURRENT: RefCell<Option<(Arc<Execution>, usize)>> =
+        RefCell::new(None);
+}
+
+/// The execution this thread belongs to, and its ID within it.
+fn current() -> Option<(Arc<Execution>, usize)> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Give the checker a chance to run another thread.
+fn yield_now() {
+    if let Some((execution, me)) = current() {
+        execution.pause(me, Status::Runnable);
+    }
+}
+
+fn address<T>(value: &T) -> usize { value as *const T as usize }
+
+impl Execution {
+    fn state(&self) -> std_sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn fail(&self, payload: Box<dyn Any + Send>) {
+        let mut failure =
+            self.failure.lock().unwrap_or_else(|e| e.into_inner());
+        failure.get_or_insert(payload);
+    }
+
+    /// Let another thread run, only coming back once `me` is picked again.
+    fn pause(&self, me: usize, status: Status) {
+        let mut state = self.state();
+
+        if thread::panicking() {
+            // let everyone unwind instead of scheduling them
+            state.aborted = true;
+            drop(state);
+            self.changed.notify_all();
+            return;
+        }
+
+        state.threads[me] = status;
+        state.steps += 1;
+
+        let problem = if state.steps > MAX_STEPS {
+            Some(format!("More than {} steps were taken", MAX_STEPS))
+        } else {
+            match state.pick(me) {
+                Some(next) => {
+                    state.active = next;
+                    None
+                },
+                None => Some(format!("Deadlock: {:?}", state.threads)),
+            }
+        };
+
+        if let Some(problem) = problem {
+            state.aborted = true;
+            drop(state);
+            self.changed.notify_all();
+            panic!("{}", problem);
+        }
+
+        self.changed.notify_all();
+        self.wait_for_turn(state, me);
+    }
+
+    fn wait_for_turn(
+        &self,
+        mut state: std_sync::MutexGuard<'_, State>,
+        me: usize,
+    ) {
+        while state.active != me && !state.aborted {
+            state =
+                self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+
+        if state.aborted {
+            drop(state);
+            panic::resume_unwind(Box::new(Aborted));
+        }
+    }
+
+    fn finish(&self, me: usize) {
+        let mut state = self.state();
+        state.threads[me] = Status::Finished;
+        state.wake(|status| status == Status::Joining(me));
+
+        if !state.aborted {
+            let finished = |&s: &Status| s == Status::Finished;
+            match state.pick(me) {
+                Some(next) => state.active = next,
+                None if state.threads.iter().all(finished) => {},
+                None => {
+                    state.aborted = true;
+                    let problem = format!("Deadlock: {:?}", state.threads);
+                    self.fail(Box::new(problem));
+                },
+            }
+        }
+
+        drop(state);
+        self.changed.notify_all();
+    }
+
+    /// Start a thread which waits for its turn before running `f`.
+    fn start<F>(self: &Arc<Self>, f: F) -> usize
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let id = {
+            let mut state = self.state();
+            state.threads.push(Status::Runnable);
+            state.timed_out.push(false);
+            state.threads.len() - 1
+        };
+
+        let execution = Arc::clone(self);
+        let os_thread = thread::spawn(move || {
+            CURRENT.with(|current| {
+                *current.borrow_mut() = Some((Arc::clone(&execution), id))
+            });
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                execution.wait_for_turn(execution.state(), id);
+                f();
+            }));
+
+            if let Err(payload) = result {
+                if !payload.is::<Aborted>() {
+                    execution.fail(payload);
+                }
+                execution.state().aborted = true;
+            }
+
+            execution.finish(id);
+            CURRENT.with(|current| *current.borrow_mut() = None);
+        });
+
+        self.os_threads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(os_thread);
+
+        id
+    }
+}
+
+/// Move on to the next schedule to try, returning `false` once they have
+/// all been explored.
+fn advance(path: &mut Vec<(usize, usize)>) -> bool {
+    while let Some(last) = path.last_mut() {
+        if last.0 + 1 < last.1 {
+            last.0 += 1;
+            return true;
+        }
+        path.pop();
+    }
+
+    false
+}
+
+/// Run `f` under every schedule within the checker's bounds (see the module
+/// docs), panicking (with the schedule that failed) if any of them panic or
+/// deadlock.
+pub(crate) fn model<F>(f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let mut path = Vec::new();
+    let mut executions = 0;
+
+    loop {
+        executions += 1;
+        let execution = Arc::new(Execution::default());
+        execution.state().path = std::mem::take(&mut path);
+
+        let f = Arc::clone(&f);
+        execution.start(move || f());
+
+        loop {
+            let os_thread = execution
+                .os_threads
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop();
+            match os_thread {
+                Some(os_thread) => os_thread.join().unwrap(),
+                None => break,
+            }
+        }
+
+        path = std::mem::take(&mut execution.state().path);
+        let failure = execution
+            .failure
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+
+        if let Some(payload) = failure {
+            let schedule: Vec<usize> = path.iter().map(|c| c.0).collect();
+            eprintln!(
+                "Execution {} failed with the schedule {:?}",
+                executions, schedule
+            );
+            panic::resume_unwind(payload);
+        }
+
+        if !advance(&mut path) {
+            return;
+        }
+    }
+}
+
+macro_rules! atomic {
+    ($name:ident($ty:ty) { $( $op:ident ),* $(,)? }) => {
+        /// A model-checked version of the `std` atomic.
+        #[derive(Debug, Default)]
+        #[repr(transparent)]
+        pub(crate) struct $name(std_sync::atomic::$name);
+
+        // not everything is used, but they mirror std
+        #[allow(dead_code)]
+        impl $name {
+            pub(crate) const fn new(value: $ty) -> Self {
+                $name(std_sync::atomic::$name::new(value))
+            }
+
+            pub(crate) fn load(&self, ordering: Ordering) -> $ty {
+                yield_now();
+                self.0.load(ordering)
+            }
+
+            pub(crate) fn store(&self, value: $ty, ordering: Ordering) {
+                yield_now();
+                self.0.store(value, ordering)
+            }
+
+            pub(crate) fn compare_exchange(
+                &self,
+                current: $ty,
+                new: $ty,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$ty, $ty> {
+                yield_now();
+                self.0.compare_exchange(current, new, success, failure)
+            }
+
+            $(
+                pub(crate) fn $op(
+                    &self,
+                    value: $ty,
+                    ordering: Ordering,
+                ) -> $ty {
+                    yield_now();
+                    self.0.$op(value, ordering)
+                }
+            )*
+        }
+    };
+}
+
+atomic!(AtomicBool(bool) { swap, fetch_or, fetch_and });
+atomic!(AtomicU32(u32) { swap, fetch_or, fetch_and, fetch_add, fetch_sub });
+
+/// A model-checked version of [`std::sync::Mutex`].
+#[derive(Default)]
+pub(crate) struct Mutex<T> {
+    inner: std_sync::Mutex<T>,
+}
+
+/// The guard returned by [`Mutex::lock()`].
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    guard: Option<std_sync::MutexGuard<'a, T>>,
+}
+
+impl<T> Mutex<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Mutex {
+            inner: std_sync::Mutex::new(value),
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> LockResult<T> { self.inner.into_inner() }
+}
+
+impl<T> Mutex<T> {
+    fn wrap<'a>(
+        &'a self,
+        guard: LockResult<std_sync::MutexGuard<'a, T>>,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        match guard {
+            Ok(guard) => Ok(MutexGuard {
+                mutex: self,
+                guard: Some(guard),
+            }),
+            Err(e) => Err(PoisonError::new(MutexGuard {
+                mutex: self,
+                guard: Some(e.into_inner()),
+            })),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let (execution, me) = match current() {
+            Some(current) => current,
+            None => return self.wrap(self.inner.lock()),
+        };
+
+        loop {
+            execution.pause(me, Status::Runnable);
+
+            match self.inner.try_lock() {
+                Ok(guard) => return self.wrap(Ok(guard)),
+                Err(TryLockError::Poisoned(e)) => return self.wrap(Err(e)),
+                Err(TryLockError::WouldBlock) => {
+                    execution.pause(me, Status::Locking(address(self)))
+                },
+            }
+        }
+    }
+
+    pub(crate) fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        yield_now();
+
+        match self.inner.try_lock() {
+            Ok(guard) => Ok(self.wrap(Ok(guard)).ok().unwrap()),
+            Err(TryLockError::Poisoned(e)) => match self.wrap(Err(e)) {
+                Err(e) => Err(TryLockError::Poisoned(e)),
+                Ok(_) => unreachable!(),
+            },
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Debug> Debug for Mutex<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.inner, f)
+    }
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// Release the lock, waking anyone waiting for it.
+    fn unlock(&mut self) {
+        drop(self.guard.take());
+
+        if let Some((execution, _)) = current() {
+            let mutex = address(self.mutex);
+            execution
+                .state()
+                .wake(|status| status == Status::Locking(mutex));
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T { self.guard.as_ref().unwrap() }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T { self.guard.as_mut().unwrap() }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.guard.is_none() {
+            return;
+        }
+
+        self.unlock();
+        if !thread::panicking() {
+            yield_now();
+        }
+    }
+}
+
+impl<'a, T: Debug> Debug for MutexGuard<'a, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+/// A model-checked version of [`std::sync::Condvar`].
+#[derive(Debug, Default)]
+pub(crate) struct Condvar {
+    inner: std_sync::Condvar,
+}
+
+/// Whether [`Condvar::wait_timeout()`] timed out.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    #[allow(dead_code)]
+    pub(crate) fn timed_out(&self) -> bool { self.0 }
+}
+
+impl Condvar {
+    pub(crate) fn wait<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        match self.wait_for(guard, None) {
+            Ok((guard, _)) => Ok(guard),
+            Err(e) => Err(PoisonError::new(e.into_inner().0)),
+        }
+    }
+
+    pub(crate) fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+        self.wait_for(guard, Some(timeout))
+    }
+
+    fn wait_for<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+        let mutex = guard.mutex;
+
+        let (execution, me) = match current() {
+            Some(current) => current,
+            None => {
+                let inner = guard.guard.take().unwrap();
+                let (inner, timed_out, poisoned) = match timeout {
+                    Some(timeout) => {
+                        match self.inner.wait_timeout(inner, timeout) {
+                            Ok((g, result)) => (g, result.timed_out(), false),
+                            Err(e) => {
+                                let (g, result) = e.into_inner();
+                                (g, result.timed_out(), true)
+                            },
+                        }
+                    },
+                    None => match self.inner.wait(inner) {
+                        Ok(g) => (g, false, false),
+                        Err(e) => (e.into_inner(), false, true),
+                    },
+                };
+
+                guard.guard = Some(inner);
+                let result = (guard, WaitTimeoutResult(timed_out));
+                return if poisoned {
+                    Err(PoisonError::new(result))
+                } else {
+                    Ok(result)
+                };
+            },
+        };
+
+        guard.unlock();
+        drop(guard);
+
+        let status = Status::Waiting {
+            condvar: address(self),
+            timed: timeout.is_some(),
+        };
+        execution.pause(me, status);
+        let timed_out =
+            std::mem::replace(&mut execution.state().timed_out[me], false);
+
+        match mutex.lock() {
+            Ok(guard) => Ok((guard, WaitTimeoutResult(timed_out))),
+            Err(e) => Err(PoisonError::new((
+                e.into_inner(),
+                WaitTimeoutResult(timed_out),
+            ))),
+        }
+    }
+
+    pub(crate) fn notify_all(&self) {
+        match current() {
+            Some((execution, _)) => {
+                let condvar = address(self);
+                execution.state().wake(
+                    |status| match status {
+                        Status::Waiting { condvar: c, .. } => c == condvar,
+                        _ => false,
+                    },
+                );
+            },
+            None => self.inner.notify_all(),
+        }
+    }
+}
+
+/// A model-checked version of [`std::thread::JoinHandle`].
+pub(crate) enum JoinHandle<T> {
+    Std(thread::JoinHandle<T>),
+    Model {
+        execution: Arc<Execution>,
+        id: usize,
+        result: Arc<std_sync::Mutex<Option<T>>>,
+    },
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn join(self) -> thread::Result<T> {
+        match self {
+            JoinHandle::Std(handle) => handle.join(),
+            JoinHandle::Model {
+                execution,
+                id,
+                result,
+            } => {
+                let (_, me) = current().expect("Joined outside the model");
+                let status = execution.state().threads[id];
+                if status != Status::Finished {
+                    execution.pause(me, Status::Joining(id));
+                }
+
+                let result =
+                    result.lock().unwrap_or_else(|e| e.into_inner()).take();
+                result.ok_or_else(|| {
+                    Box::new("The thread panicked") as Box<dyn Any + Send>
+                })
+            },
+        }
+    }
+}
+
+impl<T> Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinHandle::Std(handle) => Debug::fmt(handle, f),
+            JoinHandle::Model { id, .. } => {
+                f.debug_struct("JoinHandle").field("id", id).finish()
+            },
+        }
+    }
+}
+
+/// Start a thread, which is part of the model if the caller is.
+pub(crate) fn spawn<F>(name: &str, f: F) -> io::Result<JoinHandle<()>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    match current() {
+        Some(_) => Ok(spawn_model(f)),
+        None => thread::Builder::new()
+            .name(name.into())
+            .spawn(f)
+            .map(JoinHandle::Std),
+    }
+}
+
+/// Start a thread inside the current [`model()`].
+pub(crate) fn spawn_model<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (execution, me) = current().expect("Spawned outside the model");
+    let result = Arc::new(std_sync::Mutex::new(None));
+
+    let slot = Arc::clone(&result);
+    let id = execution.start(move || {
+        let value = f();
+        *slot.lock().unwrap_or_else(|e| e.into_inner()) = Some(value);
+    });
+    execution.pause(me, Status::Runnable);
+
+    JoinHandle::Model {
+        execution,
+        id,
+        result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ffi::{tests::SharedBuffer, *},
+        ArcFileHandle, FileHandle, IndirectWriter, OwnedFileHandle,
+    };
+    use std::io::Write;
+
+    /// A handle which can be shared with another thread.
+    struct Shared(*mut FileHandle);
+
+    unsafe impl Send for Shared {}
+    unsafe impl Sync for Shared {}
+
+    /// A writer which panics when asked to write `b"panic"`, counting how
+    /// many times it did.
+    #[derive(Clone, Default)]
+    struct Panicky(SharedBuffer, Arc<std_sync::atomic::AtomicUsize>);
+
+    impl Write for &Panicky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf == b"panic" {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                panic!("Asked to panic");
+            }
+            self.0.clone().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Write for Panicky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            (&*self).write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> { (&*self).flush() }
+    }
+
+    /// Did a call fail because the handle was poisoned? Nothing else is
+    /// allowed to go wrong.
+    fn poisoned<T>(result: io::Result<T>) -> bool {
+        match result {
+            Ok(_) => false,
+            Err(e) => {
+                assert!(crate::file_handle::is_poison_error(&e), "{}", e);
+                true
+            },
+        }
+    }
+
+    /// Once a call has seen the poisoned flag, every later call must too.
+    fn assert_stays_poisoned(outcomes: &[bool]) {
+        if let Some(first) = outcomes.iter().position(|&p| p) {
+            assert!(outcomes[first..].iter().all(|&p| p), "{:?}", outcomes);
+        }
+    }
+
+    #[test]
+    fn the_checker_finds_lost_updates() {
+        let result = panic::catch_unwind(|| {
+            model(|| {
+                let counter = Arc::new(AtomicU32::new(0));
+
+                let other = Arc::clone(&counter);
+                let thread = spawn_model(move || {
+                    let value = other.load(Ordering::SeqCst);
+                    other.store(value + 1, Ordering::SeqCst);
+                });
+                let value = counter.load(Ordering::SeqCst);
+                counter.store(value + 1, Ordering::SeqCst);
+                thread.join().unwrap();
+
+                assert_eq!(counter.load(Ordering::SeqCst), 2);
+            })
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn flag_updates_are_never_lost() {
+        model(|| {
+            let handle = Arc::new(Shared(FileHandle::for_writer(Vec::new())));
+
+            let other = Arc::clone(&handle);
+            let thread = spawn_model(move || unsafe {
+                (*other.0).set_flag(FileHandle::SYNC_ON_FLUSH);
+            });
+            unsafe {
+                (*handle.0).set_flag(FileHandle::FROZEN);
+                (*handle.0).clear_flag(FileHandle::FROZEN);
+            }
+            thread.join().unwrap();
+
+            unsafe {
+                assert!((*handle.0).has_flag(FileHandle::SYNC_ON_FLUSH));
+                assert!(!(*handle.0).has_flag(FileHandle::FROZEN));
+                file_handle_destroy(handle.0);
+            }
+        });
+    }
+
+    #[test]
+    fn shared_handles_can_always_be_unwrapped_afterwards() {
+        model(|| {
+            let buffer = SharedBuffer::default();
+            let handle = OwnedFileHandle::new(buffer.clone());
+            let handle = ArcFileHandle::new(handle);
+
+            let mut other = handle.clone();
+            let thread = spawn_model(move || {
+                other.write_all(b"a").unwrap();
+            });
+            (&handle).write_all(b"b").unwrap();
+            thread.join().unwrap();
+
+            let handle = ArcFileHandle::try_unwrap(handle).ok().unwrap();
+            drop(handle);
+            let mut written = buffer.0.lock().unwrap().clone();
+            written.sort();
+            assert_eq!(written, b"ab");
+        });
+    }
+
+    #[test]
+    fn writes_go_entirely_to_one_side_of_a_swap() {
+        model(|| {
+            let first = SharedBuffer::default();
+            let second = SharedBuffer::default();
+            let writer = Arc::new(IndirectWriter::new(OwnedFileHandle::new(
+                first.clone(),
+            )));
+
+            let other = Arc::clone(&writer);
+            let thread = spawn_model(move || {
+                (&*other).write_all(b"asdf").unwrap();
+            });
+            let old = writer.swap(OwnedFileHandle::new(second.clone()));
+            thread.join().unwrap();
+            drop(old);
+            drop(writer);
+
+            let first = first.0.lock().unwrap().clone();
+            let second = second.0.lock().unwrap().clone();
+            assert!(
+                (first == b"asdf" && second.is_empty())
+                    || (first.is_empty() && second == b"asdf")
+            );
+        });
+    }
+
+    #[test]
+    fn writes_and_flushes_see_a_panic_on_another_thread() {
+        model(|| {
+            let writer = Panicky::default();
+            let handle =
+                ArcFileHandle::new(OwnedFileHandle::new(writer.clone()));
+
+            let mut other = handle.clone();
+            let thread = spawn_model(move || {
+                assert!(poisoned(other.write(b"panic")));
+            });
+            let outcomes = [
+                poisoned((&handle).write(b"a")),
+                poisoned((&handle).flush()),
+                poisoned((&handle).write(b"b")),
+            ];
+            thread.join().unwrap();
+
+            assert_stays_poisoned(&outcomes);
+            assert!(handle.lock().is_poisoned());
+            assert!((&handle).write(b"c").is_err());
+            assert!((&handle).flush().is_err());
+
+            let written = writer.0 .0.lock().unwrap().clone();
+            let expected: Vec<u8> = [(b'a', outcomes[0]), (b'b', outcomes[2])]
+                .iter()
+                .filter(|&&(_, poisoned)| !poisoned)
+                .map(|&(byte, _)| byte)
+                .collect();
+            assert_eq!(written, expected);
+        });
+    }
+
+    #[test]
+    fn only_one_of_two_panicking_writes_runs() {
+        model(|| {
+            let writer = Panicky::default();
+            let handle =
+                ArcFileHandle::new(OwnedFileHandle::new(writer.clone()));
+
+            let mut other = handle.clone();
+            let thread = spawn_model(move || poisoned(other.write(b"panic")));
+            assert!(poisoned((&handle).write(b"panic")));
+            assert!(thread.join().unwrap());
+
+            assert_eq!(writer.1.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn overlapping_calls_see_a_panic_on_another_thread() {
+        model(|| {
+            let handle = Arc::new(Shared(FileHandle::for_concurrent_writer(
+                Panicky::default(),
+            )));
+
+            let other = Arc::clone(&handle);
+            let thread = spawn_model(move || unsafe {
+                assert!(poisoned(((*other.0).write)(other.0, b"panic")));
+            });
+            let outcomes = unsafe {
+                [
+                    poisoned(((*handle.0).write)(handle.0, b"a")),
+                    poisoned(((*handle.0).flush)(handle.0)),
+                    poisoned(((*handle.0).write)(handle.0, b"b")),
+                ]
+            };
+            thread.join().unwrap();
+
+            assert_stays_poisoned(&outcomes);
+            unsafe {
+                assert!((*handle.0).is_poisoned());
+                file_handle_destroy(handle.0);
+            }
+        });
+    }
+
+    #[test]
+    fn the_autoflush_thread_shuts_down_cleanly() {
+        let _guard = crate::lifecycle::lock_global_state();
+
+        model(|| {
+            let mut handle = OwnedFileHandle::new(Vec::new());
+            handle.enable_autoflush(Duration::from_secs(3600)).unwrap();
+
+            handle.write_all(b"asdf").unwrap();
+            drop(handle);
+
+            unsafe { crate::autoflush::shutdown() };
+        });
+    }
+}
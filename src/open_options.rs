@@ -0,0 +1,272 @@
+//! Opening handles from a path with `OpenOptions`-style flags, including
+//! reopening the path when the file is removed out from under the handle
+//! (e.g. by an external logrotate).
+
+use crate::{FileHandle, OwnedFileHandle};
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Write},
+    os::raw::c_char,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+/// Append to the file instead of truncating it.
+pub const FILE_OPEN_APPEND: u32 = 1 << 0;
+/// Sync the file to durable storage every time the handle is flushed.
+pub const FILE_OPEN_SYNC_ON_FLUSH: u32 = 1 << 1;
+/// Reopen the path and retry once when a write fails because the file was
+/// removed or went stale.
+pub const FILE_OPEN_REOPEN_ON_ERROR: u32 = 1 << 2;
+const ALL_FLAGS: u32 =
+    FILE_OPEN_APPEND | FILE_OPEN_SYNC_ON_FLUSH | FILE_OPEN_REOPEN_ON_ERROR;
+
+/// The `errno` value for "stale file handle", which NFS reports once the
+/// file has been removed on the server.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const ESTALE: Option<i32> = Some(116);
+#[cfg(windows)]
+const ESTALE: Option<i32> = None;
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+const ESTALE: Option<i32> = Some(70);
+
+/// Settings for opening a [`FileHandle`] which writes to a path.
+///
+/// ```rust,no_run
+/// # use thin_trait_objects::FileOpenOptions;
+/// # use std::io::Write;
+/// let mut handle = FileOpenOptions::new()
+///     .append(true)
+///     .reopen_on_error(true)
+///     .open("/var/log/daemon.log")?;
+///
+/// writeln!(handle, "Started")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct FileOpenOptions {
+    append: bool,
+    sync_on_flush: bool,
+    reopen_on_error: bool,
+}
+
+impl FileOpenOptions {
+    /// Options which create the file, truncating it if it already exists.
+    pub fn new() -> Self { FileOpenOptions::default() }
+
+    /// Create options from a combination of the `FILE_OPEN_*` flags, or
+    /// `None` if there are unknown flags.
+    pub fn from_flags(flags: u32) -> Option<Self> {
+        if flags & !ALL_FLAGS != 0 {
+            return None;
+        }
+
+        Some(FileOpenOptions {
+            append: flags & FILE_OPEN_APPEND != 0,
+            sync_on_flush: flags & FILE_OPEN_SYNC_ON_FLUSH != 0,
+            reopen_on_error: flags & FILE_OPEN_REOPEN_ON_ERROR != 0,
+        })
+    }
+
+    /// Append to the file instead of truncating it.
+    pub fn append(self, append: bool) -> Self {
+        FileOpenOptions { append, ..self }
+    }
+
+    /// Sync the file to durable storage every time the handle is flushed
+    /// (see [`FileHandle::for_file()`]).
+    pub fn sync_on_flush(self, sync_on_flush: bool) -> Self {
+        FileOpenOptions {
+            sync_on_flush,
+            ..self
+        }
+    }
+
+    /// When a write fails because the file was removed or went stale (`ENOENT`
+    /// or `ESTALE`), reopen the original path for appending and retry the
+    /// write once.
+    ///
+    /// Note that on most platforms writing to a file which was renamed or
+    /// deleted still succeeds, so this only helps when the error is actually
+    /// reported (e.g. on network filesystems).
+    pub fn reopen_on_error(self, reopen_on_error: bool) -> Self {
+        FileOpenOptions {
+            reopen_on_error,
+            ..self
+        }
+    }
+
+    /// Open `path` and create a handle which writes to it.
+    pub fn open<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<OwnedFileHandle, Error> {
+        unsafe { Ok(OwnedFileHandle::from_raw(self.open_raw(path.as_ref())?)) }
+    }
+
+    fn open_raw(&self, path: &Path) -> Result<*mut FileHandle, Error> {
+        let file = if self.append {
+            open_for_append(path)?
+        } else {
+            File::create(path)?
+        };
+
+        let handle = FileHandle::for_file(file, self.sync_on_flush);
+        unsafe {
+            if self.reopen_on_error {
+                FileHandle::reopen_on_error(handle, path.to_path_buf());
+            } else {
+                (*handle).cold.path = Some(PathBuf::from(path));
+            }
+        }
+
+        Ok(handle)
+    }
+}
+
+fn open_for_append(path: &Path) -> Result<File, Error> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Was this error caused by the file being removed?
+fn was_removed(e: &Error) -> bool {
+    e.kind() == ErrorKind::NotFound
+        || (ESTALE.is_some() && e.raw_os_error() == ESTALE)
+}
+
+/// Run `op`, and if it fails because the writer's file was removed, swap in
+/// a fresh writer from `reopen` and try once more.
+///
+/// The original error is returned if reopening fails.
+fn retry_after_reopen<W, T>(
+    writer: &mut W,
+    mut op: impl FnMut(&mut W) -> Result<T, Error>,
+    reopen: impl FnOnce() -> Result<W, Error>,
+) -> Result<T, Error> {
+    match op(writer) {
+        Err(e) if was_removed(&e) => match reopen() {
+            Ok(fresh) => {
+                *writer = fresh;
+                op(writer)
+            },
+            Err(_) => Err(e),
+        },
+        other => other,
+    }
+}
+
+/// Write to a file which was opened from `path`, reopening the path if the
+/// file was removed.
+pub(crate) fn write_or_reopen(
+    file: &mut File,
+    path: Option<&Path>,
+    data: &[u8],
+) -> Result<usize, Error> {
+    match path {
+        Some(path) => retry_after_reopen(
+            file,
+            |file| file.write(data),
+            || open_for_append(path),
+        ),
+        None => file.write(data),
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to a file on disk, using a
+    /// combination of [`FILE_OPEN_APPEND`], [`FILE_OPEN_SYNC_ON_FLUSH`], and
+    /// [`FILE_OPEN_REOPEN_ON_ERROR`].
+    ///
+    /// Without any flags this is the same as
+    /// [`new_file_handle_from_path()`][crate::new_file_handle_from_path].
+    /// Returns `null` if the file can't be opened or `flags` contains unknown
+    /// bits.
+    pub unsafe extern "C" fn new_file_handle_from_path_with_flags(
+        path: *const c_char,
+        flags: u32,
+    ) -> *mut FileHandle {
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(p) => p,
+            Err(_) => return ptr::null_mut(),
+        };
+
+        match FileOpenOptions::from_flags(flags) {
+            Some(options) => {
+                options.open_raw(path.as_ref()).unwrap_or(ptr::null_mut())
+            },
+            None => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn retry_once_after_the_file_is_removed() {
+        let mut attempts = 0;
+        let mut writer = 0;
+
+        let got = retry_after_reopen(
+            &mut writer,
+            |w| {
+                attempts += 1;
+                match *w {
+                    0 => Err(Error::from(ErrorKind::NotFound)),
+                    n => Ok(n),
+                }
+            },
+            || Ok(42),
+        );
+
+        assert_eq!(got.unwrap(), 42);
+        assert_eq!(attempts, 2);
+
+        // other errors (and a second failure) are passed straight through
+        let got = retry_after_reopen(
+            &mut writer,
+            |_| Err::<(), _>(Error::from(ErrorKind::NotFound)),
+            || Ok(7),
+        );
+        assert_eq!(got.unwrap_err().kind(), ErrorKind::NotFound);
+        let got = retry_after_reopen(
+            &mut writer,
+            |_| Err::<(), _>(Error::from(ErrorKind::PermissionDenied)),
+            || panic!("Shouldn't reopen"),
+        );
+        assert_eq!(got.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn open_with_flags_from_c() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("open-flags-{}.txt", std::process::id()));
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        std::fs::write(&path, "first\n").unwrap();
+
+        unsafe {
+            let flags = FILE_OPEN_APPEND | FILE_OPEN_REOPEN_ON_ERROR;
+            let handle =
+                new_file_handle_from_path_with_flags(c_path.as_ptr(), flags);
+            assert!(!handle.is_null());
+            assert!(FileHandle::downcast_raw::<File>(handle).is_some());
+
+            let ret = file_handle_write(handle, b"second\n".as_ptr().cast(), 7);
+            assert_eq!(ret, 7);
+            file_handle_destroy(handle);
+
+            let handle = new_file_handle_from_path_with_flags(
+                c_path.as_ptr(),
+                1 << 31,
+            );
+            assert!(handle.is_null());
+        }
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"first\nsecond\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
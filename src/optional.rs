@@ -0,0 +1,341 @@
+//! Operations which only some [`FileHandle`]s support, all reporting "not
+//! supported" the same way.
+
+use crate::FileHandle;
+use std::{
+    io::{Error, SeekFrom},
+    os::raw::{c_char, c_int},
+};
+
+/// The `errno` value for "operation not supported".
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const ENOTSUP: c_int = 95;
+#[cfg(windows)]
+const ENOTSUP: c_int = 50; // ERROR_NOT_SUPPORTED
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+const ENOTSUP: c_int = 45;
+
+#[cfg(not(windows))]
+const EINVAL: c_int = 22;
+#[cfg(windows)]
+const EINVAL: c_int = 87; // ERROR_INVALID_PARAMETER
+
+/// Returned when a [`FileHandle`] doesn't support an operation (see
+/// [`file_handle_supports()`]).
+pub const FILE_HANDLE_UNSUPPORTED: c_int = -ENOTSUP;
+
+/// Seek relative to the start of the file.
+pub const FILE_HANDLE_SEEK_SET: c_int = 0;
+/// Seek relative to the current position.
+pub const FILE_HANDLE_SEEK_CUR: c_int = 1;
+/// Seek relative to the end of the file.
+pub const FILE_HANDLE_SEEK_END: c_int = 2;
+
+/// Something which can be done with a [`FileHandle`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub enum Operation {
+    /// [`file_handle_write()`][crate::file_handle_write].
+    Write = 0,
+    /// [`file_handle_flush()`][crate::file_handle_flush].
+    Flush = 1,
+    /// [`file_handle_write_many()`][crate::file_handle_write_many].
+    WriteMany = 2,
+    /// [`file_handle_reserve()`].
+    Reserve = 3,
+    /// [`file_handle_seek()`].
+    Seek = 4,
+    /// [`file_handle_read()`].
+    Read = 5,
+    /// [`file_handle_sync()`].
+    Sync = 6,
+    /// Making use of the tag passed to
+    /// [`file_handle_write_tagged()`][crate::file_handle_write_tagged]
+    /// (every handle accepts tagged writes).
+    WriteTagged = 7,
+}
+
+impl Operation {
+    const ALL: [Operation; 8] = [
+        Operation::Write,
+        Operation::Flush,
+        Operation::WriteMany,
+        Operation::Reserve,
+        Operation::Seek,
+        Operation::Read,
+        Operation::Sync,
+        Operation::WriteTagged,
+    ];
+
+    /// Convert the integer representation back into an [`Operation`].
+    pub fn from_raw(raw: c_int) -> Option<Operation> {
+        Operation::ALL.iter().copied().find(|&op| op as c_int == raw)
+    }
+}
+
+/// The error used when a [`FileHandle`] doesn't support an operation.
+pub(crate) fn unsupported() -> Error { Error::from_raw_os_error(ENOTSUP) }
+
+impl FileHandle {
+    /// Does this handle's object support a particular operation?
+    pub(crate) fn supports(&self, op: Operation) -> bool {
+        match op {
+            Operation::Write | Operation::Flush | Operation::WriteMany => true,
+            Operation::Reserve => self.cold.hint_size.is_some(),
+            Operation::Seek => self.cold.seek.is_some(),
+            Operation::Read => self.cold.read.is_some(),
+            Operation::Sync => self.cold.sync.is_some(),
+            Operation::WriteTagged => self.cold.write_tagged.is_some(),
+        }
+    }
+
+    pub(crate) unsafe fn dispatch_reserve(
+        handle: *mut FileHandle,
+        bytes: u64,
+    ) -> Result<(), Error> {
+        FileHandle::check_frozen(handle)?;
+        let result = match (*handle).cold.hint_size {
+            Some(reserve) => reserve(handle, bytes),
+            None => Err(unsupported()),
+        };
+        FileHandle::record_error(handle, result)
+    }
+
+    pub(crate) unsafe fn dispatch_seek(
+        handle: *mut FileHandle,
+        pos: SeekFrom,
+    ) -> Result<u64, Error> {
+        FileHandle::check_frozen(handle)?;
+        let result = match (*handle).cold.seek {
+            Some(seek) => seek(handle, pos),
+            None => Err(unsupported()),
+        };
+        FileHandle::record_error(handle, result)
+    }
+
+    pub(crate) unsafe fn dispatch_read(
+        handle: *mut FileHandle,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        FileHandle::check_frozen(handle)?;
+        let result = match (*handle).cold.read {
+            Some(read) => read(handle, buffer),
+            None => Err(unsupported()),
+        };
+        FileHandle::record_error(handle, result)
+    }
+
+    pub(crate) unsafe fn dispatch_sync(
+        handle: *mut FileHandle,
+        data_only: bool,
+    ) -> Result<(), Error> {
+        FileHandle::check_frozen(handle)?;
+        let result = match (*handle).cold.sync {
+            Some(sync) => sync(handle, data_only),
+            None => Err(unsupported()),
+        };
+        FileHandle::record_error(handle, result)
+    }
+
+    unsafe fn record_error<T>(
+        handle: *mut FileHandle,
+        result: Result<T, Error>,
+    ) -> Result<T, Error> {
+        if let Err(ref e) = result {
+            (*handle).cold.last_error.record(e);
+        }
+
+        result
+    }
+}
+
+export! {
+    /// Check whether a [`FileHandle`] supports an [`Operation`].
+    ///
+    /// Calling an operation which isn't supported returns
+    /// [`FILE_HANDLE_UNSUPPORTED`]. Returns `false` for unknown operations.
+    pub unsafe extern "C" fn file_handle_supports(
+        handle: *mut FileHandle,
+        operation: c_int,
+    ) -> bool {
+        match Operation::from_raw(operation) {
+            Some(op) => (*handle).supports(op),
+            None => false,
+        }
+    }
+}
+
+c_unwind! {
+    /// Ask the [`FileHandle`] to allocate space for `bytes` more bytes up
+    /// front.
+    ///
+    /// Unlike [`file_handle_hint_total_size()`], this returns
+    /// [`FILE_HANDLE_UNSUPPORTED`] when the handle can't do that. Returns `0`
+    /// on success or a negative value on failure.
+    ///
+    /// [`file_handle_hint_total_size()`]: crate::file_handle_hint_total_size
+    pub unsafe extern fn file_handle_reserve(
+        handle: *mut FileHandle,
+        bytes: u64,
+    ) -> c_int {
+        match FileHandle::dispatch_reserve(handle, bytes) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+c_unwind! {
+    /// Move the [`FileHandle`]'s position to `offset` bytes relative to
+    /// `whence` (one of the `FILE_HANDLE_SEEK_*` constants), storing the new
+    /// position in `out_position` if it isn't `null`.
+    ///
+    /// Returns `0` on success or a negative value on failure.
+    pub unsafe extern fn file_handle_seek(
+        handle: *mut FileHandle,
+        offset: i64,
+        whence: c_int,
+        out_position: *mut u64,
+    ) -> c_int {
+        let pos = match whence {
+            FILE_HANDLE_SEEK_SET if offset >= 0 => {
+                SeekFrom::Start(offset as u64)
+            },
+            FILE_HANDLE_SEEK_CUR => SeekFrom::Current(offset),
+            FILE_HANDLE_SEEK_END => SeekFrom::End(offset),
+            _ => return -EINVAL,
+        };
+
+        match FileHandle::dispatch_seek(handle, pos) {
+            Ok(position) => {
+                if !out_position.is_null() {
+                    out_position.write(position);
+                }
+                0
+            },
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+c_unwind! {
+    /// Read up to `len` bytes from the [`FileHandle`] into `buffer`,
+    /// returning the number of bytes read.
+    ///
+    /// The return value is negative when reading fails.
+    pub unsafe extern fn file_handle_read(
+        handle: *mut FileHandle,
+        buffer: *mut c_char,
+        len: c_int,
+    ) -> c_int {
+        let buffer =
+            std::slice::from_raw_parts_mut(buffer as *mut u8, len as usize);
+
+        match FileHandle::dispatch_read(handle, buffer) {
+            Ok(bytes_read) => bytes_read as c_int,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+c_unwind! {
+    /// Make sure everything written to the [`FileHandle`] has reached durable
+    /// storage (e.g. using `fsync()`), which a normal flush doesn't do for
+    /// files.
+    ///
+    /// Returns `0` on success or a negative value on failure.
+    pub unsafe extern fn file_handle_sync(handle: *mut FileHandle) -> c_int {
+        match FileHandle::dispatch_sync(handle, false) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::{io::Write, ptr::null_mut as null};
+
+    #[test]
+    fn memory_handles_only_support_the_basics() {
+        unsafe {
+            let handle = new_memory_file_handle();
+            let mut buffer = [0; 4];
+
+            assert!(file_handle_supports(handle, Operation::Write as c_int));
+            assert!(file_handle_supports(handle, Operation::Reserve as c_int));
+            assert!(!file_handle_supports(handle, Operation::Seek as c_int));
+            assert!(!file_handle_supports(handle, 42));
+
+            let ret = file_handle_read(handle, buffer.as_mut_ptr(), 4);
+            assert_eq!(ret, FILE_HANDLE_UNSUPPORTED);
+            let ret = file_handle_seek(handle, 0, FILE_HANDLE_SEEK_SET, null());
+            assert_eq!(ret, FILE_HANDLE_UNSUPPORTED);
+            assert_eq!(file_handle_sync(handle), FILE_HANDLE_UNSUPPORTED);
+
+            file_handle_destroy(handle);
+
+            let handle = new_null_file_handle();
+            let ret = file_handle_reserve(handle, 10);
+            assert_eq!(ret, FILE_HANDLE_UNSUPPORTED);
+            assert_eq!(file_handle_hint_total_size(handle, 10), 0);
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn seek_and_read_files() {
+        let path = std::env::temp_dir().join("seek_and_read_files");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut buffer = [0; 5];
+        let mut position = 0;
+
+        unsafe {
+            let handle = FileHandle::for_writer(file);
+            assert!(file_handle_supports(handle, Operation::Read as c_int));
+
+            file_handle_write(handle, b"Hello, World!".as_ptr().cast(), 13);
+            let end = FILE_HANDLE_SEEK_END;
+            let ret = file_handle_seek(handle, -6, end, &mut position);
+            assert_eq!(ret, 0);
+            assert_eq!(position, 7);
+
+            let ret = file_handle_read(handle, buffer.as_mut_ptr().cast(), 5);
+            assert_eq!(ret, 5);
+            assert_eq!(file_handle_sync(handle), 0);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(&buffer, b"World");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flushing_can_sync_files() {
+        let path = std::env::temp_dir().join("flushing_can_sync_files");
+        let file = std::fs::File::create(&path).unwrap();
+        let handle = FileHandle::for_file(file, true);
+        let mut handle = unsafe { crate::OwnedFileHandle::from_raw(handle) };
+
+        let durable = crate::Capabilities::DURABLE_FLUSH;
+        assert!(handle.capabilities().contains(durable));
+        assert!(handle.supports(Operation::Sync));
+
+        handle.write_all(b"Hello, World!").unwrap();
+        handle.flush().unwrap();
+        handle.sync_data().unwrap();
+
+        drop(handle);
+        assert_eq!(std::fs::read(&path).unwrap(), b"Hello, World!");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! Creating [`FileHandle`]s from handles owned by the operating system (file
+//! descriptors on Unix, `HANDLE`s on Windows).
+
+use crate::FileHandle;
+use std::{fs::File, ptr};
+
+#[cfg(unix)]
+use std::os::raw::c_int;
+#[cfg(windows)]
+use std::os::raw::c_void;
+
+export! {
+    /// Create a new [`FileHandle`] which writes to an open file descriptor,
+    /// taking ownership of it.
+    ///
+    /// The file descriptor will be closed when the [`FileHandle`] is destroyed.
+    /// Returns `null` if `fd` is negative.
+    #[cfg(unix)]
+    pub unsafe extern "C" fn new_file_handle_from_fd(
+        fd: c_int,
+    ) -> *mut FileHandle {
+        use std::os::unix::io::FromRawFd;
+
+        if fd < 0 {
+            return ptr::null_mut();
+        }
+
+        FileHandle::for_writer(File::from_raw_fd(fd))
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to a Win32 `HANDLE` (e.g. from
+    /// `CreateFileW()` or `GetStdHandle()`), taking ownership of it.
+    ///
+    /// The `HANDLE` will be closed when the [`FileHandle`] is destroyed.
+    /// Returns `null` if `handle` is `null` or `INVALID_HANDLE_VALUE`.
+    ///
+    /// Handles opened with `FILE_FLAG_OVERLAPPED` should use
+    /// [`new_overlapped_file_handle()`][crate::new_overlapped_file_handle]
+    /// instead.
+    #[cfg(windows)]
+    pub unsafe extern "C" fn new_file_handle_from_win32_handle(
+        handle: *mut c_void,
+    ) -> *mut FileHandle {
+        use std::os::windows::io::FromRawHandle;
+
+        if handle.is_null() || handle as isize == -1 {
+            return ptr::null_mut();
+        }
+
+        FileHandle::for_writer(File::from_raw_handle(handle))
+    }
+}
+
+export! {
+    /// Get the Win32 `HANDLE` used by a [`FileHandle`] created with
+    /// [`new_file_handle_from_path()`][crate::new_file_handle_from_path] or
+    /// [`new_file_handle_from_win32_handle()`].
+    ///
+    /// The `HANDLE` is still owned by the [`FileHandle`] and must not be
+    /// closed. Returns `null` if the handle doesn't wrap a file.
+    #[cfg(windows)]
+    pub unsafe extern "C" fn file_handle_as_win32_handle(
+        handle: *mut FileHandle,
+    ) -> *mut c_void {
+        use std::os::windows::io::AsRawHandle;
+
+        match FileHandle::downcast_raw::<File>(handle) {
+            Some(f) => (*f).as_raw_handle(),
+            None => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn write_to_a_file_descriptor() {
+        use std::os::unix::io::IntoRawFd;
+
+        let path = std::env::temp_dir().join("write_to_a_file_descriptor");
+        let fd = File::create(&path).unwrap().into_raw_fd();
+
+        unsafe {
+            let handle = new_file_handle_from_fd(fd);
+            assert_eq!(file_handle_as_fd(handle), fd);
+
+            file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            file_handle_destroy(handle);
+
+            assert!(new_file_handle_from_fd(-1).is_null());
+        }
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"asdf");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn write_to_a_win32_handle() {
+        use std::os::windows::io::IntoRawHandle;
+
+        let path = std::env::temp_dir().join("write_to_a_win32_handle");
+        let raw = File::create(&path).unwrap().into_raw_handle();
+
+        unsafe {
+            let handle = new_file_handle_from_win32_handle(raw);
+            assert_eq!(file_handle_as_win32_handle(handle), raw);
+
+            file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            file_handle_destroy(handle);
+
+            assert!(new_file_handle_from_win32_handle(ptr::null_mut())
+                .is_null());
+        }
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"asdf");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
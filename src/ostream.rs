@@ -0,0 +1,406 @@
+//! Wrapping an object owned by foreign code (e.g. a C++ `std::ostream`) which
+//! is only ever accessed through callbacks.
+
+use crate::{
+    external::{DestroyCallback, FlushCallback, WriteCallback},
+    unwind::PoisonOnUnwind,
+    FileHandle, FILE_HANDLE_SEEK_CUR, FILE_HANDLE_SEEK_END,
+    FILE_HANDLE_SEEK_SET,
+};
+use std::{
+    convert::TryFrom,
+    ffi::CStr,
+    io::{Error, ErrorKind, SeekFrom, Write},
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+};
+
+c_unwind! {
+    pub(crate) type SeekCallback =
+        unsafe fn(*mut c_void, i64, c_int, *mut u64) -> c_int;
+}
+c_unwind! {
+    pub(crate) type NameCallback = unsafe fn(*mut c_void) -> *const c_char;
+}
+
+/// The callbacks used to access an opaque object passed to
+/// [`new_file_handle_from_ostream()`].
+///
+/// Each callback is given the object pointer itself. `write` should return
+/// the number of bytes written and `flush` should return `0`, with both
+/// returning a negative `errno` value on failure. Only `write` is required.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct OstreamVtable {
+    /// Write some data to the object.
+    pub write: Option<WriteCallback>,
+    /// Flush the object, or `null` if flushing does nothing.
+    pub flush: Option<FlushCallback>,
+    /// Called when the [`FileHandle`] is destroyed, or `null` if the caller
+    /// keeps ownership of the object.
+    pub destroy: Option<DestroyCallback>,
+}
+
+struct Ostream {
+    object: *mut c_void,
+    write: WriteCallback,
+    flush: Option<FlushCallback>,
+    destroy: Option<DestroyCallback>,
+}
+
+// Safety: the caller of new_file_handle_from_ostream() promises the object
+// may be used from any thread, and FileHandle never lets calls overlap.
+unsafe impl Send for Ostream {}
+unsafe impl Sync for Ostream {}
+
+fn check(ret: c_int) -> Result<usize, Error> {
+    if ret >= 0 {
+        Ok(ret as usize)
+    } else {
+        Err(Error::from_raw_os_error(-ret))
+    }
+}
+
+impl Write for Ostream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let len = buf.len().min(c_int::MAX as usize);
+        let data = buf.as_ptr().cast();
+        check(unsafe { (self.write)(self.object, data, len as c_int) })
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.flush {
+            Some(flush) => check(unsafe { flush(self.object) }).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Ostream {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            unsafe { destroy(self.object) }
+        }
+    }
+}
+
+export! {
+    /// Create a [`FileHandle`] which forwards to an opaque object (typically a
+    /// `std::ostream*`) using the callbacks in `vtable`.
+    ///
+    /// The vtable is copied, so it doesn't need to outlive this call. The
+    /// object only needs to live until the handle is destroyed, at which point
+    /// `vtable->destroy` is called if it was set.
+    ///
+    /// Returns `null` if `object` or `vtable` is `null`, or the vtable has no
+    /// `write` callback.
+    pub unsafe extern "C" fn new_file_handle_from_ostream(
+        object: *mut c_void,
+        vtable: *const OstreamVtable,
+    ) -> *mut FileHandle {
+        let vtable = match vtable.as_ref() {
+            Some(vtable) if !object.is_null() => *vtable,
+            _ => return ptr::null_mut(),
+        };
+        let write = match vtable.write {
+            Some(write) => write,
+            None => return ptr::null_mut(),
+        };
+
+        FileHandle::for_writer(Ostream {
+            object,
+            write,
+            flush: vtable.flush,
+            destroy: vtable.destroy,
+        })
+    }
+}
+
+/// A table of callbacks shared by every object of the same "class", passed
+/// to [`new_file_handle_from_vtable()`].
+///
+/// This mirrors the way C++ and object-oriented C code lay out virtual
+/// methods, so an existing per-class table can be handed over directly. Each
+/// callback is given the object pointer, returns a negative `errno` value on
+/// failure, and only `write` is required.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct CWriterVTable {
+    /// Write some data to the object, returning the number of bytes written.
+    pub write: Option<WriteCallback>,
+    /// Flush the object, or `null` if flushing does nothing.
+    pub flush: Option<FlushCallback>,
+    /// Called when the [`FileHandle`] is destroyed, or `null` if the caller
+    /// keeps ownership of the object.
+    pub destroy: Option<DestroyCallback>,
+    /// Move the object's position, with the same arguments as
+    /// [`file_handle_seek()`][crate::file_handle_seek], or `null` if the
+    /// object can't seek.
+    pub seek: Option<SeekCallback>,
+    /// Get the object's name as a null-terminated string, or `null` if
+    /// objects of this class don't have names.
+    pub name: Option<NameCallback>,
+}
+
+struct VTableObject {
+    object: *mut c_void,
+    vtable: *const CWriterVTable,
+}
+
+// Safety: the caller of new_file_handle_from_vtable() promises the object
+// may be used from any thread, and FileHandle never lets calls overlap.
+unsafe impl Send for VTableObject {}
+unsafe impl Sync for VTableObject {}
+
+impl Write for VTableObject {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let len = buf.len().min(c_int::MAX as usize);
+        let data = buf.as_ptr().cast();
+
+        unsafe {
+            let write = (*self.vtable).write.expect("Checked on creation");
+            check(write(self.object, data, len as c_int))
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match unsafe { (*self.vtable).flush } {
+            Some(flush) => check(unsafe { flush(self.object) }).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for VTableObject {
+    fn drop(&mut self) {
+        if let Some(destroy) = unsafe { (*self.vtable).destroy } {
+            unsafe { destroy(self.object) }
+        }
+    }
+}
+
+unsafe fn seek_vtable_object(
+    handle: *mut FileHandle,
+    pos: SeekFrom,
+) -> Result<u64, Error> {
+    let object = FileHandle::downcast_raw::<VTableObject>(handle)
+        .expect("Only used by vtable handles");
+    let seek = (*(*object).vtable).seek.expect("Checked on creation");
+
+    let (offset, whence) = match pos {
+        SeekFrom::Start(offset) => {
+            let offset = i64::try_from(offset)
+                .map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+            (offset, FILE_HANDLE_SEEK_SET)
+        },
+        SeekFrom::Current(offset) => (offset, FILE_HANDLE_SEEK_CUR),
+        SeekFrom::End(offset) => (offset, FILE_HANDLE_SEEK_END),
+    };
+
+    let mut position = 0;
+    let guard = PoisonOnUnwind::new(handle);
+    let ret = seek((*object).object, offset, whence, &mut position);
+    guard.disarm();
+
+    check(ret).map(|_| position)
+}
+
+export! {
+    /// Create a [`FileHandle`] for an object whose methods are in a
+    /// [`CWriterVTable`].
+    ///
+    /// Unlike [`new_file_handle_from_ostream()`], the vtable isn't copied and
+    /// must outlive the handle (typically it is a `static` shared by every
+    /// object of the same class). The object only needs to live until the
+    /// handle is destroyed, at which point `vtable->destroy` is called if it
+    /// was set. If the vtable has a `name` callback, it is called once and the
+    /// name is copied into the handle.
+    ///
+    /// Returns `null` if `object` or `vtable` is `null`, or the vtable has no
+    /// `write` callback.
+    pub unsafe extern "C" fn new_file_handle_from_vtable(
+        object: *mut c_void,
+        vtable: *const CWriterVTable,
+    ) -> *mut FileHandle {
+        let table = match vtable.as_ref() {
+            Some(table) if !object.is_null() && table.write.is_some() => table,
+            _ => return ptr::null_mut(),
+        };
+
+        let name = table.name.map(|name| name(object)).and_then(|name| {
+            if name.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(name).to_owned())
+            }
+        });
+
+        let handle = FileHandle::for_writer(VTableObject { object, vtable });
+        (*handle).cold.name = name;
+        if table.seek.is_some() {
+            (*handle).cold.seek = Some(seek_vtable_object);
+        }
+
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::os::raw::c_char;
+
+    c_unwind! {
+        unsafe fn append(
+            object: *mut c_void,
+            data: *const c_char,
+            len: c_int,
+        ) -> c_int {
+            let data = std::slice::from_raw_parts(data.cast(), len as usize);
+            (*object.cast::<Vec<u8>>()).extend_from_slice(data);
+            len
+        }
+    }
+
+    c_unwind! {
+        unsafe fn fail(_object: *mut c_void) -> c_int { -5 }
+    }
+
+    c_unwind! {
+        unsafe fn free(object: *mut c_void) {
+            drop(Box::from_raw(object.cast::<Vec<u8>>()));
+        }
+    }
+
+    #[test]
+    fn callbacks_get_the_object_itself() {
+        let mut buffer = Vec::new();
+        let vtable = OstreamVtable {
+            write: Some(append),
+            flush: Some(fail),
+            destroy: None,
+        };
+
+        unsafe {
+            let object = (&mut buffer as *mut Vec<u8>).cast();
+            let handle = new_file_handle_from_ostream(object, &vtable);
+
+            let ret = file_handle_write(handle, b"Hello".as_ptr().cast(), 5);
+            assert_eq!(ret, 5);
+            assert_eq!(file_handle_flush(handle), -5);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(buffer, b"Hello");
+    }
+
+    #[test]
+    fn owned_objects_are_destroyed() {
+        let object = Box::into_raw(Box::new(Vec::<u8>::new())).cast();
+        let mut vtable = OstreamVtable {
+            write: None,
+            flush: None,
+            destroy: Some(free),
+        };
+
+        unsafe {
+            let handle = new_file_handle_from_ostream(object, &vtable);
+            assert!(handle.is_null());
+            let handle = new_file_handle_from_ostream(ptr::null_mut(), &vtable);
+            assert!(handle.is_null());
+
+            vtable.write = Some(append);
+            let handle = new_file_handle_from_ostream(object, &vtable);
+            file_handle_write(handle, b"x".as_ptr().cast(), 1);
+            file_handle_destroy(handle);
+        }
+    }
+
+    c_unwind! {
+        unsafe fn seek_to_end(
+            object: *mut c_void,
+            offset: i64,
+            whence: c_int,
+            out_position: *mut u64,
+        ) -> c_int {
+            if whence != FILE_HANDLE_SEEK_END {
+                return -22;
+            }
+
+            let len = (*object.cast::<Vec<u8>>()).len() as i64;
+            *out_position = (len + offset) as u64;
+            0
+        }
+    }
+
+    c_unwind! {
+        unsafe fn class_name(_object: *mut c_void) -> *const c_char {
+            b"VecWriter\0".as_ptr().cast()
+        }
+    }
+
+    static VEC_WRITER: CWriterVTable = CWriterVTable {
+        write: Some(append),
+        flush: None,
+        destroy: Some(free),
+        seek: Some(seek_to_end),
+        name: Some(class_name),
+    };
+
+    #[test]
+    fn objects_share_a_static_vtable() {
+        unsafe {
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let object = Box::into_raw(Box::new(Vec::<u8>::new()));
+                    new_file_handle_from_vtable(object.cast(), &VEC_WRITER)
+                })
+                .collect();
+
+            for &handle in &handles {
+                let name = CStr::from_ptr(file_handle_name(handle));
+                assert_eq!(name.to_str().unwrap(), "VecWriter");
+                file_handle_write(handle, b"Hello".as_ptr().cast(), 5);
+
+                let mut position = 0;
+                let end = FILE_HANDLE_SEEK_END;
+                assert_eq!(file_handle_seek(handle, -1, end, &mut position), 0);
+                assert_eq!(position, 4);
+                let set = FILE_HANDLE_SEEK_SET;
+                let ret = file_handle_seek(handle, 0, set, &mut position);
+                assert_eq!(ret, -22);
+
+                file_handle_destroy(handle);
+            }
+        }
+    }
+
+    #[test]
+    fn vtables_without_optional_methods() {
+        let vtable = CWriterVTable {
+            write: Some(append),
+            flush: None,
+            destroy: None,
+            seek: None,
+            name: None,
+        };
+        let mut buffer = Vec::new();
+
+        unsafe {
+            let object = (&mut buffer as *mut Vec<u8>).cast();
+            let empty = CWriterVTable { write: None, ..vtable };
+            assert!(new_file_handle_from_vtable(object, &empty).is_null());
+
+            let handle = new_file_handle_from_vtable(object, &vtable);
+            assert!(file_handle_name(handle).is_null());
+            let seek = crate::Operation::Seek as c_int;
+            assert!(!file_handle_supports(handle, seek));
+            file_handle_write(handle, b"x".as_ptr().cast(), 1);
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(buffer, b"x");
+    }
+}
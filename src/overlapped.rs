@@ -0,0 +1,236 @@
+//! A Windows file opened for overlapped (asynchronous) I/O.
+
+use crate::FileHandle;
+use std::{
+    ffi::{CStr, OsStr},
+    io::{Error, Write},
+    os::{
+        raw::{c_char, c_void},
+        windows::ffi::OsStrExt,
+    },
+    path::Path,
+    ptr,
+};
+
+type Handle = *mut c_void;
+type Bool = i32;
+
+const GENERIC_WRITE: u32 = 0x4000_0000;
+const FILE_SHARE_READ: u32 = 0x0000_0001;
+const CREATE_ALWAYS: u32 = 2;
+const FILE_ATTRIBUTE_NORMAL: u32 = 0x0000_0080;
+const FILE_FLAG_OVERLAPPED: u32 = 0x4000_0000;
+const ERROR_IO_PENDING: i32 = 997;
+
+#[repr(C)]
+struct Overlapped {
+    internal: usize,
+    internal_high: usize,
+    offset: u32,
+    offset_high: u32,
+    event: Handle,
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn CreateFileW(
+        file_name: *const u16,
+        desired_access: u32,
+        share_mode: u32,
+        security_attributes: *mut c_void,
+        creation_disposition: u32,
+        flags_and_attributes: u32,
+        template_file: Handle,
+    ) -> Handle;
+    fn CreateEventW(
+        event_attributes: *mut c_void,
+        manual_reset: Bool,
+        initial_state: Bool,
+        name: *const u16,
+    ) -> Handle;
+    fn WriteFile(
+        file: Handle,
+        buffer: *const c_void,
+        bytes_to_write: u32,
+        bytes_written: *mut u32,
+        overlapped: *mut Overlapped,
+    ) -> Bool;
+    fn GetOverlappedResult(
+        file: Handle,
+        overlapped: *mut Overlapped,
+        bytes_transferred: *mut u32,
+        wait: Bool,
+    ) -> Bool;
+    fn FlushFileBuffers(file: Handle) -> Bool;
+    fn CloseHandle(handle: Handle) -> Bool;
+}
+
+fn invalid(handle: Handle) -> bool {
+    handle.is_null() || handle as isize == -1
+}
+
+/// A file opened with `FILE_FLAG_OVERLAPPED`.
+///
+/// Overlapped handles don't track a file position and may complete writes
+/// asynchronously, so they can't be used like a normal [`std::fs::File`].
+/// Each write is issued at the end of the previous one and then waited
+/// on, giving the same behaviour as a normal file while letting the host
+/// share the handle with IO completion ports.
+#[derive(Debug)]
+pub struct OverlappedFile {
+    file: Handle,
+    event: Handle,
+    offset: u64,
+}
+
+// Safety: the handles are only used through `&mut self`, and `&self`
+// doesn't give access to anything
+unsafe impl Send for OverlappedFile {}
+unsafe impl Sync for OverlappedFile {}
+
+impl OverlappedFile {
+    /// Create (or truncate) a file for overlapped writing.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path: Vec<u16> = OsStr::new(path.as_ref())
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+
+        unsafe {
+            let file = CreateFileW(
+                path.as_ptr(),
+                GENERIC_WRITE,
+                FILE_SHARE_READ,
+                ptr::null_mut(),
+                CREATE_ALWAYS,
+                FILE_ATTRIBUTE_NORMAL | FILE_FLAG_OVERLAPPED,
+                ptr::null_mut(),
+            );
+            if invalid(file) {
+                return Err(Error::last_os_error());
+            }
+
+            let event = CreateEventW(ptr::null_mut(), 1, 0, ptr::null());
+            if event.is_null() {
+                let e = Error::last_os_error();
+                CloseHandle(file);
+                return Err(e);
+            }
+
+            Ok(OverlappedFile {
+                file,
+                event,
+                offset: 0,
+            })
+        }
+    }
+}
+
+impl Write for OverlappedFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let len = buf.len().min(u32::MAX as usize) as u32;
+        let mut overlapped = Overlapped {
+            internal: 0,
+            internal_high: 0,
+            offset: self.offset as u32,
+            offset_high: (self.offset >> 32) as u32,
+            event: self.event,
+        };
+        let mut bytes_written = 0;
+
+        unsafe {
+            let ok = WriteFile(
+                self.file,
+                buf.as_ptr().cast(),
+                len,
+                ptr::null_mut(),
+                &mut overlapped,
+            );
+
+            if ok == 0 {
+                let e = Error::last_os_error();
+                if e.raw_os_error() != Some(ERROR_IO_PENDING) {
+                    return Err(e);
+                }
+            }
+
+            // wait for the write to complete
+            let ok = GetOverlappedResult(
+                self.file,
+                &mut overlapped,
+                &mut bytes_written,
+                1,
+            );
+            if ok == 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        self.offset += u64::from(bytes_written);
+        Ok(bytes_written as usize)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if unsafe { FlushFileBuffers(self.file) } == 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for OverlappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.event);
+            CloseHandle(self.file);
+        }
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to a file opened with
+    /// `FILE_FLAG_OVERLAPPED`, waiting for each write to complete.
+    ///
+    /// Returns `null` if the path isn't valid UTF-8 or the file couldn't be
+    /// created.
+    pub unsafe extern "C" fn new_overlapped_file_handle(
+        path: *const c_char,
+    ) -> *mut FileHandle {
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(p) => p,
+            Err(_) => return ptr::null_mut(),
+        };
+
+        match OverlappedFile::create(path) {
+            Ok(f) => FileHandle::for_writer(f),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+
+    #[test]
+    fn writes_are_appended() {
+        let path = std::env::temp_dir().join("overlapped_writes_are_appended");
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let handle = new_overlapped_file_handle(c_path.as_ptr());
+            assert!(!handle.is_null());
+
+            file_handle_write(handle, b"Hello".as_ptr().cast(), 5);
+            file_handle_write(handle, b", World!".as_ptr().cast(), 8);
+            assert_eq!(file_handle_flush(handle), 0);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"Hello, World!");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
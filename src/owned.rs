@@ -1,5 +1,17 @@
-use crate::{file_handle::Repr, FileHandle};
-use std::{any::TypeId, io::Write, ptr::NonNull};
+use crate::{
+    file_handle::{Repr, SharedWriter},
+    history, Capabilities, Clock, FileHandle, IndirectWriter, IntoChunks,
+    LatencyStats, Operation, OwnershipEvent, OwnershipRecord, ThreadStats,
+    WatchdogAction, ZeroWritePolicy,
+};
+use std::{
+    any::TypeId,
+    fmt::{self, Display, Formatter},
+    io::Write,
+    ptr::{self, NonNull},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 /// An owned wrapper around a [`*mut FileHandle`][FileHandle] for use in Rust
 /// code.
@@ -18,7 +30,10 @@ use std::{any::TypeId, io::Write, ptr::NonNull};
 /// // The "Null Pointer Optimisation" also holds
 /// assert_eq!(size_of::<Option<OwnedFileHandle>>(), size_of::<OwnedFileHandle>());
 /// ```
-#[derive(Debug)]
+///
+/// The [`Debug`][std::fmt::Debug] output describes the handle (e.g. its type,
+/// state, and the handles it wraps), the same as
+/// [`file_handle_debug_dump()`][crate::file_handle_debug_dump].
 #[repr(transparent)]
 pub struct OwnedFileHandle(NonNull<FileHandle>);
 
@@ -28,7 +43,7 @@ impl OwnedFileHandle {
         unsafe {
             let handle = FileHandle::for_writer(writer);
             assert!(!handle.is_null());
-            OwnedFileHandle::from_raw(handle)
+            OwnedFileHandle(NonNull::new_unchecked(handle))
         }
     }
 
@@ -44,6 +59,7 @@ impl OwnedFileHandle {
     /// `FileHandle`.
     pub unsafe fn from_raw(handle: *mut FileHandle) -> Self {
         debug_assert!(!handle.is_null());
+        history::record(handle, OwnershipEvent::FromRaw);
         OwnedFileHandle(NonNull::new_unchecked(handle))
     }
 
@@ -52,14 +68,236 @@ impl OwnedFileHandle {
     pub fn into_raw(self) -> *mut FileHandle {
         let ptr = self.0.as_ptr();
         std::mem::forget(self);
+        history::record(ptr, OwnershipEvent::IntoRaw);
         ptr
     }
 
+    /// Get the underlying pointer without giving up ownership.
+    pub(crate) fn as_ptr(&self) -> *mut FileHandle { self.0.as_ptr() }
+
+    /// Set the [`ZeroWritePolicy`] used when the writer accepts zero bytes.
+    pub fn set_zero_write_policy(&mut self, policy: ZeroWritePolicy) {
+        unsafe { (*self.0.as_ptr()).set_zero_write_policy(policy) }
+    }
+
+    /// Builder-style version of [`OwnedFileHandle::set_zero_write_policy()`].
+    pub fn with_zero_write_policy(mut self, policy: ZeroWritePolicy) -> Self {
+        self.set_zero_write_policy(policy);
+        self
+    }
+
+    /// Has this handle been poisoned by a panic, either in its own object or
+    /// in a handle it wraps?
+    pub fn is_poisoned(&self) -> bool {
+        unsafe { (*self.0.as_ptr()).is_poisoned() }
+    }
+
+    /// The handle's name, if it has one (see
+    /// [`file_handle_name()`][crate::file_handle_name]).
+    pub fn name(&self) -> Option<&str> {
+        unsafe {
+            let name = crate::file_handle_name(self.0.as_ptr());
+
+            if name.is_null() {
+                None
+            } else {
+                std::ffi::CStr::from_ptr(name).to_str().ok()
+            }
+        }
+    }
+
+    /// What this handle's object can do.
+    pub fn capabilities(&self) -> Capabilities {
+        unsafe { (*self.0.as_ptr()).capabilities() }
+    }
+
+    /// Does this handle support a particular [`Operation`]?
+    pub fn supports(&self, op: Operation) -> bool {
+        unsafe { (*self.0.as_ptr()).supports(op) }
+    }
+
+    /// Start recording how much each thread writes to this handle.
+    pub fn enable_thread_stats(&mut self) {
+        unsafe {
+            (*self.0.as_ptr()).extensions_or_default().thread_stats.enable()
+        }
+    }
+
+    /// Iterate over how much each thread has written to this handle since
+    /// [`OwnedFileHandle::enable_thread_stats()`] was called.
+    pub fn thread_stats(&self) -> impl Iterator<Item = ThreadStats> {
+        let stats = unsafe {
+            match (*self.0.as_ptr()).extensions() {
+                Some(ext) => ext.thread_stats.snapshot(),
+                None => Vec::new(),
+            }
+        };
+
+        stats.into_iter()
+    }
+
+    /// Start recording how long each write and flush takes, using the global
+    /// clock.
+    pub fn enable_latency_stats(&mut self) {
+        self.enable_latency_stats_with_clock(crate::global_clock());
+    }
+
+    /// Start recording how long each write and flush takes, according to
+    /// `clock`.
+    pub fn enable_latency_stats_with_clock(&mut self, clock: Arc<dyn Clock>) {
+        unsafe {
+            (*self.0.as_ptr()).extensions_or_default().latency.enable(clock)
+        }
+    }
+
+    /// How long this handle's writes have taken, if
+    /// [`OwnedFileHandle::enable_latency_stats()`] was called.
+    pub fn write_latency(&self) -> Option<LatencyStats> {
+        unsafe { (*self.0.as_ptr()).extensions()?.latency.writes() }
+    }
+
+    /// How long this handle's flushes have taken, if
+    /// [`OwnedFileHandle::enable_latency_stats()`] was called.
+    pub fn flush_latency(&self) -> Option<LatencyStats> {
+        unsafe { (*self.0.as_ptr()).extensions()?.latency.flushes() }
+    }
+
+    /// Change how much this handle reports to the sink installed with
+    /// [`set_trace_sink()`][crate::set_trace_sink].
+    #[cfg(feature = "tracing")]
+    pub fn set_tracing_level(&mut self, level: crate::TraceLevel) {
+        unsafe {
+            crate::trace_spans::set_tracing_level(self.as_ptr(), level);
+        }
+    }
+
+    /// Limit the total number of bytes which may be written to this handle
+    /// from now on (see [`file_handle_set_quota()`]).
+    ///
+    /// [`file_handle_set_quota()`]: crate::file_handle_set_quota
+    pub fn set_quota(&mut self, max_total_bytes: u64) {
+        unsafe {
+            crate::file_handle_set_quota(self.0.as_ptr(), max_total_bytes)
+        }
+    }
+
+    /// How many bytes have been written since a quota was set.
+    pub fn quota_used(&self) -> u64 {
+        unsafe { crate::file_handle_quota_used(self.0.as_ptr()) }
+    }
+
+    /// React whenever a write or flush on this handle takes longer than
+    /// `max_duration` (see [`file_handle_set_watchdog()`]).
+    ///
+    /// [`file_handle_set_watchdog()`]: crate::file_handle_set_watchdog
+    pub fn set_watchdog(
+        &mut self,
+        max_duration: Duration,
+        action: WatchdogAction,
+    ) -> std::io::Result<()> {
+        unsafe { crate::watchdog::watch(self.0.as_ptr(), max_duration, action) }
+    }
+
+    /// Stop watching this handle's operations.
+    pub fn clear_watchdog(&mut self) {
+        unsafe { crate::watchdog::unregister(self.0.as_ptr()) }
+    }
+
+    /// Flush this handle from a background thread every `interval` (see
+    /// [`file_handle_enable_autoflush()`]).
+    ///
+    /// [`file_handle_enable_autoflush()`]: crate::file_handle_enable_autoflush
+    pub fn enable_autoflush(&self, interval: Duration) -> std::io::Result<()> {
+        let millis = interval.as_millis().min(u32::MAX.into()).max(1) as u32;
+        unsafe { crate::autoflush::register(self.0.as_ptr(), millis, false) }
+    }
+
+    /// Flush this handle from a background thread once it hasn't been used
+    /// for `idle` (see [`file_handle_enable_idle_flush()`]).
+    ///
+    /// [`file_handle_enable_idle_flush()`]: crate::file_handle_enable_idle_flush
+    pub fn enable_idle_flush(&self, idle: Duration) -> std::io::Result<()> {
+        let millis = idle.as_millis().min(u32::MAX.into()).max(1) as u32;
+        unsafe { crate::autoflush::register(self.0.as_ptr(), millis, true) }
+    }
+
+    /// Stop flushing this handle in the background.
+    pub fn disable_autoflush(&self) {
+        unsafe { crate::file_handle_disable_autoflush(self.0.as_ptr()) }
+    }
+
+    /// Flush this handle and reject any further I/O until
+    /// [`OwnedFileHandle::thaw()`] is called (see [`file_handle_freeze()`]).
+    ///
+    /// [`file_handle_freeze()`]: crate::file_handle_freeze
+    pub fn freeze(&mut self) -> std::io::Result<()> {
+        unsafe { crate::freeze::freeze(self.0.as_ptr()) }
+    }
+
+    /// Let a frozen handle be used again.
+    pub fn thaw(&mut self) {
+        unsafe { crate::file_handle_thaw(self.0.as_ptr()) }
+    }
+
+    /// Is this handle frozen?
+    pub fn is_frozen(&self) -> bool {
+        unsafe { crate::file_handle_is_frozen(self.0.as_ptr()) }
+    }
+
+    /// Wait until every write so far has been forwarded by any background
+    /// threads behind this handle, without flushing it (see
+    /// [`file_handle_barrier()`][crate::file_handle_barrier]).
+    pub fn barrier(&mut self) -> std::io::Result<()> {
+        unsafe { crate::barrier::submit(self.0.as_ptr()).wait() }
+    }
+
+    /// Flush (but not destroy) this handle when the process exits.
+    pub fn flush_on_exit(&self) {
+        unsafe { crate::exit_flush::register(self.0.as_ptr()) }
+    }
+
+    /// Let the underlying object know that roughly `bytes` more bytes are about
+    /// to be written. Objects which don't support size hints will ignore it.
+    pub fn hint_total_size(&mut self, bytes: u64) -> std::io::Result<()> {
+        unsafe {
+            let ptr = self.0.as_ptr();
+
+            match (*ptr).cold.hint_size {
+                Some(hint_size) => hint_size(ptr, bytes),
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Make sure everything written so far has reached durable storage,
+    /// including the file's metadata (see [`File::sync_all()`]).
+    ///
+    /// Fails with [`FILE_HANDLE_UNSUPPORTED`] if the object isn't a file.
+    ///
+    /// [`File::sync_all()`]: std::fs::File::sync_all
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub fn sync_all(&mut self) -> std::io::Result<()> {
+        unsafe { FileHandle::dispatch_sync(self.0.as_ptr(), false) }
+    }
+
+    /// Like [`OwnedFileHandle::sync_all()`], except metadata which isn't
+    /// needed to read the data back (e.g. the modification time) may not be
+    /// synced.
+    pub fn sync_data(&mut self) -> std::io::Result<()> {
+        unsafe { FileHandle::dispatch_sync(self.0.as_ptr(), true) }
+    }
+
+    /// Every recorded change in this handle's ownership, oldest first (see
+    /// [`Config::with_ownership_history()`][crate::Config]).
+    pub fn history(&self) -> Vec<OwnershipRecord> {
+        history::records(self.0.as_ptr())
+    }
+
     /// Check if the object pointed to by a [`OwnedFileHandle`] has type `W`.
     pub fn is<W: 'static>(&self) -> bool {
         unsafe {
             let ptr = self.0.as_ptr();
-            (*ptr).type_id == TypeId::of::<W>()
+            (*ptr).cold.type_id == TypeId::of::<W>()
         }
     }
 
@@ -91,17 +329,114 @@ impl OwnedFileHandle {
         }
     }
 
+    /// Replace the wrapped `W` with the result of calling `f`, reusing the
+    /// existing allocation so any pointers to the handle (e.g. ones held by C)
+    /// stay valid.
+    ///
+    /// ```rust
+    /// # use thin_trait_objects::OwnedFileHandle;
+    /// let mut handle = OwnedFileHandle::new(b"old".to_vec());
+    /// handle.replace_writer(|_old: Vec<u8>| b"new".to_vec()).unwrap();
+    ///
+    /// assert_eq!(handle.downcast_ref::<Vec<u8>>().unwrap(), b"new");
+    /// assert!(handle.replace_writer(|s: String| s).is_err());
+    /// ```
+    ///
+    /// This waits for a flush which timed out (see
+    /// [`OwnedFileHandle::flush_timeout()`]) to finish, and the autoflush
+    /// thread won't touch the handle until `f` returns. The flushes done at
+    /// exit or after a crash can't be held off, so don't replace the writer
+    /// of a handle registered with [`OwnedFileHandle::flush_on_exit()`] while
+    /// the process may be exiting.
+    ///
+    /// If this is a memory handle, the memory budget is charged for the
+    /// replacement buffer afterwards, even if it doesn't fit.
+    ///
+    /// # Panics
+    ///
+    /// The writer has been moved out while `f` runs, so there is nothing
+    /// left to drop if it panics. The process is aborted instead.
+    pub fn replace_writer<W: 'static>(
+        &mut self,
+        f: impl FnOnce(W) -> W,
+    ) -> Result<(), WrongType> {
+        let slot: *mut W = match self.downcast_mut::<W>() {
+            Some(writer) => writer,
+            None => return Err(WrongType),
+        };
+
+        /// Aborts if it is dropped while `f` is unwinding.
+        struct AbortOnUnwind;
+
+        impl Drop for AbortOnUnwind {
+            fn drop(&mut self) { std::process::abort() }
+        }
+
+        unsafe {
+            // keep the autoflush thread and timed-out flushes away from the
+            // writer while it's moved out
+            let ext = (*self.0.as_ptr()).extensions();
+            let _paused = ext.map(crate::flush_timeout::pause);
+            let _autoflush = ext.and_then(|ext| ext.autoflush.guard());
+
+            let guard = AbortOnUnwind;
+            let replacement = f(ptr::read(slot));
+            std::mem::forget(guard);
+            ptr::write(slot, replacement);
+            crate::budget::recharge(self.0.as_ptr());
+        }
+
+        Ok(())
+    }
+
+    /// If this handle wraps an [`IndirectWriter`], replace its destination and
+    /// return the previous one.
+    ///
+    /// The `replacement` is handed back as an error if this isn't an indirect
+    /// handle.
+    pub fn swap(
+        &self,
+        replacement: OwnedFileHandle,
+    ) -> Result<OwnedFileHandle, OwnedFileHandle> {
+        match self.downcast_ref::<IndirectWriter>() {
+            Some(indirect) => Ok(indirect.swap(replacement)),
+            None => Err(replacement),
+        }
+    }
+
+    /// If this handle was created using [`FileHandle::for_shared_writer()`],
+    /// get another reference to the writer it shares.
+    pub fn shared_writer<W: 'static>(&self) -> Option<Arc<Mutex<W>>> {
+        self.downcast_ref::<SharedWriter<W>>()
+            .map(|shared| Arc::clone(&shared.0))
+    }
+
+    /// Take everything written to a memory handle (e.g. one wrapping a
+    /// [`ChunkedBuffer`][crate::ChunkedBuffer]) so far, one write at a time.
+    ///
+    /// Handles wrapping a plain `Vec<u8>` give back all their data as a
+    /// single chunk, and any other handle gives back nothing.
+    pub fn drain_chunks(&mut self) -> IntoChunks {
+        unsafe { crate::chunks::drain(self.0.as_ptr()) }
+    }
+
     /// Attempt to downcast the [`OwnedFileHandle`] to a concrete type and
     /// extract it.
     pub fn downcast<W: 'static>(self) -> Result<W, Self> {
         if self.is::<W>() {
             unsafe {
-                let ptr = self.into_raw();
+                let ptr = self.0.as_ptr();
+                std::mem::forget(self);
+                FileHandle::unregister(ptr);
                 // Safety: We just did a type check
                 let repr: *mut Repr<W> = ptr.cast();
+                let layout = (*ptr).cold.layout;
+                let dealloc = (*ptr).cold.dealloc;
 
-                let unboxed = Box::from_raw(repr);
-                Ok(unboxed.writer)
+                let writer = ptr::read(&(*repr).writer);
+                ptr::drop_in_place(&mut (*repr).base);
+                FileHandle::deallocate(ptr, dealloc, layout);
+                Ok(writer)
             }
         } else {
             Err(self)
@@ -109,31 +444,114 @@ impl OwnedFileHandle {
     }
 }
 
-impl Write for OwnedFileHandle {
+/// The error returned by [`OwnedFileHandle::replace_writer()`] when the
+/// handle doesn't wrap the requested type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WrongType;
+
+impl std::error::Error for WrongType {}
+
+impl Display for WrongType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "The handle doesn't wrap a writer of that type")
+    }
+}
+
+type BoxedWriter = Box<dyn Write + Send + Sync + 'static>;
+
+/// The parts of a boxed trait object, kept as a raw pointer so the handle
+/// calls straight into its vtable instead of going through `Box`'s `Write`
+/// impl.
+struct DynWriter(*mut (dyn Write + Send + Sync + 'static));
+
+// Safety: we only ever hold on to pointers from a Box<dyn Write + Send + Sync>
+unsafe impl Send for DynWriter {}
+unsafe impl Sync for DynWriter {}
+
+impl DynWriter {
+    fn into_box(self) -> BoxedWriter {
+        let ptr = self.0;
+        std::mem::forget(self);
+        unsafe { Box::from_raw(ptr) }
+    }
+}
+
+impl Write for DynWriter {
+    #[inline]
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        unsafe {
-            let ptr = self.0.as_ptr();
-            let write = (*ptr).write;
-            (write)(ptr, buf)
+        unsafe { (*self.0).write(buf) }
+    }
+
+    #[inline]
+    fn write_vectored(
+        &mut self,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::io::Result<usize> {
+        unsafe { (*self.0).write_vectored(bufs) }
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> { unsafe { (*self.0).flush() } }
+}
+
+impl Drop for DynWriter {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.0)) }
+    }
+}
+
+impl OwnedFileHandle {
+    /// Adopt a boxed trait object (e.g. one created by another crate)
+    /// without adding another layer of indirection.
+    ///
+    /// The trait object's data and vtable pointers are stored directly in
+    /// the handle, so each call costs the same as one made on a handle from
+    /// [`OwnedFileHandle::new()`].
+    pub fn from_dyn(writer: BoxedWriter) -> Self {
+        OwnedFileHandle::new(DynWriter(Box::into_raw(writer)))
+    }
+
+    /// Convert this handle into a normal Rust trait object.
+    ///
+    /// If the handle was originally created from a
+    /// `Box<dyn Write + Send + Sync>`, that box is returned as-is instead of
+    /// adding another layer of indirection.
+    pub fn into_boxed_writer(self) -> BoxedWriter {
+        let handle = match self.downcast::<DynWriter>() {
+            Ok(original) => return original.into_box(),
+            Err(handle) => handle,
+        };
+
+        match handle.downcast::<BoxedWriter>() {
+            Ok(original) => original,
+            Err(handle) => Box::new(handle),
         }
     }
+}
+
+impl fmt::Debug for OwnedFileHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        unsafe { crate::debug::describe(self.0.as_ptr(), f) }
+    }
+}
+
+impl From<BoxedWriter> for OwnedFileHandle {
+    fn from(writer: BoxedWriter) -> Self { OwnedFileHandle::from_dyn(writer) }
+}
+
+impl Write for OwnedFileHandle {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        unsafe { FileHandle::dispatch_write(self.0.as_ptr(), buf) }
+    }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        unsafe {
-            let ptr = self.0.as_ptr();
-            let flush = (*ptr).flush;
-            (flush)(ptr)
-        }
+        unsafe { FileHandle::dispatch_flush(self.0.as_ptr()) }
     }
 }
 
 impl Drop for OwnedFileHandle {
     fn drop(&mut self) {
-        unsafe {
-            let ptr = self.0.as_ptr();
-            let destroy = (*ptr).destroy;
-            (destroy)(ptr)
-        }
+        unsafe { FileHandle::dispatch_destroy(self.0.as_ptr()) }
     }
 }
 
@@ -147,8 +565,8 @@ mod tests {
     use super::*;
     use crate::ffi::tests::SharedBuffer;
     use std::sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
     };
 
     #[test]
@@ -169,6 +587,70 @@ mod tests {
         assert!(Arc::ptr_eq(&got.0, &buffer.0));
     }
 
+    #[test]
+    fn replace_writer_keeps_the_same_allocation() {
+        let mut handle = OwnedFileHandle::new(SharedBuffer::default());
+        handle.write_all(b"before").unwrap();
+        let raw = handle.0.as_ptr();
+        let replacement = SharedBuffer::default();
+
+        let old = Arc::new(Mutex::new(None));
+        let got_old = Arc::clone(&old);
+        handle
+            .replace_writer(|previous: SharedBuffer| {
+                *got_old.lock().unwrap() = Some(previous);
+                replacement.clone()
+            })
+            .unwrap();
+        handle.write_all(b"after").unwrap();
+
+        assert_eq!(handle.0.as_ptr(), raw);
+        let old = old.lock().unwrap().take().unwrap();
+        assert_eq!(old.0.lock().unwrap().as_slice(), b"before");
+        assert_eq!(replacement.0.lock().unwrap().as_slice(), b"after");
+    }
+
+    #[test]
+    fn replace_writer_checks_the_type() {
+        let mut handle = OwnedFileHandle::new(Vec::new());
+        let got = handle.replace_writer(|w: SharedBuffer| w);
+        assert_eq!(got, Err(WrongType));
+    }
+
+    #[test]
+    fn autoflush_is_held_off_while_replacing() {
+        #[derive(Clone, Default)]
+        struct Flushes(Arc<AtomicUsize>);
+
+        impl Write for Flushes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        let flushes = Flushes::default();
+        let mut handle = OwnedFileHandle::new(flushes.clone());
+        handle.enable_autoflush(Duration::from_millis(5)).unwrap();
+        handle.write_all(b"dirty").unwrap();
+
+        let mut during = None;
+        handle
+            .replace_writer(|previous: Flushes| {
+                let before = previous.0.load(Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(50));
+                during = Some(previous.0.load(Ordering::SeqCst) - before);
+                previous
+            })
+            .unwrap();
+
+        assert_eq!(during, Some(0));
+    }
+
     #[test]
     fn downcast_owned_doesnt_destroy_twice() {
         let handle = OwnedFileHandle::new(std::io::sink());
@@ -181,6 +663,127 @@ mod tests {
         assert!(got.is_ok());
     }
 
+    #[test]
+    fn memory_goes_back_to_the_allocator_recorded_in_the_header() {
+        static FREED: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "C" fn counting_dealloc(
+            ptr: *mut u8,
+            size: usize,
+            align: usize,
+        ) {
+            FREED.fetch_add(1, Ordering::SeqCst);
+            crate::file_handle::dealloc_global(ptr, size, align);
+        }
+
+        let counted = || {
+            let handle = OwnedFileHandle::new(Vec::<u8>::new());
+            unsafe {
+                (*handle.0.as_ptr()).cold.dealloc = counting_dealloc;
+            }
+            handle
+        };
+
+        drop(counted());
+        assert_eq!(FREED.load(Ordering::SeqCst), 1);
+
+        let leaked = counted();
+        unsafe {
+            (*leaked.0.as_ptr()).set_flag(FileHandle::LEAK_ON_DESTROY);
+        }
+        drop(leaked);
+        assert_eq!(FREED.load(Ordering::SeqCst), 2);
+
+        let writer = counted().downcast::<Vec<u8>>().unwrap();
+        assert!(writer.is_empty());
+        assert_eq!(FREED.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn share_a_writer_with_the_handle() {
+        let shared = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let mut handle = unsafe {
+            OwnedFileHandle::from_raw(FileHandle::for_shared_writer(
+                Arc::clone(&shared),
+            ))
+        };
+
+        handle.write_all(b"Hello, World!").unwrap();
+
+        // we are writing to the original object, not a copy
+        assert_eq!(&*shared.lock().unwrap(), b"Hello, World!");
+
+        let got = handle.shared_writer::<Vec<u8>>().unwrap();
+        assert!(Arc::ptr_eq(&got, &shared));
+        assert!(handle.shared_writer::<std::io::Sink>().is_none());
+    }
+
+    #[test]
+    fn shared_writers_report_poisoned_locks() {
+        let shared = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let mut handle = unsafe {
+            OwnedFileHandle::from_raw(FileHandle::for_shared_writer(
+                Arc::clone(&shared),
+            ))
+        };
+
+        let other = Arc::clone(&shared);
+        let _ = std::thread::spawn(move || {
+            let _guard = other.lock().unwrap();
+            panic!("Poisoning the lock");
+        })
+        .join();
+
+        assert!(handle.write(b"asdf").is_err());
+    }
+
+    #[test]
+    fn swap_an_indirect_handles_destination() {
+        let buffer = SharedBuffer::default();
+        let mut handle = OwnedFileHandle::new(IndirectWriter::new(
+            OwnedFileHandle::new(std::io::sink()),
+        ));
+
+        let previous = handle
+            .swap(OwnedFileHandle::new(buffer.clone()))
+            .unwrap();
+        assert!(previous.is::<std::io::Sink>());
+        handle.write_all(b"asdf").unwrap();
+        assert_eq!(&*buffer.0.lock().unwrap(), b"asdf");
+
+        let plain = OwnedFileHandle::new(std::io::sink());
+        assert!(plain.swap(previous).is_err());
+    }
+
+    #[test]
+    fn convert_to_and_from_boxed_writers() {
+        let buffer = SharedBuffer::default();
+        let boxed: Box<dyn Write + Send + Sync> = Box::new(buffer.clone());
+
+        let original = &*boxed as *const _ as *const u8;
+
+        let mut handle = OwnedFileHandle::from_dyn(boxed);
+        handle.write_all(b"Hello, ").unwrap();
+
+        // we get the original box back instead of wrapping the handle
+        let mut boxed = handle.into_boxed_writer();
+        assert_eq!(&*boxed as *const _ as *const u8, original);
+        boxed.write_all(b"World!").unwrap();
+        assert_eq!(&*buffer.0.lock().unwrap(), b"Hello, World!");
+
+        // so do boxes which were wrapped directly
+        let handle = OwnedFileHandle::new(boxed);
+        let mut boxed = handle.into_boxed_writer();
+        assert_eq!(&*boxed as *const _ as *const u8, original);
+        boxed.write_all(b"!").unwrap();
+
+        // other handles just get boxed
+        let handle = OwnedFileHandle::new(buffer.clone());
+        let mut boxed = handle.into_boxed_writer();
+        boxed.write_all(b"!").unwrap();
+        assert_eq!(&*buffer.0.lock().unwrap(), b"Hello, World!!!");
+    }
+
     #[derive(Debug)]
     struct Panicking {
         dropped: Arc<AtomicBool>,
@@ -229,4 +832,108 @@ mod tests {
             drop(other_arc);
         }
     }
+
+    /// A writer which panics once it has accepted a certain number of writes.
+    struct PanicAfter(usize);
+
+    impl Write for PanicAfter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.0 == 0 {
+                panic!("Out of writes");
+            }
+
+            self.0 -= 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn poisoning_is_visible_to_other_threads() {
+        let handle = Arc::new(Mutex::new(OwnedFileHandle::new(PanicAfter(10))));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = Arc::clone(&handle);
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        let _ = handle.lock().unwrap().write(b"asdf");
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut handle = handle.lock().unwrap();
+        let err = handle.write(b"asdf").unwrap_err();
+        assert!(crate::file_handle::is_poison_error(&err));
+        let err = handle.flush().unwrap_err();
+        assert!(crate::file_handle::is_poison_error(&err));
+    }
+
+    #[test]
+    fn poisoned_flag_is_set_on_panic() {
+        let mut handle = OwnedFileHandle::new(PanicAfter(0));
+        assert!(!handle.is_poisoned());
+
+        assert!(handle.write(b"asdf").is_err());
+
+        assert!(handle.is_poisoned());
+    }
+
+    #[test]
+    #[cfg(not(feature = "forbid-panics"))]
+    fn panics_pass_through_unguarded_handles() {
+        let mut handle = unsafe {
+            OwnedFileHandle::from_raw(FileHandle::for_writer_unguarded(
+                PanicAfter(1),
+            ))
+        };
+        handle.write_all(b"asdf").unwrap();
+
+        let got = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            handle.write(b"asdf")
+        }));
+
+        assert!(got.is_err());
+        assert!(!handle.is_poisoned());
+    }
+
+    #[test]
+    fn poison_propagates_through_wrappers() {
+        let inner = OwnedFileHandle::new(PanicAfter(1));
+        let middle = OwnedFileHandle::new(IndirectWriter::new(inner));
+        let mut outer =
+            OwnedFileHandle::new(std::io::BufWriter::new(middle));
+
+        // the first write goes all the way through
+        outer.write_all(b"asdf").unwrap();
+        outer.flush().unwrap();
+
+        // the second gets buffered, then panics when flushed
+        outer.write_all(b"asdf").unwrap();
+        assert!(outer.flush().is_err());
+        assert!(outer.is_poisoned());
+
+        // so the outer handle stops buffering immediately
+        let err = outer.write(b"asdf").unwrap_err();
+        assert!(crate::file_handle::is_poison_error(&err));
+
+        // every layer is poisoned, but only the innermost one leaks
+        let bufwriter = outer
+            .downcast_ref::<std::io::BufWriter<OwnedFileHandle>>()
+            .unwrap();
+        let middle = bufwriter.get_ref();
+        assert!(middle.is_poisoned());
+        unsafe {
+            let outer = &*outer.0.as_ptr();
+            assert!(!outer.has_flag(FileHandle::LEAK_ON_DESTROY));
+            let middle = &*middle.0.as_ptr();
+            assert!(!middle.has_flag(FileHandle::LEAK_ON_DESTROY));
+        }
+    }
 }
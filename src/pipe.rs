@@ -0,0 +1,472 @@
+//! Sinks which write to a FIFO (Unix) or a named pipe (Windows), so output
+//! can be sent to another process without the caller managing descriptors.
+
+use crate::FileHandle;
+use std::{
+    ffi::CStr,
+    io::{Error, ErrorKind},
+    os::raw::{c_char, c_int},
+    ptr,
+};
+
+/// The OS error code for "the reading end of the pipe was closed".
+#[cfg(windows)]
+const EPIPE: c_int = 109; // ERROR_BROKEN_PIPE
+#[cfg(not(windows))]
+const EPIPE: c_int = 32;
+
+/// Returned when writing to a pipe or FIFO which nothing is reading from
+/// any more.
+pub const FILE_HANDLE_BROKEN_PIPE: c_int = -EPIPE;
+
+/// Wait for something to start reading from the pipe (the default).
+pub const PIPE_OPEN_WAIT: c_int = 0;
+/// Fail instead of waiting when nothing is reading from the pipe.
+pub const PIPE_OPEN_NONBLOCK: c_int = 1;
+
+/// Report every flavour of "the other end went away" the same way, so C
+/// callers only need to check for [`FILE_HANDLE_BROKEN_PIPE`].
+fn normalize(e: Error) -> Error {
+    if e.kind() == ErrorKind::BrokenPipe {
+        Error::from_raw_os_error(EPIPE)
+    } else {
+        e
+    }
+}
+
+/// Parse a policy passed to one of the constructors, returning whether to
+/// wait for a reader.
+fn should_wait(policy: c_int) -> Option<bool> {
+    match policy {
+        PIPE_OPEN_WAIT => Some(true),
+        PIPE_OPEN_NONBLOCK => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(unix)]
+mod os {
+    use super::normalize;
+    use std::{
+        ffi::CString,
+        fs::{File, OpenOptions},
+        io::{Error, ErrorKind, Write},
+        os::{
+            raw::{c_char, c_int},
+            unix::{
+                ffi::OsStrExt,
+                fs::{FileTypeExt, OpenOptionsExt},
+                io::AsRawFd,
+            },
+        },
+        path::Path,
+    };
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(super) const O_NONBLOCK: c_int = 0o4000;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(super) const O_NONBLOCK: c_int = 4;
+    const F_GETFL: c_int = 3;
+    const F_SETFL: c_int = 4;
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly"
+    ))]
+    type Mode = u16;
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly"
+    )))]
+    type Mode = u32;
+
+    extern "C" {
+        fn mkfifo(path: *const c_char, mode: Mode) -> c_int;
+        fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+
+    /// The write end of a FIFO.
+    pub(super) struct Fifo(File);
+
+    impl Fifo {
+        pub(super) fn open(
+            path: &Path,
+            create: bool,
+            wait: bool,
+        ) -> Result<Fifo, Error> {
+            if create {
+                let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+                if unsafe { mkfifo(c_path.as_ptr(), 0o666) } != 0 {
+                    let e = Error::last_os_error();
+                    if e.kind() != ErrorKind::AlreadyExists {
+                        return Err(e);
+                    }
+                }
+            }
+
+            let mut options = OpenOptions::new();
+            options.write(true);
+            if !wait {
+                // fails with ENXIO instead of blocking when there's no reader
+                options.custom_flags(O_NONBLOCK);
+            }
+            let file = options.open(path)?;
+
+            if !file.metadata()?.file_type().is_fifo() {
+                return Err(Error::new(ErrorKind::InvalidInput, "Not a FIFO"));
+            }
+            if !wait {
+                // only the open should be non-blocking, not every write
+                unsafe {
+                    let fd = file.as_raw_fd();
+                    let flags = fcntl(fd, F_GETFL);
+                    if flags < 0 || fcntl(fd, F_SETFL, flags & !O_NONBLOCK) < 0
+                    {
+                        return Err(Error::last_os_error());
+                    }
+                }
+            }
+
+            Ok(Fifo(file))
+        }
+    }
+
+    impl Write for Fifo {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.0.write(buf).map_err(normalize)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+}
+
+#[cfg(windows)]
+mod os {
+    use super::{normalize, EPIPE};
+    use std::{
+        ffi::OsStr,
+        io::{Error, Write},
+        os::{raw::c_void, windows::ffi::OsStrExt},
+        ptr,
+    };
+
+    type Handle = *mut c_void;
+    type Bool = i32;
+
+    const PIPE_ACCESS_OUTBOUND: u32 = 0x0000_0002;
+    const PIPE_TYPE_BYTE: u32 = 0x0000_0000;
+    const PIPE_WAIT: u32 = 0x0000_0000;
+    const PIPE_NOWAIT: u32 = 0x0000_0001;
+    const BUFFER_SIZE: u32 = 64 * 1024;
+    const ERROR_PIPE_CONNECTED: i32 = 535;
+    const ERROR_PIPE_LISTENING: i32 = 536;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateNamedPipeW(
+            name: *const u16,
+            open_mode: u32,
+            pipe_mode: u32,
+            max_instances: u32,
+            out_buffer_size: u32,
+            in_buffer_size: u32,
+            default_timeout: u32,
+            security_attributes: *mut c_void,
+        ) -> Handle;
+        fn ConnectNamedPipe(pipe: Handle, overlapped: *mut c_void) -> Bool;
+        fn DisconnectNamedPipe(pipe: Handle) -> Bool;
+        fn WriteFile(
+            file: Handle,
+            buffer: *const c_void,
+            bytes_to_write: u32,
+            bytes_written: *mut u32,
+            overlapped: *mut c_void,
+        ) -> Bool;
+        fn FlushFileBuffers(file: Handle) -> Bool;
+        fn CloseHandle(handle: Handle) -> Bool;
+    }
+
+    /// The server end of a named pipe, which waits for a client to connect
+    /// before the first write and again after a client disconnects.
+    pub(super) struct NamedPipe {
+        pipe: Handle,
+        connected: bool,
+    }
+
+    // Safety: the pipe is only used through `&mut self`
+    unsafe impl Send for NamedPipe {}
+    unsafe impl Sync for NamedPipe {}
+
+    impl NamedPipe {
+        pub(super) fn create(name: &str, wait: bool) -> Result<Self, Error> {
+            let name: Vec<u16> =
+                OsStr::new(name).encode_wide().chain(Some(0)).collect();
+            let wait = if wait { PIPE_WAIT } else { PIPE_NOWAIT };
+            let mode = PIPE_TYPE_BYTE | wait;
+
+            let pipe = unsafe {
+                CreateNamedPipeW(
+                    name.as_ptr(),
+                    PIPE_ACCESS_OUTBOUND,
+                    mode,
+                    1,
+                    BUFFER_SIZE,
+                    BUFFER_SIZE,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+            if pipe.is_null() || pipe as isize == -1 {
+                return Err(Error::last_os_error());
+            }
+
+            Ok(NamedPipe {
+                pipe,
+                connected: false,
+            })
+        }
+
+        fn connect(&mut self) -> Result<(), Error> {
+            if self.connected {
+                return Ok(());
+            }
+
+            if unsafe { ConnectNamedPipe(self.pipe, ptr::null_mut()) } == 0 {
+                let e = Error::last_os_error();
+                match e.raw_os_error() {
+                    // a client connected before we started waiting
+                    Some(ERROR_PIPE_CONNECTED) => {},
+                    // non-blocking, and there's no client yet
+                    Some(ERROR_PIPE_LISTENING) => {
+                        return Err(Error::from_raw_os_error(EPIPE));
+                    },
+                    _ => return Err(e),
+                }
+            }
+
+            self.connected = true;
+            Ok(())
+        }
+
+        /// Drop the current client so the next write waits for a new one.
+        fn disconnect(&mut self) {
+            if self.connected {
+                unsafe { DisconnectNamedPipe(self.pipe) };
+                self.connected = false;
+            }
+        }
+    }
+
+    impl Write for NamedPipe {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.connect()?;
+
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let mut bytes_written = 0;
+            let ok = unsafe {
+                WriteFile(
+                    self.pipe,
+                    buf.as_ptr().cast(),
+                    len,
+                    &mut bytes_written,
+                    ptr::null_mut(),
+                )
+            };
+
+            if ok == 0 {
+                let e = normalize(Error::last_os_error());
+                if e.raw_os_error() == Some(EPIPE) {
+                    self.disconnect();
+                }
+                return Err(e);
+            }
+
+            Ok(bytes_written as usize)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            // waits until the client has read everything
+            if self.connected && unsafe { FlushFileBuffers(self.pipe) } == 0 {
+                let e = normalize(Error::last_os_error());
+                if e.raw_os_error() == Some(EPIPE) {
+                    self.disconnect();
+                }
+                return Err(e);
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Drop for NamedPipe {
+        fn drop(&mut self) {
+            self.disconnect();
+            unsafe { CloseHandle(self.pipe) };
+        }
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to the FIFO (named pipe) at
+    /// `path`, creating it first if `create` is set and it doesn't exist yet.
+    ///
+    /// This waits until something opens the FIFO for reading (see
+    /// [`new_fifo_file_handle_with_policy()`]). Returns `null` if the FIFO
+    /// couldn't be opened or `path` isn't a FIFO.
+    ///
+    /// Writes fail with [`FILE_HANDLE_BROKEN_PIPE`] once the reader has gone
+    /// away, as long as the process ignores `SIGPIPE` (like Rust programs do
+    /// by default). Otherwise the process is killed by the signal.
+    #[cfg(unix)]
+    pub unsafe extern "C" fn new_fifo_file_handle(
+        path: *const c_char,
+        create: bool,
+    ) -> *mut FileHandle {
+        new_fifo_file_handle_with_policy(path, create, PIPE_OPEN_WAIT)
+    }
+}
+
+export! {
+    /// Like [`new_fifo_file_handle()`], except with [`PIPE_OPEN_NONBLOCK`]
+    /// `null` is returned straight away if nothing has the FIFO open for
+    /// reading instead of waiting for a reader. Once opened, writes block
+    /// while the FIFO is full either way.
+    #[cfg(unix)]
+    pub unsafe extern "C" fn new_fifo_file_handle_with_policy(
+        path: *const c_char,
+        create: bool,
+        policy: c_int,
+    ) -> *mut FileHandle {
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(p) => p,
+            Err(_) => return ptr::null_mut(),
+        };
+        let wait = match should_wait(policy) {
+            Some(wait) => wait,
+            None => return ptr::null_mut(),
+        };
+
+        match os::Fifo::open(path.as_ref(), create, wait) {
+            Ok(fifo) => {
+                let handle = FileHandle::for_writer(fifo);
+                (*handle).cold.path = Some(path.into());
+                handle
+            },
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to the server end of a new
+    /// named pipe, where `name` is the pipe's full name (e.g.
+    /// `\\.\pipe\my-app-logs`).
+    ///
+    /// The first write waits for a client to connect (see
+    /// [`new_named_pipe_file_handle_with_policy()`]). When the client
+    /// disconnects, the failed write returns [`FILE_HANDLE_BROKEN_PIPE`] and
+    /// the next one waits for a new client. Returns `null` if the pipe
+    /// couldn't be created (e.g. because the name is already in use).
+    #[cfg(windows)]
+    pub unsafe extern "C" fn new_named_pipe_file_handle(
+        name: *const c_char,
+    ) -> *mut FileHandle {
+        new_named_pipe_file_handle_with_policy(name, PIPE_OPEN_WAIT)
+    }
+}
+
+export! {
+    /// Like [`new_named_pipe_file_handle()`], except with
+    /// [`PIPE_OPEN_NONBLOCK`] writes fail with [`FILE_HANDLE_BROKEN_PIPE`]
+    /// instead of waiting while no client is connected, and the pipe uses
+    /// `PIPE_NOWAIT` so a write into a full pipe may only write part of the
+    /// data.
+    #[cfg(windows)]
+    pub unsafe extern "C" fn new_named_pipe_file_handle_with_policy(
+        name: *const c_char,
+        policy: c_int,
+    ) -> *mut FileHandle {
+        let name = match CStr::from_ptr(name).to_str() {
+            Ok(name) => name,
+            Err(_) => return ptr::null_mut(),
+        };
+        let wait = match should_wait(policy) {
+            Some(wait) => wait,
+            None => return ptr::null_mut(),
+        };
+
+        match os::NamedPipe::create(name, wait) {
+            Ok(pipe) => FileHandle::for_writer(pipe),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+
+    #[test]
+    fn broken_pipes_are_reported_the_same_way() {
+        let e = normalize(Error::new(ErrorKind::BrokenPipe, "Gone"));
+        assert_eq!(e.raw_os_error(), Some(-FILE_HANDLE_BROKEN_PIPE));
+        let e = normalize(Error::from(ErrorKind::NotFound));
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+
+        unsafe {
+            let kind = thin_error_kind_from_errno(FILE_HANDLE_BROKEN_PIPE);
+            assert_eq!(kind, crate::ThinErrorKind::BrokenPipe);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn write_to_a_fifo_until_the_reader_leaves() {
+        use std::{
+            ffi::CString,
+            fs::OpenOptions,
+            io::Read,
+            os::unix::fs::OpenOptionsExt,
+        };
+
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("fifo-{}", std::process::id()));
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let handle = new_fifo_file_handle_with_policy(
+                c_path.as_ptr(),
+                true,
+                PIPE_OPEN_NONBLOCK,
+            );
+            assert!(handle.is_null(), "Nothing is reading yet");
+
+            let mut reader = OpenOptions::new()
+                .read(true)
+                .custom_flags(os::O_NONBLOCK)
+                .open(&path)
+                .unwrap();
+            let handle = new_fifo_file_handle(c_path.as_ptr(), false);
+            assert!(!handle.is_null());
+
+            let ret = file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            assert_eq!(ret, 4);
+            let mut buffer = [0; 4];
+            reader.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"asdf");
+
+            drop(reader);
+            let ret = file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            assert_eq!(ret, FILE_HANDLE_BROKEN_PIPE);
+            file_handle_destroy(handle);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+//! A typed Rust wrapper around the external handle builder, so the object
+//! placed in the handle can't be forgotten or initialized with the wrong
+//! type.
+
+use crate::{
+    external::{destroy_uninitialized, FlushCallback, WriteCallback},
+    ExternalFileHandleBuilder, FileHandle, OwnedFileHandle,
+};
+use std::{
+    convert::TryFrom,
+    ffi::CStr,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
+    os::raw::{c_char, c_int, c_void},
+};
+
+c_unwind! {
+    /// Writes data to the `T` inside an external handle (see
+    /// [`file_handle_builder_set_write()`][set_write]).
+    ///
+    /// [set_write]: crate::file_handle_builder_set_write
+    pub type PlaceWriteCallback<T> =
+        unsafe fn(*mut T, *const c_char, c_int) -> c_int;
+}
+c_unwind! {
+    /// Flushes the `T` inside an external handle (see
+    /// [`file_handle_builder_set_flush()`][set_flush]).
+    ///
+    /// [set_flush]: crate::file_handle_builder_set_flush
+    pub type PlaceFlushCallback<T> = unsafe fn(*mut T) -> c_int;
+}
+
+/// The callbacks for an external handle whose object is a `T`.
+///
+/// The object is dropped in place when the handle is destroyed, so there is
+/// no `destroy` callback.
+pub struct ExternalCallbacks<T> {
+    /// Write data to the object (required).
+    pub write: PlaceWriteCallback<T>,
+    /// Flush the object, if it needs flushing.
+    pub flush: Option<PlaceFlushCallback<T>>,
+}
+
+/// Builds an external [`FileHandle`] holding a `T`, laid out exactly like
+/// one created with [`file_handle_builder_new()`].
+///
+/// Allocating the handle gives back a [`Place`], and the handle can only be
+/// used once a `T` has been written to it. The callbacks are given a pointer
+/// to that `T`.
+///
+/// [`file_handle_builder_new()`]: crate::file_handle_builder_new
+pub struct ExternalHandleBuilder<T> {
+    builder: *mut ExternalFileHandleBuilder,
+    _object: PhantomData<fn(T)>,
+}
+
+impl<T: Send + Sync + 'static> ExternalHandleBuilder<T> {
+    /// Start building a handle which will call `callbacks` with its `T`.
+    pub fn new(callbacks: ExternalCallbacks<T>) -> Self {
+        // Note: a layout which doesn't fit in a c_int becomes -1, which
+        // makes allocate() fail
+        let size = c_int::try_from(mem::size_of::<T>()).unwrap_or(-1);
+        let alignment = c_int::try_from(mem::align_of::<T>()).unwrap_or(-1);
+
+        unsafe {
+            // Safety: *mut T and *mut c_void are passed the same way
+            let write = mem::transmute::<PlaceWriteCallback<T>, WriteCallback>(
+                callbacks.write,
+            );
+            let flush = callbacks.flush.map(|flush| {
+                mem::transmute::<PlaceFlushCallback<T>, FlushCallback>(flush)
+            });
+
+            let builder = crate::file_handle_builder_new();
+            crate::file_handle_builder_set_layout(builder, size, alignment);
+            crate::file_handle_builder_set_write(builder, Some(write));
+            crate::file_handle_builder_set_flush(builder, flush);
+            crate::file_handle_builder_set_destroy(
+                builder,
+                Some(drop_place::<T>),
+            );
+
+            ExternalHandleBuilder {
+                builder,
+                _object: PhantomData,
+            }
+        }
+    }
+
+    /// Give the handle a human-readable name (see
+    /// [`file_handle_builder_set_name()`][set_name]).
+    ///
+    /// [set_name]: crate::file_handle_builder_set_name
+    pub fn with_name(self, name: &CStr) -> Self {
+        unsafe {
+            crate::file_handle_builder_set_name(self.builder, name.as_ptr())
+        };
+        self
+    }
+
+    /// Check that the callbacks stick to their contracts (see
+    /// [`file_handle_builder_set_validation()`][validation]).
+    ///
+    /// [validation]: crate::file_handle_builder_set_validation
+    pub fn with_validation(self, enabled: bool) -> Self {
+        unsafe {
+            crate::file_handle_builder_set_validation(self.builder, enabled)
+        };
+        self
+    }
+
+    /// Allocate the handle, returning the [`Place`] its `T` must be written
+    /// to, or `None` if `T` is too big for the C API.
+    pub fn allocate(self) -> Option<Place<T>> {
+        let this = ManuallyDrop::new(self);
+        let allocated =
+            unsafe { crate::file_handle_builder_finish(this.builder) };
+
+        if allocated.file_handle.is_null() {
+            None
+        } else {
+            Some(Place {
+                handle: allocated.file_handle,
+                place: allocated.place.cast(),
+            })
+        }
+    }
+}
+
+impl<T> Drop for ExternalHandleBuilder<T> {
+    fn drop(&mut self) {
+        unsafe { crate::file_handle_builder_free(self.builder) };
+    }
+}
+
+/// An allocated external handle whose `T` hasn't been written yet.
+///
+/// The handle can only be used once [`Place::write()`] has initialized it.
+/// Dropping the [`Place`] instead frees the handle without touching the
+/// object.
+///
+/// ```rust,compile_fail
+/// # use thin_trait_objects::Place;
+/// fn initialize(place: Place<u32>) { place.write("not a u32"); }
+/// ```
+#[must_use = "The handle is freed unless a value is written to its place"]
+pub struct Place<T> {
+    handle: *mut FileHandle,
+    place: *mut T,
+}
+
+impl<T> Place<T> {
+    /// Move `value` into the handle, which is now ready to use.
+    pub fn write(self, value: T) -> OwnedFileHandle {
+        let this = ManuallyDrop::new(self);
+
+        unsafe {
+            this.place.write(value);
+            OwnedFileHandle::from_raw(this.handle)
+        }
+    }
+}
+
+impl<T> Drop for Place<T> {
+    fn drop(&mut self) { unsafe { destroy_uninitialized(self.handle) } }
+}
+
+c_unwind! {
+    unsafe fn drop_place<T>(object: *mut c_void) {
+        std::ptr::drop_in_place(object.cast::<T>());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    c_unwind! {
+        unsafe fn write_buffer(
+            buffer: *mut SharedBuffer,
+            data: *const c_char,
+            len: c_int,
+        ) -> c_int {
+            let data = std::slice::from_raw_parts(data.cast(), len as usize);
+            (*buffer).0.lock().unwrap().extend_from_slice(data);
+            len
+        }
+    }
+
+    /// Counts how often it is dropped.
+    struct Drops(Arc<AtomicUsize>);
+
+    impl Drop for Drops {
+        fn drop(&mut self) { self.0.fetch_add(1, Ordering::SeqCst); }
+    }
+
+    c_unwind! {
+        unsafe fn ignore(
+            _: *mut Drops,
+            _: *const c_char,
+            len: c_int,
+        ) -> c_int {
+            len
+        }
+    }
+
+    #[test]
+    fn written_places_become_external_handles() {
+        let buffer = SharedBuffer::default();
+        let callbacks = ExternalCallbacks {
+            write: write_buffer,
+            flush: None,
+        };
+        let name = CStr::from_bytes_with_nul(b"shared\0").unwrap();
+
+        let place = ExternalHandleBuilder::new(callbacks)
+            .with_name(name)
+            .with_validation(true)
+            .allocate()
+            .unwrap();
+        let mut handle = place.write(buffer.clone());
+        handle.write_all(b"Hello, World!").unwrap();
+
+        unsafe {
+            let external = file_handle_as_external(handle.as_ptr());
+            assert!(!external.is_null());
+            let got = file_handle_external_name(handle.as_ptr());
+            assert_eq!(CStr::from_ptr(got), name);
+        }
+        drop(handle);
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"Hello, World!");
+        // the handle's copy was dropped in place
+        assert_eq!(Arc::strong_count(&buffer.0), 1);
+    }
+
+    #[test]
+    fn unwritten_places_are_freed_without_dropping() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let callbacks = || ExternalCallbacks {
+            write: ignore,
+            flush: None,
+        };
+
+        let place = ExternalHandleBuilder::<Drops>::new(callbacks())
+            .allocate()
+            .unwrap();
+        drop(place);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        let place = ExternalHandleBuilder::new(callbacks()).allocate().unwrap();
+        drop(place.write(Drops(Arc::clone(&drops))));
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+}
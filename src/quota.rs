@@ -0,0 +1,244 @@
+//! Capping the total number of bytes which may be written to a
+//! [`FileHandle`], e.g. to stop an untrusted plugin from filling the disk.
+
+use crate::{
+    errors::{crate_status_code, crate_status_error, CRATE_ERROR_QUOTA_EXCEEDED},
+    FileHandle,
+};
+use std::{
+    io::{Error, ErrorKind},
+    os::raw::{c_int, c_void},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Returned by writes once a handle's quota (see [`file_handle_set_quota()`])
+/// has been used up.
+///
+/// This is [`CRATE_ERROR_QUOTA_EXCEEDED`] in the [`ErrorDomain::Crate`]
+/// domain, so it can't be confused with an `EDQUOT` from the file system.
+///
+/// [`CRATE_ERROR_QUOTA_EXCEEDED`]: crate::CRATE_ERROR_QUOTA_EXCEEDED
+/// [`ErrorDomain::Crate`]: crate::ErrorDomain::Crate
+pub const QUOTA_EXCEEDED: c_int = crate_status_code(CRATE_ERROR_QUOTA_EXCEEDED);
+
+c_unwind! {
+    /// Called the first time a write is rejected because the quota has been
+    /// used up.
+    pub type QuotaCallback = unsafe fn(*mut FileHandle, *mut c_void);
+}
+
+/// How many bytes a handle may still write.
+#[derive(Debug)]
+pub(crate) struct Quota {
+    limit: AtomicU64,
+    used: AtomicU64,
+    notified: AtomicBool,
+    on_exceeded: Mutex<Option<(QuotaCallback, usize)>>,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Quota {
+            limit: AtomicU64::new(u64::MAX),
+            used: AtomicU64::new(0),
+            notified: AtomicBool::new(false),
+            on_exceeded: Mutex::new(None),
+        }
+    }
+}
+
+impl Quota {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.limit.load(Ordering::Relaxed) != u64::MAX
+    }
+
+    pub(crate) fn set_limit(&self, max_total_bytes: u64) {
+        self.limit.store(max_total_bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn used(&self) -> u64 { self.used.load(Ordering::Relaxed) }
+
+    /// Reserve space for up to `len` bytes, returning how many bytes may
+    /// actually be written.
+    ///
+    /// Reserving up front (instead of checking after the write) means
+    /// concurrent writers can never go over the quota.
+    pub(crate) unsafe fn reserve(
+        &self,
+        handle: *mut FileHandle,
+        len: usize,
+    ) -> Result<usize, Error> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut granted = 0;
+
+        let _ = self.used.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |used| {
+                granted = limit.saturating_sub(used).min(len as u64);
+                Some(used + granted)
+            },
+        );
+
+        if granted == 0 && len > 0 {
+            self.exceeded(handle);
+            Err(crate_status_error(
+                ErrorKind::Other,
+                CRATE_ERROR_QUOTA_EXCEEDED,
+            ))
+        } else {
+            Ok(granted as usize)
+        }
+    }
+
+    /// Give back any reserved space which didn't actually get written.
+    pub(crate) fn settle(&self, reserved: usize, written: Option<usize>) {
+        let unused = reserved - written.unwrap_or(0).min(reserved);
+
+        if unused > 0 {
+            self.used.fetch_sub(unused as u64, Ordering::AcqRel);
+        }
+    }
+
+    unsafe fn exceeded(&self, handle: *mut FileHandle) {
+        if self.notified.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let callback =
+            *self.on_exceeded.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some((callback, user_data)) = callback {
+            callback(handle, user_data as *mut c_void);
+        }
+    }
+}
+
+export! {
+    /// Limit the total number of bytes which may be written to this
+    /// [`FileHandle`] from now on.
+    ///
+    /// A write which would go over the quota only writes what's left, and every
+    /// write after that fails with [`QUOTA_EXCEEDED`]. Setting a new quota
+    /// changes the limit without forgetting what has already been written.
+    pub unsafe extern "C" fn file_handle_set_quota(
+        handle: *mut FileHandle,
+        max_total_bytes: u64,
+    ) {
+        (*handle)
+            .extensions_or_default()
+            .quota
+            .set_limit(max_total_bytes);
+    }
+}
+
+export! {
+    /// Get how many bytes have been written since a quota was set.
+    pub unsafe extern "C" fn file_handle_quota_used(
+        handle: *mut FileHandle,
+    ) -> u64 {
+        match (*handle).extensions() {
+            Some(ext) => ext.quota.used(),
+            None => 0,
+        }
+    }
+}
+
+export! {
+    /// Set a callback which is invoked (at most once) the first time a write is
+    /// rejected because the quota was used up, passing in the `handle` and
+    /// `user_data`. Passing `null` removes the callback.
+    pub unsafe extern "C" fn file_handle_set_quota_callback(
+        handle: *mut FileHandle,
+        on_exceeded: Option<QuotaCallback>,
+        user_data: *mut c_void,
+    ) {
+        let quota = &(*handle).extensions_or_default().quota;
+        let mut slot =
+            quota.on_exceeded.lock().unwrap_or_else(|e| e.into_inner());
+        *slot = on_exceeded.map(|callback| (callback, user_data as usize));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn writes_stop_at_the_quota() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let handle = FileHandle::for_writer(buffer.clone());
+            file_handle_set_quota(handle, 6);
+
+            let ret = file_handle_write(handle, b"Hello".as_ptr().cast(), 5);
+            assert_eq!(ret, 5);
+            let ret = file_handle_write(handle, b", World".as_ptr().cast(), 7);
+            assert_eq!(ret, 1);
+            let ret = file_handle_write(handle, b"!".as_ptr().cast(), 1);
+            assert_eq!(ret, QUOTA_EXCEEDED);
+            assert_eq!(file_handle_quota_used(handle), 6);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"Hello,");
+    }
+
+    #[test]
+    fn bulk_writes_respect_the_quota() {
+        let buffers = [FfiSlice::new(b"asdf"); 3];
+        let mut results = [0; 3];
+
+        unsafe {
+            let handle = new_null_file_handle();
+            file_handle_set_quota(handle, 6);
+
+            let ret = file_handle_write_many(
+                handle,
+                buffers.as_ptr(),
+                buffers.len(),
+                results.as_mut_ptr(),
+            );
+
+            assert_eq!(ret, QUOTA_EXCEEDED);
+            assert_eq!(results, [4, 2, QUOTA_EXCEEDED]);
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn the_callback_is_only_invoked_once() {
+        c_unwind! {
+            unsafe fn on_exceeded(_: *mut FileHandle, calls: *mut c_void) {
+                *calls.cast::<u32>() += 1;
+            }
+        }
+
+        let mut calls = 0_u32;
+
+        unsafe {
+            let handle = new_null_file_handle();
+            file_handle_set_quota(handle, 0);
+            file_handle_set_quota_callback(
+                handle,
+                Some(on_exceeded),
+                (&mut calls as *mut u32).cast(),
+            );
+
+            for _ in 0..3 {
+                let ret = file_handle_write(handle, b"x".as_ptr().cast(), 1);
+                assert_eq!(ret, QUOTA_EXCEEDED);
+            }
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,242 @@
+//! A FFI-safe version of [`dyn std::io::Read`][Read], the counterpart to
+//! [`FileHandle`][crate::FileHandle].
+
+use std::{
+    alloc::Layout,
+    any::TypeId,
+    ffi::CStr,
+    fs::File,
+    io::{Cursor, Error, Read},
+    os::raw::{c_char, c_int},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A thin trait object for something which can be read from.
+///
+/// Like a [`FileHandle`][crate::FileHandle], a [`ReadHandle`] is just the
+/// header at the start of a larger object and must always be kept behind a
+/// pointer. Calls on the same handle must not overlap.
+#[repr(C)]
+pub struct ReadHandle {
+    pub(crate) layout: Layout,
+    pub(crate) type_id: TypeId,
+    /// Set when the object panicked, after which it may not be used again.
+    pub(crate) poisoned: AtomicBool,
+    pub(crate) destroy: unsafe fn(*mut ReadHandle),
+    pub(crate) read: ReadFn,
+}
+
+pub(crate) type ReadFn =
+    unsafe fn(*mut ReadHandle, &mut [u8]) -> Result<usize, Error>;
+
+impl ReadHandle {
+    /// Create a new [`ReadHandle`] that wraps a Rust [`std::io::Read`]er.
+    pub fn for_reader<R>(reader: R) -> *mut ReadHandle
+    where
+        R: Read + Send + Sync + 'static,
+    {
+        let repr = ReadRepr {
+            base: ReadHandle {
+                layout: Layout::new::<ReadRepr<R>>(),
+                type_id: TypeId::of::<R>(),
+                poisoned: AtomicBool::new(false),
+                destroy: destroy::<R>,
+                read: read::<R>,
+            },
+            reader,
+        };
+
+        // Safety: The ReadHandle is the first field of a #[repr(C)] struct
+        Box::into_raw(Box::new(repr)) as *mut ReadHandle
+    }
+
+    /// Read some data from the object.
+    pub(crate) unsafe fn dispatch_read(
+        handle: *mut ReadHandle,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        let read = (*handle).read;
+        read(handle, buffer)
+    }
+
+    /// Destroy the object and free the [`ReadHandle`].
+    pub(crate) unsafe fn dispatch_destroy(handle: *mut ReadHandle) {
+        let destroy = (*handle).destroy;
+        destroy(handle);
+    }
+}
+
+#[repr(C)]
+struct ReadRepr<R> {
+    // Safety: The ReadHandle must be the first field so we can cast between
+    // *mut ReadRepr<R> and *mut ReadHandle
+    base: ReadHandle,
+    reader: R,
+}
+
+unsafe fn destroy<R>(handle: *mut ReadHandle) {
+    if handle.is_null() {
+        return;
+    }
+
+    let repr = handle as *mut ReadRepr<R>;
+
+    // Safety: Just like FileHandle, a reader which panicked might be in an
+    // inconsistent state so we skip its destructor and just free the memory
+    if (*handle).poisoned.load(Ordering::Acquire) {
+        std::alloc::dealloc(repr.cast(), (*handle).layout);
+    } else {
+        let _ = Box::from_raw(repr);
+    }
+}
+
+unsafe fn read<R: Read>(
+    handle: *mut ReadHandle,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    if (*handle).poisoned.load(Ordering::Acquire) {
+        return Err(crate::file_handle::poison_error());
+    }
+
+    let got = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let repr = &mut *(handle as *mut ReadRepr<R>);
+        repr.reader.read(buffer)
+    }));
+
+    match got {
+        Ok(result) => result,
+        Err(_) => {
+            (*handle).poisoned.store(true, Ordering::Release);
+            Err(crate::file_handle::poison_error())
+        },
+    }
+}
+
+export! {
+    /// Create a new [`ReadHandle`] which reads from a file on disk, returning
+    /// `null` if the file can't be opened.
+    pub unsafe extern "C" fn new_read_handle_from_path(
+        path: *const c_char,
+    ) -> *mut ReadHandle {
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(p) => p,
+            Err(_) => return ptr::null_mut(),
+        };
+
+        match File::open(path) {
+            Ok(f) => ReadHandle::for_reader(f),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+export! {
+    /// Create a new [`ReadHandle`] which reads from a copy of `len` bytes
+    /// starting at `data`.
+    pub unsafe extern "C" fn new_memory_read_handle(
+        data: *const u8,
+        len: usize,
+    ) -> *mut ReadHandle {
+        let data = if data.is_null() {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(data, len).to_vec()
+        };
+
+        ReadHandle::for_reader(Cursor::new(data))
+    }
+}
+
+export! {
+    /// Read up to `len` bytes into `buffer`, returning the number of bytes
+    /// read.
+    ///
+    /// Returns `0` at the end of the stream or a negative value on failure.
+    pub unsafe extern "C" fn read_handle_read(
+        handle: *mut ReadHandle,
+        buffer: *mut c_char,
+        len: c_int,
+    ) -> c_int {
+        let buffer =
+            std::slice::from_raw_parts_mut(buffer as *mut u8, len as usize);
+
+        match ReadHandle::dispatch_read(handle, buffer) {
+            Ok(bytes_read) => bytes_read as c_int,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+export! {
+    /// Get the file descriptor behind a [`ReadHandle`] created by
+    /// [`new_read_handle_from_path()`], for use with the native API.
+    ///
+    /// The file descriptor is still owned by the [`ReadHandle`] and must not be
+    /// closed. Returns `-1` if the handle doesn't wrap a file.
+    #[cfg(unix)]
+    pub unsafe extern "C" fn read_handle_as_fd(
+        handle: *mut ReadHandle,
+    ) -> c_int {
+        use std::os::unix::io::AsRawFd;
+
+        if (*handle).type_id == TypeId::of::<File>() {
+            (*(handle as *mut ReadRepr<File>)).reader.as_raw_fd()
+        } else {
+            -1
+        }
+    }
+}
+
+export! {
+    /// Free the [`ReadHandle`], calling any destructors and cleaning up any
+    /// resources being used.
+    pub unsafe extern "C" fn read_handle_destroy(handle: *mut ReadHandle) {
+        ReadHandle::dispatch_destroy(handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_from_memory() {
+        let msg = b"Hello, World!";
+
+        unsafe {
+            let handle = new_memory_read_handle(msg.as_ptr(), msg.len());
+            let mut buffer = [0_u8; 8];
+            let ptr = buffer.as_mut_ptr().cast();
+
+            assert_eq!(read_handle_read(handle, ptr, 8), 8);
+            assert_eq!(&buffer, b"Hello, W");
+            assert_eq!(read_handle_read(handle, ptr, 8), 5);
+            assert_eq!(&buffer[..5], b"orld!");
+            assert_eq!(read_handle_read(handle, ptr, 8), 0);
+
+            read_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn panicking_readers_are_poisoned() {
+        struct Panicking;
+        impl Read for Panicking {
+            fn read(&mut self, _: &mut [u8]) -> Result<usize, Error> {
+                panic!("Oops...")
+            }
+        }
+
+        unsafe {
+            let handle = ReadHandle::for_reader(Panicking);
+            let mut buffer = [0; 8];
+
+            assert!(read_handle_read(handle, buffer.as_mut_ptr(), 8) < 0);
+            assert!((*handle).poisoned.load(Ordering::Acquire));
+            assert!(read_handle_read(handle, buffer.as_mut_ptr(), 8) < 0);
+
+            read_handle_destroy(handle);
+        }
+    }
+}
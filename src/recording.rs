@@ -0,0 +1,439 @@
+//! Recording every call made on a [`FileHandle`] so the session can be
+//! replayed later, e.g. to reproduce a plugin's I/O bug deterministically.
+//!
+//! A recording starts with [`RECORDING_MAGIC`] and the wall-clock time the
+//! recording started (nanoseconds since the Unix epoch), followed by one
+//! entry per call. Every integer is little-endian, and each entry is laid out
+//! as:
+//!
+//! | Field      | Type       | Notes                                      |
+//! | ---------- | ---------- | ------------------------------------------ |
+//! | kind       | `u8`       | `1` for a write, `2` for a flush           |
+//! | elapsed    | `u64`      | nanoseconds since the recording started    |
+//! | thread     | `u64`      | the calling thread's ID                    |
+//! | result     | `i64`      | bytes written, `0`, or a negative `errno`  |
+//! | length     | `u64`      | writes only, the payload's length          |
+//! | payload    | `[u8]`     | writes only                                |
+
+use crate::{
+    global_clock, thread_stats::current_thread_id, BufferPool, Clock,
+    FileHandle, OwnedFileHandle,
+};
+use std::{
+    convert::TryInto,
+    ffi::CStr,
+    fs::File,
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Write},
+    mem::ManuallyDrop,
+    os::raw::{c_char, c_int},
+    path::Path,
+    ptr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The bytes every recording starts with.
+pub const RECORDING_MAGIC: [u8; 8] = *b"TTOREC\x00\x01";
+
+const KIND_WRITE: u8 = 1;
+const KIND_FLUSH: u8 = 2;
+
+/// A call which was made on a recorded handle.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedCall {
+    /// A write, with the data which was passed in.
+    Write(Vec<u8>),
+    /// A flush.
+    Flush,
+}
+
+/// A single entry in a recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEntry {
+    /// When the call was made, relative to the start of the recording.
+    pub elapsed: Duration,
+    /// The ID of the thread which made the call.
+    pub thread_id: u64,
+    /// The call itself.
+    pub call: RecordedCall,
+    /// The number of bytes written (or `0` for a successful flush), or a
+    /// negative `errno` value on failure.
+    pub result: i64,
+}
+
+/// A writer which passes everything through to an inner handle while
+/// appending a record of each call to a log.
+///
+/// Recording is purely a debugging aid, so if writing to the log fails the
+/// error is ignored and recording stops.
+pub struct RecordingWriter {
+    inner: OwnedFileHandle,
+    log: Option<BufWriter<Box<dyn Write + Send + Sync>>>,
+    clock: Arc<dyn Clock>,
+    start: Instant,
+}
+
+impl RecordingWriter {
+    /// Record every call made on `inner` to `log`.
+    pub fn new<L>(inner: OwnedFileHandle, log: L) -> Self
+    where
+        L: Write + Send + Sync + 'static,
+    {
+        RecordingWriter::with_clock(inner, log, global_clock())
+    }
+
+    /// Like [`RecordingWriter::new()`], except the timestamps come from
+    /// `clock`.
+    pub fn with_clock<L>(
+        inner: OwnedFileHandle,
+        log: L,
+        clock: Arc<dyn Clock>,
+    ) -> Self
+    where
+        L: Write + Send + Sync + 'static,
+    {
+        let log: Box<dyn Write + Send + Sync> = Box::new(log);
+        let mut log = BufWriter::new(log);
+
+        let started_at = clock
+            .wall_time()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let header = log
+            .write_all(&RECORDING_MAGIC)
+            .and_then(|_| log.write_all(&started_at.to_le_bytes()));
+
+        RecordingWriter {
+            inner,
+            log: header.ok().map(|_| log),
+            start: clock.now(),
+            clock,
+        }
+    }
+
+    fn record(&mut self, kind: u8, payload: Option<&[u8]>, result: i64) {
+        let elapsed = self.clock.now().saturating_duration_since(self.start);
+        let log = match self.log.as_mut() {
+            Some(log) => log,
+            None => return,
+        };
+
+        let mut entry = BufferPool::global().take();
+        entry.push(kind);
+        entry.extend_from_slice(&(elapsed.as_nanos() as u64).to_le_bytes());
+        entry.extend_from_slice(&current_thread_id().to_le_bytes());
+        entry.extend_from_slice(&result.to_le_bytes());
+
+        let mut outcome = log.write_all(&entry);
+        if let Some(payload) = payload {
+            let len = payload.len() as u64;
+            outcome = outcome
+                .and_then(|_| log.write_all(&len.to_le_bytes()))
+                .and_then(|_| log.write_all(payload));
+        }
+
+        if outcome.is_err() {
+            self.log = None;
+        }
+    }
+
+    /// The handle being wrapped.
+    pub(crate) fn get_ref(&self) -> &OwnedFileHandle { &self.inner }
+}
+
+fn result_code<T>(result: &Result<T, Error>, ok: impl Fn(&T) -> i64) -> i64 {
+    match result {
+        Ok(value) => ok(value),
+        Err(e) => i64::from(crate::forbid_panics::errno_of(e)),
+    }
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let result = self.inner.write(buf);
+        let code = result_code(&result, |&n| n as i64);
+        self.record(KIND_WRITE, Some(buf), code);
+
+        result
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let result = self.inner.flush();
+        self.record(KIND_FLUSH, None, result_code(&result, |_| 0));
+
+        if let Some(log) = self.log.as_mut() {
+            if log.flush().is_err() {
+                self.log = None;
+            }
+        }
+
+        result
+    }
+}
+
+/// Reads the entries from a recording.
+#[derive(Debug)]
+pub struct RecordingReader<R> {
+    reader: R,
+    started_at: SystemTime,
+}
+
+impl<R: Read> RecordingReader<R> {
+    /// Start reading a recording, checking that it starts with
+    /// [`RECORDING_MAGIC`].
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+
+        if magic != RECORDING_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a recording"));
+        }
+
+        let started_at = read_u64(&mut reader)?;
+        let started_at =
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(started_at);
+
+        Ok(RecordingReader { reader, started_at })
+    }
+
+    /// The wall-clock time the recording started.
+    pub fn started_at(&self) -> SystemTime { self.started_at }
+
+    fn read_entry(&mut self) -> Result<Option<RecordedEntry>, Error> {
+        let mut kind = [0];
+        if self.reader.read(&mut kind)? == 0 {
+            return Ok(None);
+        }
+
+        let elapsed = Duration::from_nanos(read_u64(&mut self.reader)?);
+        let thread_id = read_u64(&mut self.reader)?;
+        let result = read_u64(&mut self.reader)? as i64;
+
+        let call = match kind[0] {
+            KIND_WRITE => {
+                let len = read_u64(&mut self.reader)?;
+                let mut payload = Vec::new();
+                (&mut self.reader).take(len).read_to_end(&mut payload)?;
+
+                if payload.len() as u64 != len {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                RecordedCall::Write(payload)
+            },
+            KIND_FLUSH => RecordedCall::Flush,
+            _ => {
+                let msg = "Unknown call in the recording";
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            },
+        };
+
+        Ok(Some(RecordedEntry {
+            elapsed,
+            thread_id,
+            call,
+            result,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = Result<RecordedEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> { self.read_entry().transpose() }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let mut buffer = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer.as_ref().try_into().unwrap()))
+}
+
+/// Re-execute every call in a recording against `target`, returning the
+/// number of calls whose result differed from the original.
+///
+/// A `speed` of `1.0` waits between calls so they happen at the same pace as
+/// the original session, `2.0` replays twice as fast, and so on. A `speed`
+/// of `0.0` (or less) replays everything as fast as possible.
+pub fn replay_session<R, W>(
+    recording: R,
+    target: &mut W,
+    speed: f64,
+) -> Result<u64, Error>
+where
+    R: Read,
+    W: Write,
+{
+    let clock = global_clock();
+    let start = clock.now();
+    let mut mismatches = 0;
+
+    for entry in RecordingReader::new(recording)? {
+        let entry = entry?;
+
+        if speed > 0.0 {
+            let due = entry.elapsed.div_f64(speed);
+            let so_far = clock.now().saturating_duration_since(start);
+
+            if let Some(remaining) = due.checked_sub(so_far) {
+                std::thread::sleep(remaining);
+            }
+        }
+
+        let result = match &entry.call {
+            RecordedCall::Write(data) => {
+                result_code(&target.write(data), |&n| n as i64)
+            },
+            RecordedCall::Flush => result_code(&target.flush(), |_| 0),
+        };
+
+        if result != entry.result {
+            mismatches += 1;
+        }
+    }
+
+    Ok(mismatches)
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to `inner` (taking ownership of
+    /// it) and records every call to the file at `recording_path`, overwriting
+    /// it if it already exists.
+    ///
+    /// Returns `null` if `inner` is `null` or the recording can't be created.
+    pub unsafe extern "C" fn new_recording_file_handle(
+        inner: *mut FileHandle,
+        recording_path: *const c_char,
+    ) -> *mut FileHandle {
+        if inner.is_null() || recording_path.is_null() {
+            return ptr::null_mut();
+        }
+
+        let path = match CStr::from_ptr(recording_path).to_str() {
+            Ok(path) => path,
+            Err(_) => return ptr::null_mut(),
+        };
+
+        match File::create(path) {
+            Ok(log) => {
+                let inner = OwnedFileHandle::from_raw(inner);
+                FileHandle::for_writer(RecordingWriter::new(inner, log))
+            },
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+export! {
+    /// Replay a recording made by [`new_recording_file_handle()`] against
+    /// `target`, at `speed` times the original pace (or as fast as possible if
+    /// `speed` isn't positive).
+    ///
+    /// The number of calls whose result differed from the original is stored in
+    /// `out_mismatches` if it isn't `null`. Returns `0` on success or a
+    /// negative value if the recording couldn't be read.
+    pub unsafe extern "C" fn replay_recording(
+        recording_path: *const c_char,
+        target: *mut FileHandle,
+        speed: f64,
+        out_mismatches: *mut u64,
+    ) -> c_int {
+        let path = match CStr::from_ptr(recording_path).to_str() {
+            Ok(path) => Path::new(path),
+            Err(_) => return -1,
+        };
+
+        // Note: the caller still owns the target
+        let mut target = ManuallyDrop::new(OwnedFileHandle::from_raw(target));
+        let outcome = File::open(path).and_then(|f| {
+            replay_session(BufReader::new(f), &mut *target, speed)
+        });
+
+        match outcome {
+            Ok(mismatches) => {
+                if !out_mismatches.is_null() {
+                    out_mismatches.write(mismatches);
+                }
+                0
+            },
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::tests::SharedBuffer, ManualClock};
+
+    #[test]
+    fn record_and_read_back_a_session() {
+        let log = SharedBuffer::default();
+        let clock = Arc::new(ManualClock::default());
+        let mut writer = RecordingWriter::with_clock(
+            OwnedFileHandle::new(Vec::new()),
+            log.clone(),
+            Arc::clone(&clock) as Arc<dyn Clock>,
+        );
+
+        writer.write_all(b"Hello").unwrap();
+        clock.advance(Duration::from_millis(250));
+        writer.flush().unwrap();
+        drop(writer);
+
+        let recording = log.0.lock().unwrap().clone();
+        let reader = RecordingReader::new(recording.as_slice()).unwrap();
+        assert_eq!(reader.started_at(), SystemTime::UNIX_EPOCH);
+        let entries: Vec<_> = reader.map(Result::unwrap).collect();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].call, RecordedCall::Write(b"Hello".to_vec()));
+        assert_eq!(entries[0].result, 5);
+        assert_eq!(entries[1].call, RecordedCall::Flush);
+        assert_eq!(entries[1].elapsed, Duration::from_millis(250));
+        assert_eq!(entries[1].thread_id, entries[0].thread_id);
+    }
+
+    #[test]
+    fn replay_a_recording_from_c() {
+        let path = std::env::temp_dir().join(format!(
+            "thin-trait-objects-recording-{}.bin",
+            std::process::id()
+        ));
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        let buffer = SharedBuffer::default();
+        let mut mismatches = 42;
+
+        unsafe {
+            let inner = crate::new_null_file_handle();
+            let handle = new_recording_file_handle(inner, c_path.as_ptr());
+            assert!(!handle.is_null());
+            crate::file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+            crate::file_handle_flush(handle);
+            crate::file_handle_destroy(handle);
+
+            let target = FileHandle::for_writer(buffer.clone());
+            let ret = replay_recording(
+                c_path.as_ptr(),
+                target,
+                0.0,
+                &mut mismatches,
+            );
+            crate::file_handle_destroy(target);
+
+            assert_eq!(ret, 0);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mismatches, 0);
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"asdf");
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        let err = RecordingReader::new(&b"definitely not a recording"[..])
+            .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,211 @@
+//! A [`FileHandle`] which masks secrets before they reach their destination.
+
+use crate::{FfiSlice, FileHandle, OwnedFileHandle};
+use std::{
+    io::{Error, ErrorKind, Write},
+    ptr,
+};
+
+/// A writer which replaces every occurrence of a set of byte patterns (API
+/// keys, passwords, etc.) with a mask before passing the data on to an inner
+/// handle.
+///
+/// Matches are found even when a secret is split across several writes. To
+/// make that possible, the last few bytes of each write (one less than the
+/// longest pattern) are held back until more data arrives or the writer is
+/// dropped, so a flush won't pass those bytes on either.
+///
+/// Patterns are matched literally. Each matching byte is overwritten, so
+/// the amount of data reaching the inner handle doesn't change.
+pub struct RedactingWriter {
+    inner: OwnedFileHandle,
+    patterns: Vec<Vec<u8>>,
+    mask: u8,
+    pending: Vec<u8>,
+    /// How many bytes at the start of `pending` are safe to pass on.
+    ready: usize,
+}
+
+impl RedactingWriter {
+    /// Create a new [`RedactingWriter`] which masks each pattern with `*`.
+    /// Empty patterns are ignored.
+    pub fn new<I, P>(inner: OwnedFileHandle, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Vec<u8>>,
+    {
+        RedactingWriter {
+            inner,
+            patterns: patterns
+                .into_iter()
+                .map(Into::into)
+                .filter(|p: &Vec<u8>| !p.is_empty())
+                .collect(),
+            mask: b'*',
+            pending: Vec::new(),
+            ready: 0,
+        }
+    }
+
+    /// Use a different byte to mask secrets.
+    pub fn with_mask(mut self, mask: u8) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    fn held_back(&self) -> usize {
+        let longest = self.patterns.iter().map(Vec::len).max().unwrap_or(0);
+        longest.saturating_sub(1)
+    }
+
+    fn redact(&mut self) {
+        let mut start = 0;
+
+        while start < self.pending.len() {
+            let rest = &self.pending[start..];
+            let matched = self
+                .patterns
+                .iter()
+                .filter(|p| rest.starts_with(p))
+                .map(Vec::len)
+                .max();
+
+            match matched {
+                Some(len) => {
+                    for byte in &mut self.pending[start..start + len] {
+                        *byte = self.mask;
+                    }
+                    start += len;
+                },
+                None => start += 1,
+            }
+        }
+    }
+
+    /// Pass any redacted data on to the inner handle.
+    fn forward(&mut self) -> Result<(), Error> {
+        while self.ready > 0 {
+            match self.inner.write(&self.pending[..self.ready]) {
+                Ok(0) => return Err(crate::zero_write::write_zero()),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                    self.ready -= n;
+                },
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The handle being wrapped.
+    pub(crate) fn get_ref(&self) -> &OwnedFileHandle { &self.inner }
+}
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        // report errors from last time before accepting more data
+        self.forward()?;
+
+        self.pending.extend_from_slice(buf);
+        self.redact();
+        self.ready = self.pending.len().saturating_sub(self.held_back());
+
+        // Note: the data has been accepted, so any errors will be reported
+        // by the next call
+        let _ = self.forward();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.forward()?;
+        self.inner.flush()
+    }
+}
+
+impl Drop for RedactingWriter {
+    fn drop(&mut self) {
+        // nothing else is coming, so what's left can't be part of a secret
+        self.ready = self.pending.len();
+        let _ = self.forward();
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which masks every occurrence of the `count`
+    /// `patterns` with `*` before writing to `inner`, taking ownership of it.
+    ///
+    /// The patterns are copied. Returns `null` if `inner` is `null`, in which
+    /// case nothing happens.
+    pub unsafe extern "C" fn new_redacting_file_handle(
+        inner: *mut FileHandle,
+        patterns: *const FfiSlice,
+        count: usize,
+    ) -> *mut FileHandle {
+        if inner.is_null() {
+            return ptr::null_mut();
+        }
+
+        let patterns = if patterns.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(patterns, count)
+        };
+        let patterns = patterns.iter().map(|p| p.as_slice().to_vec());
+
+        let inner = OwnedFileHandle::from_raw(inner);
+        FileHandle::for_writer(RedactingWriter::new(inner, patterns))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn secrets_are_masked() {
+        let buffer = SharedBuffer::default();
+        let patterns = [FfiSlice::new(b"hunter2"), FfiSlice::new(b"s3cr3t")];
+
+        unsafe {
+            let inner = FileHandle::for_writer(buffer.clone());
+            let handle =
+                new_redacting_file_handle(inner, patterns.as_ptr(), 2);
+
+            let msg = b"password=hunter2, token=s3cr3t\n";
+            let ret = file_handle_write(handle, msg.as_ptr().cast(), 31);
+            assert_eq!(ret, 31);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(
+            buffer.0.lock().unwrap().as_slice(),
+            b"password=*******, token=******\n"
+        );
+    }
+
+    #[test]
+    fn secrets_split_across_writes_are_masked() {
+        let buffer = SharedBuffer::default();
+        let inner = OwnedFileHandle::new(buffer.clone());
+        let mut writer =
+            RedactingWriter::new(inner, vec!["hunter2"]).with_mask(b'#');
+
+        writer.write_all(b"password=hun").unwrap();
+        writer.write_all(b"te").unwrap();
+        writer.flush().unwrap();
+        // the start of the secret is held back
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"password");
+        writer.write_all(b"r2 and hunter").unwrap();
+        drop(writer);
+
+        assert_eq!(
+            buffer.0.lock().unwrap().as_slice(),
+            b"password=####### and hunter"
+        );
+    }
+}
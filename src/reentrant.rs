@@ -0,0 +1,172 @@
+//! Handles for callbacks which might end up writing to themselves (e.g. a
+//! host's log sink which logs its own errors).
+
+use crate::{
+    errors::{crate_status_code, crate_status_error, CRATE_ERROR_REENTRANT_CALL},
+    thread_stats::current_thread_id,
+    FileHandle,
+};
+use std::{
+    io::{Error, ErrorKind, Write},
+    os::raw::c_int,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Returned when a handle created by [`FileHandle::for_reentrant_safe_fn()`]
+/// is written to from inside its own callback.
+///
+/// This is [`CRATE_ERROR_REENTRANT_CALL`] in the [`ErrorDomain::Crate`]
+/// domain, so it can't be confused with an `EDEADLK` from the callback.
+///
+/// [`CRATE_ERROR_REENTRANT_CALL`]: crate::CRATE_ERROR_REENTRANT_CALL
+/// [`ErrorDomain::Crate`]: crate::ErrorDomain::Crate
+pub const FILE_HANDLE_REENTRANT_CALL: c_int =
+    crate_status_code(CRATE_ERROR_REENTRANT_CALL);
+
+/// The error used when a callback calls back into its own handle.
+pub(crate) fn reentrant_call() -> Error {
+    crate_status_error(ErrorKind::Other, CRATE_ERROR_REENTRANT_CALL)
+}
+
+/// No thread is running the callback.
+const NO_OWNER: u64 = u64::MAX;
+
+/// A writer which calls `F`, refusing to call it again from the thread which
+/// is already running it.
+struct ReentrantSafeFn<F> {
+    callback: Mutex<F>,
+    /// The [`current_thread_id()`] of whoever is running the callback.
+    owner: AtomicU64,
+}
+
+/// Marks the current thread as running the callback until it is dropped
+/// (including when the callback panics).
+struct Running<'a>(&'a AtomicU64);
+
+impl<'a> Drop for Running<'a> {
+    fn drop(&mut self) { self.0.store(NO_OWNER, Ordering::Release); }
+}
+
+impl<F> Write for &ReentrantSafeFn<F>
+where
+    F: FnMut(&[u8]) -> Result<usize, Error>,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let me = current_thread_id();
+        if self.owner.load(Ordering::Acquire) == me {
+            return Err(reentrant_call());
+        }
+
+        // other threads just wait their turn
+        let mut callback = match self.callback.lock() {
+            Ok(callback) => callback,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        self.owner.store(me, Ordering::Release);
+        let _running = Running(&self.owner);
+
+        (*callback)(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+}
+
+impl FileHandle {
+    /// Create a new [`FileHandle`] which passes everything written to it to
+    /// `callback`.
+    ///
+    /// If the callback writes to the same handle (directly or via something
+    /// like a log sink), the nested write fails with
+    /// [`FILE_HANDLE_REENTRANT_CALL`] instead of recursing forever. Calls
+    /// from other threads wait for the callback to return.
+    pub fn for_reentrant_safe_fn<F>(callback: F) -> *mut FileHandle
+    where
+        F: FnMut(&[u8]) -> Result<usize, Error> + Send + 'static,
+    {
+        FileHandle::for_concurrent_writer(ReentrantSafeFn {
+            callback: Mutex::new(callback),
+            owner: AtomicU64::new(NO_OWNER),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicUsize},
+        Arc,
+    };
+
+    #[test]
+    fn writing_to_yourself_is_an_error() {
+        let this = Arc::new(AtomicPtr::new(std::ptr::null_mut()));
+        let nested = Arc::new(Mutex::new(Vec::new()));
+
+        let handle = {
+            let this = Arc::clone(&this);
+            let nested = Arc::clone(&nested);
+            FileHandle::for_reentrant_safe_fn(move |data: &[u8]| {
+                let handle = this.load(Ordering::SeqCst);
+                let x = b"x".as_ptr().cast();
+                let ret = unsafe { file_handle_write(handle, x, 1) };
+                nested.lock().unwrap().push(ret);
+                Ok(data.len())
+            })
+        };
+        this.store(handle, Ordering::SeqCst);
+
+        unsafe {
+            let data = b"asdf".as_ptr().cast();
+            assert_eq!(file_handle_write(handle, data, 4), 4);
+            assert_eq!(file_handle_write(handle, data, 2), 2);
+            assert!(!file_handle_is_poisoned(handle));
+            file_handle_destroy(handle);
+        }
+
+        let nested = nested.lock().unwrap();
+        assert_eq!(*nested, vec![FILE_HANDLE_REENTRANT_CALL; 2]);
+    }
+
+    #[test]
+    fn other_threads_take_turns() {
+        let inside = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(AtomicUsize::new(0));
+
+        let handle = {
+            let inside = Arc::clone(&inside);
+            let written = Arc::clone(&written);
+            FileHandle::for_reentrant_safe_fn(move |data: &[u8]| {
+                assert!(!inside.swap(true, Ordering::SeqCst));
+                std::thread::yield_now();
+                written.fetch_add(data.len(), Ordering::SeqCst);
+                inside.store(false, Ordering::SeqCst);
+                Ok(data.len())
+            })
+        };
+
+        let address = handle as usize;
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let handle = address as *mut FileHandle;
+                    for _ in 0..100 {
+                        let data = b"asdf".as_ptr().cast();
+                        let ret = unsafe { file_handle_write(handle, data, 4) };
+                        assert_eq!(ret, 4);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        unsafe { file_handle_destroy(handle) };
+        assert_eq!(written.load(Ordering::SeqCst), 4 * 4 * 100);
+    }
+}
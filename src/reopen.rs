@@ -0,0 +1,158 @@
+//! Reading back what was written to a [`FileHandle`], without the caller
+//! needing to know where it went.
+
+use crate::{optional::unsupported, ChunkedBuffer, FileHandle, ReadHandle};
+use std::{
+    fs::File,
+    io::{Cursor, Error, Read},
+    ptr,
+};
+
+/// Open a new, independent [`ReadHandle`] over everything written so far.
+unsafe fn reopen_for_read(
+    handle: *mut FileHandle,
+) -> Result<*mut ReadHandle, Error> {
+    FileHandle::dispatch_flush(handle)?;
+
+    if let Some(buffer) = FileHandle::downcast_raw::<Vec<u8>>(handle) {
+        return Ok(ReadHandle::for_reader(Cursor::new((*buffer).clone())));
+    }
+    if let Some(buffer) = FileHandle::downcast_raw::<ChunkedBuffer>(handle) {
+        let data = (*buffer).as_bytes().to_vec();
+        return Ok(ReadHandle::for_reader(Cursor::new(data)));
+    }
+    if let Some(path) = &(*handle).cold.path {
+        return Ok(ReadHandle::for_reader(File::open(path)?));
+    }
+
+    match FileHandle::downcast_raw::<File>(handle) {
+        Some(file) => reopen_file(&*file),
+        None => Err(unsupported()),
+    }
+}
+
+/// Read a file we only have the descriptor for, without moving the offset
+/// the writer is using.
+#[cfg(unix)]
+fn reopen_file(file: &File) -> Result<*mut ReadHandle, Error> {
+    struct PositionalReader {
+        file: File,
+        offset: u64,
+    }
+
+    impl Read for PositionalReader {
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+            use std::os::unix::fs::FileExt;
+
+            let bytes_read = self.file.read_at(buffer, self.offset)?;
+            self.offset += bytes_read as u64;
+            Ok(bytes_read)
+        }
+    }
+
+    Ok(ReadHandle::for_reader(PositionalReader {
+        file: file.try_clone()?,
+        offset: 0,
+    }))
+}
+
+/// Reading through a cloned `HANDLE` would move the writer's offset, so only
+/// files opened from a path can be reopened.
+#[cfg(not(unix))]
+fn reopen_file(_file: &File) -> Result<*mut ReadHandle, Error> {
+    Err(unsupported())
+}
+
+export! {
+    /// Flush the [`FileHandle`] and open a new [`ReadHandle`] which reads
+    /// everything written to it so far, from the start.
+    ///
+    /// This works for memory handles (which are copied) and for handles which
+    /// write to a file, either opened by
+    /// [`new_file_handle_from_path()`][crate::new_file_handle_from_path] or (on
+    /// Unix) created from a file descriptor. Reading never affects where the
+    /// [`FileHandle`] writes next.
+    ///
+    /// Returns `null` on failure (e.g. [`FILE_HANDLE_UNSUPPORTED`] for other
+    /// kinds of handle), with the reason available from
+    /// [`file_handle_last_error_kind()`][crate::file_handle_last_error_kind].
+    /// The [`ReadHandle`] must be freed with
+    /// [`read_handle_destroy()`][crate::read_handle_destroy].
+    ///
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub unsafe extern "C" fn file_handle_reopen_for_read(
+        handle: *mut FileHandle,
+    ) -> *mut ReadHandle {
+        match reopen_for_read(handle) {
+            Ok(reader) => reader,
+            Err(e) => {
+                (*handle).cold.last_error.record(&e);
+                ptr::null_mut()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::{ffi::CString, os::raw::c_int};
+
+    unsafe fn read_all(reader: *mut ReadHandle) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buffer = [0_u8; 4];
+
+        loop {
+            let ptr = buffer.as_mut_ptr().cast();
+            match read_handle_read(reader, ptr, buffer.len() as c_int) {
+                0 => break,
+                n if n < 0 => panic!("Read failed with {}", n),
+                n => data.extend_from_slice(&buffer[..n as usize]),
+            }
+        }
+        read_handle_destroy(reader);
+
+        data
+    }
+
+    #[test]
+    fn read_back_memory_and_files() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("reopen-{}.txt", std::process::id()));
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let memory = new_memory_file_handle();
+            file_handle_write(memory, b"in memory".as_ptr().cast(), 9);
+            let reader = file_handle_reopen_for_read(memory);
+            assert_eq!(read_all(reader), b"in memory");
+            file_handle_destroy(memory);
+
+            let file = new_file_handle_from_path(c_path.as_ptr());
+            file_handle_write(file, b"on disk".as_ptr().cast(), 7);
+            let reader = file_handle_reopen_for_read(file);
+            assert_eq!(read_all(reader), b"on disk");
+
+            // the writer carries on where it left off
+            file_handle_write(file, b"!".as_ptr().cast(), 1);
+            file_handle_destroy(file);
+        }
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"on disk!");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unsupported_handles_return_null() {
+        unsafe {
+            let handle = new_null_file_handle();
+            assert!(file_handle_reopen_for_read(handle).is_null());
+            assert_eq!(
+                file_handle_last_error_os_error(handle),
+                -FILE_HANDLE_UNSUPPORTED
+            );
+            file_handle_destroy(handle);
+        }
+    }
+}
@@ -0,0 +1,541 @@
+//! Writing to a file which is periodically renamed out of the way (and
+//! optionally compressed) and replaced with a fresh one.
+
+use crate::{global_clock, Clock, FileHandle};
+use std::{
+    ffi::{CStr, OsString},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Error, ErrorKind, Write},
+    os::raw::{c_char, c_int},
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    ptr,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Never rotate automatically (see [`new_rotating_file_handle()`]).
+pub const ROTATE_NEVER: c_int = 0;
+/// Rotate at the start of every hour.
+pub const ROTATE_HOURLY: c_int = 1;
+/// Rotate at the start of every day.
+pub const ROTATE_DAILY: c_int = 2;
+
+/// How often a [`RotatingFile`] starts a new file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RotationInterval {
+    /// At the start of every hour (UTC).
+    Hourly,
+    /// At the start of every day (UTC).
+    Daily,
+}
+
+impl RotationInterval {
+    fn as_secs(self) -> u64 {
+        match self {
+            RotationInterval::Hourly => 60 * 60,
+            RotationInterval::Daily => 24 * 60 * 60,
+        }
+    }
+}
+
+/// Settings for a [`RotatingFile`].
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use thin_trait_objects::{RotationConfig, RotationInterval};
+/// // start a new file at 03:00 UTC every day, gzipping the old one
+/// let config = RotationConfig::new("/var/log/app.log")
+///     .rotate_every(RotationInterval::Daily, Duration::from_secs(3 * 3600))
+///     .compress(true);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RotationConfig {
+    path: PathBuf,
+    interval: Option<RotationInterval>,
+    offset: Duration,
+    compress: bool,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl RotationConfig {
+    /// Write to `path`, only rotating when asked to.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        RotationConfig {
+            path: path.into(),
+            interval: None,
+            offset: Duration::from_secs(0),
+            compress: false,
+            clock: None,
+        }
+    }
+
+    /// Rotate automatically each `interval`, `offset` after the boundary
+    /// (e.g. an `offset` of 30 minutes with [`RotationInterval::Hourly`]
+    /// rotates at half past every hour).
+    ///
+    /// The offset wraps around if it is longer than the interval.
+    pub fn rotate_every(
+        mut self,
+        interval: RotationInterval,
+        offset: Duration,
+    ) -> Self {
+        self.interval = Some(interval);
+        let offset = offset.as_secs() % interval.as_secs();
+        self.offset = Duration::from_secs(offset);
+        self
+    }
+
+    /// Gzip files on a background thread once they have been rotated.
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Use a particular [`Clock`] instead of the [`global_clock()`] to
+    /// decide when to rotate.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+}
+
+/// A file which is renamed to `<path>.<YYYYMMDD-HHMMSS>` (the UTC time it
+/// was rotated) and replaced with an empty file on a schedule or when
+/// [`RotatingFile::rotate()`] is called.
+#[derive(Debug)]
+pub struct RotatingFile {
+    config: RotationConfig,
+    clock: Arc<dyn Clock>,
+    file: File,
+    /// When to rotate next, in seconds since the Unix epoch.
+    next_rotation: Option<u64>,
+    compressor: Compressor,
+}
+
+impl RotatingFile {
+    /// Create (or truncate) the file at the config's path.
+    pub fn open(config: RotationConfig) -> Result<Self, Error> {
+        let clock = config.clock.clone().unwrap_or_else(global_clock);
+        let file = File::create(&config.path)?;
+
+        let mut rotating = RotatingFile {
+            config,
+            clock,
+            file,
+            next_rotation: None,
+            compressor: Compressor::default(),
+        };
+        rotating.next_rotation = rotating.next_boundary();
+
+        Ok(rotating)
+    }
+
+    /// The path being written to.
+    pub fn path(&self) -> &Path { &self.config.path }
+
+    fn now_secs(&self) -> u64 {
+        self.clock
+            .wall_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    fn next_boundary(&self) -> Option<u64> {
+        let interval = self.config.interval?.as_secs();
+        let offset = self.config.offset.as_secs();
+        let now = self.now_secs();
+
+        let periods = now.saturating_sub(offset) / interval;
+        Some(offset + (periods + 1) * interval)
+    }
+
+    /// Move the current file out of the way and start a new one, returning
+    /// the rotated file's new path (before compression).
+    pub fn rotate(&mut self) -> Result<PathBuf, Error> {
+        self.file.flush()?;
+
+        let rotated = self.rotated_path(self.clock.wall_time());
+        fs::rename(&self.config.path, &rotated)?;
+        self.file = File::create(&self.config.path)?;
+        self.next_rotation = self.next_boundary();
+
+        if self.config.compress {
+            self.compressor.submit(rotated.clone())?;
+        }
+
+        Ok(rotated)
+    }
+
+    fn rotated_path(&self, when: SystemTime) -> PathBuf {
+        let secs = when
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut name = OsString::from(self.config.path.as_os_str());
+        name.push(format!(".{}", timestamp(secs)));
+
+        let mut candidate = PathBuf::from(&name);
+        let mut n = 1;
+        while candidate.exists() || gz_path(&candidate).exists() {
+            let mut numbered = name.clone();
+            numbered.push(format!(".{}", n));
+            candidate = PathBuf::from(numbered);
+            n += 1;
+        }
+
+        candidate
+    }
+
+    /// Wait for every rotated file to finish being compressed, returning the
+    /// first error since the last call.
+    pub fn wait_for_compression(&mut self) -> Result<(), Error> {
+        self.compressor.wait()
+    }
+
+    fn rotate_if_due(&mut self) -> Result<(), Error> {
+        match self.next_rotation {
+            Some(due) if self.now_secs() >= due => self.rotate().map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.rotate_if_due()?;
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.file.flush() }
+}
+
+/// Compresses rotated files one at a time on a single background thread.
+///
+/// Dropping it waits for the files which are still queued, so a `.gz` is
+/// never left half-written next to the original.
+#[derive(Debug, Default)]
+struct Compressor {
+    shared: Arc<(Mutex<Queue>, Condvar)>,
+    worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    waiting: VecDeque<PathBuf>,
+    busy: bool,
+    closed: bool,
+    first_error: Option<Error>,
+}
+
+impl Compressor {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.shared.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queue `path` to be compressed, starting the worker if this is the
+    /// first file.
+    fn submit(&mut self, path: PathBuf) -> Result<(), Error> {
+        if self.worker.is_none() {
+            let shared = Arc::clone(&self.shared);
+            let worker = thread::Builder::new()
+                .name(String::from("rotation-compress"))
+                .spawn(move || run_compressor(&shared))?;
+            self.worker = Some(worker);
+        }
+
+        self.lock().waiting.push_back(path);
+        self.shared.1.notify_all();
+        Ok(())
+    }
+
+    /// Wait until the queue is empty, returning the first error.
+    fn wait(&mut self) -> Result<(), Error> {
+        let mut queue = self.lock();
+
+        while queue.busy || !queue.waiting.is_empty() {
+            queue = self
+                .shared
+                .1
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+
+        match queue.first_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Compressor {
+    fn drop(&mut self) {
+        self.lock().closed = true;
+        self.shared.1.notify_all();
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_compressor(shared: &(Mutex<Queue>, Condvar)) {
+    let (state, changed) = shared;
+    let mut queue = state.lock().unwrap_or_else(|e| e.into_inner());
+
+    loop {
+        match queue.waiting.pop_front() {
+            Some(path) => {
+                queue.busy = true;
+                drop(queue);
+
+                let outcome = std::panic::catch_unwind(|| compress(&path))
+                    .unwrap_or_else(|_| {
+                        Err(Error::new(ErrorKind::Other, "A panic occurred"))
+                    });
+
+                queue = state.lock().unwrap_or_else(|e| e.into_inner());
+                queue.busy = false;
+                if let Err(e) = outcome {
+                    queue.first_error.get_or_insert(e);
+                }
+                changed.notify_all();
+            },
+            None if queue.closed => return,
+            None => {
+                queue = changed.wait(queue).unwrap_or_else(|e| e.into_inner());
+            },
+        }
+    }
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+/// Replace `path` with a gzipped copy at `path.gz`.
+fn compress(path: &Path) -> Result<(), Error> {
+    let destination = gz_path(path);
+    let reader = BufReader::new(File::open(path)?);
+    let writer = BufWriter::new(File::create(&destination)?);
+
+    let mut writer = crate::gzip::compress(reader, writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::remove_file(path)
+}
+
+/// Format a Unix timestamp as `YYYYMMDD-HHMMSS` in UTC.
+fn timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Howard Hinnant's civil_from_days()
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to `path`, rotating it every
+    /// hour or day (see [`ROTATE_HOURLY`] and [`ROTATE_DAILY`]) at
+    /// `offset_secs` past the boundary, and gzipping rotated files in the
+    /// background if `compress` is set.
+    ///
+    /// Files are compressed one at a time on a single helper thread, and
+    /// destroying the handle waits for the ones which haven't finished.
+    ///
+    /// Returns `null` if `interval` is unknown or the file can't be created.
+    pub unsafe extern "C" fn new_rotating_file_handle(
+        path: *const c_char,
+        interval: c_int,
+        offset_secs: u64,
+        compress: bool,
+    ) -> *mut FileHandle {
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(p) => p,
+            Err(_) => return ptr::null_mut(),
+        };
+
+        let offset = Duration::from_secs(offset_secs);
+        let config = match interval {
+            ROTATE_NEVER => RotationConfig::new(path),
+            ROTATE_HOURLY => RotationConfig::new(path)
+                .rotate_every(RotationInterval::Hourly, offset),
+            ROTATE_DAILY => RotationConfig::new(path)
+                .rotate_every(RotationInterval::Daily, offset),
+            _ => return ptr::null_mut(),
+        };
+
+        match RotatingFile::open(config.compress(compress)) {
+            Ok(file) => {
+                let handle = FileHandle::for_writer(file);
+                (*handle).cold.path = Some(path.into());
+                handle
+            },
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+export! {
+    /// Rotate a handle created by [`new_rotating_file_handle()`] straight away.
+    ///
+    /// Returns `0` on success, [`FILE_HANDLE_UNSUPPORTED`] if this isn't a
+    /// rotating handle, or a negative error code.
+    ///
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub unsafe extern "C" fn file_handle_rotate_now(
+        handle: *mut FileHandle,
+    ) -> c_int {
+        let file = match FileHandle::downcast_raw::<RotatingFile>(handle) {
+            Some(file) => file,
+            None => return crate::FILE_HANDLE_UNSUPPORTED,
+        };
+
+        if let Err(e) = FileHandle::check_frozen(handle) {
+            return crate::forbid_panics::into_errno(e);
+        }
+
+        match (*file).rotate() {
+            Ok(_) => 0,
+            Err(e) => {
+                (*handle).cold.last_error.record(&e);
+                crate::forbid_panics::into_errno(e)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, OwnedFileHandle};
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn format_timestamps() {
+        assert_eq!(timestamp(0), "19700101-000000");
+        assert_eq!(timestamp(951_782_400), "20000229-000000");
+        assert_eq!(timestamp(1_790_856_000 + 3723), "20261001-130203");
+    }
+
+    #[test]
+    fn rotate_at_the_configured_time() {
+        let dir = scratch_dir("rotate_at_the_configured_time");
+        let path = dir.join("app.log");
+        // 2026-10-01 11:50:00 UTC
+        let start = UNIX_EPOCH + Duration::from_secs(1_790_855_400);
+        let clock = Arc::new(ManualClock::new(start));
+        let config = RotationConfig::new(&path)
+            .rotate_every(RotationInterval::Hourly, Duration::from_secs(0))
+            .with_clock(clock.clone());
+
+        let file = RotatingFile::open(config).unwrap();
+        let mut handle = OwnedFileHandle::new(file);
+        handle.write_all(b"before").unwrap();
+        clock.advance(Duration::from_secs(9 * 60));
+        handle.write_all(b", still before").unwrap();
+        clock.advance(Duration::from_secs(60));
+        handle.write_all(b"after").unwrap();
+        drop(handle);
+
+        let rotated = dir.join("app.log.20261001-120000");
+        assert_eq!(fs::read(&rotated).unwrap(), b"before, still before");
+        assert_eq!(fs::read(&path).unwrap(), b"after");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_now_and_compress() {
+        let dir = scratch_dir("rotate_now_and_compress");
+        let path = dir.join("app.log");
+        let config = RotationConfig::new(&path)
+            .compress(true)
+            .with_clock(Arc::new(ManualClock::default()));
+        let mut file = RotatingFile::open(config).unwrap();
+
+        unsafe {
+            let handle = FileHandle::for_writer(file);
+            let data = b"Hello, World!";
+            crate::file_handle_write(handle, data.as_ptr().cast(), 13);
+            assert_eq!(file_handle_rotate_now(handle), 0);
+            assert_eq!(file_handle_rotate_now(handle), 0);
+
+            let owned = OwnedFileHandle::from_raw(handle);
+            file = owned.downcast::<RotatingFile>().unwrap();
+
+            let other = crate::new_null_file_handle();
+            let ret = file_handle_rotate_now(other);
+            assert_eq!(ret, crate::FILE_HANDLE_UNSUPPORTED);
+            crate::file_handle_destroy(other);
+        }
+
+        file.wait_for_compression().unwrap();
+        let first = dir.join("app.log.19700101-000000.gz");
+        let second = dir.join("app.log.19700101-000000.1.gz");
+        let compressed = fs::read(&first).unwrap();
+        assert_eq!(&compressed[..2], &[0x1F, 0x8B]);
+        assert!(second.exists());
+        assert!(!dir.join("app.log.19700101-000000").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_waits_for_queued_compressions() {
+        let dir = scratch_dir("dropping_waits_for_queued_compressions");
+        let path = dir.join("app.log");
+        let config = RotationConfig::new(&path)
+            .compress(true)
+            .with_clock(Arc::new(ManualClock::default()));
+        let mut file = RotatingFile::open(config).unwrap();
+
+        for _ in 0..3 {
+            file.write_all(&[b'x'; 4096]).unwrap();
+            file.rotate().unwrap();
+        }
+        drop(file);
+
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "app.log",
+                "app.log.19700101-000000.1.gz",
+                "app.log.19700101-000000.2.gz",
+                "app.log.19700101-000000.gz",
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
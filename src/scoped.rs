@@ -0,0 +1,181 @@
+//! Hierarchical [`FileHandle`]s which share a single destination, tagging
+//! each write with the name of the scope it came from.
+
+use crate::{BufferPool, FileHandle, OwnedFileHandle};
+use std::{
+    ffi::CStr,
+    io::{Error, Write},
+    os::raw::c_char,
+    ptr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// A writer which prefixes everything it writes with a scope name before
+/// passing it on to a destination shared with its parent and siblings.
+///
+/// This lets a plugin host give each plugin its own handle to a common log
+/// sink without opening a new file for every plugin. Each child holds a
+/// reference to the destination, so children can be created and destroyed
+/// independently and the destination is only destroyed along with the last
+/// handle using it.
+///
+/// Every write is passed to the destination as a single write, with the
+/// prefix (e.g. `"[host/plugin] "`) in front of it.
+pub struct ScopedWriter {
+    sink: Arc<Mutex<OwnedFileHandle>>,
+    scope: String,
+    prefix: Vec<u8>,
+}
+
+impl ScopedWriter {
+    /// Create the root of a new hierarchy, which writes to `inner` without
+    /// adding a prefix.
+    pub fn new(inner: OwnedFileHandle) -> Self {
+        ScopedWriter {
+            sink: Arc::new(Mutex::new(inner)),
+            scope: String::new(),
+            prefix: Vec::new(),
+        }
+    }
+
+    /// Create a child which shares this writer's destination and adds
+    /// `name` to the end of its scope.
+    pub fn child(&self, name: &str) -> ScopedWriter {
+        let scope = if self.scope.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.scope, name)
+        };
+        let prefix = format!("[{}] ", scope).into_bytes();
+
+        ScopedWriter {
+            sink: Arc::clone(&self.sink),
+            scope,
+            prefix,
+        }
+    }
+
+    /// The full name of this writer's scope (e.g. `"host/plugin"`), which is
+    /// empty for the root.
+    pub fn scope(&self) -> &str { &self.scope }
+
+    fn sink(&self) -> MutexGuard<'_, OwnedFileHandle> {
+        // The sink catches its own panics, so the lock can't be left in an
+        // inconsistent state
+        self.sink.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Write for &ScopedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.prefix.is_empty() {
+            return self.sink().write(buf);
+        }
+
+        let mut message = BufferPool::global().take();
+        message.extend_from_slice(&self.prefix);
+        message.extend_from_slice(buf);
+        self.sink().write_all(&message)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.sink().flush() }
+}
+
+export! {
+    /// Create the root of a hierarchy of handles which share `inner`, taking
+    /// ownership of it. Children can be created using [`file_handle_child()`].
+    ///
+    /// Returns `null` if `inner` is `null`.
+    pub unsafe extern "C" fn new_scoped_file_handle(
+        inner: *mut FileHandle,
+    ) -> *mut FileHandle {
+        if inner.is_null() {
+            return ptr::null_mut();
+        }
+
+        let writer = ScopedWriter::new(OwnedFileHandle::from_raw(inner));
+        FileHandle::for_concurrent_writer(writer)
+    }
+}
+
+export! {
+    /// Create a new handle which writes to the same destination as `parent`,
+    /// prefixing each write with `scope_name`.
+    ///
+    /// The child must be destroyed separately, and may outlive its parent.
+    /// Returns `null` if `parent` wasn't created by
+    /// [`new_scoped_file_handle()`] or [`file_handle_child()`], or `scope_name`
+    /// isn't valid UTF-8.
+    pub unsafe extern "C" fn file_handle_child(
+        parent: *mut FileHandle,
+        scope_name: *const c_char,
+    ) -> *mut FileHandle {
+        let parent = match FileHandle::downcast_raw::<ScopedWriter>(parent) {
+            Some(parent) => &*parent,
+            None => return ptr::null_mut(),
+        };
+
+        match CStr::from_ptr(scope_name).to_str() {
+            Ok(name) => FileHandle::for_concurrent_writer(parent.child(name)),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn children_prefix_their_writes() {
+        let buffer = SharedBuffer::default();
+        let root = ScopedWriter::new(OwnedFileHandle::new(buffer.clone()));
+        let plugin = root.child("plugin");
+        let nested = plugin.child("worker");
+
+        (&root).write_all(b"root\n").unwrap();
+        (&plugin).write_all(b"plugin\n").unwrap();
+        (&nested).write_all(b"nested\n").unwrap();
+
+        assert_eq!(nested.scope(), "plugin/worker");
+        let written = buffer.0.lock().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&written),
+            "root\n[plugin] plugin\n[plugin/worker] nested\n"
+        );
+    }
+
+    #[test]
+    fn children_can_outlive_their_parent() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let root =
+                new_scoped_file_handle(FileHandle::for_writer(buffer.clone()));
+            let child = file_handle_child(root, b"child\0".as_ptr().cast());
+            assert!(!child.is_null());
+
+            file_handle_destroy(root);
+            let ret = file_handle_write(child, b"asdf".as_ptr().cast(), 4);
+            assert_eq!(ret, 4);
+            file_handle_destroy(child);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"[child] asdf");
+    }
+
+    #[test]
+    fn only_scoped_handles_have_children() {
+        unsafe {
+            let handle = new_null_file_handle();
+
+            let child = file_handle_child(handle, b"child\0".as_ptr().cast());
+            assert!(child.is_null());
+
+            file_handle_destroy(handle);
+        }
+    }
+}
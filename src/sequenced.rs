@@ -0,0 +1,181 @@
+//! Numbering every write so output from many threads can be put back in
+//! order downstream.
+
+use crate::{BufferPool, FileHandle, OwnedFileHandle};
+use std::{
+    io::{Error, Write},
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, TryLockError,
+    },
+};
+
+/// How a [`SequencedWriter`] labels each write.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SequenceFormat {
+    /// The sequence number in decimal followed by a space (e.g. `"42 "`).
+    Text,
+    /// The sequence number followed by the length of the data, as a
+    /// little-endian `u64` and `u32`.
+    Binary,
+}
+
+/// A writer which gives each write the next number from a shared counter and
+/// writes it in front of the data.
+///
+/// Clones share the same counter and destination, and `&SequencedWriter`
+/// implements [`Write`], so it can be used with
+/// [`FileHandle::for_concurrent_writer()`]. Each write (along with its
+/// header) is passed to the inner handle as a single write.
+#[derive(Clone)]
+pub struct SequencedWriter {
+    inner: Arc<Mutex<OwnedFileHandle>>,
+    next: Arc<AtomicU64>,
+    format: SequenceFormat,
+}
+
+impl SequencedWriter {
+    /// Create a new [`SequencedWriter`] which uses a textual header and
+    /// starts counting from `0`.
+    pub fn new(inner: OwnedFileHandle) -> Self {
+        SequencedWriter {
+            inner: Arc::new(Mutex::new(inner)),
+            next: Arc::new(AtomicU64::new(0)),
+            format: SequenceFormat::Text,
+        }
+    }
+
+    /// Use a different header format.
+    pub fn with_format(self, format: SequenceFormat) -> Self {
+        SequencedWriter { format, ..self }
+    }
+
+    /// The sequence number the next write will get.
+    pub fn next_sequence(&self) -> u64 { self.next.load(Ordering::Relaxed) }
+
+    /// Lock the handle being wrapped, unless someone else is using it.
+    pub(crate) fn try_inner(
+        &self,
+    ) -> Option<MutexGuard<'_, OwnedFileHandle>> {
+        match self.inner.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+impl Write for &SequencedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let buf = &buf[..buf.len().min(u32::MAX as usize)];
+        let mut record = BufferPool::global().take();
+        // The lock makes sure sequence numbers reach the inner handle in order
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+
+        match self.format {
+            SequenceFormat::Text => write!(record, "{} ", sequence)?,
+            SequenceFormat::Binary => {
+                record.extend_from_slice(&sequence.to_le_bytes());
+                record.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+            },
+        }
+        record.extend_from_slice(buf);
+        inner.write_all(&record)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+impl Write for SequencedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { (&*self).flush() }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes each write's sequence number
+    /// (e.g. `"42 "`) in front of it before passing it to `inner`, taking
+    /// ownership of `inner`.
+    ///
+    /// The returned handle may be written to from several threads at once.
+    /// Returns `null` if `inner` is `null`.
+    pub unsafe extern "C" fn new_sequenced_file_handle(
+        inner: *mut FileHandle,
+    ) -> *mut FileHandle {
+        if inner.is_null() {
+            return ptr::null_mut();
+        }
+
+        let inner = OwnedFileHandle::from_raw(inner);
+        FileHandle::for_concurrent_writer(SequencedWriter::new(inner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn writes_are_numbered() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let inner = FileHandle::for_writer(buffer.clone());
+            let handle = new_sequenced_file_handle(inner);
+
+            let ret = file_handle_write(handle, b"first\n".as_ptr().cast(), 6);
+            assert_eq!(ret, 6);
+            file_handle_write(handle, b"second\n".as_ptr().cast(), 7);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(
+            buffer.0.lock().unwrap().as_slice(),
+            b"0 first\n1 second\n"
+        );
+    }
+
+    #[test]
+    fn clones_share_a_counter() {
+        let buffer = SharedBuffer::default();
+        let inner = OwnedFileHandle::new(buffer.clone());
+        let mut first =
+            SequencedWriter::new(inner).with_format(SequenceFormat::Binary);
+        let mut second = first.clone();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mut writer = first.clone();
+                std::thread::spawn(move || writer.write_all(b"ab").unwrap())
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        second.write_all(b"c").unwrap();
+        first.flush().unwrap();
+        assert_eq!(first.next_sequence(), 5);
+
+        let written = buffer.0.lock().unwrap();
+        let mut expected = Vec::new();
+        for sequence in 0..4_u64 {
+            expected.extend_from_slice(&sequence.to_le_bytes());
+            expected.extend_from_slice(&2_u32.to_le_bytes());
+            expected.extend_from_slice(b"ab");
+        }
+        expected.extend_from_slice(&4_u64.to_le_bytes());
+        expected.extend_from_slice(&1_u32.to_le_bytes());
+        expected.push(b'c');
+        assert_eq!(written.as_slice(), expected.as_slice());
+    }
+}
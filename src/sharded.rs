@@ -0,0 +1,228 @@
+//! A [`FileHandle`] which spreads writes from many threads across a set of
+//! lazily created inner handles.
+
+use crate::{thread_stats::current_thread_id, FileHandle, OwnedFileHandle};
+use std::{
+    io::{Error, ErrorKind, Write},
+    os::raw::c_int,
+    ptr,
+    sync::Mutex,
+};
+
+type Factory = dyn Fn(usize) -> Result<OwnedFileHandle, Error> + Send + Sync;
+
+/// A writer which gives each thread its own "shard", so threads logging
+/// through the same handle don't all contend on a single lock.
+///
+/// Each shard is created on first use by calling the factory with the shard's
+/// index. Writes go straight to the calling thread's shard (e.g. a per-shard
+/// file), while flushing will flush every shard created so far.
+///
+/// Because `&ShardedWriter` implements [`Write`], it can be wrapped with
+/// [`FileHandle::for_concurrent_writer()`] and shared between threads without
+/// any external synchronisation.
+pub struct ShardedWriter {
+    factory: Box<Factory>,
+    shards: Box<[Mutex<Option<OwnedFileHandle>>]>,
+}
+
+impl ShardedWriter {
+    /// Create a new [`ShardedWriter`] which will use up to `shards` inner
+    /// handles.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `shards` is zero.
+    pub fn new<F>(shards: usize, factory: F) -> Self
+    where
+        F: Fn(usize) -> Result<OwnedFileHandle, Error> + Send + Sync + 'static,
+    {
+        assert!(shards > 0, "There must be at least one shard");
+
+        ShardedWriter {
+            factory: Box::new(factory),
+            shards: (0..shards).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    /// The number of shards being used.
+    pub fn shards(&self) -> usize { self.shards.len() }
+
+    fn with_shard<F, T>(&self, func: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut OwnedFileHandle) -> Result<T, Error>,
+    {
+        let index = (current_thread_id() % self.shards.len() as u64) as usize;
+        let mut shard = self.shards[index]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if shard.is_none() {
+            *shard = Some((self.factory)(index)?);
+        }
+
+        func(shard.as_mut().expect("The shard was just initialized"))
+    }
+}
+
+impl Write for &ShardedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.with_shard(|handle| handle.write(buf))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap_or_else(|e| e.into_inner());
+
+            if let Some(handle) = shard.as_mut() {
+                let ret = handle.flush();
+                // make sure every shard gets flushed, even if one fails
+                if result.is_ok() {
+                    result = ret;
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl Write for ShardedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { (&*self).flush() }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which gives each thread its own inner
+    /// handle.
+    ///
+    /// The `factory` is called lazily with a shard number in `0..shards` the
+    /// first time a thread mapped to that shard writes something, and may
+    /// return `null` to indicate failure. Unlike most handles, the returned
+    /// [`FileHandle`] may be written to from multiple threads at the same time.
+    ///
+    /// Returns `null` if `shards` isn't positive.
+    pub unsafe extern "C" fn new_sharded_file_handle(
+        factory: unsafe extern "C" fn(c_int) -> *mut FileHandle,
+        shards: c_int,
+    ) -> *mut FileHandle {
+        if shards <= 0 {
+            return ptr::null_mut();
+        }
+
+        let writer = ShardedWriter::new(shards as usize, move |shard| {
+            let handle = factory(shard as c_int);
+
+            if handle.is_null() {
+                Err(Error::new(ErrorKind::Other, "Unable to create the shard"))
+            } else {
+                Ok(OwnedFileHandle::from_raw(handle))
+            }
+        });
+
+        FileHandle::for_concurrent_writer(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    struct SendPtr(*mut FileHandle);
+    unsafe impl Send for SendPtr {}
+    unsafe impl Sync for SendPtr {}
+
+    #[test]
+    fn shards_are_created_lazily() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&created);
+        let mut writer = ShardedWriter::new(4, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(OwnedFileHandle::new(std::io::sink()))
+        });
+        assert_eq!(created.load(Ordering::SeqCst), 0);
+
+        writer.write_all(b"Hello, World!").unwrap();
+        writer.write_all(b"Hello, World!").unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn write_from_many_threads_at_once() {
+        let buffer = SharedBuffer::default();
+        let shard_buffer = buffer.clone();
+        let handle = SendPtr(FileHandle::for_concurrent_writer(
+            ShardedWriter::new(3, move |_| {
+                Ok(OwnedFileHandle::new(shard_buffer.clone()))
+            }),
+        ));
+        let handle = Arc::new(handle);
+        let msg = "Hello, World!";
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let handle = Arc::clone(&handle);
+                std::thread::spawn(move || unsafe {
+                    for _ in 0..100 {
+                        let ret = file_handle_write(
+                            handle.0,
+                            msg.as_ptr() as *const _,
+                            msg.len() as _,
+                        );
+                        assert_eq!(ret, msg.len() as c_int);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        unsafe {
+            assert_eq!(file_handle_flush(handle.0), 0);
+            file_handle_destroy(handle.0);
+        }
+        assert_eq!(buffer.0.lock().unwrap().len(), 8 * 100 * msg.len());
+    }
+
+    #[test]
+    fn failing_factory_is_reported_as_an_error() {
+        unsafe extern "C" fn factory(_shard: c_int) -> *mut FileHandle {
+            ptr::null_mut()
+        }
+
+        unsafe {
+            let handle = new_sharded_file_handle(factory, 2);
+            assert!(!handle.is_null());
+
+            let ret = file_handle_write(handle, b"asdf".as_ptr() as _, 4);
+            assert!(ret < 0);
+
+            file_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn zero_shards_is_rejected() {
+        unsafe extern "C" fn factory(_shard: c_int) -> *mut FileHandle {
+            new_null_file_handle()
+        }
+
+        unsafe {
+            assert!(new_sharded_file_handle(factory, 0).is_null());
+        }
+    }
+}
@@ -0,0 +1,465 @@
+//! Letting several producers (e.g. plugins) write to one physical sink
+//! without a noisy one starving the rest, using per-producer priorities and
+//! rate limits.
+
+use crate::{
+    errors::{crate_status_code, crate_status_error, CRATE_ERROR_RATE_LIMITED},
+    global_clock, Clock, FileHandle, OwnedFileHandle,
+};
+use std::{
+    cmp::Reverse,
+    io::{Error, ErrorKind, Write},
+    os::raw::c_int,
+    ptr,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// Returned by writes to a producer which has used up its
+/// [rate][file_handle_set_rate()] for the current interval.
+///
+/// This is [`CRATE_ERROR_RATE_LIMITED`] in the [`ErrorDomain::Crate`]
+/// domain, so it can't be confused with [`FILE_HANDLE_SUSPENDED`].
+///
+/// [`CRATE_ERROR_RATE_LIMITED`]: crate::CRATE_ERROR_RATE_LIMITED
+/// [`ErrorDomain::Crate`]: crate::ErrorDomain::Crate
+/// [`FILE_HANDLE_SUSPENDED`]: crate::FILE_HANDLE_SUSPENDED
+pub const FILE_HANDLE_RATE_LIMITED: c_int =
+    crate_status_code(CRATE_ERROR_RATE_LIMITED);
+
+/// One sink shared by any number of producer handles.
+///
+/// When several producers want to use the sink at once, the one with the
+/// highest priority goes first, and producers with the same priority take
+/// turns in the order they arrived. Giving a producer a rate limit stops it
+/// from hogging the sink no matter what its priority is.
+///
+/// ```rust
+/// # use std::{io::Write, time::Duration};
+/// # use thin_trait_objects::{OwnedFileHandle, SharedSink, SharedSinkProducer};
+/// let sink = SharedSink::new(OwnedFileHandle::new(Vec::<u8>::new()));
+///
+/// let mut noisy = sink.producer();
+/// let producer = noisy.downcast_ref::<SharedSinkProducer>().unwrap();
+/// producer.set_rate(5, Duration::from_secs(60));
+///
+/// noisy.write_all(b"Hello").unwrap();
+/// assert!(noisy.write_all(b", World!").is_err());
+/// ```
+#[derive(Debug, Clone)]
+pub struct SharedSink {
+    arbiter: Arc<Arbiter>,
+}
+
+impl SharedSink {
+    /// Start sharing `sink`.
+    pub fn new(sink: OwnedFileHandle) -> Self {
+        SharedSink::with_clock(sink, global_clock())
+    }
+
+    /// Start sharing `sink`, measuring rate limit intervals with `clock`.
+    pub fn with_clock(sink: OwnedFileHandle, clock: Arc<dyn Clock>) -> Self {
+        SharedSink {
+            arbiter: Arc::new(Arbiter {
+                sink: Mutex::new(sink),
+                queue: Mutex::new(Queue::default()),
+                changed: Condvar::new(),
+                clock,
+            }),
+        }
+    }
+
+    /// Create a new handle which writes to the sink, with a priority of `0`
+    /// and no rate limit.
+    ///
+    /// The sink stays alive until the [`SharedSink`] and every producer have
+    /// been dropped.
+    pub fn producer(&self) -> OwnedFileHandle {
+        OwnedFileHandle::new(SharedSinkProducer {
+            arbiter: Arc::clone(&self.arbiter),
+            priority: AtomicI32::new(0),
+            rate: Mutex::new(None),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Arbiter {
+    sink: Mutex<OwnedFileHandle>,
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    clock: Arc<dyn Clock>,
+}
+
+/// The producers waiting for their turn.
+#[derive(Debug, Default)]
+struct Queue {
+    busy: bool,
+    /// The priority and ticket number of each waiting producer.
+    waiting: Vec<(i32, u64)>,
+    next_ticket: u64,
+}
+
+impl Queue {
+    /// Who should go next (the highest priority, then the oldest ticket).
+    fn next(&self) -> Option<(i32, u64)> {
+        self.waiting
+            .iter()
+            .copied()
+            .max_by_key(|&(priority, ticket)| (priority, Reverse(ticket)))
+    }
+}
+
+impl Arbiter {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until it's our turn to use the sink.
+    fn turn(&self, priority: i32) -> Turn<'_> {
+        let mut queue = self.queue();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.waiting.push((priority, ticket));
+
+        while queue.busy || queue.next() != Some((priority, ticket)) {
+            queue = self.changed.wait(queue).unwrap_or_else(|e| e.into_inner());
+        }
+
+        queue.waiting.retain(|&(_, t)| t != ticket);
+        queue.busy = true;
+        drop(queue);
+
+        Turn {
+            arbiter: self,
+            // the sink handles its own panics (see is_poisoned())
+            sink: self.sink.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+}
+
+/// Exclusive access to the sink, handed to the next producer when dropped.
+struct Turn<'a> {
+    arbiter: &'a Arbiter,
+    sink: MutexGuard<'a, OwnedFileHandle>,
+}
+
+impl<'a> Drop for Turn<'a> {
+    fn drop(&mut self) {
+        self.arbiter.queue().busy = false;
+        self.arbiter.changed.notify_all();
+    }
+}
+
+/// How much a producer may write per interval.
+#[derive(Debug)]
+struct Rate {
+    max_bytes: u64,
+    interval: Duration,
+    started: Instant,
+    used: u64,
+}
+
+/// The writer behind each of a [`SharedSink`]'s producer handles.
+///
+/// Use [`OwnedFileHandle::downcast_ref()`] to get at it and change the
+/// producer's settings.
+#[derive(Debug)]
+pub struct SharedSinkProducer {
+    arbiter: Arc<Arbiter>,
+    priority: AtomicI32,
+    rate: Mutex<Option<Rate>>,
+}
+
+impl SharedSinkProducer {
+    /// The producer's priority, where higher values go first.
+    pub fn priority(&self) -> i32 { self.priority.load(Ordering::Relaxed) }
+
+    /// Change the producer's priority.
+    pub fn set_priority(&self, priority: i32) {
+        self.priority.store(priority, Ordering::Relaxed);
+    }
+
+    /// Only let the producer write `max_bytes` every `interval`.
+    ///
+    /// A write which would go over the limit only writes what's left, and
+    /// every write after that fails with [`FILE_HANDLE_RATE_LIMITED`] until
+    /// the next interval starts.
+    pub fn set_rate(&self, max_bytes: u64, interval: Duration) {
+        *self.rate() = Some(Rate {
+            max_bytes,
+            interval,
+            started: self.arbiter.clock.now(),
+            used: 0,
+        });
+    }
+
+    /// Stop rate limiting the producer.
+    pub fn clear_rate(&self) { *self.rate() = None; }
+
+    fn rate(&self) -> MutexGuard<'_, Option<Rate>> {
+        self.rate.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Work out how much of a `len` byte write can go ahead.
+    fn reserve(&self, len: usize) -> Result<usize, Error> {
+        let mut rate = self.rate();
+        let rate = match rate.as_mut() {
+            Some(rate) => rate,
+            None => return Ok(len),
+        };
+
+        let now = self.arbiter.clock.now();
+        if now.saturating_duration_since(rate.started) >= rate.interval {
+            rate.started = now;
+            rate.used = 0;
+        }
+
+        let granted = rate.max_bytes.saturating_sub(rate.used).min(len as u64);
+        if granted == 0 && len > 0 {
+            return Err(crate_status_error(
+                ErrorKind::WouldBlock,
+                CRATE_ERROR_RATE_LIMITED,
+            ));
+        }
+
+        rate.used += granted;
+        Ok(granted as usize)
+    }
+
+    /// Give back whatever was reserved but not written.
+    fn settle(&self, reserved: usize, written: usize) {
+        if let Some(rate) = self.rate().as_mut() {
+            let unused = reserved.saturating_sub(written) as u64;
+            rate.used = rate.used.saturating_sub(unused);
+        }
+    }
+}
+
+impl Write for SharedSinkProducer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let granted = self.reserve(buf.len())?;
+
+        let result = self
+            .arbiter
+            .turn(self.priority())
+            .sink
+            .write(&buf[..granted]);
+        self.settle(granted, *result.as_ref().unwrap_or(&0));
+
+        result
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.arbiter.turn(self.priority()).sink.flush()
+    }
+}
+
+export! {
+    /// Start sharing `sink` between producer handles created with
+    /// [`shared_sink_new_producer()`], taking ownership of it.
+    ///
+    /// The [`SharedSink`] must be released with [`shared_sink_destroy()`].
+    /// Returns `null` if `sink` is `null`.
+    pub unsafe extern "C" fn new_shared_sink(
+        sink: *mut FileHandle,
+    ) -> *mut SharedSink {
+        if sink.is_null() {
+            return ptr::null_mut();
+        }
+
+        let sink = SharedSink::new(OwnedFileHandle::from_raw(sink));
+        Box::into_raw(Box::new(sink))
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes to the shared sink.
+    ///
+    /// Use [`file_handle_set_priority()`] and [`file_handle_set_rate()`] to
+    /// stop it from starving the sink's other producers.
+    pub unsafe extern "C" fn shared_sink_new_producer(
+        sink: *mut SharedSink,
+    ) -> *mut FileHandle {
+        (*sink).producer().into_raw()
+    }
+}
+
+export! {
+    /// Release a [`SharedSink`].
+    ///
+    /// The sink itself is destroyed once every producer has been destroyed
+    /// too.
+    pub unsafe extern "C" fn shared_sink_destroy(sink: *mut SharedSink) {
+        if !sink.is_null() {
+            drop(Box::from_raw(sink));
+        }
+    }
+}
+
+/// Get the producer behind a handle created by [`shared_sink_new_producer()`].
+unsafe fn producer<'a>(
+    handle: *mut FileHandle,
+) -> Option<&'a SharedSinkProducer> {
+    FileHandle::downcast_raw::<SharedSinkProducer>(handle).map(|p| &*p)
+}
+
+export! {
+    /// Set the priority of a producer created with
+    /// [`shared_sink_new_producer()`], where producers with higher priorities
+    /// get to use the sink first.
+    ///
+    /// Returns [`FILE_HANDLE_UNSUPPORTED`] if the handle isn't a producer.
+    ///
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub unsafe extern "C" fn file_handle_set_priority(
+        handle: *mut FileHandle,
+        priority: c_int,
+    ) -> c_int {
+        match producer(handle) {
+            Some(producer) => {
+                producer.set_priority(priority);
+                0
+            },
+            None => crate::FILE_HANDLE_UNSUPPORTED,
+        }
+    }
+}
+
+export! {
+    /// Only let a producer created with [`shared_sink_new_producer()`] write
+    /// `max_bytes` every `interval_ms` milliseconds, or remove the limit when
+    /// `interval_ms` is `0`.
+    ///
+    /// Writes over the limit fail with [`FILE_HANDLE_RATE_LIMITED`]. Returns
+    /// [`FILE_HANDLE_UNSUPPORTED`] if the handle isn't a producer.
+    ///
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub unsafe extern "C" fn file_handle_set_rate(
+        handle: *mut FileHandle,
+        max_bytes: u64,
+        interval_ms: u32,
+    ) -> c_int {
+        match producer(handle) {
+            Some(producer) if interval_ms == 0 => {
+                producer.clear_rate();
+                0
+            },
+            Some(producer) => {
+                let interval = Duration::from_millis(interval_ms.into());
+                producer.set_rate(max_bytes, interval);
+                0
+            },
+            None => crate::FILE_HANDLE_UNSUPPORTED,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ffi::{tests::SharedBuffer, *},
+        ManualClock,
+    };
+
+    fn settings(handle: &OwnedFileHandle) -> &SharedSinkProducer {
+        handle.downcast_ref().unwrap()
+    }
+
+    #[test]
+    fn rate_limits_reset_every_interval() {
+        let clock = Arc::new(ManualClock::default());
+        let buffer = SharedBuffer::default();
+        let sink = SharedSink::with_clock(
+            OwnedFileHandle::new(buffer.clone()),
+            Arc::clone(&clock) as Arc<dyn Clock>,
+        );
+        let mut noisy = sink.producer();
+        let mut quiet = sink.producer();
+        settings(&noisy).set_rate(4, Duration::from_secs(1));
+
+        assert_eq!(noisy.write(b"Hello").unwrap(), 4);
+        let err = noisy.write(b"o").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let code = crate::encode_error(&err);
+        assert_eq!(code, FILE_HANDLE_RATE_LIMITED);
+        quiet.write_all(b", World").unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        noisy.write_all(b"o!").unwrap();
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"Hell, Worldo!");
+    }
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let buffer = SharedBuffer::default();
+        let sink = SharedSink::new(OwnedFileHandle::new(buffer.clone()));
+
+        // keep the sink busy until everyone is queued up
+        let turn = sink.arbiter.turn(0);
+
+        let threads: Vec<_> = [(1, "low "), (5, "high "), (1, "low again")]
+            .iter()
+            .map(|&(priority, msg)| {
+                let mut producer = sink.producer();
+                settings(&producer).set_priority(priority);
+                let queued = sink.arbiter.queue().waiting.len();
+
+                let thread = std::thread::spawn(move || {
+                    producer.write_all(msg.as_bytes()).unwrap();
+                });
+                while sink.arbiter.queue().waiting.len() == queued {
+                    std::thread::yield_now();
+                }
+                thread
+            })
+            .collect();
+
+        drop(turn);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let written = buffer.0.lock().unwrap().clone();
+        assert_eq!(written, b"high low low again");
+    }
+
+    #[test]
+    fn configure_producers_from_c() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let sink = FileHandle::for_writer(buffer.clone());
+            let sink = new_shared_sink(sink);
+            let producer = shared_sink_new_producer(sink);
+            shared_sink_destroy(sink);
+
+            assert_eq!(file_handle_set_priority(producer, 3), 0);
+            assert_eq!(file_handle_set_rate(producer, 2, 1000), 0);
+            let ret = file_handle_write(producer, b"asdf".as_ptr().cast(), 4);
+            assert_eq!(ret, 2);
+            let ret = file_handle_write(producer, b"df".as_ptr().cast(), 2);
+            assert_eq!(ret, FILE_HANDLE_RATE_LIMITED);
+            assert_ne!(ret, FILE_HANDLE_SUSPENDED);
+            let name = std::ffi::CStr::from_ptr(file_handle_status_name(ret));
+            assert_eq!(name.to_str().unwrap(), "RateLimited");
+            assert_eq!(file_handle_set_rate(producer, 0, 0), 0);
+            let ret = file_handle_write(producer, b"df".as_ptr().cast(), 2);
+            assert_eq!(ret, 2);
+            file_handle_destroy(producer);
+
+            let other = new_null_file_handle();
+            let ret = file_handle_set_priority(other, 1);
+            assert_eq!(ret, FILE_HANDLE_UNSUPPORTED);
+            file_handle_destroy(other);
+            assert!(new_shared_sink(ptr::null_mut()).is_null());
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"asdf");
+    }
+}
@@ -0,0 +1,118 @@
+//! A [`FileHandle`] which only accepts part of each write, for testing code
+//! which needs to handle partial writes.
+
+use crate::{FileHandle, OwnedFileHandle};
+use std::{
+    io::{Error, Write},
+    ptr,
+};
+
+/// A writer which passes at most `max_per_call` bytes of each write to an
+/// inner handle.
+///
+/// Real sockets and pipes only accept part of a write when they're under
+/// backpressure, so code which forgets to retry the rest usually works fine
+/// until it's deployed. Wrapping a handle in a [`ShortWriter`] makes those
+/// bugs show up straight away.
+pub struct ShortWriter {
+    inner: OwnedFileHandle,
+    max_per_call: usize,
+}
+
+impl ShortWriter {
+    /// Create a new [`ShortWriter`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_per_call` is zero, because a writer which never
+    /// accepts anything would make every write loop spin forever.
+    pub fn new(inner: OwnedFileHandle, max_per_call: usize) -> Self {
+        assert!(max_per_call > 0, "Writes must accept at least one byte");
+
+        ShortWriter {
+            inner,
+            max_per_call,
+        }
+    }
+
+    /// The maximum number of bytes accepted by each write.
+    pub fn max_per_call(&self) -> usize { self.max_per_call }
+
+    /// The handle being wrapped.
+    pub(crate) fn get_ref(&self) -> &OwnedFileHandle { &self.inner }
+}
+
+impl Write for ShortWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let len = buf.len().min(self.max_per_call);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.inner.flush() }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which writes at most `max_per_call` bytes of
+    /// each write to `inner`, taking ownership of it.
+    ///
+    /// Returns `null` if `inner` is `null` or `max_per_call` is zero, in which
+    /// case `inner` is left untouched.
+    pub unsafe extern "C" fn new_short_write_file_handle(
+        inner: *mut FileHandle,
+        max_per_call: usize,
+    ) -> *mut FileHandle {
+        if inner.is_null() || max_per_call == 0 {
+            return ptr::null_mut();
+        }
+
+        let inner = OwnedFileHandle::from_raw(inner);
+        FileHandle::for_writer(ShortWriter::new(inner, max_per_call))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn writes_are_capped() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let inner = FileHandle::for_writer(buffer.clone());
+            let handle = new_short_write_file_handle(inner, 3);
+
+            let ret = file_handle_write(handle, b"Hello".as_ptr().cast(), 5);
+            assert_eq!(ret, 3);
+            let ret = file_handle_write(handle, b"lo".as_ptr().cast(), 2);
+            assert_eq!(ret, 2);
+
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"Hello");
+    }
+
+    #[test]
+    fn write_all_still_writes_everything() {
+        let buffer = SharedBuffer::default();
+        let inner = OwnedFileHandle::new(buffer.clone());
+        let mut writer = ShortWriter::new(inner, 1);
+
+        writer.write_all(b"Hello, World!").unwrap();
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"Hello, World!");
+    }
+
+    #[test]
+    fn a_zero_cap_is_rejected() {
+        unsafe {
+            let inner = new_null_file_handle();
+
+            assert!(new_short_write_file_handle(inner, 0).is_null());
+
+            file_handle_destroy(inner);
+        }
+    }
+}
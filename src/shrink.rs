@@ -0,0 +1,262 @@
+//! Giving memory back after a burst of writes, so a long-running host's
+//! memory usage doesn't stay at its peak forever.
+
+use crate::{
+    budget::{self, MemoryBuffer},
+    optional::unsupported,
+    BoundedBuffer, ChunkedBuffer, FileHandle, OwnedFileHandle,
+};
+use std::{
+    io::Error,
+    os::raw::c_int,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Passed to [`file_handle_set_shrink_policy()`] to turn automatic
+/// shrinking off.
+pub const SHRINK_NEVER: usize = usize::MAX;
+
+/// When a memory handle should automatically release unused capacity.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShrinkPolicy {
+    /// Only shrink when asked to (the default).
+    Never,
+    /// Shrink whenever the handle is flushed and more than this many bytes
+    /// of capacity are unused.
+    MaxSpare(usize),
+}
+
+impl Default for ShrinkPolicy {
+    fn default() -> Self { ShrinkPolicy::Never }
+}
+
+/// Where a handle's [`ShrinkPolicy`] is kept.
+#[derive(Debug)]
+pub(crate) struct ShrinkSlot(AtomicUsize);
+
+impl ShrinkSlot {
+    fn policy(&self) -> ShrinkPolicy {
+        match self.0.load(Ordering::Relaxed) {
+            SHRINK_NEVER => ShrinkPolicy::Never,
+            max_spare => ShrinkPolicy::MaxSpare(max_spare),
+        }
+    }
+
+    fn set(&self, policy: ShrinkPolicy) {
+        let max_spare = match policy {
+            ShrinkPolicy::Never => SHRINK_NEVER,
+            ShrinkPolicy::MaxSpare(max_spare) => {
+                max_spare.min(SHRINK_NEVER - 1)
+            },
+        };
+        self.0.store(max_spare, Ordering::Relaxed);
+    }
+}
+
+impl Default for ShrinkSlot {
+    fn default() -> Self { ShrinkSlot(AtomicUsize::new(SHRINK_NEVER)) }
+}
+
+/// The number of bytes a memory handle has allocated but isn't using.
+unsafe fn spare_capacity(handle: *mut FileHandle) -> Option<usize> {
+    if let Some(buffer) = FileHandle::downcast_raw::<Vec<u8>>(handle) {
+        Some((*buffer).capacity() - (*buffer).len())
+    } else if let Some(buffer) =
+        FileHandle::downcast_raw::<ChunkedBuffer>(handle)
+    {
+        Some((*buffer).spare_capacity())
+    } else {
+        FileHandle::downcast_raw::<BoundedBuffer>(handle)
+            .map(|buffer| (*buffer).spare_capacity())
+    }
+}
+
+unsafe fn shrink_to_fit(handle: *mut FileHandle) -> Result<(), Error> {
+    let allocated = if let Some(buffer) =
+        FileHandle::downcast_raw::<Vec<u8>>(handle)
+    {
+        (*buffer).shrink_to_fit();
+        (*buffer).allocated()
+    } else if let Some(buffer) =
+        FileHandle::downcast_raw::<ChunkedBuffer>(handle)
+    {
+        (*buffer).shrink_to_fit();
+        (*buffer).allocated()
+    } else if let Some(buffer) =
+        FileHandle::downcast_raw::<BoundedBuffer>(handle)
+    {
+        (*buffer).shrink_to_fit();
+        (*buffer).allocated()
+    } else {
+        return Err(unsupported());
+    };
+
+    budget::release_unused(handle, allocated);
+    Ok(())
+}
+
+unsafe fn set_shrink_policy(
+    handle: *mut FileHandle,
+    policy: ShrinkPolicy,
+) -> Result<(), Error> {
+    if spare_capacity(handle).is_none() {
+        return Err(unsupported());
+    }
+
+    (*handle).extensions_or_default().shrink.set(policy);
+    Ok(())
+}
+
+/// Apply the handle's [`ShrinkPolicy`] after a successful flush.
+pub(crate) unsafe fn after_flush(handle: *mut FileHandle) {
+    let policy = match (*handle).extensions() {
+        Some(ext) => ext.shrink.policy(),
+        None => return,
+    };
+
+    if let ShrinkPolicy::MaxSpare(max_spare) = policy {
+        if spare_capacity(handle).map_or(false, |spare| spare > max_spare) {
+            let _ = shrink_to_fit(handle);
+        }
+    }
+}
+
+impl OwnedFileHandle {
+    /// Release any capacity a memory handle (e.g. one created with
+    /// [`new_memory_file_handle()`]) has allocated but isn't using.
+    ///
+    /// Fails with an "unsupported" error for other handles.
+    ///
+    /// [`new_memory_file_handle()`]: crate::new_memory_file_handle
+    pub fn shrink_to_fit(&mut self) -> Result<(), Error> {
+        unsafe { shrink_to_fit(self.as_ptr()) }
+    }
+
+    /// Set when a memory handle should automatically release unused
+    /// capacity.
+    ///
+    /// Fails with an "unsupported" error for handles which aren't memory
+    /// handles.
+    pub fn set_shrink_policy(
+        &mut self,
+        policy: ShrinkPolicy,
+    ) -> Result<(), Error> {
+        unsafe { set_shrink_policy(self.as_ptr(), policy) }
+    }
+
+    /// Builder-style version of [`OwnedFileHandle::set_shrink_policy()`],
+    /// which leaves handles that aren't memory handles untouched.
+    ///
+    /// ```rust
+    /// # use thin_trait_objects::{OwnedFileHandle, ShrinkPolicy};
+    /// let handle = OwnedFileHandle::new(Vec::<u8>::new())
+    ///     .with_shrink_policy(ShrinkPolicy::MaxSpare(64 * 1024));
+    /// ```
+    pub fn with_shrink_policy(mut self, policy: ShrinkPolicy) -> Self {
+        let _ = self.set_shrink_policy(policy);
+        self
+    }
+}
+
+export! {
+    /// Release any capacity a memory handle (created with
+    /// [`new_memory_file_handle()`], [`new_chunked_memory_file_handle()`], or
+    /// [`new_bounded_memory_file_handle()`]) has allocated but isn't using.
+    ///
+    /// Returns `0` on success, or [`FILE_HANDLE_UNSUPPORTED`] for other
+    /// handles.
+    ///
+    /// [`new_memory_file_handle()`]: crate::new_memory_file_handle
+    /// [`new_chunked_memory_file_handle()`]:
+    /// crate::new_chunked_memory_file_handle
+    /// [`new_bounded_memory_file_handle()`]:
+    /// crate::new_bounded_memory_file_handle
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub unsafe extern "C" fn file_handle_shrink_to_fit(
+        handle: *mut FileHandle,
+    ) -> c_int {
+        match shrink_to_fit(handle) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+export! {
+    /// Make a memory handle release its unused capacity every time it is
+    /// flushed while more than `max_spare` bytes are unused, or turn this off
+    /// again with [`SHRINK_NEVER`]. Best called straight after creating the
+    /// handle.
+    ///
+    /// Returns `0` on success, or [`FILE_HANDLE_UNSUPPORTED`] if this isn't a
+    /// memory handle (see [`file_handle_shrink_to_fit()`]).
+    ///
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub unsafe extern "C" fn file_handle_set_shrink_policy(
+        handle: *mut FileHandle,
+        max_spare: usize,
+    ) -> c_int {
+        let policy = match max_spare {
+            SHRINK_NEVER => ShrinkPolicy::Never,
+            max_spare => ShrinkPolicy::MaxSpare(max_spare),
+        };
+
+        match set_shrink_policy(handle, policy) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, OverflowPolicy};
+    use std::io::Write;
+
+    #[test]
+    fn shrink_memory_handles_from_c() {
+        unsafe {
+            let handle = new_memory_file_handle();
+            let buffer = FileHandle::downcast_raw::<Vec<u8>>(handle).unwrap();
+            (*buffer).reserve(1 << 20);
+            file_handle_write(handle, b"asdf".as_ptr().cast(), 4);
+
+            assert_eq!(file_handle_shrink_to_fit(handle), 0);
+
+            assert!((*buffer).capacity() < 1024);
+            assert_eq!(file_handle_as_memory(handle).as_slice(), b"asdf");
+            file_handle_destroy(handle);
+
+            let other = new_null_file_handle();
+            let ret = file_handle_shrink_to_fit(other);
+            assert_eq!(ret, FILE_HANDLE_UNSUPPORTED);
+            let ret = file_handle_set_shrink_policy(other, 0);
+            assert_eq!(ret, FILE_HANDLE_UNSUPPORTED);
+            file_handle_destroy(other);
+        }
+    }
+
+    #[test]
+    fn automatically_shrink_when_flushed() {
+        let mut handle = OwnedFileHandle::new(BoundedBuffer::new(
+            16,
+            OverflowPolicy::Ring,
+        ))
+        .with_shrink_policy(ShrinkPolicy::MaxSpare(0));
+
+        for _ in 0..1000 {
+            handle.write_all(b"a").unwrap();
+        }
+        // a large write evicts all the small ones
+        handle.write_all(&[b'b'; 16]).unwrap();
+        let buffer = handle.downcast_ref::<BoundedBuffer>().unwrap();
+        assert!(buffer.spare_capacity() > 0);
+
+        handle.flush().unwrap();
+
+        let buffer = handle.downcast_ref::<BoundedBuffer>().unwrap();
+        assert_eq!(buffer.spare_capacity(), 0);
+        assert_eq!(buffer.contents(), [b'b'; 16]);
+    }
+}
@@ -0,0 +1,300 @@
+//! Moving data between handles backed by file descriptors without copying it
+//! through userspace.
+
+use crate::{copy, Capabilities, FileHandle, ReadHandle};
+use std::{io::Error, os::raw::c_int};
+
+/// Copy up to `len` bytes from `reader` into `writer`, letting the kernel
+/// move the data when both sides are file descriptors.
+pub(crate) unsafe fn splice(
+    reader: *mut ReadHandle,
+    writer: *mut FileHandle,
+    len: u64,
+    copied: &mut u64,
+) -> Result<(), Error> {
+    FileHandle::check_frozen(writer)?;
+    FileHandle::check_timed_out(writer)?;
+
+    if let Some((from, to)) = raw_fds(reader, writer) {
+        if kernel::transfer(from, to, len, copied)? {
+            return Ok(());
+        }
+    }
+
+    let remaining = len - *copied;
+    copy::copy_up_to(reader, writer, None, remaining, copied).map(|_| ())
+}
+
+/// Get the file descriptors on either side, if the data doesn't need to go
+/// through the [`FileHandle`]'s write path.
+unsafe fn raw_fds(
+    reader: *mut ReadHandle,
+    writer: *mut FileHandle,
+) -> Option<(c_int, c_int)> {
+    if (*writer).is_poisoned()
+        || !(*writer).capabilities().contains(Capabilities::RAW_FD)
+    {
+        return None;
+    }
+
+    // Note: the kernel would bypass any quota or write filter
+    if let Some(ext) = (*writer).extensions() {
+        if ext.quota.is_enabled() || ext.write_filter.is_enabled() {
+            return None;
+        }
+    }
+
+    let (from, to) = fds(reader, writer);
+    if from < 0 || to < 0 {
+        None
+    } else {
+        Some((from, to))
+    }
+}
+
+#[cfg(unix)]
+unsafe fn fds(
+    reader: *mut ReadHandle,
+    writer: *mut FileHandle,
+) -> (c_int, c_int) {
+    (crate::read_handle_as_fd(reader), crate::file_handle_as_fd(writer))
+}
+
+#[cfg(not(unix))]
+unsafe fn fds(_: *mut ReadHandle, _: *mut FileHandle) -> (c_int, c_int) {
+    (-1, -1)
+}
+
+#[cfg(target_os = "linux")]
+mod kernel {
+    use std::{
+        io::Error,
+        os::raw::{c_int, c_long, c_uint},
+        ptr,
+    };
+
+    extern "C" {
+        fn sendfile(
+            out_fd: c_int,
+            in_fd: c_int,
+            offset: *mut i64,
+            count: usize,
+        ) -> isize;
+        fn splice(
+            fd_in: c_int,
+            off_in: *mut i64,
+            fd_out: c_int,
+            off_out: *mut i64,
+            len: usize,
+            flags: c_uint,
+        ) -> isize;
+        fn syscall(number: c_long, ...) -> c_long;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const SYS_COPY_FILE_RANGE: Option<c_long> = Some(326);
+    #[cfg(target_arch = "aarch64")]
+    const SYS_COPY_FILE_RANGE: Option<c_long> = Some(285);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const SYS_COPY_FILE_RANGE: Option<c_long> = None;
+
+    /// The most sendfile() will move in one call.
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    const EXDEV: c_int = 18;
+    const EINVAL: c_int = 22;
+    const ENOSYS: c_int = 38;
+    const EOPNOTSUPP: c_int = 95;
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    enum Method {
+        /// Between regular files, possibly without touching the data at all.
+        CopyFileRange,
+        /// From something which can be memory-mapped (e.g. a file).
+        SendFile,
+        /// When either side is a pipe.
+        Splice,
+    }
+
+    /// Move up to `len` bytes, trying each method in turn.
+    ///
+    /// Returns `Ok(false)` if none of them work with these file descriptors,
+    /// in which case nothing was moved.
+    pub(super) unsafe fn transfer(
+        from: c_int,
+        to: c_int,
+        len: u64,
+        copied: &mut u64,
+    ) -> Result<bool, Error> {
+        let methods = [Method::CopyFileRange, Method::SendFile, Method::Splice];
+
+        for &method in &methods {
+            if method == Method::CopyFileRange && SYS_COPY_FILE_RANGE.is_none()
+            {
+                continue;
+            }
+            if transfer_with(method, from, to, len, copied)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    unsafe fn transfer_with(
+        method: Method,
+        from: c_int,
+        to: c_int,
+        len: u64,
+        copied: &mut u64,
+    ) -> Result<bool, Error> {
+        let mut moved = 0;
+
+        while moved < len {
+            let count = (len - moved).min(MAX_CHUNK) as usize;
+            let ret = call(method, from, to, count);
+
+            if ret > 0 {
+                moved += ret as u64;
+                *copied += ret as u64;
+                continue;
+            } else if ret == 0 {
+                // end of the stream
+                break;
+            }
+
+            let e = Error::last_os_error();
+            match e.raw_os_error() {
+                Some(4) => continue, // EINTR
+                Some(code) if moved == 0 && is_unsupported(code) => {
+                    return Ok(false);
+                },
+                _ => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+
+    unsafe fn call(
+        method: Method,
+        from: c_int,
+        to: c_int,
+        count: usize,
+    ) -> isize {
+        let null = ptr::null_mut::<i64>();
+
+        match method {
+            Method::CopyFileRange => match SYS_COPY_FILE_RANGE {
+                Some(number) => {
+                    syscall(number, from, null, to, null, count, 0 as c_uint)
+                        as isize
+                },
+                None => unreachable!(),
+            },
+            Method::SendFile => sendfile(to, from, null, count),
+            Method::Splice => splice(from, null, to, null, count, 0),
+        }
+    }
+
+    fn is_unsupported(code: c_int) -> bool {
+        code == EXDEV || code == EINVAL || code == ENOSYS || code == EOPNOTSUPP
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod kernel {
+    use std::{io::Error, os::raw::c_int};
+
+    pub(super) unsafe fn transfer(
+        _from: c_int,
+        _to: c_int,
+        _len: u64,
+        _copied: &mut u64,
+    ) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+export! {
+    /// Copy up to `len` bytes from `reader` into `writer`.
+    ///
+    /// When both handles are file descriptors (see [`read_handle_as_fd()`] and
+    /// [`CAPABILITY_RAW_FD`]), the data is moved by the kernel using
+    /// `copy_file_range()`, `sendfile()`, or `splice()` instead of being read
+    /// into a buffer and written back out. Otherwise (or when the writer has a
+    /// quota or write filter) this falls back to a normal copy.
+    ///
+    /// Returns the number of bytes copied, which is less than `len` if the end
+    /// of the stream was reached, or a negative error code.
+    ///
+    /// [`read_handle_as_fd()`]: crate::read_handle_as_fd
+    /// [`CAPABILITY_RAW_FD`]: crate::CAPABILITY_RAW_FD
+    pub unsafe extern "C" fn file_handle_splice(
+        reader: *mut ReadHandle,
+        writer: *mut FileHandle,
+        len: u64,
+    ) -> i64 {
+        let mut copied = 0;
+
+        match splice(reader, writer, len, &mut copied) {
+            Ok(()) => copied.min(i64::MAX as u64) as i64,
+            Err(e) => i64::from(crate::forbid_panics::into_errno(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+    use std::{ffi::CString, fs::File, io::Write};
+
+    #[test]
+    fn fall_back_to_a_normal_copy() {
+        let data = [7_u8; 100];
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let reader = new_memory_read_handle(data.as_ptr(), data.len());
+            let writer = FileHandle::for_writer(buffer.clone());
+
+            assert_eq!(file_handle_splice(reader, writer, 60), 60);
+            assert_eq!(file_handle_splice(reader, writer, 60), 40);
+            assert_eq!(file_handle_splice(reader, writer, 60), 0);
+
+            read_handle_destroy(reader);
+            file_handle_destroy(writer);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), &data[..]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn splice_between_files() {
+        let dir = std::env::temp_dir();
+        let src = dir.join("splice_between_files.in");
+        let dest = dir.join("splice_between_files.out");
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        File::create(&src).unwrap().write_all(&data).unwrap();
+
+        unsafe {
+            let path = CString::new(src.to_str().unwrap()).unwrap();
+            let reader = new_read_handle_from_path(path.as_ptr());
+            let writer = FileHandle::for_writer(File::create(&dest).unwrap());
+            let capabilities = file_handle_capabilities(writer);
+            assert_ne!(capabilities & CAPABILITY_RAW_FD, 0);
+
+            assert_eq!(file_handle_splice(reader, writer, 4000), 4000);
+            assert_eq!(file_handle_splice(reader, writer, u64::MAX), 6000);
+
+            read_handle_destroy(reader);
+            file_handle_destroy(writer);
+        }
+
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+        let _ = std::fs::remove_file(src);
+        let _ = std::fs::remove_file(dest);
+    }
+}
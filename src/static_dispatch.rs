@@ -0,0 +1,138 @@
+//! Handles for a fixed set of writer types, where picking the writer is a
+//! `match` instead of a call through a different function pointer for every
+//! type.
+
+/// Generate an enum which can hold any of a fixed set of writers, for
+/// embedders who know up front every type they will put in a [`FileHandle`].
+///
+/// The enum implements [`Write`] by matching on the variant, so the
+/// compiler can inline each writer's methods. Every handle created from it
+/// shares the same vtable, meaning the call through the handle always goes
+/// to the same place no matter which writer is inside, which can help the
+/// branch predictor when writes are spread across handles of different
+/// types (see the `small_writes` benchmark). Handles are created with
+/// `into_handle()` (or `into_raw_handle()` for passing to C) and work with
+/// the normal C API.
+///
+/// ```rust
+/// use std::{fs::File, io::Write, net::TcpStream};
+///
+/// thin_trait_objects::static_dispatch_handles! {
+///     /// Every writer our application uses.
+///     pub enum AppWriter {
+///         File(File),
+///         Tcp(TcpStream),
+///         Memory(Vec<u8>),
+///     }
+/// }
+///
+/// let mut handle = AppWriter::from(Vec::new()).into_handle();
+/// handle.write_all(b"Hello, World!").unwrap();
+///
+/// let writer = handle.downcast_ref::<AppWriter>().unwrap();
+/// assert!(matches!(writer, AppWriter::Memory(m) if m == b"Hello, World!"));
+/// ```
+///
+/// Each variant needs a name because the macro can't make one up from a
+/// type like `Vec<u8>`, and a `From` impl is generated for each type, so a
+/// type can only appear once. Functions which look for a particular writer
+/// type (e.g. [`file_handle_as_memory()`]) see the enum rather than the
+/// writer inside it.
+///
+/// [`FileHandle`]: crate::FileHandle
+/// [`Write`]: std::io::Write
+/// [`file_handle_as_memory()`]: crate::file_handle_as_memory
+#[macro_export]
+macro_rules! static_dispatch_handles {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $( $(#[$vmeta:meta])* $variant:ident($ty:ty) ),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $( $(#[$vmeta])* $variant($ty), )+
+        }
+
+        impl ::std::io::Write for $name {
+            #[inline]
+            fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+                match self {
+                    $( $name::$variant(w) => ::std::io::Write::write(w, buf), )+
+                }
+            }
+
+            #[inline]
+            fn flush(&mut self) -> ::std::io::Result<()> {
+                match self {
+                    $( $name::$variant(w) => ::std::io::Write::flush(w), )+
+                }
+            }
+        }
+
+        $(
+            impl ::std::convert::From<$ty> for $name {
+                fn from(writer: $ty) -> Self { $name::$variant(writer) }
+            }
+        )+
+
+        impl $name {
+            /// Put the writer in a handle.
+            #[allow(dead_code)]
+            $vis fn into_handle(self) -> $crate::OwnedFileHandle {
+                $crate::OwnedFileHandle::new(self)
+            }
+
+            /// Put the writer in a handle which can be passed to C, and is
+            /// destroyed with
+            /// [`file_handle_destroy()`][$crate::file_handle_destroy].
+            #[allow(dead_code)]
+            $vis fn into_raw_handle(self) -> *mut $crate::FileHandle {
+                $crate::FileHandle::for_writer(self)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ffi::tests::SharedBuffer, ffi::*};
+    use std::io::{self, Write};
+
+    crate::static_dispatch_handles! {
+        enum TestWriter {
+            Sink(io::Sink),
+            Shared(SharedBuffer),
+        }
+    }
+
+    #[test]
+    fn every_variant_is_dispatched_to() {
+        let buffer = SharedBuffer::default();
+        let mut sink = TestWriter::from(io::sink()).into_handle();
+        let mut shared = TestWriter::from(buffer.clone()).into_handle();
+
+        sink.write_all(b"discarded").unwrap();
+        shared.write_all(b"kept").unwrap();
+        shared.flush().unwrap();
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"kept");
+    }
+
+    #[test]
+    fn handles_share_a_vtable() {
+        unsafe {
+            let sink = TestWriter::Sink(io::sink()).into_raw_handle();
+            let shared =
+                TestWriter::Shared(SharedBuffer::default()).into_raw_handle();
+
+            assert_eq!((*sink).write as usize, (*shared).write as usize);
+            let ret = file_handle_write(shared, b"asdf".as_ptr().cast(), 4);
+            assert_eq!(ret, 4);
+
+            file_handle_destroy(sink);
+            file_handle_destroy(shared);
+        }
+    }
+}
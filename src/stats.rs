@@ -0,0 +1,252 @@
+//! Reading all of a handle's counters at once, so a snapshot never mixes
+//! values from before and after a concurrent write.
+
+use crate::{FileHandle, OwnedFileHandle};
+use std::{
+    io::Error,
+    sync::atomic::{self, AtomicU64, Ordering},
+    thread,
+};
+
+/// A consistent view of a [`FileHandle`]'s counters, where every field was
+/// read at the same moment.
+///
+/// Counters are only recorded while the handle has thread stats or latency
+/// stats enabled (e.g. with [`file_handle_enable_thread_stats()`]).
+///
+/// [`file_handle_enable_thread_stats()`]:
+/// crate::file_handle_enable_thread_stats
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct FileHandleStats {
+    /// The number of successful writes.
+    pub writes: u64,
+    /// The total number of bytes written.
+    pub bytes_written: u64,
+    /// The number of writes which failed.
+    pub write_errors: u64,
+    /// The number of successful flushes.
+    pub flushes: u64,
+    /// The number of flushes which failed.
+    pub flush_errors: u64,
+}
+
+/// The handle's counters, guarded by a seqlock so readers can take a
+/// snapshot without blocking writers.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    /// Bumped before and after every update, so it is odd while the
+    /// counters are being changed.
+    sequence: AtomicU64,
+    writes: AtomicU64,
+    bytes_written: AtomicU64,
+    write_errors: AtomicU64,
+    flushes: AtomicU64,
+    flush_errors: AtomicU64,
+}
+
+impl Counters {
+    /// Update the counters, making sure only one thread does so at a time.
+    fn update(&self, update: impl FnOnce(&Counters)) {
+        let mut sequence = self.sequence.load(Ordering::Relaxed);
+
+        loop {
+            if sequence % 2 == 1 {
+                thread::yield_now();
+                sequence = self.sequence.load(Ordering::Relaxed);
+                continue;
+            }
+
+            match self.sequence.compare_exchange_weak(
+                sequence,
+                sequence + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => sequence = actual,
+            }
+        }
+        // readers must see the odd sequence before any of the new values
+        atomic::fence(Ordering::Release);
+
+        update(self);
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    pub(crate) fn record_write(&self, result: Result<usize, &Error>) {
+        self.update(|c| match result {
+            Ok(bytes_written) => {
+                c.writes.fetch_add(1, Ordering::Relaxed);
+                c.bytes_written
+                    .fetch_add(bytes_written as u64, Ordering::Relaxed);
+            },
+            Err(_) => {
+                c.write_errors.fetch_add(1, Ordering::Relaxed);
+            },
+        });
+    }
+
+    pub(crate) fn record_flush(&self, succeeded: bool) {
+        self.update(|c| {
+            let counter = if succeeded { &c.flushes } else { &c.flush_errors };
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    pub(crate) fn snapshot(&self) -> FileHandleStats {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                thread::yield_now();
+                continue;
+            }
+
+            let stats = FileHandleStats {
+                writes: self.writes.load(Ordering::Relaxed),
+                bytes_written: self.bytes_written.load(Ordering::Relaxed),
+                write_errors: self.write_errors.load(Ordering::Relaxed),
+                flushes: self.flushes.load(Ordering::Relaxed),
+                flush_errors: self.flush_errors.load(Ordering::Relaxed),
+            };
+
+            atomic::fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return stats;
+            }
+        }
+    }
+}
+
+impl FileHandle {
+    /// The handle's counters, if they are being recorded.
+    pub(crate) fn counters(&self) -> Option<&Counters> {
+        let ext = self.extensions()?;
+
+        if ext.thread_stats.is_enabled() || ext.latency.is_enabled() {
+            Some(&ext.counters)
+        } else {
+            None
+        }
+    }
+
+    fn stats_snapshot(&self) -> FileHandleStats {
+        match self.extensions() {
+            Some(ext) => ext.counters.snapshot(),
+            None => FileHandleStats::default(),
+        }
+    }
+}
+
+impl OwnedFileHandle {
+    /// Read all of the handle's counters at once (see [`FileHandleStats`]).
+    pub fn stats_snapshot(&self) -> FileHandleStats {
+        unsafe { (*self.as_ptr()).stats_snapshot() }
+    }
+}
+
+export! {
+    /// Copy all of a [`FileHandle`]'s counters into `out` at once, so they
+    /// are consistent with each other even while other threads are using the
+    /// handle.
+    ///
+    /// Counters are only recorded while thread stats or latency stats are
+    /// enabled (see [`file_handle_enable_thread_stats()`]).
+    ///
+    /// [`file_handle_enable_thread_stats()`]:
+    /// crate::file_handle_enable_thread_stats
+    pub unsafe extern "C" fn file_handle_stats_snapshot(
+        handle: *mut FileHandle,
+        out: *mut FileHandleStats,
+    ) {
+        out.write((*handle).stats_snapshot());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::{
+        io::Write,
+        sync::{atomic::AtomicBool, Arc},
+    };
+
+    struct Concurrent;
+
+    impl Write for &Concurrent {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+
+    struct SendPtr(*mut FileHandle);
+    unsafe impl Send for SendPtr {}
+    unsafe impl Sync for SendPtr {}
+
+    #[test]
+    fn nothing_is_counted_by_default() {
+        let mut handle = OwnedFileHandle::new(Vec::new());
+
+        handle.write_all(b"asdf").unwrap();
+        handle.flush().unwrap();
+
+        assert_eq!(handle.stats_snapshot(), FileHandleStats::default());
+    }
+
+    #[test]
+    fn snapshots_are_never_torn() {
+        let handle = Arc::new(SendPtr(FileHandle::for_concurrent_writer(
+            Concurrent,
+        )));
+        unsafe { file_handle_enable_thread_stats(handle.0) };
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = Arc::clone(&handle);
+                std::thread::spawn(move || unsafe {
+                    for _ in 0..1000 {
+                        file_handle_write(handle.0, b"asdf".as_ptr().cast(), 4);
+                    }
+                })
+            })
+            .collect();
+
+        let reader = {
+            let (handle, done) = (Arc::clone(&handle), Arc::clone(&done));
+            std::thread::spawn(move || unsafe {
+                while !done.load(Ordering::SeqCst) {
+                    let mut stats = FileHandleStats::default();
+                    file_handle_stats_snapshot(handle.0, &mut stats);
+                    assert_eq!(stats.bytes_written, stats.writes * 4);
+                }
+            })
+        };
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        reader.join().unwrap();
+
+        unsafe {
+            file_handle_flush(handle.0);
+            let mut stats = FileHandleStats::default();
+            file_handle_stats_snapshot(handle.0, &mut stats);
+            file_handle_destroy(handle.0);
+
+            assert_eq!(
+                stats,
+                FileHandleStats {
+                    writes: 4000,
+                    bytes_written: 16000,
+                    flushes: 1,
+                    ..Default::default()
+                }
+            );
+        }
+    }
+}
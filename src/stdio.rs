@@ -0,0 +1,242 @@
+//! Giving an [`OwnedFileHandle`] to things which only accept an OS pipe
+//! (e.g. a child process' stdout), by copying everything sent down the pipe
+//! into the handle from a background thread.
+
+use crate::{
+    log_bridge::{self, LogLevel, LogRecord},
+    OwnedFileHandle,
+};
+use std::{
+    fs::File,
+    io::{self, Error, Write},
+    process::Stdio,
+};
+
+#[cfg(unix)]
+use crate::FileHandle;
+#[cfg(unix)]
+use std::os::raw::c_int;
+
+/// The `errno` value for "invalid argument".
+#[cfg(unix)]
+const EINVAL: c_int = 22;
+
+impl OwnedFileHandle {
+    /// Use the handle as a child process' stdout or stderr.
+    ///
+    /// Everything the child writes goes down a pipe and is copied into the
+    /// handle by a background thread. Once every copy of the pipe's write
+    /// end has been closed (i.e. the child has exited and the [`Command`]
+    /// was dropped), the handle is flushed and destroyed.
+    ///
+    /// ```rust,no_run
+    /// # use std::{fs::File, process::Command};
+    /// # use thin_trait_objects::OwnedFileHandle;
+    /// let log = OwnedFileHandle::new(File::create("build.log").unwrap());
+    ///
+    /// Command::new("make").stdout(log.into_stdio()).status().unwrap();
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the pipe or its thread can't be created.
+    ///
+    /// [`Command`]: std::process::Command
+    pub fn into_stdio(self) -> Stdio {
+        let write_end = pipe_writer(self).expect("Unable to create a pipe");
+        Stdio::from(write_end)
+    }
+}
+
+/// Create a pipe which is copied into `handle`, returning its write end.
+fn pipe_writer(handle: OwnedFileHandle) -> Result<File, Error> {
+    let (read_end, write_end) = os::anonymous_pipe()?;
+
+    std::thread::Builder::new()
+        .name(String::from("file-handle-pipe"))
+        .spawn(move || pump(read_end, handle))?;
+
+    Ok(write_end)
+}
+
+fn pump(mut read_end: File, mut handle: OwnedFileHandle) {
+    let copied = io::copy(&mut read_end, &mut handle);
+
+    if let Err(e) = copied.and_then(|_| handle.flush()) {
+        // dropping the read end lets the other side know we gave up
+        let message =
+            format!("Unable to copy a pipe into {:p}: {}", handle.as_ptr(), e);
+        log_bridge::emit_to_global_sink(&LogRecord {
+            level: LogLevel::Warn,
+            target: "thin_trait_objects::stdio",
+            message: &message,
+        });
+    }
+}
+
+#[cfg(unix)]
+mod os {
+    use std::{
+        fs::File,
+        io::Error,
+        os::{raw::c_int, unix::io::FromRawFd},
+    };
+
+    extern "C" {
+        fn pipe(fds: *mut c_int) -> c_int;
+        fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+
+    const F_SETFD: c_int = 2;
+    const FD_CLOEXEC: c_int = 1;
+
+    pub(super) fn anonymous_pipe() -> Result<(File, File), Error> {
+        let mut fds = [0; 2];
+
+        unsafe {
+            if pipe(fds.as_mut_ptr()) != 0 {
+                return Err(Error::last_os_error());
+            }
+            let ends = (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]));
+
+            // don't leak either end into unrelated child processes
+            for &fd in &fds {
+                if fcntl(fd, F_SETFD, FD_CLOEXEC) != 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+
+            Ok(ends)
+        }
+    }
+}
+
+#[cfg(windows)]
+mod os {
+    use std::{
+        fs::File,
+        io::Error,
+        os::{raw::c_void, windows::io::FromRawHandle},
+        ptr,
+    };
+
+    extern "system" {
+        fn CreatePipe(
+            read_pipe: *mut *mut c_void,
+            write_pipe: *mut *mut c_void,
+            pipe_attributes: *mut c_void,
+            size: u32,
+        ) -> i32;
+    }
+
+    pub(super) fn anonymous_pipe() -> Result<(File, File), Error> {
+        let mut read_end = ptr::null_mut();
+        let mut write_end = ptr::null_mut();
+
+        unsafe {
+            // the handles aren't inheritable, so only the child sees them
+            if CreatePipe(&mut read_end, &mut write_end, ptr::null_mut(), 0)
+                == 0
+            {
+                return Err(Error::last_os_error());
+            }
+
+            let read_end = File::from_raw_handle(read_end);
+            Ok((read_end, File::from_raw_handle(write_end)))
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod os {
+    use std::{
+        fs::File,
+        io::{Error, ErrorKind},
+    };
+
+    pub(super) fn anonymous_pipe() -> Result<(File, File), Error> {
+        Err(Error::new(ErrorKind::Other, "Pipes aren't supported"))
+    }
+}
+
+export! {
+    /// Create a pipe which is copied into `handle` by a background thread,
+    /// returning the pipe's write end for native code which only accepts a
+    /// file descriptor.
+    ///
+    /// Ownership of `handle` is always taken. The caller owns the file
+    /// descriptor, and once it (and any copies of it) have been closed the
+    /// handle is flushed and destroyed.
+    ///
+    /// Returns a negative `errno` value if the pipe couldn't be created.
+    #[cfg(unix)]
+    pub unsafe extern "C" fn file_handle_make_pipe_writer_fd(
+        handle: *mut FileHandle,
+    ) -> c_int {
+        use std::os::unix::io::IntoRawFd;
+
+        if handle.is_null() {
+            return -EINVAL;
+        }
+
+        match pipe_writer(OwnedFileHandle::from_raw(handle)) {
+            Ok(write_end) => write_end.into_raw_fd(),
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::tests::SharedBuffer;
+    use std::time::{Duration, Instant};
+
+    /// Wait for the pump thread to copy `expected` into `buffer`.
+    fn wait_for(buffer: &SharedBuffer, expected: &[u8]) {
+        let start = Instant::now();
+
+        while buffer.0.lock().unwrap().as_slice() != expected {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn capture_a_child_process() {
+        let buffer = SharedBuffer::default();
+        let handle = OwnedFileHandle::new(buffer.clone());
+
+        let status = std::process::Command::new("sh")
+            .args(&["-c", "echo Hello, World!"])
+            .stdout(handle.into_stdio())
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+        wait_for(&buffer, b"Hello, World!\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn native_code_writes_to_the_fd() {
+        use std::os::unix::io::FromRawFd;
+
+        let buffer = SharedBuffer::default();
+        let handle = OwnedFileHandle::new(buffer.clone()).into_raw();
+
+        unsafe {
+            let fd = file_handle_make_pipe_writer_fd(handle);
+            assert!(fd >= 0);
+
+            let mut write_end = File::from_raw_fd(fd);
+            write_end.write_all(b"asdf").unwrap();
+
+            let ret = file_handle_make_pipe_writer_fd(std::ptr::null_mut());
+            assert_eq!(ret, -EINVAL);
+        }
+
+        wait_for(&buffer, b"asdf");
+    }
+}
@@ -0,0 +1,25 @@
+//! The synchronisation primitives used by the handle header and the
+//! concurrent parts of the crate, which the `loom-tests` feature swaps for
+//! model-checked versions (see `crate::model`).
+
+#[cfg(all(test, feature = "loom-tests"))]
+pub(crate) use crate::model::{
+    spawn, AtomicBool, AtomicU32, Condvar, JoinHandle, Mutex, MutexGuard,
+};
+#[cfg(not(all(test, feature = "loom-tests")))]
+pub(crate) use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32},
+        Condvar, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+};
+
+/// Start a new thread with the given name.
+#[cfg(not(all(test, feature = "loom-tests")))]
+pub(crate) fn spawn<F>(name: &str, f: F) -> std::io::Result<JoinHandle<()>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    std::thread::Builder::new().name(name.into()).spawn(f)
+}
@@ -0,0 +1,139 @@
+//! Writing data along with a small piece of metadata (e.g. a log level), so
+//! callers don't need to encode it into the text itself.
+
+use crate::{FileHandle, LogLevel, LogWriter, OwnedFileHandle};
+use std::{
+    convert::TryFrom,
+    io::{Error, Write},
+    os::raw::{c_char, c_int},
+};
+
+/// A [`Write`]r which can make use of the tag passed to
+/// [`file_handle_write_tagged()`] (see [`FileHandle::for_tagged_writer()`]).
+///
+/// Tags between `1` and `5` are [`LogLevel`]s, and `0` means "no tag". Other
+/// values are free for sinks to interpret however they like.
+pub trait TaggedWrite: Write {
+    /// Write some data which has been tagged with `tag`.
+    fn write_tagged(&mut self, buf: &[u8], tag: u32) -> Result<usize, Error>;
+}
+
+impl TaggedWrite for LogWriter {
+    /// Lines are logged at the level given by the tag, if it is a valid
+    /// [`LogLevel`].
+    fn write_tagged(&mut self, buf: &[u8], tag: u32) -> Result<usize, Error> {
+        let level = c_int::try_from(tag).ok().and_then(LogLevel::from_raw);
+        self.write_with_level(buf, level)
+    }
+}
+
+impl OwnedFileHandle {
+    /// Create a new [`OwnedFileHandle`] for a [`TaggedWrite`]r.
+    pub fn new_tagged<W>(writer: W) -> Self
+    where
+        W: TaggedWrite + Send + Sync + 'static,
+    {
+        unsafe {
+            OwnedFileHandle::from_raw(FileHandle::for_tagged_writer(writer))
+        }
+    }
+
+    /// Write some data tagged with `tag` (see [`file_handle_write_tagged()`]).
+    pub fn write_tagged(
+        &mut self,
+        buf: &[u8],
+        tag: u32,
+    ) -> Result<usize, Error> {
+        let handle = self.as_ptr();
+        unsafe { FileHandle::dispatch_write_tagged(handle, buf, Some(tag)) }
+    }
+}
+
+c_unwind! {
+    /// Write some data to the file handle like [`file_handle_write()`],
+    /// attaching a `tag` which tag-aware handles use to decide how to handle
+    /// it and every other handle ignores.
+    ///
+    /// For example, a handle created by [`new_log_crate_file_handle()`] logs
+    /// the data at the [`LogLevel`] given by the tag (`1` for errors through
+    /// to `5` for trace messages).
+    ///
+    /// [`file_handle_write()`]: crate::file_handle_write
+    /// [`new_log_crate_file_handle()`]: crate::new_log_crate_file_handle
+    pub unsafe extern fn file_handle_write_tagged(
+        handle: *mut FileHandle,
+        data: *const c_char,
+        len: c_int,
+        tag: u32,
+    ) -> c_int {
+        let len = match usize::try_from(len) {
+            Ok(len) => len,
+            Err(_) => return crate::FILE_HANDLE_INVALID_LENGTH,
+        };
+        let data = std::slice::from_raw_parts(data as *const u8, len);
+
+        match FileHandle::dispatch_write_tagged(handle, data, Some(tag)) {
+            Ok(bytes_written) => bytes_written as c_int,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, LogRecord, LogSink, Operation};
+    use std::{
+        ffi::CString,
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Default)]
+    struct Levels(Mutex<Vec<(LogLevel, String)>>);
+
+    impl LogSink for Levels {
+        fn log(&self, record: &LogRecord<'_>) {
+            let entry = (record.level, record.message.to_string());
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn log_writers_use_the_tag_as_the_level() {
+        let sink = Arc::new(Levels::default());
+        let writer = LogWriter::with_sink("test", LogLevel::Info, sink.clone());
+        let mut handle = OwnedFileHandle::new_tagged(writer);
+        assert!(handle.supports(Operation::WriteTagged));
+
+        handle.write_tagged(b"oh no\n", LogLevel::Error as u32).unwrap();
+        handle.write_tagged(b"unknown\n", 42).unwrap();
+        handle.write_all(b"WARN: untagged\n").unwrap();
+
+        let got = sink.0.lock().unwrap().clone();
+        let expected = vec![
+            (LogLevel::Error, String::from("oh no")),
+            (LogLevel::Info, String::from("unknown")),
+            (LogLevel::Warn, String::from("untagged")),
+        ];
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn plain_handles_ignore_the_tag() {
+        let target = CString::new("plugin").unwrap();
+
+        unsafe {
+            let handle = new_memory_file_handle();
+            assert!(!file_handle_supports(handle, Operation::WriteTagged as _));
+            let data = b"asdf".as_ptr().cast();
+            let ret = file_handle_write_tagged(handle, data, 4, 1);
+            assert_eq!(ret, 4);
+            file_handle_destroy(handle);
+
+            // the C constructor gives a tag-aware handle
+            let handle = new_log_crate_file_handle(target.as_ptr(), 3);
+            assert!(file_handle_supports(handle, Operation::WriteTagged as _));
+            file_handle_destroy(handle);
+        }
+    }
+}
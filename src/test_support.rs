@@ -0,0 +1,352 @@
+//! Helpers for property-testing [`FileHandle`] implementations against a
+//! reference model.
+//!
+//! This is deliberately dependency-free. A [`Strategy`] generates random
+//! inputs from a seeded [`TestRng`], and [`check()`] runs a property against
+//! many generated inputs, reporting the seed of any failing case so it can be
+//! reproduced with [`check_seed()`]. Failing inputs aren't shrunk.
+//!
+//! ```rust
+//! use thin_trait_objects::{
+//!     test_support::{self, ModelWriter},
+//!     OwnedFileHandle,
+//! };
+//!
+//! let strategy = test_support::arb_write_sequence(16, 64);
+//!
+//! test_support::check(100, &strategy, |ops| {
+//!     let mut model = ModelWriter::default();
+//!     let mut handle = OwnedFileHandle::new(Vec::new());
+//!
+//!     model.apply_all(&ops).unwrap();
+//!     ModelWriter::replay(&ops, &mut handle).unwrap();
+//!
+//!     let written = handle.downcast::<Vec<u8>>().ok().unwrap();
+//!     model.assert_matches(&written)
+//! });
+//! ```
+
+use crate::{
+    BackgroundWriter, FileHandle, IndirectWriter, OwnedFileHandle,
+    ScopedWriter,
+};
+use std::{
+    fmt::Debug,
+    io::{BufWriter, Error, Write},
+};
+
+/// A small, deterministic pseudo-random number generator (SplitMix64).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestRng {
+    state: u64,
+}
+
+impl TestRng {
+    /// Create a generator which always produces the same sequence for a
+    /// given `seed`.
+    pub fn from_seed(seed: u64) -> Self { TestRng { state: seed } }
+
+    /// Generate the next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Generate a number in `0..=max`.
+    pub fn below_or_equal(&mut self, max: usize) -> usize {
+        (self.next_u64() % (max as u64 + 1)) as usize
+    }
+}
+
+/// Something which generates random values of a particular type.
+pub trait Strategy {
+    /// The type of value being generated.
+    type Value: Debug;
+
+    /// Generate a new value.
+    fn generate(&self, rng: &mut TestRng) -> Self::Value;
+}
+
+impl<F, T> Strategy for F
+where
+    F: Fn(&mut TestRng) -> T,
+    T: Debug,
+{
+    type Value = T;
+
+    fn generate(&self, rng: &mut TestRng) -> T { self(rng) }
+}
+
+/// A single operation performed on a handle.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    /// Write all of these bytes.
+    Write(Vec<u8>),
+    /// Flush the handle.
+    Flush,
+}
+
+/// Generate up to `max_ops` writes and flushes, where each write is at most
+/// `max_len` bytes long (possibly empty).
+pub fn arb_write_sequence(
+    max_ops: usize,
+    max_len: usize,
+) -> impl Strategy<Value = Vec<WriteOp>> {
+    move |rng: &mut TestRng| {
+        let ops = rng.below_or_equal(max_ops);
+
+        (0..ops)
+            .map(|_| {
+                // roughly one in five operations should be a flush
+                if rng.below_or_equal(4) == 0 {
+                    return WriteOp::Flush;
+                }
+
+                let len = rng.below_or_equal(max_len);
+                WriteOp::Write((0..len).map(|_| rng.next_u64() as u8).collect())
+            })
+            .collect::<Vec<_>>()
+    }
+}
+
+/// A wrapper which shouldn't change what ends up being written.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Layer {
+    /// Buffer writes using a [`BufWriter`].
+    Buffered,
+    /// Write through an [`IndirectWriter`].
+    Indirect,
+    /// Write on a background thread using a [`BackgroundWriter`].
+    Background,
+    /// Write through the root of a [`ScopedWriter`] hierarchy.
+    Scoped,
+}
+
+/// A stack of [`Layer`]s wrapped around some inner handle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandleChain {
+    /// The layers, from innermost to outermost.
+    pub layers: Vec<Layer>,
+}
+
+impl HandleChain {
+    /// Wrap `inner` in each of the layers.
+    pub fn build(&self, inner: OwnedFileHandle) -> OwnedFileHandle {
+        self.layers.iter().fold(inner, |inner, layer| match layer {
+            Layer::Buffered => OwnedFileHandle::new(BufWriter::new(inner)),
+            Layer::Indirect => {
+                OwnedFileHandle::new(IndirectWriter::new(inner))
+            },
+            Layer::Background => {
+                OwnedFileHandle::new(BackgroundWriter::new(inner, 4))
+            },
+            Layer::Scoped => unsafe {
+                let writer = ScopedWriter::new(inner);
+                OwnedFileHandle::from_raw(FileHandle::for_concurrent_writer(
+                    writer,
+                ))
+            },
+        })
+    }
+}
+
+/// Generate chains of up to `max_depth` transparent [`Layer`]s.
+pub fn arb_handle_chain(
+    max_depth: usize,
+) -> impl Strategy<Value = HandleChain> {
+    move |rng: &mut TestRng| {
+        let all = [
+            Layer::Buffered,
+            Layer::Indirect,
+            Layer::Background,
+            Layer::Scoped,
+        ];
+        let depth = rng.below_or_equal(max_depth);
+        let layers = (0..depth)
+            .map(|_| all[rng.below_or_equal(all.len() - 1)])
+            .collect();
+
+        HandleChain { layers }
+    }
+}
+
+/// The reference model for how a well-behaved handle should respond to a
+/// sequence of [`WriteOp`]s.
+///
+/// Everything written must arrive in order without being duplicated, and
+/// anything written before a flush must have reached the destination once
+/// the flush returns.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ModelWriter {
+    written: Vec<u8>,
+    flushed: usize,
+    flushes: usize,
+}
+
+impl ModelWriter {
+    /// Everything which should have been written so far.
+    pub fn written(&self) -> &[u8] { &self.written }
+
+    /// How many bytes should have reached the destination as of the last
+    /// flush.
+    pub fn flushed(&self) -> usize { self.flushed }
+
+    /// How many times the model was flushed.
+    pub fn flushes(&self) -> usize { self.flushes }
+
+    /// Update the model to reflect `op`.
+    pub fn apply(&mut self, op: &WriteOp) -> Result<(), Error> {
+        match op {
+            WriteOp::Write(data) => self.write_all(data),
+            WriteOp::Flush => self.flush(),
+        }
+    }
+
+    /// Update the model to reflect every operation in `ops`.
+    pub fn apply_all(&mut self, ops: &[WriteOp]) -> Result<(), Error> {
+        ops.iter().try_for_each(|op| self.apply(op))
+    }
+
+    /// Perform the same operations on a real writer.
+    pub fn replay<W>(ops: &[WriteOp], writer: &mut W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        ops.iter().try_for_each(|op| match op {
+            WriteOp::Write(data) => writer.write_all(data),
+            WriteOp::Flush => writer.flush(),
+        })
+    }
+
+    /// Check what ended up at the destination once everything is done.
+    pub fn assert_matches(&self, actual: &[u8]) -> Result<(), String> {
+        if actual == self.written.as_slice() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Expected {} bytes ({:?}) but found {} bytes ({:?})",
+                self.written.len(),
+                self.written,
+                actual.len(),
+                actual,
+            ))
+        }
+    }
+
+    /// Check what reached the destination straight after a flush.
+    pub fn assert_flushed(&self, actual: &[u8]) -> Result<(), String> {
+        if actual.len() >= self.flushed
+            && actual[..self.flushed] == self.written[..self.flushed]
+        {
+            Ok(())
+        } else {
+            Err(format!(
+                "Expected the first {} bytes to be flushed, but found {:?}",
+                self.flushed, actual,
+            ))
+        }
+    }
+}
+
+impl Write for ModelWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.flushed = self.written.len();
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+/// Run `property` against `cases` inputs generated by `strategy`, panicking
+/// with the failing input and its seed if the property doesn't hold.
+pub fn check<S, F>(cases: u32, strategy: &S, mut property: F)
+where
+    S: Strategy,
+    F: FnMut(S::Value) -> Result<(), String>,
+{
+    for case in 0..cases {
+        check_seed(u64::from(case), strategy, &mut property);
+    }
+}
+
+/// Run `property` against the single input generated from `seed`, so a
+/// failure found by [`check()`] can be reproduced.
+pub fn check_seed<S, F>(seed: u64, strategy: &S, mut property: F)
+where
+    S: Strategy,
+    F: FnMut(S::Value) -> Result<(), String>,
+{
+    let value = strategy.generate(&mut TestRng::from_seed(seed));
+    let description = format!("{:?}", value);
+
+    if let Err(msg) = property(value) {
+        panic!(
+            "Property failed for seed {} with input {}: {}",
+            seed, description, msg
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::tests::SharedBuffer;
+
+    #[test]
+    fn generation_is_deterministic() {
+        let strategy = arb_write_sequence(32, 32);
+
+        let first = strategy.generate(&mut TestRng::from_seed(42));
+        let second = strategy.generate(&mut TestRng::from_seed(42));
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn handle_chains_match_the_model() {
+        let (chains, sequences) =
+            (arb_handle_chain(4), arb_write_sequence(16, 64));
+        let strategy = |rng: &mut TestRng| {
+            (chains.generate(rng), sequences.generate(rng))
+        };
+
+        check(64, &strategy, |(chain, ops)| {
+            let buffer = SharedBuffer::default();
+            let mut model = ModelWriter::default();
+            let mut handle = chain.build(OwnedFileHandle::new(buffer.clone()));
+
+            for op in &ops {
+                model.apply(op).unwrap();
+                ModelWriter::replay(std::slice::from_ref(op), &mut handle)
+                    .map_err(|e| e.to_string())?;
+
+                if *op == WriteOp::Flush {
+                    model.assert_flushed(&buffer.0.lock().unwrap())?;
+                }
+            }
+
+            drop(handle);
+            let written = buffer.0.lock().unwrap();
+            model.assert_matches(&written)
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Property failed for seed")]
+    fn failures_report_the_seed() {
+        check(16, &arb_write_sequence(8, 8), |ops| {
+            if ops.len() < 4 {
+                Ok(())
+            } else {
+                Err(String::from("Too long"))
+            }
+        });
+    }
+}
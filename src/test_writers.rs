@@ -0,0 +1,376 @@
+//! Ready-made writers for testing code which uses [`FileHandle`]s, so
+//! downstream test suites don't need to write their own.
+//!
+//! Each writer can wrap any [`Write`]r, and has an FFI constructor which
+//! wraps [`std::io::sink()`] for tests written in other languages.
+//!
+//! ```rust
+//! use std::io::Write;
+//! use thin_trait_objects::{
+//!     test_writers::{CountingWriter, FlakyWriter},
+//!     OwnedFileHandle,
+//! };
+//!
+//! let counting = CountingWriter::new(Vec::new());
+//! let counts = counting.counts();
+//! let flaky = FlakyWriter::fail_every(counting, 2);
+//! let mut handle = OwnedFileHandle::new(flaky);
+//!
+//! assert!(handle.write_all(b"first").is_ok());
+//! assert!(handle.write_all(b"second").is_err());
+//! assert!(handle.write_all(b"third").is_ok());
+//!
+//! assert_eq!(counts.get().writes, 2);
+//! assert_eq!(counts.get().bytes_written, 10);
+//! ```
+
+use crate::{FileHandle, FileHandleStats};
+use std::{
+    io::{self, Error, Write},
+    os::raw::c_int,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// The `errno` value for "input/output error".
+#[cfg(windows)]
+const EIO: c_int = 29; // ERROR_WRITE_FAULT
+#[cfg(not(windows))]
+const EIO: c_int = 5;
+
+/// A writer which takes a while to accept each write, like a slow disk or a
+/// congested network connection.
+#[derive(Debug)]
+pub struct SlowWriter<W> {
+    inner: W,
+    latency: Duration,
+    bytes_per_second: Option<u64>,
+}
+
+impl<W: Write> SlowWriter<W> {
+    /// Create a new [`SlowWriter`] which waits `latency` before every write
+    /// and flush.
+    pub fn new(inner: W, latency: Duration) -> Self {
+        SlowWriter {
+            inner,
+            latency,
+            bytes_per_second: None,
+        }
+    }
+
+    /// Create a new [`SlowWriter`] which accepts at most `bytes_per_second`,
+    /// waiting as long as it would take to send each write at that rate.
+    ///
+    /// # Panics
+    ///
+    /// If `bytes_per_second` is zero.
+    pub fn with_max_throughput(inner: W, bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "The throughput must be non-zero");
+
+        SlowWriter {
+            inner,
+            latency: Duration::from_secs(0),
+            bytes_per_second: Some(bytes_per_second),
+        }
+    }
+
+    /// Get a reference to the writer being wrapped.
+    pub fn get_ref(&self) -> &W { &self.inner }
+
+    /// Get the writer being wrapped back.
+    pub fn into_inner(self) -> W { self.inner }
+
+    fn delay(&self, len: usize) -> Duration {
+        let transfer = match self.bytes_per_second {
+            Some(rate) => {
+                let nanos = len as u128 * 1_000_000_000 / u128::from(rate);
+                Duration::from_nanos(nanos as u64)
+            },
+            None => Duration::from_secs(0),
+        };
+
+        self.latency + transfer
+    }
+}
+
+impl<W: Write> Write for SlowWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        thread::sleep(self.delay(buf.len()));
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        thread::sleep(self.latency);
+        self.inner.flush()
+    }
+}
+
+/// A writer which fails writes according to a fixed schedule, so tests of
+/// error handling and retries are reproducible.
+///
+/// Flushes are always passed through.
+#[derive(Debug)]
+pub struct FlakyWriter<W> {
+    inner: W,
+    /// Whether each write should fail, repeated once it runs out.
+    schedule: Vec<bool>,
+    calls: usize,
+}
+
+impl<W: Write> FlakyWriter<W> {
+    /// Create a new [`FlakyWriter`] where the `n`'th write fails whenever
+    /// `schedule[n % schedule.len()]` is `true`.
+    ///
+    /// # Panics
+    ///
+    /// If the `schedule` is empty.
+    pub fn new(inner: W, schedule: Vec<bool>) -> Self {
+        assert!(!schedule.is_empty(), "The schedule can't be empty");
+
+        FlakyWriter {
+            inner,
+            schedule,
+            calls: 0,
+        }
+    }
+
+    /// Create a new [`FlakyWriter`] where every `n`'th write fails, starting
+    /// with the `n`'th.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is zero.
+    pub fn fail_every(inner: W, n: usize) -> Self {
+        assert!(n > 0, "Writes can't fail every 0th time");
+
+        let mut schedule = vec![false; n];
+        schedule[n - 1] = true;
+        FlakyWriter::new(inner, schedule)
+    }
+
+    /// The number of writes so far, including failed writes.
+    pub fn calls(&self) -> usize { self.calls }
+
+    /// Get a reference to the writer being wrapped.
+    pub fn get_ref(&self) -> &W { &self.inner }
+
+    /// Get the writer being wrapped back.
+    pub fn into_inner(self) -> W { self.inner }
+}
+
+impl<W: Write> Write for FlakyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let fails = self.schedule[self.calls % self.schedule.len()];
+        self.calls += 1;
+
+        if fails {
+            Err(Error::from_raw_os_error(EIO))
+        } else {
+            self.inner.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.inner.flush() }
+}
+
+/// A writer which counts the writes and flushes passed to the writer it
+/// wraps.
+#[derive(Debug)]
+pub struct CountingWriter<W> {
+    inner: W,
+    counts: WriteCounts,
+}
+
+impl<W: Write> CountingWriter<W> {
+    /// Create a new [`CountingWriter`].
+    pub fn new(inner: W) -> Self {
+        CountingWriter {
+            inner,
+            counts: WriteCounts::default(),
+        }
+    }
+
+    /// Get something which can read the counts after the writer has been
+    /// moved into a handle.
+    pub fn counts(&self) -> WriteCounts { self.counts.clone() }
+
+    /// Get a reference to the writer being wrapped.
+    pub fn get_ref(&self) -> &W { &self.inner }
+
+    /// Get the writer being wrapped back.
+    pub fn into_inner(self) -> W { self.inner }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let result = self.inner.write(buf);
+
+        self.counts.update(|stats| match result {
+            Ok(bytes_written) => {
+                stats.writes += 1;
+                stats.bytes_written += bytes_written as u64;
+            },
+            Err(_) => stats.write_errors += 1,
+        });
+
+        result
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let result = self.inner.flush();
+
+        self.counts.update(|stats| match result {
+            Ok(_) => stats.flushes += 1,
+            Err(_) => stats.flush_errors += 1,
+        });
+
+        result
+    }
+}
+
+/// The counts recorded by a [`CountingWriter`], shared with every clone.
+#[derive(Debug, Default, Clone)]
+pub struct WriteCounts(Arc<Mutex<FileHandleStats>>);
+
+impl WriteCounts {
+    /// The counts so far.
+    pub fn get(&self) -> FileHandleStats {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update(&self, update: impl FnOnce(&mut FileHandleStats)) {
+        update(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which discards everything written to it
+    /// after waiting `latency_ms` milliseconds (see [`SlowWriter`]).
+    pub unsafe extern "C" fn new_slow_test_handle(
+        latency_ms: u32,
+    ) -> *mut FileHandle {
+        let latency = Duration::from_millis(u64::from(latency_ms));
+        FileHandle::for_writer(SlowWriter::new(io::sink(), latency))
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which discards everything written to it,
+    /// accepting at most `bytes_per_second` (see
+    /// [`SlowWriter::with_max_throughput()`]).
+    ///
+    /// Returns `null` if `bytes_per_second` is zero.
+    pub unsafe extern "C" fn new_throttled_test_handle(
+        bytes_per_second: u64,
+    ) -> *mut FileHandle {
+        if bytes_per_second == 0 {
+            return std::ptr::null_mut();
+        }
+
+        let sink = io::sink();
+        FileHandle::for_writer(SlowWriter::with_max_throughput(
+            sink,
+            bytes_per_second,
+        ))
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which discards everything written to it,
+    /// failing every `n`'th write with `EIO` (see
+    /// [`FlakyWriter::fail_every()`]).
+    ///
+    /// Returns `null` if `n` is zero.
+    pub unsafe extern "C" fn new_flaky_test_handle(n: u32) -> *mut FileHandle {
+        if n == 0 {
+            return std::ptr::null_mut();
+        }
+
+        FileHandle::for_writer(FlakyWriter::fail_every(io::sink(), n as usize))
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which discards everything written to it,
+    /// counting the writes and flushes (see [`counting_test_handle_counts()`]).
+    pub unsafe extern "C" fn new_counting_test_handle() -> *mut FileHandle {
+        FileHandle::for_writer(CountingWriter::new(io::sink()))
+    }
+}
+
+export! {
+    /// Copy the counts recorded by a handle created with
+    /// [`new_counting_test_handle()`] into `out`.
+    ///
+    /// Returns `0` on success, or [`FILE_HANDLE_UNSUPPORTED`] if the handle
+    /// wasn't created by [`new_counting_test_handle()`].
+    ///
+    /// [`FILE_HANDLE_UNSUPPORTED`]: crate::FILE_HANDLE_UNSUPPORTED
+    pub unsafe extern "C" fn counting_test_handle_counts(
+        handle: *mut FileHandle,
+        out: *mut FileHandleStats,
+    ) -> c_int {
+        match FileHandle::downcast_raw::<CountingWriter<io::Sink>>(handle) {
+            Some(writer) => {
+                out.write((*writer).counts.get());
+                0
+            },
+            None => crate::FILE_HANDLE_UNSUPPORTED,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+    use std::time::Instant;
+
+    #[test]
+    fn schedules_repeat() {
+        let mut writer = FlakyWriter::new(Vec::new(), vec![false, true, true]);
+
+        let results: Vec<_> =
+            (0..6).map(|_| writer.write(b"a").is_ok()).collect();
+
+        assert_eq!(results, &[true, false, false, true, false, false]);
+        assert_eq!(writer.calls(), 6);
+        assert_eq!(writer.into_inner(), b"aa");
+    }
+
+    #[test]
+    fn slow_writers_take_their_time() {
+        let mut writer = SlowWriter::with_max_throughput(Vec::new(), 1000);
+        let start = Instant::now();
+
+        writer.write_all(&[0; 50]).unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_handles_from_c() {
+        unsafe {
+            let counting = new_counting_test_handle();
+            let flaky = new_flaky_test_handle(2);
+            let data = b"asdf".as_ptr().cast();
+
+            assert_eq!(file_handle_write(counting, data, 4), 4);
+            file_handle_flush(counting);
+            let mut stats = FileHandleStats::default();
+            assert_eq!(counting_test_handle_counts(counting, &mut stats), 0);
+            assert_eq!(stats.bytes_written, 4);
+            assert_eq!(stats.flushes, 1);
+
+            assert_eq!(file_handle_write(flaky, data, 4), 4);
+            assert_eq!(file_handle_write(flaky, data, 4), -EIO);
+            let ret = counting_test_handle_counts(flaky, &mut stats);
+            assert_eq!(ret, FILE_HANDLE_UNSUPPORTED);
+
+            assert!(new_flaky_test_handle(0).is_null());
+            file_handle_destroy(counting);
+            file_handle_destroy(flaky);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Keeping track of how much each thread writes to a [`FileHandle`].
+
+use crate::FileHandle;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// How much a particular thread has written to a [`FileHandle`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct ThreadStats {
+    /// The thread's ID, as returned by
+    /// [`thin_trait_objects_current_thread_id()`].
+    pub thread_id: u64,
+    /// The number of successful writes.
+    pub writes: u64,
+    /// The total number of bytes written.
+    pub bytes_written: u64,
+}
+
+/// Get a small number which uniquely identifies the current thread, assigned
+/// in the order threads first ask for it.
+pub(crate) fn current_thread_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    thread_local! {
+        static ID: Cell<Option<u64>> = Cell::new(None);
+    }
+
+    ID.with(|id| match id.get() {
+        Some(id) => id,
+        None => {
+            let new_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            id.set(Some(new_id));
+            new_id
+        },
+    })
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ThreadStatsTable {
+    enabled: AtomicBool,
+    per_thread: Mutex<HashMap<u64, ThreadStats>>,
+}
+
+impl ThreadStatsTable {
+    pub(crate) fn enable(&self) { self.enabled.store(true, Ordering::Relaxed); }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_write(&self, bytes_written: usize) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let thread_id = current_thread_id();
+        let mut per_thread =
+            self.per_thread.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = per_thread.entry(thread_id).or_insert(ThreadStats {
+            thread_id,
+            ..Default::default()
+        });
+
+        stats.writes += 1;
+        stats.bytes_written += bytes_written as u64;
+    }
+
+    /// Get a copy of the statistics, sorted by thread ID.
+    pub(crate) fn snapshot(&self) -> Vec<ThreadStats> {
+        let per_thread =
+            self.per_thread.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stats: Vec<_> = per_thread.values().copied().collect();
+        stats.sort_by_key(|s| s.thread_id);
+
+        stats
+    }
+}
+
+export! {
+    /// Get the ID this crate uses to identify the calling thread.
+    pub unsafe extern "C" fn thin_trait_objects_current_thread_id() -> u64 {
+        current_thread_id()
+    }
+}
+
+export! {
+    /// Start recording how many bytes each thread writes to this
+    /// [`FileHandle`].
+    ///
+    /// Recording adds a small amount of overhead to every write and can't be
+    /// turned off again.
+    pub unsafe extern "C" fn file_handle_enable_thread_stats(
+        handle: *mut FileHandle,
+    ) {
+        (*handle).extensions_or_default().thread_stats.enable();
+    }
+}
+
+export! {
+    /// Copy the per-thread statistics for a [`FileHandle`] into `out_array`,
+    /// writing at most `len` entries.
+    ///
+    /// Returns the total number of threads which have written to the handle,
+    /// which may be more than `len`. No statistics are recorded unless they
+    /// were turned on with [`file_handle_enable_thread_stats()`].
+    pub unsafe extern "C" fn file_handle_thread_stats(
+        handle: *mut FileHandle,
+        out_array: *mut ThreadStats,
+        len: usize,
+    ) -> usize {
+        let stats = match (*handle).extensions() {
+            Some(ext) => ext.thread_stats.snapshot(),
+            None => return 0,
+        };
+
+        if !out_array.is_null() {
+            for (i, entry) in stats.iter().take(len).enumerate() {
+                out_array.add(i).write(*entry);
+            }
+        }
+
+        stats.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, OwnedFileHandle};
+    use std::{io::Write, ptr};
+
+    #[test]
+    fn nothing_is_recorded_by_default() {
+        let mut handle = OwnedFileHandle::new(std::io::sink());
+
+        handle.write_all(b"asdf").unwrap();
+
+        assert_eq!(handle.thread_stats().count(), 0);
+    }
+
+    #[test]
+    fn attribute_writes_to_threads() {
+        let mut handle = OwnedFileHandle::new(std::io::sink());
+        handle.enable_thread_stats();
+
+        handle.write_all(b"asdf").unwrap();
+        let handle = std::thread::spawn(move || {
+            handle.write_all(b"Hello, World!").unwrap();
+            handle.write_all(b"Hello, World!").unwrap();
+            handle
+        })
+        .join()
+        .unwrap();
+
+        let stats: Vec<_> = handle.thread_stats().collect();
+        assert_eq!(stats.len(), 2);
+        let me = stats
+            .iter()
+            .find(|s| s.thread_id == current_thread_id())
+            .unwrap();
+        assert_eq!(me.writes, 1);
+        assert_eq!(me.bytes_written, 4);
+        let other = stats
+            .iter()
+            .find(|s| s.thread_id != current_thread_id())
+            .unwrap();
+        assert_eq!(other.writes, 2);
+        assert_eq!(other.bytes_written, 26);
+    }
+
+    #[test]
+    fn copy_stats_into_a_c_array() {
+        unsafe {
+            let handle = new_null_file_handle();
+            file_handle_enable_thread_stats(handle);
+
+            file_handle_write(handle, b"asdf".as_ptr() as _, 4);
+            assert_eq!(file_handle_thread_stats(handle, ptr::null_mut(), 0), 1);
+
+            let mut stats = [ThreadStats::default(); 4];
+            let ret = file_handle_thread_stats(
+                handle,
+                stats.as_mut_ptr(),
+                stats.len(),
+            );
+
+            assert_eq!(ret, 1);
+            assert_eq!(
+                stats[0],
+                ThreadStats {
+                    thread_id: thin_trait_objects_current_thread_id(),
+                    writes: 1,
+                    bytes_written: 4,
+                }
+            );
+
+            file_handle_destroy(handle);
+        }
+    }
+}
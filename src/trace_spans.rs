@@ -0,0 +1,388 @@
+//! Spans around the writes and flushes of individual handles, so Rust hosts
+//! can see which handles dominate I/O time in a profiler or flamegraph.
+//!
+//! Like the log and metrics bridges, this doesn't depend on a particular
+//! framework. The host installs a [`TraceSink`] with [`set_trace_sink()`]
+//! which turns each [`TraceSpan`] into a `tracing` span (or whatever else it
+//! uses), then picks which handles report and how much with
+//! [`OwnedFileHandle::set_tracing_level()`]. Untraced handles only pay for
+//! checking their level.
+//!
+//! ```rust
+//! use std::{io::{Error, Write}, sync::Arc};
+//! use thin_trait_objects::{
+//!     OwnedFileHandle, TraceLevel, TraceOutcome, TraceSink, TraceSpan,
+//! };
+//!
+//! struct Bridge;
+//!
+//! impl TraceSink for Bridge {
+//!     fn span(
+//!         &self,
+//!         span: &TraceSpan<'_>,
+//!         operation: &mut dyn FnMut() -> TraceOutcome,
+//!     ) {
+//!         // e.g. tracing::info_span!("write", handle = span.handle_id, ..)
+//!         //     .in_scope(operation)
+//!         let outcome = operation();
+//!         println!("{:?}: {:?}", span, outcome);
+//!     }
+//!
+//!     fn error(&self, span: &TraceSpan<'_>, error: &Error) {
+//!         eprintln!("{} failed: {}", span.type_name, error);
+//!     }
+//! }
+//!
+//! thin_trait_objects::set_trace_sink(Arc::new(Bridge));
+//!
+//! let mut handle = OwnedFileHandle::new(Vec::new());
+//! handle.set_tracing_level(TraceLevel::Spans);
+//! handle.write_all(b"Hello, World!")?;
+//! # Ok::<(), Error>(())
+//! ```
+
+use crate::{global::Global, FileHandle, ThinErrorKind};
+use std::{
+    ffi::CStr,
+    io::Error,
+    os::raw::c_int,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Don't report anything (the default).
+pub const TRACE_LEVEL_OFF: c_int = 0;
+/// Report every write or flush which fails.
+pub const TRACE_LEVEL_ERRORS: c_int = 1;
+/// Wrap every write and flush in a span, as well as reporting failures.
+pub const TRACE_LEVEL_SPANS: c_int = 2;
+
+/// How much a handle reports to the [`TraceSink`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TraceLevel {
+    /// Don't report anything (the default).
+    Off,
+    /// Report every write or flush which fails, with [`TraceSink::error()`].
+    Errors,
+    /// Wrap every write and flush in a [`TraceSink::span()`], as well as
+    /// reporting failures.
+    Spans,
+}
+
+impl TraceLevel {
+    /// Parse one of the `TRACE_LEVEL_*` constants.
+    pub fn from_raw(level: c_int) -> Option<TraceLevel> {
+        match level {
+            TRACE_LEVEL_OFF => Some(TraceLevel::Off),
+            TRACE_LEVEL_ERRORS => Some(TraceLevel::Errors),
+            TRACE_LEVEL_SPANS => Some(TraceLevel::Spans),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of operation a span covers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TraceOperation {
+    /// A single write (including each buffer of a vectored write).
+    Write,
+    /// A flush.
+    Flush,
+}
+
+/// Describes a write or flush on a traced handle.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceSpan<'a> {
+    /// What is being done.
+    pub operation: TraceOperation,
+    /// Identifies the handle (its address, which is unique while the handle
+    /// is alive).
+    pub handle_id: usize,
+    /// The name of the object's type.
+    pub type_name: &'a str,
+    /// The handle's name, if it has one (see
+    /// [`file_handle_name()`][crate::file_handle_name]).
+    pub name: Option<&'a str>,
+    /// The number of bytes passed to a write, or `0` for a flush.
+    pub requested: usize,
+}
+
+/// How a write or flush went.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TraceOutcome {
+    /// The number of bytes written, or `0` for a flush.
+    pub bytes: usize,
+    /// Why the operation failed, if it did.
+    pub error: Option<ThinErrorKind>,
+}
+
+/// Somewhere spans and error events from traced handles can be sent.
+///
+/// The sink is called from whichever thread used the handle.
+pub trait TraceSink: Send + Sync {
+    /// Run the `operation` described by `span` inside a span of its own, so
+    /// time spent in it gets attributed to the handle. The returned
+    /// [`TraceOutcome`] can be recorded on the span.
+    ///
+    /// The `operation` should be called exactly once. If the sink doesn't
+    /// call it, it is run without a span afterwards.
+    fn span(
+        &self,
+        span: &TraceSpan<'_>,
+        operation: &mut dyn FnMut() -> TraceOutcome,
+    );
+
+    /// The operation described by `span` failed.
+    fn error(&self, span: &TraceSpan<'_>, error: &Error) {
+        let _ = (span, error);
+    }
+}
+
+/// A handle's [`TraceLevel`], which can be changed while it's being used.
+#[derive(Debug, Default)]
+pub(crate) struct TraceLevelSlot(AtomicU8);
+
+impl TraceLevelSlot {
+    fn set(&self, level: TraceLevel) {
+        self.0.store(level as u8, Ordering::Relaxed);
+    }
+
+    /// The handle's level, or `None` if it isn't traced.
+    pub(crate) fn get(&self) -> Option<TraceLevel> {
+        match self.0.load(Ordering::Relaxed) {
+            1 => Some(TraceLevel::Errors),
+            2 => Some(TraceLevel::Spans),
+            _ => None,
+        }
+    }
+}
+
+/// Lets the hot path skip locking when there is no sink.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static TRACE_SINK: Global<Mutex<Option<Arc<dyn TraceSink>>>> = Global::new();
+
+fn sink_slot() -> &'static Mutex<Option<Arc<dyn TraceSink>>> {
+    TRACE_SINK.get_or_init(|| Mutex::new(None))
+}
+
+/// Free the trace sink.
+pub(crate) unsafe fn shutdown() {
+    ENABLED.store(false, Ordering::Relaxed);
+    TRACE_SINK.reset();
+}
+
+/// Send spans and errors from every handle with a [`TraceLevel`] to `sink`.
+pub fn set_trace_sink(sink: Arc<dyn TraceSink>) {
+    let mut slot = sink_slot().lock().unwrap_or_else(|e| e.into_inner());
+    ENABLED.store(true, Ordering::Relaxed);
+    *slot = Some(sink);
+}
+
+fn current() -> Option<Arc<dyn TraceSink>> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    sink_slot()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Run an operation on a traced handle, reporting it to the sink according
+/// to `level`.
+pub(crate) unsafe fn traced<T>(
+    handle: *mut FileHandle,
+    level: TraceLevel,
+    operation: TraceOperation,
+    requested: usize,
+    bytes: fn(&T) -> usize,
+    run: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let sink = match current() {
+        Some(sink) => sink,
+        None => return run(),
+    };
+
+    let name = crate::file_handle_name(handle);
+    let span = TraceSpan {
+        operation,
+        handle_id: handle as usize,
+        type_name: (*handle).cold.type_name,
+        name: if name.is_null() {
+            None
+        } else {
+            CStr::from_ptr(name).to_str().ok()
+        },
+        requested,
+    };
+
+    let mut run = Some(run);
+    let mut result = None;
+    if level >= TraceLevel::Spans {
+        sink.span(&span, &mut || match run.take() {
+            Some(run) => {
+                let got = run();
+                let outcome = TraceOutcome {
+                    bytes: got.as_ref().map_or(0, bytes),
+                    error: got.as_ref().err().map(ThinErrorKind::from),
+                };
+                result = Some(got);
+                outcome
+            },
+            // called more than once
+            None => TraceOutcome::default(),
+        });
+    }
+
+    let result = match (result, run) {
+        (Some(result), _) => result,
+        (None, Some(run)) => run(),
+        (None, None) => unreachable!("The operation always leaves a result"),
+    };
+
+    if let Err(ref e) = result {
+        sink.error(&span, e);
+    }
+
+    result
+}
+
+/// Change how much a handle reports to the sink.
+pub(crate) unsafe fn set_tracing_level(
+    handle: *mut FileHandle,
+    level: TraceLevel,
+) {
+    match (*handle).extensions() {
+        None if level == TraceLevel::Off => {},
+        _ => (*handle).extensions_or_default().tracing.set(level),
+    }
+}
+
+export! {
+    /// Change how much this [`FileHandle`] reports to the Rust host's trace
+    /// sink, using [`TRACE_LEVEL_OFF`], [`TRACE_LEVEL_ERRORS`], or
+    /// [`TRACE_LEVEL_SPANS`].
+    ///
+    /// Returns `-1` if the level isn't recognised.
+    pub unsafe extern "C" fn file_handle_set_tracing_level(
+        handle: *mut FileHandle,
+        level: c_int,
+    ) -> c_int {
+        match TraceLevel::from_raw(level) {
+            Some(level) => {
+                set_tracing_level(handle, level);
+                0
+            },
+            None => -1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, lifecycle::lock_global_state, OwnedFileHandle};
+    use std::io::{ErrorKind, Write};
+
+    type Recorded = (TraceOperation, usize, usize, TraceOutcome);
+
+    /// Remembers every span and error it is sent.
+    #[derive(Default)]
+    struct Recorder {
+        spans: Mutex<Vec<Recorded>>,
+        errors: Mutex<Vec<(TraceOperation, ErrorKind)>>,
+    }
+
+    impl TraceSink for Recorder {
+        fn span(
+            &self,
+            span: &TraceSpan<'_>,
+            operation: &mut dyn FnMut() -> TraceOutcome,
+        ) {
+            let outcome = operation();
+            let recorded =
+                (span.operation, span.handle_id, span.requested, outcome);
+            self.spans.lock().unwrap().push(recorded);
+        }
+
+        fn error(&self, span: &TraceSpan<'_>, error: &Error) {
+            let event = (span.operation, error.kind());
+            self.errors.lock().unwrap().push(event);
+        }
+    }
+
+    fn install() -> Arc<Recorder> {
+        let recorder = Arc::new(Recorder::default());
+        set_trace_sink(Arc::clone(&recorder) as Arc<dyn TraceSink>);
+        recorder
+    }
+
+    /// A writer which rejects everything.
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+            Err(Error::from(ErrorKind::BrokenPipe))
+        }
+
+        fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+
+    #[test]
+    fn writes_and_flushes_are_wrapped_in_spans() {
+        let _global = lock_global_state();
+        let recorder = install();
+        let mut handle = OwnedFileHandle::new(Vec::<u8>::new());
+        let id = handle.as_ptr() as usize;
+
+        handle.write_all(b"untraced").unwrap();
+        handle.set_tracing_level(TraceLevel::Spans);
+        handle.write_all(b"asdf").unwrap();
+        handle.flush().unwrap();
+        handle.set_tracing_level(TraceLevel::Off);
+        handle.write_all(b"untraced").unwrap();
+
+        let written = TraceOutcome {
+            bytes: 4,
+            error: None,
+        };
+        assert_eq!(
+            *recorder.spans.lock().unwrap(),
+            vec![
+                (TraceOperation::Write, id, 4, written),
+                (TraceOperation::Flush, id, 0, TraceOutcome::default()),
+            ]
+        );
+        assert!(recorder.errors.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failures_are_reported_at_every_level() {
+        let _global = lock_global_state();
+        let recorder = install();
+
+        unsafe {
+            let handle = FileHandle::for_writer(Broken);
+            assert_eq!(file_handle_set_tracing_level(handle, 42), -1);
+
+            let level = TRACE_LEVEL_ERRORS;
+            assert_eq!(file_handle_set_tracing_level(handle, level), 0);
+            assert!(file_handle_write(handle, b"a".as_ptr().cast(), 1) < 0);
+            assert!(recorder.spans.lock().unwrap().is_empty());
+
+            file_handle_set_tracing_level(handle, TRACE_LEVEL_SPANS);
+            assert!(file_handle_write(handle, b"a".as_ptr().cast(), 1) < 0);
+            file_handle_destroy(handle);
+        }
+
+        let errors = recorder.errors.lock().unwrap();
+        let broken = (TraceOperation::Write, ErrorKind::BrokenPipe);
+        assert_eq!(*errors, vec![broken, broken]);
+        let spans = recorder.spans.lock().unwrap();
+        assert_eq!(spans[0].3.error, Some(ThinErrorKind::BrokenPipe));
+    }
+}
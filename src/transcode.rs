@@ -0,0 +1,369 @@
+//! Converting text between encodings as it is written.
+//!
+//! Only the handful of encodings C hosts commonly produce are supported, so
+//! everything is implemented using `std` instead of pulling in a full
+//! encoding library.
+
+use crate::{FileHandle, OwnedFileHandle};
+use std::{
+    io::{Error, Write},
+    os::raw::c_int,
+    ptr,
+};
+
+/// A text encoding understood by [`TranscodingWriter`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub enum Encoding {
+    /// UTF-8.
+    Utf8 = 0,
+    /// UTF-16, little-endian (what Windows uses for `wchar_t` strings).
+    Utf16Le = 1,
+    /// UTF-16, big-endian.
+    Utf16Be = 2,
+    /// ISO-8859-1, where each byte is the Unicode code point with the same
+    /// value.
+    Latin1 = 3,
+    /// Windows code page 1252 ("Western European").
+    Windows1252 = 4,
+}
+
+/// The characters for bytes `0x80` to `0x9F` in code page 1252, which is
+/// otherwise identical to [`Encoding::Latin1`].
+const WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}',
+    '\u{2020}', '\u{2021}', '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}',
+    '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}', '\u{90}', '\u{2018}',
+    '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}',
+    '\u{17E}', '\u{178}',
+];
+
+impl Encoding {
+    const ALL: [Encoding; 5] = [
+        Encoding::Utf8,
+        Encoding::Utf16Le,
+        Encoding::Utf16Be,
+        Encoding::Latin1,
+        Encoding::Windows1252,
+    ];
+
+    /// Convert the integer representation back into an [`Encoding`].
+    pub fn from_raw(raw: c_int) -> Option<Encoding> {
+        Encoding::ALL.iter().copied().find(|&e| e as c_int == raw)
+    }
+
+    /// Decode as much of `bytes` as possible, returning how many bytes were
+    /// used.
+    ///
+    /// Invalid input becomes `U+FFFD`. An incomplete sequence at the end is
+    /// left for next time, unless this is the `last` of the input.
+    fn decode(self, bytes: &[u8], last: bool, text: &mut String) -> usize {
+        match self {
+            Encoding::Utf8 => decode_utf8(bytes, last, text),
+            Encoding::Utf16Le => {
+                decode_utf16(bytes, last, text, u16::from_le_bytes)
+            },
+            Encoding::Utf16Be => {
+                decode_utf16(bytes, last, text, u16::from_be_bytes)
+            },
+            Encoding::Latin1 => {
+                text.extend(bytes.iter().map(|&b| char::from(b)));
+                bytes.len()
+            },
+            Encoding::Windows1252 => {
+                text.extend(bytes.iter().map(|&b| match b {
+                    0x80..=0x9F => WINDOWS_1252[usize::from(b - 0x80)],
+                    _ => char::from(b),
+                }));
+                bytes.len()
+            },
+        }
+    }
+
+    /// Encode `text`, replacing characters which can't be represented with
+    /// `?`.
+    fn encode(self, text: &str, bytes: &mut Vec<u8>) {
+        match self {
+            Encoding::Utf8 => bytes.extend_from_slice(text.as_bytes()),
+            Encoding::Utf16Le => {
+                for unit in text.encode_utf16() {
+                    bytes.extend_from_slice(&unit.to_le_bytes());
+                }
+            },
+            Encoding::Utf16Be => {
+                for unit in text.encode_utf16() {
+                    bytes.extend_from_slice(&unit.to_be_bytes());
+                }
+            },
+            Encoding::Latin1 => bytes.extend(text.chars().map(|c| {
+                if (c as u32) < 0x100 {
+                    c as u8
+                } else {
+                    b'?'
+                }
+            })),
+            Encoding::Windows1252 => {
+                bytes.extend(text.chars().map(encode_windows_1252))
+            },
+        }
+    }
+}
+
+fn encode_windows_1252(c: char) -> u8 {
+    if let Some(index) = WINDOWS_1252.iter().position(|&w| w == c) {
+        return 0x80 + index as u8;
+    }
+
+    match c as u32 {
+        0..=0x7F | 0xA0..=0xFF => c as u8,
+        _ => b'?',
+    }
+}
+
+fn decode_utf8(bytes: &[u8], last: bool, text: &mut String) -> usize {
+    let mut rest = bytes;
+
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return bytes.len();
+            },
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                text.extend(std::str::from_utf8(valid));
+
+                match e.error_len() {
+                    Some(invalid) => {
+                        text.push(std::char::REPLACEMENT_CHARACTER);
+                        rest = &after[invalid..];
+                    },
+                    // the sequence was cut off part way through
+                    None if last => {
+                        text.push(std::char::REPLACEMENT_CHARACTER);
+                        return bytes.len();
+                    },
+                    None => return bytes.len() - after.len(),
+                }
+            },
+        }
+    }
+}
+
+fn decode_utf16(
+    bytes: &[u8],
+    last: bool,
+    text: &mut String,
+    to_unit: fn([u8; 2]) -> u16,
+) -> usize {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| to_unit([pair[0], pair[1]]))
+        .collect();
+
+    // hold back an odd byte or a high surrogate missing its other half
+    let is_high_surrogate = |u: &u16| (0xD800..0xDC00).contains(u);
+    let mut complete = units.len();
+    if !last && units.last().map_or(false, is_high_surrogate) {
+        complete -= 1;
+    }
+
+    text.extend(
+        std::char::decode_utf16(units[..complete].iter().copied())
+            .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER)),
+    );
+
+    if last {
+        if bytes.len() % 2 == 1 {
+            text.push(std::char::REPLACEMENT_CHARACTER);
+        }
+        bytes.len()
+    } else {
+        complete * 2
+    }
+}
+
+/// A writer which converts text from one [`Encoding`] to another before
+/// passing it to an inner handle.
+///
+/// Characters may be split across writes (e.g. half of a UTF-16 surrogate
+/// pair), in which case the leftover bytes are held back until the rest
+/// arrives. Anything still held back when the writer is dropped is written
+/// as a replacement character.
+///
+/// ```rust
+/// # use std::io::Write;
+/// # use thin_trait_objects::{Encoding, OwnedFileHandle, TranscodingWriter};
+/// let inner = OwnedFileHandle::new(Vec::<u8>::new());
+/// let mut writer =
+///     TranscodingWriter::new(inner, Encoding::Utf16Le, Encoding::Utf8);
+///
+/// writer.write_all(&[0x48, 0x00, 0x69]).unwrap();
+/// writer.write_all(&[0x00]).unwrap();
+///
+/// let inner = writer.into_inner();
+/// assert_eq!(inner.downcast_ref::<Vec<u8>>().unwrap(), b"Hi");
+/// ```
+pub struct TranscodingWriter {
+    inner: Option<OwnedFileHandle>,
+    from: Encoding,
+    to: Encoding,
+    pending: Vec<u8>,
+}
+
+impl TranscodingWriter {
+    /// Create a new [`TranscodingWriter`] which converts text from `from` to
+    /// `to`.
+    pub fn new(inner: OwnedFileHandle, from: Encoding, to: Encoding) -> Self {
+        TranscodingWriter {
+            inner: Some(inner),
+            from,
+            to,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Finish transcoding and get the inner handle back.
+    pub fn into_inner(mut self) -> OwnedFileHandle {
+        let _ = self.convert(&[], true);
+        self.inner.take().expect("The inner handle is only taken once")
+    }
+
+    fn convert(&mut self, buf: &[u8], last: bool) -> Result<(), Error> {
+        self.pending.extend_from_slice(buf);
+
+        let mut text = String::new();
+        let used = self.from.decode(&self.pending, last, &mut text);
+        self.pending.drain(..used);
+
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let mut bytes = Vec::with_capacity(text.len());
+        self.to.encode(&text, &mut bytes);
+
+        match self.inner.as_mut() {
+            Some(inner) => inner.write_all(&bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// The handle being wrapped.
+    pub(crate) fn get_ref(&self) -> Option<&OwnedFileHandle> {
+        self.inner.as_ref()
+    }
+}
+
+impl Write for TranscodingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.convert(buf, false)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TranscodingWriter {
+    fn drop(&mut self) { let _ = self.convert(&[], true); }
+}
+
+export! {
+    /// Create a new [`FileHandle`] which converts text written to it from
+    /// `from_encoding` to `to_encoding` (both [`Encoding`] values) before
+    /// passing it to `inner`, taking ownership of `inner`.
+    ///
+    /// Returns `null` if `inner` is `null` or either encoding is unknown, in
+    /// which case `inner` is left untouched.
+    pub unsafe extern "C" fn new_transcoding_file_handle(
+        inner: *mut FileHandle,
+        from_encoding: c_int,
+        to_encoding: c_int,
+    ) -> *mut FileHandle {
+        let from = Encoding::from_raw(from_encoding);
+        let to = Encoding::from_raw(to_encoding);
+
+        match (from, to) {
+            (Some(from), Some(to)) if !inner.is_null() => {
+                let inner = OwnedFileHandle::from_raw(inner);
+                FileHandle::for_writer(TranscodingWriter::new(inner, from, to))
+            },
+            _ => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    fn transcode(from: Encoding, to: Encoding, writes: &[&[u8]]) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let inner = OwnedFileHandle::new(buffer.clone());
+        let mut writer = TranscodingWriter::new(inner, from, to);
+
+        for data in writes {
+            writer.write_all(data).unwrap();
+        }
+        drop(writer);
+
+        let written = buffer.0.lock().unwrap();
+        written.clone()
+    }
+
+    #[test]
+    fn split_sequences_are_reassembled() {
+        // "é😀" split in awkward places
+        let utf8 = "é😀".as_bytes();
+        let got = transcode(
+            Encoding::Utf8,
+            Encoding::Utf16Le,
+            &[&utf8[..1], &utf8[1..3], &utf8[3..]],
+        );
+        assert_eq!(got, [0xE9, 0x00, 0x3D, 0xD8, 0x00, 0xDE]);
+
+        let got = transcode(
+            Encoding::Utf16Le,
+            Encoding::Utf8,
+            &[&[0xE9, 0x00, 0x3D], &[0xD8], &[0x00, 0xDE]],
+        );
+        assert_eq!(got, utf8);
+    }
+
+    #[test]
+    fn code_pages_and_bad_input() {
+        let got = transcode(Encoding::Windows1252, Encoding::Utf8, &[b"\x80"]);
+        assert_eq!(got, "€".as_bytes());
+        let text = "é€".as_bytes();
+        let got = transcode(Encoding::Utf8, Encoding::Latin1, &[text]);
+        assert_eq!(got, b"\xE9?");
+
+        // a truncated sequence at the very end becomes U+FFFD
+        let got = transcode(Encoding::Utf8, Encoding::Utf8, &[b"a\xF0\x9F"]);
+        assert_eq!(got, "a\u{FFFD}".as_bytes());
+    }
+
+    #[test]
+    fn invalid_arguments_from_c() {
+        unsafe {
+            let inner = new_memory_file_handle();
+            assert!(new_transcoding_file_handle(inner, 42, 0).is_null());
+
+            let handle = new_transcoding_file_handle(
+                inner,
+                Encoding::Latin1 as c_int,
+                Encoding::Utf8 as c_int,
+            );
+            let ret = file_handle_write(handle, b"\xFF".as_ptr().cast(), 1);
+            assert_eq!(ret, 1);
+            file_handle_destroy(handle);
+        }
+    }
+}
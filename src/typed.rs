@@ -0,0 +1,178 @@
+//! An [`OwnedFileHandle`] which remembers the type of its writer, so Rust
+//! code can skip the dynamic dispatch.
+
+use crate::{file_handle::Repr, FileHandle, OwnedFileHandle};
+use std::{
+    fmt::{self, Debug, Formatter},
+    io::{Error, Write},
+    marker::PhantomData,
+};
+
+/// An [`OwnedFileHandle`] known to wrap a `W`.
+///
+/// Writing to a [`TypedFileHandle`] calls `W`'s methods directly instead of
+/// going through the handle's function pointers, so the compiler can inline
+/// them into hot loops, while [`TypedFileHandle::into_raw()`] still gives
+/// native code a normal `*mut FileHandle`.
+///
+/// Because they skip the dispatch, writes from Rust also skip any policies
+/// set on the handle itself (quotas, write filters, statistics, freezing,
+/// poisoning, etc.). Native code using the raw pointer still gets them.
+///
+/// ```rust
+/// # use std::io::Write;
+/// # use thin_trait_objects::{OwnedFileHandle, TypedFileHandle};
+/// let mut handle = TypedFileHandle::new(Vec::new());
+/// handle.write_all(b"Hello, World!").unwrap();
+/// assert_eq!(handle.get_ref(), b"Hello, World!");
+///
+/// // converting back and forth keeps the same handle
+/// let owned: OwnedFileHandle = handle.into();
+/// let handle = owned.into_typed::<Vec<u8>>().unwrap();
+/// assert_eq!(handle.into_inner(), b"Hello, World!");
+/// ```
+#[repr(transparent)]
+pub struct TypedFileHandle<W> {
+    handle: OwnedFileHandle,
+    _writer: PhantomData<W>,
+}
+
+impl<W: Write + Send + Sync + 'static> TypedFileHandle<W> {
+    /// Create a new handle for `writer`.
+    pub fn new(writer: W) -> Self {
+        unsafe { TypedFileHandle::from_raw(FileHandle::for_writer(writer)) }
+    }
+}
+
+impl<W: 'static> TypedFileHandle<W> {
+    /// Take ownership of a `*mut FileHandle`, returning `None` (and leaving
+    /// the handle alone) if it doesn't wrap a `W`.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`OwnedFileHandle::from_raw()`].
+    pub unsafe fn try_from_raw(handle: *mut FileHandle) -> Option<Self> {
+        if (*handle).cold.type_id == std::any::TypeId::of::<W>() {
+            Some(TypedFileHandle::from_raw(handle))
+        } else {
+            None
+        }
+    }
+
+    unsafe fn from_raw(handle: *mut FileHandle) -> Self {
+        TypedFileHandle {
+            handle: OwnedFileHandle::from_raw(handle),
+            _writer: PhantomData,
+        }
+    }
+
+    fn repr(&self) -> *mut Repr<W> { self.handle.as_ptr().cast() }
+
+    /// Get a reference to the writer.
+    pub fn get_ref(&self) -> &W { unsafe { &(*self.repr()).writer } }
+
+    /// Get a mutable reference to the writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        unsafe { &mut (*self.repr()).writer }
+    }
+
+    /// Get the handle's type-erased API (e.g. to check
+    /// [`OwnedFileHandle::is_poisoned()`]).
+    pub fn as_owned(&self) -> &OwnedFileHandle { &self.handle }
+
+    /// Forget the writer's type.
+    pub fn into_owned(self) -> OwnedFileHandle { self.handle }
+
+    /// Consume the handle and get a `*mut FileHandle` that can be used from
+    /// native code (see [`OwnedFileHandle::into_raw()`]).
+    pub fn into_raw(self) -> *mut FileHandle { self.handle.into_raw() }
+
+    /// Destroy the handle, giving back the writer.
+    pub fn into_inner(self) -> W {
+        match self.handle.downcast() {
+            Ok(writer) => writer,
+            Err(_) => unreachable!("The handle always wraps a W"),
+        }
+    }
+}
+
+impl OwnedFileHandle {
+    /// Remember that this handle wraps a `W`, so writes from Rust can call
+    /// it directly.
+    ///
+    /// The handle is given back if it wraps something else.
+    pub fn into_typed<W: 'static>(self) -> Result<TypedFileHandle<W>, Self> {
+        if self.is::<W>() {
+            Ok(TypedFileHandle {
+                handle: self,
+                _writer: PhantomData,
+            })
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<W: Write + 'static> Write for TypedFileHandle<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.get_mut().write(buf)
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.get_mut().write_all(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), Error> { self.get_mut().flush() }
+}
+
+impl<W> From<TypedFileHandle<W>> for OwnedFileHandle {
+    fn from(typed: TypedFileHandle<W>) -> OwnedFileHandle { typed.handle }
+}
+
+impl<W> Debug for TypedFileHandle<W> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.handle, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+
+    #[test]
+    fn only_the_right_type_can_be_typed() {
+        let handle = OwnedFileHandle::new(SharedBuffer::default());
+
+        let handle = handle.into_typed::<Vec<u8>>().unwrap_err();
+        let typed = handle.into_typed::<SharedBuffer>().unwrap();
+
+        unsafe {
+            let raw = typed.into_raw();
+            assert!(TypedFileHandle::<Vec<u8>>::try_from_raw(raw).is_none());
+            let typed = TypedFileHandle::<SharedBuffer>::try_from_raw(raw);
+            assert!(typed.is_some());
+        }
+    }
+
+    #[test]
+    fn rust_and_native_code_write_to_the_same_object() {
+        let buffer = SharedBuffer::default();
+        let mut typed = TypedFileHandle::new(buffer.clone());
+
+        typed.write_all(b"Hello, ").unwrap();
+        let raw = typed.into_raw();
+        unsafe {
+            let ret = file_handle_write(raw, b"World!".as_ptr().cast(), 6);
+            assert_eq!(ret, 6);
+            let mut typed =
+                TypedFileHandle::<SharedBuffer>::try_from_raw(raw).unwrap();
+            typed.flush().unwrap();
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"Hello, World!");
+    }
+}
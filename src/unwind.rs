@@ -0,0 +1,209 @@
+//! Keeping panics and foreign exceptions from unwinding across the FFI
+//! boundary in ways the other side doesn't expect.
+//!
+//! By default every exported function and callback uses `extern "C"`, so an
+//! unwind which tries to cross the boundary (e.g. a C++ exception thrown by
+//! a callback) aborts the process. Enabling the `c-unwind` feature (which
+//! requires Rust 1.71) switches the functions which call back into
+//! user-supplied code over to `extern "C-unwind"`, letting such exceptions
+//! propagate back to the caller instead. Any handle an unwind passes through
+//! is poisoned.
+//!
+//! Rust code which implements a callback for C should use a
+//! [`PanicBarrier`] so its panics never reach the boundary at all.
+
+use crate::FileHandle;
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+};
+
+/// Declare a function (or callback type) which uses `extern "C-unwind"`
+/// when the `c-unwind` feature is enabled and `extern "C"` otherwise.
+///
+/// Functions declared as `extern fn` are exported with [`export!`].
+macro_rules! c_unwind {
+    (
+        $(#[$attr:meta])*
+        $vis:vis unsafe extern fn $name:ident ($($args:tt)*) $(-> $ret:ty)?
+            $body:block
+    ) => {
+        export! {
+            $(#[$attr])*
+            #[cfg(not(feature = "c-unwind"))]
+            $vis unsafe extern "C" fn $name($($args)*) $(-> $ret)? $body
+        }
+
+        export! {
+            $(#[$attr])*
+            #[cfg(feature = "c-unwind")]
+            $vis unsafe extern "C-unwind" fn $name($($args)*) $(-> $ret)? $body
+        }
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis unsafe fn $name:ident $(<$($gen:ident),*>)?
+            ($($args:tt)*) $(-> $ret:ty)? $body:block
+    ) => {
+        $(#[$attr])*
+        #[cfg(not(feature = "c-unwind"))]
+        $vis unsafe extern "C" fn $name $(<$($gen),*>)? ($($args)*)
+            $(-> $ret)? $body
+
+        $(#[$attr])*
+        #[cfg(feature = "c-unwind")]
+        $vis unsafe extern "C-unwind" fn $name $(<$($gen),*>)? ($($args)*)
+            $(-> $ret)? $body
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis type $name:ident $(<$($gen:ident),*>)? =
+            unsafe fn($($arg:ty),* $(,)?) $(-> $ret:ty)?;
+    ) => {
+        $(#[$attr])*
+        #[cfg(not(feature = "c-unwind"))]
+        $vis type $name $(<$($gen),*>)? =
+            unsafe extern "C" fn($($arg),*) $(-> $ret)?;
+
+        $(#[$attr])*
+        #[cfg(feature = "c-unwind")]
+        $vis type $name $(<$($gen),*>)? =
+            unsafe extern "C-unwind" fn($($arg),*) $(-> $ret)?;
+    };
+}
+
+/// Poisons a [`FileHandle`] if it is dropped during an unwind (i.e. a
+/// callback unwound through the handle instead of returning normally).
+///
+/// Unlike `catch_unwind()` this also works for foreign exceptions, which
+/// Rust isn't allowed to catch.
+pub(crate) struct PoisonOnUnwind {
+    handle: *mut FileHandle,
+    completed: bool,
+}
+
+impl PoisonOnUnwind {
+    pub(crate) fn new(handle: *mut FileHandle) -> Self {
+        PoisonOnUnwind {
+            handle,
+            completed: false,
+        }
+    }
+
+    /// The callback returned normally, so the handle is still usable.
+    pub(crate) fn disarm(mut self) { self.completed = true; }
+}
+
+impl Drop for PoisonOnUnwind {
+    fn drop(&mut self) {
+        if !self.completed {
+            unsafe {
+                (*self.handle).set_flag(
+                    FileHandle::POISONED | FileHandle::LEAK_ON_DESTROY,
+                );
+            }
+        }
+    }
+}
+
+/// Stops panics from escaping a callback which is called from C.
+///
+/// The first panic is caught and held on to, and the callback returns a
+/// fallback value instead. Once the C code has returned control to Rust the
+/// panic can be re-raised with [`PanicBarrier::resume()`].
+///
+/// ```rust
+/// # use thin_trait_objects::PanicBarrier;
+/// let mut barrier = PanicBarrier::new();
+///
+/// let ret = barrier.call(-1, || -> i32 { panic!("Oops...") });
+///
+/// assert_eq!(ret, -1);
+/// assert!(barrier.has_panicked());
+/// ```
+#[derive(Debug, Default)]
+pub struct PanicBarrier {
+    payload: Option<Box<dyn Any + Send>>,
+}
+
+impl PanicBarrier {
+    /// Create a new [`PanicBarrier`].
+    pub fn new() -> Self { PanicBarrier::default() }
+
+    /// Run `thunk`, returning `on_panic` if it panics.
+    ///
+    /// After a panic, later calls return `on_panic` without running `thunk`
+    /// because whatever it was using may have been left in an inconsistent
+    /// state.
+    pub fn call<T, F>(&mut self, on_panic: T, thunk: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        if self.has_panicked() {
+            return on_panic;
+        }
+
+        match panic::catch_unwind(AssertUnwindSafe(thunk)) {
+            Ok(value) => value,
+            Err(payload) => {
+                self.payload = Some(payload);
+                on_panic
+            },
+        }
+    }
+
+    /// Has a callback run by this barrier panicked?
+    pub fn has_panicked(&self) -> bool { self.payload.is_some() }
+
+    /// Take the payload of the panic which was caught, if there was one.
+    pub fn take_panic(&mut self) -> Option<Box<dyn Any + Send>> {
+        self.payload.take()
+    }
+
+    /// Re-raise the panic which was caught, if there was one.
+    pub fn resume(&mut self) {
+        if let Some(payload) = self.take_panic() {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+
+    #[test]
+    fn panics_are_caught_and_can_be_resumed() {
+        let mut barrier = PanicBarrier::new();
+
+        assert_eq!(barrier.call(0, || 42), 42);
+        assert_eq!(barrier.call(0, || panic!("Oops...")), 0);
+        assert!(barrier.has_panicked());
+        // the callback isn't run again after panicking
+        assert_eq!(barrier.call(0, || 42), 0);
+
+        let got = panic::catch_unwind(AssertUnwindSafe(|| barrier.resume()));
+        let payload = got.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"Oops..."));
+        assert!(!barrier.has_panicked());
+    }
+
+    #[test]
+    fn unwinding_through_a_handle_poisons_it() {
+        unsafe {
+            let handle = new_null_file_handle();
+
+            PoisonOnUnwind::new(handle).disarm();
+            assert!(!file_handle_is_poisoned(handle));
+
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _guard = PoisonOnUnwind::new(handle);
+                panic!("Unwinding");
+            }));
+            assert!(file_handle_is_poisoned(handle));
+
+            file_handle_destroy(handle);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Sanity checks for handles whose callbacks are supplied by the caller (see
+//! [`file_handle_builder_set_validation()`]), so a misbehaving callback is
+//! reported instead of silently corrupting memory.
+//!
+//! [`file_handle_builder_set_validation()`]:
+//! crate::file_handle_builder_set_validation
+
+use crate::{global::Global, log_bridge, FileHandle, LogLevel, LogRecord};
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    ptr, slice,
+    sync::Mutex,
+};
+
+/// How many canary bytes follow a validated object.
+pub(crate) const CANARY_LEN: usize = 16;
+
+const CANARY_BYTE: u8 = 0xFD;
+
+/// How many destroyed handles are kept around so destroying one a second
+/// time can be detected.
+const QUARANTINE_LEN: usize = 64;
+
+/// Destroyed handles which haven't been freed yet, oldest first.
+static QUARANTINE: Global<Mutex<VecDeque<Quarantined>>> = Global::new();
+
+struct Quarantined {
+    handle: usize,
+    free: unsafe fn(*mut FileHandle),
+}
+
+pub(crate) unsafe fn fill_canary(canary: *mut u8) {
+    ptr::write_bytes(canary, CANARY_BYTE, CANARY_LEN);
+}
+
+pub(crate) unsafe fn canary_intact(canary: *const u8) -> bool {
+    slice::from_raw_parts(canary, CANARY_LEN)
+        .iter()
+        .all(|&b| b == CANARY_BYTE)
+}
+
+/// Report a callback which broke its contract, poisoning the handle so it
+/// isn't called again.
+pub(crate) unsafe fn violation(
+    handle: *mut FileHandle,
+    message: String,
+) -> Error {
+    log_bridge::emit_to_global_sink(&LogRecord {
+        level: LogLevel::Error,
+        target: "thin_trait_objects::validation",
+        message: &message,
+    });
+    (*handle).set_flag(FileHandle::POISONED);
+
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Hold on to a destroyed handle's memory for a while, calling `free` once
+/// it is evicted to make room for newer handles.
+pub(crate) unsafe fn quarantine(
+    handle: *mut FileHandle,
+    free: unsafe fn(*mut FileHandle),
+) {
+    let evicted = {
+        let quarantine = QUARANTINE.get_or_init(Default::default);
+        let mut quarantine =
+            quarantine.lock().unwrap_or_else(|e| e.into_inner());
+
+        quarantine.push_back(Quarantined {
+            handle: handle as usize,
+            free,
+        });
+
+        if quarantine.len() > QUARANTINE_LEN {
+            quarantine.pop_front()
+        } else {
+            None
+        }
+    };
+
+    if let Some(Quarantined { handle, free }) = evicted {
+        free(handle as *mut FileHandle);
+    }
+}
+
+/// Free every quarantined handle.
+pub(crate) unsafe fn shutdown() {
+    let drained: Vec<Quarantined> = match QUARANTINE.get() {
+        Some(quarantine) => quarantine
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .drain(..)
+            .collect(),
+        None => return,
+    };
+
+    for Quarantined { handle, free } in drained {
+        free(handle as *mut FileHandle);
+    }
+}
@@ -0,0 +1,123 @@
+//! Which build of the crate is running, so native hosts can log it when
+//! diagnosing ABI mismatches.
+//!
+//! Everything besides the version is captured by `build.rs`.
+
+use crate::FfiStr;
+
+/// The crate's version, with a null terminator.
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+const GIT_HASH: &str = concat!(env!("THIN_TRAIT_OBJECTS_GIT_HASH"), "\0");
+const RUSTC_VERSION: &str =
+    concat!(env!("THIN_TRAIT_OBJECTS_RUSTC_VERSION"), "\0");
+const TARGET: &str = concat!(env!("THIN_TRAIT_OBJECTS_TARGET"), "\0");
+const PROFILE: &str = concat!(env!("THIN_TRAIT_OBJECTS_PROFILE"), "\0");
+const FEATURES: &str = concat!(env!("THIN_TRAIT_OBJECTS_FEATURES"), "\0");
+const SYMBOL_PREFIX: &str =
+    concat!(env!("THIN_TRAIT_OBJECTS_SYMBOL_PREFIX"), "\0");
+
+/// Details about how this copy of the crate was built.
+///
+/// Every field points to a static string.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct BuildInfo {
+    /// The crate's version (e.g. `"0.1.0"`).
+    pub version: FfiStr,
+    /// The git commit the crate was built from, with a `-dirty` suffix if
+    /// there were uncommitted changes, or `"unknown"` if it wasn't built from
+    /// a git checkout.
+    pub git_hash: FfiStr,
+    /// The output of `rustc --version`.
+    pub rustc_version: FfiStr,
+    /// The target triple (e.g. `"x86_64-unknown-linux-gnu"`).
+    pub target: FfiStr,
+    /// Either `"debug"` or `"release"`.
+    pub profile: FfiStr,
+    /// The enabled cargo features, separated by commas.
+    pub features: FfiStr,
+}
+
+impl BuildInfo {
+    /// Get the [`BuildInfo`] for this copy of the crate.
+    pub fn current() -> Self {
+        BuildInfo {
+            version: FfiStr::from_nul_terminated(VERSION),
+            git_hash: FfiStr::from_nul_terminated(GIT_HASH),
+            rustc_version: FfiStr::from_nul_terminated(RUSTC_VERSION),
+            target: FfiStr::from_nul_terminated(TARGET),
+            profile: FfiStr::from_nul_terminated(PROFILE),
+            features: FfiStr::from_nul_terminated(FEATURES),
+        }
+    }
+}
+
+/// The prefix added to every exported symbol (e.g. `"myplugin_"` if the
+/// crate was built with `THIN_TRAIT_OBJECTS_SYMBOL_PREFIX=myplugin_`), or
+/// `""` if there isn't one.
+pub fn symbol_prefix() -> &'static str {
+    &SYMBOL_PREFIX[..SYMBOL_PREFIX.len() - 1]
+}
+
+export! {
+    /// Get the crate's version as a static string.
+    pub unsafe extern "C" fn thin_trait_objects_version() -> FfiStr {
+        FfiStr::from_nul_terminated(VERSION)
+    }
+}
+
+export! {
+    /// Get details about how this copy of the crate was built.
+    pub unsafe extern "C" fn thin_trait_objects_build_info() -> BuildInfo {
+        BuildInfo::current()
+    }
+}
+
+export! {
+    /// Get the prefix added to every exported symbol, which is empty unless
+    /// one was chosen at build time (see [`symbol_prefix()`]).
+    pub unsafe extern "C" fn thin_trait_objects_symbol_prefix() -> FfiStr {
+        FfiStr::from_nul_terminated(SYMBOL_PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn version_matches_the_manifest() {
+        unsafe {
+            let version = thin_trait_objects_version();
+            assert_eq!(version.as_str(), env!("CARGO_PKG_VERSION"));
+
+            let terminated = CStr::from_ptr(version.data);
+            assert_eq!(terminated.to_bytes().len(), version.len);
+        }
+    }
+
+    #[test]
+    fn build_info_is_filled_in() {
+        let info = unsafe { thin_trait_objects_build_info() };
+
+        unsafe {
+            assert!(info.rustc_version.as_str().starts_with("rustc "));
+            assert!(!info.git_hash.as_str().is_empty());
+            assert!(["debug", "release"].contains(&info.profile.as_str()));
+            assert!(info.target.as_str().contains('-'));
+        }
+    }
+
+    #[test]
+    fn symbol_prefix_is_a_c_identifier() {
+        let prefix = unsafe { thin_trait_objects_symbol_prefix() };
+        let prefix = unsafe { prefix.as_str() };
+
+        assert_eq!(prefix, symbol_prefix());
+        assert!(prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        assert!(!prefix.starts_with(|c: char| c.is_ascii_digit()));
+    }
+}
@@ -0,0 +1,394 @@
+//! Compile-time checked declarations of FFI-safe vtables.
+
+use crate::{FfiSlice, FileHandle, ThinErrorKind, ThreadStats};
+use std::os::raw::c_void;
+
+/// A marker trait for types which may be passed across the FFI boundary.
+///
+/// This is implemented for primitives, raw pointers, `extern "C"` function
+/// pointers, and this crate's own `#[repr(C)]` types. It is used by
+/// [`thin_vtable!`][crate::thin_vtable] to reject method signatures which would
+/// silently produce an unsound vtable.
+///
+/// # Safety
+///
+/// Implementing this trait asserts that the type has a stable, C-compatible
+/// layout (e.g. it is `#[repr(C)]`, `#[repr(transparent)]`, or a `#[repr]`'d
+/// fieldless enum containing only [`FfiSafe`] fields).
+pub unsafe trait FfiSafe {}
+
+macro_rules! impl_ffi_safe {
+    ($($ty:ty),* $(,)?) => {
+        $( unsafe impl FfiSafe for $ty {} )*
+    };
+}
+
+impl_ffi_safe!(
+    (),
+    bool,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    f32,
+    f64,
+    FfiSlice,
+    ThinErrorKind,
+    ThreadStats,
+);
+
+unsafe impl<T> FfiSafe for *const T {}
+unsafe impl<T> FfiSafe for *mut T {}
+
+macro_rules! impl_ffi_safe_for_fn_pointers {
+    ($( ($($arg:ident),*) ),* $(,)?) => {
+        $(
+            unsafe impl<Ret: FfiSafe, $($arg: FfiSafe),*> FfiSafe
+                for unsafe extern "C" fn($($arg),*) -> Ret {}
+            unsafe impl<Ret: FfiSafe, $($arg: FfiSafe),*> FfiSafe
+                for Option<unsafe extern "C" fn($($arg),*) -> Ret> {}
+        )*
+    };
+}
+
+impl_ffi_safe_for_fn_pointers!(
+    (),
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+);
+
+#[doc(hidden)]
+pub fn assert_ffi_safe<T: FfiSafe>() {}
+
+/// Declare a `#[repr(C)]` vtable for a thin trait object.
+///
+/// Each method becomes a field containing an
+/// `unsafe extern "C" fn(*mut c_void, args...) -> ret`, where the first
+/// argument is the object. The macro validates every method at compile time:
+///
+/// - Generic methods can't be represented by a single function pointer
+/// - Only the `destroy` method may take `self` by value
+/// - Every argument and return type must implement [`FfiSafe`]
+///
+/// ```rust
+/// use std::os::raw::c_int;
+///
+/// thin_trait_objects::thin_vtable! {
+///     /// The vtable for a counter.
+///     pub struct CounterVTable {
+///         fn destroy(self);
+///         fn increment(&mut self, amount: u32) -> c_int;
+///         fn get(&self) -> u32;
+///     }
+/// }
+/// ```
+///
+/// Generic methods are rejected,
+///
+/// ```rust,compile_fail
+/// // error: The `convert` method can't be generic because each vtable slot
+/// // is a single function pointer
+/// thin_trait_objects::thin_vtable! {
+///     pub struct Generic {
+///         fn convert<T>(&self, value: T) -> u32;
+///     }
+/// }
+/// ```
+///
+/// as are methods (other than `destroy`) which take `self` by value,
+///
+/// ```rust,compile_fail
+/// // error: The `finish` method can't take `self` by value, only `destroy`
+/// // may do that
+/// thin_trait_objects::thin_vtable! {
+///     pub struct ByValue {
+///         fn finish(self) -> u32;
+///     }
+/// }
+/// ```
+///
+/// and arguments which aren't FFI-safe.
+///
+/// The first two come from `compile_error!()`, which doesn't have an error
+/// code, so the tests check their messages instead.
+///
+/// ```rust,compile_fail,E0277
+/// thin_trait_objects::thin_vtable! {
+///     pub struct NotFfiSafe {
+///         fn set_name(&mut self, name: String);
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! thin_vtable {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident { $($methods:tt)* }
+    ) => {
+        $crate::thin_vtable!(
+            @munch [$(#[$meta])*] [$vis] $name [] [] $($methods)*
+        );
+    };
+
+    // We've processed every method, emit the vtable
+    (
+        @munch [$($meta:tt)*] [$vis:vis] $name:ident
+        [$($fields:tt)*] [$($types:ty,)*]
+    ) => {
+        $($meta)*
+        #[repr(C)]
+        #[derive(Copy, Clone)]
+        $vis struct $name { $($fields)* }
+
+        const _: () = {
+            #[allow(dead_code)]
+            fn assert_all_types_are_ffi_safe() {
+                $( $crate::vtable::assert_ffi_safe::<$types>(); )*
+            }
+        };
+    };
+
+    // destroy() is the one method allowed to take `self` by value
+    (
+        @munch $meta:tt $vis:tt $name:ident [$($fields:tt)*] [$($types:tt)*]
+        $(#[$fmeta:meta])* fn destroy(self); $($rest:tt)*
+    ) => {
+        $crate::thin_vtable!(
+            @munch $meta $vis $name
+            [
+                $($fields)*
+                $(#[$fmeta])*
+                pub destroy: unsafe extern "C" fn(*mut ::std::os::raw::c_void),
+            ]
+            [$($types)*]
+            $($rest)*
+        );
+    };
+
+    (
+        @munch $meta:tt $vis:tt $name:ident $fields:tt $types:tt
+        $(#[$fmeta:meta])* fn $method:ident < $($rest:tt)*
+    ) => {
+        compile_error!(concat!(
+            "The `",
+            stringify!($method),
+            "` method can't be generic because each vtable slot is a single ",
+            "function pointer",
+        ));
+    };
+
+    (
+        @munch $meta:tt $vis:tt $name:ident $fields:tt $types:tt
+        $(#[$fmeta:meta])* fn $method:ident (self $($args:tt)*) $($rest:tt)*
+    ) => {
+        compile_error!(concat!(
+            "The `",
+            stringify!($method),
+            "` method can't take `self` by value, only `destroy` may do that",
+        ));
+    };
+
+    (
+        @munch $meta:tt $vis:tt $name:ident [$($fields:tt)*] [$($types:tt)*]
+        $(#[$fmeta:meta])*
+        fn $method:ident (&mut self $(, $arg:ident : $ty:ty)* $(,)?)
+            $(-> $ret:ty)?;
+        $($rest:tt)*
+    ) => {
+        $crate::thin_vtable!(
+            @munch $meta $vis $name
+            [
+                $($fields)*
+                $(#[$fmeta])*
+                pub $method: unsafe extern "C" fn(
+                    *mut ::std::os::raw::c_void,
+                    $($ty),*
+                ) $(-> $ret)?,
+            ]
+            [$($types)* $($ty,)* $($ret,)?]
+            $($rest)*
+        );
+    };
+
+    (
+        @munch $meta:tt $vis:tt $name:ident [$($fields:tt)*] [$($types:tt)*]
+        $(#[$fmeta:meta])*
+        fn $method:ident (&self $(, $arg:ident : $ty:ty)* $(,)?)
+            $(-> $ret:ty)?;
+        $($rest:tt)*
+    ) => {
+        $crate::thin_vtable!(
+            @munch $meta $vis $name
+            [
+                $($fields)*
+                $(#[$fmeta])*
+                pub $method: unsafe extern "C" fn(
+                    *const ::std::os::raw::c_void,
+                    $($ty),*
+                ) $(-> $ret)?,
+            ]
+            [$($types)* $($ty,)* $($ret,)?]
+            $($rest)*
+        );
+    };
+
+    (
+        @munch $meta:tt $vis:tt $name:ident $fields:tt $types:tt
+        $(#[$fmeta:meta])* fn $method:ident $($rest:tt)*
+    ) => {
+        compile_error!(concat!(
+            "Unable to parse the `",
+            stringify!($method),
+            "` method. Methods must look like ",
+            "`fn name(&self, arg: Type, ...) -> Ret;` or ",
+            "`fn name(&mut self, arg: Type, ...) -> Ret;`",
+        ));
+    };
+}
+
+// Make sure the types exported by this crate can be used in vtables
+const _: () = {
+    #[allow(dead_code)]
+    fn crate_types_are_ffi_safe() {
+        assert_ffi_safe::<*mut FileHandle>();
+        assert_ffi_safe::<*mut c_void>();
+        assert_ffi_safe::<unsafe extern "C" fn(*mut c_void) -> i32>();
+    }
+};
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        io::Write,
+        os::raw::{c_char, c_int, c_void},
+        path::Path,
+        process::{Command, Stdio},
+    };
+
+    thin_vtable! {
+        /// A vtable used for testing.
+        struct TestVTable {
+            fn destroy(self);
+            /// Write some data.
+            fn write(&mut self, data: *const c_char, len: c_int) -> c_int;
+            fn flush(&mut self) -> c_int;
+            fn len(&self) -> usize;
+        }
+    }
+
+    unsafe extern "C" fn destroy(_: *mut c_void) {}
+    unsafe extern "C" fn write(
+        _: *mut c_void,
+        _: *const c_char,
+        len: c_int,
+    ) -> c_int {
+        len
+    }
+    unsafe extern "C" fn flush(_: *mut c_void) -> c_int { 0 }
+    unsafe extern "C" fn len(_: *const c_void) -> usize { 42 }
+
+    /// Compile each snippet against this crate, returning the compiler's
+    /// output for each one.
+    fn compile(snippets: &[&str]) -> Vec<String> {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target_dir = manifest_dir.join("target").join("vtable-check");
+
+        let built = Command::new(env!("CARGO"))
+            .args(&["build", "--lib", "--target-dir"])
+            .arg(&target_dir)
+            .current_dir(manifest_dir)
+            .output()
+            .unwrap();
+        assert!(
+            built.status.success(),
+            "{}",
+            String::from_utf8_lossy(&built.stderr)
+        );
+
+        let debug = target_dir.join("debug");
+        let mut extern_arg = OsString::from("thin_trait_objects=");
+        extern_arg.push(debug.join("libthin_trait_objects.rlib"));
+        let mut deps_arg = OsString::from("dependency=");
+        deps_arg.push(debug.join("deps"));
+        let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+
+        snippets
+            .iter()
+            .map(|snippet| {
+                let mut child = Command::new(&rustc)
+                    .args(&["--edition=2018", "--crate-type=lib"])
+                    .args(&["--crate-name=snippet", "--emit=metadata"])
+                    .arg("--extern")
+                    .arg(&extern_arg)
+                    .arg("-L")
+                    .arg(&deps_arg)
+                    .arg("--out-dir")
+                    .arg(&target_dir)
+                    .arg("-")
+                    .stdin(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .unwrap();
+                child
+                    .stdin
+                    .take()
+                    .unwrap()
+                    .write_all(snippet.as_bytes())
+                    .unwrap();
+                let output = child.wait_with_output().unwrap();
+                assert!(!output.status.success(), "{}", snippet);
+
+                String::from_utf8(output.stderr).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejected_methods_say_why() {
+        let errors = compile(&[
+            "thin_trait_objects::thin_vtable! {
+                pub struct Generic { fn convert<T>(&self, value: T) -> u32; }
+            }",
+            "thin_trait_objects::thin_vtable! {
+                pub struct ByValue { fn finish(self) -> u32; }
+            }",
+        ]);
+
+        assert!(errors[0].contains(
+            "error: The `convert` method can't be generic because each \
+             vtable slot is a single function pointer"
+        ));
+        assert!(errors[1].contains(
+            "error: The `finish` method can't take `self` by value, only \
+             `destroy` may do that"
+        ));
+    }
+
+    #[test]
+    fn generated_vtable_can_be_used() {
+        let vtable = TestVTable {
+            destroy,
+            write,
+            flush,
+            len,
+        };
+
+        unsafe {
+            let object = std::ptr::null_mut();
+            assert_eq!((vtable.write)(object, std::ptr::null(), 5), 5);
+            assert_eq!((vtable.flush)(object), 0);
+            assert_eq!((vtable.len)(object), 42);
+            (vtable.destroy)(object);
+        }
+    }
+}
@@ -0,0 +1,543 @@
+//! Noticing writes and flushes which have been stuck for too long (e.g. on a
+//! hung network filesystem) and reacting before they freeze the whole host.
+
+use crate::{
+    errors::{crate_status_code, crate_status_unboxed, CRATE_ERROR_TIMED_OUT},
+    global::Global,
+    log_bridge::{self, LogLevel, LogRecord},
+    FileHandle,
+};
+use std::{
+    collections::HashMap,
+    io::Error,
+    os::raw::{c_int, c_void},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Returned by every operation on a [`FileHandle`] after its watchdog gave up
+/// on it with [`WATCHDOG_FAIL`].
+///
+/// This is [`CRATE_ERROR_TIMED_OUT`] in the [`ErrorDomain::Crate`] domain,
+/// so it can't be confused with an `ETIMEDOUT` from the writer itself.
+///
+/// [`CRATE_ERROR_TIMED_OUT`]: crate::CRATE_ERROR_TIMED_OUT
+/// [`ErrorDomain::Crate`]: crate::ErrorDomain::Crate
+pub const FILE_HANDLE_TIMED_OUT: c_int =
+    crate_status_code(CRATE_ERROR_TIMED_OUT);
+
+/// Log a warning (see [`set_log_sink()`][crate::set_log_sink]) when an
+/// operation takes too long.
+pub const WATCHDOG_LOG: c_int = 0;
+/// Call the callback passed to [`file_handle_set_watchdog_callback()`].
+pub const WATCHDOG_CALLBACK: c_int = 1;
+/// Mark the handle as failed, so every later operation immediately fails
+/// with [`FILE_HANDLE_TIMED_OUT`].
+pub const WATCHDOG_FAIL: c_int = 2;
+
+c_unwind! {
+    /// Told how long a handle's operation has been running so far, in
+    /// milliseconds.
+    pub(crate) type WatchdogCallback =
+        unsafe fn(*mut c_void, *mut FileHandle, u64);
+}
+
+/// What to do when a [`FileHandle`]'s write or flush takes too long.
+#[derive(Clone)]
+pub enum WatchdogAction {
+    /// Log a warning using the global [`LogSink`][crate::LogSink].
+    Log,
+    /// Call a function with how long the operation has taken so far.
+    Callback(Arc<dyn Fn(Duration) + Send + Sync>),
+    /// Mark the handle as failed, so every later write or flush fails with
+    /// [`FILE_HANDLE_TIMED_OUT`] instead of getting stuck too.
+    Fail,
+}
+
+/// The error used once a handle's watchdog has marked it as failed.
+pub(crate) fn timed_out() -> Error {
+    crate_status_unboxed(CRATE_ERROR_TIMED_OUT)
+}
+
+/// Per-handle state recording when the current operation started.
+#[derive(Debug, Default)]
+pub(crate) struct WatchdogTimer {
+    enabled: AtomicBool,
+    next_id: AtomicU64,
+    current: Mutex<Option<Started>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Started {
+    id: u64,
+    at: Instant,
+}
+
+impl WatchdogTimer {
+    /// Note that an operation is starting, if the handle is being watched.
+    pub(crate) fn start(&self) -> Option<WatchdogGuard<'_>> {
+        if !self.enabled.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        *self.current() = Some(Started {
+            id,
+            at: Instant::now(),
+        });
+
+        Some(WatchdogGuard(self))
+    }
+
+    fn current(&self) -> MutexGuard<'_, Option<Started>> {
+        self.current.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Marks the operation as finished when dropped.
+pub(crate) struct WatchdogGuard<'a>(&'a WatchdogTimer);
+
+impl Drop for WatchdogGuard<'_> {
+    fn drop(&mut self) { *self.0.current() = None; }
+}
+
+impl FileHandle {
+    /// Fail with [`timed_out()`] if the watchdog has given up on the handle.
+    pub(crate) unsafe fn check_timed_out(
+        handle: *mut FileHandle,
+    ) -> Result<(), Error> {
+        if (*handle).has_flag(FileHandle::TIMED_OUT) {
+            let e = timed_out();
+            (*handle).cold.last_error.record(&e);
+            Err(e)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+struct Watch {
+    limit: Duration,
+    action: WatchdogAction,
+    /// The operation the action was last triggered for, so each stuck
+    /// operation only triggers it once.
+    reported: Option<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    watches: HashMap<usize, Watch>,
+    /// The handle whose action is currently being run.
+    in_flight: Option<usize>,
+    stop: bool,
+}
+
+#[derive(Default)]
+struct Monitor {
+    state: Mutex<State>,
+    changed: Condvar,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Monitor {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(&self) {
+        let mut state = self.state();
+
+        while !state.stop {
+            if let Some((handle, elapsed)) = overdue(&mut state) {
+                let action = state.watches[&handle].action.clone();
+                state.in_flight = Some(handle);
+                drop(state);
+                unsafe { trigger(handle as *mut FileHandle, &action, elapsed) };
+                state = self.state();
+                state.in_flight = None;
+                self.changed.notify_all();
+                continue;
+            }
+
+            // Note: operations don't wake us up when they start, so poll
+            // often enough to notice one overrunning the shortest limit
+            let poll = state
+                .watches
+                .values()
+                .map(|w| w.limit / 2)
+                .min()
+                .map(|p| p.max(Duration::from_millis(1)));
+            state = match poll {
+                Some(poll) => {
+                    self.changed
+                        .wait_timeout(state, poll)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                },
+                None => self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// Stop watching a handle, waiting for its action to finish if it is
+    /// currently running.
+    fn remove(&self, handle: usize) {
+        let mut state = self.state();
+        state.watches.remove(&handle);
+
+        while state.in_flight == Some(handle) {
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Find a handle whose current operation has gone past its limit and hasn't
+/// been reported yet, marking it as reported.
+fn overdue(state: &mut State) -> Option<(usize, Duration)> {
+    let now = Instant::now();
+
+    for (&handle, watch) in state.watches.iter_mut() {
+        let timer = unsafe {
+            match (*(handle as *mut FileHandle)).extensions() {
+                Some(ext) => &ext.watchdog,
+                None => continue,
+            }
+        };
+        let started = match *timer.current() {
+            Some(started) => started,
+            None => continue,
+        };
+        let elapsed = now.saturating_duration_since(started.at);
+
+        if elapsed > watch.limit && watch.reported != Some(started.id) {
+            watch.reported = Some(started.id);
+            return Some((handle, elapsed));
+        }
+    }
+
+    None
+}
+
+unsafe fn trigger(
+    handle: *mut FileHandle,
+    action: &WatchdogAction,
+    elapsed: Duration,
+) {
+    match action {
+        WatchdogAction::Log => {
+            let message = format!(
+                "An operation on the handle at {:p} has been running for {:?}",
+                handle, elapsed
+            );
+            log_bridge::emit_to_global_sink(&LogRecord {
+                level: LogLevel::Warn,
+                target: "thin_trait_objects::watchdog",
+                message: &message,
+            });
+        },
+        WatchdogAction::Callback(callback) => callback(elapsed),
+        WatchdogAction::Fail => {
+            (*handle).set_flag(FileHandle::TIMED_OUT);
+            (*handle).cold.last_error.record(&timed_out());
+        },
+    }
+}
+
+static MONITOR: Global<Arc<Monitor>> = Global::new();
+
+/// Get the monitor, starting its thread if it isn't running yet.
+fn monitor() -> Result<&'static Arc<Monitor>, Error> {
+    let monitor = MONITOR.get_or_init(Default::default);
+    let mut thread = monitor.thread.lock().unwrap_or_else(|e| e.into_inner());
+
+    if thread.is_none() {
+        let monitor = Arc::clone(monitor);
+        *thread = Some(
+            std::thread::Builder::new()
+                .name("thin-trait-objects-watchdog".into())
+                .spawn(move || monitor.run())?,
+        );
+    }
+
+    Ok(monitor)
+}
+
+/// Stop the monitor thread and forget about every watched handle.
+pub(crate) unsafe fn shutdown() {
+    if let Some(monitor) = MONITOR.get() {
+        monitor.state().stop = true;
+        monitor.changed.notify_all();
+
+        let thread = monitor
+            .thread
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+
+    MONITOR.reset();
+}
+
+/// Start watching the handle, replacing any previous watchdog, failing if
+/// the monitor thread couldn't be started.
+pub(crate) unsafe fn watch(
+    handle: *mut FileHandle,
+    limit: Duration,
+    action: WatchdogAction,
+) -> Result<(), Error> {
+    let monitor = monitor()?;
+    (*handle)
+        .extensions_or_default()
+        .watchdog
+        .enabled
+        .store(true, Ordering::Release);
+
+    monitor.state().watches.insert(
+        handle as usize,
+        Watch {
+            limit,
+            action,
+            reported: None,
+        },
+    );
+    monitor.changed.notify_all();
+
+    Ok(())
+}
+
+/// Start watching the handle on behalf of the FFI, recording why the monitor
+/// thread couldn't be started.
+unsafe fn watch_or_errno(
+    handle: *mut FileHandle,
+    limit: Duration,
+    action: WatchdogAction,
+) -> c_int {
+    match watch(handle, limit, action) {
+        Ok(()) => 0,
+        Err(e) => {
+            (*handle).cold.last_error.record(&e);
+            crate::forbid_panics::into_errno(e)
+        },
+    }
+}
+
+/// Forget about a handle which is about to be destroyed.
+pub(crate) unsafe fn unregister(handle: *mut FileHandle) {
+    let enabled = (*handle)
+        .extensions()
+        .map_or(false, |ext| ext.watchdog.enabled.load(Ordering::Acquire));
+
+    if enabled {
+        if let Some(monitor) = MONITOR.get() {
+            monitor.remove(handle as usize);
+        }
+    }
+}
+
+export! {
+    /// Watch for writes and flushes on this [`FileHandle`] which take longer
+    /// than `max_write_duration_ms` milliseconds, or stop watching if
+    /// `max_write_duration_ms` is `0`.
+    ///
+    /// A crate-managed thread checks on the handle, and when an operation
+    /// overruns the limit it performs the `action` ([`WATCHDOG_LOG`] or
+    /// [`WATCHDOG_FAIL`]) once for that operation. The stuck operation itself
+    /// keeps running, since there is no way to interrupt it safely. Use
+    /// [`file_handle_set_watchdog_callback()`] to be notified directly instead.
+    ///
+    /// Returns `0` on success, `-1` if the `action` is unknown, or another
+    /// negative value (also recorded as the handle's last error) if the
+    /// watchdog's thread couldn't be started.
+    pub unsafe extern "C" fn file_handle_set_watchdog(
+        handle: *mut FileHandle,
+        max_write_duration_ms: u32,
+        action: c_int,
+    ) -> c_int {
+        let action = match action {
+            WATCHDOG_LOG => WatchdogAction::Log,
+            WATCHDOG_FAIL => WatchdogAction::Fail,
+            _ => return -1,
+        };
+
+        if max_write_duration_ms == 0 {
+            unregister(handle);
+            return 0;
+        }
+
+        let limit = Duration::from_millis(max_write_duration_ms.into());
+        watch_or_errno(handle, limit, action)
+    }
+}
+
+export! {
+    /// Like [`file_handle_set_watchdog()`] with the [`WATCHDOG_CALLBACK`]
+    /// action, calling `callback` with `user_data`, the handle, and how long
+    /// the operation has been running in milliseconds.
+    ///
+    /// The callback runs on the watchdog's thread while the operation is still
+    /// stuck, so it must not use or destroy the handle. Returns `-1` if
+    /// `callback` is `null`, and otherwise the same as
+    /// [`file_handle_set_watchdog()`].
+    pub unsafe extern "C" fn file_handle_set_watchdog_callback(
+        handle: *mut FileHandle,
+        max_write_duration_ms: u32,
+        callback: Option<WatchdogCallback>,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let callback = match callback {
+            Some(callback) => callback,
+            None => return -1,
+        };
+
+        if max_write_duration_ms == 0 {
+            unregister(handle);
+            return 0;
+        }
+
+        struct Target {
+            user_data: *mut c_void,
+            handle: *mut FileHandle,
+        }
+
+        // Safety: the caller promised the callback can be called from any
+        // thread
+        unsafe impl Send for Target {}
+        unsafe impl Sync for Target {}
+
+        let target = Target { user_data, handle };
+        let action = WatchdogAction::Callback(Arc::new(move |elapsed| {
+            let ms = elapsed.as_millis() as u64;
+            callback(target.user_data, target.handle, ms);
+        }));
+        let limit = Duration::from_millis(max_write_duration_ms.into());
+        watch_or_errno(handle, limit, action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, lifecycle::lock_global_state, OwnedFileHandle};
+    use std::{
+        io::Write,
+        sync::mpsc,
+    };
+
+    /// A writer whose first write hangs until it is told to continue.
+    struct Hangs(Mutex<Option<mpsc::Receiver<()>>>);
+
+    impl Write for Hangs {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            if let Some(rx) = self.0.lock().unwrap().take() {
+                let _ = rx.recv();
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+    }
+
+    fn hangs() -> (Hangs, mpsc::Sender<()>) {
+        let (tx, rx) = mpsc::channel();
+        (Hangs(Mutex::new(Some(rx))), tx)
+    }
+
+    #[test]
+    fn stuck_handles_fail_fast() {
+        let _global = lock_global_state();
+        let (writer, release) = hangs();
+        let mut handle = OwnedFileHandle::new(writer);
+        handle
+            .set_watchdog(Duration::from_millis(5), WatchdogAction::Fail)
+            .unwrap();
+        let raw = handle.into_raw();
+
+        let address = raw as usize;
+        let stuck = std::thread::spawn(move || unsafe {
+            let handle = address as *mut FileHandle;
+            file_handle_write(handle, b"x".as_ptr().cast(), 1)
+        });
+
+        unsafe {
+            while !(*raw).has_flag(FileHandle::TIMED_OUT) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+
+            let ret = file_handle_flush(raw);
+            assert_eq!(ret, FILE_HANDLE_TIMED_OUT);
+            let mut handle = OwnedFileHandle::from_raw(raw);
+            let err = handle.write(b"y").unwrap_err();
+            assert_eq!(
+                crate::errors::crate_status_of(&err),
+                Some(CRATE_ERROR_TIMED_OUT)
+            );
+
+            // the stuck write still finishes normally
+            release.send(()).unwrap();
+            assert_eq!(stuck.join().unwrap(), 1);
+            drop(handle);
+            shutdown();
+        }
+    }
+
+    #[test]
+    fn callbacks_are_called_once_per_stuck_write() {
+        let _global = lock_global_state();
+        let (writer, release) = hangs();
+        let (tx, rx) = mpsc::channel::<u64>();
+
+        c_unwind! {
+            unsafe fn on_stuck(
+                user_data: *mut c_void,
+                _handle: *mut FileHandle,
+                elapsed_ms: u64,
+            ) {
+                let tx = &*(user_data as *const Mutex<mpsc::Sender<u64>>);
+                tx.lock().unwrap().send(elapsed_ms).unwrap();
+            }
+        }
+
+        unsafe {
+            let tx = Mutex::new(tx);
+            let handle = FileHandle::for_writer(writer);
+            let user_data = &tx as *const _ as *mut c_void;
+            let ret = file_handle_set_watchdog_callback(
+                handle,
+                5,
+                Some(on_stuck),
+                user_data,
+            );
+            assert_eq!(ret, 0);
+            assert_eq!(file_handle_set_watchdog(handle, 5, 42), -1);
+
+            let address = handle as usize;
+            let stuck = std::thread::spawn(move || {
+                let handle = address as *mut FileHandle;
+                file_handle_write(handle, b"x".as_ptr().cast(), 1)
+            });
+
+            let elapsed = rx.recv().unwrap();
+            assert!(elapsed >= 5);
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(rx.try_recv().is_err());
+
+            release.send(()).unwrap();
+            assert_eq!(stuck.join().unwrap(), 1);
+            file_handle_destroy(handle);
+            shutdown();
+        }
+    }
+}
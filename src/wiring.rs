@@ -0,0 +1,631 @@
+//! Building a graph of handles from a short textual description, so which
+//! sinks are used (and how they're wrapped) can live in a config file.
+
+use crate::{
+    BackgroundWriter, BinaryEncoding, BinaryEncodingWriter, FileHandle,
+    LossyWriter, OwnedFileHandle, SequencedWriter, ShortWriter,
+};
+use std::{
+    ffi::CStr,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{BufWriter, Error, Write},
+    net::TcpStream,
+    os::raw::c_char,
+    ptr,
+    time::Duration,
+};
+
+/// Why a wiring description couldn't be turned into a handle.
+#[derive(Debug)]
+pub struct WiringError {
+    /// The byte offset of the part of the description which was rejected.
+    pub position: usize,
+    /// What went wrong.
+    pub kind: WiringErrorKind,
+}
+
+/// The different ways a wiring description can be rejected.
+#[derive(Debug)]
+pub enum WiringErrorKind {
+    /// The description ended part way through a sink.
+    UnexpectedEnd,
+    /// A character which isn't allowed at this position.
+    UnexpectedCharacter(char),
+    /// There is no sink with this name.
+    UnknownSink(String),
+    /// The sink was given the wrong target or arguments.
+    InvalidArguments {
+        /// The sink's name.
+        sink: String,
+        /// What was wrong with them.
+        reason: String,
+    },
+    /// A file couldn't be created or a connection couldn't be made.
+    Io(Error),
+}
+
+impl std::error::Error for WiringError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.kind {
+            WiringErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Display for WiringError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            WiringErrorKind::UnexpectedEnd => {
+                write!(f, "The description ended unexpectedly")?
+            },
+            WiringErrorKind::UnexpectedCharacter(c) => {
+                write!(f, "Unexpected {:?}", c)?
+            },
+            WiringErrorKind::UnknownSink(ref name) => {
+                write!(f, "There is no \"{}\" sink", name)?
+            },
+            WiringErrorKind::InvalidArguments {
+                ref sink,
+                ref reason,
+            } => write!(f, "Invalid arguments for \"{}\": {}", sink, reason)?,
+            WiringErrorKind::Io(ref e) => write!(f, "{}", e)?,
+        }
+
+        write!(f, " at position {}", self.position)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    position: usize,
+    name: String,
+    target: Option<String>,
+    args: Vec<Arg>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Sink(Node),
+    Number(usize, usize),
+}
+
+impl Arg {
+    fn position(&self) -> usize {
+        match *self {
+            Arg::Sink(ref node) => node.position,
+            Arg::Number(position, _) => position,
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, kind: WiringErrorKind) -> WiringError {
+        WiringError {
+            position: self.position,
+            kind,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.position += c.len_utf8();
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if !predicate(c) {
+                break;
+            }
+            self.position += c.len_utf8();
+        }
+        &self.text[start..self.position]
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), WiringError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.position += 1;
+                Ok(())
+            },
+            Some(c) => Err(self.error(WiringErrorKind::UnexpectedCharacter(c))),
+            None => Err(self.error(WiringErrorKind::UnexpectedEnd)),
+        }
+    }
+
+    fn node(&mut self) -> Result<Node, WiringError> {
+        self.skip_whitespace();
+        let position = self.position;
+        let name =
+            self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+
+        if name.is_empty() {
+            return Err(match self.peek() {
+                Some(c) => self.error(WiringErrorKind::UnexpectedCharacter(c)),
+                None => self.error(WiringErrorKind::UnexpectedEnd),
+            });
+        }
+
+        let mut node = Node {
+            position,
+            name: name.to_string(),
+            target: None,
+            args: Vec::new(),
+        };
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some(':') => {
+                self.position += 1;
+                let target = self.take_while(|c| c != ',' && c != ')');
+                node.target = Some(target.trim().to_string());
+            },
+            Some('(') => {
+                self.position += 1;
+                node.args.push(self.arg()?);
+                self.skip_whitespace();
+
+                while self.peek() == Some(',') {
+                    self.position += 1;
+                    node.args.push(self.arg()?);
+                    self.skip_whitespace();
+                }
+
+                self.expect(')')?;
+            },
+            _ => {},
+        }
+
+        Ok(node)
+    }
+
+    fn arg(&mut self) -> Result<Arg, WiringError> {
+        self.skip_whitespace();
+        let position = self.position;
+
+        if !self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            return self.node().map(Arg::Sink);
+        }
+
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        match digits.parse() {
+            Ok(n) => Ok(Arg::Number(position, n)),
+            Err(e) => Err(WiringError {
+                position,
+                kind: WiringErrorKind::InvalidArguments {
+                    sink: String::new(),
+                    reason: e.to_string(),
+                },
+            }),
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<Node, WiringError> {
+    let mut parser = Parser { text, position: 0 };
+    let node = parser.node()?;
+
+    parser.skip_whitespace();
+    match parser.peek() {
+        Some(c) => Err(parser.error(WiringErrorKind::UnexpectedCharacter(c))),
+        None => Ok(node),
+    }
+}
+
+impl Node {
+    fn invalid(&self, position: usize, reason: &str) -> WiringError {
+        WiringError {
+            position,
+            kind: WiringErrorKind::InvalidArguments {
+                sink: self.name.clone(),
+                reason: reason.to_string(),
+            },
+        }
+    }
+
+    fn io_error(&self, e: Error) -> WiringError {
+        WiringError {
+            position: self.position,
+            kind: WiringErrorKind::Io(e),
+        }
+    }
+
+    /// Check this sink has a target (and no arguments) if it needs one.
+    fn target(&self, needed: bool) -> Result<Option<&str>, WiringError> {
+        if !self.args.is_empty() {
+            return Err(self.invalid(self.position, "expected no arguments"));
+        }
+
+        match self.target.as_deref() {
+            Some("") | None if needed => {
+                Err(self.invalid(self.position, "expected \"name:target\""))
+            },
+            Some(_) if !needed => {
+                Err(self.invalid(self.position, "unexpected target"))
+            },
+            target => Ok(target),
+        }
+    }
+
+    /// Check this wrapper was given one sink followed by between `min` and
+    /// `max` numbers.
+    fn wrapper(
+        &self,
+        min: usize,
+        max: usize,
+    ) -> Result<(&Node, Vec<usize>), WiringError> {
+        if self.target.is_some() {
+            return Err(self.invalid(self.position, "unexpected target"));
+        }
+
+        let inner = match self.args.first() {
+            Some(Arg::Sink(inner)) => inner,
+            Some(other) => {
+                return Err(self.invalid(other.position(), "expected a sink"))
+            },
+            None => return Err(self.invalid(self.position, "expected a sink")),
+        };
+
+        let mut numbers = Vec::new();
+        for arg in &self.args[1..] {
+            match *arg {
+                Arg::Number(position, 0) => {
+                    return Err(self.invalid(position, "must be positive"))
+                },
+                Arg::Number(_, n) => numbers.push(n),
+                Arg::Sink(ref node) => {
+                    return Err(self.invalid(node.position, "expected a number"))
+                },
+            }
+        }
+
+        if numbers.len() < min || numbers.len() > max {
+            let reason = if min == max {
+                format!("expected a sink and {} number(s)", min)
+            } else {
+                format!("expected a sink and {} to {} numbers", min, max)
+            };
+            return Err(self.invalid(self.position, &reason));
+        }
+
+        Ok((inner, numbers))
+    }
+
+    fn build(&self) -> Result<OwnedFileHandle, WiringError> {
+        match self.name.as_str() {
+            "null" => {
+                self.target(false)?;
+                Ok(OwnedFileHandle::new(std::io::sink()))
+            },
+            "memory" => {
+                self.target(false)?;
+                Ok(OwnedFileHandle::new(Vec::<u8>::new()))
+            },
+            "stdout" => {
+                self.target(false)?;
+                Ok(OwnedFileHandle::new(std::io::stdout()))
+            },
+            "stderr" => {
+                self.target(false)?;
+                Ok(OwnedFileHandle::new(std::io::stderr()))
+            },
+            "file" => {
+                let path = self.target(true)?.unwrap_or_default();
+                let file = File::create(path).map_err(|e| self.io_error(e))?;
+
+                unsafe {
+                    let handle = FileHandle::for_writer(file);
+                    (*handle).cold.path = Some(path.into());
+                    Ok(OwnedFileHandle::from_raw(handle))
+                }
+            },
+            "tcp" => {
+                let address = self.target(true)?.unwrap_or_default();
+                let stream = TcpStream::connect(address)
+                    .map_err(|e| self.io_error(e))?;
+                Ok(OwnedFileHandle::new(stream))
+            },
+            "buffered" => {
+                let (inner, numbers) = self.wrapper(0, 2)?;
+                let inner = inner.build()?;
+                let writer = match numbers.first() {
+                    Some(&capacity) => {
+                        BufWriter::with_capacity(capacity, inner)
+                    },
+                    None => BufWriter::new(inner),
+                };
+                idle_flush(OwnedFileHandle::new(writer), numbers.get(1))
+                    .map_err(|e| self.io_error(e))
+            },
+            "background" => {
+                let (inner, numbers) = self.wrapper(1, 2)?;
+                let writer =
+                    BackgroundWriter::try_new(inner.build()?, numbers[0])
+                        .map_err(|e| self.io_error(e))?;
+                idle_flush(OwnedFileHandle::new(writer), numbers.get(1))
+                    .map_err(|e| self.io_error(e))
+            },
+            "lossy" => {
+                let (inner, numbers) = self.wrapper(1, 1)?;
+                let writer = LossyWriter::try_new(inner.build()?, numbers[0])
+                    .map_err(|e| self.io_error(e))?;
+                Ok(OwnedFileHandle::new(writer))
+            },
+            "sequenced" => {
+                let (inner, _) = self.wrapper(0, 0)?;
+                Ok(OwnedFileHandle::new(SequencedWriter::new(inner.build()?)))
+            },
+            "short_write" => {
+                let (inner, numbers) = self.wrapper(1, 1)?;
+                let writer = ShortWriter::new(inner.build()?, numbers[0]);
+                Ok(OwnedFileHandle::new(writer))
+            },
+            "base64" | "hex" => {
+                let (inner, _) = self.wrapper(0, 0)?;
+                let encoding = if self.name == "hex" {
+                    BinaryEncoding::Hex
+                } else {
+                    BinaryEncoding::Base64
+                };
+                let inner = inner.build()?;
+                let writer = BinaryEncodingWriter::new(inner, encoding);
+                Ok(OwnedFileHandle::new(writer))
+            },
+            "tee" => {
+                if self.target.is_some() || self.args.is_empty() {
+                    let reason = "expected one or more sinks";
+                    return Err(self.invalid(self.position, reason));
+                }
+
+                let mut branches = Vec::new();
+                for arg in &self.args {
+                    match *arg {
+                        Arg::Sink(ref node) => branches.push(node.build()?),
+                        Arg::Number(position, _) => {
+                            let reason = "expected a sink";
+                            return Err(self.invalid(position, reason));
+                        },
+                    }
+                }
+                Ok(OwnedFileHandle::new(Tee(branches)))
+            },
+            _ => Err(WiringError {
+                position: self.position,
+                kind: WiringErrorKind::UnknownSink(self.name.clone()),
+            }),
+        }
+    }
+}
+
+/// Writes everything to several handles in turn.
+struct Tee(Vec<OwnedFileHandle>);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        for branch in &mut self.0 {
+            branch.write_all(buf)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        // Note: every branch gets flushed, even if an earlier one failed
+        for branch in &mut self.0 {
+            if let Err(e) = branch.flush() {
+                result = result.and(Err(e));
+            }
+        }
+
+        result
+    }
+}
+
+/// Flush the handle after `idle_ms` milliseconds without any writes, if set.
+fn idle_flush(
+    handle: OwnedFileHandle,
+    idle_ms: Option<&usize>,
+) -> Result<OwnedFileHandle, Error> {
+    if let Some(&idle_ms) = idle_ms {
+        handle.enable_idle_flush(Duration::from_millis(idle_ms as u64))?;
+    }
+
+    Ok(handle)
+}
+
+impl OwnedFileHandle {
+    /// Create a handle from a wiring description, opening any files or
+    /// connections it refers to.
+    ///
+    /// A description is a single sink, where a sink is either a destination
+    /// (`name` or `name:target`) or a wrapper around other sinks
+    /// (`name(sink, arguments...)`). Numeric arguments must be positive.
+    /// Buffering sinks can be given an `idle` time in milliseconds, after
+    /// which they are flushed if nothing has been written (see
+    /// [`OwnedFileHandle::enable_idle_flush()`]).
+    ///
+    /// | Sink                                 | Creates                     |
+    /// | ------------------------------------ | --------------------------- |
+    /// | `null`                               | [`std::io::Sink`]           |
+    /// | `memory`                             | A `Vec<u8>`                 |
+    /// | `stdout`, `stderr`                   | The standard streams        |
+    /// | `file:path`                          | A new (or truncated) file   |
+    /// | `tcp:host:port`                      | A [`TcpStream`]             |
+    /// | `buffered(sink[, capacity[, idle]])` | A [`BufWriter`]             |
+    /// | `background(sink, capacity[, idle])` | A [`BackgroundWriter`]      |
+    /// | `lossy(sink, max_pending_bytes)`     | A [`LossyWriter`]           |
+    /// | `sequenced(sink)`                    | A [`SequencedWriter`]       |
+    /// | `short_write(sink, max_per_call)`    | A [`ShortWriter`]           |
+    /// | `base64(sink)`, `hex(sink)`          | A [`BinaryEncodingWriter`]  |
+    /// | `tee(sink, sink...)`                 | Copies writes to every sink |
+    ///
+    /// ```rust
+    /// # use std::io::Write;
+    /// # use thin_trait_objects::OwnedFileHandle;
+    /// let mut handle =
+    ///     OwnedFileHandle::from_wiring("tee(buffered(null, 8192), memory)")
+    ///         .unwrap();
+    /// handle.write_all(b"Hello, World!").unwrap();
+    ///
+    /// let err = OwnedFileHandle::from_wiring("gzip(null, 6)").unwrap_err();
+    /// assert_eq!(err.position, 0);
+    /// ```
+    pub fn from_wiring(description: &str) -> Result<Self, WiringError> {
+        parse(description)?.build()
+    }
+}
+
+export! {
+    /// Create a new [`FileHandle`] from a wiring description (a null-terminated
+    /// string like `"tee(buffered(file:/var/log/app.log, 8192), stderr)"`).
+    ///
+    /// Returns `null` if the description is invalid or a sink couldn't be
+    /// opened, in which case a null-terminated message saying why (truncated to
+    /// `err_len` bytes, including the terminator) is copied into `err_buf`
+    /// unless it is `null`.
+    pub unsafe extern "C" fn new_file_handle_from_wiring(
+        description: *const c_char,
+        err_buf: *mut c_char,
+        err_len: usize,
+    ) -> *mut FileHandle {
+        let result = match CStr::from_ptr(description).to_str() {
+            Ok(description) => OwnedFileHandle::from_wiring(description),
+            Err(e) => Err(WiringError {
+                position: e.valid_up_to(),
+                kind: WiringErrorKind::UnexpectedCharacter(
+                    std::char::REPLACEMENT_CHARACTER,
+                ),
+            }),
+        };
+
+        match result {
+            Ok(handle) => handle.into_raw(),
+            Err(e) => {
+                if !err_buf.is_null() && err_len > 0 {
+                    let message = e.to_string();
+                    let len = message.len().min(err_len - 1);
+                    ptr::copy_nonoverlapping(
+                        message.as_ptr(),
+                        err_buf.cast(),
+                        len,
+                    );
+                    *err_buf.add(len) = 0;
+                }
+
+                ptr::null_mut()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::*;
+
+    #[test]
+    fn parse_nested_sinks() {
+        let got = parse(" tee( buffered(file:/log/a, 8192), hex(null) )")
+            .unwrap();
+
+        let file = Node {
+            position: 15,
+            name: String::from("file"),
+            target: Some(String::from("/log/a")),
+            args: Vec::new(),
+        };
+        let buffered = Node {
+            position: 6,
+            name: String::from("buffered"),
+            target: None,
+            args: vec![Arg::Sink(file), Arg::Number(28, 8192)],
+        };
+        assert_eq!(got.name, "tee");
+        assert_eq!(got.args[0], Arg::Sink(buffered));
+        assert_eq!(got.args.len(), 2);
+    }
+
+    #[test]
+    fn errors_say_where_the_problem_is() {
+        let inputs = [
+            ("", 0, "The description ended unexpectedly"),
+            ("tee(null", 8, "The description ended unexpectedly"),
+            ("null)", 4, "Unexpected ')'"),
+            ("buffered(gzip(null))", 9, "There is no \"gzip\" sink"),
+            ("file", 0, "expected \"name:target\""),
+            ("background(null)", 0, "expected a sink and 1 to 2 numbers"),
+            ("buffered(null, memory)", 15, "expected a number"),
+            ("lossy(null, 0)", 12, "must be positive"),
+        ];
+
+        for &(input, position, message) in &inputs {
+            let err = OwnedFileHandle::from_wiring(input).unwrap_err();
+            assert_eq!(err.position, position, "{}", input);
+            let got = err.to_string();
+            assert!(got.contains(message), "{:?} => {:?}", input, got);
+        }
+    }
+
+    #[test]
+    fn buffered_sinks_can_flush_when_idle() {
+        let _global = crate::lifecycle::lock_global_state();
+        let mut handle =
+            OwnedFileHandle::from_wiring("buffered(memory, 8192, 10)").unwrap();
+
+        handle.write_all(b"Hello, World!").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let buffered = handle
+            .downcast_ref::<BufWriter<OwnedFileHandle>>()
+            .unwrap();
+        assert!(buffered.buffer().is_empty());
+        let memory = buffered.get_ref().downcast_ref::<Vec<u8>>().unwrap();
+        assert_eq!(memory.as_slice(), b"Hello, World!");
+
+        drop(handle);
+        unsafe { crate::autoflush::shutdown() };
+    }
+
+    #[test]
+    fn wire_up_a_handle_from_c() {
+        let mut err = [1 as c_char; 16];
+
+        unsafe {
+            let handle = new_file_handle_from_wiring(
+                b"sequenced(memory)\0".as_ptr().cast(),
+                err.as_mut_ptr(),
+                err.len(),
+            );
+            assert!(!handle.is_null());
+            assert_eq!(err[0], 1);
+            file_handle_destroy(handle);
+
+            let handle = new_file_handle_from_wiring(
+                b"tcp\0".as_ptr().cast(),
+                err.as_mut_ptr(),
+                err.len(),
+            );
+            assert!(handle.is_null());
+            let message = CStr::from_ptr(err.as_ptr()).to_str().unwrap();
+            assert_eq!(message, "Invalid argumen");
+        }
+    }
+}
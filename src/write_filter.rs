@@ -0,0 +1,212 @@
+//! Letting native code rewrite the bytes written to a handle after it has
+//! been created.
+
+use crate::{unwind::PoisonOnUnwind, FileHandle};
+use std::{
+    cell::Cell,
+    io::Error,
+    os::raw::{c_int, c_void},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+/// Given to a [`WriteFilter`] so it can ask for `len` more bytes of output.
+///
+/// The returned pointer is only valid until the next call, and is `null` if
+/// it was used outside of a filter.
+pub type FilterAlloc = unsafe extern "C" fn(usize) -> *mut u8;
+
+c_unwind! {
+    /// Rewrites the `in_len` bytes at `in_ptr` before they reach a handle's
+    /// writer, putting its output in memory obtained from the [`FilterAlloc`].
+    ///
+    /// Returns `0` on success, or a negative error code which fails the
+    /// write.
+    pub type WriteFilter = unsafe fn(
+        *mut c_void,
+        *const u8,
+        usize,
+        FilterAlloc,
+    ) -> c_int;
+}
+
+thread_local! {
+    /// Where [`allocate_output()`] puts bytes for the filter currently
+    /// running on this thread.
+    static OUTPUT: Cell<*mut Vec<u8>> = Cell::new(ptr::null_mut());
+}
+
+unsafe extern "C" fn allocate_output(len: usize) -> *mut u8 {
+    OUTPUT.with(|current| match current.get().as_mut() {
+        Some(output) => {
+            let start = output.len();
+            output.resize(start + len, 0);
+            output.as_mut_ptr().add(start)
+        },
+        None => ptr::null_mut(),
+    })
+}
+
+/// Points [`OUTPUT`] somewhere else until it is dropped, so filters which
+/// write to other filtered handles don't mix up their output.
+struct OutputScope(*mut Vec<u8>);
+
+impl OutputScope {
+    fn enter(output: &mut Vec<u8>) -> Self {
+        OutputScope(OUTPUT.with(|current| current.replace(output)))
+    }
+}
+
+impl Drop for OutputScope {
+    fn drop(&mut self) { OUTPUT.with(|current| current.set(self.0)); }
+}
+
+/// The filter set with [`file_handle_set_write_filter()`], if any.
+#[derive(Debug, Default)]
+pub(crate) struct WriteFilterSlot {
+    enabled: AtomicBool,
+    filter: Mutex<Option<(WriteFilter, usize)>>,
+}
+
+impl WriteFilterSlot {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    fn set(&self, filter: Option<(WriteFilter, usize)>) {
+        let mut slot = self.filter.lock().unwrap_or_else(|e| e.into_inner());
+        *slot = filter;
+        self.enabled.store(slot.is_some(), Ordering::Release);
+    }
+
+    /// Run `data` through the filter, returning `None` if there isn't one.
+    pub(crate) unsafe fn apply(
+        &self,
+        handle: *mut FileHandle,
+        data: &[u8],
+    ) -> Option<Result<Vec<u8>, Error>> {
+        let (filter, ctx) =
+            (*self.filter.lock().unwrap_or_else(|e| e.into_inner()))?;
+
+        let mut output = Vec::new();
+        let scope = OutputScope::enter(&mut output);
+        let guard = PoisonOnUnwind::new(handle);
+        let ctx = ctx as *mut c_void;
+        let ret = filter(ctx, data.as_ptr(), data.len(), allocate_output);
+        guard.disarm();
+        drop(scope);
+
+        if ret < 0 {
+            Some(Err(Error::from_raw_os_error(-ret)))
+        } else {
+            Some(Ok(output))
+        }
+    }
+}
+
+export! {
+    /// Pass everything written to this [`FileHandle`] through `filter` before
+    /// it reaches the underlying writer, or stop filtering if `filter` is
+    /// `null`.
+    ///
+    /// For each write, `filter` is called with `ctx`, the bytes being written
+    /// and a [`FilterAlloc`] which it calls to get space for its output (which
+    /// may be any length, including nothing). The whole output is written
+    /// before the write returns, and the caller is told every byte it passed in
+    /// was written. If the filter returns a negative error code, nothing is
+    /// written and the write fails with that error.
+    ///
+    /// `filter` may be called from any thread that writes to the handle, and
+    /// `ctx` must stay valid until the filter is replaced or the handle is
+    /// destroyed.
+    pub unsafe extern "C" fn file_handle_set_write_filter(
+        handle: *mut FileHandle,
+        filter: Option<WriteFilter>,
+        ctx: *mut c_void,
+    ) {
+        let filter = filter.map(|f| (f, ctx as usize));
+
+        if filter.is_some() {
+            (*handle).extensions_or_default().write_filter.set(filter);
+        } else if let Some(ext) = (*handle).extensions() {
+            ext.write_filter.set(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{tests::SharedBuffer, *};
+    use std::{io::Write, slice};
+
+    c_unwind! {
+        /// Upper-case everything and add a newline.
+        unsafe fn shout(
+            _: *mut c_void,
+            data: *const u8,
+            len: usize,
+            alloc: FilterAlloc,
+        ) -> c_int {
+            let input = slice::from_raw_parts(data, len);
+            let out = slice::from_raw_parts_mut(alloc(len), len);
+            out.copy_from_slice(&input.to_ascii_uppercase());
+            *alloc(1) = b'\n';
+            0
+        }
+    }
+
+    c_unwind! {
+        /// Reject every write with the error code stored in `ctx`.
+        unsafe fn reject(
+            ctx: *mut c_void,
+            _: *const u8,
+            _: usize,
+            _: FilterAlloc,
+        ) -> c_int {
+            *(ctx as *const c_int)
+        }
+    }
+
+    #[test]
+    fn rewrite_writes_to_an_existing_handle() {
+        let buffer = SharedBuffer::default();
+
+        unsafe {
+            let handle = FileHandle::for_writer(buffer.clone());
+            file_handle_write(handle, b"a ".as_ptr().cast(), 2);
+            file_handle_set_write_filter(handle, Some(shout), ptr::null_mut());
+
+            let ret = file_handle_write(handle, b"quiet".as_ptr().cast(), 5);
+            assert_eq!(ret, 5);
+
+            file_handle_set_write_filter(handle, None, ptr::null_mut());
+            file_handle_write(handle, b"!".as_ptr().cast(), 1);
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"a QUIET\n!");
+        assert!(unsafe { allocate_output(1) }.is_null());
+    }
+
+    #[test]
+    fn filter_errors_fail_the_write() {
+        let buffer = SharedBuffer::default();
+        let mut code: c_int = -5;
+
+        unsafe {
+            let handle = FileHandle::for_writer(buffer.clone());
+            let ctx = &mut code as *mut c_int as *mut c_void;
+            file_handle_set_write_filter(handle, Some(reject), ctx);
+
+            let mut owned = crate::OwnedFileHandle::from_raw(handle);
+            let err = owned.write(b"denied").unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(5));
+        }
+
+        assert!(buffer.0.lock().unwrap().is_empty());
+    }
+}
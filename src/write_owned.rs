@@ -0,0 +1,304 @@
+//! Handing a buffer to a queued [`FileHandle`] without copying it, for large
+//! payloads written from C.
+
+use crate::{budget::Charge, BackgroundWriter, FileHandle, LossyWriter};
+use std::{
+    cell::RefCell,
+    io::Error,
+    ops::Deref,
+    os::raw::{c_char, c_int, c_void},
+    sync::Arc,
+};
+
+c_unwind! {
+    /// Called with the buffer, its length, and the `ctx` pointer once a
+    /// buffer passed to [`file_handle_write_owned()`] is no longer needed.
+    pub type FreeCallback = unsafe fn(*mut c_char, usize, *mut c_void);
+}
+
+/// A buffer owned by native code, which is given back to its `free`
+/// callback when dropped.
+pub(crate) struct ForeignBuffer {
+    data: *mut c_char,
+    len: usize,
+    free: Option<FreeCallback>,
+    ctx: *mut c_void,
+}
+
+// Safety: the caller of file_handle_write_owned() promises the buffer and
+// its free callback may be used from any thread, and the data is never
+// mutated while we own it.
+unsafe impl Send for ForeignBuffer {}
+unsafe impl Sync for ForeignBuffer {}
+
+impl ForeignBuffer {
+    fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(self.data.cast(), self.len) }
+        }
+    }
+}
+
+impl Drop for ForeignBuffer {
+    fn drop(&mut self) {
+        if let Some(free) = self.free {
+            unsafe { free(self.data, self.len, self.ctx) };
+        }
+    }
+}
+
+/// The data for a queued write, which was either copied (and charged to the
+/// memory budget) or taken from the caller without copying.
+pub(crate) enum Payload {
+    // Note: the charge is only held so it is given back when we're dropped
+    Copied(Vec<u8>, #[allow(dead_code)] Charge),
+    Owned(Arc<ForeignBuffer>),
+}
+
+impl Payload {
+    /// Take ownership of `buf` if it is the buffer being offered to `writer`
+    /// by [`file_handle_write_owned()`], otherwise make a copy if it fits in
+    /// the memory budget.
+    pub(crate) fn take_or_copy<W>(
+        writer: &W,
+        buf: &[u8],
+    ) -> Result<Payload, Error> {
+        let owner = writer as *const W as *const ();
+
+        OFFER.with(|offer| {
+            let mut offer = offer.borrow_mut();
+
+            match offer.take() {
+                Some((o, buffer))
+                    if o == owner
+                        && buffer.as_slice().as_ptr() == buf.as_ptr()
+                        && buffer.len == buf.len() =>
+                {
+                    Ok(Payload::Owned(buffer))
+                },
+                other => {
+                    *offer = other;
+                    let charge = Charge::new(buf.len())?;
+                    Ok(Payload::Copied(buf.to_vec(), charge))
+                },
+            }
+        })
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Payload::Copied(data, _) => data,
+            Payload::Owned(buffer) => buffer.as_slice(),
+        }
+    }
+}
+
+thread_local! {
+    /// The buffer being written by [`file_handle_write_owned()`] on this
+    /// thread, and the address of the only writer allowed to take it.
+    ///
+    /// Only the handle's own writer may take the buffer, because a writer
+    /// which passes the data on to several other handles still needs it
+    /// after the first one has returned.
+    static OFFER: RefCell<Option<(*const (), Arc<ForeignBuffer>)>> =
+        RefCell::new(None);
+}
+
+/// Offers a buffer to a writer until dropped.
+///
+/// We keep our own reference to the buffer so it outlives the write call,
+/// even when the writer passes it to a thread which finishes with it first.
+struct OfferScope {
+    buffer: Arc<ForeignBuffer>,
+    previous: Option<(*const (), Arc<ForeignBuffer>)>,
+}
+
+impl OfferScope {
+    fn enter(owner: Option<*const ()>, buffer: ForeignBuffer) -> Self {
+        let buffer = Arc::new(buffer);
+        let offered = owner.map(|owner| (owner, Arc::clone(&buffer)));
+        let previous = OFFER.with(|offer| offer.replace(offered));
+
+        OfferScope { buffer, previous }
+    }
+}
+
+impl Drop for OfferScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        OFFER.with(|offer| offer.replace(previous));
+    }
+}
+
+/// The address of the object behind a handle, if it can take ownership of
+/// a buffer instead of copying it.
+unsafe fn queued_writer(handle: *mut FileHandle) -> Option<*const ()> {
+    if let Some(writer) = FileHandle::downcast_raw::<BackgroundWriter>(handle)
+    {
+        Some(writer as *const ())
+    } else {
+        FileHandle::downcast_raw::<LossyWriter>(handle)
+            .map(|writer| writer as *const ())
+    }
+}
+
+c_unwind! {
+    /// Write all `len` bytes of `data` to the handle, taking ownership of the
+    /// buffer instead of copying it.
+    ///
+    /// Handles created with [`new_background_file_handle()`] or
+    /// [`new_lossy_file_handle()`] put the buffer itself on their queue, and
+    /// `free_cb` is called (possibly from the background thread) once it has
+    /// been written or dropped. Other handles write it immediately, so
+    /// `free_cb` is called before this function returns. `free_cb` may be
+    /// `null` if the buffer doesn't need freeing.
+    ///
+    /// Ownership of the buffer is always taken, even when writing fails.
+    /// Returns `0` on success or a negative error code.
+    ///
+    /// [`new_background_file_handle()`]: crate::new_background_file_handle
+    /// [`new_lossy_file_handle()`]: crate::new_lossy_file_handle
+    pub unsafe extern fn file_handle_write_owned(
+        handle: *mut FileHandle,
+        data: *mut c_char,
+        len: usize,
+        free_cb: Option<FreeCallback>,
+        ctx: *mut c_void,
+    ) -> c_int {
+        let buffer = ForeignBuffer {
+            data,
+            len,
+            free: free_cb,
+            ctx,
+        };
+        let scope = OfferScope::enter(queued_writer(handle), buffer);
+
+        match FileHandle::dispatch_write_all(handle, scope.buffer.as_slice()) {
+            Ok(_) => 0,
+            Err(e) => crate::forbid_panics::into_errno(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ffi::{tests::SharedBuffer, *},
+        OwnedFileHandle,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts how often [`free_boxed()`] frees a buffer.
+    #[derive(Default)]
+    struct Frees(AtomicUsize);
+
+    c_unwind! {
+        unsafe fn free_boxed(data: *mut c_char, len: usize, ctx: *mut c_void) {
+            let slice = std::ptr::slice_from_raw_parts_mut(data.cast(), len);
+            drop(Box::<[u8]>::from_raw(slice));
+            (*ctx.cast::<Frees>()).0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn boxed(data: &[u8]) -> (*mut c_char, usize) {
+        let data = Box::into_raw(data.to_vec().into_boxed_slice());
+        (data.cast(), data.len())
+    }
+
+    #[test]
+    fn only_the_offered_writer_takes_the_buffer() {
+        let (writer, other) = (0_u8, 0_u8);
+        let frees = Frees::default();
+        let (data, len) = boxed(b"asdf");
+        let buffer = ForeignBuffer {
+            data,
+            len,
+            free: Some(free_boxed),
+            ctx: &frees as *const Frees as *mut c_void,
+        };
+
+        let owner = &writer as *const u8 as *const ();
+        let scope = OfferScope::enter(Some(owner), buffer);
+        let buf = scope.buffer.as_slice();
+
+        let copied = Payload::take_or_copy(&other, buf).unwrap();
+        assert!(matches!(copied, Payload::Copied(..)));
+        let taken = Payload::take_or_copy(&writer, buf).unwrap();
+        assert!(matches!(taken, Payload::Owned(_)));
+        // the buffer can only be taken once
+        let copied = Payload::take_or_copy(&writer, buf).unwrap();
+        assert!(matches!(copied, Payload::Copied(..)));
+
+        drop(scope);
+        assert_eq!(frees.0.load(Ordering::SeqCst), 0);
+        assert_eq!(&*taken, b"asdf");
+        drop(taken);
+        assert_eq!(frees.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn queued_handles_take_the_buffer() {
+        let buffer = SharedBuffer::default();
+        let frees = Frees::default();
+        let ctx = &frees as *const Frees as *mut c_void;
+
+        unsafe {
+            let inner = FileHandle::for_writer(buffer.clone());
+            let handle = new_background_file_handle(inner, 4);
+
+            for _ in 0..10 {
+                let (data, len) = boxed(b"payload\n");
+                let free = Some(free_boxed as FreeCallback);
+                let ret = file_handle_write_owned(handle, data, len, free, ctx);
+                assert_eq!(ret, 0);
+            }
+            file_handle_destroy(handle);
+        }
+
+        assert_eq!(frees.0.load(Ordering::SeqCst), 10);
+        assert_eq!(buffer.0.lock().unwrap().len(), 10 * 8);
+    }
+
+    #[test]
+    fn other_handles_copy_and_free_immediately() {
+        let buffer = SharedBuffer::default();
+        let frees = Frees::default();
+        let ctx = &frees as *const Frees as *mut c_void;
+        let mut handle = OwnedFileHandle::new(buffer.clone());
+        handle.freeze().unwrap();
+
+        unsafe {
+            let (data, len) = boxed(b"asdf");
+            let ret = file_handle_write_owned(
+                handle.as_ptr(),
+                data,
+                len,
+                Some(free_boxed as FreeCallback),
+                ctx,
+            );
+            assert_eq!(ret, FILE_HANDLE_SUSPENDED);
+            assert_eq!(frees.0.load(Ordering::SeqCst), 1);
+
+            handle.thaw();
+            let (data, len) = boxed(b"asdf");
+            let ret = file_handle_write_owned(
+                handle.as_ptr(),
+                data,
+                len,
+                Some(free_boxed as FreeCallback),
+                ctx,
+            );
+            assert_eq!(ret, 0);
+            assert_eq!(frees.0.load(Ordering::SeqCst), 2);
+        }
+
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"asdf");
+    }
+}
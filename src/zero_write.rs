@@ -0,0 +1,256 @@
+//! Deciding what to do when a writer accepts zero bytes.
+
+use crate::FileHandle;
+use std::{
+    io::{Error, ErrorKind},
+    os::raw::c_int,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Pass `0` straight through to the caller (the default).
+pub const ZERO_WRITE_PASS_THROUGH: c_int = 0;
+/// Turn a zero-byte write into an [`ErrorKind::WriteZero`] error.
+pub const ZERO_WRITE_ERROR: c_int = 1;
+/// Retry the write a number of times before failing with
+/// [`ErrorKind::WriteZero`].
+pub const ZERO_WRITE_RETRY: c_int = 2;
+
+/// What a [`FileHandle`] should do when its writer returns `Ok(0)` for a
+/// non-empty buffer.
+///
+/// Many C callers treat a return value of `0` as success or EOF and will loop
+/// forever trying to write the rest of their buffer, so it is often better to
+/// turn it into an error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZeroWritePolicy {
+    /// Return `0` to the caller.
+    PassThrough,
+    /// Fail with [`ErrorKind::WriteZero`].
+    Error,
+    /// Try writing again up to this many times, failing with
+    /// [`ErrorKind::WriteZero`] if the writer still doesn't accept anything.
+    Retry(u32),
+}
+
+impl ZeroWritePolicy {
+    // The policy is packed into a single u32 so it can live in an atomic:
+    // the low 2 bits hold the kind and the rest hold the retry count.
+    const KIND_MASK: u32 = 0b11;
+    const MAX_RETRIES: u32 = u32::MAX >> 2;
+
+    pub(crate) fn to_bits(self) -> u32 {
+        match self {
+            ZeroWritePolicy::PassThrough => 0,
+            ZeroWritePolicy::Error => 1,
+            ZeroWritePolicy::Retry(n) => 2 | (n.min(Self::MAX_RETRIES) << 2),
+        }
+    }
+
+    /// Parse the `policy` and `retries` taken by
+    /// [`file_handle_set_zero_write_policy()`].
+    pub(crate) fn from_raw(policy: c_int, retries: c_int) -> Option<Self> {
+        match (policy, retries) {
+            (ZERO_WRITE_PASS_THROUGH, _) => Some(ZeroWritePolicy::PassThrough),
+            (ZERO_WRITE_ERROR, _) => Some(ZeroWritePolicy::Error),
+            (ZERO_WRITE_RETRY, n) if n >= 0 => {
+                Some(ZeroWritePolicy::Retry(n as u32))
+            },
+            _ => None,
+        }
+    }
+
+    /// The inverse of [`ZeroWritePolicy::from_raw()`].
+    pub(crate) fn to_raw(self) -> (c_int, c_int) {
+        match self {
+            ZeroWritePolicy::PassThrough => (ZERO_WRITE_PASS_THROUGH, 0),
+            ZeroWritePolicy::Error => (ZERO_WRITE_ERROR, 0),
+            ZeroWritePolicy::Retry(n) => {
+                (ZERO_WRITE_RETRY, n.min(c_int::MAX as u32) as c_int)
+            },
+        }
+    }
+
+    pub(crate) fn from_bits(bits: u32) -> Self {
+        match bits & Self::KIND_MASK {
+            1 => ZeroWritePolicy::Error,
+            2 => ZeroWritePolicy::Retry(bits >> 2),
+            _ => ZeroWritePolicy::PassThrough,
+        }
+    }
+
+    /// Apply the policy to a write which returned `bytes_written`, using
+    /// `retry` to try again.
+    pub(crate) fn apply<F>(
+        self,
+        bytes_written: usize,
+        mut retry: F,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut() -> Result<usize, Error>,
+    {
+        if bytes_written != 0 {
+            return Ok(bytes_written);
+        }
+
+        match self {
+            ZeroWritePolicy::PassThrough => Ok(0),
+            ZeroWritePolicy::Error => Err(write_zero()),
+            ZeroWritePolicy::Retry(attempts) => {
+                for _ in 0..attempts {
+                    let bytes_written = retry()?;
+                    if bytes_written != 0 {
+                        return Ok(bytes_written);
+                    }
+                }
+
+                Err(write_zero())
+            },
+        }
+    }
+}
+
+impl Default for ZeroWritePolicy {
+    fn default() -> Self { ZeroWritePolicy::PassThrough }
+}
+
+/// The policy new handles start with (see [`Config`][crate::Config]).
+static DEFAULT_POLICY: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) fn default_policy() -> ZeroWritePolicy {
+    ZeroWritePolicy::from_bits(DEFAULT_POLICY.load(Ordering::Relaxed))
+}
+
+pub(crate) fn set_default_policy(policy: ZeroWritePolicy) {
+    DEFAULT_POLICY.store(policy.to_bits(), Ordering::Relaxed);
+}
+
+/// Go back to the original default policy.
+pub(crate) fn shutdown() { set_default_policy(ZeroWritePolicy::default()); }
+
+pub(crate) fn write_zero() -> Error {
+    // Note: no message, because the error mustn't allocate (see the
+    // forbid-panics feature)
+    ErrorKind::WriteZero.into()
+}
+
+export! {
+    /// Change what the [`FileHandle`] does when its writer accepts zero bytes.
+    ///
+    /// The `policy` is one of [`ZERO_WRITE_PASS_THROUGH`],
+    /// [`ZERO_WRITE_ERROR`], or [`ZERO_WRITE_RETRY`], with `retries` only being
+    /// used by the latter. Returns `0` on success or a negative value if the
+    /// arguments are invalid.
+    pub unsafe extern "C" fn file_handle_set_zero_write_policy(
+        handle: *mut FileHandle,
+        policy: c_int,
+        retries: c_int,
+    ) -> c_int {
+        let policy = match ZeroWritePolicy::from_raw(policy, retries) {
+            Some(policy) => policy,
+            None => return -1,
+        };
+
+        (*handle).set_zero_write_policy(policy);
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffi::*, OwnedFileHandle};
+    use std::io::Write;
+
+    /// A writer which only accepts data every `n`'th call.
+    struct Reluctant {
+        every: usize,
+        calls: usize,
+    }
+
+    impl Write for Reluctant {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+
+            if self.calls % self.every == 0 {
+                Ok(buf.len())
+            } else {
+                Ok(0)
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    fn reluctant(every: usize) -> OwnedFileHandle {
+        OwnedFileHandle::new(Reluctant { every, calls: 0 })
+    }
+
+    #[test]
+    fn policies_survive_a_round_trip() {
+        let policies = [
+            ZeroWritePolicy::PassThrough,
+            ZeroWritePolicy::Error,
+            ZeroWritePolicy::Retry(0),
+            ZeroWritePolicy::Retry(42),
+            ZeroWritePolicy::Retry(ZeroWritePolicy::MAX_RETRIES),
+        ];
+
+        for &policy in policies.iter() {
+            let bits = policy.to_bits();
+            assert_eq!(ZeroWritePolicy::from_bits(bits), policy);
+        }
+    }
+
+    #[test]
+    fn zero_writes_pass_through_by_default() {
+        // the default can be changed with a Config
+        let _global = crate::lifecycle::lock_global_state();
+        let mut handle = reluctant(2);
+
+        assert_eq!(handle.write(b"asdf").unwrap(), 0);
+    }
+
+    #[test]
+    fn zero_writes_can_be_errors() {
+        let mut handle =
+            reluctant(2).with_zero_write_policy(ZeroWritePolicy::Error);
+
+        let err = handle.write(b"asdf").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+
+        // empty writes are always fine
+        assert_eq!(handle.write(b"").unwrap(), 0);
+    }
+
+    #[test]
+    fn zero_writes_can_be_retried() {
+        let mut handle =
+            reluctant(3).with_zero_write_policy(ZeroWritePolicy::Retry(2));
+        assert_eq!(handle.write(b"asdf").unwrap(), 4);
+
+        let mut handle =
+            reluctant(3).with_zero_write_policy(ZeroWritePolicy::Retry(1));
+        let err = handle.write(b"asdf").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn set_the_policy_from_c() {
+        unsafe {
+            let handle = reluctant(2).into_raw();
+
+            let ret =
+                file_handle_set_zero_write_policy(handle, ZERO_WRITE_RETRY, 1);
+            assert_eq!(ret, 0);
+            assert_eq!(file_handle_write(handle, b"asdf".as_ptr() as _, 4), 4);
+
+            assert_eq!(file_handle_set_zero_write_policy(handle, 42, 0), -1);
+            assert_eq!(
+                file_handle_set_zero_write_policy(handle, ZERO_WRITE_RETRY, -1),
+                -1
+            );
+
+            file_handle_destroy(handle);
+        }
+    }
+}